@@ -0,0 +1,383 @@
+// arrow.rs
+
+//! Apache Arrow IPC stream output, for `--format arrow`: a schema message, record batches of
+//! up to `BATCH_ROWS` results, and the end-of-stream marker. DuckDB, pandas (pyarrow) and
+//! polars read it without conversion.
+//!
+//! The Flatbuffers metadata is encoded by hand. Objects are laid out parents first, so every
+//! offset points forward as the format requires, and each vtable sits before its table.
+
+use std::io::Write;
+use std::time::Duration;
+
+use crate::lookup::LookupResult;
+use crate::output::{write_error, OutputWriter};
+use crate::time::format_rfc3339;
+use crate::validation::is_nif_valid_local;
+
+/// Results per record batch. Each batch is flushed once full, so readers of a pipe see
+/// results in chunks while a long run goes on.
+pub const BATCH_ROWS: usize = 1024;
+
+const CONTINUATION: [u8; 4] = [0xff; 4];
+const METADATA_V5: i16 = 4;
+const HEADER_SCHEMA: u8 = 1;
+const HEADER_RECORD_BATCH: u8 = 3;
+
+/// One column of the table, with how its values are taken from a result.
+enum Column {
+    Utf8(&'static str, fn(&LookupResult) -> Option<String>),
+    Int32(&'static str, fn(&LookupResult) -> Option<i32>),
+    Float64(&'static str, fn(&LookupResult) -> Option<f64>),
+    Bool(&'static str, fn(&LookupResult) -> bool),
+}
+
+/// The columns, the fields of the JSON result with the entity and report flattened.
+const COLUMNS: &[Column] = &[
+    Column::Utf8("nif", |result| Some(result.nif.clone())),
+    Column::Utf8("status", |result| Some(result.status.label().to_string())),
+    Column::Int32("http_status", |result| result.status.http_status().map(i32::from)),
+    Column::Bool("valid_locally", |result| is_nif_valid_local(&result.nif)),
+    Column::Utf8("source", |result| Some(result.source.label().to_string())),
+    Column::Utf8("name", |result| result.entity.as_ref().map(|entity| entity.name.clone())),
+    Column::Utf8("address", |result| result.entity.as_ref().and_then(|entity| entity.address.clone())),
+    Column::Utf8("postal_code", |result| result.entity.as_ref().and_then(|entity| entity.postal_code.clone())),
+    Column::Utf8("locality", |result| result.entity.as_ref().and_then(|entity| entity.locality.clone())),
+    Column::Utf8("phone", |result| result.entity.as_ref().and_then(|entity| entity.phone.clone())),
+    Column::Utf8("email", |result| result.entity.as_ref().and_then(|entity| entity.email.clone())),
+    Column::Utf8("district", |result| result.entity.as_ref().and_then(|entity| entity.district.clone())),
+    Column::Utf8("municipality", |result| result.entity.as_ref().and_then(|entity| entity.municipality.clone())),
+    Column::Float64("latitude", |result| result.location.map(|location| location.latitude)),
+    Column::Float64("longitude", |result| result.location.map(|location| location.longitude)),
+    Column::Utf8("checked_at", |result| result.checked_at.map(format_rfc3339)),
+    Column::Utf8("confidence", |result| result.confidence.map(|confidence| confidence.label().to_string())),
+    Column::Utf8("tag", |result| result.tag.clone()),
+    Column::Utf8("expected_name", |result| result.name_check.as_ref().map(|check| check.expected.clone())),
+    Column::Float64("name_similarity", |result| result.name_check.as_ref().map(|check| check.similarity)),
+    Column::Bool("name_mismatch", |result| result.name_check.as_ref().is_some_and(|check| !check.matches)),
+    Column::Float64("parse_confidence", |result| result.parse_confidence.as_ref().map(|parsed| parsed.score())),
+    Column::Bool("needs_review", |result| result.parse_confidence.as_ref().is_some_and(|parsed| parsed.needs_review())),
+    Column::Float64("total_ms", |result| Some(millis(result.report.total))),
+    Column::Float64("fetch_ms", |result| Some(millis(result.report.fetch))),
+    Column::Float64("parse_ms", |result| Some(millis(result.report.parse))),
+    Column::Int32("retries", |result| Some(result.report.retries as i32)),
+    Column::Int32("attempts", |result| Some(result.report.attempts as i32)),
+    Column::Utf8("backend", |result| Some(result.report.backend.to_string())),
+    Column::Bool("cache_hit", |result| result.report.cache_hit),
+    Column::Utf8("error", |result| result.report.error.clone()),
+];
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+impl Column {
+    /// The `Field` of the schema. Text and number columns are nullable, booleans never miss
+    /// a value.
+    fn field(&self) -> Node {
+        let (name, type_id, type_fields, nullable) = match self {
+            Column::Utf8(name, _) => (name, 5, vec![], true),
+            Column::Int32(name, _) => (name, 2, vec![(0, Slot::I32(32)), (1, Slot::U8(1))], true),
+            Column::Float64(name, _) => (name, 3, vec![(0, Slot::I16(2))], true),
+            Column::Bool(name, _) => (name, 6, vec![], false),
+        };
+        Node::Table(vec![
+            (0, Slot::Offset(Node::String(name.to_string()))),
+            (1, Slot::U8(u8::from(nullable))),
+            (2, Slot::U8(type_id)),
+            (3, Slot::Offset(Node::Table(type_fields))),
+            (5, Slot::Offset(Node::Tables(Vec::new()))), // Children, required by readers even when empty
+        ])
+    }
+
+    /// Adds the node and the buffers of this column over `rows` to a record batch.
+    fn encode(&self, rows: &[LookupResult], batch: &mut BatchBody) {
+        match self {
+            Column::Utf8(_, get) => {
+                let values: Vec<Option<String>> = rows.iter().map(get).collect();
+                batch.validity(&values.iter().map(Option::is_some).collect::<Vec<_>>());
+                let (mut offsets, mut data) = (vec![0i32], Vec::new());
+                for value in &values {
+                    data.extend_from_slice(value.as_deref().unwrap_or_default().as_bytes());
+                    offsets.push(data.len() as i32);
+                }
+                batch.buffer(&offsets.iter().flat_map(|offset| offset.to_le_bytes()).collect::<Vec<_>>());
+                batch.buffer(&data);
+            }
+            Column::Int32(_, get) => {
+                let values: Vec<Option<i32>> = rows.iter().map(get).collect();
+                batch.validity(&values.iter().map(Option::is_some).collect::<Vec<_>>());
+                batch.buffer(&values.iter().flat_map(|value| value.unwrap_or_default().to_le_bytes()).collect::<Vec<_>>());
+            }
+            Column::Float64(_, get) => {
+                let values: Vec<Option<f64>> = rows.iter().map(get).collect();
+                batch.validity(&values.iter().map(Option::is_some).collect::<Vec<_>>());
+                batch.buffer(&values.iter().flat_map(|value| value.unwrap_or_default().to_le_bytes()).collect::<Vec<_>>());
+            }
+            Column::Bool(_, get) => {
+                batch.validity(&vec![true; rows.len()]);
+                batch.buffer(&bitmap(&rows.iter().map(get).collect::<Vec<_>>()));
+            }
+        }
+    }
+}
+
+/// Nodes, buffers and body of a record batch being encoded.
+#[derive(Default)]
+struct BatchBody {
+    nodes: Vec<u8>,   // FieldNode structs, one per column
+    buffers: Vec<u8>, // Buffer structs, where each buffer sits in `body`
+    body: Vec<u8>,
+}
+
+impl BatchBody {
+    /// Adds the node of a column and its validity bitmap, left empty without nulls.
+    fn validity(&mut self, present: &[bool]) {
+        let nulls = present.iter().filter(|present| !**present).count();
+        self.nodes.extend_from_slice(&(present.len() as i64).to_le_bytes());
+        self.nodes.extend_from_slice(&(nulls as i64).to_le_bytes());
+        self.buffer(&if nulls == 0 { Vec::new() } else { bitmap(present) });
+    }
+
+    /// Appends a buffer to the body, padded to 8 bytes.
+    fn buffer(&mut self, data: &[u8]) {
+        self.buffers.extend_from_slice(&(self.body.len() as i64).to_le_bytes());
+        self.buffers.extend_from_slice(&(data.len() as i64).to_le_bytes());
+        self.body.extend_from_slice(data);
+        self.body.resize(self.body.len().next_multiple_of(8), 0);
+    }
+}
+
+/// Bits in Arrow order: least significant bit first.
+fn bitmap(bits: &[bool]) -> Vec<u8> {
+    let mut bytes = vec![0u8; bits.len().div_ceil(8)];
+    for (index, _) in bits.iter().enumerate().filter(|(_, bit)| **bit) {
+        bytes[index / 8] |= 1 << (index % 8);
+    }
+    bytes
+}
+
+/// The schema message.
+pub fn encode_schema() -> Vec<u8> {
+    let schema = Node::Table(vec![
+        (0, Slot::I16(0)), // Little-endian
+        (1, Slot::Offset(Node::Tables(COLUMNS.iter().map(Column::field).collect()))),
+    ]);
+    encode_message(HEADER_SCHEMA, schema, &[])
+}
+
+/// A record batch message holding `rows`.
+pub fn encode_batch(rows: &[LookupResult]) -> Vec<u8> {
+    let mut batch = BatchBody::default();
+    for column in COLUMNS {
+        column.encode(rows, &mut batch);
+    }
+    let header = Node::Table(vec![
+        (0, Slot::I64(rows.len() as i64)),
+        (1, Slot::Offset(Node::Structs(batch.nodes))),
+        (2, Slot::Offset(Node::Structs(batch.buffers))),
+    ]);
+    encode_message(HEADER_RECORD_BATCH, header, &batch.body)
+}
+
+/// The end-of-stream marker.
+pub fn encode_end() -> Vec<u8> {
+    let mut end = CONTINUATION.to_vec();
+    end.extend_from_slice(&0i32.to_le_bytes());
+    end
+}
+
+/// Frames a message: continuation marker, length of the metadata padded to 8 bytes, the
+/// `Message` flatbuffer, then the body.
+fn encode_message(header_type: u8, header: Node, body: &[u8]) -> Vec<u8> {
+    let message = Node::Table(vec![
+        (0, Slot::I16(METADATA_V5)),
+        (1, Slot::U8(header_type)),
+        (2, Slot::Offset(header)),
+        (3, Slot::I64(body.len() as i64)),
+    ]);
+    let mut metadata = flatbuffer(&message);
+    metadata.resize(metadata.len().next_multiple_of(8), 0);
+    let mut framed = CONTINUATION.to_vec();
+    framed.extend_from_slice(&(metadata.len() as i32).to_le_bytes());
+    framed.extend_from_slice(&metadata);
+    framed.extend_from_slice(body);
+    framed
+}
+
+/// Flatbuffers object.
+enum Node {
+    Table(Vec<(usize, Slot)>), // Fields by slot number, the order of the .fbs declaration
+    Tables(Vec<Node>),         // Vector of tables
+    Structs(Vec<u8>),          // Vector of 16-byte structs (FieldNode, Buffer), as raw bytes
+    String(String),
+}
+
+/// Value of a table field.
+enum Slot {
+    U8(u8), // Also bools and union types
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    Offset(Node),
+}
+
+impl Slot {
+    fn size(&self) -> usize {
+        match self {
+            Slot::U8(_) => 1,
+            Slot::I16(_) => 2,
+            Slot::I32(_) | Slot::Offset(_) => 4,
+            Slot::I64(_) => 8,
+        }
+    }
+}
+
+/// Serializes a root object.
+fn flatbuffer(root: &Node) -> Vec<u8> {
+    let mut buffer = vec![0; 4];
+    let position = write_node(&mut buffer, root);
+    set_offset(&mut buffer, 0, position);
+    buffer
+}
+
+/// Writes an object at the end of `buffer`, its children after it, and returns where it starts.
+fn write_node(buffer: &mut Vec<u8>, node: &Node) -> usize {
+    match node {
+        Node::Table(fields) => write_table(buffer, fields),
+        Node::Tables(tables) => {
+            pad(buffer, 4, 0);
+            let start = buffer.len();
+            buffer.extend_from_slice(&(tables.len() as u32).to_le_bytes());
+            buffer.resize(start + 4 + 4 * tables.len(), 0);
+            for (index, table) in tables.iter().enumerate() {
+                let position = write_node(buffer, table);
+                set_offset(buffer, start + 4 + 4 * index, position);
+            }
+            start
+        }
+        Node::Structs(bytes) => {
+            pad(buffer, 8, 4); // The structs, after the length, are aligned on 8
+            let start = buffer.len();
+            buffer.extend_from_slice(&((bytes.len() / 16) as u32).to_le_bytes());
+            buffer.extend_from_slice(bytes);
+            start
+        }
+        Node::String(text) => {
+            pad(buffer, 4, 0);
+            let start = buffer.len();
+            buffer.extend_from_slice(&(text.len() as u32).to_le_bytes());
+            buffer.extend_from_slice(text.as_bytes());
+            buffer.push(0);
+            start
+        }
+    }
+}
+
+fn write_table(buffer: &mut Vec<u8>, fields: &[(usize, Slot)]) -> usize {
+    // After the vtable offset, the fields from the largest down, each aligned on its size
+    let mut order: Vec<&(usize, Slot)> = fields.iter().collect();
+    order.sort_by_key(|(_, slot)| std::cmp::Reverse(slot.size()));
+    let mut offsets = vec![0u16; fields.iter().map(|(number, _)| number + 1).max().unwrap_or(0)];
+    let mut size: usize = 4;
+    for (number, slot) in &order {
+        size = size.next_multiple_of(slot.size());
+        offsets[*number] = size as u16;
+        size += slot.size();
+    }
+    let align = order.first().map_or(4, |(_, slot)| slot.size().max(4));
+
+    pad(buffer, 2, 0);
+    let vtable = buffer.len();
+    buffer.extend_from_slice(&((4 + 2 * offsets.len()) as u16).to_le_bytes());
+    buffer.extend_from_slice(&(size as u16).to_le_bytes());
+    for offset in &offsets {
+        buffer.extend_from_slice(&offset.to_le_bytes());
+    }
+    pad(buffer, align, 0);
+    let table = buffer.len();
+    buffer.resize(table + size, 0);
+    buffer[table..table + 4].copy_from_slice(&((table - vtable) as i32).to_le_bytes());
+    for (number, slot) in fields {
+        let at = table + usize::from(offsets[*number]);
+        match slot {
+            Slot::U8(value) => buffer[at] = *value,
+            Slot::I16(value) => buffer[at..at + 2].copy_from_slice(&value.to_le_bytes()),
+            Slot::I32(value) => buffer[at..at + 4].copy_from_slice(&value.to_le_bytes()),
+            Slot::I64(value) => buffer[at..at + 8].copy_from_slice(&value.to_le_bytes()),
+            Slot::Offset(_) => {} // Set once the child is written
+        }
+    }
+    for (number, slot) in fields {
+        if let Slot::Offset(child) = slot {
+            let position = write_node(buffer, child);
+            set_offset(buffer, table + usize::from(offsets[*number]), position);
+        }
+    }
+    table
+}
+
+/// Pads with zeros until the length is `remainder` modulo `align`.
+fn pad(buffer: &mut Vec<u8>, align: usize, remainder: usize) {
+    while buffer.len() % align != remainder {
+        buffer.push(0);
+    }
+}
+
+/// Sets the offset stored at `at` to point at `target`, further in the buffer.
+fn set_offset(buffer: &mut [u8], at: usize, target: usize) {
+    buffer[at..at + 4].copy_from_slice(&((target - at) as u32).to_le_bytes());
+}
+
+/// Writes results as an Arrow IPC stream.
+pub struct ArrowWriter<W> {
+    out: W,
+    rows: Vec<LookupResult>, // Results of the batch being filled
+    started: bool,           // Whether the schema was written
+}
+
+impl<W: Write> ArrowWriter<W> {
+    pub fn new(out: W) -> Self {
+        ArrowWriter { out, rows: Vec::new(), started: false }
+    }
+
+    fn send(&mut self, data: &[u8]) -> Result<(), String> {
+        self.out
+            .write_all(data)
+            .and_then(|_| self.out.flush())
+            .map_err(write_error)
+    }
+
+    fn start(&mut self) -> Result<(), String> {
+        if !self.started {
+            self.started = true;
+            self.send(&encode_schema())?;
+        }
+        Ok(())
+    }
+
+    fn send_batch(&mut self) -> Result<(), String> {
+        let batch = encode_batch(&self.rows);
+        self.rows.clear();
+        self.send(&batch)
+    }
+}
+
+impl<W: Write> OutputWriter for ArrowWriter<W> {
+    fn write(&mut self, result: &LookupResult) -> Result<(), String> {
+        self.start()?;
+        self.rows.push(result.clone());
+        if self.rows.len() >= BATCH_ROWS {
+            self.send_batch()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        self.start()?;
+        if !self.rows.is_empty() {
+            self.send_batch()?;
+        }
+        self.send(&encode_end())
+    }
+}
@@ -0,0 +1,109 @@
+// auth.rs
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::http::Request;
+
+/// Environment variable holding API keys, separated by commas.
+pub const API_KEYS_ENV: &str = "CHECK_NIF_API_KEYS";
+
+/// API keys accepted by the server, with a request counter per key.
+///
+/// Keys are written `NAME:KEY` (or a bare `KEY`, then named after its position);
+/// only names ever reach logs and statistics. Keys are compared through their
+/// SHA-256 digest, so the comparison time tells nothing about the expected key.
+#[derive(Debug, Default)]
+pub struct ApiKeys {
+    keys: Vec<(String, [u8; 32])>, // Name and digest of each key
+    usage: Mutex<BTreeMap<String, u64>>,
+}
+
+impl ApiKeys {
+    /// Parses keys, one entry per item of `entries`; blank entries and `#` comments are skipped.
+    pub fn parse<'a>(entries: impl IntoIterator<Item = &'a str>) -> Result<Self, String> {
+        let mut keys = ApiKeys::default();
+        for entry in entries {
+            let entry = entry.trim();
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+            let (name, key) = match entry.split_once(':') {
+                Some((name, key)) => (name.trim().to_string(), key.trim()),
+                None => (format!("key{}", keys.keys.len() + 1), entry),
+            };
+            if key.len() < 16 {
+                return Err(format!("API key '{}' is too short, use at least 16 characters", name));
+            }
+            if keys.keys.iter().any(|(existing, _)| *existing == name) {
+                return Err(format!("API key name '{}' is used twice", name));
+            }
+            keys.keys.push((name, digest(key)));
+        }
+        Ok(keys)
+    }
+
+    /// Loads keys from a file, one per line.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        Self::parse(text.lines()).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Loads keys from `CHECK_NIF_API_KEYS`, when set.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        match std::env::var(API_KEYS_ENV) {
+            Ok(value) => Self::parse(value.split(',')).map(Some).map_err(|e| format!("{}: {}", API_KEYS_ENV, e)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Adds the keys of `other`.
+    pub fn extend(&mut self, other: ApiKeys) -> Result<(), String> {
+        for (name, digest) in other.keys {
+            if self.keys.iter().any(|(existing, _)| *existing == name) {
+                return Err(format!("API key name '{}' is used twice", name));
+            }
+            self.keys.push((name, digest));
+        }
+        Ok(())
+    }
+
+    /// Number of configured keys.
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Tells whether no key is configured.
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Returns the name of the key presented by `request`, counting the request against it.
+    ///
+    /// The key is read from `X-Api-Key`, or from `Authorization: Bearer <key>`.
+    pub fn authenticate(&self, request: &Request) -> Option<String> {
+        let presented = request.header("x-api-key").or_else(|| {
+            request
+                .header("authorization")
+                .and_then(|value| value.strip_prefix("Bearer ").or_else(|| value.strip_prefix("bearer ")))
+        })?;
+        let presented = digest(presented.trim());
+        let (name, _) = self.keys.iter().find(|(_, expected)| *expected == presented)?;
+        *self.usage.lock().unwrap().entry(name.clone()).or_default() += 1;
+        Some(name.clone())
+    }
+
+    /// Requests counted per key name since the server started.
+    pub fn usage(&self) -> BTreeMap<String, u64> {
+        let mut usage = self.usage.lock().unwrap().clone();
+        for (name, _) in &self.keys {
+            usage.entry(name.clone()).or_default();
+        }
+        usage
+    }
+}
+
+fn digest(key: &str) -> [u8; 32] {
+    let digest = ring::digest::digest(&ring::digest::SHA256, key.as_bytes());
+    digest.as_ref().try_into().unwrap()
+}
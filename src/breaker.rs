@@ -0,0 +1,179 @@
+// breaker.rs
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::logging;
+use crate::status::NifStatus;
+
+/// State of the circuit breaker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,   // Remote calls go through normally
+    Open,     // Remote calls are skipped until the cool-down is over
+    HalfOpen, // One probe call is in flight to check whether nif.pt recovered
+}
+
+impl BreakerState {
+    /// Name of the state, as shown by the server dashboard.
+    pub fn label(&self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half_open",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: BreakerState,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+}
+
+/// Stops hammering nif.pt once it keeps failing or rate-limiting us.
+///
+/// After `failure_threshold` consecutive failures the breaker opens and every call is
+/// refused for `cool_down`. The first call after that is let through as a probe: a
+/// success closes the breaker again, a failure re-opens it for another cool-down.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cool_down: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cool_down: Duration) -> Self {
+        CircuitBreaker {
+            failure_threshold: failure_threshold.max(1),
+            cool_down,
+            inner: Mutex::new(Inner {
+                state: BreakerState::Closed,
+                consecutive_failures: 0,
+                opened_at: None,
+            }),
+        }
+    }
+
+    /// Returns the current state, without changing it.
+    pub fn state(&self) -> BreakerState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// Tells whether a remote call may be made now.
+    ///
+    /// When the cool-down is over this moves the breaker to half-open and grants a
+    /// single probe; concurrent callers keep being refused until the probe reports back.
+    pub fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            BreakerState::Closed => true,
+            BreakerState::HalfOpen => false,
+            BreakerState::Open => {
+                let cooled_down = inner
+                    .opened_at
+                    .is_none_or(|opened| opened.elapsed() >= self.cool_down);
+                if cooled_down {
+                    inner.state = BreakerState::HalfOpen;
+                }
+                cooled_down
+            }
+        }
+    }
+
+    /// Feeds the outcome of a remote call back into the breaker.
+    pub fn record(&self, status: &NifStatus) {
+        let mut inner = self.inner.lock().unwrap();
+        if is_failure(status) {
+            inner.consecutive_failures += 1;
+            let reopen = inner.state == BreakerState::HalfOpen;
+            if reopen || inner.consecutive_failures >= self.failure_threshold {
+                if inner.state != BreakerState::Open {
+                    logging::warn(
+                        "circuit_opened",
+                        &[
+                            ("failures", inner.consecutive_failures.into()),
+                            ("cool_down_secs", (self.cool_down.as_secs() as i64).into()),
+                        ],
+                        format!(
+                            "Circuit breaker open after {} failure(s), skipping remote lookups for {}s",
+                            inner.consecutive_failures,
+                            self.cool_down.as_secs()
+                        ),
+                    );
+                }
+                inner.state = BreakerState::Open;
+                inner.opened_at = Some(Instant::now());
+            }
+        } else {
+            if inner.state != BreakerState::Closed {
+                logging::info(
+                    "circuit_closed",
+                    &[],
+                    "Circuit breaker closed, nif.pt is answering again".to_string(),
+                );
+            }
+            inner.state = BreakerState::Closed;
+            inner.consecutive_failures = 0;
+            inner.opened_at = None;
+        }
+    }
+}
+
+/// Outcomes that say something about nif.pt's health rather than about the NIF.
+fn is_failure(status: &NifStatus) -> bool {
+    status.is_retryable() || matches!(status, NifStatus::Unknown | NifStatus::UnsupportedLayout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+        breaker.record(&NifStatus::HttpError(503));
+        breaker.record(&NifStatus::Unknown);
+        breaker.record(&NifStatus::ValidUnknown); // An answer, even without an entity
+        breaker.record(&NifStatus::HttpError(429));
+        breaker.record(&NifStatus::UnsupportedLayout);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.allow());
+        breaker.record(&NifStatus::HttpError(500));
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(!breaker.allow());
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn one_probe_after_the_cool_down() {
+        let breaker = CircuitBreaker::new(1, Duration::ZERO);
+        breaker.record(&NifStatus::HttpError(503));
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(breaker.allow());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(!breaker.allow()); // Until the probe reports back
+        breaker.record(&NifStatus::Unknown);
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(breaker.allow());
+        breaker.record(&NifStatus::ValidKnown);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn half_open_failure_reopens_below_the_threshold() {
+        let breaker = CircuitBreaker::new(5, Duration::from_millis(20));
+        for _ in 0..5 {
+            breaker.record(&NifStatus::HttpError(502));
+        }
+        assert!(!breaker.allow());
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(breaker.allow());
+        breaker.record(&NifStatus::HttpError(502));
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(!breaker.allow()); // A new cool-down
+    }
+}
@@ -0,0 +1,327 @@
+// cache.rs
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::entity::NifEntity;
+use crate::json::JsonValue;
+use crate::logging;
+use crate::lookup::LookupSource;
+use crate::redis_cache::RedisCache;
+use crate::status::NifStatus;
+
+/// How long a cached answer is trusted when nothing else is configured.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(30 * 86_400);
+
+/// A lookup result remembered by a cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheEntry {
+    pub status: NifStatus,
+    pub fetched_at: SystemTime,
+    pub entity: Option<NifEntity>, // Details parsed from the page, for known entities
+}
+
+impl CacheEntry {
+    /// Creates an entry for a result fetched just now.
+    pub fn now(status: NifStatus, entity: Option<NifEntity>) -> Self {
+        CacheEntry {
+            status,
+            fetched_at: SystemTime::now(),
+            entity,
+        }
+    }
+
+    /// Age of the entry; zero if the clock went backwards.
+    pub fn age(&self) -> Duration {
+        self.fetched_at.elapsed().unwrap_or_default()
+    }
+
+    /// Serializes the entry as `status<TAB>unix_seconds`, followed by `<TAB>entity_json`
+    /// when the entity is known. JSON escapes tabs and line breaks, so the line stays whole.
+    pub fn encode(&self) -> String {
+        let secs = self.fetched_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        match &self.entity {
+            Some(entity) => format!("{}\t{}\t{}", self.status.label(), secs, entity.to_json()),
+            None => format!("{}\t{}", self.status.label(), secs),
+        }
+    }
+
+    /// Parses the output of `encode`.
+    pub fn decode(value: &str) -> Option<Self> {
+        let mut parts = value.splitn(3, '\t');
+        let status = NifStatus::from_label(parts.next()?)?;
+        let secs = parts.next()?.trim().parse().ok()?;
+        let entity = parts
+            .next()
+            .and_then(|json| JsonValue::parse(json).ok())
+            .and_then(|json| NifEntity::from_json(&json));
+        Some(CacheEntry {
+            status,
+            fetched_at: UNIX_EPOCH + Duration::from_secs(secs),
+            entity,
+        })
+    }
+}
+
+/// Tells whether an answer goes into the cache. Only real answers of nif.pt are cached:
+/// failures must be retried next time, and fallback answers only stand in until nif.pt
+/// answers again.
+pub fn is_cacheable(status: &NifStatus, source: LookupSource) -> bool {
+    status.is_definitive() && source == LookupSource::Remote
+}
+
+/// Storage for lookup results, shared by every lookup made with the same options.
+///
+/// Implementations must never fail a lookup: backend errors are logged and the call
+/// then behaves as a miss (or a dropped write).
+pub trait Cache: Send + Sync {
+    /// Returns the entry for `nif` when present and younger than the cache TTL.
+    fn get(&self, nif: &str) -> Option<CacheEntry>;
+
+    /// Stores (or replaces) the entry for `nif`.
+    fn put(&self, nif: &str, entry: CacheEntry);
+
+    /// Lists every stored entry, expired ones included, for administration commands.
+    fn entries(&self) -> Result<Vec<(String, CacheEntry)>, String>;
+
+    /// Deletes the entries of the given NIFs and returns how many existed.
+    fn remove(&self, nifs: &[String]) -> Result<usize, String>;
+
+    /// Deletes every entry and returns how many there were.
+    fn clear(&self) -> Result<usize, String> {
+        let nifs: Vec<String> = self.entries()?.into_iter().map(|(nif, _)| nif).collect();
+        self.remove(&nifs)
+    }
+
+    /// Bytes used by the cache on disk, when the backend can tell.
+    fn size_on_disk(&self) -> Option<u64> {
+        None
+    }
+
+    /// Human-readable description of where the cache lives.
+    fn location(&self) -> String;
+}
+
+/// Opens the cache described by `location`: a `redis://` URL or a file path.
+pub fn open_cache(location: &str, ttl: Duration) -> Result<Arc<dyn Cache>, String> {
+    if location.starts_with("redis://") {
+        Ok(Arc::new(RedisCache::connect(location, ttl)?))
+    } else {
+        Ok(Arc::new(FileCache::open(location, ttl)?))
+    }
+}
+
+/// Default location of the file cache: `$XDG_CACHE_HOME/check_nif/lookups.tsv`,
+/// falling back to `~/.cache/check_nif/lookups.tsv`.
+pub fn default_cache_path() -> PathBuf {
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir);
+    base.join("check_nif").join("lookups.tsv")
+}
+
+/// Cache persisted in a local append-only file, one `nif<TAB>status<TAB>unix_seconds[<TAB>entity]`
+/// line per stored result. The last line for a NIF wins; everything is loaded in memory on open.
+#[derive(Debug)]
+pub struct FileCache {
+    path: PathBuf,
+    ttl: Duration,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl FileCache {
+    /// Opens (or creates) the cache file at `path`.
+    pub fn open(path: impl AsRef<Path>, ttl: Duration) -> Result<Self, String> {
+        let path = path.as_ref().to_path_buf();
+        let entries = Self::load(&path)?;
+        Ok(FileCache {
+            path,
+            ttl,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// Path of the backing file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads the file into a map, the last line of each NIF winning.
+    fn load(path: &Path) -> Result<HashMap<String, CacheEntry>, String> {
+        let mut entries = HashMap::new();
+        match File::open(path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line.map_err(|e| format!("cannot read cache {}: {}", path.display(), e))?;
+                    // Skip lines that do not parse, e.g. a write cut short by a crash
+                    if let Some((nif, value)) = line.split_once('\t')
+                        && let Some(entry) = CacheEntry::decode(value)
+                    {
+                        entries.insert(nif.to_string(), entry);
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("cannot open cache {}: {}", path.display(), e)),
+        }
+        Ok(entries)
+    }
+
+    /// Locks the cache against the other processes writing it, until the file returned is
+    /// dropped. As for the store, the lock is a separate file, `lookups.tsv.lock` next to
+    /// `lookups.tsv`, since `rewrite` replaces the cache file itself.
+    fn lock(&self) -> std::io::Result<File> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut lock_path = self.path.clone().into_os_string();
+        lock_path.push(".lock");
+        let file = OpenOptions::new().write(true).create(true).truncate(false).open(lock_path)?;
+        file.lock()?;
+        Ok(file)
+    }
+
+    /// Rewrites the file with exactly the given entries, dropping superseded lines; the
+    /// caller holds the lock.
+    fn rewrite(&self, entries: &HashMap<String, CacheEntry>) -> Result<(), String> {
+        let write = || -> std::io::Result<()> {
+            if entries.is_empty() {
+                return match fs::remove_file(&self.path) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+                    _ => Ok(()),
+                };
+            }
+            // Write next to the original and rename, so a crash never leaves half a file
+            let tmp = self.path.with_extension("tmp");
+            let mut file = File::create(&tmp)?;
+            let text: String = entries.iter().map(|(nif, entry)| line(nif, entry)).collect();
+            file.write_all(text.as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&tmp, &self.path)
+        };
+        write().map_err(|e| format!("cannot rewrite cache {}: {}", self.path.display(), e))
+    }
+
+    fn append(&self, nif: &str, entry: &CacheEntry) -> std::io::Result<()> {
+        // Opened under the lock, so not a file that a rewrite is replacing
+        let _lock = self.lock()?;
+        let mut file = OpenOptions::new().create(true).read(true).append(true).open(&self.path)?;
+        // A write cut short by a crash left a line without its newline: end it, so that the
+        // damage stays in that line
+        let mut last = [b'\n'];
+        if file.seek(SeekFrom::End(-1)).is_ok() {
+            file.read_exact(&mut last)?;
+        }
+        let mut text = if last[0] == b'\n' { String::new() } else { "\n".to_string() };
+        text.push_str(&line(nif, entry));
+        // A single write, so that lines appended by other processes never interleave
+        file.write_all(text.as_bytes())
+    }
+}
+
+/// Line of the cache file holding `entry`, with its newline.
+fn line(nif: &str, entry: &CacheEntry) -> String {
+    format!("{}\t{}\n", nif, entry.encode())
+}
+
+impl Cache for FileCache {
+    fn get(&self, nif: &str) -> Option<CacheEntry> {
+        let entries = self.entries.lock().unwrap();
+        entries.get(nif).cloned().filter(|entry| entry.age() < self.ttl)
+    }
+
+    fn put(&self, nif: &str, entry: CacheEntry) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Err(e) = self.append(nif, &entry) {
+            logging::warn(
+                "cache_write_failed",
+                &[("error", e.to_string().into())],
+                format!("Error writing cache {}: {}", self.path.display(), e),
+            );
+        }
+        entries.insert(nif.to_string(), entry);
+    }
+
+    fn entries(&self) -> Result<Vec<(String, CacheEntry)>, String> {
+        let entries = self.entries.lock().unwrap();
+        Ok(entries.iter().map(|(nif, entry)| (nif.clone(), entry.clone())).collect())
+    }
+
+    fn remove(&self, nifs: &[String]) -> Result<usize, String> {
+        let mut entries = self.entries.lock().unwrap();
+        let _lock = self.lock().map_err(|e| format!("cannot lock cache {}: {}", self.path.display(), e))?;
+        // Pick up whatever other processes appended since we loaded the file
+        *entries = Self::load(&self.path)?;
+        let removed = nifs.iter().filter(|nif| entries.remove(*nif).is_some()).count();
+        self.rewrite(&entries)?;
+        Ok(removed)
+    }
+
+    fn size_on_disk(&self) -> Option<u64> {
+        Some(fs::metadata(&self.path).map(|meta| meta.len()).unwrap_or(0))
+    }
+
+    fn location(&self) -> String {
+        self.path.display().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Path of a cache file in a directory of its own, removed first.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("check_nif-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("lookups.tsv")
+    }
+
+    #[test]
+    fn concurrent_writers_keep_whole_lines() {
+        let path = scratch("concurrent");
+        // One cache per thread, as separate processes would open it
+        let writers: Vec<_> = (0..8)
+            .map(|writer| {
+                let cache = FileCache::open(&path, DEFAULT_TTL).unwrap();
+                std::thread::spawn(move || {
+                    for i in 0..50 {
+                        cache.put(&format!("{}{:08}", writer, i), CacheEntry::now(NifStatus::ValidUnknown, None));
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(FileCache::open(&path, DEFAULT_TTL).unwrap().entries().unwrap().len(), 400);
+    }
+
+    #[test]
+    fn remove_keeps_lines_of_other_writers() {
+        let path = scratch("remove");
+        let first = FileCache::open(&path, DEFAULT_TTL).unwrap();
+        let second = FileCache::open(&path, DEFAULT_TTL).unwrap();
+        first.put("500960046", CacheEntry::now(NifStatus::ValidUnknown, None));
+        second.put("509442013", CacheEntry::now(NifStatus::ValidUnknown, None));
+        assert_eq!(first.remove(&["500960046".to_string()]), Ok(1));
+        let nifs: Vec<String> = FileCache::open(&path, DEFAULT_TTL).unwrap().entries().unwrap().into_iter().map(|(nif, _)| nif).collect();
+        assert_eq!(nifs, ["509442013"]);
+    }
+
+    #[test]
+    fn line_cut_short_is_ended() {
+        let path = scratch("cut-short");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "500960046\tvalid_unk").unwrap();
+        FileCache::open(&path, DEFAULT_TTL).unwrap().put("509442013", CacheEntry::now(NifStatus::ValidUnknown, None));
+        let entries = FileCache::open(&path, DEFAULT_TTL).unwrap().entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0, "509442013");
+    }
+}
@@ -0,0 +1,63 @@
+// cancel.rs
+
+//! Cancellation of lookups by the application embedding the library, e.g. when the request
+//! that needed the answer went away or the application shuts down.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Set once by `cancel` and shared by its clones; lookups made with it stop at the next step
+/// (a wait for the rate limit, a retry delay, the next site to ask) and get
+/// `NifStatus::Cancelled`. A request already sent is not cut short, it ends within its timeout.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels the lookups using the token, waking those sleeping in `sleep`.
+    pub fn cancel(&self) {
+        let (cancelled, woken) = &*self.inner;
+        *cancelled.lock().unwrap() = true;
+        woken.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.inner.0.lock().unwrap()
+    }
+
+    /// Sleeps for `duration`, or until `cancel`. Returns false if cancelled.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let (cancelled, woken) = &*self.inner;
+        let until = Instant::now() + duration;
+        let mut cancelled = cancelled.lock().unwrap();
+        while !*cancelled {
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return true;
+            }
+            cancelled = woken.wait_timeout(cancelled, left).unwrap().0;
+        }
+        false
+    }
+}
+
+/// Sleeps for `duration`, cut short by `cancel` when there is one. Returns false if cancelled.
+pub(crate) fn sleep(duration: Duration, cancel: Option<&CancellationToken>) -> bool {
+    match cancel {
+        Some(cancel) => cancel.sleep(duration),
+        None => {
+            std::thread::sleep(duration);
+            true
+        }
+    }
+}
+
+/// Tells whether `cancel` is set and was cancelled.
+pub(crate) fn is_cancelled(cancel: Option<&CancellationToken>) -> bool {
+    cancel.is_some_and(CancellationToken::is_cancelled)
+}
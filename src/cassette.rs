@@ -0,0 +1,201 @@
+// cassette.rs
+
+//! Record and replay of the HTTP exchanges of lookups, so the whole lookup pipeline can run
+//! in tests and CI without reaching the sites.
+//!
+//! A cassette holds one JSON object per line, `{"method", "url", "status", "body"}`, in the
+//! order the requests were made. Replaying answers each URL with its recorded answers in
+//! turn, the last one again once they are used up.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::json::JsonValue;
+
+/// One recorded request and the answer it got.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interaction {
+    pub method: String,
+    pub url: String,
+    pub status: u16, // HTTP status of the answer
+    pub body: String,
+}
+
+impl Interaction {
+    fn to_json(&self) -> JsonValue {
+        JsonValue::object()
+            .with("method", self.method.as_str())
+            .with("url", self.url.as_str())
+            .with("status", i64::from(self.status))
+            .with("body", self.body.as_str())
+    }
+
+    fn from_json(json: &JsonValue) -> Option<Self> {
+        Some(Interaction {
+            method: json.str_field("method").unwrap_or("GET").to_string(),
+            url: json.str_field("url")?.to_string(),
+            status: u16::try_from(json.get("status")?.as_i64()?).ok()?,
+            body: json.str_field("body").unwrap_or_default().to_string(),
+        })
+    }
+}
+
+/// Whether a cassette is being written or played back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CassetteMode {
+    Record, // Requests go out, and are appended to the cassette with their answers
+    Replay, // Requests are answered from the cassette, nothing goes out
+}
+
+impl CassetteMode {
+    /// Parses a `--cassette-mode` value.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "record" => Ok(CassetteMode::Record),
+            "replay" => Ok(CassetteMode::Replay),
+            other => Err(format!("unknown cassette mode '{}', expected record or replay", other)),
+        }
+    }
+}
+
+/// A cassette file, shared by every lookup made with the same options.
+#[derive(Debug)]
+pub struct Cassette {
+    path: PathBuf,
+    mode: CassetteMode,
+    file: Mutex<Option<File>>,                   // Open for appending, when recording
+    recorded: HashMap<String, Vec<Interaction>>, // Answers by "METHOD URL", when replaying
+    played: Mutex<HashMap<String, usize>>,       // Answers already given, by "METHOD URL"
+}
+
+impl Cassette {
+    /// Starts recording to `path`, replacing what it held.
+    pub fn record(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+        }
+        let file = File::create(&path).map_err(|e| format!("cannot create cassette {}: {}", path.display(), e))?;
+        Ok(Cassette {
+            path,
+            mode: CassetteMode::Record,
+            file: Mutex::new(Some(file)),
+            recorded: HashMap::new(),
+            played: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Loads the cassette at `path` for replaying.
+    pub fn replay(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref().to_path_buf();
+        let text = fs::read_to_string(&path).map_err(|e| format!("cannot read cassette {}: {}", path.display(), e))?;
+        let mut recorded: HashMap<String, Vec<Interaction>> = HashMap::new();
+        for (index, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            let interaction = JsonValue::parse(line)
+                .ok()
+                .and_then(|json| Interaction::from_json(&json))
+                .ok_or_else(|| format!("{}:{}: not a recorded interaction", path.display(), index + 1))?;
+            recorded.entry(key(&interaction.method, &interaction.url)).or_default().push(interaction);
+        }
+        Ok(Cassette {
+            path,
+            mode: CassetteMode::Replay,
+            file: Mutex::new(None),
+            recorded,
+            played: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Opens `path` in `mode`.
+    pub fn open(path: impl AsRef<Path>, mode: CassetteMode) -> Result<Self, String> {
+        match mode {
+            CassetteMode::Record => Cassette::record(path),
+            CassetteMode::Replay => Cassette::replay(path),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn mode(&self) -> CassetteMode {
+        self.mode
+    }
+
+    /// Next recorded answer to `method url`, or `None` when the cassette has none: the
+    /// request was never made while recording.
+    pub fn play(&self, method: &str, url: &str) -> Option<Interaction> {
+        let key = key(method, url);
+        let answers = self.recorded.get(&key)?;
+        let mut played = self.played.lock().unwrap();
+        let count = played.entry(key).or_insert(0);
+        let interaction = answers[(*count).min(answers.len() - 1)].clone();
+        *count += 1;
+        Some(interaction)
+    }
+
+    /// Appends an exchange to the cassette; a no-op unless recording.
+    pub fn add(&self, interaction: &Interaction) -> Result<(), String> {
+        let mut file = self.file.lock().unwrap();
+        let Some(file) = file.as_mut() else {
+            return Ok(());
+        };
+        writeln!(file, "{}", interaction.to_json()).map_err(|e| format!("cannot write {}: {}", self.path.display(), e))
+    }
+}
+
+fn key(method: &str, url: &str) -> String {
+    format!("{} {}", method, url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interaction(url: &str, status: u16, body: &str) -> Interaction {
+        Interaction {
+            method: "GET".to_string(),
+            url: url.to_string(),
+            status,
+            body: body.to_string(),
+        }
+    }
+
+    #[test]
+    fn record_then_replay() {
+        let path = std::env::temp_dir().join(format!("check_nif-cassette-{}", std::process::id())).join("lookups.ndjson");
+        let cassette = Cassette::record(&path).unwrap();
+        let answers = [
+            interaction("https://www.nif.pt/?q=500960046", 503, ""),
+            interaction("https://www.nif.pt/?q=500960046", 200, "<html>\n\"quoted\"</html>"),
+            interaction("https://www.nif.pt/?q=123456789", 200, "other"),
+        ];
+        for answer in &answers {
+            cassette.add(answer).unwrap();
+        }
+        drop(cassette);
+
+        let cassette = Cassette::replay(&path).unwrap();
+        assert_eq!(cassette.mode(), CassetteMode::Replay);
+        assert_eq!(cassette.play("GET", &answers[0].url), Some(answers[0].clone()));
+        assert_eq!(cassette.play("GET", &answers[2].url), Some(answers[2].clone()));
+        assert_eq!(cassette.play("GET", &answers[0].url), Some(answers[1].clone()));
+        assert_eq!(cassette.play("GET", &answers[0].url), Some(answers[1].clone()));
+        assert_eq!(cassette.play("POST", &answers[0].url), None);
+        assert_eq!(cassette.play("GET", "https://www.nif.pt/?q=501442600"), None);
+        // Replaying writes nothing
+        cassette.add(&answers[2]).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+    }
+
+    #[test]
+    fn damaged_cassette() {
+        let path = std::env::temp_dir().join(format!("check_nif-cassette-damaged-{}.ndjson", std::process::id()));
+        fs::write(&path, "{\"url\": \"https://www.nif.pt/?q=1\", \"status\": 200}\n\nnot json\n").unwrap();
+        let error = Cassette::replay(&path).unwrap_err();
+        assert_eq!(error, format!("{}:3: not a recorded interaction", path.display()));
+    }
+}
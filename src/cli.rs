@@ -0,0 +1,1603 @@
+// cli.rs
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use check_nif::breaker::CircuitBreaker;
+use check_nif::cache::{self, Cache};
+use check_nif::cassette::{Cassette, CassetteMode};
+use check_nif::config::{self, ConfigFile, ConfigValue};
+use check_nif::dns::NameServerResolver;
+use check_nif::health::BackendHealth;
+use check_nif::fallback::Fallback;
+use check_nif::hooks::{CommandHook, Hooks, DEFAULT_HOOK_TIMEOUT};
+use check_nif::input::{read_expected_names, read_failures, read_nif_list};
+use check_nif::lang::{self, Lang};
+use check_nif::logging::{self, LogFormat, NifPrivacy};
+use check_nif::lookup::{parse_resolve, ConnectionLimit, PoolOptions, TimeoutOptions};
+use check_nif::mail::{SmtpConfig, SMTP_URL_ENV};
+use check_nif::names::{ExpectedNames, DEFAULT_MIN_SIMILARITY};
+use check_nif::output::{
+    FilterWriter, GroupedTextWriter, NifFormat, OutputFormat, OutputWriter, SortKey, SortWriter, StatusFilter, VatNifWriter,
+};
+use check_nif::page_cache::PageCache;
+use check_nif::postal::PostalTable;
+use check_nif::ratelimit::{self, JobRate, Throttle};
+use check_nif::request_lock::RequestLock;
+#[cfg(feature = "geocode")]
+use check_nif::geocode::{Geocoder, NOMINATIM_RATE, NOMINATIM_URL};
+#[cfg(feature = "kafka")]
+use check_nif::kafka::KafkaProducer;
+#[cfg(feature = "otlp")]
+use check_nif::otlp::OtlpExporter;
+use check_nif::statsd::StatsdClient;
+use check_nif::progress::{ProgressFormat, ProgressWriter, DEFAULT_PROGRESS_INTERVAL};
+use check_nif::store::{self, ResultStore, RetentionPolicy, Store, StoreKey};
+use check_nif::time::{parse_date, parse_duration};
+use check_nif::tls;
+use check_nif::validation::{normalize_nif, NifCategory};
+#[cfg(feature = "wasm")]
+use check_nif::wasm::WasmHook;
+use check_nif::LookupOptions;
+
+/// Describes one `--long` command line option.
+pub struct OptSpec {
+    pub long: &'static str,          // Name without the leading dashes
+    pub value: Option<&'static str>, // Placeholder of the value, `None` for plain switches
+    pub help: &'static str,          // One-line description shown in the usage text
+}
+
+/// Options accepted by every command.
+pub const LOG_OPTIONS: &[OptSpec] = &[
+    OptSpec {
+        long: "log-format",
+        value: Some("text|json"),
+        help: "Format of diagnostic messages; json writes one object per line to stderr",
+    },
+    OptSpec {
+        long: "nif-privacy",
+        value: Some("clear|hash|mask"),
+        help: "How NIFs appear in logs and traces (default: clear in text logs, hash in json)",
+    },
+    OptSpec {
+        long: "nif-hash-key",
+        value: Some("FILE"),
+        help: "Secret key of the NIF hashes, so they match across runs (default: CHECK_NIF_HASH_KEY, else a key of the run)",
+    },
+    OptSpec {
+        long: "lang",
+        value: Some("pt|en"),
+        help: "Language of the text output, status titles and reports (default: en); logs stay in English",
+    },
+];
+
+/// Options controlling how nif.pt is reached, for commands doing remote lookups.
+pub const NETWORK_OPTIONS: &[OptSpec] = &[
+    OptSpec {
+        long: "config",
+        value: Some("FILE"),
+        help: "Config file with per-backend settings (default: CHECK_NIF_CONFIG, ~/.config/check_nif/config.toml)",
+    },
+    OptSpec {
+        long: "profile",
+        value: Some("NAME"),
+        help: "Take the options of [profile.NAME] in the config file, e.g. bulk; those on the command line win",
+    },
+    OptSpec {
+        long: "resolve",
+        value: Some("HOST:IP"),
+        help: "Connect to IP whenever HOST is requested (repeatable)",
+    },
+    OptSpec {
+        long: "dns-server",
+        value: Some("IP[:PORT]"),
+        help: "Resolve host names through this DNS server instead of the system resolver",
+    },
+    OptSpec {
+        long: "ca-bundle",
+        value: Some("FILE"),
+        help: "Trust the CA certificates in this PEM file in addition to the system ones",
+    },
+    OptSpec {
+        long: "insecure",
+        value: None,
+        help: "Do not validate TLS certificates (for TLS-intercepting proxies only)",
+    },
+    OptSpec {
+        long: "pin-sha256",
+        value: Some("FINGERPRINT"),
+        help: "Only accept a server certificate with this SHA-256 fingerprint (repeatable)",
+    },
+    OptSpec {
+        long: "rate-limit",
+        value: Some("PER_MINUTE"),
+        help: "Send at most this many requests a minute to nif.pt, shared with other runs (default: no limit)",
+    },
+    OptSpec {
+        long: "rate-state",
+        value: Some("FILE"),
+        help: "File sharing --rate-limit between runs (default: a file in the user cache directory)",
+    },
+    OptSpec {
+        long: "request-lock",
+        value: Some("FILE|REDIS_URL"),
+        help: "Send remote requests one at a time with every run sharing this lock file or redis:// key",
+    },
+    OptSpec {
+        long: "max-connections",
+        value: Some("N"),
+        help: "Open at most N connections to nif.pt at once (default: no limit)",
+    },
+    OptSpec {
+        long: "pool-max-idle",
+        value: Some("N"),
+        help: "Keep at most N idle connections to nif.pt for reuse (default: no limit)",
+    },
+    OptSpec {
+        long: "pool-idle-timeout",
+        value: Some("DURATION"),
+        help: "Close idle connections after this long, e.g. 30s (default 90s)",
+    },
+    OptSpec {
+        long: "connect-timeout",
+        value: Some("DURATION"),
+        help: "Give up connecting to a site after this long, e.g. 5s (default: no limit)",
+    },
+    OptSpec {
+        long: "read-timeout",
+        value: Some("DURATION"),
+        help: "Give up waiting for a response, then for its body, after this long (default 30s)",
+    },
+    OptSpec {
+        long: "deadline",
+        value: Some("DURATION"),
+        help: "Give up a lookup, fallback sites included, after this long (default: no limit)",
+    },
+    OptSpec {
+        long: "proxy",
+        value: Some("URL"),
+        help: "Send every request through this proxy, e.g. http://proxy:3128 (default: HTTPS_PROXY)",
+    },
+    OptSpec {
+        long: "user-agent",
+        value: Some("TEXT"),
+        help: "User-Agent header of the requests (default: none)",
+    },
+    OptSpec {
+        long: "cassette",
+        value: Some("FILE"),
+        help: "Answer the requests from this recording instead of the sites (see --cassette-mode)",
+    },
+    OptSpec {
+        long: "cassette-mode",
+        value: Some("record|replay"),
+        help: "Record the requests and their answers to --cassette, or replay them (default replay)",
+    },
+    OptSpec {
+        long: "debug-html",
+        value: Some("DIR"),
+        help: "Save the nif.pt pages that could not be parsed into DIR",
+    },
+    OptSpec {
+        long: "fallback",
+        value: Some("SITE"),
+        help: "Ask SITE (racius, einforma) when nif.pt gives no answer (repeatable, in order)",
+    },
+    OptSpec {
+        long: "fallback-order",
+        value: Some("auto|given"),
+        help: "Ask the healthiest fallback sites first (auto, default), or always in the order given",
+    },
+    OptSpec {
+        long: "breaker-threshold",
+        value: Some("N"),
+        help: "Stop querying nif.pt after N consecutive failures (default 5)",
+    },
+    OptSpec {
+        long: "breaker-cooldown",
+        value: Some("SECONDS"),
+        help: "How long to skip remote lookups once the breaker opened (default 60)",
+    },
+    OptSpec {
+        long: "statsd",
+        value: Some("HOST:PORT"),
+        help: "Send lookup counters and timings to this StatsD/DogStatsD agent",
+    },
+    OptSpec {
+        long: "statsd-prefix",
+        value: Some("PREFIX"),
+        help: "Prefix of the StatsD metric names (default check_nif)",
+    },
+    OptSpec {
+        long: "statsd-tag",
+        value: Some("KEY:VALUE"),
+        help: "Add a DogStatsD tag to every metric (repeatable)",
+    },
+    OptSpec {
+        long: "otlp-endpoint",
+        value: Some("URL"),
+        help: "Export one trace span per lookup to this OTLP/HTTP collector (otlp feature)",
+    },
+];
+
+/// Options selecting the lookup cache.
+pub const CACHE_OPTIONS: &[OptSpec] = &[
+    OptSpec {
+        long: "cache",
+        value: Some("PATH|redis://HOST[:PORT][/DB]"),
+        help: "Where to cache lookup results (default: a file in the user cache directory)",
+    },
+    OptSpec {
+        long: "cache-ttl",
+        value: Some("DURATION"),
+        help: "How long cached results are trusted, e.g. 12h or 30d (default 30d)",
+    },
+    OptSpec {
+        long: "stale-after",
+        value: Some("DURATION"),
+        help: "Answers of the cache or the store older than this are reported stale (default 7d)",
+    },
+    OptSpec {
+        long: "expired-after",
+        value: Some("DURATION"),
+        help: "Answers older than this are reported expired (default 90d)",
+    },
+    OptSpec {
+        long: "page-cache",
+        value: Some("DIR"),
+        help: "Also keep the raw pages fetched from nif.pt in DIR, to re-parse them later",
+    },
+];
+
+/// Option disabling the cache, for commands that can work without it.
+pub const NO_CACHE_OPTIONS: &[OptSpec] = &[OptSpec {
+    long: "no-cache",
+    value: None,
+    help: "Always query nif.pt, without reading or writing the cache",
+}];
+
+/// Options of the `cache` administration command.
+pub const CACHE_ADMIN_OPTIONS: &[OptSpec] = &[
+    OptSpec {
+        long: "older-than",
+        value: Some("DURATION"),
+        help: "prune: delete entries fetched longer ago than this, e.g. 30d",
+    },
+    OptSpec {
+        long: "output",
+        value: Some("FILE"),
+        help: "export: write the CSV to this file instead of stdout",
+    },
+    OptSpec {
+        long: "input",
+        value: Some("FILE"),
+        help: "warm: file with one NIF per line ('-' for stdin)",
+    },
+    OptSpec {
+        long: "rate",
+        value: Some("PER_MINUTE"),
+        help: "warm: remote lookups per minute, or auto to adapt to nif.pt (default 20)",
+    },
+];
+
+/// Options selecting the local store.
+pub const STORE_OPTIONS: &[OptSpec] = &[
+    OptSpec {
+        long: "store",
+        value: Some("FILE"),
+        help: "Local store answering lookups offline (default: a file in the user data directory)",
+    },
+    OptSpec {
+        long: "store-key",
+        value: Some("FILE"),
+        help: "Encrypt the store with the key in FILE, 64 hex digits (default: CHECK_NIF_STORE_KEY, else not encrypted)",
+    },
+    OptSpec {
+        long: "store-max-age",
+        value: Some("DURATION"),
+        help: "Look up again store records older than this, e.g. 90d (default: records never go stale)",
+    },
+    OptSpec {
+        long: "as-of",
+        value: Some("YYYY-MM-DD"),
+        help: "Answer from the store history as it was at the end of that day (UTC), looking nothing up",
+    },
+];
+
+/// Option disabling the local store, for commands doing lookups.
+pub const NO_STORE_OPTIONS: &[OptSpec] = &[OptSpec {
+    long: "no-store",
+    value: None,
+    help: "Do not answer lookups from the local store",
+}];
+
+/// Options of `saft`.
+pub const SAFT_OPTIONS: &[OptSpec] = &[OptSpec {
+    long: "remote",
+    value: None,
+    help: "Also look up the locally valid NIFs on nif.pt",
+}];
+
+/// Options of `invoice`.
+pub const INVOICE_OPTIONS: &[OptSpec] = &[OptSpec {
+    long: "remote",
+    value: None,
+    help: "Also look up the locally valid NIFs on nif.pt and compare the registered names",
+}];
+
+/// Options of `compare`.
+pub const COMPARE_OPTIONS: &[OptSpec] = &[OptSpec {
+    long: "format",
+    value: Some("text|csv"),
+    help: "Write the differences as text lines (default) or as CSV",
+}];
+
+/// Options of `verify`.
+pub const VERIFY_OPTIONS: &[OptSpec] = &[
+    OptSpec {
+        long: "require",
+        value: Some("STATUS"),
+        help: "Fail unless every NIF gets one of these statuses or groups, e.g. valid-known (default valid)",
+    },
+    OptSpec {
+        long: "input",
+        value: Some("FILE"),
+        help: "Also verify the NIFs of this file, one per line ('-' for stdin)",
+    },
+];
+
+/// Options of `generate`.
+pub const GENERATE_OPTIONS: &[OptSpec] = &[
+    OptSpec {
+        long: "count",
+        value: Some("N"),
+        help: "Number of distinct NIFs to generate (default 10)",
+    },
+    OptSpec {
+        long: "seed",
+        value: Some("SEED"),
+        help: "Seed of the generator, for the same NIFs on every run (default: random, printed on stderr)",
+    },
+    OptSpec {
+        long: "kind",
+        value: Some("person|company|public|other"),
+        help: "Category of the NIFs (default: a mix of people, companies and public bodies)",
+    },
+    OptSpec {
+        long: "output",
+        value: Some("FILE"),
+        help: "Write the NIFs to FILE instead of stdout, one per line",
+    },
+];
+
+/// Options of `scan`.
+pub const SCAN_OPTIONS: &[OptSpec] = &[
+    OptSpec {
+        long: "remote",
+        value: None,
+        help: "Also look up the NIFs found on nif.pt",
+    },
+    OptSpec {
+        long: "all",
+        value: None,
+        help: "Also list the nine-digit numbers failing local validation",
+    },
+];
+
+/// Options of `reparse`.
+pub const REPARSE_OPTIONS: &[OptSpec] = &[OptSpec {
+    long: "dry-run",
+    value: None,
+    help: "Only print the answers that would change, leave the cache and the store as they are",
+}];
+
+/// Options of `store reverify` and `store prune`.
+pub const STORE_REVERIFY_OPTIONS: &[OptSpec] = &[
+    OptSpec {
+        long: "older-than",
+        value: Some("DURATION"),
+        help: "reverify: refresh records older than this (default: --store-max-age, else 90d); prune: as --keep",
+    },
+    OptSpec {
+        long: "rate",
+        value: Some("PER_MINUTE"),
+        help: "reverify: remote lookups per minute, or auto to adapt to nif.pt (default 20)",
+    },
+];
+
+/// Retention of the store history, for `store prune` and the long-running commands.
+pub const RETENTION_OPTIONS: &[OptSpec] = &[
+    OptSpec {
+        long: "keep",
+        value: Some("DURATION"),
+        help: "Delete the store records superseded longer ago than this, e.g. 2y (store prune; daily under serve and worker)",
+    },
+    OptSpec {
+        long: "keep-last",
+        value: Some("N"),
+        help: "Keep at most N store records per NIF, the current one included",
+    },
+];
+
+/// Options of `store import`.
+pub const STORE_IMPORT_OPTIONS: &[OptSpec] = &[
+    OptSpec {
+        long: "delimiter",
+        value: Some("CHAR"),
+        help: "Field delimiter of the CSV files: ',', ';' or 'tab' (default: detected)",
+    },
+    OptSpec {
+        long: "column",
+        value: Some("FIELD=HEADER"),
+        help: "Read FIELD (nif, name, address, postal_code, locality, phone, email) from HEADER (repeatable)",
+    },
+    OptSpec {
+        long: "source",
+        value: Some("NAME"),
+        help: "Name recorded as the origin of the imported records (default: the file name)",
+    },
+    OptSpec {
+        long: "tag",
+        value: Some("NAME"),
+        help: "import: tag the records with this campaign name; list, export: only the records with this tag",
+    },
+];
+
+/// Options of `store list` and `store export`.
+pub const STORE_QUERY_OPTIONS: &[OptSpec] = &[
+    OptSpec {
+        long: "status",
+        value: Some("STATUS"),
+        help: "Only the records with this status, e.g. invalid or valid-known, as for --only (repeatable)",
+    },
+    OptSpec {
+        long: "since",
+        value: Some("YYYY-MM-DD"),
+        help: "Only the records written on or after this day (UTC)",
+    },
+    OptSpec {
+        long: "output",
+        value: Some("FILE"),
+        help: "export: write the CSV to this file instead of stdout",
+    },
+];
+
+/// Options choosing how lookup results are written.
+pub const OUTPUT_OPTIONS: &[OptSpec] = &[
+    OptSpec {
+        long: "format",
+        value: Some("FORMAT"),
+        help: "Output format: text, csv, json, ndjson, xml, proto, msgpack, arrow (with the arrow feature), or vcard (one NIF.vcf file per entity)",
+    },
+    OptSpec {
+        long: "output",
+        value: Some("FILE"),
+        help: "Write the results to FILE instead of stdout",
+    },
+    OptSpec {
+        long: "output-dir",
+        value: Some("DIR"),
+        help: "vcard: directory receiving the .vcf files (default: current directory)",
+    },
+    OptSpec {
+        long: "nif-format",
+        value: Some("plain|vat"),
+        help: "Write NIFs as nine digits (plain, the default) or in the EU VAT form, e.g. PT500960046",
+    },
+    OptSpec {
+        long: "only",
+        value: Some("STATUS"),
+        help: "Only write the results with this status, e.g. invalid, failed or valid-unknown (repeatable)",
+    },
+    OptSpec {
+        long: "exclude",
+        value: Some("STATUS"),
+        help: "Do not write the results with this status, e.g. valid-known (repeatable)",
+    },
+    OptSpec {
+        long: "sort",
+        value: Some("status|nif|name"),
+        help: "Write the results sorted, at the end of the run (default: as they come)",
+    },
+    OptSpec {
+        long: "group",
+        value: None,
+        help: "text: write one section per status, one line per NIF, at the end of the run",
+    },
+];
+
+/// Options for checking many NIFs in one run.
+pub const BATCH_OPTIONS: &[OptSpec] = &[
+    OptSpec {
+        long: "input",
+        value: Some("FILE"),
+        help: "Also check the NIFs in FILE, one per line (- for stdin)",
+    },
+    OptSpec {
+        long: "retry-failed",
+        value: None,
+        help: "Read --input as failed lookups (a --failures file, or JSON or CSV results) and check those NIFs again",
+    },
+    OptSpec {
+        long: "report",
+        value: Some("FILE"),
+        help: "Write a report of the run to FILE: Markdown for .md files, else a self-contained HTML page",
+    },
+    OptSpec {
+        long: "checkpoint",
+        value: Some("FILE"),
+        help: "Where an interrupted run saves the NIFs left to check (default check_nif.checkpoint)",
+    },
+    OptSpec {
+        long: "failures",
+        value: Some("FILE"),
+        help: "Write the failed lookups to FILE as CSV (NIF, error class, HTTP status, attempts, error)",
+    },
+    OptSpec {
+        long: "duplicates",
+        value: Some("FILE"),
+        help: "Write the NIFs whose entities share a name or address to FILE as CSV, to find suppliers registered twice",
+    },
+    OptSpec {
+        long: "changed-only",
+        value: None,
+        help: "Look every NIF up, write only the results that differ from the store record of their NIF (or that it lacks), and record them",
+    },
+    OptSpec {
+        long: "tag",
+        value: Some("NAME"),
+        help: "Tag every result of the run with this campaign name, e.g. onboarding-2024-06",
+    },
+    OptSpec {
+        long: "progress-format",
+        value: Some("text|json"),
+        help: "Report the progress of the run on stderr, as lines or as JSON events (default: no progress)",
+    },
+    OptSpec {
+        long: "progress-interval",
+        value: Some("DURATION"),
+        help: "Time between two progress reports, e.g. 30s (default 10s)",
+    },
+];
+
+/// Checkpoint file of interrupted batch runs, without `--checkpoint`.
+pub const DEFAULT_CHECKPOINT: &str = "check_nif.checkpoint";
+
+/// Options emailing the summary of a batch run.
+pub const EMAIL_OPTIONS: &[OptSpec] = &[
+    OptSpec {
+        long: "email-to",
+        value: Some("ADDRESS"),
+        help: "Email the summary of the run to ADDRESS when it ends (repeatable)",
+    },
+    OptSpec {
+        long: "email-from",
+        value: Some("ADDRESS"),
+        help: "Sender of the email (required with --email-to)",
+    },
+    OptSpec {
+        long: "smtp",
+        value: Some("URL"),
+        help: "SMTP server, smtps://user@host[:port], smtp://... or smtp+insecure://... (default: CHECK_NIF_SMTP_URL)",
+    },
+    OptSpec {
+        long: "email-csv",
+        value: None,
+        help: "Attach every result as a CSV file to the email",
+    },
+];
+
+/// Options running external commands for lookup results.
+pub const HOOK_OPTIONS: &[OptSpec] = &[
+    OptSpec {
+        long: "on-result",
+        value: Some("COMMAND"),
+        help: "Run COMMAND (sh -c) for every result, with the result JSON on stdin (repeatable)",
+    },
+    OptSpec {
+        long: "on-change",
+        value: Some("COMMAND"),
+        help: "Run COMMAND when a result differs from the store record of the NIF (repeatable)",
+    },
+    OptSpec {
+        long: "hook-timeout",
+        value: Some("DURATION"),
+        help: "Kill hook commands still running after DURATION, e.g. 10s (default 30s)",
+    },
+    OptSpec {
+        long: "kafka-brokers",
+        value: Some("HOST:PORT,..."),
+        help: "Publish every result to Kafka through these bootstrap brokers (kafka feature)",
+    },
+    OptSpec {
+        long: "kafka-topic",
+        value: Some("TOPIC"),
+        help: "Kafka topic of the results and change events (default check_nif.results)",
+    },
+];
+
+/// Options running the results through WASM plugins.
+pub const WASM_OPTIONS: &[OptSpec] = &[OptSpec {
+    long: "wasm-hook",
+    value: Some("FILE"),
+    help: "Run every result through this WASM plugin, which may drop, tag or enrich it (repeatable, wasm feature)",
+}];
+
+/// Options of the `ping` command.
+pub const PING_OPTIONS: &[OptSpec] = &[
+    OptSpec {
+        long: "server",
+        value: Some("http://HOST:PORT|unix:PATH"),
+        help: "Check GET /health of a running serve instead of looking up the canary NIF",
+    },
+    OptSpec {
+        long: "timeout",
+        value: Some("DURATION"),
+        help: "Fail when the check takes longer than this (default 10s)",
+    },
+];
+
+/// Options of the `pipe` command.
+pub const PIPE_OPTIONS: &[OptSpec] = &[
+    OptSpec {
+        long: "nif-path",
+        value: Some("JSONPATH"),
+        help: "Where the NIF is in each record, e.g. $.supplier.nif (default $.nif)",
+    },
+    OptSpec {
+        long: "output-field",
+        value: Some("NAME"),
+        help: "Field added to each record with the result (default check_nif)",
+    },
+    OptSpec {
+        long: "validate-only",
+        value: None,
+        help: "Only validate NIFs locally, without any lookup",
+    },
+];
+
+/// Options of the `serve` command.
+pub const SERVE_OPTIONS: &[OptSpec] = &[
+    OptSpec {
+        long: "listen",
+        value: Some("ADDR:PORT|unix:PATH"),
+        help: "Address to listen on, or a unix socket (default 127.0.0.1:8080)",
+    },
+    OptSpec {
+        long: "api-key-file",
+        value: Some("FILE"),
+        help: "Require one of the API keys in FILE (NAME:KEY per line); CHECK_NIF_API_KEYS also works",
+    },
+    OptSpec {
+        long: "client-rate",
+        value: Some("PER_MINUTE"),
+        help: "Requests per minute allowed to each API key (or IP without keys); 0 = unlimited",
+    },
+    OptSpec {
+        long: "client-quota",
+        value: Some("PER_DAY"),
+        help: "Requests per 24 hours allowed to each API key (or IP without keys); 0 = unlimited",
+    },
+    OptSpec {
+        long: "cors-origin",
+        value: Some("ORIGIN"),
+        help: "Allow browser calls from ORIGIN, e.g. https://intranet.example.pt, or * (repeatable)",
+    },
+    OptSpec {
+        long: "cors-methods",
+        value: Some("METHODS"),
+        help: "Comma-separated methods allowed cross-origin (default GET)",
+    },
+    OptSpec {
+        long: "jobs-dir",
+        value: Some("DIR"),
+        help: "Keep batch jobs in DIR, to resume them after a restart (default: in memory only)",
+    },
+];
+
+/// Options of the `worker` command.
+pub const WORKER_OPTIONS: &[OptSpec] = &[
+    OptSpec {
+        long: "kafka-brokers",
+        value: Some("HOST:PORT,..."),
+        help: "Bootstrap brokers of the Kafka cluster (required)",
+    },
+    OptSpec {
+        long: "request-topic",
+        value: Some("TOPIC"),
+        help: "Topic of the NIF requests (default check_nif.requests)",
+    },
+    OptSpec {
+        long: "reply-topic",
+        value: Some("TOPIC"),
+        help: "Topic the results are published to (default check_nif.replies)",
+    },
+    OptSpec {
+        long: "group",
+        value: Some("GROUP"),
+        help: "Consumer group keeping the offsets of the requests (default check_nif.worker)",
+    },
+    OptSpec {
+        long: "partitions",
+        value: Some("N,..."),
+        help: "Only read these partitions of the request topic, to share it between workers",
+    },
+];
+
+/// Options of `selftest`.
+pub const SELFTEST_OPTIONS: &[OptSpec] = &[OptSpec {
+    long: "reference",
+    value: None,
+    help: "Also look up every embedded reference NIF, expecting a known entity",
+}];
+
+/// Option restricting the categories of NIFs accepted.
+pub const EXPECT_OPTIONS: &[OptSpec] = &[OptSpec {
+    long: "expect",
+    value: Some("person|company|public|other"),
+    help: "Reject the locally valid NIFs of other categories without looking them up (repeatable, or comma-separated)",
+}];
+
+/// Options checking the addresses of the entities found.
+pub const ADDRESS_OPTIONS: &[OptSpec] = &[
+    OptSpec {
+        long: "check-postal-codes",
+        value: None,
+        help: "Flag entities whose postal code does not go with their locality (main towns only, see --postal-codes)",
+    },
+    OptSpec {
+        long: "postal-codes",
+        value: Some("FILE"),
+        help: "Check every postal code against this CTT list (todos_cp.txt); implies --check-postal-codes",
+    },
+    OptSpec {
+        long: "geocode",
+        value: None,
+        help: "Add the coordinates of each entity address, from OpenStreetMap's Nominatim (geocode feature)",
+    },
+    OptSpec {
+        long: "geocoder-url",
+        value: Some("URL"),
+        help: "Nominatim server to ask instead of the public one; implies --geocode",
+    },
+    OptSpec {
+        long: "geocode-rate",
+        value: Some("PER_MINUTE"),
+        help: "Requests a minute to the geocoder (default: 60, the limit of the public server)",
+    },
+];
+
+/// Options comparing the registered names with the expected ones.
+pub const NAME_OPTIONS: &[OptSpec] = &[
+    OptSpec {
+        long: "expected-names",
+        value: Some("FILE"),
+        help: "Check the NIFs of this CSV (nif and name columns) and score their registered name against its name",
+    },
+    OptSpec {
+        long: "min-similarity",
+        value: Some("N"),
+        help: "Flag registered names scoring under N, from 0 to 1 (default: 0.8)",
+    },
+];
+
+/// Option groups accepted when checking NIFs given on the command line.
+pub const LOOKUP_OPTIONS: &[&[OptSpec]] = &[
+    LOG_OPTIONS,
+    OUTPUT_OPTIONS,
+    BATCH_OPTIONS,
+    EXPECT_OPTIONS,
+    ADDRESS_OPTIONS,
+    NAME_OPTIONS,
+    EMAIL_OPTIONS,
+    HOOK_OPTIONS,
+    WASM_OPTIONS,
+    NETWORK_OPTIONS,
+    CACHE_OPTIONS,
+    NO_CACHE_OPTIONS,
+    STORE_OPTIONS,
+    NO_STORE_OPTIONS,
+];
+
+/// Describes a subcommand (`check_nif <name> ...`).
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub args: &'static str,                // Positional arguments, as shown in the usage line
+    pub about: &'static str,               // One-line description
+    pub options: &'static [&'static [OptSpec]], // Option groups accepted by the command
+}
+
+/// Every subcommand; anything else on the command line is taken as NIFs to check.
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "cache",
+        args: "<stats|clear|prune|export|warm>",
+        about: "Inspect, maintain and pre-warm the lookup cache",
+        options: &[LOG_OPTIONS, CACHE_OPTIONS, CACHE_ADMIN_OPTIONS, NETWORK_OPTIONS],
+    },
+    CommandSpec {
+        name: "reparse",
+        args: "[DIR...]",
+        about: "Parse the kept nif.pt pages again and correct the cached and stored answers",
+        options: &[LOG_OPTIONS, REPARSE_OPTIONS, CACHE_OPTIONS, STORE_OPTIONS, NO_STORE_OPTIONS],
+    },
+    CommandSpec {
+        name: "store",
+        args: "<import <FILE>...|reverify|list|export|history <NIF>|prune>",
+        about: "Import company registries (CSV) into the local store, refresh stale records, query and export them",
+        options: &[
+            LOG_OPTIONS,
+            STORE_OPTIONS,
+            STORE_IMPORT_OPTIONS,
+            STORE_REVERIFY_OPTIONS,
+            RETENTION_OPTIONS,
+            STORE_QUERY_OPTIONS,
+            HOOK_OPTIONS,
+            NETWORK_OPTIONS,
+        ],
+    },
+    CommandSpec {
+        name: "pipe",
+        args: "",
+        about: "Read NDJSON records on stdin, write them to stdout with their NIF checked",
+        options: &[
+            LOG_OPTIONS,
+            PIPE_OPTIONS,
+            EXPECT_OPTIONS,
+            ADDRESS_OPTIONS,
+            NETWORK_OPTIONS,
+            CACHE_OPTIONS,
+            NO_CACHE_OPTIONS,
+            STORE_OPTIONS,
+            NO_STORE_OPTIONS,
+        ],
+    },
+    CommandSpec {
+        name: "invoice",
+        args: "<FILE>...",
+        about: "Check the seller and buyer NIFs of UBL (CIUS-PT) e-invoices",
+        options: &[
+            LOG_OPTIONS,
+            INVOICE_OPTIONS,
+            NETWORK_OPTIONS,
+            CACHE_OPTIONS,
+            NO_CACHE_OPTIONS,
+            STORE_OPTIONS,
+            NO_STORE_OPTIONS,
+        ],
+    },
+    CommandSpec {
+        name: "saft",
+        args: "<FILE>...",
+        about: "Check the customer and supplier NIFs of SAF-T (PT) files before submission",
+        options: &[
+            LOG_OPTIONS,
+            SAFT_OPTIONS,
+            NETWORK_OPTIONS,
+            CACHE_OPTIONS,
+            NO_CACHE_OPTIONS,
+            STORE_OPTIONS,
+            NO_STORE_OPTIONS,
+        ],
+    },
+    CommandSpec {
+        name: "compare",
+        args: "<OLD> <NEW>",
+        about: "Compare two result files (csv, json or ndjson output) by NIF",
+        options: &[LOG_OPTIONS, COMPARE_OPTIONS],
+    },
+    CommandSpec {
+        name: "scan",
+        args: "[FILE...]",
+        about: "Find the NIFs in free text, such as contracts or exported mailboxes (stdin by default)",
+        options: &[
+            LOG_OPTIONS,
+            SCAN_OPTIONS,
+            NETWORK_OPTIONS,
+            CACHE_OPTIONS,
+            NO_CACHE_OPTIONS,
+            STORE_OPTIONS,
+            NO_STORE_OPTIONS,
+        ],
+    },
+    CommandSpec {
+        name: "schema",
+        args: "[result|batch|proto]",
+        about: "Print the JSON Schema of a result or of the --format json array, or the .proto file",
+        options: &[LOG_OPTIONS],
+    },
+    CommandSpec {
+        name: "generate",
+        args: "",
+        about: "Generate random locally valid NIFs, reproducibly with --seed, for test fixtures",
+        options: &[LOG_OPTIONS, GENERATE_OPTIONS],
+    },
+    CommandSpec {
+        name: "selftest",
+        args: "[NIF...]",
+        about: "Check the nif.pt page parser against canary NIFs with known answers",
+        options: &[LOG_OPTIONS, SELFTEST_OPTIONS, NETWORK_OPTIONS],
+    },
+    CommandSpec {
+        name: "verify",
+        args: "[NIF...]",
+        about: "Look NIFs up and fail unless each has a required status, for CI gating",
+        options: &[
+            LOG_OPTIONS,
+            VERIFY_OPTIONS,
+            OUTPUT_OPTIONS,
+            EXPECT_OPTIONS,
+            ADDRESS_OPTIONS,
+            NETWORK_OPTIONS,
+            CACHE_OPTIONS,
+            NO_CACHE_OPTIONS,
+            STORE_OPTIONS,
+            NO_STORE_OPTIONS,
+        ],
+    },
+    CommandSpec {
+        name: "serve",
+        args: "",
+        about: "Serve lookups over HTTP: GET /nif/{nif}",
+        options: &[
+            LOG_OPTIONS,
+            SERVE_OPTIONS,
+            EXPECT_OPTIONS,
+            ADDRESS_OPTIONS,
+            NETWORK_OPTIONS,
+            CACHE_OPTIONS,
+            NO_CACHE_OPTIONS,
+            STORE_OPTIONS,
+            NO_STORE_OPTIONS,
+            RETENTION_OPTIONS,
+        ],
+    },
+    CommandSpec {
+        name: "ping",
+        args: "",
+        about: "Check once that lookups (or a running server) work, exiting 0 or 1, for health checks",
+        options: &[
+            LOG_OPTIONS,
+            PING_OPTIONS,
+            NETWORK_OPTIONS,
+            CACHE_OPTIONS,
+            NO_CACHE_OPTIONS,
+            STORE_OPTIONS,
+            NO_STORE_OPTIONS,
+        ],
+    },
+    CommandSpec {
+        name: "lambda",
+        args: "",
+        about: "Answer the events of an AWS Lambda function, as its custom runtime (lambda feature)",
+        options: &[
+            LOG_OPTIONS,
+            EXPECT_OPTIONS,
+            ADDRESS_OPTIONS,
+            NETWORK_OPTIONS,
+            CACHE_OPTIONS,
+            NO_CACHE_OPTIONS,
+            STORE_OPTIONS,
+            NO_STORE_OPTIONS,
+        ],
+    },
+    CommandSpec {
+        name: "worker",
+        args: "",
+        about: "Answer NIF requests read from a Kafka topic on a reply topic (kafka feature)",
+        options: &[
+            LOG_OPTIONS,
+            WORKER_OPTIONS,
+            EXPECT_OPTIONS,
+            ADDRESS_OPTIONS,
+            NETWORK_OPTIONS,
+            CACHE_OPTIONS,
+            NO_CACHE_OPTIONS,
+            STORE_OPTIONS,
+            NO_STORE_OPTIONS,
+            RETENTION_OPTIONS,
+        ],
+    },
+];
+
+/// Subcommands left out of the usage text, for packagers and maintainers rather than users.
+pub const HIDDEN_COMMANDS: &[CommandSpec] = &[CommandSpec {
+    name: "gen-docs",
+    args: "[DIR]",
+    about: "Write man pages of check_nif and of every command into DIR (default: the current directory)",
+    options: &[LOG_OPTIONS],
+}];
+
+/// Tells whether the user asked for the usage text.
+pub fn wants_help(args: &[String]) -> bool {
+    args.iter().take_while(|arg| *arg != "--").any(|arg| arg == "--help" || arg == "-h")
+}
+
+/// Finds the subcommand called `name`, hidden ones included.
+pub fn find_command(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().chain(HIDDEN_COMMANDS).find(|command| command.name == name)
+}
+
+/// Command line split into positional arguments and recognized options.
+#[derive(Debug, Default)]
+pub struct ParsedArgs {
+    pub positionals: Vec<String>,
+    options: Vec<(&'static str, Option<String>)>,
+}
+
+impl ParsedArgs {
+    /// Tells whether the switch (or option) was given at least once.
+    pub fn flag(&self, long: &str) -> bool {
+        self.options.iter().any(|(name, _)| *name == long)
+    }
+
+    /// Returns the last value given for an option.
+    pub fn value(&self, long: &str) -> Option<&str> {
+        self.values(long).pop()
+    }
+
+    /// Returns every value given for a repeatable option, in order.
+    pub fn values(&self, long: &str) -> Vec<&str> {
+        self.options
+            .iter()
+            .filter(|(name, _)| *name == long)
+            .filter_map(|(_, value)| value.as_deref())
+            .collect()
+    }
+}
+
+/// Parses `args` (without the program name) against the given option groups.
+///
+/// Options may be written `--name value` or `--name=value`; everything after `--`
+/// is taken as positional.
+pub fn parse_args(args: &[String], groups: &[&[OptSpec]]) -> Result<ParsedArgs, String> {
+    let mut parsed = ParsedArgs::default();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--" {
+            parsed.positionals.extend(iter.by_ref().cloned());
+            break;
+        }
+        let Some(option) = arg.strip_prefix("--") else {
+            parsed.positionals.push(arg.clone());
+            continue;
+        };
+
+        // Split "--name=value" forms
+        let (name, inline_value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (option, None),
+        };
+        let spec = groups
+            .iter()
+            .flat_map(|group| group.iter())
+            .find(|spec| spec.long == name)
+            .ok_or_else(|| format!("unknown option '--{}'", name))?;
+
+        let value = match (spec.value, inline_value) {
+            (None, None) => None,
+            (None, Some(_)) => return Err(format!("option '--{}' does not take a value", name)),
+            (Some(_), Some(value)) => Some(value),
+            (Some(placeholder), None) => match iter.next() {
+                Some(value) => Some(value.clone()),
+                None => return Err(format!("option '--{}' requires a value ({})", name, placeholder)),
+            },
+        };
+        parsed.options.push((spec.long, value));
+    }
+    Ok(parsed)
+}
+
+/// Adds the options of the `[profile.NAME]` picked by `--profile` that are not on the
+/// command line.
+///
+/// Profile keys are long option names (`rate-limit`, or `rate_limit`), valued with a string
+/// or a number, or `true` for switches. A profile is shared by every command, so options
+/// `groups` does not have are ignored, as long as some command has them.
+pub fn apply_profile(parsed: &mut ParsedArgs, groups: &[&[OptSpec]]) -> Result<(), String> {
+    let Some(name) = parsed.value("profile") else {
+        return Ok(());
+    };
+    let config = ConfigFile::find(parsed.value("config"))?
+        .ok_or_else(|| format!("--profile {} needs a config file, none at {}", name, config::default_config_path().display()))?;
+    let profile = config.profile(name)?;
+    let every_group = COMMANDS.iter().chain(HIDDEN_COMMANDS).flat_map(|command| command.options).chain(LOOKUP_OPTIONS);
+    let mut options = Vec::new();
+    for entry in &profile.entries {
+        let long = entry.key.replace('_', "-");
+        let error = |message: String| format!("{}:{}: {}", config.path.display(), entry.line, message);
+        if long == "profile" || long == "config" {
+            return Err(error(format!("--{} cannot be set in a profile", long)));
+        }
+        let Some(spec) = groups.iter().flat_map(|group| group.iter()).find(|spec| spec.long == long) else {
+            if !every_group.clone().flat_map(|group| group.iter()).any(|spec| spec.long == long) {
+                return Err(error(format!("unknown option '--{}' in [{}]", long, profile.name)));
+            }
+            continue;
+        };
+        if parsed.flag(spec.long) {
+            continue;
+        }
+        match (spec.value, &entry.value) {
+            (None, ConfigValue::Bool(true)) => options.push((spec.long, None)),
+            (None, ConfigValue::Bool(false)) => {}
+            (None, _) => return Err(error(format!("--{} is a switch, expected true or false", long))),
+            (Some(_), ConfigValue::String(value)) => options.push((spec.long, Some(value.clone()))),
+            (Some(_), ConfigValue::Int(value)) => options.push((spec.long, Some(value.to_string()))),
+            (Some(placeholder), ConfigValue::Bool(_)) => return Err(error(format!("--{} expects a value ({})", long, placeholder))),
+        }
+    }
+    // Before those of the command line, so they stay the last values
+    options.append(&mut parsed.options);
+    parsed.options = options;
+    Ok(())
+}
+
+/// Builds the usage text of the default mode, listing subcommands and lookup options.
+pub fn usage(program: &str) -> String {
+    let mut text = format!("Usage: {} [OPTIONS] <NIF_NUMBER>...\n", program);
+    text.push_str(&format!("       {} <COMMAND> [ARGS] [OPTIONS]\n\nCommands:\n", program));
+    for command in COMMANDS {
+        text.push_str(&format!("  {:<40} {}\n", format!("{} {}", command.name, command.args), command.about));
+    }
+    text.push_str("\nOptions:\n");
+    push_options(&mut text, LOOKUP_OPTIONS);
+    text
+}
+
+/// Builds the usage text of a subcommand.
+pub fn command_usage(program: &str, command: &CommandSpec) -> String {
+    let mut text = format!(
+        "Usage: {} {} {} [OPTIONS]\n\n{}\n\nOptions:\n",
+        program, command.name, command.args, command.about
+    );
+    push_options(&mut text, command.options);
+    text
+}
+
+fn push_options(text: &mut String, groups: &[&[OptSpec]]) {
+    for spec in groups.iter().flat_map(|group| group.iter()) {
+        let left = match spec.value {
+            Some(placeholder) => format!("--{} {}", spec.long, placeholder),
+            None => format!("--{}", spec.long),
+        };
+        text.push_str(&format!("  {:<40} {}\n", left, spec.help));
+    }
+}
+
+/// Builds the writer of the results, from `--format` (text by default) and `--output`
+/// (stdout by default) or `--output-dir` (vCard).
+pub fn output_writer(parsed: &ParsedArgs) -> Result<Box<dyn OutputWriter>, String> {
+    let format = OutputFormat::parse(parsed.value("format").unwrap_or("text"))?;
+    let out: Box<dyn Write> = match parsed.value("output") {
+        Some(_) if format == OutputFormat::Vcard => return Err("vcard writes files to --output-dir, not --output".to_string()),
+        Some(path) => Box::new(BufWriter::new(
+            File::create(path).map_err(|e| format!("cannot create {}: {}", path, e))?,
+        )),
+        None => {
+            if !matches!(format, OutputFormat::Text | OutputFormat::Vcard) {
+                logging::set_info_to_stderr(true); // Keep stdout parseable
+            }
+            Box::new(BufWriter::new(std::io::stdout()))
+        }
+    };
+    let mut writer = match format {
+        OutputFormat::Text if parsed.flag("group") => Box::new(GroupedTextWriter::new(out)),
+        _ if parsed.flag("group") => return Err("--group only applies to --format text".to_string()),
+        _ => format.writer(out, PathBuf::from(parsed.value("output-dir").unwrap_or("."))),
+    };
+    if let Some(key) = parsed.value("sort") {
+        writer = Box::new(SortWriter::new(writer, SortKey::parse(key)?));
+    }
+    if NifFormat::parse(parsed.value("nif-format").unwrap_or("plain"))? == NifFormat::Vat {
+        writer = Box::new(VatNifWriter::new(writer));
+    }
+    let filter = StatusFilter::parse(&parsed.values("only"), &parsed.values("exclude"))?;
+    if !filter.is_empty() {
+        writer = Box::new(FilterWriter::new(writer, filter));
+    }
+    Ok(writer)
+}
+
+/// NIFs to check: the positional arguments, then the lines of `--input` (its failed lookups
+/// under `--retry-failed`) and the NIFs of `--expected-names`, without the `PT` prefix of the
+/// VAT form.
+pub fn batch_nifs(parsed: &ParsedArgs) -> Result<Vec<String>, String> {
+    let mut nifs = parsed.positionals.clone();
+    match parsed.value("input") {
+        Some(path) if parsed.flag("retry-failed") => nifs.extend(read_failures(path)?),
+        Some(path) => nifs.extend(read_nif_list(path)?),
+        None if parsed.flag("retry-failed") => return Err("--retry-failed requires --input".to_string()),
+        None => {}
+    }
+    if let Some(path) = parsed.value("expected-names") {
+        nifs.extend(read_expected_names(path)?.into_iter().map(|(nif, _)| nif));
+    }
+    Ok(nifs.iter().map(|nif| normalize_nif(nif).to_string()).collect())
+}
+
+/// Reads `--on-result`, `--on-change`, `--hook-timeout` and the Kafka options.
+pub fn hooks(parsed: &ParsedArgs) -> Result<Hooks, String> {
+    let timeout = match parsed.value("hook-timeout") {
+        Some(text) => parse_duration(text)?,
+        None => DEFAULT_HOOK_TIMEOUT,
+    };
+    let commands = |long| {
+        parsed
+            .values(long)
+            .into_iter()
+            .map(|command| CommandHook {
+                command: command.to_string(),
+                timeout,
+            })
+            .collect()
+    };
+    let brokers: Vec<String> = parsed
+        .value("kafka-brokers")
+        .unwrap_or_default()
+        .split(',')
+        .map(|broker| broker.trim().to_string())
+        .filter(|broker| !broker.is_empty())
+        .collect();
+    if brokers.is_empty() && parsed.value("kafka-topic").is_some() {
+        return Err("--kafka-topic requires --kafka-brokers".to_string());
+    }
+    #[cfg(feature = "kafka")]
+    let kafka = if brokers.is_empty() {
+        None
+    } else {
+        let topic = parsed.value("kafka-topic").unwrap_or("check_nif.results");
+        Some(Arc::new(KafkaProducer::new(brokers, topic)?))
+    };
+    #[cfg(not(feature = "kafka"))]
+    if !brokers.is_empty() {
+        return Err("cannot publish to Kafka: built without the kafka feature".to_string());
+    }
+    Ok(Hooks {
+        on_result: commands("on-result"),
+        on_change: commands("on-change"),
+        #[cfg(feature = "kafka")]
+        kafka,
+    })
+}
+
+/// Loads the plugins of `--wasm-hook`, in the order given.
+#[cfg(feature = "wasm")]
+pub fn wasm_hooks(parsed: &ParsedArgs) -> Result<Vec<WasmHook>, String> {
+    parsed.values("wasm-hook").into_iter().map(|path| WasmHook::load(path.as_ref())).collect()
+}
+
+/// Reads the email options: `None` unless `--email-to` is given.
+pub fn email_config(parsed: &ParsedArgs) -> Result<Option<SmtpConfig>, String> {
+    let to: Vec<String> = parsed.values("email-to").into_iter().map(str::to_string).collect();
+    if to.is_empty() {
+        if parsed.flag("email-csv") || parsed.value("email-from").is_some() || parsed.value("smtp").is_some() {
+            return Err("--email-from, --smtp and --email-csv need --email-to".to_string());
+        }
+        return Ok(None);
+    }
+    let from = parsed.value("email-from").ok_or("--email-to needs --email-from")?;
+    let url = match parsed.value("smtp") {
+        Some(url) => url.to_string(),
+        None => std::env::var(SMTP_URL_ENV).map_err(|_| format!("--email-to needs --smtp or {}", SMTP_URL_ENV))?,
+    };
+    SmtpConfig::parse(&url, from, to).map(Some)
+}
+
+/// Applies `--log-format`, `--nif-privacy`, `--nif-hash-key` and `--lang`, which every command accepts.
+pub fn apply_log_format(parsed: &ParsedArgs) -> Result<(), String> {
+    if let Some(format) = parsed.value("log-format") {
+        logging::set_format(LogFormat::parse(format)?);
+    }
+    if let Some(privacy) = parsed.value("nif-privacy") {
+        logging::set_nif_privacy(NifPrivacy::parse(privacy)?);
+    }
+    let hash_key = match parsed.value("nif-hash-key") {
+        Some(path) => Some(std::fs::read_to_string(path).map_err(|e| format!("cannot read NIF hash key {}: {}", path, e))?),
+        None => std::env::var(logging::NIF_HASH_KEY_ENV).ok(),
+    };
+    if let Some(key) = hash_key {
+        logging::set_nif_hash_key(key.trim().as_bytes())?;
+    }
+    if let Some(value) = parsed.value("lang") {
+        lang::set_lang(Lang::parse(value)?);
+    }
+    Ok(())
+}
+
+/// Opens the cache selected by `--cache`/`--cache-ttl`, or the default file cache.
+pub fn open_cache(parsed: &ParsedArgs) -> Result<Arc<dyn Cache>, String> {
+    let ttl = match parsed.value("cache-ttl") {
+        Some(text) => parse_duration(text)?,
+        None => cache::DEFAULT_TTL,
+    };
+    let location = match parsed.value("cache") {
+        Some(location) => location.to_string(),
+        None => cache::default_cache_path().display().to_string(),
+    };
+    cache::open_cache(&location, ttl)
+}
+
+/// Opens the page cache selected by `--page-cache`, if any.
+pub fn open_page_cache(parsed: &ParsedArgs) -> Result<Option<Arc<PageCache>>, String> {
+    let pages = parsed.value("page-cache").map(PageCache::open).transpose()?;
+    Ok(pages.map(Arc::new))
+}
+
+/// Opens the store selected by `--store`, or the default one, encrypted with `--store-key`.
+pub fn open_store(parsed: &ParsedArgs) -> Result<Arc<dyn ResultStore>, String> {
+    let path = match parsed.value("store") {
+        Some(path) => path.into(),
+        None => store::default_store_path(),
+    };
+    let key = match parsed.value("store-key") {
+        Some(file) => Some(std::fs::read_to_string(file).map_err(|e| format!("cannot read store key {}: {}", file, e))?),
+        None => std::env::var(store::STORE_KEY_ENV).ok(),
+    };
+    let key = key.map(|key| StoreKey::from_hex(&key)).transpose()?;
+    Ok(Arc::new(Store::open_with_key(path, key)?))
+}
+
+/// Reads `--keep` and `--keep-last`; an empty policy when neither is given.
+pub fn retention_policy(parsed: &ParsedArgs) -> Result<RetentionPolicy, String> {
+    let keep_last = match parsed.value("keep-last") {
+        Some(text) => Some(text.parse::<usize>().ok().filter(|&n| n > 0).ok_or(format!("invalid --keep-last '{}', expected at least 1", text))?),
+        None => None,
+    };
+    Ok(RetentionPolicy {
+        keep: parsed.value("keep").map(parse_duration).transpose()?,
+        keep_last,
+    })
+}
+
+/// Prunes `store`, that of the lookup options, by `--keep` and `--keep-last` in the
+/// background, for the long-running commands; does nothing without either option.
+pub fn spawn_pruner(parsed: &ParsedArgs, store: Option<Arc<dyn ResultStore>>) -> Result<(), String> {
+    let policy = retention_policy(parsed)?;
+    if policy.is_empty() {
+        return Ok(());
+    }
+    let store = store.ok_or("--keep and --keep-last prune the store, they cannot go with --no-store")?;
+    store::spawn_pruner(store, policy);
+    Ok(())
+}
+
+/// Builds the remote lookup options from the parsed command line.
+pub fn lookup_options(parsed: &ParsedArgs) -> Result<LookupOptions, String> {
+    let mut options = LookupOptions::default();
+    for spec in parsed.values("resolve") {
+        options.resolve.push(parse_resolve(spec)?);
+    }
+    if let Some(server) = parsed.value("dns-server") {
+        options.dns_resolver = Some(Arc::new(NameServerResolver::parse(server)?));
+    }
+    if let Some(path) = parsed.value("ca-bundle") {
+        options.ca_certificates = tls::load_ca_bundle(path)?;
+    }
+    options.accept_invalid_certs = parsed.flag("insecure");
+    for pin in parsed.values("pin-sha256") {
+        options.pinned_certificates.push(tls::parse_pin(pin)?);
+    }
+    if let Some(text) = parsed.value("max-connections") {
+        match parse_number(Some(text), "max-connections", 0)? {
+            0 => return Err("--max-connections must be at least 1".to_string()),
+            limit => options.connection_limit = Some(Arc::new(ConnectionLimit::new(limit as usize))),
+        }
+    }
+    let max_idle = parsed.value("pool-max-idle").map(|text| parse_number(Some(text), "pool-max-idle", 0));
+    options.pool = PoolOptions {
+        max_idle_per_host: max_idle.transpose()?.map(|max| max as usize),
+        idle_timeout: parsed.value("pool-idle-timeout").map(parse_duration).transpose()?,
+    };
+    options.timeouts = TimeoutOptions {
+        connect: timeout(parsed, "connect-timeout")?,
+        read: timeout(parsed, "read-timeout")?,
+        deadline: timeout(parsed, "deadline")?,
+    };
+    for categories in parsed.values("expect") {
+        for category in categories.split(',') {
+            options.expect.push(NifCategory::parse(category.trim())?);
+        }
+    }
+    if let Some(path) = parsed.value("postal-codes") {
+        options.postal_codes = Some(Arc::new(PostalTable::load(path)?));
+    } else if parsed.flag("check-postal-codes") {
+        options.postal_codes = Some(Arc::new(PostalTable::embedded()));
+    }
+    if let Some(path) = parsed.value("expected-names") {
+        let min_similarity = match parsed.value("min-similarity") {
+            Some(value) => value
+                .parse::<f64>()
+                .ok()
+                .filter(|n| (0.0..=1.0).contains(n))
+                .ok_or(format!("invalid --min-similarity '{}', expected a number from 0 to 1", value))?,
+            None => DEFAULT_MIN_SIMILARITY,
+        };
+        let mut names = ExpectedNames::new(min_similarity);
+        for (nif, name) in read_expected_names(path)? {
+            names.insert(normalize_nif(&nif), name);
+        }
+        options.expected_names = Some(Arc::new(names));
+    } else if parsed.value("min-similarity").is_some() {
+        return Err("--min-similarity requires --expected-names".to_string());
+    }
+    if parsed.flag("geocode") || parsed.value("geocoder-url").is_some() {
+        let url = parsed.value("geocoder-url");
+        #[cfg(feature = "geocode")]
+        {
+            let per_minute = parse_u32(parsed.value("geocode-rate"), "geocode-rate", NOMINATIM_RATE)?;
+            if per_minute == 0 {
+                return Err("invalid value '0' for --geocode-rate, expected at least 1".to_string());
+            }
+            options.geocoder = Some(Arc::new(Geocoder::new(url.unwrap_or(NOMINATIM_URL)).with_rate(per_minute)));
+        }
+        #[cfg(not(feature = "geocode"))]
+        return Err(format!("cannot geocode with {}: built without the geocode feature", url.unwrap_or("Nominatim")));
+    } else if parsed.value("geocode-rate").is_some() {
+        return Err("--geocode-rate requires --geocode".to_string());
+    }
+    if let Some(proxy) = parsed.value("proxy") {
+        reqwest::Proxy::all(proxy).map_err(|e| format!("invalid proxy URL '{}': {}", proxy, e))?;
+        options.proxy = Some(proxy.to_string());
+    }
+    options.user_agent = parsed.value("user-agent").map(String::from);
+    // Asking for NIFs to be hashed or masked must not leave them in clear files next to the logs
+    let privacy = parsed.value("nif-privacy").filter(|privacy| *privacy != "clear");
+    if let Some(privacy) = privacy
+        && let Some(option) = ["debug-html", "page-cache"].into_iter().find(|option| parsed.value(option).is_some())
+    {
+        return Err(format!("--{} saves pages naming the NIFs in clear, it cannot go with --nif-privacy {}", option, privacy));
+    }
+    options.debug_html = parsed.value("debug-html").map(PathBuf::from);
+    options.page_cache = open_page_cache(parsed)?;
+    if let Some(value) = parsed.value("rate-limit") {
+        let per_minute = parse_u32(Some(value), "rate-limit", 0)?;
+        if per_minute == 0 {
+            return Err(format!("invalid value '{}' for --rate-limit, expected at least 1", value));
+        }
+        let state = parsed.value("rate-state").map_or_else(ratelimit::default_state_path, PathBuf::from);
+        options.throttle = Some(Arc::new(Throttle::per_minute(per_minute).with_state_file(state)));
+    } else if parsed.value("rate-state").is_some() {
+        return Err("--rate-state requires --rate-limit".to_string());
+    }
+    if let Some(location) = parsed.value("request-lock") {
+        options.request_lock = Some(Arc::new(RequestLock::open(location)?));
+    }
+    let mode = parsed.value("cassette-mode").map(CassetteMode::parse).transpose()?;
+    match (parsed.value("cassette"), mode) {
+        (Some(path), mode) => {
+            options.cassette = Some(Arc::new(Cassette::open(path, mode.unwrap_or(CassetteMode::Replay))?));
+        }
+        (None, Some(_)) => return Err("--cassette-mode requires --cassette".to_string()),
+        (None, None) => {}
+    }
+    for site in parsed.values("fallback") {
+        options.fallbacks.push(Fallback::parse(site)?);
+    }
+    if let Some(config) = ConfigFile::find(parsed.value("config"))? {
+        options.backends = config.backends(parsed.value("profile"))?;
+    }
+    // A fallback enabled in the config file joins the chain, after those of --fallback
+    for fallback in [Fallback::Racius, Fallback::Einforma] {
+        if options.backends.fallback(fallback).enabled == Some(true) && !options.fallbacks.contains(&fallback) {
+            options.fallbacks.push(fallback);
+        }
+    }
+    let backends = options.backends.clone();
+    options.fallbacks.retain(|fallback| backends.fallback(*fallback).enabled != Some(false));
+    if options.backends.nif_pt.enabled == Some(false) && options.fallbacks.is_empty() {
+        return Err("nif.pt is disabled in the config file and no fallback site is enabled".to_string());
+    }
+    match parsed.value("fallback-order").unwrap_or("auto") {
+        "auto" if options.fallbacks.len() > 1 => options.backend_health = Some(Arc::new(BackendHealth::default())),
+        "auto" | "given" => {}
+        other => return Err(format!("invalid value '{}' for --fallback-order, expected auto or given", other)),
+    }
+    let threshold = parse_u32(parsed.value("breaker-threshold"), "breaker-threshold", 5)?;
+    if threshold == 0 {
+        return Err("invalid value '0' for --breaker-threshold, expected at least 1".to_string());
+    }
+    let cool_down = parse_number(parsed.value("breaker-cooldown"), "breaker-cooldown", 60)?;
+    options.circuit_breaker = Some(Arc::new(CircuitBreaker::new(threshold, Duration::from_secs(cool_down))));
+    if !parsed.flag("no-cache") {
+        if let Some(privacy) = privacy {
+            return Err(format!("the cache keeps the NIFs in clear, --nif-privacy {} needs --no-cache", privacy));
+        }
+        options.cache = Some(open_cache(parsed)?);
+    }
+    if !parsed.flag("no-store") {
+        options.store = Some(open_store(parsed)?);
+        options.store_max_age = parsed.value("store-max-age").map(parse_duration).transpose()?;
+        // The whole day counts, up to its last second
+        options.as_of = parsed.value("as-of").map(parse_date).transpose()?.map(|day| day + Duration::from_secs(86_399));
+    } else if parsed.value("as-of").is_some() {
+        return Err("--as-of answers from the store, it cannot go with --no-store".to_string());
+    }
+    if let Some(text) = parsed.value("stale-after") {
+        options.freshness.stale_after = parse_duration(text)?;
+    }
+    if let Some(text) = parsed.value("expired-after") {
+        options.freshness.expired_after = parse_duration(text)?;
+    }
+    if options.freshness.stale_after > options.freshness.expired_after {
+        return Err("--stale-after must not be longer than --expired-after".to_string());
+    }
+    options.tag = parsed.value("tag").map(parse_tag).transpose()?;
+    if let Some(address) = parsed.value("statsd") {
+        let prefix = parsed.value("statsd-prefix").unwrap_or("check_nif");
+        let tags = parsed.values("statsd-tag").into_iter().map(String::from).collect();
+        options.statsd = Some(Arc::new(StatsdClient::new(address, prefix, tags)?));
+    }
+    if let Some(endpoint) = parsed.value("otlp-endpoint") {
+        #[cfg(feature = "otlp")]
+        {
+            options.tracer = Some(Arc::new(OtlpExporter::new(endpoint, env!("CARGO_PKG_NAME"))?));
+        }
+        #[cfg(not(feature = "otlp"))]
+        return Err(format!("cannot export traces to {}: built without the otlp feature", endpoint));
+    }
+    if options.accept_invalid_certs && options.pinned_certificates.is_empty() {
+        logging::warn(
+            "tls_validation_disabled",
+            &[],
+            "Warning: TLS certificate validation is disabled (--insecure)".to_string(),
+        );
+    }
+    Ok(options)
+}
+
+/// Reads a timeout option, which must not be zero.
+fn timeout(parsed: &ParsedArgs, name: &str) -> Result<Option<Duration>, String> {
+    match parsed.value(name).map(parse_duration).transpose()? {
+        Some(Duration::ZERO) => Err(format!("--{} must be more than 0", name)),
+        timeout => Ok(timeout),
+    }
+}
+
+/// Reads `--rate` of batch jobs, 20 lookups per minute by default.
+pub fn job_rate(parsed: &ParsedArgs) -> Result<JobRate, String> {
+    parsed.value("rate").map_or(Ok(JobRate::Fixed(20)), JobRate::parse)
+}
+
+/// Builds the progress writer of `--progress-format`, over `total` NIFs.
+pub fn progress_writer(parsed: &ParsedArgs, total: usize) -> Result<Option<ProgressWriter>, String> {
+    let Some(format) = parsed.value("progress-format") else {
+        if parsed.flag("progress-interval") {
+            return Err("--progress-interval requires --progress-format".to_string());
+        }
+        return Ok(None);
+    };
+    let interval = match parsed.value("progress-interval") {
+        Some(text) => parse_duration(text)?,
+        None => DEFAULT_PROGRESS_INTERVAL,
+    };
+    Ok(Some(ProgressWriter::new(ProgressFormat::parse(format)?, interval, total)))
+}
+
+/// Checks a `--tag`: a non-empty name without spaces, commas or control characters.
+pub fn parse_tag(text: &str) -> Result<String, String> {
+    if text.is_empty() || text.chars().any(|c| c.is_whitespace() || c.is_control() || c == ',') {
+        return Err(format!("invalid tag '{}', expected a name without spaces or commas", text));
+    }
+    Ok(text.to_string())
+}
+
+/// Parses an optional numeric option, falling back to `default` when absent.
+pub fn parse_number(value: Option<&str>, name: &str, default: u64) -> Result<u64, String> {
+    match value {
+        Some(text) => text
+            .parse()
+            .map_err(|_| format!("invalid value '{}' for --{}, expected a number", text, name)),
+        None => Ok(default),
+    }
+}
+
+/// Parses the number of an option that must fit a `u32`, such as a rate, instead of
+/// letting larger values wrap around.
+pub fn parse_u32(value: Option<&str>, name: &str, default: u32) -> Result<u32, String> {
+    let number = parse_number(value, name, u64::from(default))?;
+    u32::try_from(number).map_err(|_| format!("invalid value '{}' for --{}, expected at most {}", number, name, u32::MAX))
+}
@@ -0,0 +1,512 @@
+// client.rs
+
+//! Reusable lookup client, configured once with `NifClient::builder()`.
+
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use reqwest::Certificate;
+
+use crate::breaker::CircuitBreaker;
+use crate::cache::Cache;
+use crate::cancel::{self, CancellationToken};
+use crate::fallback::Fallback;
+use crate::health::BackendHealth;
+use crate::logging::{self, display_nif, nif_field};
+use crate::lookup::{lookup_nif, FreshnessPolicy, LookupOptions, LookupResult};
+use crate::queue::{Priority, RequestQueue};
+use crate::ratelimit::Throttle;
+use crate::request_lock::RequestLock;
+use crate::retry::{RetryBudget, RetryPolicy, DEFAULT_RETRY_BUDGET_MINIMUM};
+use crate::status::NifStatus;
+use crate::store::ResultStore;
+
+/// Callback registered on a `NifClient`, called with the result of a lookup.
+type Listener = Arc<dyn Fn(&LookupResult) + Send + Sync>;
+
+/// Callbacks of a `NifClient`, called on the thread making the lookup.
+#[derive(Clone, Default)]
+struct Listeners {
+    result: Vec<Listener>,       // Every lookup, once its retries are over
+    error: Vec<Listener>,        // Lookups ending without an answer
+    rate_limited: Vec<Listener>, // Every attempt nif.pt answered with 429
+}
+
+impl Listeners {
+    fn notify(listeners: &[Listener], result: &LookupResult) {
+        for listener in listeners {
+            listener(result);
+        }
+    }
+}
+
+/// Looks NIFs up with settings fixed at build time. Clones share the HTTP connections, the
+/// cache, the rate limit and the circuit breaker, and the client can be used from several
+/// threads at once.
+#[derive(Clone)]
+pub struct NifClient {
+    options: LookupOptions,
+    retry: RetryPolicy,                     // For lookups that got no answer
+    retry_budget: Option<Arc<RetryBudget>>, // Cap on the retries of all the lookups
+    concurrency: usize,                     // Lookups made at once by `lookup_many_blocking`
+    listeners: Listeners,
+}
+
+impl NifClient {
+    pub fn builder() -> NifClientBuilder {
+        NifClientBuilder::default()
+    }
+
+    /// Client using `options` as they are, without retries.
+    pub fn from_options(options: LookupOptions) -> Self {
+        NifClient {
+            options,
+            retry: RetryPolicy::default(),
+            retry_budget: None,
+            concurrency: 1,
+            listeners: Listeners::default(),
+        }
+    }
+
+    /// Client with the default settings, shared by the whole process.
+    pub fn shared() -> &'static NifClient {
+        static SHARED: OnceLock<NifClient> = OnceLock::new();
+        SHARED.get_or_init(|| NifClient::from_options(LookupOptions::default()))
+    }
+
+    /// Copy of the client whose lookups wait for their turn with `priority`, behind the more
+    /// urgent lookups of the other copies, e.g. `Priority::Batch` for a background warm-up.
+    pub fn with_priority(&self, priority: Priority) -> NifClient {
+        let mut client = self.clone();
+        client.options.priority = priority;
+        client
+    }
+
+    /// Copy of the client whose lookups, retries included, are abandoned once `token` is
+    /// cancelled, ending in `NifStatus::Cancelled`.
+    pub fn with_cancellation(&self, token: CancellationToken) -> NifClient {
+        let mut client = self.clone();
+        client.options.cancel = Some(token);
+        client
+    }
+
+    /// Options the lookups are made with.
+    pub fn options(&self) -> &LookupOptions {
+        &self.options
+    }
+
+    /// Retry budget shared by the lookups of the client and its clones, when one is set.
+    pub fn retry_budget(&self) -> Option<&RetryBudget> {
+        self.retry_budget.as_deref()
+    }
+
+    /// Looks a NIF up, see `lookup_nif`. Lookups getting no answer (rate limiting, server
+    /// errors, network errors) are tried again up to the configured number of retries, while
+    /// the retry budget lasts.
+    pub fn lookup(&self, nif: &str) -> LookupResult {
+        if let Some(budget) = &self.retry_budget {
+            budget.record_lookup();
+        }
+        let mut result = self.attempt(nif);
+        for attempt in 1..=self.retry.retries {
+            // The history of the store gives the same answer every time
+            if !RetryPolicy::should_retry(&result.status) || self.options.as_of.is_some() {
+                break;
+            }
+            if let Some(budget) = self.retry_budget.as_ref().filter(|budget| !budget.try_retry()) {
+                let fields = [nif_field(nif), ("retries", (budget.retries() as i64).into()), ("lookups", (budget.lookups() as i64).into())];
+                let text = format!("Retry budget spent, not retrying NIF {} ({})", display_nif(nif), result.status.label());
+                if budget.exhaust() {
+                    logging::warn("retry_budget_exhausted", &fields, text);
+                } else {
+                    logging::info("retry_skipped", &fields, text);
+                }
+                break;
+            }
+            let delay = self.retry.delay(attempt);
+            logging::info(
+                "lookup_retry",
+                &[nif_field(nif), ("attempt", i64::from(attempt).into()), ("status", result.status.label().into())],
+                format!("Retrying NIF {} in {:?} ({})", display_nif(nif), delay, result.status.label()),
+            );
+            if !cancel::sleep(delay, self.options.cancel.as_ref()) {
+                result.status = NifStatus::Cancelled;
+                break;
+            }
+            result = self.attempt(nif);
+            result.report.attempts = attempt + 1;
+        }
+        Listeners::notify(&self.listeners.result, &result);
+        if !result.status.is_definitive() && !matches!(result.status, NifStatus::WrongCategory | NifStatus::Cancelled) {
+            Listeners::notify(&self.listeners.error, &result);
+        }
+        result
+    }
+
+    /// One try of `lookup`.
+    fn attempt(&self, nif: &str) -> LookupResult {
+        let result = lookup_nif(nif, &self.options);
+        if result.status == NifStatus::HttpError(429) {
+            Listeners::notify(&self.listeners.rate_limited, &result);
+        }
+        result
+    }
+
+    /// Status of a NIF, see `lookup`.
+    pub fn status(&self, nif: &str) -> NifStatus {
+        self.lookup(nif).status
+    }
+
+    /// Looks every NIF of `nifs` up, `concurrency` at a time, each as `lookup` does (rate
+    /// limit and retries included). The results come as they are ready, with the NIF as
+    /// given, so not always in the order of `nifs`.
+    ///
+    /// The NIFs are read as the lookups go, and at most `concurrency` results wait to be
+    /// taken. Dropping the iterator stops the lookups once the current ones end. Reading it
+    /// blocks the thread, so async code should read it in `spawn_blocking`.
+    pub fn lookup_many_blocking<I>(&self, nifs: I) -> LookupIter
+    where
+        I: IntoIterator<Item = String>,
+        I::IntoIter: Send + 'static,
+    {
+        let nifs = Arc::new(Mutex::new(nifs.into_iter()));
+        let (sender, results) = mpsc::sync_channel(self.concurrency);
+        for _ in 0..self.concurrency {
+            let (client, nifs, sender) = (self.clone(), Arc::clone(&nifs), sender.clone());
+            thread::spawn(move || {
+                // The lock is released before the lookup, for the other threads to go on. A
+                // poisoned one means the iterator panicked on another thread: nothing is left
+                while let Some(nif) = nifs.lock().ok().and_then(|mut nifs| nifs.next()) {
+                    let result = client.lookup(&nif);
+                    if sender.send((nif, result)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        LookupIter { results }
+    }
+}
+
+/// Results of `NifClient::lookup_many_blocking`, as the NIF given and its result. `next`
+/// waits for the next lookup to end, and gives `None` once every NIF was looked up.
+pub struct LookupIter {
+    results: Receiver<(String, LookupResult)>,
+}
+
+impl Iterator for LookupIter {
+    type Item = (String, LookupResult);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.results.recv().ok()
+    }
+}
+
+/// Settings of a `NifClient`; anything not set keeps the defaults of `LookupOptions`.
+#[derive(Default)]
+pub struct NifClientBuilder {
+    options: LookupOptions,
+    retry: RetryPolicy,
+    retry_budget: Option<f64>,
+    rate_limit: Option<u32>,
+    rate_state: Option<PathBuf>,
+    fixed_fallback_order: bool,
+    concurrency: Option<usize>,
+    listeners: Listeners,
+}
+
+impl NifClientBuilder {
+    /// Gives up waiting for a response, then for its body, after this long (30s by default).
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.options.timeouts.read = Some(timeout);
+        self
+    }
+
+    /// Gives up connecting to a site after this long (no limit by default).
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.options.timeouts.connect = Some(timeout);
+        self
+    }
+
+    /// Gives up a lookup, fallback sites included, after this long (no limit by default).
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.options.timeouts.deadline = Some(deadline);
+        self
+    }
+
+    /// Tries lookups getting no answer again, up to `retries` more times (none by default).
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retry.retries = retries;
+        self
+    }
+
+    /// Wait before the first retry, doubled before each of the next ones (1s by default).
+    pub fn retry_delay(mut self, delay: Duration) -> Self {
+        self.retry.delay = delay;
+        self
+    }
+
+    /// Caps the retries at `ratio` of the lookups (e.g. 0.1 for one in ten), counted over
+    /// every lookup of the client and its clones, plus `DEFAULT_RETRY_BUDGET_MINIMUM` to start
+    /// with; no cap by default.
+    pub fn retry_budget(mut self, ratio: f64) -> Self {
+        self.retry_budget = Some(ratio);
+        self
+    }
+
+    /// Ages at which answers of the cache and the store are reported stale, then expired, in
+    /// `LookupResult::confidence` (7 and 90 days by default).
+    pub fn freshness(mut self, stale_after: Duration, expired_after: Duration) -> Self {
+        self.options.freshness = FreshnessPolicy { stale_after, expired_after };
+        self
+    }
+
+    /// Tags every result with the campaign `tag` (none by default).
+    pub fn tag(mut self, tag: impl Into<String>) -> Self {
+        self.options.tag = Some(tag.into());
+        self
+    }
+
+    /// Lookups made at once by `lookup_many_blocking` (1 by default); they still share the rate limit.
+    pub fn concurrency(mut self, lookups: usize) -> Self {
+        self.concurrency = Some(lookups);
+        self
+    }
+
+    /// Sends at most `per_minute` requests a minute to nif.pt, evenly spaced (no limit by default).
+    pub fn rate_limit(mut self, per_minute: u32) -> Self {
+        self.rate_limit = Some(per_minute);
+        self
+    }
+
+    /// Shares the rate limit, and the waits asked by nif.pt, with the other processes using
+    /// this state file (none by default). Needs a `rate_limit`.
+    pub fn rate_state(mut self, path: impl Into<PathBuf>) -> Self {
+        self.rate_state = Some(path.into());
+        self
+    }
+
+    /// Sites asked when nif.pt gives no answer (none by default): in this order at first,
+    /// then the healthiest first, unless `fixed_fallback_order` is set.
+    pub fn fallbacks(mut self, fallbacks: impl IntoIterator<Item = Fallback>) -> Self {
+        self.options.fallbacks = fallbacks.into_iter().collect();
+        self
+    }
+
+    /// Always asks the fallback sites in the order given.
+    pub fn fixed_fallback_order(mut self) -> Self {
+        self.fixed_fallback_order = true;
+        self
+    }
+
+    /// Cache consulted before, and filled after, every remote lookup (none by default).
+    pub fn cache(mut self, cache: Arc<dyn Cache>) -> Self {
+        self.options.cache = Some(cache);
+        self
+    }
+
+    /// Local store answering lookups before the cache (none by default).
+    pub fn store(mut self, store: Arc<dyn ResultStore>) -> Self {
+        self.options.store = Some(store);
+        self
+    }
+
+    /// Stops querying nif.pt for a while after repeated failures (no breaker by default).
+    pub fn circuit_breaker(mut self, breaker: Arc<CircuitBreaker>) -> Self {
+        self.options.circuit_breaker = Some(breaker);
+        self
+    }
+
+    /// Sends the remote requests one at a time with every process sharing this lock.
+    pub fn request_lock(mut self, lock: Arc<RequestLock>) -> Self {
+        self.options.request_lock = Some(lock);
+        self
+    }
+
+    /// Sends every request through this proxy, e.g. `http://proxy.example.pt:3128`.
+    pub fn proxy(mut self, url: impl Into<String>) -> Self {
+        self.options.proxy = Some(url.into());
+        self
+    }
+
+    /// `User-Agent` header of the requests.
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.options.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Connects to `ip` whenever `host` is requested, like curl's `--resolve`.
+    pub fn resolve(mut self, host: impl Into<String>, ip: IpAddr) -> Self {
+        self.options.resolve.push((host.into(), ip));
+        self
+    }
+
+    /// Trusts this CA certificate in addition to the system ones.
+    pub fn ca_certificate(mut self, certificate: Certificate) -> Self {
+        self.options.ca_certificates.push(certificate);
+        self
+    }
+
+    /// Calls `listener` with the result of every lookup, once its retries are over, e.g. to
+    /// feed the metrics of the application.
+    pub fn on_result(mut self, listener: impl Fn(&LookupResult) + Send + Sync + 'static) -> Self {
+        self.listeners.result.push(Arc::new(listener));
+        self
+    }
+
+    /// Calls `listener` with the result of every lookup ending without an answer: HTTP errors,
+    /// network errors, open circuit breaker, unsupported page layout.
+    pub fn on_error(mut self, listener: impl Fn(&LookupResult) + Send + Sync + 'static) -> Self {
+        self.listeners.error.push(Arc::new(listener));
+        self
+    }
+
+    /// Calls `listener` each time nif.pt answers 429 Too Many Requests, retries included.
+    pub fn on_rate_limited(mut self, listener: impl Fn(&LookupResult) + Send + Sync + 'static) -> Self {
+        self.listeners.rate_limited.push(Arc::new(listener));
+        self
+    }
+
+    /// Builds the client, and its HTTP client right away so bad settings are reported here.
+    pub fn build(mut self) -> Result<NifClient, String> {
+        let concurrency = self.concurrency.unwrap_or(1);
+        if concurrency == 0 {
+            return Err("the concurrency must be at least 1 lookup".to_string());
+        }
+        if self.options.freshness.stale_after > self.options.freshness.expired_after {
+            return Err("answers cannot go stale after they expire".to_string());
+        }
+        if let Some(per_minute) = self.rate_limit {
+            if per_minute == 0 {
+                return Err("the rate limit must be at least 1 request per minute".to_string());
+            }
+            let throttle = Throttle::per_minute(per_minute);
+            let throttle = match self.rate_state {
+                Some(path) => throttle.with_state_file(path),
+                None => throttle,
+            };
+            self.options.throttle = Some(Arc::new(throttle));
+        } else if let Some(path) = &self.rate_state {
+            return Err(format!("the rate state {} needs a rate limit to share", path.display()));
+        }
+        self.options.request_queue.get_or_insert_with(|| Arc::new(RequestQueue::new()));
+        if self.options.fallbacks.len() > 1 && !self.fixed_fallback_order {
+            self.options.backend_health.get_or_insert_with(|| Arc::new(BackendHealth::default()));
+        }
+        let retry_budget = self
+            .retry_budget
+            .map(|ratio| RetryBudget::new(ratio, DEFAULT_RETRY_BUDGET_MINIMUM).map(Arc::new))
+            .transpose()?;
+        self.options.init_client()?;
+        Ok(NifClient {
+            options: self.options,
+            retry: self.retry,
+            retry_budget,
+            concurrency,
+            listeners: self.listeners,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::entity::NifEntity;
+    use crate::lookup::LookupSource;
+    use crate::store::{Store, StoreRecord};
+
+    // Web frameworks keep the client in their application state and call it from every
+    // request handler
+    fn assert_shareable<T: Send + Sync + Clone + 'static>() {}
+
+    #[test]
+    fn client_is_shareable() {
+        assert_shareable::<NifClient>();
+        assert_shareable::<Arc<NifClient>>();
+    }
+
+    /// A client answering from a store holding `nifs`, so lookups need no network.
+    fn stored_client(name: &str, nifs: &[&str], results: Arc<AtomicUsize>) -> NifClient {
+        let dir = std::env::temp_dir().join(format!("check_nif-client-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = Store::open(dir.join("store.jsonl")).unwrap();
+        let records = nifs.iter().map(|nif| StoreRecord {
+            nif: nif.to_string(),
+            status: NifStatus::ValidKnown,
+            entity: Some(NifEntity {
+                nif: nif.to_string(),
+                name: format!("Entidade {}", nif),
+                ..NifEntity::default()
+            }),
+            source: "test".to_string(),
+            recorded_at: std::time::SystemTime::now(),
+            removed_at: None,
+            tag: None,
+        });
+        store.put(records.collect()).unwrap();
+        NifClient::builder()
+            .store(Arc::new(store))
+            .concurrency(3)
+            .on_result(move |_| {
+                results.fetch_add(1, Ordering::SeqCst);
+            })
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn clones_used_from_other_threads() {
+        let results = Arc::new(AtomicUsize::new(0));
+        let client = stored_client("threads", &["500960046", "501442600"], Arc::clone(&results));
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let client = client.clone();
+                thread::spawn(move || client.lookup(if i % 2 == 0 { "500960046" } else { "PT501442600" }))
+            })
+            .collect();
+        for handle in handles {
+            let result = handle.join().unwrap();
+            assert_eq!(result.status, NifStatus::ValidKnown);
+            assert_eq!(result.source, LookupSource::Store);
+        }
+        // The listeners are shared by the clones
+        assert_eq!(results.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn lookup_many_blocking_gives_every_result() {
+        let results = Arc::new(AtomicUsize::new(0));
+        let nifs = ["500960046", "501442600", "502011378", "503504564", "504615947"];
+        let client = stored_client("many", &nifs, Arc::clone(&results));
+        let mut looked_up: Vec<(String, NifStatus)> =
+            client.lookup_many_blocking(nifs.map(String::from)).map(|(nif, result)| (nif, result.status)).collect();
+        looked_up.sort_by(|a, b| a.0.cmp(&b.0));
+        let expected: Vec<(String, NifStatus)> = nifs.iter().map(|nif| (nif.to_string(), NifStatus::ValidKnown)).collect();
+        assert_eq!(looked_up, expected);
+        assert_eq!(results.load(Ordering::SeqCst), nifs.len());
+    }
+
+    #[test]
+    fn lookup_many_blocking_stops_when_the_list_panics() {
+        let results = Arc::new(AtomicUsize::new(0));
+        let client = stored_client("many-panic", &["500960046"], Arc::clone(&results));
+        let nifs = (0..5).map(|i| if i < 1 { "500960046".to_string() } else { panic!("list broken") });
+        // Each thread ends, the one whose next panicked included, instead of the others
+        // panicking on the poisoned lock or waiting forever
+        let looked_up: Vec<String> = client.lookup_many_blocking(nifs).map(|(nif, _)| nif).collect();
+        assert_eq!(looked_up, ["500960046"]);
+    }
+
+    #[test]
+    fn rate_state_needs_rate_limit() {
+        let error = NifClient::builder().rate_state("rate.state").build().err().unwrap();
+        assert_eq!(error, "the rate state rate.state needs a rate limit to share");
+        assert!(NifClient::builder().rate_limit(30).rate_state(std::env::temp_dir().join("rate.state")).build().is_ok());
+    }
+}
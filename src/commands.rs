@@ -0,0 +1,86 @@
+// commands.rs
+
+pub mod cache;
+pub mod compare;
+pub mod generate;
+pub mod gen_docs;
+pub mod invoice;
+pub mod lambda;
+pub mod ping;
+pub mod pipe;
+pub mod reparse;
+pub mod saft;
+pub mod scan;
+pub mod schema;
+pub mod selftest;
+pub mod serve;
+pub mod store;
+pub mod verify;
+pub mod worker;
+
+use crate::cli::{self, CommandSpec};
+
+/// Runs a subcommand and returns the process exit code.
+///
+/// Exit codes: 0 on success, 1 when the command failed, 2 on invalid usage.
+pub fn run(command: &CommandSpec, program: &str, args: &[String]) -> i32 {
+    if cli::wants_help(args) {
+        print!("{}", cli::command_usage(program, command));
+        return 0;
+    }
+    let mut parsed = match cli::parse_args(args, command.options) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::command_usage(program, command));
+            return 2;
+        }
+    };
+    if let Err(e) = cli::apply_profile(&mut parsed, command.options).and_then(|_| cli::apply_log_format(&parsed)) {
+        eprintln!("{}", e);
+        return 2;
+    }
+    let result = match command.name {
+        "cache" => cache::run(&parsed),
+        "compare" => compare::run(&parsed),
+        "gen-docs" => gen_docs::run(&parsed),
+        "generate" => generate::run(&parsed),
+        "invoice" => invoice::run(&parsed),
+        "lambda" => lambda::run(&parsed),
+        "ping" => ping::run(&parsed),
+        "pipe" => pipe::run(&parsed),
+        "reparse" => reparse::run(&parsed),
+        "saft" => saft::run(&parsed),
+        "scan" => scan::run(&parsed),
+        "schema" => schema::run(&parsed),
+        "selftest" => selftest::run(&parsed),
+        "serve" => serve::run(&parsed),
+        "store" => store::run(&parsed),
+        "verify" => verify::run(&parsed),
+        "worker" => worker::run(&parsed),
+        _ => unreachable!("command {} is declared but not dispatched", command.name),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(CommandError::Usage(e)) => {
+            eprintln!("{}\n\n{}", e, cli::command_usage(program, command));
+            2
+        }
+        Err(CommandError::Failed(e)) => {
+            eprintln!("Error: {}", e);
+            1
+        }
+    }
+}
+
+/// Why a subcommand did not succeed.
+#[derive(Debug)]
+pub enum CommandError {
+    Usage(String),  // Wrong arguments, the usage text is printed
+    Failed(String), // The command ran but could not complete
+}
+
+impl From<String> for CommandError {
+    fn from(message: String) -> Self {
+        CommandError::Failed(message)
+    }
+}
@@ -0,0 +1,179 @@
+// commands/cache.rs
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::time::Duration;
+
+use check_nif::cache::{Cache, CacheEntry};
+use check_nif::csv;
+use check_nif::input::read_nif_list;
+use check_nif::ratelimit::{JobRate, Pacer};
+use check_nif::time::{format_duration, format_rfc3339, parse_duration};
+use check_nif::validation::normalize_nif;
+use check_nif::{check_nif_status_with, is_nif_valid_local, LookupOptions};
+
+use crate::cli::{self, ParsedArgs};
+use crate::commands::CommandError;
+
+/// `check_nif cache <stats|clear|prune|export|warm>`.
+pub fn run(parsed: &ParsedArgs) -> Result<(), CommandError> {
+    let action = parsed
+        .positionals
+        .first()
+        .ok_or_else(|| CommandError::Usage("missing cache action".to_string()))?;
+    let cache = cli::open_cache(parsed)?;
+    match action.as_str() {
+        "stats" => stats(cache.as_ref(), ttl(parsed)?),
+        "clear" => {
+            let removed = cache.clear()?;
+            println!("Removed {} entries from {}", removed, cache.location());
+            Ok(())
+        }
+        "prune" => {
+            let older_than = parsed
+                .value("older-than")
+                .ok_or_else(|| CommandError::Usage("prune requires --older-than".to_string()))?;
+            prune(cache.as_ref(), parse_duration(older_than)?)
+        }
+        "export" => export(cache.as_ref(), parsed.value("output")),
+        "warm" => {
+            let input = parsed
+                .value("input")
+                .ok_or_else(|| CommandError::Usage("warm requires --input".to_string()))?;
+            let rate = cli::job_rate(parsed).map_err(CommandError::Usage)?;
+            let mut options = cli::lookup_options(parsed)?;
+            options.cache = Some(cache.clone());
+            warm(cache.as_ref(), &options, &read_nif_list(input)?, rate)
+        }
+        other => Err(CommandError::Usage(format!("unknown cache action '{}'", other))),
+    }
+}
+
+fn ttl(parsed: &ParsedArgs) -> Result<Duration, String> {
+    match parsed.value("cache-ttl") {
+        Some(text) => parse_duration(text),
+        None => Ok(check_nif::cache::DEFAULT_TTL),
+    }
+}
+
+/// Prints entry counts per status, expiry and disk usage.
+fn stats(cache: &dyn Cache, ttl: Duration) -> Result<(), CommandError> {
+    let entries = cache.entries()?;
+    let mut per_status: BTreeMap<&str, usize> = BTreeMap::new();
+    for (_, entry) in &entries {
+        *per_status.entry(entry.status.label()).or_default() += 1;
+    }
+    let expired = entries.iter().filter(|(_, entry)| entry.age() >= ttl).count();
+
+    println!("Cache: {}", cache.location());
+    println!("Entries: {} ({} expired with a TTL of {})", entries.len(), expired, format_duration(ttl));
+    if let Some(size) = cache.size_on_disk() {
+        println!("Size on disk: {}", format_size(size));
+    }
+    if !per_status.is_empty() {
+        println!("By status:");
+        for (status, count) in &per_status {
+            println!("  {:<18} {}", status, count);
+        }
+    }
+    let oldest = entries.iter().map(|(_, entry)| entry.fetched_at).min();
+    let newest = entries.iter().map(|(_, entry)| entry.fetched_at).max();
+    if let (Some(oldest), Some(newest)) = (oldest, newest) {
+        println!("Oldest entry: {}", format_rfc3339(oldest));
+        println!("Newest entry: {}", format_rfc3339(newest));
+    }
+    Ok(())
+}
+
+/// Looks up every NIF missing from the cache, at the pace of `rate`.
+///
+/// NIFs are normalized first so `PT500960046` and `500960046` share one entry. NIFs
+/// failing local validation are skipped, they would only waste the rate limit.
+fn warm(cache: &dyn Cache, options: &LookupOptions, nifs: &[String], rate: JobRate) -> Result<(), CommandError> {
+    let mut pacer = Pacer::new(rate);
+    let (mut warmed, mut cached, mut invalid, mut failed) = (0, 0, 0, 0);
+
+    for nif in nifs {
+        let nif = normalize_nif(nif);
+        if !is_nif_valid_local(nif) {
+            invalid += 1;
+            continue;
+        }
+        if cache.get(nif).is_some() {
+            cached += 1;
+            continue;
+        }
+        pacer.wait();
+        let status = check_nif_status_with(nif, options);
+        pacer.record(&status);
+        if status.is_definitive() {
+            warmed += 1;
+        } else {
+            failed += 1;
+        }
+    }
+
+    println!(
+        "Warmed {} entries ({} already cached, {} locally invalid, {} failed) in {}",
+        warmed,
+        cached,
+        invalid,
+        failed,
+        cache.location()
+    );
+    if rate == JobRate::Adaptive {
+        println!("Ended at {:.0} lookups per minute", pacer.per_minute());
+    }
+    if failed > 0 {
+        return Err(CommandError::Failed(format!("{} lookups failed, run warm again later", failed)));
+    }
+    Ok(())
+}
+
+/// Deletes entries fetched longer ago than `older_than`.
+fn prune(cache: &dyn Cache, older_than: Duration) -> Result<(), CommandError> {
+    let stale: Vec<String> = cache
+        .entries()?
+        .into_iter()
+        .filter(|(_, entry)| entry.age() >= older_than)
+        .map(|(nif, _)| nif)
+        .collect();
+    let removed = cache.remove(&stale)?;
+    println!("Pruned {} entries older than {} from {}", removed, format_duration(older_than), cache.location());
+    Ok(())
+}
+
+/// Writes every entry as CSV (`nif,status,fetched_at`), sorted by NIF.
+fn export(cache: &dyn Cache, output: Option<&str>) -> Result<(), CommandError> {
+    let mut entries: Vec<(String, CacheEntry)> = cache.entries()?;
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut out: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(
+            File::create(path).map_err(|e| format!("cannot create {}: {}", path, e))?,
+        )),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    let write = |out: &mut dyn Write| -> io::Result<()> {
+        writeln!(out, "nif,status,fetched_at")?;
+        for (nif, entry) in &entries {
+            let fetched_at = format_rfc3339(entry.fetched_at);
+            writeln!(out, "{}", csv::format_record(&[nif.as_str(), entry.status.label(), &fetched_at]))?;
+        }
+        out.flush()
+    };
+    write(out.as_mut()).map_err(|e| format!("cannot write export: {}", e))?;
+    if let Some(path) = output {
+        eprintln!("Exported {} entries to {}", entries.len(), path);
+    }
+    Ok(())
+}
+
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1 << 20 => format!("{:.1} MiB", b as f64 / (1u64 << 20) as f64),
+        b if b >= 1 << 10 => format!("{:.1} KiB", b as f64 / 1024.0),
+        b => format!("{} B", b),
+    }
+}
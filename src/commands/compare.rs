@@ -0,0 +1,101 @@
+// commands/compare.rs
+
+use check_nif::compare::{compare, read_results, Difference, ResultRow};
+use check_nif::csv;
+
+use crate::cli::ParsedArgs;
+use crate::commands::CommandError;
+
+/// `check_nif compare <OLD> <NEW> [--format text|csv]`: lists the NIFs added, removed or
+/// changed between two result sets.
+pub fn run(parsed: &ParsedArgs) -> Result<(), CommandError> {
+    let [old_path, new_path] = parsed.positionals.as_slice() else {
+        return Err(CommandError::Usage("compare requires two result files".to_string()));
+    };
+    let csv = match parsed.value("format").unwrap_or("text") {
+        "text" => false,
+        "csv" => true,
+        other => return Err(CommandError::Usage(format!("unknown compare format '{}', expected text or csv", other))),
+    };
+    let old = read_results(old_path)?;
+    let new = read_results(new_path)?;
+    let differences = compare(&old, &new);
+
+    if csv {
+        println!("nif,change,old_status,new_status,old_name,new_name,old_address,new_address");
+    }
+    for difference in &differences {
+        if csv {
+            println!("{}", csv_record(difference));
+        } else {
+            println!("{}", describe(difference));
+        }
+    }
+    let count = |label| differences.iter().filter(|difference| difference.label() == label).count();
+    let (added, removed, changed) = (count("added"), count("removed"), count("changed"));
+    eprintln!(
+        "Compared {} ({} NIFs) with {} ({} NIFs): {} added, {} removed, {} changed, {} unchanged",
+        old_path,
+        old.len(),
+        new_path,
+        new.len(),
+        added,
+        removed,
+        changed,
+        new.len() - added - changed
+    );
+    Ok(())
+}
+
+/// One line per difference: `+`, `-` or `~`, the NIF, and what it is or what changed.
+fn describe(difference: &Difference) -> String {
+    match difference {
+        Difference::Added(row) => format!("+ {} {}", row.nif, summary(row)),
+        Difference::Removed(row) => format!("- {} {}", row.nif, summary(row)),
+        Difference::Changed(old, new) => {
+            let mut changes = Vec::new();
+            if old.status != new.status {
+                changes.push(format!("status {} -> {}", old.status, new.status));
+            }
+            if old.name != new.name {
+                changes.push(format!("name {} -> {}", text(&old.name), text(&new.name)));
+            }
+            if old.address != new.address {
+                changes.push(format!("address {} -> {}", text(&old.address), text(&new.address)));
+            }
+            format!("~ {} {}", new.nif, changes.join("; "))
+        }
+    }
+}
+
+fn summary(row: &ResultRow) -> String {
+    match &row.name {
+        Some(name) => format!("{} ({})", row.status, name),
+        None => row.status.clone(),
+    }
+}
+
+fn text(value: &Option<String>) -> &str {
+    value.as_deref().unwrap_or("(none)")
+}
+
+fn csv_record(difference: &Difference) -> String {
+    let (old, new) = match difference {
+        Difference::Added(row) => (None, Some(row)),
+        Difference::Removed(row) => (Some(row), None),
+        Difference::Changed(old, new) => (Some(old), Some(new)),
+    };
+    let status = |row: Option<&ResultRow>| row.map(|row| row.status.clone()).unwrap_or_default();
+    let name = |row: Option<&ResultRow>| row.and_then(|row| row.name.clone()).unwrap_or_default();
+    let address = |row: Option<&ResultRow>| row.and_then(|row| row.address.clone()).unwrap_or_default();
+    csv::format_record(&[
+        difference.nif().to_string(),
+        difference.label().to_string(),
+        status(old),
+        status(new),
+        name(old),
+        name(new),
+        address(old),
+        address(new),
+    ])
+}
@@ -0,0 +1,109 @@
+// commands/gen_docs.rs
+
+use std::fs;
+use std::path::Path;
+
+use crate::cli::{CommandSpec, OptSpec, ParsedArgs, COMMANDS, LOOKUP_OPTIONS};
+use crate::commands::CommandError;
+
+const PROGRAM: &str = env!("CARGO_PKG_NAME");
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// `check_nif gen-docs [DIR]`: writes the man pages, `check_nif.1` and one
+/// `check_nif-<command>.1` per command, from the option and command tables of the CLI.
+pub fn run(parsed: &ParsedArgs) -> Result<(), CommandError> {
+    let dir = match parsed.positionals.as_slice() {
+        [] => ".",
+        [dir] => dir.as_str(),
+        _ => return Err(CommandError::Usage("gen-docs takes one directory".to_string())),
+    };
+    fs::create_dir_all(dir).map_err(|e| format!("cannot create {}: {}", dir, e))?;
+    let mut pages = vec![(format!("{}.1", PROGRAM), main_page())];
+    pages.extend(COMMANDS.iter().map(|command| (format!("{}-{}.1", PROGRAM, command.name), command_page(command))));
+    for (name, page) in &pages {
+        let path = Path::new(dir).join(name);
+        fs::write(&path, page).map_err(|e| format!("cannot write {}: {}", path.display(), e))?;
+        println!("{}", path.display());
+    }
+    Ok(())
+}
+
+/// Page of the program: the NIF lookups, then the list of commands.
+fn main_page() -> String {
+    let mut page = header(PROGRAM, "check Portuguese NIFs locally or through nif.pt");
+    page.push_str(&format!(
+        ".SH SYNOPSIS\n.B {0}\n[\\fIOPTIONS\\fR] \\fINIF_NUMBER\\fR...\n.br\n.B {0}\n\\fICOMMAND\\fR [\\fIARGS\\fR] [\\fIOPTIONS\\fR]\n",
+        PROGRAM
+    ));
+    page.push_str(
+        ".SH DESCRIPTION\nChecks Portuguese NIFs (Número de Identificação Fiscal), either locally \
+         with the check digit algorithm or online through nif.pt, and writes one result per NIF.\n",
+    );
+    page.push_str(".SH COMMANDS\n");
+    for command in COMMANDS {
+        page.push_str(&format!(
+            ".TP\n\\fB{}\\fR {}\n{}\n.br\nSee \\fB{}\\fR(1).\n",
+            escape(command.name),
+            escape(command.args),
+            escape(command.about),
+            escape(&format!("{}-{}", PROGRAM, command.name))
+        ));
+    }
+    push_options(&mut page, LOOKUP_OPTIONS);
+    let see_also = COMMANDS.iter().map(|command| format!("{}-{}", PROGRAM, command.name)).collect();
+    push_footer(&mut page, ".TP\n128+\\fIN\\fR\nInterrupted by signal \\fIN\\fR, the NIFs left saved to resume.\n", see_also);
+    page
+}
+
+fn command_page(command: &CommandSpec) -> String {
+    let name = format!("{}-{}", PROGRAM, command.name);
+    let mut page = header(&name, command.about);
+    page.push_str(&format!(
+        ".SH SYNOPSIS\n.B {} {}\n{} [\\fIOPTIONS\\fR]\n.SH DESCRIPTION\n{}\n",
+        PROGRAM,
+        command.name,
+        escape(command.args),
+        escape(command.about)
+    ));
+    push_options(&mut page, command.options);
+    push_footer(&mut page, "", vec![PROGRAM.to_string()]);
+    page
+}
+
+/// Title line and NAME section. No date, so that packages build reproducibly.
+fn header(name: &str, summary: &str) -> String {
+    format!(
+        ".TH \"{}\" \"1\" \"\" \"{} {}\" \"User Commands\"\n.SH NAME\n{} \\- {}\n",
+        name.to_uppercase(),
+        PROGRAM,
+        VERSION,
+        escape(name),
+        escape(summary)
+    )
+}
+
+fn push_options(page: &mut String, groups: &[&[OptSpec]]) {
+    page.push_str(".SH OPTIONS\n");
+    for spec in groups.iter().flat_map(|group| group.iter()) {
+        let value = spec.value.map(|value| format!(" \\fI{}\\fR", escape(value))).unwrap_or_default();
+        page.push_str(&format!(".TP\n\\fB\\-\\-{}\\fR{}\n{}\n", escape(spec.long), value, escape(spec.help)));
+    }
+}
+
+/// EXIT STATUS and SEE ALSO sections; `more_status` holds the exit codes of this page only.
+fn push_footer(page: &mut String, more_status: &str, see_also: Vec<String>) {
+    page.push_str(".SH EXIT STATUS\n.TP\n0\nSuccess.\n.TP\n1\nThe command ran but could not complete.\n.TP\n2\nInvalid usage.\n");
+    page.push_str(more_status);
+    let references: Vec<String> = see_also.iter().map(|name| format!("\\fB{}\\fR(1)", escape(name))).collect();
+    page.push_str(&format!(".SH SEE ALSO\n{}\n", references.join(", ")));
+}
+
+/// Escapes text for roff: backslashes, dashes (kept as ASCII hyphen-minus, as options are typed),
+/// and the control characters that would start a request at the beginning of a line.
+fn escape(text: &str) -> String {
+    let escaped = text.replace('\\', "\\e").replace('-', "\\-");
+    match escaped.chars().next() {
+        Some('.' | '\'') => format!("\\&{}", escaped),
+        _ => escaped,
+    }
+}
@@ -0,0 +1,55 @@
+// commands/generate.rs
+
+use std::io::Write;
+
+use check_nif::generate::generate_nifs;
+use check_nif::logging;
+use check_nif::validation::NifCategory;
+
+use crate::cli::ParsedArgs;
+use crate::commands::CommandError;
+
+/// Most NIFs one run may generate; the categories have between ten and a few hundred
+/// million NIFs each, so drawing distinct ones stays quick below this.
+const MAX_COUNT: usize = 1_000_000;
+
+/// `check_nif generate [--count N] [--seed SEED] [--kind KIND] [--output FILE]`: writes
+/// random locally valid NIFs, one per line.
+pub fn run(parsed: &ParsedArgs) -> Result<(), CommandError> {
+    if !parsed.positionals.is_empty() {
+        return Err(CommandError::Usage("generate takes no arguments".to_string()));
+    }
+    let count = match parsed.value("count") {
+        Some(text) => text
+            .parse::<usize>()
+            .ok()
+            .filter(|count| *count <= MAX_COUNT)
+            .ok_or_else(|| CommandError::Usage(format!("invalid --count '{}', expected 0 to {}", text, MAX_COUNT)))?,
+        None => 10,
+    };
+    let seed = match parsed.value("seed") {
+        Some(text) => text.parse::<u64>().map_err(|_| CommandError::Usage(format!("invalid --seed '{}'", text)))?,
+        None => {
+            let seed = rand::random::<u64>();
+            logging::set_info_to_stderr(true); // stdout may carry the NIFs
+            logging::info(
+                "generate_seed",
+                &[("seed", seed.to_string().into())], // As a string, u64 seeds do not all fit an i64
+                format!("Seed: {} (pass --seed {} for the same NIFs again)", seed, seed),
+            );
+            seed
+        }
+    };
+    let kind = parsed.value("kind").map(NifCategory::parse).transpose().map_err(CommandError::Usage)?;
+
+    let mut text = String::new();
+    for nif in generate_nifs(count, seed, kind) {
+        text += &nif;
+        text.push('\n');
+    }
+    match parsed.value("output") {
+        Some(path) => std::fs::write(path, text).map_err(|e| format!("cannot write {}: {}", path, e))?,
+        None => std::io::stdout().write_all(text.as_bytes()).map_err(|e| format!("cannot write output: {}", e))?,
+    }
+    Ok(())
+}
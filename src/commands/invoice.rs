@@ -0,0 +1,114 @@
+// commands/invoice.rs
+
+use std::collections::HashMap;
+
+use check_nif::invoice::{self, InvoiceParty, InvoiceRole};
+use check_nif::names::names_match;
+use check_nif::saft::FINAL_CONSUMER_NIF;
+use check_nif::{is_nif_valid_local, lookup_nif, LookupOptions, LookupResult, NifStatus};
+
+use crate::cli::{self, ParsedArgs};
+use crate::commands::CommandError;
+
+/// Counters of the check of one file.
+#[derive(Debug, Default)]
+struct InvoiceStats {
+    checked: usize,
+    offending: usize,
+    foreign: usize,         // Tax IDs of parties outside Portugal, not checked
+    final_consumers: usize, // Buyers with the final consumer NIF
+    unchecked: usize,       // Locally valid NIFs whose remote lookup failed
+}
+
+/// `check_nif invoice <FILE>... [--remote]`: checks the seller and buyer tax IDs of UBL
+/// e-invoices, and with `--remote` their names against the registered ones.
+pub fn run(parsed: &ParsedArgs) -> Result<(), CommandError> {
+    if parsed.positionals.is_empty() {
+        return Err(CommandError::Usage("invoice requires at least one invoice file".to_string()));
+    }
+    let options = if parsed.flag("remote") { Some(cli::lookup_options(parsed)?) } else { None };
+    let mut remote: HashMap<String, LookupResult> = HashMap::new(); // Each NIF is looked up once
+    let mut offending = 0;
+    for path in &parsed.positionals {
+        let stats = check_file(path, options.as_ref(), &mut remote)?;
+        let mut summary = format!(
+            "{}: checked {} tax IDs, {} offending ({} foreign skipped, {} final consumers)",
+            path, stats.checked, stats.offending, stats.foreign, stats.final_consumers
+        );
+        if stats.unchecked > 0 {
+            summary += &format!(", {} not checked on nif.pt", stats.unchecked);
+        }
+        println!("{}", summary);
+        offending += stats.offending;
+    }
+    if offending > 0 {
+        return Err(CommandError::Failed(format!("{} offending tax IDs", offending)));
+    }
+    Ok(())
+}
+
+/// Checks the parties of one file, printing the offending ones.
+fn check_file(
+    path: &str,
+    options: Option<&LookupOptions>,
+    remote: &mut HashMap<String, LookupResult>,
+) -> Result<InvoiceStats, CommandError> {
+    let mut stats = InvoiceStats::default();
+    let parties = invoice::read_file(path)?;
+    if parties.is_empty() {
+        return Err(CommandError::Failed(format!("{}: no UBL invoice parties found", path)));
+    }
+    for party in parties {
+        stats.checked += 1;
+        if !party.is_portuguese() {
+            stats.foreign += 1;
+            continue;
+        }
+        let nif = party.nif();
+        let problem = if nif.is_empty() {
+            Some("no tax ID".to_string())
+        } else if nif == FINAL_CONSUMER_NIF {
+            match party.role {
+                InvoiceRole::Buyer => {
+                    stats.final_consumers += 1;
+                    None
+                }
+                InvoiceRole::Seller => Some(format!("final consumer NIF {} used for the seller", nif)),
+            }
+        } else if !is_nif_valid_local(&nif) {
+            Some(format!("NIF {} fails local validation", nif))
+        } else if let Some(options) = options {
+            let result = remote.entry(nif.clone()).or_insert_with(|| lookup_nif(&nif, options));
+            match (result.status, &result.entity, &party.name) {
+                (NifStatus::Error, _, _) => Some(format!("NIF {} is rejected by nif.pt", nif)),
+                (status, _, _) if !status.is_definitive() => {
+                    stats.unchecked += 1;
+                    None
+                }
+                (_, Some(entity), Some(name)) if !names_match(name, &entity.name) => {
+                    Some(format!("name does not match {}, registered for NIF {}", entity.name, nif))
+                }
+                _ => None,
+            }
+        } else {
+            None
+        };
+        if let Some(problem) = problem {
+            stats.offending += 1;
+            println!("{}:{}: {} {}: {}", path, party.line, describe(&party), party.path, problem);
+        }
+    }
+    Ok(stats)
+}
+
+/// Names the party of an entry, e.g. `buyer (Foo, Lda) of FT 2026/12`.
+fn describe(party: &InvoiceParty) -> String {
+    let mut text = party.role.label().to_string();
+    if let Some(name) = &party.name {
+        text += &format!(" ({})", name);
+    }
+    if let Some(invoice) = &party.invoice {
+        text += &format!(" of {}", invoice);
+    }
+    text + ","
+}
@@ -0,0 +1,33 @@
+// commands/lambda.rs
+
+#[cfg(feature = "lambda")]
+use check_nif::lambda::LambdaRuntime;
+
+#[cfg(feature = "lambda")]
+use crate::cli;
+use crate::cli::ParsedArgs;
+use crate::commands::CommandError;
+
+/// `check_nif lambda`: the custom runtime of an AWS Lambda function, answering its events.
+#[cfg(feature = "lambda")]
+pub fn run(parsed: &ParsedArgs) -> Result<(), CommandError> {
+    if let Some(extra) = parsed.positionals.first() {
+        return Err(CommandError::Usage(format!("unexpected argument '{}'", extra)));
+    }
+    let runtime = LambdaRuntime::from_env()?;
+    let options = match cli::lookup_options(parsed) {
+        Ok(options) => options,
+        Err(e) => {
+            // Lambda shows the error of the failed start instead of a bare timeout
+            runtime.init_error(&e)?;
+            return Err(CommandError::Failed(e));
+        }
+    };
+    runtime.run(&options)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "lambda"))]
+pub fn run(_parsed: &ParsedArgs) -> Result<(), CommandError> {
+    Err(CommandError::Failed("cannot run as a Lambda function: built without the lambda feature".to_string()))
+}
@@ -0,0 +1,112 @@
+// commands/ping.rs
+
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+use check_nif::server::UNIX_PREFIX;
+use check_nif::time::parse_duration;
+use check_nif::{lookup_nif, LookupSource, NifStatus};
+
+use crate::cli::{self, ParsedArgs};
+use crate::commands::CommandError;
+
+/// NIF looked up by `ping`, the first canary of `selftest`.
+const CANARY: &str = "500960046";
+
+/// How long `ping` waits without `--timeout`, below the 30 s of a Docker `HEALTHCHECK`.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `check_nif ping [--server URL]`: one cheap check, exiting 0 when it passes and 1 otherwise.
+///
+/// Without `--server` the canary NIF is looked up as usual, through the cache and store, so
+/// a healthy container answers from the cache and only reaches nif.pt when it expired.
+pub fn run(parsed: &ParsedArgs) -> Result<(), CommandError> {
+    if let Some(extra) = parsed.positionals.first() {
+        return Err(CommandError::Usage(format!("unexpected argument '{}'", extra)));
+    }
+    let timeout = match parsed.value("timeout") {
+        Some(value) => parse_duration(value).map_err(CommandError::Usage)?,
+        None => DEFAULT_TIMEOUT,
+    };
+    if timeout.is_zero() {
+        return Err(CommandError::Usage("--timeout must not be zero".to_string()));
+    }
+    match parsed.value("server") {
+        Some(server) => ping_server(server, timeout),
+        None => ping_lookup(parsed, timeout),
+    }
+}
+
+fn ping_lookup(parsed: &ParsedArgs, timeout: Duration) -> Result<(), CommandError> {
+    let mut options = cli::lookup_options(parsed)?;
+    options.timeouts.deadline = Some(options.timeouts.deadline.map_or(timeout, |deadline| deadline.min(timeout)));
+    let started = Instant::now();
+    let result = lookup_nif(CANARY, &options);
+    let elapsed = started.elapsed().as_millis();
+    let from = match result.source {
+        LookupSource::Store => "the store",
+        LookupSource::Cache => "the cache",
+        _ => result.report.backend,
+    };
+    if result.status != NifStatus::ValidKnown {
+        return Err(CommandError::Failed(format!("canary {} got {} from {} in {} ms", CANARY, result.status.label(), from, elapsed)));
+    }
+    println!("ok: canary {} answered by {} in {} ms", CANARY, from, elapsed);
+    Ok(())
+}
+
+/// Asks `GET /health` of a running `serve`, at `http://HOST:PORT` or `unix:PATH`.
+fn ping_server(server: &str, timeout: Duration) -> Result<(), CommandError> {
+    let started = Instant::now();
+    let request = b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+    let mut response = Vec::new();
+    let exchanged = if let Some(path) = server.strip_prefix(UNIX_PREFIX) {
+        exchange_unix(path, timeout, request, &mut response)
+    } else {
+        let address = server.strip_prefix("http://").unwrap_or(server).trim_end_matches('/');
+        exchange_tcp(address, timeout, request, &mut response)
+    };
+    exchanged.map_err(|e| CommandError::Failed(format!("{}: {}", server, e)))?;
+
+    let head = String::from_utf8_lossy(&response);
+    let status_line = head.lines().next().unwrap_or_default();
+    let healthy = status_line.split_whitespace().nth(1) == Some("200");
+    if !healthy {
+        return Err(CommandError::Failed(format!("{}: unhealthy, answered '{}'", server, status_line)));
+    }
+    println!("ok: {} is up ({} ms)", server, started.elapsed().as_millis());
+    Ok(())
+}
+
+fn exchange_tcp(address: &str, timeout: Duration, request: &[u8], response: &mut Vec<u8>) -> Result<(), String> {
+    let socket_address = address
+        .to_socket_addrs()
+        .map_err(|e| format!("cannot resolve: {}", e))?
+        .next()
+        .ok_or("cannot resolve: no address")?;
+    let mut stream = TcpStream::connect_timeout(&socket_address, timeout).map_err(|e| format!("cannot connect: {}", e))?;
+    stream.set_read_timeout(Some(timeout)).and_then(|_| stream.set_write_timeout(Some(timeout))).map_err(|e| e.to_string())?;
+    exchange(&mut stream, request, response)
+}
+
+#[cfg(unix)]
+fn exchange_unix(path: &str, timeout: Duration, request: &[u8], response: &mut Vec<u8>) -> Result<(), String> {
+    let mut stream = UnixStream::connect(path).map_err(|e| format!("cannot connect: {}", e))?;
+    stream.set_read_timeout(Some(timeout)).and_then(|_| stream.set_write_timeout(Some(timeout))).map_err(|e| e.to_string())?;
+    exchange(&mut stream, request, response)
+}
+
+#[cfg(not(unix))]
+fn exchange_unix(_path: &str, _timeout: Duration, _request: &[u8], _response: &mut Vec<u8>) -> Result<(), String> {
+    Err("unix sockets are not supported on this platform".to_string())
+}
+
+fn exchange(stream: &mut (impl Read + Write), request: &[u8], response: &mut Vec<u8>) -> Result<(), String> {
+    stream.write_all(request).map_err(|e| format!("cannot send: {}", e))?;
+    // The status line is enough, and comes first
+    stream.take(4096).read_to_end(response).map_err(|e| format!("no answer: {}", e))?;
+    Ok(())
+}
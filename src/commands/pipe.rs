@@ -0,0 +1,37 @@
+// commands/pipe.rs
+
+use std::io::{self, BufWriter};
+
+use check_nif::logging;
+use check_nif::pipeline::{run_pipeline, JsonPath, PipelineConfig};
+
+use crate::cli::{self, ParsedArgs};
+use crate::commands::CommandError;
+
+/// `check_nif pipe [--nif-path PATH]`: NDJSON records in on stdin, enriched records out on stdout.
+pub fn run(parsed: &ParsedArgs) -> Result<(), CommandError> {
+    if let Some(extra) = parsed.positionals.first() {
+        return Err(CommandError::Usage(format!("unexpected argument '{}'", extra)));
+    }
+    let config = PipelineConfig {
+        nif_path: JsonPath::parse(parsed.value("nif-path").unwrap_or("$.nif")).map_err(CommandError::Usage)?,
+        output_field: parsed.value("output-field").unwrap_or("check_nif").to_string(),
+        validate_only: parsed.flag("validate-only"),
+    };
+    logging::set_info_to_stderr(true); // stdout carries the records
+    let options = cli::lookup_options(parsed)?;
+    let stats = run_pipeline(io::stdin().lock(), BufWriter::new(io::stdout().lock()), &config, &options)?;
+    logging::info(
+        "pipeline_done",
+        &[
+            ("records", (stats.records as i64).into()),
+            ("missing", (stats.missing as i64).into()),
+            ("passed_through", (stats.passed_through as i64).into()),
+        ],
+        format!(
+            "Processed {} records ({} without a NIF at {}, {} lines that are not JSON objects passed through)",
+            stats.records, stats.missing, config.nif_path, stats.passed_through
+        ),
+    );
+    Ok(())
+}
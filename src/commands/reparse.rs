@@ -0,0 +1,196 @@
+// commands/reparse.rs
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use check_nif::cache::CacheEntry;
+use check_nif::config::ConfigFile;
+use check_nif::entity::same_entity;
+use check_nif::lookup::{parse_page, results_url_nif, results_url_nif_at};
+use check_nif::page_cache::{PageCache, PageEntry};
+use check_nif::{NifEntity, NifStatus};
+
+use crate::cli::{self, ParsedArgs};
+use crate::commands::CommandError;
+
+/// An answer recorded this long after its page was fetched came from the same lookup.
+const SAME_FETCH: Duration = Duration::from_secs(60);
+
+/// Source string of the store records written from nif.pt answers.
+const NIF_PT_SOURCE: &str = "nif.pt";
+
+/// A page of nif.pt kept on disk: in a page cache, or saved by `--debug-html`.
+enum Capture {
+    Cached(Arc<PageCache>, PageEntry),
+    Debug(PathBuf),
+}
+
+impl Capture {
+    fn read(&self) -> Result<String, String> {
+        match self {
+            Capture::Cached(pages, entry) => pages.read(entry),
+            Capture::Debug(path) => fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path.display(), e)),
+        }
+    }
+}
+
+/// Counters of a run.
+#[derive(Debug, Default)]
+struct ReparseStats {
+    parsed: usize,
+    cache_updates: usize,
+    store_updates: usize,
+    undecided: usize,  // Pages still giving no definitive answer
+    superseded: usize, // Pages older than the answer recorded for their NIF
+    unreadable: usize,
+}
+
+/// `check_nif reparse [DIR...] [--page-cache DIR] [--dry-run]`: parses the kept nif.pt pages
+/// again and corrects the cached answers, and the store records taken from nif.pt.
+pub fn run(parsed: &ParsedArgs) -> Result<(), CommandError> {
+    // Pages fetched from the `[backend.nifpt] base_url` of the config file are kept under its URLs
+    let base_url = match ConfigFile::find(parsed.value("config"))? {
+        Some(config) => config.backends(parsed.value("profile"))?.nif_pt.base_url,
+        None => None,
+    };
+    let base_url = base_url.as_deref();
+    let mut captures: HashMap<String, (SystemTime, Capture)> = HashMap::new();
+    let mut dirs = 0;
+    if let Some(pages) = cli::open_page_cache(parsed)? {
+        add_page_cache(&mut captures, pages, base_url)?;
+        dirs += 1;
+    }
+    for dir in &parsed.positionals {
+        let dir = Path::new(dir);
+        if dir.join("index.tsv").is_file() {
+            add_page_cache(&mut captures, Arc::new(PageCache::open(dir)?), base_url)?;
+        } else {
+            add_debug_dir(&mut captures, dir)?;
+        }
+        dirs += 1;
+    }
+    if dirs == 0 {
+        return Err(CommandError::Usage("reparse requires a page cache or --debug-html directory".to_string()));
+    }
+
+    let cache = cli::open_cache(parsed)?;
+    let store = if parsed.flag("no-store") { None } else { Some(cli::open_store(parsed)?) };
+    let dry_run = parsed.flag("dry-run");
+    let cached: HashMap<String, CacheEntry> = cache.entries()?.into_iter().collect();
+    let mut stats = ReparseStats::default();
+
+    let mut captures: Vec<(String, (SystemTime, Capture))> = captures.into_iter().collect();
+    captures.sort_by(|a, b| a.0.cmp(&b.0));
+    for (nif, (fetched_at, capture)) in captures {
+        let body = match capture.read() {
+            Ok(body) => body,
+            Err(e) => {
+                eprintln!("Warning: {}", e);
+                stats.unreadable += 1;
+                continue;
+            }
+        };
+        stats.parsed += 1;
+        let (status, entity) = parse_page(&body, &nif);
+        if !status.is_definitive() {
+            stats.undecided += 1;
+            continue;
+        }
+        let mut superseded = false;
+        match cached.get(&nif) {
+            Some(old) if old.status == status && same_entity(old.entity.as_ref(), entity.as_ref()) => {}
+            Some(old) if old.fetched_at > fetched_at + SAME_FETCH => superseded = true,
+            old => {
+                let before = old.map_or("not cached".to_string(), |old| describe(old.status, old.entity.as_ref()));
+                println!("NIF {}: {} -> {} (cache)", nif, before, describe(status, entity.as_ref()));
+                if !dry_run {
+                    cache.put(&nif, CacheEntry { status, entity: entity.clone(), fetched_at });
+                }
+                stats.cache_updates += 1;
+            }
+        }
+        // Imported records are left alone, only answers of nif.pt are corrected
+        match store.as_ref().and_then(|store| store.get(&nif)) {
+            Some(old) if old.source != NIF_PT_SOURCE => {}
+            Some(old) if old.status == status && (same_entity(old.entity.as_ref(), entity.as_ref()) || entity.is_none()) => {}
+            Some(old) if old.recorded_at > fetched_at + SAME_FETCH => superseded = true,
+            Some(old) => {
+                let after = describe(status, entity.as_ref());
+                println!("NIF {}: {} -> {} (store)", nif, describe(old.status, old.entity.as_ref()), after);
+                if !dry_run && let Some(store) = &store {
+                    store.put(vec![old.updated(status, entity, NIF_PT_SOURCE.to_string(), fetched_at)])?;
+                }
+                stats.store_updates += 1;
+            }
+            None => {}
+        }
+        stats.superseded += usize::from(superseded);
+    }
+
+    println!(
+        "Re-parsed {} pages: {} cache entries and {} store records {} ({} without a definitive answer, {} older than the recorded answer)",
+        stats.parsed,
+        stats.cache_updates,
+        stats.store_updates,
+        if dry_run { "would change" } else { "changed" },
+        stats.undecided,
+        stats.superseded
+    );
+    if stats.unreadable > 0 {
+        return Err(CommandError::Failed(format!("{} pages could not be read", stats.unreadable)));
+    }
+    Ok(())
+}
+
+/// Keeps `capture` for `nif` when it is the latest page seen of it.
+fn keep_latest(captures: &mut HashMap<String, (SystemTime, Capture)>, nif: &str, fetched_at: SystemTime, capture: Capture) {
+    match captures.get(nif) {
+        Some((latest, _)) if *latest >= fetched_at => {}
+        _ => {
+            captures.insert(nif.to_string(), (fetched_at, capture));
+        }
+    }
+}
+
+/// Adds the results pages of a page cache, from nif.pt or from the server at `base_url`.
+fn add_page_cache(captures: &mut HashMap<String, (SystemTime, Capture)>, pages: Arc<PageCache>, base_url: Option<&str>) -> Result<(), String> {
+    for entry in pages.entries()? {
+        let nif = base_url.and_then(|base_url| results_url_nif_at(base_url, &entry.url)).or_else(|| results_url_nif(&entry.url));
+        if let Some(nif) = nif {
+            let nif = nif.to_string();
+            let fetched_at = entry.stored_at;
+            keep_latest(captures, &nif, fetched_at, Capture::Cached(pages.clone(), entry));
+        }
+    }
+    Ok(())
+}
+
+/// Adds the pages saved by `--debug-html`, named `<nif>-<unix seconds>.html`.
+fn add_debug_dir(captures: &mut HashMap<String, (SystemTime, Capture)>, dir: &Path) -> Result<(), String> {
+    let listing = fs::read_dir(dir).map_err(|e| format!("cannot read {}: {}", dir.display(), e))?;
+    for item in listing {
+        let path = item.map_err(|e| format!("cannot read {}: {}", dir.display(), e))?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let parsed = name
+            .strip_suffix(".html")
+            .and_then(|stem| stem.split_once('-'))
+            .and_then(|(nif, secs)| Some((nif.to_string(), secs.parse::<u64>().ok()?)));
+        if let Some((nif, secs)) = parsed {
+            keep_latest(captures, &nif, UNIX_EPOCH + Duration::from_secs(secs), Capture::Debug(path));
+        }
+    }
+    Ok(())
+}
+
+/// Status of an answer, with the entity name when known.
+fn describe(status: NifStatus, entity: Option<&NifEntity>) -> String {
+    match entity {
+        Some(entity) => format!("{} ({})", status.label(), entity.name),
+        None => status.label().to_string(),
+    }
+}
@@ -0,0 +1,106 @@
+// commands/saft.rs
+
+use std::collections::HashMap;
+
+use check_nif::saft::{self, PartyKind, SaftParty, FINAL_CONSUMER_NIF};
+use check_nif::{is_nif_valid_local, lookup_nif, LookupOptions, NifStatus};
+
+use crate::cli::{self, ParsedArgs};
+use crate::commands::CommandError;
+
+/// Counters of the check of one file.
+#[derive(Debug, Default)]
+struct SaftStats {
+    checked: usize,
+    offending: usize,
+    foreign: usize,         // Tax IDs of parties outside Portugal, not checked
+    final_consumers: usize, // Customers with the final consumer NIF
+    unchecked: usize,       // Locally valid NIFs whose remote lookup failed
+}
+
+/// `check_nif saft <FILE>... [--remote]`: checks the tax IDs of SAF-T (PT) files before
+/// submission, printing each offending entry with its location.
+pub fn run(parsed: &ParsedArgs) -> Result<(), CommandError> {
+    if parsed.positionals.is_empty() {
+        return Err(CommandError::Usage("saft requires at least one SAF-T file".to_string()));
+    }
+    let options = if parsed.flag("remote") { Some(cli::lookup_options(parsed)?) } else { None };
+    let mut remote: HashMap<String, NifStatus> = HashMap::new(); // Each NIF is looked up once
+    let mut offending = 0;
+    for path in &parsed.positionals {
+        let stats = check_file(path, options.as_ref(), &mut remote)?;
+        let mut summary = format!(
+            "{}: checked {} tax IDs, {} offending ({} foreign skipped, {} final consumers)",
+            path, stats.checked, stats.offending, stats.foreign, stats.final_consumers
+        );
+        if stats.unchecked > 0 {
+            summary += &format!(", {} not checked on nif.pt", stats.unchecked);
+        }
+        println!("{}", summary);
+        offending += stats.offending;
+    }
+    if offending > 0 {
+        return Err(CommandError::Failed(format!("{} offending tax IDs", offending)));
+    }
+    Ok(())
+}
+
+/// Checks the tax IDs of one file, printing the offending ones.
+fn check_file(
+    path: &str,
+    options: Option<&LookupOptions>,
+    remote: &mut HashMap<String, NifStatus>,
+) -> Result<SaftStats, CommandError> {
+    let mut stats = SaftStats::default();
+    for party in saft::read_file(path)? {
+        stats.checked += 1;
+        if !party.is_portuguese() {
+            stats.foreign += 1;
+            continue;
+        }
+        let problem = if party.tax_id.is_empty() {
+            Some("no tax ID".to_string())
+        } else if party.tax_id == FINAL_CONSUMER_NIF {
+            match party.kind {
+                PartyKind::Customer => {
+                    stats.final_consumers += 1;
+                    None
+                }
+                _ => Some(format!("final consumer NIF {} used for a {}", party.tax_id, party.kind.label())),
+            }
+        } else if !is_nif_valid_local(&party.tax_id) {
+            Some(format!("NIF {} fails local validation", party.tax_id))
+        } else if let Some(options) = options {
+            let status = *remote
+                .entry(party.tax_id.clone())
+                .or_insert_with(|| lookup_nif(&party.tax_id, options).status);
+            match status {
+                NifStatus::Error => Some(format!("NIF {} is rejected by nif.pt", party.tax_id)),
+                status if !status.is_definitive() => {
+                    stats.unchecked += 1;
+                    None
+                }
+                _ => None,
+            }
+        } else {
+            None
+        };
+        if let Some(problem) = problem {
+            stats.offending += 1;
+            println!("{}:{}: {} {}: {}", path, party.line, describe(&party), party.path, problem);
+        }
+    }
+    Ok(stats)
+}
+
+/// Names the party of an entry, e.g. `customer C0003 (Foo, Lda)`.
+fn describe(party: &SaftParty) -> String {
+    let mut text = party.kind.label().to_string();
+    if let Some(id) = &party.id {
+        text += &format!(" {}", id);
+    }
+    if let Some(name) = &party.name {
+        text += &format!(" ({})", name);
+    }
+    text + ","
+}
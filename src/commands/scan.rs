@@ -0,0 +1,93 @@
+// commands/scan.rs
+
+use std::collections::HashMap;
+
+use check_nif::input::{decode_text, read_bytes};
+use check_nif::scan::{find_candidates, NifCandidate};
+use check_nif::{lookup_nif, LookupOptions, LookupResult};
+
+use crate::cli::{self, ParsedArgs};
+use crate::commands::CommandError;
+
+/// Prints the candidates found, looking them up when asked.
+struct Scanner {
+    options: Option<LookupOptions>,
+    all: bool,                             // Also print candidates failing local validation
+    remote: HashMap<String, LookupResult>, // Each NIF is looked up once
+}
+
+impl Scanner {
+    /// Prints one candidate after `location`; returns whether it is a valid NIF.
+    fn report(&mut self, location: &str, candidate: &NifCandidate) -> bool {
+        let mut line = format!("{}: {}", location, candidate.nif);
+        if !candidate.is_valid() {
+            if self.all {
+                println!("{} fails local validation", line);
+            }
+            return false;
+        }
+        if let Some(options) = &self.options {
+            let result = self
+                .remote
+                .entry(candidate.nif.clone())
+                .or_insert_with(|| lookup_nif(&candidate.nif, options));
+            line += &format!(" {}", result.status.label());
+            if let Some(entity) = &result.entity {
+                line += &format!(" ({})", entity.name);
+            }
+        }
+        println!("{}", line);
+        true
+    }
+}
+
+/// `check_nif scan [FILE...] [--remote] [--all]`: lists the NIFs found in free text, stdin
+/// when no file is given, and with `--remote` what nif.pt knows of them.
+pub fn run(parsed: &ParsedArgs) -> Result<(), CommandError> {
+    let mut scanner = Scanner {
+        options: if parsed.flag("remote") { Some(cli::lookup_options(parsed)?) } else { None },
+        all: parsed.flag("all"),
+        remote: HashMap::new(),
+    };
+    let stdin = vec!["-".to_string()];
+    let paths = if parsed.positionals.is_empty() { &stdin } else { &parsed.positionals };
+    for path in paths {
+        let bytes = read_bytes(path)?;
+        if bytes.starts_with(b"%PDF-") {
+            scan_pdf(&mut scanner, path, &bytes)?;
+            continue;
+        }
+        let candidates = find_candidates(&decode_text(bytes));
+        let mut valid = 0;
+        for candidate in &candidates {
+            let location = format!("{}:{}:{}", path, candidate.line, candidate.column);
+            valid += usize::from(scanner.report(&location, candidate));
+        }
+        println!("{}: {} candidates, {} valid NIFs", path, candidates.len(), valid);
+    }
+    Ok(())
+}
+
+/// Scans the text of each page of a PDF file.
+#[cfg(feature = "pdf")]
+fn scan_pdf(scanner: &mut Scanner, path: &str, bytes: &[u8]) -> Result<(), CommandError> {
+    let pages = check_nif::pdf::page_texts(bytes).map_err(|e| format!("{}: {}", path, e))?;
+    let (mut count, mut valid) = (0, 0);
+    for (index, text) in pages.iter().enumerate() {
+        let candidates = find_candidates(text);
+        for candidate in &candidates {
+            valid += usize::from(scanner.report(&format!("{}:page {}", path, index + 1), candidate));
+        }
+        count += candidates.len();
+    }
+    println!("{}: {} pages, {} candidates, {} valid NIFs", path, pages.len(), count, valid);
+    Ok(())
+}
+
+#[cfg(not(feature = "pdf"))]
+fn scan_pdf(_scanner: &mut Scanner, path: &str, _bytes: &[u8]) -> Result<(), CommandError> {
+    Err(CommandError::Failed(format!(
+        "{}: reading PDF files needs check_nif built with --features pdf",
+        path
+    )))
+}
@@ -0,0 +1,22 @@
+// commands/schema.rs
+
+use check_nif::proto::PROTO_DEFINITION;
+use check_nif::schema::{BATCH_SCHEMA, RESULT_SCHEMA};
+
+use crate::cli::ParsedArgs;
+use crate::commands::CommandError;
+
+/// `check_nif schema [result|batch|proto]`: prints the JSON Schema of a result (the default),
+/// or of the batch array written by `--format json`, or the protobuf definitions of
+/// `--format proto`.
+pub fn run(parsed: &ParsedArgs) -> Result<(), CommandError> {
+    let schema = match parsed.positionals.as_slice() {
+        [] => RESULT_SCHEMA,
+        [kind] if kind == "result" => RESULT_SCHEMA,
+        [kind] if kind == "batch" => BATCH_SCHEMA,
+        [kind] if kind == "proto" => PROTO_DEFINITION,
+        _ => return Err(CommandError::Usage("schema takes result, batch or proto".to_string())),
+    };
+    print!("{}", schema);
+    Ok(())
+}
@@ -0,0 +1,132 @@
+// commands/selftest.rs
+
+use std::collections::HashSet;
+
+use check_nif::entity::NifEntity;
+use check_nif::reference::reference_nifs;
+use check_nif::validation::nif_category;
+use check_nif::{lookup_nif, NifStatus};
+
+use crate::cli::{self, ParsedArgs};
+use crate::commands::CommandError;
+
+/// NIFs with a known answer, each exercising one branch of the page parser.
+const CANARIES: &[(&str, Expected)] = &[
+    ("500960046", Expected::Entity),
+    ("000000000", Expected::Status(NifStatus::MultipleResults)),
+    ("000000001", Expected::Status(NifStatus::Error)),
+];
+
+/// What a canary must parse to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Expected {
+    Entity,            // A known entity, with every address field
+    Status(NifStatus), // This status
+}
+
+/// Tells how a canary answer differs from the expected one, `None` when it matches.
+fn mismatch(expected: Expected, status: &NifStatus, entity: Option<&NifEntity>) -> Option<String> {
+    match expected {
+        Expected::Status(want) if *status != want => Some(format!("expected {}, got {}", want.label(), status.label())),
+        Expected::Status(_) => None,
+        Expected::Entity if *status != NifStatus::ValidKnown => {
+            Some(format!("expected valid_known, got {}", status.label()))
+        }
+        Expected::Entity => {
+            let Some(entity) = entity else {
+                return Some("no entity details parsed".to_string());
+            };
+            let postal_code_ok = entity.postal_code.as_deref().is_some_and(|code| {
+                code.len() == 8 && code.char_indices().all(|(i, c)| if i == 4 { c == '-' } else { c.is_ascii_digit() })
+            });
+            let missing: Vec<&str> = [
+                ("name", !entity.name.is_empty()),
+                ("address", entity.address.is_some()),
+                ("postal_code", postal_code_ok),
+                ("locality", entity.locality.is_some()),
+            ]
+            .into_iter()
+            .filter(|(_, ok)| !ok)
+            .map(|(field, _)| field)
+            .collect();
+            (!missing.is_empty()).then(|| format!("missing or malformed: {}", missing.join(", ")))
+        }
+    }
+}
+
+/// Checks the check digit and category of every reference NIF, and returns how many are wrong.
+fn check_references_locally() -> usize {
+    let mut wrong = 0;
+    for reference in reference_nifs() {
+        let category = nif_category(reference.nif);
+        if category != Some(reference.category) {
+            wrong += 1;
+            let got = category.map_or("invalid", |category| category.label());
+            println!("NIF {} ({}): FAILED locally, expected {}, got {}", reference.nif, reference.name, reference.category.label(), got);
+        }
+    }
+    wrong
+}
+
+/// `check_nif selftest [NIF...]`: checks the reference NIFs locally, then looks up the
+/// canary NIFs, plus the given NIFs of known entities (and the reference NIFs with
+/// `--reference`), and checks the parser still reads what it should from nif.pt.
+pub fn run(parsed: &ParsedArgs) -> Result<(), CommandError> {
+    let wrong = check_references_locally();
+    if wrong > 0 {
+        return Err(CommandError::Failed(format!(
+            "{} of {} reference NIFs fail the local checks, the check digit code is broken",
+            wrong,
+            reference_nifs().len()
+        )));
+    }
+    println!("All {} reference NIFs pass the local checks", reference_nifs().len());
+
+    // Straight to nif.pt, and every canary is tried even if some fail
+    let mut options = cli::lookup_options(parsed)?;
+    options.cache = None;
+    options.store = None;
+    options.circuit_breaker = None;
+
+    let mut canaries: Vec<(&str, Expected)> = CANARIES.to_vec();
+    if parsed.flag("reference") {
+        canaries.extend(reference_nifs().iter().map(|reference| (reference.nif, Expected::Entity)));
+    }
+    canaries.extend(parsed.positionals.iter().map(|nif| (nif.as_str(), Expected::Entity)));
+    let mut seen = HashSet::new();
+    canaries.retain(|(nif, _)| seen.insert(*nif)); // The first canary is a reference NIF too
+    let (mut broken, mut unreachable) = (0, 0);
+    for (nif, expected) in &canaries {
+        let result = lookup_nif(nif, &options);
+        // `unknown` is what a layout change looks like, but also a network error: see the log
+        if matches!(result.status, NifStatus::HttpError(_) | NifStatus::CircuitOpen) {
+            unreachable += 1;
+            println!("NIF {}: not checked, lookup failed ({})", nif, result.status.label());
+            continue;
+        }
+        match mismatch(*expected, &result.status, result.entity.as_ref()) {
+            None => println!("NIF {}: ok", nif),
+            Some(reason) => {
+                broken += 1;
+                println!("NIF {}: FAILED, {}", nif, reason);
+            }
+        }
+    }
+
+    if broken > 0 {
+        return Err(CommandError::Failed(format!(
+            "{} of {} canaries parsed wrong, nif.pt may have changed its layout (see --debug-html)",
+            broken,
+            canaries.len()
+        )));
+    }
+    if unreachable > 0 {
+        return Err(CommandError::Failed(format!(
+            "{} of {} canaries could not be looked up, run selftest again later",
+            unreachable,
+            canaries.len()
+        )));
+    }
+    println!("All {} canaries parsed as expected", canaries.len());
+    Ok(())
+}
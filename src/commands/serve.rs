@@ -0,0 +1,78 @@
+// commands/serve.rs
+
+use std::path::PathBuf;
+
+use check_nif::auth::ApiKeys;
+use check_nif::cors::CorsConfig;
+use check_nif::ratelimit::ClientLimits;
+use check_nif::server::{Server, ServerConfig};
+use check_nif::systemd;
+
+use crate::cli::{self, ParsedArgs};
+use crate::commands::CommandError;
+
+/// `check_nif serve [--listen ADDR:PORT|unix:PATH] [--api-key-file FILE]`.
+pub fn run(parsed: &ParsedArgs) -> Result<(), CommandError> {
+    if let Some(extra) = parsed.positionals.first() {
+        return Err(CommandError::Usage(format!("unexpected argument '{}'", extra)));
+    }
+    let listen = parsed.value("listen").unwrap_or("127.0.0.1:8080");
+
+    // Keys from the file and from the environment add up
+    let mut api_keys = match parsed.value("api-key-file") {
+        Some(path) => Some(ApiKeys::load(path)?),
+        None => None,
+    };
+    if let Some(env_keys) = ApiKeys::from_env()? {
+        match &mut api_keys {
+            Some(keys) => keys.extend(env_keys)?,
+            None => api_keys = Some(env_keys),
+        }
+    }
+    if api_keys.as_ref().is_some_and(ApiKeys::is_empty) {
+        return Err(CommandError::Failed("API keys configured, but none found".to_string()));
+    }
+
+    let mut client_limits = ClientLimits::default();
+    if let Some(rate) = parsed.value("client-rate") {
+        client_limits.per_minute = Some(cli::parse_u32(Some(rate), "client-rate", 0)?).filter(|&n| n > 0);
+    }
+    if let Some(quota) = parsed.value("client-quota") {
+        client_limits.per_day = Some(cli::parse_number(Some(quota), "client-quota", 0)?).filter(|&n| n > 0);
+    }
+
+    let origins: Vec<String> = parsed.values("cors-origin").into_iter().map(String::from).collect();
+    let methods: Vec<String> = parsed
+        .value("cors-methods")
+        .unwrap_or_default()
+        .split(',')
+        .map(|method| method.trim().to_uppercase())
+        .filter(|method| !method.is_empty())
+        .collect();
+    if origins.is_empty() && !methods.is_empty() {
+        return Err(CommandError::Usage("--cors-methods requires --cors-origin".to_string()));
+    }
+    let cors = (!origins.is_empty()).then_some(CorsConfig { origins, methods });
+
+    let authenticated = api_keys.is_some();
+    let options = cli::lookup_options(parsed)?;
+    let store = options.store.clone();
+    let config = ServerConfig {
+        api_keys,
+        client_limits,
+        cors,
+        jobs_dir: parsed.value("jobs-dir").map(PathBuf::from),
+    };
+    // Under systemd socket activation the socket is already bound, --listen does not apply
+    let server = Server::activate_or_bind(listen, options, config)?;
+    cli::spawn_pruner(parsed, store)?;
+    let address = server.local_addr()?;
+    if !address.is_local() && !authenticated {
+        eprintln!("Warning: listening on {} without API keys, anyone reaching it can use the service", address);
+    }
+    eprintln!("Listening on {}", address);
+    systemd::notify(&format!("READY=1\nSTATUS=Listening on {}", address))?;
+    systemd::spawn_watchdog();
+    server.run()?;
+    Ok(())
+}
@@ -0,0 +1,274 @@
+// commands/store.rs
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use check_nif::hooks::Hooks;
+use check_nif::csv;
+use check_nif::import;
+use check_nif::output::StatusFilter;
+use check_nif::ratelimit::{JobRate, Pacer};
+use check_nif::store::{ResultStore, StoreRecord};
+use check_nif::time::{format_duration, format_rfc3339, parse_date, parse_duration};
+use check_nif::validation::normalize_nif;
+use check_nif::{lookup_nif, LookupOptions};
+
+use crate::cli::{self, ParsedArgs};
+use crate::commands::CommandError;
+
+/// Age after which `reverify` refreshes a record when no policy is given.
+const DEFAULT_REVERIFY_AGE: Duration = Duration::from_secs(90 * 86_400);
+
+/// Columns of `store export`.
+const EXPORT_HEADER: &str =
+    "nif,status,source,recorded_at,removed_at,name,address,postal_code,locality,phone,email,district,municipality,tag";
+
+/// `check_nif store <import <FILE>...|reverify|list|export|history <NIF>|prune>`.
+pub fn run(parsed: &ParsedArgs) -> Result<(), CommandError> {
+    let action = parsed
+        .positionals
+        .first()
+        .ok_or_else(|| CommandError::Usage("missing store action".to_string()))?;
+    match action.as_str() {
+        "import" => {
+            let files = &parsed.positionals[1..];
+            if files.is_empty() {
+                return Err(CommandError::Usage("import requires at least one CSV file".to_string()));
+            }
+            let store = cli::open_store(parsed)?;
+            for path in files {
+                import_file(store.as_ref(), path, parsed)?;
+            }
+            println!("Store {} now holds {} NIFs", store.location(), store.len());
+            Ok(())
+        }
+        "reverify" => {
+            let older_than = match parsed.value("older-than").or(parsed.value("store-max-age")) {
+                Some(text) => parse_duration(text)?,
+                None => DEFAULT_REVERIFY_AGE,
+            };
+            let rate = cli::job_rate(parsed).map_err(CommandError::Usage)?;
+            let store = cli::open_store(parsed)?;
+            // Fresh answers from nif.pt only: neither the store nor the cache may answer
+            let mut options = cli::lookup_options(parsed)?;
+            options.store = None;
+            options.cache = None;
+            let hooks = cli::hooks(parsed).map_err(CommandError::Usage)?;
+            reverify(store.as_ref(), &options, &hooks, older_than, rate)
+        }
+        "list" => {
+            let store = cli::open_store(parsed)?;
+            list(&selected(store.as_ref(), parsed)?);
+            Ok(())
+        }
+        "export" => {
+            let store = cli::open_store(parsed)?;
+            export(&selected(store.as_ref(), parsed)?, parsed.value("output"))
+        }
+        "history" => {
+            let nif = parsed
+                .positionals
+                .get(1)
+                .ok_or_else(|| CommandError::Usage("history requires a NIF".to_string()))?;
+            let store = cli::open_store(parsed)?;
+            for record in store.history(normalize_nif(nif))? {
+                println!("{}", record.to_json());
+            }
+            Ok(())
+        }
+        "prune" => {
+            let mut policy = cli::retention_policy(parsed)?;
+            if policy.keep.is_none() {
+                policy.keep = parsed.value("older-than").map(parse_duration).transpose()?;
+            }
+            if policy.is_empty() {
+                return Err(CommandError::Usage("prune requires --keep or --keep-last".to_string()));
+            }
+            let store = cli::open_store(parsed)?;
+            let removed = store.prune(&policy)?;
+            println!("Removed {} superseded records from {}", removed, store.location());
+            Ok(())
+        }
+        other => Err(CommandError::Usage(format!("unknown store action '{}'", other))),
+    }
+}
+
+/// Looks up again on nif.pt every record older than `older_than`, at the pace of `rate`,
+/// oldest first.
+///
+/// Definitive answers replace the records, known entities that nif.pt no longer knows
+/// becoming tombstones; failed lookups keep the old record, to be retried by the next run.
+fn reverify(
+    store: &dyn ResultStore,
+    options: &LookupOptions,
+    hooks: &Hooks,
+    older_than: Duration,
+    rate: JobRate,
+) -> Result<(), CommandError> {
+    let mut stale: Vec<StoreRecord> = store.records().into_iter().filter(|record| record.age() >= older_than).collect();
+    stale.sort_by_key(|record| record.recorded_at);
+    println!("{} of {} records are older than {}", stale.len(), store.len(), format_duration(older_than));
+
+    let mut pacer = Pacer::new(rate);
+    let (mut refreshed, mut changed, mut failed) = (0, 0, 0);
+    for old in stale {
+        pacer.wait();
+        let result = lookup_nif(&old.nif, options);
+        pacer.record(&result.status);
+        hooks.notify(&result, Some(&old));
+        if !result.status.is_definitive() {
+            failed += 1;
+            continue;
+        }
+        let record = old.updated(result.status, result.entity, result.source.backend().to_string(), SystemTime::now());
+        if record.removed_at.is_some() && !old.is_tombstone() {
+            changed += 1;
+            println!("NIF {}: {} -> {} (removed, last details kept)", old.nif, old.status.label(), result.status.label());
+        } else if result.status != old.status {
+            changed += 1;
+            println!("NIF {}: {} -> {}", old.nif, old.status.label(), result.status.label());
+        } else if record.content_hash() != old.content_hash() {
+            changed += 1;
+            println!("NIF {}: {} (details changed)", old.nif, result.status.label());
+        }
+        store.put(vec![record])?;
+        refreshed += 1;
+    }
+
+    println!("Refreshed {} records ({} changed, {} failed)", refreshed, changed, failed);
+    if rate == JobRate::Adaptive {
+        println!("Ended at {:.0} lookups per minute", pacer.per_minute());
+    }
+    if failed > 0 {
+        return Err(CommandError::Failed(format!("{} lookups failed, run reverify again later", failed)));
+    }
+    Ok(())
+}
+
+/// Current records kept by `--status`, `--since` and `--tag`, sorted by NIF.
+fn selected(store: &dyn ResultStore, parsed: &ParsedArgs) -> Result<Vec<StoreRecord>, CommandError> {
+    let filter = StatusFilter::parse(&parsed.values("status"), &[])?;
+    let since = parsed.value("since").map(parse_date).transpose()?;
+    let tag = parsed.value("tag");
+    let mut records: Vec<StoreRecord> = store
+        .records()
+        .into_iter()
+        .filter(|record| filter.keeps(&record.status) && since.is_none_or(|since| record.recorded_at >= since))
+        .filter(|record| tag.is_none_or(|tag| record.tag.as_deref() == Some(tag)))
+        .collect();
+    records.sort_by(|a, b| a.nif.cmp(&b.nif));
+    Ok(records)
+}
+
+/// Prints one line per record: NIF, status, the day it was recorded, the entity name and tag.
+fn list(records: &[StoreRecord]) {
+    for record in records {
+        let name = record.entity.as_ref().map_or("", |entity| entity.name.as_str());
+        let removed = match record.removed_at {
+            Some(removed_at) => format!(" (removed {})", &format_rfc3339(removed_at)[..10]),
+            None => String::new(),
+        };
+        let tag = record.tag.as_ref().map(|tag| format!(" [{}]", tag)).unwrap_or_default();
+        println!(
+            "{}  {:<16} {}  {}{}{}",
+            record.nif,
+            record.status.label(),
+            &format_rfc3339(record.recorded_at)[..10],
+            name,
+            removed,
+            tag
+        );
+    }
+    println!("{} records", records.len());
+}
+
+/// Writes the records as CSV, one row per NIF with its entity details.
+fn export(records: &[StoreRecord], output: Option<&str>) -> Result<(), CommandError> {
+    let mut out: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(
+            File::create(path).map_err(|e| format!("cannot create {}: {}", path, e))?,
+        )),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    let write = |out: &mut dyn Write| -> io::Result<()> {
+        writeln!(out, "{}", EXPORT_HEADER)?;
+        for record in records {
+            let entity = record.entity.as_ref();
+            let recorded_at = format_rfc3339(record.recorded_at);
+            let removed_at = record.removed_at.map(format_rfc3339).unwrap_or_default();
+            writeln!(
+                out,
+                "{}",
+                csv::format_record(&[
+                    record.nif.as_str(),
+                    record.status.label(),
+                    &record.source,
+                    &recorded_at,
+                    &removed_at,
+                    entity.map_or("", |entity| entity.name.as_str()),
+                    entity.and_then(|entity| entity.address.as_deref()).unwrap_or_default(),
+                    entity.and_then(|entity| entity.postal_code.as_deref()).unwrap_or_default(),
+                    entity.and_then(|entity| entity.locality.as_deref()).unwrap_or_default(),
+                    entity.and_then(|entity| entity.phone.as_deref()).unwrap_or_default(),
+                    entity.and_then(|entity| entity.email.as_deref()).unwrap_or_default(),
+                    entity.and_then(|entity| entity.district.as_deref()).unwrap_or_default(),
+                    entity.and_then(|entity| entity.municipality.as_deref()).unwrap_or_default(),
+                    record.tag.as_deref().unwrap_or_default(),
+                ])
+            )?;
+        }
+        out.flush()
+    };
+    write(out.as_mut()).map_err(|e| format!("cannot write export: {}", e))?;
+    if let Some(path) = output {
+        eprintln!("Exported {} records to {}", records.len(), path);
+    }
+    Ok(())
+}
+
+/// Imports one CSV dataset, replacing the store records of the NIFs it lists.
+fn import_file(store: &dyn ResultStore, path: &str, parsed: &ParsedArgs) -> Result<(), CommandError> {
+    let delimiter = match parsed.value("delimiter") {
+        None => None,
+        Some("tab" | "\\t") => Some('\t'),
+        Some(text) if text.chars().count() == 1 => text.chars().next(),
+        Some(text) => return Err(CommandError::Usage(format!("invalid delimiter '{}', expected one character", text))),
+    };
+    let mut overrides = Vec::new();
+    for spec in parsed.values("column") {
+        let (field, header) = spec
+            .split_once('=')
+            .ok_or_else(|| CommandError::Usage(format!("invalid column mapping '{}', expected FIELD=HEADER", spec)))?;
+        if !import::FIELDS.contains(&field) {
+            return Err(CommandError::Usage(format!(
+                "unknown field '{}', expected one of {}",
+                field,
+                import::FIELDS.join(", ")
+            )));
+        }
+        overrides.push((field.to_string(), header.to_string()));
+    }
+    let source = match parsed.value("source") {
+        Some(source) => source.to_string(),
+        None => format!(
+            "import:{}",
+            Path::new(path).file_name().map_or(path.into(), |name| name.to_string_lossy())
+        ),
+    };
+
+    let file = File::open(path).map_err(|e| format!("cannot open {}: {}", path, e))?;
+    let (mut records, report) =
+        import::read_dataset(BufReader::new(file), delimiter, &overrides, &source).map_err(|e| format!("{}: {}", path, e))?;
+    let tag = parsed.value("tag").map(cli::parse_tag).transpose()?;
+    for record in &mut records {
+        record.tag = tag.clone();
+    }
+    store.put(records)?;
+    println!(
+        "Imported {} entities from {} ({} rows with an invalid NIF, {} without a NIF or name)",
+        report.imported, path, report.invalid_nif, report.incomplete
+    );
+    Ok(())
+}
@@ -0,0 +1,49 @@
+// commands/verify.rs
+
+use check_nif::output::StatusFilter;
+use check_nif::lookup_nif;
+
+use crate::cli::{self, ParsedArgs};
+use crate::commands::CommandError;
+
+/// Statuses accepted when `--require` is not given.
+const DEFAULT_REQUIRE: &str = "valid";
+
+/// `check_nif verify [NIF...] [--input FILE] [--require STATUS]`: looks the NIFs up like the
+/// default mode and fails unless every result has one of the required statuses, to gate
+/// onboarding data in CI.
+pub fn run(parsed: &ParsedArgs) -> Result<(), CommandError> {
+    let require = parsed.values("require");
+    let require = if require.is_empty() { vec![DEFAULT_REQUIRE] } else { require };
+    let filter = StatusFilter::parse(&require, &[]).map_err(|e| CommandError::Usage(format!("invalid --require: {}", e)))?;
+    let nifs = cli::batch_nifs(parsed)?;
+    if nifs.is_empty() {
+        return Err(CommandError::Usage("verify requires NIFs, as arguments or with --input".to_string()));
+    }
+    let options = cli::lookup_options(parsed)?;
+    let mut writer = cli::output_writer(parsed)?;
+
+    let mut rejected = Vec::new();
+    for nif in &nifs {
+        writer.before_lookup(nif)?;
+        let result = lookup_nif(nif, &options);
+        writer.write(&result)?;
+        if !filter.keeps(&result.status) {
+            rejected.push(format!("{} ({})", result.nif, result.status.label()));
+        }
+    }
+    writer.finish()?;
+
+    let required = require.join(",");
+    if rejected.is_empty() {
+        eprintln!("All {} NIFs are {}", nifs.len(), required);
+        return Ok(());
+    }
+    Err(CommandError::Failed(format!(
+        "{} of {} NIFs are not {}: {}",
+        rejected.len(),
+        nifs.len(),
+        required,
+        rejected.join(", ")
+    )))
+}
@@ -0,0 +1,135 @@
+// commands/worker.rs
+
+#[cfg(feature = "kafka")]
+use std::time::Duration;
+
+#[cfg(feature = "kafka")]
+use check_nif::interrupt;
+#[cfg(feature = "kafka")]
+use check_nif::json::JsonValue;
+#[cfg(feature = "kafka")]
+use check_nif::kafka::{KafkaConsumer, KafkaProducer, KafkaRecord};
+#[cfg(feature = "kafka")]
+use check_nif::logging;
+#[cfg(feature = "kafka")]
+use check_nif::lookup::lookup_nif;
+#[cfg(feature = "kafka")]
+use check_nif::validation::normalize_nif;
+
+#[cfg(feature = "kafka")]
+use crate::cli;
+use crate::cli::ParsedArgs;
+use crate::commands::CommandError;
+
+/// `check_nif worker --kafka-brokers HOSTS --request-topic TOPIC --reply-topic TOPIC`:
+/// looks up the NIFs asked on one topic and publishes the results to another, until stopped.
+#[cfg(feature = "kafka")]
+pub fn run(parsed: &ParsedArgs) -> Result<(), CommandError> {
+    if let Some(extra) = parsed.positionals.first() {
+        return Err(CommandError::Usage(format!("unexpected argument '{}'", extra)));
+    }
+    let brokers: Vec<String> = parsed
+        .value("kafka-brokers")
+        .unwrap_or_default()
+        .split(',')
+        .map(|broker| broker.trim().to_string())
+        .filter(|broker| !broker.is_empty())
+        .collect();
+    if brokers.is_empty() {
+        return Err(CommandError::Usage("--kafka-brokers is required".to_string()));
+    }
+    let request_topic = parsed.value("request-topic").unwrap_or("check_nif.requests");
+    let reply_topic = parsed.value("reply-topic").unwrap_or("check_nif.replies");
+    let group = parsed.value("group").unwrap_or("check_nif.worker");
+    let partitions = match parsed.value("partitions") {
+        Some(list) => Some(
+            list.split(',')
+                .map(|partition| partition.trim().parse::<i32>().map_err(|_| format!("invalid partition '{}'", partition.trim())))
+                .collect::<Result<Vec<i32>, String>>()
+                .map_err(CommandError::Usage)?,
+        ),
+        None => None,
+    };
+
+    let options = cli::lookup_options(parsed)?;
+    cli::spawn_pruner(parsed, options.store.clone())?;
+    let mut consumer = KafkaConsumer::new(brokers.clone(), request_topic, group, partitions)?;
+    let producer = KafkaProducer::new(brokers, reply_topic)?;
+
+    interrupt::install();
+    logging::info(
+        "worker_started",
+        &[("requests", request_topic.into()), ("replies", reply_topic.into()), ("group", group.into())],
+        format!("Reading NIF requests from {}, replying to {}", request_topic, reply_topic),
+    );
+    let mut handled = 0i64;
+    while interrupt::interrupted().is_none() {
+        for record in consumer.poll(Duration::from_secs(1))? {
+            let (key, reply, id) = match request_of(&record) {
+                Ok((nif, id)) => {
+                    let result = lookup_nif(&nif, &options);
+                    (result.nif.clone(), result.to_json(), id)
+                }
+                Err(e) => {
+                    logging::warn(
+                        "worker_invalid_request",
+                        &[("partition", i64::from(record.partition).into()), ("offset", record.offset.into())],
+                        format!("Request at {}/{}: {}", record.partition, record.offset, e),
+                    );
+                    let key = String::from_utf8_lossy(record.key.as_deref().unwrap_or_default()).into_owned();
+                    (key, JsonValue::object().with("error", e), record.header("correlation-id").map(JsonValue::from))
+                }
+            };
+            let header = id.as_ref().map(|id| match id {
+                JsonValue::String(id) => id.clone(),
+                other => other.to_string(),
+            });
+            let reply = reply.with("correlation_id", id.unwrap_or(JsonValue::Null));
+            let headers: Vec<(&str, &str)> = header.iter().map(|id| ("correlation-id", id.as_str())).collect();
+            // The request is committed once answered: after a crash it is answered again
+            producer.publish(&key, reply.to_string().as_bytes(), &headers)?;
+            consumer.commit(&record)?;
+            handled += 1;
+            if interrupt::interrupted().is_some() {
+                break;
+            }
+        }
+    }
+    logging::info(
+        "worker_stopped",
+        &[("requests", handled.into())],
+        format!("Stopped after answering {} requests", handled),
+    );
+    Ok(())
+}
+
+/// Reads a request: `{"nif": "...", "id": ...}`, or the bare NIF.
+#[cfg(feature = "kafka")]
+fn request_of(record: &KafkaRecord) -> Result<(String, Option<JsonValue>), String> {
+    let text = std::str::from_utf8(record.value.as_deref().unwrap_or_default())
+        .map_err(|_| "the request is not UTF-8".to_string())?
+        .trim();
+    let header_id = record.header("correlation-id").map(JsonValue::from);
+    if text.is_empty() {
+        return Err("the request is empty".to_string());
+    }
+    if !text.starts_with('{') {
+        let nif = normalize_nif(text);
+        if nif.len() != 9 || !nif.bytes().all(|b| b.is_ascii_digit()) {
+            return Err("the request is neither JSON nor a NIF".to_string());
+        }
+        return Ok((nif.to_string(), header_id));
+    }
+    let request = JsonValue::parse(text).map_err(|e| format!("invalid JSON request: {}", e))?;
+    let nif = match request.get("nif") {
+        Some(JsonValue::String(nif)) => nif.clone(),
+        Some(JsonValue::Int(nif)) if *nif >= 0 => format!("{:09}", nif),
+        _ => return Err("the request has no \"nif\"".to_string()),
+    };
+    Ok((nif, request.get("id").cloned().or(header_id)))
+}
+
+#[cfg(not(feature = "kafka"))]
+pub fn run(_parsed: &ParsedArgs) -> Result<(), CommandError> {
+    Err(CommandError::Failed("cannot run a worker: built without the kafka feature".to_string()))
+}
@@ -0,0 +1,136 @@
+// compare.rs
+
+use std::collections::BTreeMap;
+
+use crate::csv::{self, detect_delimiter};
+use crate::entity::NifEntity;
+use crate::input::read_text;
+use crate::json::JsonValue;
+use crate::validation::normalize_nif;
+
+/// One result of a result set, as written by the csv, json or ndjson output formats.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResultRow {
+    pub nif: String,
+    pub status: String,          // Status label, e.g. `valid_known`
+    pub name: Option<String>,    // Entity name, when known
+    pub address: Option<String>, // Entity address on one line, when known
+}
+
+/// How the result of a NIF differs between two result sets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    Added(ResultRow),              // Only in the new set
+    Removed(ResultRow),            // Only in the old set
+    Changed(ResultRow, ResultRow), // In both, with another status or other entity details
+}
+
+impl Difference {
+    pub fn nif(&self) -> &str {
+        match self {
+            Difference::Added(row) | Difference::Removed(row) | Difference::Changed(_, row) => &row.nif,
+        }
+    }
+
+    /// Lowercase name of the kind of difference, for reports.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Difference::Added(_) => "added",
+            Difference::Removed(_) => "removed",
+            Difference::Changed(..) => "changed",
+        }
+    }
+}
+
+/// Result set keyed by NIF; a NIF listed twice keeps its last result.
+pub type ResultSet = BTreeMap<String, ResultRow>;
+
+/// Reads a result set: a JSON array or NDJSON lines of result objects, or CSV with at least
+/// `nif` and `status` columns.
+pub fn parse_results(text: &str) -> Result<ResultSet, String> {
+    match text.trim_start().chars().next() {
+        Some('[') => {
+            let json = JsonValue::parse(text)?;
+            let results = json.as_array().ok_or("expected a JSON array of results")?;
+            results.iter().map(json_row).map(|row| row.map(|row| (row.nif.clone(), row))).collect()
+        }
+        Some('{') => {
+            let mut rows = ResultSet::new();
+            for (index, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+                let row = JsonValue::parse(line).and_then(|json| json_row(&json));
+                let row = row.map_err(|e| format!("line {}: {}", index + 1, e))?;
+                rows.insert(row.nif.clone(), row);
+            }
+            Ok(rows)
+        }
+        _ => csv_rows(text),
+    }
+}
+
+/// Reads a result set from disk, see `parse_results`.
+pub fn read_results(path: &str) -> Result<ResultSet, String> {
+    parse_results(&read_text(path)?).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn json_row(json: &JsonValue) -> Result<ResultRow, String> {
+    let nif = json.str_field("nif").ok_or("result without a nif")?;
+    let status = json.str_field("status").ok_or("result without a status")?;
+    let entity = json.get("entity").and_then(NifEntity::from_json);
+    Ok(ResultRow {
+        nif: normalize_nif(nif).to_string(),
+        status: status.to_string(),
+        name: entity.as_ref().map(|entity| entity.name.clone()),
+        address: entity.as_ref().and_then(NifEntity::full_address),
+    })
+}
+
+fn csv_rows(text: &str) -> Result<ResultSet, String> {
+    let header_line = text.lines().next().ok_or("empty file")?;
+    let mut reader = csv::Reader::new(text.as_bytes(), detect_delimiter(header_line));
+    let header: Vec<String> = match reader.next() {
+        Some(header) => header?.iter().map(|column| column.trim().to_lowercase()).collect(),
+        None => return Err("empty file".to_string()),
+    };
+    let column = |name: &str| header.iter().position(|column| column == name);
+    let (Some(nif), Some(status)) = (column("nif"), column("status")) else {
+        return Err("expected a CSV header with nif and status columns".to_string());
+    };
+    let (name, address) = (column("name"), column("address"));
+    let mut rows = ResultSet::new();
+    for record in reader {
+        let record = record?;
+        let get = |index: Option<usize>| {
+            index.and_then(|index| record.get(index)).map(|value| value.trim()).filter(|value| !value.is_empty())
+        };
+        let Some(nif) = get(Some(nif)) else {
+            continue; // Blank line
+        };
+        let row = ResultRow {
+            nif: normalize_nif(nif).to_string(),
+            status: get(Some(status)).unwrap_or_default().to_string(),
+            name: get(name).map(str::to_string),
+            address: get(address).map(str::to_string),
+        };
+        rows.insert(row.nif.clone(), row);
+    }
+    Ok(rows)
+}
+
+/// Differences between two result sets, by NIF.
+pub fn compare(old: &ResultSet, new: &ResultSet) -> Vec<Difference> {
+    let mut differences = Vec::new();
+    for (nif, old_row) in old {
+        match new.get(nif) {
+            None => differences.push(Difference::Removed(old_row.clone())),
+            Some(new_row) if new_row != old_row => differences.push(Difference::Changed(old_row.clone(), new_row.clone())),
+            Some(_) => {}
+        }
+    }
+    for (nif, new_row) in new {
+        if !old.contains_key(nif) {
+            differences.push(Difference::Added(new_row.clone()));
+        }
+    }
+    differences.sort_by(|a, b| a.nif().cmp(b.nif()));
+    differences
+}
@@ -0,0 +1,400 @@
+// config.rs
+
+//! Config file of the command line: `--config FILE`, else `CHECK_NIF_CONFIG`, else
+//! `$XDG_CONFIG_HOME/check_nif/config.toml` when it exists. A subset of TOML: `[SECTION]`
+//! tables of `key = value` lines, whose values are strings, integers or booleans.
+//!
+//! `[profile.NAME]` tables bundle command-line options, picked with `--profile NAME`;
+//! `[profile.NAME.backend.SITE]` tables override the backend settings for that profile.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::lookup::{BackendOptions, BackendSettings};
+use crate::ratelimit::Throttle;
+use crate::time::parse_duration;
+
+/// Environment variable naming the config file, when `--config` is not given.
+pub const CONFIG_ENV: &str = "CHECK_NIF_CONFIG";
+
+/// A value of the config file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigValue {
+    String(String),
+    Int(i64),
+    Bool(bool),
+}
+
+/// One `key = value` line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigEntry {
+    pub key: String,
+    pub value: ConfigValue,
+    pub line: usize, // 1-based, for error messages
+}
+
+/// One `[name]` table and its entries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigSection {
+    pub name: String,
+    pub line: usize,
+    pub entries: Vec<ConfigEntry>,
+}
+
+/// A parsed config file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigFile {
+    pub path: PathBuf,
+    pub sections: Vec<ConfigSection>,
+}
+
+/// Default location of the config file: `$XDG_CONFIG_HOME/check_nif/config.toml`,
+/// falling back to `~/.config/check_nif/config.toml`.
+pub fn default_config_path() -> PathBuf {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .unwrap_or_else(std::env::temp_dir);
+    base.join("check_nif").join("config.toml")
+}
+
+impl ConfigFile {
+    /// Loads the config file given with `--config`, else the one in `CHECK_NIF_CONFIG`, else
+    /// the default one if it exists. Only the default file may be missing.
+    pub fn find(explicit: Option<&str>) -> Result<Option<Self>, String> {
+        if let Some(path) = explicit {
+            return Self::load(Path::new(path)).map(Some);
+        }
+        if let Some(path) = std::env::var_os(CONFIG_ENV).filter(|path| !path.is_empty()) {
+            return Self::load(Path::new(&path)).map(Some);
+        }
+        let path = default_config_path();
+        if !path.exists() {
+            return Ok(None);
+        }
+        Self::load(&path).map(Some)
+    }
+
+    /// Reads and parses a config file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        Self::parse(path, &text)
+    }
+
+    /// Parses the text of a config file; `path` is only used in error messages.
+    pub fn parse(path: &Path, text: &str) -> Result<Self, String> {
+        let mut config = ConfigFile {
+            path: path.to_path_buf(),
+            sections: Vec::new(),
+        };
+        for (index, raw) in text.lines().enumerate() {
+            let line = index + 1;
+            let content = strip_comment(raw).trim();
+            if content.is_empty() {
+                continue;
+            }
+            if let Some(name) = content.strip_prefix('[') {
+                let name = name.strip_suffix(']').ok_or_else(|| config.error(line, "expected ']' after the section name"))?;
+                let name = name.trim();
+                if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')) {
+                    return Err(config.error(line, &format!("invalid section name '{}'", name)));
+                }
+                if config.sections.iter().any(|section| section.name == name) {
+                    return Err(config.error(line, &format!("section [{}] is given twice", name)));
+                }
+                config.sections.push(ConfigSection {
+                    name: name.to_string(),
+                    line,
+                    entries: Vec::new(),
+                });
+                continue;
+            }
+            let (key, value) = content.split_once('=').ok_or_else(|| config.error(line, "expected key = value"))?;
+            let key = key.trim();
+            if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-')) {
+                return Err(config.error(line, &format!("invalid key '{}'", key)));
+            }
+            let value = parse_value(value.trim()).map_err(|e| config.error(line, &e))?;
+            let Some(section) = config.sections.last() else {
+                return Err(config.error(line, "settings must be in a [section]"));
+            };
+            if section.entries.iter().any(|entry| entry.key == key) {
+                return Err(config.error(line, &format!("{} is given twice in [{}]", key, section.name)));
+            }
+            let section = config.sections.last_mut().unwrap();
+            section.entries.push(ConfigEntry {
+                key: key.to_string(),
+                value,
+                line,
+            });
+        }
+        Ok(config)
+    }
+
+    /// Returns the section called `name`.
+    pub fn section(&self, name: &str) -> Option<&ConfigSection> {
+        self.sections.iter().find(|section| section.name == name)
+    }
+
+    /// Returns the `[profile.NAME]` section.
+    pub fn profile(&self, name: &str) -> Result<&ConfigSection, String> {
+        self.section(&format!("profile.{}", name)).ok_or_else(|| {
+            let names: Vec<&str> = self.sections.iter().filter_map(|section| profile_name(&section.name)).collect();
+            match names.is_empty() {
+                true => format!("{} has no profiles, expected a [profile.{}] section", self.path.display(), name),
+                false => format!("unknown profile '{}' in {}, expected one of {}", name, self.path.display(), names.join(", ")),
+            }
+        })
+    }
+
+    /// Reads the `[backend.nifpt]`, `[backend.racius]` and `[backend.einforma]` sections,
+    /// with the `[profile.NAME.backend.…]` settings of `profile` taking precedence.
+    pub fn backends(&self, profile: Option<&str>) -> Result<BackendSettings, String> {
+        for section in &self.sections {
+            let backend = match section.name.strip_prefix("profile.") {
+                Some(rest) => rest.split_once('.').map(|(_, backend)| backend),
+                None => Some(section.name.as_str()),
+            };
+            if backend.is_some_and(|backend| !BACKEND_SECTIONS.contains(&backend)) {
+                let expected = BACKEND_SECTIONS.iter().map(|name| format!("[{}]", name)).collect::<Vec<_>>().join(", ");
+                return Err(self.error(
+                    section.line,
+                    &format!("unknown section [{}], expected {} or [profile.NAME]", section.name, expected),
+                ));
+            }
+        }
+        let backend = |name: &str| self.backend(name, profile.map(|profile| format!("profile.{}.{}", profile, name)));
+        Ok(BackendSettings {
+            nif_pt: backend("backend.nifpt")?,
+            racius: backend("backend.racius")?,
+            einforma: backend("backend.einforma")?,
+        })
+    }
+
+    /// Reads the settings of one backend, from `name` then from the `overrides` section.
+    fn backend(&self, name: &str, overrides: Option<String>) -> Result<BackendOptions, String> {
+        let mut backend = BackendOptions::default();
+        let sections = [Some(name), overrides.as_deref()].into_iter().flatten().filter_map(|name| self.section(name));
+        for (section, entry) in sections.flat_map(|section| section.entries.iter().map(move |entry| (section, entry))) {
+            let name = section.name.as_str();
+            let error = |message: String| self.error(entry.line, &message);
+            match (entry.key.as_str(), &entry.value) {
+                ("base_url", ConfigValue::String(url)) => {
+                    if !url.starts_with("https://") && !url.starts_with("http://") {
+                        return Err(error(format!("base_url must start with https:// or http://, got '{}'", url)));
+                    }
+                    backend.base_url = Some(url.trim_end_matches('/').to_string());
+                }
+                ("timeout", ConfigValue::String(text)) => match parse_duration(text).map_err(error)? {
+                    Duration::ZERO => return Err(self.error(entry.line, &format!("the timeout of [{}] must be more than 0", name))),
+                    timeout => backend.timeout = Some(timeout),
+                },
+                ("timeout", ConfigValue::Int(secs)) if *secs > 0 => backend.timeout = Some(Duration::from_secs(*secs as u64)),
+                ("rate_limit", ConfigValue::Int(per_minute)) => {
+                    let Ok(per_minute @ 1..) = u32::try_from(*per_minute) else {
+                        return Err(error("invalid rate_limit, expected requests per minute, at least 1".to_string()));
+                    };
+                    backend.throttle = Some(Arc::new(Throttle::per_minute(per_minute)));
+                }
+                ("enabled", ConfigValue::Bool(enabled)) => backend.enabled = Some(*enabled),
+                ("base_url" | "timeout" | "rate_limit" | "enabled", _) => {
+                    let expected = match entry.key.as_str() {
+                        "base_url" => "a URL string",
+                        "timeout" => "a duration such as \"10s\", or seconds",
+                        "rate_limit" => "requests per minute, at least 1",
+                        _ => "true or false",
+                    };
+                    return Err(error(format!("invalid {}, expected {}", entry.key, expected)));
+                }
+                (key, _) => {
+                    return Err(error(format!("unknown setting {} in [{}], expected base_url, timeout, rate_limit or enabled", key, name)));
+                }
+            }
+        }
+        Ok(backend)
+    }
+
+    fn error(&self, line: usize, message: &str) -> String {
+        format!("{}:{}: {}", self.path.display(), line, message)
+    }
+}
+
+/// Sections of the per-backend settings.
+const BACKEND_SECTIONS: &[&str] = &["backend.nifpt", "backend.racius", "backend.einforma"];
+
+/// Name of the profile of a `[profile.NAME]` section, not of its backend tables.
+fn profile_name(section: &str) -> Option<&str> {
+    section.strip_prefix("profile.").filter(|name| !name.contains('.'))
+}
+
+/// Drops a `#` comment, unless it is inside a string.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_value(text: &str) -> Result<ConfigValue, String> {
+    match text {
+        "true" => return Ok(ConfigValue::Bool(true)),
+        "false" => return Ok(ConfigValue::Bool(false)),
+        _ => {}
+    }
+    if let Some(quoted) = text.strip_prefix('"') {
+        let inner = quoted.strip_suffix('"').ok_or("unterminated string")?;
+        let mut value = String::new();
+        let mut chars = inner.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    other => return Err(format!("unsupported escape \\{}", other.map(String::from).unwrap_or_default())),
+                },
+                '"' => return Err("unexpected '\"' inside a string".to_string()),
+                c => value.push(c),
+            }
+        }
+        return Ok(ConfigValue::String(value));
+    }
+    let digits = text.replace('_', "");
+    digits
+        .parse::<i64>()
+        .map(ConfigValue::Int)
+        .map_err(|_| format!("invalid value '{}', expected a \"string\", an integer, true or false", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<ConfigFile, String> {
+        ConfigFile::parse(Path::new("config.toml"), text)
+    }
+
+    fn entry(key: &str, value: ConfigValue, line: usize) -> ConfigEntry {
+        ConfigEntry {
+            key: key.to_string(),
+            value,
+            line,
+        }
+    }
+
+    #[test]
+    fn sections_and_values() {
+        let config = parse(
+            "# check_nif settings\n\
+             [backend.nifpt]\n\
+             base_url = \"https://nif.example.pt/\"  # a mirror\n\
+             \n\
+             rate_limit = 1_000\n\
+             [profile.nightly]\n\
+             enabled = false\n\
+             tag = \"a \\\"quoted\\\" # not a comment\\n\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.sections.len(), 2);
+        assert_eq!(
+            config.section("backend.nifpt").unwrap().entries,
+            vec![
+                entry("base_url", ConfigValue::String("https://nif.example.pt/".to_string()), 3),
+                entry("rate_limit", ConfigValue::Int(1000), 5),
+            ]
+        );
+        let profile = config.profile("nightly").unwrap();
+        assert_eq!(profile.line, 6);
+        assert_eq!(
+            profile.entries,
+            vec![
+                entry("enabled", ConfigValue::Bool(false), 7),
+                entry("tag", ConfigValue::String("a \"quoted\" # not a comment\n".to_string()), 8),
+            ]
+        );
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let error = |text: &str| parse(text).unwrap_err();
+        assert_eq!(error("key = 1"), "config.toml:1: settings must be in a [section]");
+        assert_eq!(error("[a]\n[a]"), "config.toml:2: section [a] is given twice");
+        assert_eq!(error("[a]\nkey = 1\nkey = 2"), "config.toml:3: key is given twice in [a]");
+        assert_eq!(error("[a"), "config.toml:1: expected ']' after the section name");
+        assert_eq!(error("[a b]"), "config.toml:1: invalid section name 'a b'");
+        assert_eq!(error("[a]\njust text"), "config.toml:2: expected key = value");
+        assert_eq!(error("[a]\nkey = \"open"), "config.toml:2: unterminated string");
+        assert_eq!(error("[a]\nkey = \"\\x\""), "config.toml:2: unsupported escape \\x");
+        assert_eq!(error("[a]\nkey = 1.5"), "config.toml:2: invalid value '1.5', expected a \"string\", an integer, true or false");
+        assert_eq!(error("[a]\nthe key = 1"), "config.toml:2: invalid key 'the key'");
+    }
+
+    #[test]
+    fn unknown_profile() {
+        let config = parse("[profile.day]\n[profile.day.backend.nifpt]\n[profile.night]").unwrap();
+        assert_eq!(config.profile("weekend").unwrap_err(), "unknown profile 'weekend' in config.toml, expected one of day, night");
+        let config = parse("[backend.nifpt]").unwrap();
+        assert_eq!(config.profile("day").unwrap_err(), "config.toml has no profiles, expected a [profile.day] section");
+    }
+
+    #[test]
+    fn backends_with_profile_overrides() {
+        let config = parse(
+            "[backend.nifpt]\n\
+             base_url = \"http://127.0.0.1:8080/\"\n\
+             timeout = \"10s\"\n\
+             [backend.racius]\n\
+             enabled = false\n\
+             [profile.slow.backend.nifpt]\n\
+             timeout = 60\n\
+             rate_limit = 5\n",
+        )
+        .unwrap();
+        let backends = config.backends(None).unwrap();
+        assert_eq!(backends.nif_pt.base_url.as_deref(), Some("http://127.0.0.1:8080"));
+        assert_eq!(backends.nif_pt.timeout, Some(Duration::from_secs(10)));
+        assert!(backends.nif_pt.throttle.is_none());
+        assert_eq!(backends.racius.enabled, Some(false));
+        assert_eq!(backends.einforma.enabled, None);
+
+        let backends = config.backends(Some("slow")).unwrap();
+        assert_eq!(backends.nif_pt.base_url.as_deref(), Some("http://127.0.0.1:8080"));
+        assert_eq!(backends.nif_pt.timeout, Some(Duration::from_secs(60)));
+        assert!(backends.nif_pt.throttle.is_some());
+    }
+
+    #[test]
+    fn invalid_backend_settings() {
+        let error = |text: &str| parse(text).unwrap().backends(None).unwrap_err();
+        assert_eq!(
+            error("[backend.other]"),
+            "config.toml:1: unknown section [backend.other], expected [backend.nifpt], [backend.racius], [backend.einforma] or [profile.NAME]"
+        );
+        assert_eq!(
+            error("[backend.nifpt]\nbase_url = \"ftp://nif.pt\""),
+            "config.toml:2: base_url must start with https:// or http://, got 'ftp://nif.pt'"
+        );
+        assert_eq!(error("[backend.nifpt]\ntimeout = 0"), "config.toml:2: invalid timeout, expected a duration such as \"10s\", or seconds");
+        assert_eq!(error("[backend.nifpt]\ntimeout = \"0s\""), "config.toml:2: the timeout of [backend.nifpt] must be more than 0");
+        assert_eq!(error("[backend.nifpt]\nrate_limit = 0"), "config.toml:2: invalid rate_limit, expected requests per minute, at least 1");
+        assert_eq!(error("[backend.nifpt]\nrate_limit = 4294967296"), "config.toml:2: invalid rate_limit, expected requests per minute, at least 1");
+        assert_eq!(error("[backend.racius]\nenabled = \"yes\""), "config.toml:2: invalid enabled, expected true or false");
+        assert_eq!(
+            error("[backend.nifpt]\nretries = 3"),
+            "config.toml:2: unknown setting retries in [backend.nifpt], expected base_url, timeout, rate_limit or enabled"
+        );
+    }
+}
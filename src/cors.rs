@@ -0,0 +1,69 @@
+// cors.rs
+
+use crate::http::{Request, Response};
+
+/// Request headers browsers may send cross-origin.
+const ALLOWED_HEADERS: &str = "Authorization, Content-Type, X-Api-Key, X-Request-Id";
+
+/// Response headers scripts may read cross-origin.
+const EXPOSED_HEADERS: &str = "Retry-After, X-Request-Id";
+
+/// Cross-origin (CORS) policy of the server, letting browser frontends call it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CorsConfig {
+    pub origins: Vec<String>, // Allowed origins, e.g. `https://intranet.example.pt`, or `*`
+    pub methods: Vec<String>, // Allowed methods; GET when empty
+}
+
+impl CorsConfig {
+    /// Tells whether requests from `origin` are allowed.
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.origins.iter().any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+    }
+
+    fn methods(&self) -> String {
+        if self.methods.is_empty() {
+            "GET".to_string()
+        } else {
+            self.methods.join(", ")
+        }
+    }
+
+    /// Tells whether `request` is a CORS preflight, to be answered by `preflight`.
+    pub fn is_preflight(request: &Request) -> bool {
+        request.method == "OPTIONS"
+            && request.header("origin").is_some()
+            && request.header("access-control-request-method").is_some()
+    }
+
+    /// Answers a preflight request: 204 with the policy when the origin and method are
+    /// allowed, 403 otherwise.
+    pub fn preflight(&self, request: &Request) -> Response {
+        let origin = request.header("origin").unwrap_or_default();
+        let method = request.header("access-control-request-method").unwrap_or_default();
+        let method_allowed = self.methods().split(", ").any(|allowed| allowed.eq_ignore_ascii_case(method));
+        if !self.allows_origin(origin) || !method_allowed {
+            return Response::error(403, "CORS request not allowed").with_header("Vary", "Origin");
+        }
+        Response::empty(204)
+        .with_header("Access-Control-Allow-Origin", origin)
+        .with_header("Access-Control-Allow-Methods", self.methods())
+        .with_header("Access-Control-Allow-Headers", ALLOWED_HEADERS)
+        .with_header("Access-Control-Max-Age", "600")
+        .with_header("Vary", "Origin")
+    }
+
+    /// Adds the CORS headers to the response of an allowed cross-origin request.
+    pub fn apply(&self, request: &Request, response: Response) -> Response {
+        if response.headers.iter().any(|(name, _)| name == "Access-Control-Allow-Origin") {
+            return response; // Preflight answer, already complete
+        }
+        match request.header("origin") {
+            Some(origin) if self.allows_origin(origin) => response
+                .with_header("Access-Control-Allow-Origin", origin)
+                .with_header("Access-Control-Expose-Headers", EXPOSED_HEADERS)
+                .with_header("Vary", "Origin"),
+            _ => response,
+        }
+    }
+}
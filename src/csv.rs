@@ -0,0 +1,116 @@
+// csv.rs
+
+/// Quotes a CSV field when it contains a separator, a quote or a line break (RFC 4180).
+pub fn escape_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Joins fields into one CSV record, without the line terminator.
+pub fn format_record<S: AsRef<str>>(fields: &[S]) -> String {
+    fields
+        .iter()
+        .map(|field| escape_field(field.as_ref()))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Streaming CSV reader yielding one record (a vector of fields) at a time.
+///
+/// Quoted fields may span lines. Lines that are not valid UTF-8 are decoded as
+/// Latin-1, the usual encoding of spreadsheets exported on Portuguese Windows setups.
+pub struct Reader<R> {
+    input: R,
+    delimiter: char,
+    line_number: usize,
+}
+
+impl<R: std::io::BufRead> Reader<R> {
+    pub fn new(input: R, delimiter: char) -> Self {
+        Reader {
+            input,
+            delimiter,
+            line_number: 0,
+        }
+    }
+
+    /// Line number where the last returned record ended.
+    pub fn line_number(&self) -> usize {
+        self.line_number
+    }
+
+    fn read_line(&mut self) -> Result<Option<String>, String> {
+        let mut bytes = Vec::new();
+        let read = self
+            .input
+            .read_until(b'\n', &mut bytes)
+            .map_err(|e| format!("cannot read line {}: {}", self.line_number + 1, e))?;
+        if read == 0 {
+            return Ok(None);
+        }
+        self.line_number += 1;
+        let mut line = match String::from_utf8(bytes) {
+            Ok(line) => line,
+            Err(e) => e.into_bytes().iter().map(|&b| b as char).collect(),
+        };
+        if self.line_number == 1 && line.starts_with('\u{feff}') {
+            line.remove(0); // Byte order mark
+        }
+        Ok(Some(line))
+    }
+}
+
+impl<R: std::io::BufRead> Iterator for Reader<R> {
+    type Item = Result<Vec<String>, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut in_quotes = false;
+        let mut started = false;
+        loop {
+            let line = match self.read_line() {
+                Ok(Some(line)) => line,
+                Ok(None) if !started => return None,
+                Ok(None) if in_quotes => {
+                    return Some(Err(format!("unterminated quoted field at line {}", self.line_number)));
+                }
+                Ok(None) => break,
+                Err(e) => return Some(Err(e)),
+            };
+            started = true;
+            let mut chars = line.chars().peekable();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' if in_quotes && chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    '"' if in_quotes => in_quotes = false,
+                    '"' if field.is_empty() => in_quotes = true,
+                    c if in_quotes => field.push(c),
+                    c if c == self.delimiter => fields.push(std::mem::take(&mut field)),
+                    '\r' | '\n' => {}
+                    c => field.push(c),
+                }
+            }
+            if !in_quotes {
+                break;
+            }
+        }
+        fields.push(field);
+        Some(Ok(fields))
+    }
+}
+
+/// Guesses the delimiter of a header line: the most frequent of `,`, `;` and tab.
+pub fn detect_delimiter(header: &str) -> char {
+    // Reversed, since `max_by_key` keeps the last of equal counts and commas should win ties
+    ['\t', ';', ',']
+        .into_iter()
+        .max_by_key(|&d| header.matches(d).count())
+        .unwrap()
+}
@@ -0,0 +1,113 @@
+// dashboard.rs
+
+//! Web dashboard of `serve`, at `/ui`: a static page polling `/ui/status` for the recent
+//! lookups, the cache hit rate and the health of the backends, with a form to check a NIF.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::breaker::CircuitBreaker;
+use crate::json::JsonValue;
+use crate::lookup::{LookupResult, LookupSource};
+use crate::time::unix_secs;
+
+/// The dashboard page; it only talks to the server through its API.
+pub const DASHBOARD_PAGE: &str = include_str!("../ui/dashboard.html");
+
+/// Lookups kept for the list of recent lookups.
+const RECENT_LOOKUPS: usize = 50;
+
+#[derive(Debug)]
+struct RecentLookup {
+    owner: Option<String>, // API key name of the client, who alone sees the lookup
+    at: SystemTime,
+    result: JsonValue,
+}
+
+/// Answers of one backend since the server started.
+#[derive(Debug, Default)]
+struct BackendStats {
+    lookups: u64,
+    answered: u64, // With a definitive status
+    last_status: &'static str,
+    last_at: Option<SystemTime>,
+}
+
+#[derive(Debug, Default)]
+struct HistoryInner {
+    recent: VecDeque<RecentLookup>,
+    lookups: u64,    // Lookups needing an answer from elsewhere than the check digit
+    cache_hits: u64, // Of those, answered by the store or the cache
+    backends: BTreeMap<&'static str, BackendStats>,
+}
+
+/// What the dashboard shows of the lookups made by the server.
+#[derive(Debug, Default)]
+pub struct LookupHistory {
+    inner: Mutex<HistoryInner>,
+}
+
+impl LookupHistory {
+    /// Records a lookup made for the client with this API key name.
+    pub fn record(&self, owner: &Option<String>, result: &LookupResult) {
+        let now = SystemTime::now();
+        let mut inner = self.inner.lock().unwrap();
+        if inner.recent.len() == RECENT_LOOKUPS {
+            inner.recent.pop_back();
+        }
+        inner.recent.push_front(RecentLookup {
+            owner: owner.clone(),
+            at: now,
+            result: result.to_json(),
+        });
+        if result.source == LookupSource::Local {
+            return;
+        }
+        inner.lookups += 1;
+        if result.report.cache_hit {
+            inner.cache_hits += 1;
+            return;
+        }
+        let backend = inner.backends.entry(result.report.backend).or_default();
+        backend.lookups += 1;
+        backend.answered += u64::from(result.status.is_definitive());
+        backend.last_status = result.status.label();
+        backend.last_at = Some(now);
+    }
+
+    /// State shown by the dashboard to the client with this API key name: its own recent
+    /// lookups, and the figures of the whole server.
+    pub fn to_json(&self, owner: &Option<String>, breaker: Option<&CircuitBreaker>) -> JsonValue {
+        let inner = self.inner.lock().unwrap();
+        let recent: Vec<JsonValue> = inner
+            .recent
+            .iter()
+            .filter(|lookup| lookup.owner == *owner)
+            .map(|lookup| JsonValue::object().with("at", unix_secs(lookup.at)).with("result", lookup.result.clone()))
+            .collect();
+        let hit_rate = (inner.lookups > 0).then(|| inner.cache_hits as f64 / inner.lookups as f64);
+        let backends: Vec<JsonValue> = inner
+            .backends
+            .iter()
+            .map(|(name, stats)| {
+                let mut json = JsonValue::object()
+                    .with("name", *name)
+                    .with("lookups", stats.lookups as i64)
+                    .with("answered", stats.answered as i64)
+                    .with("last_status", stats.last_status)
+                    .with("last_at", stats.last_at.map(unix_secs));
+                if *name == "nif.pt" {
+                    json = json.with("breaker", breaker.map(|breaker| breaker.state().label()));
+                }
+                json
+            })
+            .collect();
+        JsonValue::object()
+            .with("recent", recent)
+            .with("lookups", inner.lookups as i64)
+            .with("cache_hits", inner.cache_hits as i64)
+            .with("cache_hit_rate", hit_rate)
+            .with("backends", backends)
+    }
+}
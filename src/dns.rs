@@ -0,0 +1,153 @@
+// dns.rs
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+
+/// Adapter letting a shared `dyn Resolve` be handed to reqwest, which wants a sized type.
+pub(crate) struct SharedResolver(pub(crate) Arc<dyn Resolve>);
+
+impl Resolve for SharedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        self.0.resolve(name)
+    }
+}
+
+/// Resolves host names by asking one specific DNS server, bypassing the system resolver.
+///
+/// Useful with split-horizon DNS, where the system resolver gives an address that is not
+/// reachable from the current egress. Only IPv4 (A) records are requested.
+#[derive(Debug, Clone)]
+pub struct NameServerResolver {
+    server: SocketAddr,
+    timeout: Duration,
+}
+
+impl NameServerResolver {
+    /// Creates a resolver querying `server` (port 53 is used when the IP is given alone).
+    pub fn new(server: SocketAddr) -> Self {
+        NameServerResolver {
+            server,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Parses a `--dns-server` value: `IP` or `IP:PORT`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        if let Ok(ip) = spec.parse::<IpAddr>() {
+            return Ok(Self::new(SocketAddr::new(ip, 53)));
+        }
+        spec.parse::<SocketAddr>()
+            .map(Self::new)
+            .map_err(|_| format!("invalid DNS server '{}', expected IP or IP:PORT", spec))
+    }
+
+    /// Sends a single A query for `host` and returns the addresses found.
+    pub fn lookup(&self, host: &str) -> Result<Vec<Ipv4Addr>, String> {
+        let bind_addr = if self.server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind_addr).map_err(|e| e.to_string())?;
+        socket.set_read_timeout(Some(self.timeout)).map_err(|e| e.to_string())?;
+
+        let id: u16 = rand::random();
+        let query = build_query(id, host)?;
+        socket.send_to(&query, self.server).map_err(|e| e.to_string())?;
+
+        let mut buf = [0u8; 1500];
+        let (len, _) = socket
+            .recv_from(&mut buf)
+            .map_err(|e| format!("no answer from DNS server {}: {}", self.server, e))?;
+        parse_response(id, &buf[..len])
+    }
+}
+
+impl Resolve for NameServerResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolver = self.clone();
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            // The UDP exchange is blocking, keep it off the reqwest runtime threads
+            let result = tokio::task::spawn_blocking(move || resolver.lookup(&host)).await?;
+            let ips = result?;
+            let addrs: Addrs = Box::new(
+                ips.into_iter()
+                    .map(|ip| SocketAddr::new(IpAddr::V4(ip), 0))
+                    .collect::<Vec<_>>()
+                    .into_iter(),
+            );
+            Ok(addrs)
+        })
+    }
+}
+
+/// Encodes a recursive DNS query for the A records of `host`.
+fn build_query(id: u16, host: &str) -> Result<Vec<u8>, String> {
+    let mut packet = Vec::with_capacity(32 + host.len());
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&[0x01, 0x00]); // Standard query, recursion desired
+    packet.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); // One question, no other records
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("invalid host name '{}'", host));
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&[0, 1, 0, 1]); // QTYPE A, QCLASS IN
+    Ok(packet)
+}
+
+/// Extracts the A records from a DNS response to the query with the given id.
+fn parse_response(id: u16, packet: &[u8]) -> Result<Vec<Ipv4Addr>, String> {
+    let malformed = || "malformed DNS response".to_string();
+    if packet.len() < 12 || u16::from_be_bytes([packet[0], packet[1]]) != id {
+        return Err(malformed());
+    }
+    let rcode = packet[3] & 0x0f;
+    if rcode != 0 {
+        return Err(format!("DNS server answered with error code {}", rcode));
+    }
+    let questions = u16::from_be_bytes([packet[4], packet[5]]);
+    let answers = u16::from_be_bytes([packet[6], packet[7]]);
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(packet, pos).ok_or_else(malformed)? + 4;
+    }
+
+    let mut ips = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(packet, pos).ok_or_else(malformed)?;
+        let header = packet.get(pos..pos + 10).ok_or_else(malformed)?;
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let rdlength = u16::from_be_bytes([header[8], header[9]]) as usize;
+        pos += 10;
+        let rdata = packet.get(pos..pos + rdlength).ok_or_else(malformed)?;
+        if rtype == 1 && rdlength == 4 {
+            ips.push(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]));
+        }
+        pos += rdlength;
+    }
+
+    if ips.is_empty() {
+        return Err("DNS server returned no A records".to_string());
+    }
+    Ok(ips)
+}
+
+/// Returns the position right after the (possibly compressed) name starting at `pos`.
+fn skip_name(packet: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *packet.get(pos)? as usize;
+        if len == 0 {
+            return Some(pos + 1);
+        }
+        if len & 0xc0 == 0xc0 {
+            // Compression pointer, the name ends here as far as this record is concerned
+            return Some(pos + 2);
+        }
+        pos += len + 1;
+    }
+}
@@ -0,0 +1,127 @@
+// entity.rs
+
+use crate::json::JsonValue;
+
+/// What is known about the company or person behind a NIF.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NifEntity {
+    pub nif: String,
+    pub name: String,
+    pub address: Option<String>,     // Street part of the address
+    pub postal_code: Option<String>, // NNNN-NNN
+    pub locality: Option<String>,
+    pub phone: Option<String>,
+    pub email: Option<String>,
+    pub district: Option<String>,     // Code, e.g. `11` for Lisboa, from the postal code
+    pub municipality: Option<String>, // DICO code, e.g. `1106` for Lisboa, from the postal code
+}
+
+impl NifEntity {
+    /// Serializes the entity as a JSON object; missing fields are left out.
+    pub fn to_json(&self) -> JsonValue {
+        let mut json = JsonValue::object().with("nif", self.nif.as_str()).with("name", self.name.as_str());
+        for (key, value) in self.optional_fields() {
+            if let Some(value) = value {
+                json = json.with(key, value.as_str());
+            }
+        }
+        json
+    }
+
+    /// Parses the output of `to_json`.
+    pub fn from_json(json: &JsonValue) -> Option<Self> {
+        let field = |key: &str| json.str_field(key).map(str::to_string);
+        Some(NifEntity {
+            nif: field("nif")?,
+            name: field("name")?,
+            address: field("address"),
+            postal_code: field("postal_code"),
+            locality: field("locality"),
+            phone: field("phone"),
+            email: field("email"),
+            district: field("district"),
+            municipality: field("municipality"),
+        })
+    }
+
+    /// Tells whether both entities have the same details, leaving out the district and
+    /// municipality, which are derived from the postal code rather than read from the page.
+    pub fn same_details(&self, other: &NifEntity) -> bool {
+        let unplaced = |entity: &NifEntity| NifEntity { district: None, municipality: None, ..entity.clone() };
+        unplaced(self) == unplaced(other)
+    }
+
+    /// Single-line postal address, e.g. `Rua Augusta 1, 1100-048 Lisboa`.
+    pub fn full_address(&self) -> Option<String> {
+        let town = match (&self.postal_code, &self.locality) {
+            (Some(code), Some(locality)) => Some(format!("{} {}", code, locality)),
+            (code, locality) => code.clone().or_else(|| locality.clone()),
+        };
+        match (&self.address, town) {
+            (Some(street), Some(town)) => Some(format!("{}, {}", street, town)),
+            (street, town) => street.clone().or(town),
+        }
+    }
+
+    fn optional_fields(&self) -> [(&'static str, &Option<String>); 7] {
+        [
+            ("address", &self.address),
+            ("postal_code", &self.postal_code),
+            ("locality", &self.locality),
+            ("phone", &self.phone),
+            ("email", &self.email),
+            ("district", &self.district),
+            ("municipality", &self.municipality),
+        ]
+    }
+}
+
+/// One of the companies nif.pt lists when a search matches several, to tell them apart.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EntityCandidate {
+    pub name: String,
+    pub nif: Option<String>,      // When the list shows it, or links to its page
+    pub locality: Option<String>, // Town after the postal code, when shown
+}
+
+impl EntityCandidate {
+    /// Serializes the candidate as a JSON object; missing fields are left out.
+    pub fn to_json(&self) -> JsonValue {
+        let mut json = JsonValue::object().with("name", self.name.as_str());
+        if let Some(nif) = &self.nif {
+            json = json.with("nif", nif.as_str());
+        }
+        if let Some(locality) = &self.locality {
+            json = json.with("locality", locality.as_str());
+        }
+        json
+    }
+
+    /// Parses the output of `to_json`.
+    pub fn from_json(json: &JsonValue) -> Option<Self> {
+        Some(EntityCandidate {
+            name: json.str_field("name")?.to_string(),
+            nif: json.str_field("nif").map(str::to_string),
+            locality: json.str_field("locality").map(str::to_string),
+        })
+    }
+}
+
+/// Splits `1000-001 Lisboa` into the postal code and the locality.
+pub fn split_postal_code(line: &str) -> Option<(String, String)> {
+    let bytes = line.as_bytes();
+    let is_code = bytes.len() >= 8
+        && bytes[..4].iter().all(u8::is_ascii_digit)
+        && bytes[4] == b'-'
+        && bytes[5..8].iter().all(u8::is_ascii_digit)
+        && bytes.get(8).is_none_or(|b| *b == b' ');
+    is_code.then(|| (line[..8].to_string(), line[8..].trim().to_string()))
+}
+
+/// `NifEntity::same_details` for entities that may be missing.
+pub fn same_entity(a: Option<&NifEntity>, b: Option<&NifEntity>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.same_details(b),
+        (a, b) => a.is_none() && b.is_none(),
+    }
+}
@@ -0,0 +1,243 @@
+// fallback.rs
+
+use std::time::{Duration, Instant};
+
+use reqwest::blocking::Client;
+use scraper::{ElementRef, Html, Selector};
+
+use crate::cassette::{Cassette, CassetteMode};
+use crate::entity::{split_postal_code, NifEntity};
+use crate::json::JsonValue;
+use crate::logging::{self, display_nif, nif_field};
+use crate::lookup::{record_exchange, request_error, status_text, LookupReport};
+
+/// Company-information sites asked for the entity when nif.pt cannot answer.
+///
+/// Their company pages carry schema.org `Organization` data (JSON-LD or microdata),
+/// which is what gets read, so the answers normalise to the same `NifEntity` fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Fallback {
+    Racius,   // www.racius.com
+    Einforma, // www.einforma.pt
+}
+
+impl Fallback {
+    /// Parses a `--fallback` value.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "racius" => Ok(Fallback::Racius),
+            "einforma" => Ok(Fallback::Einforma),
+            other => Err(format!("unknown fallback '{}', expected racius or einforma", other)),
+        }
+    }
+
+    /// Name of the backend, as reported in logs and traces.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Fallback::Racius => "racius.com",
+            Fallback::Einforma => "einforma.pt",
+        }
+    }
+
+    /// Scheme and host of the site.
+    fn base_url(&self) -> &'static str {
+        match self {
+            Fallback::Racius => "https://www.racius.com",
+            Fallback::Einforma => "https://www.einforma.pt",
+        }
+    }
+
+    /// Page of the company with this NIF, or a search for it, on the site or at `base_url`.
+    fn url(&self, nif_number: &str, base_url: Option<&str>) -> String {
+        let base_url = base_url.unwrap_or(self.base_url()).trim_end_matches('/');
+        match self {
+            Fallback::Racius => format!("{}/pesquisa/?q={}", base_url, nif_number),
+            Fallback::Einforma => format!(
+                "{}/servlet/app/portal/ENTP/prod/ETIQUETA_EMPRESA_CONTRIBUINTE/nif/{}",
+                base_url, nif_number
+            ),
+        }
+    }
+
+    /// Looks the NIF up on this site. Returns `Ok(None)` when the site has no company page
+    /// for it, and the error (already logged) when it could not be reached: these sites only
+    /// tell about companies, never that a NIF is invalid. `base_url` replaces the scheme and host of the site, e.g. for a
+    /// mirror. `timeout` bounds each wait of the request; request and parse times are added
+    /// to `report`. With a `cassette`, the exchange is recorded or replayed.
+    pub fn query(
+        &self,
+        nif_number: &str,
+        base_url: Option<&str>,
+        client: &Client,
+        timeout: Duration,
+        report: &mut LookupReport,
+        cassette: Option<&Cassette>,
+    ) -> Result<Option<NifEntity>, String> {
+        logging::info(
+            "fallback_query",
+            &[nif_field(nif_number), ("backend", self.name().into())],
+            format!("Querying {} for NIF: {}", self.name(), display_nif(nif_number)),
+        );
+        let fail = |event: &str, error: String| {
+            logging::warn(
+                event,
+                &[nif_field(nif_number), ("backend", self.name().into()), ("error", error.as_str().into())],
+                format!("{} lookup failed for NIF {}: {}", self.name(), display_nif(nif_number), error),
+            );
+            Err(error)
+        };
+        let fetch_started = Instant::now();
+        let body = self.fetch(&self.url(nif_number, base_url), nif_number, client, timeout, cassette);
+        report.fetch += fetch_started.elapsed();
+        let body = match body {
+            Ok(body) => body,
+            Err((event, error)) => return fail(event, error),
+        };
+        let parse_started = Instant::now();
+        let entity = parse_company_page(&body, nif_number);
+        report.parse += parse_started.elapsed();
+        if entity.is_none() {
+            logging::info(
+                "fallback_not_found",
+                &[nif_field(nif_number), ("backend", self.name().into())],
+                format!("No company found on {} for NIF: {}", self.name(), display_nif(nif_number)),
+            );
+        }
+        Ok(entity)
+    }
+
+    /// Fetches the page; on failure, returns the event to log and the error.
+    fn fetch(
+        &self,
+        url: &str,
+        nif_number: &str,
+        client: &Client,
+        timeout: Duration,
+        cassette: Option<&Cassette>,
+    ) -> Result<String, (&'static str, String)> {
+        if let Some(cassette) = cassette.filter(|cassette| cassette.mode() == CassetteMode::Replay) {
+            let interaction = cassette
+                .play("GET", url)
+                .ok_or_else(|| ("cassette_miss", format!("no recorded answer in {}", cassette.path().display())))?;
+            if !(200..300).contains(&interaction.status) {
+                return Err(("fallback_http_error", status_text(interaction.status)));
+            }
+            return Ok(interaction.body);
+        }
+        let response = match client.get(url).timeout(timeout).send() {
+            Ok(response) => response,
+            Err(e) => return Err(("fallback_request_failed", request_error(e))),
+        };
+        let code = response.status().as_u16();
+        if !response.status().is_success() {
+            record_exchange(cassette, nif_number, url, code, &response.text().unwrap_or_default());
+            return Err(("fallback_http_error", status_text(code)));
+        }
+        let body = response.text().map_err(|e| ("fallback_request_failed", request_error(e)))?;
+        record_exchange(cassette, nif_number, url, code, &body);
+        Ok(body)
+    }
+}
+
+/// Reads the schema.org `Organization` of a company page, JSON-LD first, then microdata.
+///
+/// The page must be about `nif_number`: its `taxID`/`vatID` when given, otherwise the
+/// NIF must at least appear in the page, so a search listing another company is not taken.
+pub fn parse_company_page(body: &str, nif_number: &str) -> Option<NifEntity> {
+    let document = Html::parse_document(body);
+    let (entity, tax_id) = json_ld_organization(&document, nif_number).or_else(|| microdata_organization(&document, nif_number))?;
+    let about_nif = match tax_id {
+        Some(id) => id.chars().filter(char::is_ascii_digit).collect::<String>().ends_with(nif_number),
+        None => body.contains(nif_number),
+    };
+    (about_nif && !entity.name.is_empty()).then_some(entity)
+}
+
+/// Collapses runs of whitespace, as found in HTML text.
+fn clean(text: &str) -> Option<String> {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!text.is_empty()).then_some(text)
+}
+
+/// Fills the postal code and locality, accepting a locality given with the code.
+fn set_town(entity: &mut NifEntity, postal_code: Option<String>, locality: Option<String>) {
+    match postal_code.as_deref().and_then(split_postal_code) {
+        Some((code, town)) => {
+            entity.postal_code = Some(code);
+            entity.locality = locality.or((!town.is_empty()).then_some(town));
+        }
+        None => entity.locality = locality,
+    }
+}
+
+const ORGANIZATION_TYPES: &[&str] = &["Organization", "Corporation", "LocalBusiness"];
+
+/// Finds an organization in the `application/ld+json` scripts; returns it with its tax ID.
+fn json_ld_organization(document: &Html, nif_number: &str) -> Option<(NifEntity, Option<String>)> {
+    let scripts = Selector::parse(r#"script[type="application/ld+json"]"#).unwrap();
+    let mut candidates = Vec::new();
+    for script in document.select(&scripts) {
+        let Ok(json) = JsonValue::parse(&script.text().collect::<String>()) else {
+            continue;
+        };
+        match json.get("@graph").and_then(JsonValue::as_array).or(json.as_array()) {
+            Some(items) => candidates.extend(items.iter().cloned()),
+            None => candidates.push(json),
+        }
+    }
+    let organization = candidates.into_iter().find(|item| {
+        let is_organization = |value: &JsonValue| value.as_str().is_some_and(|kind| ORGANIZATION_TYPES.contains(&kind));
+        match item.get("@type") {
+            Some(JsonValue::Array(kinds)) => kinds.iter().any(is_organization),
+            Some(kind) => is_organization(kind),
+            None => false,
+        }
+    })?;
+
+    let text = |value: Option<&JsonValue>| value.and_then(JsonValue::as_str).and_then(clean);
+    let mut entity = NifEntity {
+        nif: nif_number.to_string(),
+        name: text(organization.get("legalName").or(organization.get("name")))?,
+        phone: text(organization.get("telephone")),
+        email: text(organization.get("email")),
+        ..NifEntity::default()
+    };
+    match organization.get("address") {
+        Some(JsonValue::String(address)) => entity.address = clean(address),
+        Some(address) => {
+            entity.address = text(address.get("streetAddress"));
+            set_town(&mut entity, text(address.get("postalCode")), text(address.get("addressLocality")));
+        }
+        None => {}
+    }
+    let tax_id = text(organization.get("taxID").or(organization.get("vatID")));
+    Some((entity, tax_id))
+}
+
+/// Finds an organization marked up with `itemscope`/`itemprop`; returns it with its tax ID.
+fn microdata_organization(document: &Html, nif_number: &str) -> Option<(NifEntity, Option<String>)> {
+    let scopes = Selector::parse("[itemscope][itemtype]").unwrap();
+    let organization = document.select(&scopes).find(|element| {
+        let kind = element.value().attr("itemtype").unwrap_or_default();
+        ORGANIZATION_TYPES.iter().any(|name| kind.ends_with(&format!("schema.org/{}", name)))
+    })?;
+    let property = |name: &str| -> Option<String> {
+        let selector = Selector::parse(&format!(r#"[itemprop="{}"]"#, name)).unwrap();
+        let element: ElementRef = organization.select(&selector).next()?;
+        // `<meta itemprop content>` and links keep the value in an attribute
+        match element.value().attr("content") {
+            Some(content) => clean(content),
+            None => clean(&element.text().collect::<String>()),
+        }
+    };
+    let mut entity = NifEntity {
+        nif: nif_number.to_string(),
+        name: property("legalName").or_else(|| property("name"))?,
+        address: property("streetAddress"),
+        phone: property("telephone"),
+        email: property("email"),
+        ..NifEntity::default()
+    };
+    set_town(&mut entity, property("postalCode"), property("addressLocality"));
+    Some((entity, property("taxID").or_else(|| property("vatID"))))
+}
@@ -0,0 +1,105 @@
+// generate.rs
+
+//! Generation of locally valid NIFs, for test fixtures and load tests. The NIFs are random,
+//! so most belong to nobody: nif.pt answers them `valid_unknown`, or not at all.
+
+use std::collections::HashSet;
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::validation::{nif_check_digit, NifCategory};
+
+/// First digits of the NIFs of each category, as told apart by `nif_category`, each with
+/// a weight: the main prefixes come out far more often than the rare ones.
+fn prefixes(category: NifCategory) -> &'static [(&'static str, u32)] {
+    match category {
+        NifCategory::Person => &[("1", 4), ("2", 4), ("3", 1), ("45", 1), ("8", 1)],
+        NifCategory::Company => &[("5", 18), ("71", 1), ("99", 1)],
+        NifCategory::Public => &[("6", 1)],
+        NifCategory::Other => &[("70", 1), ("72", 1), ("74", 1), ("75", 1), ("77", 1), ("78", 1), ("79", 1), ("90", 1), ("91", 1), ("98", 1)],
+    }
+}
+
+/// Draws one valid NIF from `rng`, of the given category or, with `None`, of any category.
+pub fn generate_nif<R: Rng + ?Sized>(rng: &mut R, category: Option<NifCategory>) -> String {
+    let category = category.unwrap_or_else(|| {
+        // Weighted like the categories are in practice: mostly people and companies
+        match rng.gen_range(0..10) {
+            0..=5 => NifCategory::Person,
+            6..=8 => NifCategory::Company,
+            _ => NifCategory::Public,
+        }
+    });
+    let candidates = prefixes(category);
+    let mut pick = rng.gen_range(0..candidates.iter().map(|(_, weight)| weight).sum::<u32>());
+    let mut prefix = candidates[0].0;
+    for (candidate, weight) in candidates {
+        if pick < *weight {
+            prefix = candidate;
+            break;
+        }
+        pick -= weight;
+    }
+    let mut digits: Vec<u32> = prefix.chars().filter_map(|c| c.to_digit(10)).collect();
+    while digits.len() < 8 {
+        digits.push(rng.gen_range(0..10));
+    }
+    digits.push(nif_check_digit(&digits));
+    digits.iter().map(|digit| char::from_digit(*digit, 10).unwrap_or('0')).collect()
+}
+
+/// Generates `count` distinct valid NIFs, always the same ones for the same seed, count and
+/// category, whatever the platform or the version of check_nif.
+pub fn generate_nifs(count: usize, seed: u64, category: Option<NifCategory>) -> Vec<String> {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let mut seen = HashSet::with_capacity(count);
+    let mut nifs = Vec::with_capacity(count);
+    while nifs.len() < count {
+        let nif = generate_nif(&mut rng, category);
+        if seen.insert(nif.clone()) {
+            nifs.push(nif);
+        }
+    }
+    nifs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::nif_category;
+
+    const CATEGORIES: [NifCategory; 4] = [NifCategory::Person, NifCategory::Company, NifCategory::Public, NifCategory::Other];
+
+    // The NIFs of a seed are part of the interface: fixtures are generated from a seed
+    // given in a script, and must stay the same across versions and platforms
+    #[test]
+    fn seed_gives_the_same_nifs() {
+        assert_eq!(generate_nifs(5, 42, None), ["219436762", "138682550", "614163609", "183755286", "107424061"]);
+        assert_eq!(generate_nifs(3, 42, Some(NifCategory::Company)), ["519436768", "538682558", "599731419"]);
+    }
+
+    #[test]
+    fn prefixes_are_their_category() {
+        for category in CATEGORIES {
+            for (prefix, _) in prefixes(category) {
+                let mut digits: Vec<u32> = format!("{:0<8}", prefix).chars().filter_map(|c| c.to_digit(10)).collect();
+                digits.push(nif_check_digit(&digits));
+                let nif: String = digits.iter().map(|digit| char::from_digit(*digit, 10).unwrap()).collect();
+                assert_eq!(nif_category(&nif), Some(category), "prefix {}", prefix);
+            }
+        }
+    }
+
+    #[test]
+    fn generated_nifs_are_of_the_category_asked() {
+        for category in CATEGORIES {
+            for nif in generate_nifs(500, 7, Some(category)) {
+                assert_eq!(nif_category(&nif), Some(category), "{}", nif);
+            }
+        }
+        for nif in generate_nifs(500, 7, None) {
+            assert!(matches!(nif_category(&nif), Some(NifCategory::Person | NifCategory::Company | NifCategory::Public)), "{}", nif);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+// geocode.rs
+
+//! Coordinates of entity addresses, from a Nominatim server (the search API of
+//! OpenStreetMap, public or self-hosted), to put supplier locations on a map.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use reqwest::blocking::Client;
+use reqwest::header::USER_AGENT;
+
+use crate::entity::NifEntity;
+use crate::json::JsonValue;
+use crate::lookup::{request_error, status_text, Location};
+use crate::ratelimit::Throttle;
+
+/// The public server of OpenStreetMap.
+pub const NOMINATIM_URL: &str = "https://nominatim.openstreetmap.org";
+
+/// Requests per minute allowed by the usage policy of the public server.
+pub const NOMINATIM_RATE: u32 = 60;
+
+/// Sent with every request, as the usage policy asks applications to identify themselves.
+const GEOCODER_AGENT: &str = concat!("check_nif/", env!("CARGO_PKG_VERSION"));
+
+/// Parameters of one search, e.g. `q` and the address.
+type Search = Vec<(&'static str, String)>;
+
+/// Asks a Nominatim server for the coordinates of entity addresses.
+///
+/// The full address is searched first, then the postal code and locality alone, which
+/// still places the entity when the street is not mapped. Requests are spaced by a
+/// throttle, and answers (found or not) are remembered for the life of the geocoder, so
+/// entities sharing an address cost one request.
+#[derive(Debug)]
+pub struct Geocoder {
+    url: String,
+    throttle: Throttle,
+    answers: Mutex<HashMap<Search, Option<Location>>>,
+}
+
+impl Geocoder {
+    /// Geocoder asking the server at `url`, at most `NOMINATIM_RATE` times a minute.
+    pub fn new(url: impl Into<String>) -> Self {
+        Geocoder {
+            url: url.into().trim_end_matches('/').to_string(),
+            throttle: Throttle::per_minute(NOMINATIM_RATE),
+            answers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Allows `per_minute` requests a minute, e.g. for a self-hosted server.
+    pub fn with_rate(mut self, per_minute: u32) -> Self {
+        self.throttle = Throttle::per_minute(per_minute);
+        self
+    }
+
+    /// Scheme and host of the server.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Coordinates of the address of `entity`; `None` when it has no address or the server
+    /// does not find it.
+    pub fn locate(&self, entity: &NifEntity, client: &Client, timeout: Duration) -> Result<Option<Location>, String> {
+        let mut searches = Vec::new();
+        if let Some(address) = entity.full_address() {
+            searches.push(vec![("q", address)]);
+        }
+        if let Some(postal_code) = &entity.postal_code {
+            let mut search = vec![("postalcode", postal_code.clone())];
+            search.extend(entity.locality.clone().map(|locality| ("city", locality)));
+            searches.push(search);
+        }
+        for search in searches {
+            if let Some(location) = self.search(search, client, timeout)? {
+                return Ok(Some(location));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Runs one search, restricted to Portugal, or answers it from the earlier ones.
+    fn search(&self, search: Search, client: &Client, timeout: Duration) -> Result<Option<Location>, String> {
+        if let Some(answer) = self.answers.lock().unwrap().get(&search) {
+            return Ok(*answer);
+        }
+        self.throttle.wait();
+        let response = client
+            .get(format!("{}/search", self.url))
+            .query(&search)
+            .query(&[("countrycodes", "pt"), ("format", "jsonv2"), ("limit", "1")])
+            .header(USER_AGENT, GEOCODER_AGENT)
+            .timeout(timeout)
+            .send()
+            .map_err(request_error)?;
+        if !response.status().is_success() {
+            return Err(status_text(response.status().as_u16()));
+        }
+        let body = response.text().map_err(request_error)?;
+        let answer = parse_search(&body)?;
+        self.answers.lock().unwrap().insert(search, answer);
+        Ok(answer)
+    }
+}
+
+/// Reads the first place of a `format=jsonv2` search answer, whose coordinates are strings.
+pub fn parse_search(body: &str) -> Result<Option<Location>, String> {
+    let json = JsonValue::parse(body)?;
+    let places = json.as_array().ok_or("the answer is not a list of places")?;
+    let Some(place) = places.first() else {
+        return Ok(None);
+    };
+    let coordinate = |key: &str| {
+        place
+            .str_field(key)
+            .and_then(|value| value.parse::<f64>().ok())
+            .ok_or(format!("the place has no valid {}", key))
+    };
+    Ok(Some(Location {
+        latitude: coordinate("lat")?,
+        longitude: coordinate("lon")?,
+    }))
+}
@@ -0,0 +1,1378 @@
+// graphql.rs
+
+//! GraphQL API of `serve`, at `/graphql`: parsing and execution of GraphQL documents against
+//! the schema below. Objects are the `to_json` forms of the crate's types, whose snake_case
+//! keys the schema exposes in camelCase; what the root fields return is up to a `Root`.
+//!
+//! Supported: queries and mutations, variables with defaults, aliases, named and inline
+//! fragments, `@include`/`@skip` and `__typename`. No subscriptions and no introspection;
+//! `{ _service { sdl } }` returns the schema, for gateways federating this service.
+
+use std::collections::HashMap;
+
+use crate::json::JsonValue;
+
+/// Nesting limit of selections and values, so hostile documents cannot overflow the stack.
+const MAX_DEPTH: usize = 32;
+
+/// Selections a document may expand to once its fragments are spread, so fragments spreading
+/// each other several times cannot make it exponential.
+const MAX_SELECTIONS: usize = 10_000;
+
+/// A field of a schema type.
+struct FieldDef {
+    name: &'static str,
+    key: &'static str, // Key of the field in the JSON form of the object
+    ty: &'static str,  // In SDL notation, e.g. `[LookupResult!]!`
+    args: &'static [(&'static str, &'static str)],
+}
+
+const fn field(name: &'static str, key: &'static str, ty: &'static str) -> FieldDef {
+    FieldDef { name, key, ty, args: &[] }
+}
+
+const fn root(name: &'static str, ty: &'static str, args: &'static [(&'static str, &'static str)]) -> FieldDef {
+    FieldDef { name, key: name, ty, args }
+}
+
+/// The object types of the schema, roots first.
+const TYPES: &[(&str, &[FieldDef])] = &[
+    (
+        "Query",
+        &[
+            root("nif", "LookupResult!", &[("nif", "String!")]),
+            root("nifs", "[LookupResult!]!", &[("nifs", "[String!]!")]),
+            root("stored", "StoredRecord", &[("nif", "String!")]),
+            root("job", "Job", &[("id", "ID!")]),
+            root("jobs", "[Job!]!", &[]),
+            root("_service", "_Service!", &[]),
+        ],
+    ),
+    (
+        "Mutation",
+        &[
+            root("startJob", "Job!", &[("nifs", "[String!]!")]),
+            root("cancelJob", "Boolean!", &[("id", "ID!")]),
+        ],
+    ),
+    (
+        "LookupResult",
+        &[
+            field("nif", "nif", "String!"),
+            field("status", "status", "String!"),
+            field("httpStatus", "http_status", "Int"),
+            field("validLocally", "valid_locally", "Boolean!"),
+            field("source", "source", "String!"),
+            field("entity", "entity", "Entity"),
+            field("candidates", "candidates", "[EntityCandidate!]!"),
+            field("postalCheck", "postal_check", "PostalCheck"),
+            field("nameCheck", "name_check", "NameCheck"),
+            field("location", "location", "Location"),
+            field("parseConfidence", "parse_confidence", "ParseConfidence"),
+            field("checkedAt", "checked_at", "String"),
+            field("confidence", "confidence", "String"),
+            field("tag", "tag", "String"),
+            field("report", "report", "Report!"),
+        ],
+    ),
+    (
+        "EntityCandidate",
+        &[
+            field("name", "name", "String!"),
+            field("nif", "nif", "String"),
+            field("locality", "locality", "String"),
+        ],
+    ),
+    (
+        "Entity",
+        &[
+            field("nif", "nif", "String!"),
+            field("name", "name", "String!"),
+            field("address", "address", "String"),
+            field("postalCode", "postal_code", "String"),
+            field("locality", "locality", "String"),
+            field("phone", "phone", "String"),
+            field("email", "email", "String"),
+            field("district", "district", "String"),
+            field("municipality", "municipality", "String"),
+        ],
+    ),
+    (
+        "PostalCheck",
+        &[field("status", "status", "String!"), field("expected", "expected", "String")],
+    ),
+    (
+        "NameCheck",
+        &[
+            field("expected", "expected", "String!"),
+            field("similarity", "similarity", "Float!"),
+            field("match", "match", "Boolean!"),
+        ],
+    ),
+    (
+        "ParseConfidence",
+        &[
+            field("score", "score", "Float!"),
+            field("needsReview", "needs_review", "Boolean!"),
+            field("layout", "layout", "String"),
+            field("matched", "matched", "[String!]!"),
+            field("missing", "missing", "[String!]!"),
+        ],
+    ),
+    (
+        "Location",
+        &[field("latitude", "latitude", "Float!"), field("longitude", "longitude", "Float!")],
+    ),
+    (
+        "Report",
+        &[
+            field("totalMs", "total_ms", "Float!"),
+            field("fetchMs", "fetch_ms", "Float!"),
+            field("parseMs", "parse_ms", "Float!"),
+            field("retries", "retries", "Int!"),
+            field("attempts", "attempts", "Int!"),
+            field("backend", "backend", "String!"),
+            field("cacheHit", "cache_hit", "Boolean!"),
+            field("error", "error", "String"),
+        ],
+    ),
+    (
+        "StoredRecord",
+        &[
+            field("nif", "nif", "String!"),
+            field("status", "status", "String!"),
+            field("source", "source", "String!"),
+            field("recordedAt", "recorded_at", "Int!"),
+            field("entity", "entity", "Entity"),
+            field("removedAt", "removed_at", "Int"),
+        ],
+    ),
+    (
+        "Job",
+        &[
+            field("id", "id", "ID!"),
+            field("state", "state", "String!"),
+            field("createdAt", "created_at", "Int!"),
+            field("total", "total", "Int!"),
+            field("done", "done", "Int!"),
+            field("results", "results", "[LookupResult!]!"),
+        ],
+    ),
+    ("_Service", &[field("sdl", "sdl", "String!")]),
+];
+
+fn type_fields(name: &str) -> Option<&'static [FieldDef]> {
+    TYPES.iter().find(|(type_name, _)| *type_name == name).map(|(_, fields)| *fields)
+}
+
+/// The schema in SDL, as returned by `_service { sdl }`.
+pub fn schema_sdl() -> String {
+    let mut sdl = String::new();
+    for (name, fields) in TYPES {
+        sdl.push_str(&format!("type {} {{\n", name));
+        for field in *fields {
+            let args: Vec<String> = field.args.iter().map(|(name, ty)| format!("{}: {}", name, ty)).collect();
+            let args = if args.is_empty() { String::new() } else { format!("({})", args.join(", ")) };
+            sdl.push_str(&format!("  {}{}: {}\n", field.name, args, field.ty));
+        }
+        sdl.push_str("}\n\n");
+    }
+    sdl.truncate(sdl.len() - 1);
+    sdl
+}
+
+/// Resolves the root fields; anything below them is read from the JSON they return.
+pub trait Root {
+    /// Value of a field of `Query`, `args` being an object of its arguments.
+    fn query(&self, field: &str, args: &JsonValue) -> Result<JsonValue, String>;
+    /// Value of a field of `Mutation`, with the same conventions.
+    fn mutate(&self, field: &str, args: &JsonValue) -> Result<JsonValue, String>;
+    /// Lookups resolving a root field makes, counted against `max_lookups`.
+    fn lookups(&self, _field: &str, _args: &JsonValue) -> usize {
+        0
+    }
+    /// Lookups a root field costs the client, counting those it leaves to a background job;
+    /// by default the lookups it makes.
+    fn cost(&self, field: &str, args: &JsonValue) -> usize {
+        self.lookups(field, args)
+    }
+    /// Most lookups a document may make in all, whatever aliases and fragments it uses.
+    fn max_lookups(&self) -> usize {
+        usize::MAX
+    }
+}
+
+/// A GraphQL request, as posted in JSON or given in the query string.
+#[derive(Debug, Clone, Default)]
+pub struct GraphqlRequest {
+    pub query: String,
+    pub variables: Option<JsonValue>,
+    pub operation_name: Option<String>,
+}
+
+impl GraphqlRequest {
+    /// Parses the JSON body of a `POST`: `{"query": ..., "variables": {...}, "operationName": ...}`.
+    pub fn from_json(json: &JsonValue) -> Result<Self, String> {
+        let query = json.str_field("query").ok_or("the request has no query")?.to_string();
+        let variables = json.get("variables").filter(|variables| **variables != JsonValue::Null).cloned();
+        if variables.as_ref().is_some_and(|variables| !matches!(variables, JsonValue::Object(_))) {
+            return Err("variables must be an object".to_string());
+        }
+        Ok(GraphqlRequest {
+            query,
+            variables,
+            operation_name: json.str_field("operationName").map(String::from),
+        })
+    }
+
+    /// Tells whether the operation to run is a mutation, e.g. to refuse it over `GET`.
+    /// Documents that do not parse are left for `execute` to report.
+    pub fn is_mutation(&self) -> bool {
+        parse_document(&self.query)
+            .and_then(|document| document.operation(self.operation_name.as_deref()).map(|op| op.mutation))
+            .unwrap_or(false)
+    }
+}
+
+/// Runs a request and returns the response, `{"data": ...}` with `"errors"` when some
+/// fields failed, or `{"errors": [...]}` alone when the document could not be run.
+pub fn execute(request: &GraphqlRequest, root: &dyn Root) -> JsonValue {
+    let document = match parse_document(&request.query) {
+        Ok(document) => document,
+        Err(e) => return errors_only(e),
+    };
+    let operation = match document.operation(request.operation_name.as_deref()) {
+        Ok(operation) => operation,
+        Err(e) => return errors_only(e),
+    };
+    let root_type = if operation.mutation { "Mutation" } else { "Query" };
+    // Reject unknown fields before anything is looked up
+    if let Err(e) = validate(&document, root_type, &operation.selection, 0, &mut 0) {
+        return errors_only(e);
+    }
+    let variables = match coerce_variables(operation, request.variables.as_ref()) {
+        Ok(variables) => variables,
+        Err(e) => return errors_only(e),
+    };
+    let mut executor = Executor {
+        document: &document,
+        variables,
+        root,
+        errors: Vec::new(),
+    };
+    if let Err(e) = executor.check_lookups(root_type, &operation.selection) {
+        return errors_only(e);
+    }
+    let data = executor.root_selection(root_type, &operation.selection);
+    let mut response = JsonValue::object();
+    if !executor.errors.is_empty() {
+        response = response.with("errors", executor.errors);
+    }
+    response.with("data", data)
+}
+
+/// Lookups a request costs the client, see `Root::cost`, counted before it runs. A request
+/// that cannot run costs nothing: `execute` reports it.
+pub fn cost(request: &GraphqlRequest, root: &dyn Root) -> usize {
+    let Ok(document) = parse_document(&request.query) else {
+        return 0;
+    };
+    let Ok(operation) = document.operation(request.operation_name.as_deref()) else {
+        return 0;
+    };
+    let root_type = if operation.mutation { "Mutation" } else { "Query" };
+    if validate(&document, root_type, &operation.selection, 0, &mut 0).is_err() {
+        return 0;
+    }
+    let Ok(variables) = coerce_variables(operation, request.variables.as_ref()) else {
+        return 0;
+    };
+    let mut executor = Executor {
+        document: &document,
+        variables,
+        root,
+        errors: Vec::new(),
+    };
+    if executor.check_lookups(root_type, &operation.selection).is_err() {
+        return 0;
+    }
+    executor.count(root_type, &operation.selection, |root, field, args| root.cost(field, args))
+}
+
+fn errors_only(message: String) -> JsonValue {
+    JsonValue::object().with("errors", vec![JsonValue::object().with("message", message)])
+}
+
+// Lexing
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Punct(char), // One of `!$&()...:=@[]{}|`, `...` being `.`
+    Name(String),
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            // Commas are insignificant, like white space
+            ' ' | '\t' | '\n' | '\r' | ',' | '\u{feff}' => i += 1,
+            '#' => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            '.' => {
+                if chars.get(i..i + 3) != Some(&['.', '.', '.']) {
+                    return Err("unexpected '.', expected '...'".to_string());
+                }
+                tokens.push(Token::Punct('.'));
+                i += 3;
+            }
+            '!' | '$' | '&' | '(' | ')' | ':' | '=' | '@' | '[' | ']' | '{' | '}' | '|' => {
+                tokens.push(Token::Punct(c));
+                i += 1;
+            }
+            '"' => {
+                let (value, end) = lex_string(&chars, i)?;
+                tokens.push(Token::Str(value));
+                i = end;
+            }
+            c if c == '_' || c.is_ascii_alphabetic() => {
+                let start = i;
+                while i < chars.len() && (chars[i] == '_' || chars[i].is_ascii_alphanumeric()) {
+                    i += 1;
+                }
+                tokens.push(Token::Name(chars[start..i].iter().collect()));
+            }
+            c if c == '-' || c.is_ascii_digit() => {
+                let start = i;
+                i += 1;
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || matches!(chars[i], '.' | '+' | '-')) {
+                    i += 1;
+                }
+                let number: String = chars[start..i].iter().collect();
+                let token = if number.contains(['.', 'e', 'E']) {
+                    number.parse().map(Token::Float).ok()
+                } else {
+                    number.parse().map(Token::Int).ok()
+                };
+                tokens.push(token.ok_or_else(|| format!("invalid number '{}'", number))?);
+            }
+            other => return Err(format!("unexpected character '{}'", other)),
+        }
+    }
+    Ok(tokens)
+}
+
+/// Reads the string starting at `chars[start]`, a quote, returning it and the index after it.
+fn lex_string(chars: &[char], start: usize) -> Result<(String, usize), String> {
+    if chars.get(start..start + 3) == Some(&['"', '"', '"']) {
+        return lex_block_string(chars, start + 3);
+    }
+    let mut value = String::new();
+    let mut i = start + 1;
+    loop {
+        match chars.get(i) {
+            None | Some('\n') => return Err("unterminated string".to_string()),
+            Some('"') => return Ok((value, i + 1)),
+            Some('\\') => {
+                let escaped = match chars.get(i + 1) {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('/') => '/',
+                    Some('b') => '\u{8}',
+                    Some('f') => '\u{c}',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('u') => {
+                        let hex: String = chars.get(i + 2..i + 6).unwrap_or_default().iter().collect();
+                        let code = u32::from_str_radix(&hex, 16).ok().filter(|_| hex.len() == 4);
+                        i += 4;
+                        code.and_then(char::from_u32).ok_or("invalid unicode escape")?
+                    }
+                    _ => return Err("invalid escape in string".to_string()),
+                };
+                value.push(escaped);
+                i += 2;
+            }
+            Some(c) => {
+                value.push(*c);
+                i += 1;
+            }
+        }
+    }
+}
+
+/// Block strings: raw text up to `"""`, with the common indentation of its lines removed.
+fn lex_block_string(chars: &[char], start: usize) -> Result<(String, usize), String> {
+    let mut raw = String::new();
+    let mut i = start;
+    loop {
+        match chars.get(i) {
+            None => return Err("unterminated block string".to_string()),
+            Some('"') if chars.get(i..i + 3) == Some(&['"', '"', '"']) => break,
+            Some('\\') if chars.get(i + 1..i + 4) == Some(&['"', '"', '"']) => {
+                raw.push_str("\"\"\"");
+                i += 4;
+            }
+            Some(c) => {
+                raw.push(*c);
+                i += 1;
+            }
+        }
+    }
+    let lines: Vec<&str> = raw.lines().collect();
+    let indent = lines
+        .iter()
+        .skip(1)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    let lines: Vec<&str> = lines
+        .iter()
+        .enumerate()
+        .map(|(n, line)| if n == 0 { line } else { line.get(indent..).unwrap_or("") })
+        .collect();
+    let first = lines.iter().position(|line| !line.trim().is_empty()).unwrap_or(lines.len());
+    let last = lines.iter().rposition(|line| !line.trim().is_empty()).map_or(first, |last| last + 1);
+    Ok((lines[first..last].join("\n"), i + 3))
+}
+
+// Parsing
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Variable(String),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Bool(bool),
+    Null,
+    Enum(String),
+    List(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+#[derive(Debug)]
+struct Directive {
+    name: String,
+    args: Vec<(String, Value)>,
+}
+
+#[derive(Debug)]
+enum Selection {
+    Field {
+        alias: Option<String>,
+        name: String,
+        args: Vec<(String, Value)>,
+        directives: Vec<Directive>,
+        selection: Vec<Selection>,
+    },
+    Spread {
+        name: String,
+        directives: Vec<Directive>,
+    },
+    Inline {
+        on: Option<String>,
+        directives: Vec<Directive>,
+        selection: Vec<Selection>,
+    },
+}
+
+#[derive(Debug)]
+struct VariableDef {
+    name: String,
+    ty: String,
+    default: Option<Value>,
+}
+
+#[derive(Debug)]
+struct Operation {
+    name: Option<String>,
+    mutation: bool,
+    variables: Vec<VariableDef>,
+    selection: Vec<Selection>,
+}
+
+#[derive(Debug)]
+struct Fragment {
+    on: String,
+    selection: Vec<Selection>,
+}
+
+#[derive(Debug, Default)]
+struct Document {
+    operations: Vec<Operation>,
+    fragments: HashMap<String, Fragment>,
+}
+
+impl Document {
+    /// The operation called `name`, or the only one when no name is given.
+    fn operation(&self, name: Option<&str>) -> Result<&Operation, String> {
+        match name {
+            Some(name) => self
+                .operations
+                .iter()
+                .find(|op| op.name.as_deref() == Some(name))
+                .ok_or_else(|| format!("unknown operation '{}'", name)),
+            None => match self.operations.as_slice() {
+                [operation] => Ok(operation),
+                [] => Err("the document has no operation".to_string()),
+                _ => Err("the document has several operations, operationName is required".to_string()),
+            },
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+}
+
+fn parse_document(text: &str) -> Result<Document, String> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        pos: 0,
+        depth: 0,
+    };
+    let mut document = Document::default();
+    while parser.pos < parser.tokens.len() {
+        match parser.peek() {
+            Some(Token::Punct('{')) => {
+                let selection = parser.selection_set()?;
+                document.operations.push(Operation {
+                    name: None,
+                    mutation: false,
+                    variables: Vec::new(),
+                    selection,
+                });
+            }
+            Some(Token::Name(keyword)) if keyword == "query" || keyword == "mutation" => {
+                let mutation = keyword == "mutation";
+                parser.pos += 1;
+                let name = match parser.peek() {
+                    Some(Token::Name(_)) => Some(parser.name()?),
+                    _ => None,
+                };
+                let variables = parser.variable_defs()?;
+                parser.directives()?;
+                let selection = parser.selection_set()?;
+                document.operations.push(Operation {
+                    name,
+                    mutation,
+                    variables,
+                    selection,
+                });
+            }
+            Some(Token::Name(keyword)) if keyword == "subscription" => {
+                return Err("subscriptions are not supported".to_string());
+            }
+            Some(Token::Name(keyword)) if keyword == "fragment" => {
+                parser.pos += 1;
+                let name = parser.name()?;
+                parser.keyword("on")?;
+                let on = parser.name()?;
+                parser.directives()?;
+                let selection = parser.selection_set()?;
+                if document.fragments.insert(name.clone(), Fragment { on, selection }).is_some() {
+                    return Err(format!("fragment '{}' is defined twice", name));
+                }
+            }
+            _ => return Err(parser.unexpected("an operation or a fragment")),
+        }
+    }
+    Ok(document)
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn unexpected(&self, expected: &str) -> String {
+        match self.peek() {
+            Some(token) => format!("syntax error: expected {}, found {}", expected, describe(token)),
+            None => format!("syntax error: expected {}, found the end of the document", expected),
+        }
+    }
+
+    fn eat(&mut self, punct: char) -> bool {
+        if self.peek() == Some(&Token::Punct(punct)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, punct: char) -> Result<(), String> {
+        if self.eat(punct) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("'{}'", punct)))
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Some(Token::Name(name)) => {
+                let name = name.clone();
+                self.pos += 1;
+                Ok(name)
+            }
+            _ => Err(self.unexpected("a name")),
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str) -> Result<(), String> {
+        match self.peek() {
+            Some(Token::Name(name)) if name == keyword => {
+                self.pos += 1;
+                Ok(())
+            }
+            _ => Err(self.unexpected(&format!("'{}'", keyword))),
+        }
+    }
+
+    fn enter(&mut self) -> Result<(), String> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err("the document is nested too deeply".to_string());
+        }
+        Ok(())
+    }
+
+    fn selection_set(&mut self) -> Result<Vec<Selection>, String> {
+        self.enter()?;
+        self.expect('{')?;
+        let mut selection = Vec::new();
+        while !self.eat('}') {
+            selection.push(self.selection()?);
+        }
+        if selection.is_empty() {
+            return Err("syntax error: empty selection".to_string());
+        }
+        self.depth -= 1;
+        Ok(selection)
+    }
+
+    fn selection(&mut self) -> Result<Selection, String> {
+        if self.eat('.') {
+            return match self.peek() {
+                Some(Token::Name(name)) if name != "on" => {
+                    let name = self.name()?;
+                    let directives = self.directives()?;
+                    Ok(Selection::Spread { name, directives })
+                }
+                _ => {
+                    let on = match self.peek() {
+                        Some(Token::Name(_)) => {
+                            self.keyword("on")?;
+                            Some(self.name()?)
+                        }
+                        _ => None,
+                    };
+                    let directives = self.directives()?;
+                    let selection = self.selection_set()?;
+                    Ok(Selection::Inline { on, directives, selection })
+                }
+            };
+        }
+        let mut name = self.name()?;
+        let mut alias = None;
+        if self.eat(':') {
+            alias = Some(name);
+            name = self.name()?;
+        }
+        let args = self.arguments()?;
+        let directives = self.directives()?;
+        let selection = if self.peek() == Some(&Token::Punct('{')) { self.selection_set()? } else { Vec::new() };
+        Ok(Selection::Field {
+            alias,
+            name,
+            args,
+            directives,
+            selection,
+        })
+    }
+
+    fn arguments(&mut self) -> Result<Vec<(String, Value)>, String> {
+        let mut args = Vec::new();
+        if self.eat('(') {
+            while !self.eat(')') {
+                let name = self.name()?;
+                self.expect(':')?;
+                args.push((name, self.value()?));
+            }
+        }
+        Ok(args)
+    }
+
+    fn directives(&mut self) -> Result<Vec<Directive>, String> {
+        let mut directives = Vec::new();
+        while self.eat('@') {
+            let name = self.name()?;
+            let args = self.arguments()?;
+            directives.push(Directive { name, args });
+        }
+        Ok(directives)
+    }
+
+    fn variable_defs(&mut self) -> Result<Vec<VariableDef>, String> {
+        let mut variables = Vec::new();
+        if self.eat('(') {
+            while !self.eat(')') {
+                self.expect('$')?;
+                let name = self.name()?;
+                self.expect(':')?;
+                let ty = self.type_ref()?;
+                let default = if self.eat('=') { Some(self.value()?) } else { None };
+                self.directives()?;
+                variables.push(VariableDef { name, ty, default });
+            }
+        }
+        Ok(variables)
+    }
+
+    /// A type in SDL notation, e.g. `[String!]!`.
+    fn type_ref(&mut self) -> Result<String, String> {
+        self.enter()?;
+        let mut ty = if self.eat('[') {
+            let inner = self.type_ref()?;
+            self.expect(']')?;
+            format!("[{}]", inner)
+        } else {
+            self.name()?
+        };
+        if self.eat('!') {
+            ty.push('!');
+        }
+        self.depth -= 1;
+        Ok(ty)
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.enter()?;
+        let token = self.peek().cloned().ok_or_else(|| self.unexpected("a value"))?;
+        self.pos += 1;
+        let value = match token {
+            Token::Punct('$') => Value::Variable(self.name()?),
+            Token::Int(n) => Value::Int(n),
+            Token::Float(n) => Value::Float(n),
+            Token::Str(s) => Value::Str(s),
+            Token::Name(name) => match name.as_str() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                "null" => Value::Null,
+                _ => Value::Enum(name),
+            },
+            Token::Punct('[') => {
+                let mut items = Vec::new();
+                while !self.eat(']') {
+                    items.push(self.value()?);
+                }
+                Value::List(items)
+            }
+            Token::Punct('{') => {
+                let mut fields = Vec::new();
+                while !self.eat('}') {
+                    let name = self.name()?;
+                    self.expect(':')?;
+                    fields.push((name, self.value()?));
+                }
+                Value::Object(fields)
+            }
+            _ => {
+                self.pos -= 1;
+                return Err(self.unexpected("a value"));
+            }
+        };
+        self.depth -= 1;
+        Ok(value)
+    }
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Punct('.') => "'...'".to_string(),
+        Token::Punct(c) => format!("'{}'", c),
+        Token::Name(name) => format!("'{}'", name),
+        Token::Int(n) => n.to_string(),
+        Token::Float(n) => n.to_string(),
+        Token::Str(_) => "a string".to_string(),
+    }
+}
+
+// Validation
+
+/// Checks that a selection on `type_name` only asks for fields of the schema, each with a
+/// selection when it is an object and none when it is a scalar. `expanded` counts the
+/// selections seen so far, fragments spread.
+fn validate(document: &Document, type_name: &str, selection: &[Selection], depth: usize, expanded: &mut usize) -> Result<(), String> {
+    if depth > MAX_DEPTH {
+        return Err("fragments are nested too deeply".to_string());
+    }
+    let definitions = type_fields(type_name).unwrap_or_default();
+    for item in selection {
+        *expanded += 1;
+        if *expanded > MAX_SELECTIONS {
+            return Err(format!("the document expands to more than {} selections", MAX_SELECTIONS));
+        }
+        match item {
+            Selection::Field {
+                name, args, selection, ..
+            } => {
+                if name == "__typename" {
+                    continue;
+                }
+                let definition = definitions
+                    .iter()
+                    .find(|definition| definition.name == name)
+                    .ok_or_else(|| format!("cannot query field '{}' on type {}", name, type_name))?;
+                if let Some((arg, _)) = args.iter().find(|(arg, _)| !definition.args.iter().any(|(known, _)| known == arg)) {
+                    return Err(format!("unknown argument '{}' of field '{}'", arg, name));
+                }
+                let field_type = definition.ty.trim_matches(['[', ']', '!']);
+                match (type_fields(field_type).is_some(), selection.is_empty()) {
+                    (true, true) => return Err(format!("field '{}' of type {} needs a selection", name, definition.ty)),
+                    (false, false) => return Err(format!("field '{}' of type {} takes no selection", name, definition.ty)),
+                    (true, false) => validate(document, field_type, selection, depth + 1, expanded)?,
+                    (false, true) => {}
+                }
+            }
+            Selection::Spread { name, .. } => {
+                let fragment = document.fragments.get(name).ok_or_else(|| format!("unknown fragment '{}'", name))?;
+                if type_fields(&fragment.on).is_none() {
+                    return Err(format!("unknown type {} of fragment '{}'", fragment.on, name));
+                }
+                if fragment.on == type_name {
+                    validate(document, type_name, &fragment.selection, depth + 1, expanded)?;
+                }
+            }
+            Selection::Inline { on, selection, .. } => match on {
+                Some(on) if type_fields(on).is_none() => return Err(format!("unknown type {}", on)),
+                Some(on) if on != type_name => {}
+                _ => validate(document, type_name, selection, depth + 1, expanded)?,
+            },
+        }
+    }
+    Ok(())
+}
+
+// Execution
+
+/// Checks and completes the variables given with the request against their definitions.
+fn coerce_variables(operation: &Operation, given: Option<&JsonValue>) -> Result<HashMap<String, JsonValue>, String> {
+    let mut variables = HashMap::new();
+    for definition in &operation.variables {
+        let value = match given.and_then(|given| given.get(&definition.name)) {
+            Some(value) => value.clone(),
+            None => match &definition.default {
+                Some(default) => literal(default)?,
+                None => JsonValue::Null,
+            },
+        };
+        check_type(&value, &definition.ty).map_err(|e| format!("variable ${}: {}", definition.name, e))?;
+        variables.insert(definition.name.clone(), value);
+    }
+    Ok(variables)
+}
+
+/// A constant value, as in variable defaults.
+fn literal(value: &Value) -> Result<JsonValue, String> {
+    resolve_value(value, &HashMap::new())
+}
+
+fn resolve_value(value: &Value, variables: &HashMap<String, JsonValue>) -> Result<JsonValue, String> {
+    Ok(match value {
+        Value::Variable(name) => variables.get(name).cloned().ok_or_else(|| format!("variable ${} is not defined", name))?,
+        Value::Int(n) => JsonValue::Int(*n),
+        Value::Float(n) => JsonValue::Float(*n),
+        Value::Str(s) | Value::Enum(s) => JsonValue::String(s.clone()),
+        Value::Bool(b) => JsonValue::Bool(*b),
+        Value::Null => JsonValue::Null,
+        Value::List(items) => JsonValue::Array(items.iter().map(|item| resolve_value(item, variables)).collect::<Result<_, _>>()?),
+        Value::Object(fields) => JsonValue::Object(
+            fields
+                .iter()
+                .map(|(name, value)| Ok((name.clone(), resolve_value(value, variables)?)))
+                .collect::<Result<_, String>>()?,
+        ),
+    })
+}
+
+/// Checks an input value against a type of the schema; the inputs are all scalars or lists.
+fn check_type(value: &JsonValue, ty: &str) -> Result<(), String> {
+    if let Some(inner) = ty.strip_suffix('!') {
+        if *value == JsonValue::Null {
+            return Err(format!("expected {}, found null", ty));
+        }
+        return check_type(value, inner);
+    }
+    if *value == JsonValue::Null {
+        return Ok(());
+    }
+    if let Some(item) = ty.strip_prefix('[').and_then(|ty| ty.strip_suffix(']')) {
+        return match value {
+            JsonValue::Array(items) => items.iter().try_for_each(|value| check_type(value, item)),
+            // A single value is taken as a list of one
+            value => check_type(value, item),
+        };
+    }
+    let valid = match (ty, value) {
+        ("String", JsonValue::String(_)) => true,
+        ("ID", JsonValue::String(_) | JsonValue::Int(_)) => true,
+        ("Int", JsonValue::Int(n)) => i32::try_from(*n).is_ok(),
+        ("Float", JsonValue::Int(_) | JsonValue::Float(_)) => true,
+        ("Boolean", JsonValue::Bool(_)) => true,
+        _ => false,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("expected {}, found {}", ty, value))
+    }
+}
+
+struct Executor<'a> {
+    document: &'a Document,
+    variables: HashMap<String, JsonValue>,
+    root: &'a dyn Root,
+    errors: Vec<JsonValue>,
+}
+
+/// A field to resolve, once fragments are expanded and directives applied.
+struct CollectedField<'a> {
+    key: String, // Response key: the alias, or else the name
+    name: &'a str,
+    args: &'a [(String, Value)],
+    selection: Vec<&'a Selection>,
+}
+
+impl<'a> Executor<'a> {
+    fn error(&mut self, message: String, path: &[JsonValue]) {
+        self.errors.push(JsonValue::object().with("message", message).with("path", path.to_vec()));
+    }
+
+    /// Tells whether `@include`/`@skip` let a selection through.
+    fn included(&self, directives: &[Directive]) -> Result<bool, String> {
+        for directive in directives {
+            let condition = match directive.args.iter().find(|(name, _)| name == "if") {
+                Some((_, value)) => resolve_value(value, &self.variables)?.as_bool(),
+                None => None,
+            };
+            match (directive.name.as_str(), condition) {
+                ("include", Some(false)) | ("skip", Some(true)) => return Ok(false),
+                ("include" | "skip", Some(_)) => {}
+                ("include" | "skip", None) => return Err(format!("@{} needs a Boolean 'if' argument", directive.name)),
+                (other, _) => return Err(format!("unknown directive @{}", other)),
+            }
+        }
+        Ok(true)
+    }
+
+    /// The fields selected on an object of `type_name`, merged by response key.
+    fn collect(&mut self, type_name: &str, selection: &[&'a Selection], fields: &mut Vec<CollectedField<'a>>, depth: usize) -> Result<(), String> {
+        if depth > MAX_DEPTH {
+            return Err("fragments are nested too deeply".to_string());
+        }
+        for item in selection {
+            match item {
+                Selection::Field {
+                    alias,
+                    name,
+                    args,
+                    directives,
+                    selection,
+                } => {
+                    if !self.included(directives)? {
+                        continue;
+                    }
+                    let key = alias.clone().unwrap_or_else(|| name.clone());
+                    match fields.iter_mut().find(|field| field.key == key) {
+                        Some(field) if field.name == name.as_str() => field.selection.extend(selection.iter()),
+                        Some(_) => return Err(format!("'{}' names two different fields", key)),
+                        None => fields.push(CollectedField {
+                            key,
+                            name,
+                            args,
+                            selection: selection.iter().collect(),
+                        }),
+                    }
+                }
+                Selection::Spread { name, directives } => {
+                    if !self.included(directives)? {
+                        continue;
+                    }
+                    let document = self.document;
+                    let fragment = document.fragments.get(name).ok_or_else(|| format!("unknown fragment '{}'", name))?;
+                    if fragment.on == type_name {
+                        let selection: Vec<&Selection> = fragment.selection.iter().collect();
+                        self.collect(type_name, &selection, fields, depth + 1)?;
+                    }
+                }
+                Selection::Inline { on, directives, selection } => {
+                    if self.included(directives)? && on.as_deref().is_none_or(|on| on == type_name) {
+                        let selection: Vec<&Selection> = selection.iter().collect();
+                        self.collect(type_name, &selection, fields, depth + 1)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts the lookups the root fields would make, aliases and fragments expanded, and
+    /// refuses the document when they are more than the root allows. Fields that cannot be
+    /// resolved make none; `root_selection` reports them.
+    fn check_lookups(&mut self, root_type: &str, selection: &'a [Selection]) -> Result<(), String> {
+        let lookups = self.count(root_type, selection, |root, field, args| root.lookups(field, args));
+        let max = self.root.max_lookups();
+        if lookups > max {
+            return Err(format!("the document makes {} lookups, at most {} are allowed, start a job for more", lookups, max));
+        }
+        Ok(())
+    }
+
+    /// Sums `per_field` over the root fields, aliases and fragments expanded. Fields that
+    /// cannot be resolved count for nothing.
+    fn count(&mut self, root_type: &str, selection: &'a [Selection], per_field: impl Fn(&dyn Root, &str, &JsonValue) -> usize) -> usize {
+        let mut fields = Vec::new();
+        if self.collect(root_type, &selection.iter().collect::<Vec<_>>(), &mut fields, 0).is_err() {
+            return 0;
+        }
+        let definitions = type_fields(root_type).unwrap_or_default();
+        let mut total = 0usize;
+        for field in &fields {
+            let Some(definition) = definitions.iter().find(|definition| definition.name == field.name) else {
+                continue;
+            };
+            if let Ok(args) = self.root_args(definition, field.args) {
+                total = total.saturating_add(per_field(self.root, field.name, &args));
+            }
+        }
+        total
+    }
+
+    /// Runs the root fields; a failing field is null, with its error reported.
+    fn root_selection(&mut self, root_type: &str, selection: &'a [Selection]) -> JsonValue {
+        let mut fields = Vec::new();
+        if let Err(e) = self.collect(root_type, &selection.iter().collect::<Vec<_>>(), &mut fields, 0) {
+            self.error(e, &[]);
+            return JsonValue::Null;
+        }
+        let definitions = type_fields(root_type).unwrap_or_default();
+        let mut data = JsonValue::object();
+        for field in fields {
+            let path = [JsonValue::from(field.key.as_str())];
+            if field.name == "__typename" {
+                data = data.with(&field.key, root_type);
+                continue;
+            }
+            let Some(definition) = definitions.iter().find(|definition| definition.name == field.name) else {
+                self.error(format!("cannot query field '{}' on type {}", field.name, root_type), &path);
+                data = data.with(&field.key, JsonValue::Null);
+                continue;
+            };
+            let value = self.root_args(definition, field.args).and_then(|args| match (root_type, field.name) {
+                (_, "_service") => Ok(JsonValue::object().with("sdl", schema_sdl())),
+                ("Mutation", name) => self.root.mutate(name, &args),
+                (_, name) => self.root.query(name, &args),
+            });
+            let value = match value {
+                Ok(value) => self.complete(value, definition.ty, &field.selection, &path),
+                Err(e) => {
+                    self.error(e, &path);
+                    JsonValue::Null
+                }
+            };
+            data = data.with(&field.key, value);
+        }
+        data
+    }
+
+    /// The arguments of a root field, checked against its definition.
+    fn root_args(&self, definition: &FieldDef, args: &[(String, Value)]) -> Result<JsonValue, String> {
+        let mut values = JsonValue::object();
+        for (name, value) in args {
+            if !definition.args.iter().any(|(arg, _)| arg == name) {
+                return Err(format!("unknown argument '{}' of field '{}'", name, definition.name));
+            }
+            values = values.with(name, resolve_value(value, &self.variables)?);
+        }
+        for (name, ty) in definition.args {
+            let value = values.get(name).cloned().unwrap_or(JsonValue::Null);
+            check_type(&value, ty).map_err(|e| format!("argument '{}' of field '{}': {}", name, definition.name, e))?;
+        }
+        Ok(values)
+    }
+
+    /// Shapes a resolved value to its type and selection.
+    fn complete(&mut self, value: JsonValue, ty: &str, selection: &[&'a Selection], path: &[JsonValue]) -> JsonValue {
+        if let Some(inner) = ty.strip_suffix('!') {
+            if value == JsonValue::Null {
+                self.error(format!("null value for non-null field of type {}", ty), path);
+            }
+            return self.complete(value, inner, selection, path);
+        }
+        if value == JsonValue::Null {
+            return JsonValue::Null;
+        }
+        if let Some(item) = ty.strip_prefix('[').and_then(|ty| ty.strip_suffix(']')) {
+            let JsonValue::Array(items) = value else {
+                self.error(format!("expected a list of {}", item), path);
+                return JsonValue::Null;
+            };
+            let items = items.into_iter().enumerate().map(|(index, value)| {
+                let mut path = path.to_vec();
+                path.push(JsonValue::Int(index as i64));
+                self.complete(value, item, selection, &path)
+            });
+            return JsonValue::Array(items.collect());
+        }
+        let Some(definitions) = type_fields(ty) else {
+            // A scalar: it takes no selection
+            if !selection.is_empty() {
+                self.error(format!("field of type {} takes no selection", ty), path);
+            }
+            return value;
+        };
+        if selection.is_empty() {
+            self.error(format!("field of type {} needs a selection", ty), path);
+            return JsonValue::Null;
+        }
+        let mut fields = Vec::new();
+        if let Err(e) = self.collect(ty, selection, &mut fields, 0) {
+            self.error(e, path);
+            return JsonValue::Null;
+        }
+        let mut object = JsonValue::object();
+        for field in fields {
+            let mut field_path = path.to_vec();
+            field_path.push(JsonValue::from(field.key.as_str()));
+            if field.name == "__typename" {
+                object = object.with(&field.key, ty);
+                continue;
+            }
+            let Some(definition) = definitions.iter().find(|definition| definition.name == field.name) else {
+                self.error(format!("cannot query field '{}' on type {}", field.name, ty), &field_path);
+                continue;
+            };
+            if let Some((name, _)) = field.args.first() {
+                self.error(format!("unknown argument '{}' of field '{}'", name, field.name), &field_path);
+            }
+            let value = value.get(definition.key).cloned().unwrap_or(JsonValue::Null);
+            let value = self.complete(value, definition.ty, &field.selection, &field_path);
+            object = object.with(&field.key, value);
+        }
+        object
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    /// Answers `nif` and `nifs` with results of status `valid_known`, counting the lookups made.
+    #[derive(Default)]
+    struct FakeRoot {
+        lookups: Cell<usize>,
+    }
+
+    impl FakeRoot {
+        fn result(&self, nif: &str) -> JsonValue {
+            self.lookups.set(self.lookups.get() + 1);
+            JsonValue::object().with("nif", nif).with("status", "valid_known").with("valid_locally", true)
+        }
+    }
+
+    impl Root for FakeRoot {
+        fn query(&self, field: &str, args: &JsonValue) -> Result<JsonValue, String> {
+            match field {
+                "nif" => Ok(self.result(args.str_field("nif").unwrap_or_default())),
+                "nifs" => match args.get("nifs") {
+                    Some(JsonValue::Array(nifs)) => Ok(JsonValue::Array(nifs.iter().map(|nif| self.result(nif.as_str().unwrap_or_default())).collect())),
+                    _ => Err("argument 'nifs' is required".to_string()),
+                },
+                "jobs" => Ok(JsonValue::Array(Vec::new())),
+                other => Err(format!("no resolver for field '{}'", other)),
+            }
+        }
+
+        fn mutate(&self, field: &str, _args: &JsonValue) -> Result<JsonValue, String> {
+            Err(format!("no resolver for field '{}'", field))
+        }
+
+        fn lookups(&self, field: &str, args: &JsonValue) -> usize {
+            match field {
+                "nif" => 1,
+                "nifs" => args.get("nifs").and_then(JsonValue::as_array).map_or(0, <[JsonValue]>::len),
+                _ => 0,
+            }
+        }
+
+        fn max_lookups(&self) -> usize {
+            3
+        }
+    }
+
+    fn run(query: &str, variables: Option<&str>) -> (JsonValue, usize) {
+        let request = GraphqlRequest {
+            query: query.to_string(),
+            variables: variables.map(|variables| JsonValue::parse(variables).unwrap_or(JsonValue::Null)),
+            operation_name: None,
+        };
+        let root = FakeRoot::default();
+        let response = execute(&request, &root);
+        (response, root.lookups.get())
+    }
+
+    fn error_message(response: &JsonValue) -> String {
+        let errors = response.get("errors").and_then(JsonValue::as_array).unwrap_or_default();
+        errors.first().and_then(|error| error.str_field("message")).unwrap_or_default().to_string()
+    }
+
+    #[test]
+    fn aliases_fragments_and_variables() {
+        let (response, lookups) = run(
+            "query Q($nif: String!, $full: Boolean = false) {
+                a: nif(nif: $nif) { ...Result }
+                b: nif(nif: \"123456789\") { nif ... on LookupResult @include(if: $full) { validLocally } }
+            }
+            fragment Result on LookupResult { nif status __typename }",
+            Some(r#"{"nif": "500960046"}"#),
+        );
+        assert_eq!(
+            response.to_string(),
+            r#"{"data":{"a":{"nif":"500960046","status":"valid_known","__typename":"LookupResult"},"b":{"nif":"123456789"}}}"#
+        );
+        assert_eq!(lookups, 2);
+    }
+
+    #[test]
+    fn unknown_field_rejected_before_lookups() {
+        let (response, lookups) = run("{ nif(nif: \"500960046\") { status } other }", None);
+        assert_eq!(error_message(&response), "cannot query field 'other' on type Query");
+        assert!(response.get("data").is_none());
+        assert_eq!(lookups, 0);
+    }
+
+    #[test]
+    fn missing_variable() {
+        let (response, lookups) = run("query($nif: String!) { nif(nif: $nif) { status } }", None);
+        assert_eq!(error_message(&response), "variable $nif: expected String!, found null");
+        assert_eq!(lookups, 0);
+    }
+
+    #[test]
+    fn aliased_lookups_counted() {
+        let (response, lookups) = run(
+            "{ a: nif(nif: \"1\") { status } b: nif(nif: \"2\") { status } c: nif(nif: \"3\") { status } }",
+            None,
+        );
+        assert!(response.get("errors").is_none(), "{}", response);
+        assert_eq!(lookups, 3);
+
+        let (response, lookups) = run(
+            "{ a: nif(nif: \"1\") { status } b: nif(nif: \"2\") { status } c: nif(nif: \"3\") { status } d: nif(nif: \"4\") { status } }",
+            None,
+        );
+        assert_eq!(error_message(&response), "the document makes 4 lookups, at most 3 are allowed, start a job for more");
+        assert!(response.get("data").is_none());
+        assert_eq!(lookups, 0);
+    }
+
+    #[test]
+    fn lookups_counted_across_fragments_and_lists() {
+        let (response, lookups) = run(
+            "{ ...A ...B } fragment A on Query { a: nif(nif: \"1\") { status } } fragment B on Query { b: nifs(nifs: [\"2\", \"3\", \"4\"]) { status } }",
+            None,
+        );
+        assert_eq!(error_message(&response), "the document makes 4 lookups, at most 3 are allowed, start a job for more");
+        assert_eq!(lookups, 0);
+
+        // The same field selected twice is merged, and looked up once
+        let (response, lookups) = run("{ nif(nif: \"1\") { status } ...F } fragment F on Query { nif(nif: \"1\") { nif } }", None);
+        assert!(response.get("errors").is_none(), "{}", response);
+        assert_eq!(lookups, 1);
+    }
+
+    #[test]
+    fn skipped_fields_make_no_lookups() {
+        let (response, lookups) = run(
+            "query($skip: Boolean!) { a: nifs(nifs: [\"1\", \"2\"]) { status } b: nifs(nifs: [\"3\", \"4\"]) @skip(if: $skip) { status } }",
+            Some(r#"{"skip": true}"#),
+        );
+        assert!(response.get("errors").is_none(), "{}", response);
+        assert_eq!(lookups, 2);
+    }
+
+    #[test]
+    fn exponential_fragments_rejected() {
+        // Each fragment spreads the next one twice: 2^20 copies of the innermost field
+        let mut query = "{ ...F0 }".to_string();
+        for level in 0..20 {
+            query.push_str(&format!(" fragment F{} on Query {{ ...F{} ...F{} }}", level, level + 1, level + 1));
+        }
+        query.push_str(" fragment F20 on Query { jobs { id } }");
+        let (response, lookups) = run(&query, None);
+        assert_eq!(error_message(&response), "the document expands to more than 10000 selections");
+        assert_eq!(lookups, 0);
+    }
+
+    #[test]
+    fn fragment_cycle_rejected() {
+        let (response, _) = run("{ ...A } fragment A on Query { ...A }", None);
+        assert_eq!(error_message(&response), "fragments are nested too deeply");
+    }
+
+    #[test]
+    fn syntax_error() {
+        let (response, _) = run("{ nif(nif: \"1\") { status }", None);
+        assert!(response.get("data").is_none());
+        assert!(!error_message(&response).is_empty());
+    }
+
+    #[test]
+    fn mutations_told_apart() {
+        let request = |query: &str| GraphqlRequest {
+            query: query.to_string(),
+            ..GraphqlRequest::default()
+        };
+        assert!(request("mutation { cancelJob(id: \"1\") }").is_mutation());
+        assert!(!request("{ jobs { id } }").is_mutation());
+        assert!(!request("mutation {").is_mutation());
+    }
+
+    #[test]
+    fn cost_counted_before_running() {
+        let request = |query: &str| GraphqlRequest {
+            query: query.to_string(),
+            ..GraphqlRequest::default()
+        };
+        let root = FakeRoot::default();
+        assert_eq!(cost(&request("{ a: nif(nif: \"1\") { nif } b: nifs(nifs: [\"2\", \"3\"]) { nif } }"), &root), 3);
+        assert_eq!(root.lookups.get(), 0);
+        // Refused documents cost nothing
+        assert_eq!(cost(&request("{ nifs(nifs: [\"1\", \"2\", \"3\", \"4\"]) { nif } }"), &root), 0);
+        assert_eq!(cost(&request("{ nif(nif: \"1\") { unknown } }"), &root), 0);
+    }
+
+    #[test]
+    fn sdl() {
+        let sdl = schema_sdl();
+        assert!(sdl.starts_with("type Query {\n  nif(nif: String!): LookupResult!\n"));
+        assert!(sdl.contains("type Mutation {\n  startJob(nifs: [String!]!): Job!\n"));
+    }
+}
@@ -0,0 +1,129 @@
+// health.rs
+
+//! Health of the fallback sites, scored from the answers of recent requests, so the chain
+//! asks the healthy sites first and traffic moves away from a degraded one by itself.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::fallback::Fallback;
+use crate::json::JsonValue;
+use crate::logging;
+
+/// Weight of the latest request in the moving averages.
+const ALPHA: f64 = 0.2;
+/// A site reorders ahead of another only when its score is this much higher, so two
+/// sites of about the same health do not swap places on every request.
+const HYSTERESIS: f64 = 1.25;
+/// The failures of a site not asked for this long count half as much, so a site moved
+/// to the back of the chain comes forward again to be tried.
+const FORGET_HALF_LIFE: Duration = Duration::from_secs(300);
+/// Latency that halves the score of a site.
+const SLOW: Duration = Duration::from_secs(2);
+
+/// Moving averages of the requests to one site.
+#[derive(Debug, Clone, Copy)]
+struct SiteHealth {
+    success: f64,    // Share of requests answered, 1 for a site never asked
+    latency: f64,    // Seconds
+    requests: u64,
+    last: Instant,   // Time of the latest request
+}
+
+impl SiteHealth {
+    /// Success rate, failures fading back towards full health while the site is not asked.
+    fn success(&self, now: Instant) -> f64 {
+        let idle = now.saturating_duration_since(self.last).as_secs_f64();
+        let kept = 0.5f64.powf(idle / FORGET_HALF_LIFE.as_secs_f64());
+        1.0 - (1.0 - self.success) * kept
+    }
+
+    fn score(&self, now: Instant) -> f64 {
+        self.success(now) / (1.0 + self.latency / SLOW.as_secs_f64())
+    }
+}
+
+#[derive(Debug, Default)]
+struct HealthInner {
+    sites: HashMap<Fallback, SiteHealth>,
+    order: Vec<Fallback>, // Current order of the chain, starting from the configured one
+}
+
+/// Scores of the fallback sites, shared by every lookup made with the same options.
+#[derive(Debug, Default)]
+pub struct BackendHealth {
+    inner: Mutex<HealthInner>,
+}
+
+impl BackendHealth {
+    /// Records a request to a site: whether it answered (a company page or none), and how
+    /// long it took.
+    pub fn record(&self, fallback: Fallback, answered: bool, latency: Duration) {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        let site = inner.sites.entry(fallback).or_insert(SiteHealth {
+            success: 1.0,
+            latency: latency.as_secs_f64(),
+            requests: 0,
+            last: now,
+        });
+        site.success = site.success(now) * (1.0 - ALPHA) + if answered { ALPHA } else { 0.0 };
+        site.latency = site.latency * (1.0 - ALPHA) + latency.as_secs_f64() * ALPHA;
+        site.requests += 1;
+        site.last = now;
+    }
+
+    /// Order in which to ask the `configured` sites: theirs at first, then a site moves
+    /// ahead of the one before it when its score is clearly higher.
+    pub fn order(&self, configured: &[Fallback]) -> Vec<Fallback> {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        if inner.order.len() != configured.len() || !configured.iter().all(|fallback| inner.order.contains(fallback)) {
+            inner.order = configured.to_vec();
+        }
+        let score = |fallback: &Fallback| inner.sites.get(fallback).map_or(1.0, |site| site.score(now));
+        let mut order = inner.order.clone();
+        let mut moved = true;
+        while moved {
+            moved = false;
+            for i in 1..order.len() {
+                if score(&order[i]) > score(&order[i - 1]) * HYSTERESIS {
+                    order.swap(i, i - 1);
+                    moved = true;
+                }
+            }
+        }
+        if order != inner.order {
+            let names: Vec<&str> = order.iter().map(Fallback::name).collect();
+            logging::info(
+                "fallback_order_changed",
+                &[("order", names.join(",").into())],
+                format!("Fallback sites now asked in this order: {}", names.join(", ")),
+            );
+            inner.order = order.clone();
+        }
+        order
+    }
+
+    /// Scores of the sites asked so far, for `/stats`.
+    pub fn to_json(&self) -> JsonValue {
+        let now = Instant::now();
+        let inner = self.inner.lock().unwrap();
+        let mut sites: Vec<(&Fallback, &SiteHealth)> = inner.sites.iter().collect();
+        sites.sort_by_key(|(fallback, _)| fallback.name());
+        let sites: Vec<JsonValue> = sites
+            .into_iter()
+            .map(|(fallback, site)| {
+                JsonValue::object()
+                    .with("name", fallback.name())
+                    .with("requests", site.requests as i64)
+                    .with("success_rate", site.success(now))
+                    .with("latency_ms", site.latency * 1000.0)
+                    .with("score", site.score(now))
+            })
+            .collect();
+        let order: Vec<JsonValue> = inner.order.iter().map(|fallback| JsonValue::from(fallback.name())).collect();
+        JsonValue::object().with("order", order).with("sites", sites)
+    }
+}
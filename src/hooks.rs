@@ -0,0 +1,359 @@
+// hooks.rs
+
+use std::io::Write;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::entity::NifEntity;
+use crate::json::JsonValue;
+#[cfg(feature = "kafka")]
+use crate::kafka::KafkaProducer;
+use crate::logging::{self, nif_field};
+use crate::lookup::LookupResult;
+use crate::output::OutputWriter;
+use crate::store::{content_hash, is_removal, ResultStore, StoreRecord};
+use crate::time::format_duration;
+
+/// How long a hook command may run before it is killed (`--hook-timeout`).
+pub const DEFAULT_HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Pause between two checks of whether a hook command exited.
+const HOOK_POLL: Duration = Duration::from_millis(10);
+
+/// A shell command run for lookup results, receiving the result JSON on stdin.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandHook {
+    pub command: String,   // Run with `sh -c`
+    pub timeout: Duration, // After which the command is killed
+}
+
+impl CommandHook {
+    /// Runs the command with `payload` on stdin and waits for it; a non-zero exit is an error,
+    /// and so is a command still running after `timeout`, which is killed with the processes
+    /// it started.
+    ///
+    /// The NIF and status are also in the `CHECK_NIF_NIF` and `CHECK_NIF_STATUS`
+    /// environment variables, for one-liners that do not parse JSON.
+    pub fn run(&self, result: &LookupResult, payload: &JsonValue) -> Result<(), String> {
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(&self.command)
+            .env("CHECK_NIF_NIF", &result.nif)
+            .env("CHECK_NIF_STATUS", result.status.label())
+            .stdin(Stdio::piped());
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0); // Killed as a whole
+        let mut child = command.spawn().map_err(|e| format!("cannot run '{}': {}", self.command, e))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // Written aside, so a command that does not read its input cannot block us; one
+            // that exits without reading closes the pipe early, that is fine
+            let payload = payload.to_string();
+            thread::spawn(move || writeln!(stdin, "{}", payload));
+        }
+        let started = Instant::now();
+        let status = loop {
+            match child.try_wait().map_err(|e| format!("cannot wait for '{}': {}", self.command, e))? {
+                Some(status) => break status,
+                None if started.elapsed() >= self.timeout => {
+                    kill(&mut child);
+                    return Err(format!("'{}' still running after {}, killed", self.command, format_duration(self.timeout)));
+                }
+                None => thread::sleep(HOOK_POLL),
+            }
+        };
+        if status.success() {
+            Ok(())
+        } else {
+            Err(format!("'{}' failed ({})", self.command, status))
+        }
+    }
+}
+
+/// Kills a hook command and the processes it started, in its own process group.
+fn kill(child: &mut Child) {
+    #[cfg(unix)]
+    // SAFETY: kill only sends a signal, to the group of our own child
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Commands run after lookups: `on_result` for every result, `on_change` when a result
+/// differs from the store record of the NIF. With `kafka`, every result is also published,
+/// as a `result`, `change` or `removal` event.
+#[derive(Debug, Clone, Default)]
+pub struct Hooks {
+    pub on_result: Vec<CommandHook>,
+    pub on_change: Vec<CommandHook>,
+    #[cfg(feature = "kafka")]
+    pub kafka: Option<Arc<KafkaProducer>>,
+}
+
+impl Hooks {
+    /// Tells whether no hook is configured.
+    pub fn is_empty(&self) -> bool {
+        #[cfg(feature = "kafka")]
+        if self.kafka.is_some() {
+            return false;
+        }
+        self.on_result.is_empty() && self.on_change.is_empty()
+    }
+
+    /// Tells whether the hooks tell changes apart from other results (`on_change`, and the
+    /// events of `kafka`), so that lookups must be compared with the store, not answered by it.
+    pub fn tracks_changes(&self) -> bool {
+        #[cfg(feature = "kafka")]
+        if self.kafka.is_some() {
+            return true;
+        }
+        !self.on_change.is_empty()
+    }
+
+    /// Runs the hooks of `result`, given the store record of the NIF before the lookup.
+    ///
+    /// A change is a definitive answer whose `content_hash` differs from the stored one;
+    /// failed lookups and NIFs missing from the store never count as changes. A known
+    /// entity that nif.pt now rejects or does not know is a removal, whose payload also has
+    /// `removed` and the `previous_entity`. Hook failures are logged and do not stop the run.
+    pub fn notify(&self, result: &LookupResult, previous: Option<&StoreRecord>) {
+        if self.is_empty() {
+            return;
+        }
+        let mut payload = result.to_json();
+        if let Some(previous) = previous {
+            payload = payload.with("previous_status", previous.status.label());
+        }
+        let changed = previous.is_some_and(|previous| is_change(result, previous));
+        // The entity nif.pt no longer knows goes with the change, as the result has none
+        let removed = previous.filter(|previous| is_removal(previous.status, result.status) && result.entity.is_none());
+        if let Some(previous) = removed {
+            payload = payload
+                .with("removed", true)
+                .with("previous_entity", previous.entity.as_ref().map(NifEntity::to_json));
+        }
+        #[cfg(feature = "kafka")]
+        if let Some(kafka) = &self.kafka {
+            // Keyed by NIF, so the events of a NIF stay in order
+            let event = match (changed, removed) {
+                (true, Some(_)) => "removal",
+                (true, None) => "change",
+                (false, _) => "result",
+            };
+            if let Err(e) = kafka.publish(&result.nif, payload.to_string().as_bytes(), &[("event", event)]) {
+                logging::warn(
+                    "kafka_publish_failed",
+                    &[nif_field(&result.nif), ("topic", kafka.topic().into()), ("error", e.clone().into())],
+                    format!("Cannot publish the result to Kafka: {}", e),
+                );
+            }
+        }
+        let hooks = self.on_result.iter().chain(self.on_change.iter().filter(|_| changed));
+        for hook in hooks {
+            if let Err(e) = hook.run(result, &payload) {
+                logging::warn(
+                    "hook_failed",
+                    &[nif_field(&result.nif), ("command", hook.command.as_str().into()), ("error", e.clone().into())],
+                    format!("Hook {}", e),
+                );
+            }
+        }
+    }
+}
+
+/// Tells whether `result` is a definitive answer telling something else than `previous`, the
+/// store record of the NIF, by their `content_hash`.
+pub fn is_change(result: &LookupResult, previous: &StoreRecord) -> bool {
+    result.status.is_definitive() && content_hash(result.status, result.entity.as_ref()) != previous.content_hash()
+}
+
+/// Output writer running the hooks of each result, next to the actual output.
+///
+/// The hooks run on a thread of their own, in the order of the results, so slow commands do
+/// not hold up the lookups; `finish` waits for those still to run.
+///
+/// The store record read for `on_change` is the one before the lookup only if nothing wrote
+/// the result into the store yet: put a `StoreWriter` after this writer, not before.
+pub struct HookWriter {
+    store: Option<Arc<dyn ResultStore>>, // Where previous answers are read, for `on_change`
+    sender: Option<Sender<(LookupResult, Option<StoreRecord>)>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl HookWriter {
+    pub fn new(hooks: Hooks, store: Option<Arc<dyn ResultStore>>) -> Self {
+        let (sender, receiver) = mpsc::channel::<(LookupResult, Option<StoreRecord>)>();
+        let worker = thread::spawn(move || {
+            for (result, previous) in receiver {
+                hooks.notify(&result, previous.as_ref());
+            }
+        });
+        HookWriter {
+            store,
+            sender: Some(sender),
+            worker: Some(worker),
+        }
+    }
+}
+
+impl OutputWriter for HookWriter {
+    fn write(&mut self, result: &LookupResult) -> Result<(), String> {
+        let previous = self.store.as_ref().and_then(|store| store.get(&result.nif));
+        let sender = self.sender.as_ref().ok_or("hooks already finished")?;
+        sender.send((result.clone(), previous)).map_err(|_| "the hook thread stopped".to_string())
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        self.sender = None; // Ends the worker once the results sent are done
+        match self.worker.take().map(JoinHandle::join) {
+            Some(Err(_)) => Err("the hook thread panicked".to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Passes on to another writer only the results that moved since the store recorded them,
+/// for `--changed-only`: changes as for `on_change`, and definitive answers for NIFs the store
+/// does not have yet.
+///
+/// The results must come from lookups that did not use the store (`LookupOptions::store`
+/// unset), else a stored NIF is compared with itself; and a `StoreWriter` after this writer
+/// records them, for the next run to compare with.
+pub struct ChangedWriter {
+    inner: Box<dyn OutputWriter>,
+    store: Arc<dyn ResultStore>,
+}
+
+impl ChangedWriter {
+    pub fn new(inner: Box<dyn OutputWriter>, store: Arc<dyn ResultStore>) -> Self {
+        ChangedWriter { inner, store }
+    }
+}
+
+impl OutputWriter for ChangedWriter {
+    fn write(&mut self, result: &LookupResult) -> Result<(), String> {
+        let changed = match self.store.get(&result.nif) {
+            Some(previous) => is_change(result, &previous),
+            None => result.status.is_definitive(),
+        };
+        if !changed {
+            return Ok(());
+        }
+        self.inner.before_lookup(&result.nif)?;
+        self.inner.write(result)
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        self.inner.finish()
+    }
+}
+
+/// Writes the definitive answers of lookups into the store, as the new current records of
+/// their NIFs (tombstones for known entities gone from nif.pt), so that runs comparing fresh
+/// lookups with the store (`--changed-only`, `on_change`) compare the next run with this one.
+pub struct StoreWriter {
+    store: Arc<dyn ResultStore>,
+}
+
+impl StoreWriter {
+    pub fn new(store: Arc<dyn ResultStore>) -> Self {
+        StoreWriter { store }
+    }
+}
+
+impl OutputWriter for StoreWriter {
+    fn write(&mut self, result: &LookupResult) -> Result<(), String> {
+        if !result.status.is_definitive() {
+            return Ok(());
+        }
+        let source = result.source.backend().to_string();
+        let now = SystemTime::now();
+        let record = match self.store.get(&result.nif) {
+            Some(old) => old.updated(result.status, result.entity.clone(), source, now),
+            None => StoreRecord {
+                nif: result.nif.clone(),
+                status: result.status,
+                entity: result.entity.clone(),
+                source,
+                recorded_at: now,
+                removed_at: None,
+                tag: result.tag.clone(),
+            },
+        };
+        self.store.put(vec![record])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lookup::{LookupReport, LookupSource};
+    use crate::status::NifStatus;
+
+    fn result(nif: &str) -> LookupResult {
+        LookupResult {
+            nif: nif.to_string(),
+            status: NifStatus::ValidUnknown,
+            entity: None,
+            candidates: Vec::new(),
+            postal_check: None,
+            name_check: None,
+            location: None,
+            parse_confidence: None,
+            checked_at: None,
+            confidence: None,
+            tag: None,
+            source: LookupSource::Remote,
+            report: LookupReport::default(),
+        }
+    }
+
+    fn hook(command: &str, timeout: Duration) -> CommandHook {
+        CommandHook { command: command.to_string(), timeout }
+    }
+
+    #[test]
+    fn command_gets_the_result() {
+        let result = result("500960046");
+        let checked = hook(r#"grep -q '"nif":"500960046"' && test "$CHECK_NIF_STATUS" = valid_unknown"#, DEFAULT_HOOK_TIMEOUT);
+        assert_eq!(checked.run(&result, &result.to_json()), Ok(()));
+        let error = hook("exit 3", DEFAULT_HOOK_TIMEOUT).run(&result, &result.to_json()).unwrap_err();
+        assert!(error.contains("failed"), "{}", error);
+    }
+
+    #[test]
+    fn command_killed_after_its_timeout() {
+        let result = result("500960046");
+        let started = Instant::now();
+        // The payload is not read, and the shell waits for a child of its own
+        let error = hook("sleep 30; true", Duration::from_millis(200)).run(&result, &JsonValue::from("x".repeat(1 << 20))).unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+        assert!(error.contains("still running after"), "{}", error);
+    }
+
+    #[test]
+    fn writer_runs_hooks_in_order_before_finishing() {
+        let dir = std::env::temp_dir().join(format!("check_nif-hooks-order-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("nifs.txt");
+        let hooks = Hooks {
+            on_result: vec![hook(&format!(r#"sleep 0.1; echo "$CHECK_NIF_NIF" >> '{}'"#, log.display()), DEFAULT_HOOK_TIMEOUT)],
+            ..Hooks::default()
+        };
+        let mut writer = HookWriter::new(hooks, None);
+        let started = Instant::now();
+        for nif in ["500960046", "509442013", "501442600"] {
+            writer.write(&result(nif)).unwrap();
+        }
+        assert!(started.elapsed() < Duration::from_millis(100), "{:?}", started.elapsed());
+        writer.finish().unwrap();
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "500960046\n509442013\n501442600\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,304 @@
+// http.rs
+
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Read, Write};
+
+use crate::encoding::percent_decode;
+use crate::json::JsonValue;
+
+/// Largest request head (request line and headers) accepted, in bytes.
+const MAX_HEAD: usize = 64 * 1024;
+/// Largest request body accepted, in bytes.
+pub const MAX_BODY: usize = 1024 * 1024;
+
+/// An HTTP/1.1 request as received by the server.
+#[derive(Debug, Clone, Default)]
+pub struct Request {
+    pub method: String,
+    pub path: String,                   // Without the query string, percent-decoded
+    pub query: Vec<(String, String)>,   // Decoded query parameters, in order
+    pub headers: Vec<(String, String)>, // Names lowercased
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Returns the first header called `name` (lowercase).
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    /// Returns the first query parameter called `name`.
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+}
+
+/// Why a request could not be read.
+#[derive(Debug)]
+pub enum RequestError {
+    Closed,           // The client closed the connection before sending anything
+    Io(io::Error),    // Read failure or timeout
+    Invalid(String),  // Malformed request, answered with 400
+    TooLarge(String), // Head or body over the limits, answered with 413 or 431
+}
+
+/// Reads one request from `reader`.
+pub fn read_request(reader: &mut impl BufRead) -> Result<Request, RequestError> {
+    let mut head_size = 0;
+    let mut read_line = |reader: &mut dyn BufRead| -> Result<Option<String>, RequestError> {
+        let mut line = Vec::new();
+        let read = reader
+            .take((MAX_HEAD - head_size) as u64 + 1)
+            .read_until(b'\n', &mut line)
+            .map_err(RequestError::Io)?;
+        head_size += read;
+        if head_size > MAX_HEAD {
+            return Err(RequestError::TooLarge("request head too large".to_string()));
+        }
+        if read == 0 {
+            return Ok(None);
+        }
+        let line = String::from_utf8(line).map_err(|_| RequestError::Invalid("request head is not UTF-8".to_string()))?;
+        Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+    };
+
+    let request_line = read_line(reader)?.ok_or(RequestError::Closed)?;
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(RequestError::Invalid(format!("invalid request line '{}'", request_line)));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(RequestError::Invalid(format!("unsupported HTTP version '{}'", version)));
+    }
+
+    let mut request = Request {
+        method: method.to_string(),
+        ..Request::default()
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    request.path = percent_decode(path);
+    request.query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(name), percent_decode(&value.replace('+', " ")))
+        })
+        .collect();
+
+    loop {
+        let line = read_line(reader)?.ok_or_else(|| RequestError::Invalid("unexpected end of headers".to_string()))?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| RequestError::Invalid(format!("invalid header line '{}'", line)))?;
+        request.headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+    }
+
+    if request.header("transfer-encoding").is_some() {
+        return Err(RequestError::Invalid("chunked request bodies are not supported".to_string()));
+    }
+    if let Some(length) = request.header("content-length") {
+        let length: usize = length
+            .parse()
+            .map_err(|_| RequestError::Invalid(format!("invalid Content-Length '{}'", length)))?;
+        if length > MAX_BODY {
+            return Err(RequestError::TooLarge(format!("request body over {} bytes", MAX_BODY)));
+        }
+        request.body = vec![0; length];
+        reader.read_exact(&mut request.body).map_err(RequestError::Io)?;
+    }
+    Ok(request)
+}
+
+/// An HTTP response about to be sent.
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub stream: Option<BodyStream>, // Written after `body`, until the connection closes
+}
+
+/// Function writing a body of unknown length to the connection.
+pub type StreamWriter = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()> + Send>;
+
+/// Writer producing a body of unknown length, such as a Server-Sent Events stream.
+pub struct BodyStream(pub StreamWriter);
+
+impl std::fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("BodyStream")
+    }
+}
+
+impl Response {
+    /// Response with a JSON body.
+    pub fn json(status: u16, body: &JsonValue) -> Self {
+        Response {
+            status,
+            headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+            body: format!("{}\n", body).into_bytes(),
+            stream: None,
+        }
+    }
+
+    /// Response with an HTML page.
+    pub fn html(status: u16, page: &str) -> Self {
+        Response {
+            status,
+            headers: vec![("Content-Type".to_string(), "text/html; charset=utf-8".to_string())],
+            body: page.as_bytes().to_vec(),
+            stream: None,
+        }
+    }
+
+    /// Response without a body.
+    pub fn empty(status: u16) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            stream: None,
+        }
+    }
+
+    /// Response whose body is produced by `write` until it returns; the length is not
+    /// announced, the end of the body is the end of the connection.
+    pub fn streaming(
+        content_type: &str,
+        write: impl FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static,
+    ) -> Self {
+        Response {
+            status: 200,
+            headers: vec![
+                ("Content-Type".to_string(), content_type.to_string()),
+                ("Cache-Control".to_string(), "no-cache".to_string()),
+            ],
+            body: Vec::new(),
+            stream: Some(BodyStream(Box::new(write))),
+        }
+    }
+
+    /// JSON error response: `{"error": message}`.
+    pub fn error(status: u16, message: &str) -> Self {
+        Response::json(status, &JsonValue::object().with("error", message))
+    }
+
+    /// Adds a header and returns the response, for chaining.
+    pub fn with_header(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.headers.push((name.to_string(), value.into()));
+        self
+    }
+
+    /// Writes the response, closing the exchange (`Connection: close`).
+    pub fn write_to(self, out: &mut impl Write) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in &self.headers {
+            let _ = write!(head, "{}: {}\r\n", name, value);
+        }
+        if self.stream.is_none() {
+            let _ = write!(head, "Content-Length: {}\r\n", self.body.len());
+        }
+        head.push_str("Connection: close\r\n\r\n");
+        out.write_all(head.as_bytes())?;
+        out.write_all(&self.body)?;
+        out.flush()?;
+        match self.stream {
+            Some(BodyStream(write)) => write(out),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reason phrase of the status codes the server sends.
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Content Too Large",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(text: &str) -> Result<Request, RequestError> {
+        read_request(&mut io::Cursor::new(text.as_bytes().to_vec()))
+    }
+
+    #[test]
+    fn request_line_headers_and_body() {
+        let request = read("POST /nif/batch%20x?nifs=500960046&format=csv&note=a+b%2Bc&flag HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nX-API-Key:  secret \r\n\r\nhello, trailing").unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/nif/batch x");
+        assert_eq!(request.query_param("nifs"), Some("500960046"));
+        assert_eq!(request.query_param("note"), Some("a b+c"));
+        assert_eq!(request.query_param("flag"), Some(""));
+        assert_eq!(request.query_param("missing"), None);
+        assert_eq!(request.header("x-api-key"), Some("secret"));
+        assert_eq!(request.header("host"), Some("localhost"));
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn bare_newlines_accepted() {
+        let request = read("GET /health HTTP/1.0\nAccept: */*\n\n").unwrap();
+        assert_eq!(request.path, "/health");
+        assert!(request.query.is_empty());
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn malformed_requests() {
+        assert!(matches!(read(""), Err(RequestError::Closed)));
+        for text in [
+            "GET /\r\n\r\n",
+            "GET / HTTP/2\r\n\r\n",
+            "GET / HTTP/1.1\r\nno colon\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost: x\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: many\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n",
+        ] {
+            assert!(matches!(read(text), Err(RequestError::Invalid(_))), "{:?}", text);
+        }
+        assert!(matches!(read("POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort"), Err(RequestError::Io(_))));
+    }
+
+    #[test]
+    fn limits() {
+        let long_header = format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", "x".repeat(MAX_HEAD));
+        assert!(matches!(read(&long_header), Err(RequestError::TooLarge(_))));
+        let big_body = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 1);
+        assert!(matches!(read(&big_body), Err(RequestError::TooLarge(_))));
+    }
+
+    #[test]
+    fn responses_written() {
+        let mut out = Vec::new();
+        Response::error(429, "slow down").with_header("Retry-After", "3").write_to(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 429 Too Many Requests\r\nContent-Type: application/json\r\nRetry-After: 3\r\nContent-Length: 22\r\nConnection: close\r\n\r\n{\"error\":\"slow down\"}\n"
+        );
+        let mut out = Vec::new();
+        Response::streaming("text/event-stream", |out| out.write_all(b"data: 1\n\n")).write_to(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(!out.contains("Content-Length"), "{}", out);
+        assert!(out.ends_with("Connection: close\r\n\r\ndata: 1\n\n"), "{}", out);
+    }
+}
@@ -0,0 +1,190 @@
+// import.rs
+
+use std::io::BufRead;
+use std::time::SystemTime;
+
+use crate::csv;
+use crate::entity::NifEntity;
+use crate::status::NifStatus;
+use crate::store::StoreRecord;
+use crate::validation::is_nif_valid_local;
+
+/// Entity fields that can be filled from a dataset column.
+pub const FIELDS: &[&str] = &["nif", "name", "address", "postal_code", "locality", "phone", "email"];
+
+/// Header names recognized without configuration, compared after `normalize_header`.
+///
+/// They cover the column names used by the usual Portuguese open-data exports
+/// (dados.gov.pt, municipal registries, Portal BASE dumps).
+const KNOWN_HEADERS: &[(&str, &[&str])] = &[
+    ("nif", &["nif", "nipc", "nif_nipc", "nif_entidade", "contribuinte", "numero_contribuinte", "n_contribuinte", "vat"]),
+    ("name", &["nome", "name", "denominacao", "denominacao_social", "firma", "designacao", "entidade", "nome_entidade"]),
+    ("address", &["morada", "endereco", "address", "sede", "morada_sede"]),
+    ("postal_code", &["codigo_postal", "cod_postal", "cp", "postal_code"]),
+    ("locality", &["localidade", "locality", "cidade", "concelho", "municipio"]),
+    ("phone", &["telefone", "phone", "tel", "telemovel"]),
+    ("email", &["email", "e_mail", "correio_eletronico", "correio_electronico"]),
+];
+
+/// Counts of what an import did with the dataset rows.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ImportReport {
+    pub imported: usize,   // Rows turned into store records
+    pub invalid_nif: usize, // Rows whose NIF fails local validation
+    pub incomplete: usize, // Rows without a NIF or a name
+}
+
+/// Lowercases a header and strips accents and punctuation: `Código Postal` → `codigo_postal`.
+pub fn normalize_header(header: &str) -> String {
+    let mut out = String::new();
+    for c in header.trim().to_lowercase().chars() {
+        let c = match c {
+            'á' | 'à' | 'â' | 'ã' => 'a',
+            'é' | 'ê' => 'e',
+            'í' => 'i',
+            'ó' | 'ô' | 'õ' => 'o',
+            'ú' => 'u',
+            'ç' => 'c',
+            'º' | 'ª' => continue,
+            c if c.is_ascii_alphanumeric() => c,
+            _ => '_',
+        };
+        if c != '_' || !out.ends_with('_') {
+            out.push(c);
+        }
+    }
+    out.trim_matches('_').to_string()
+}
+
+/// Turns a NIF as written in datasets (`PT 500 960 046`, `500960046.0`) into its nine digits.
+pub fn clean_nif(raw: &str) -> String {
+    let raw = raw.trim();
+    let raw = raw.strip_suffix(".0").unwrap_or(raw); // Numbers saved by spreadsheets
+    let raw = raw.strip_prefix("PT").or_else(|| raw.strip_prefix("pt")).unwrap_or(raw);
+    raw.chars().filter(|c| !c.is_whitespace() && *c != '.' && *c != '-').collect()
+}
+
+/// Maps entity fields to column positions, from the header row.
+#[derive(Debug, Clone)]
+pub struct ColumnMap {
+    columns: Vec<(&'static str, usize)>,
+}
+
+impl ColumnMap {
+    /// Detects the columns from the header, `overrides` (`field`, `header`) taking precedence.
+    ///
+    /// Fails when no NIF or name column can be found.
+    pub fn from_header(header: &[String], overrides: &[(String, String)]) -> Result<Self, String> {
+        let normalized: Vec<String> = header.iter().map(|h| normalize_header(h)).collect();
+        let mut columns = Vec::new();
+        for &field in FIELDS {
+            let position = match overrides.iter().rev().find(|(f, _)| f == field) {
+                Some((_, wanted)) => Some(
+                    normalized
+                        .iter()
+                        .position(|h| *h == normalize_header(wanted))
+                        .ok_or_else(|| format!("column '{}' not found in the header", wanted))?,
+                ),
+                None => {
+                    let known = KNOWN_HEADERS.iter().find(|(f, _)| *f == field).map_or(&[][..], |(_, h)| h);
+                    normalized.iter().position(|h| known.contains(&h.as_str()))
+                }
+            };
+            if let Some(position) = position {
+                columns.push((field, position));
+            }
+        }
+        for required in ["nif", "name"] {
+            if !columns.iter().any(|(field, _)| *field == required) {
+                return Err(format!(
+                    "no {} column found in the header, name it with --column {}=HEADER",
+                    required, required
+                ));
+            }
+        }
+        Ok(ColumnMap { columns })
+    }
+
+    /// Column detected for each field, for reporting.
+    pub fn columns(&self) -> &[(&'static str, usize)] {
+        &self.columns
+    }
+
+    /// Value of `field` in `row`, with line breaks and runs of spaces collapsed.
+    fn get(&self, row: &[String], field: &str) -> Option<String> {
+        let (_, position) = self.columns.iter().find(|(f, _)| *f == field)?;
+        let value = row.get(*position)?.split_whitespace().collect::<Vec<_>>().join(" ");
+        (!value.is_empty()).then_some(value)
+    }
+
+    /// Builds the entity described by a data row.
+    pub fn entity(&self, row: &[String]) -> Option<NifEntity> {
+        let text = |field| self.get(row, field);
+        Some(NifEntity {
+            nif: clean_nif(&self.get(row, "nif")?),
+            name: text("name")?,
+            address: text("address"),
+            postal_code: text("postal_code"),
+            locality: text("locality"),
+            phone: text("phone"),
+            email: text("email"),
+            ..NifEntity::default()
+        })
+    }
+}
+
+/// Parses a dataset into store records, one per row with a valid NIF and a name.
+///
+/// `delimiter` is detected from the header when `None`. Every record is marked
+/// `ValidKnown`, as registries only list existing entities.
+pub fn read_dataset(
+    mut input: impl BufRead,
+    delimiter: Option<char>,
+    overrides: &[(String, String)],
+    source: &str,
+) -> Result<(Vec<StoreRecord>, ImportReport), String> {
+    // Peek at the header line to guess the delimiter, without consuming it
+    let delimiter = match delimiter {
+        Some(delimiter) => delimiter,
+        None => {
+            let buffer = input.fill_buf().map_err(|e| format!("cannot read dataset: {}", e))?;
+            let end = buffer.iter().position(|&b| b == b'\n').unwrap_or(buffer.len());
+            csv::detect_delimiter(&String::from_utf8_lossy(&buffer[..end]))
+        }
+    };
+    let mut reader = csv::Reader::new(input, delimiter);
+    let header = match reader.next() {
+        Some(header) => header?,
+        None => return Err("dataset is empty".to_string()),
+    };
+    let columns = ColumnMap::from_header(&header, overrides)?;
+
+    let recorded_at = SystemTime::now();
+    let mut records = Vec::new();
+    let mut report = ImportReport::default();
+    for row in reader {
+        let row = row?;
+        if row.iter().all(|field| field.trim().is_empty()) {
+            continue;
+        }
+        let Some(entity) = columns.entity(&row) else {
+            report.incomplete += 1;
+            continue;
+        };
+        if !is_nif_valid_local(&entity.nif) {
+            report.invalid_nif += 1;
+            continue;
+        }
+        records.push(StoreRecord {
+            nif: entity.nif.clone(),
+            status: NifStatus::ValidKnown,
+            entity: Some(entity),
+            source: source.to_string(),
+            recorded_at,
+            removed_at: None,
+            tag: None,
+        });
+        report.imported += 1;
+    }
+    Ok((records, report))
+}
@@ -0,0 +1,311 @@
+// inflate.rs
+
+//! Decompressor for the zlib format (RFC 1950) wrapping DEFLATE (RFC 1951), as used by
+//! the `FlateDecode` filter of PDF streams, and for gzip (RFC 1952) Kafka record batches.
+
+/// Reads the bits of a DEFLATE stream, least significant first.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize, // Next byte
+    bit: u32,   // Bits already used of the byte at `pos`
+}
+
+impl<'a> BitReader<'a> {
+    fn bits(&mut self, count: u32) -> Result<u32, String> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = *self.data.get(self.pos).ok_or("truncated deflate stream")?;
+            value |= (((byte >> self.bit) & 1) as u32) << i;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.pos += 1;
+            }
+        }
+        Ok(value)
+    }
+
+    /// Skips to the next byte boundary, for stored blocks.
+    fn align(&mut self) {
+        if self.bit > 0 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+    }
+}
+
+/// Canonical Huffman code, as counts of codes per length and symbols in code order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0u16; 16];
+        for len in 1..16 {
+            offsets[len] = offsets[len - 1] + counts[len - 1];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len > 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, String> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..16 {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("invalid Huffman code in deflate stream".to_string())
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+/// Order in which the code length code lengths are stored in a dynamic block.
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// Decompresses zlib data, of at most `limit` bytes once decompressed. A missing or bad
+/// Adler-32 trailer is tolerated, as some PDF writers get it wrong; the header is optional
+/// for the same reason.
+pub fn zlib_decompress(data: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+    let has_header = data.len() >= 2 && data[0] & 0x0f == 8 && (u16::from(data[0]) << 8 | u16::from(data[1])) % 31 == 0;
+    inflate(if has_header { &data[2..] } else { data }, limit)
+}
+
+/// Decompresses gzip data, of one member and at most `limit` bytes once decompressed; the
+/// CRC-32 trailer is not checked.
+pub fn gunzip(data: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+    if data.len() < 10 || data[..3] != [0x1f, 0x8b, 8] {
+        return Err("not gzip data".to_string());
+    }
+    let flags = data[3];
+    let mut pos = 10;
+    if flags & 0x04 != 0 {
+        let len = data.get(pos..pos + 2).ok_or("truncated gzip header")?;
+        pos += 2 + u16::from_le_bytes([len[0], len[1]]) as usize;
+    }
+    for flag in [0x08, 0x10] {
+        // File name, then comment: zero-terminated
+        if flags & flag != 0 {
+            let end = data.get(pos..).and_then(|rest| rest.iter().position(|b| *b == 0)).ok_or("truncated gzip header")?;
+            pos += end + 1;
+        }
+    }
+    if flags & 0x02 != 0 {
+        pos += 2;
+    }
+    inflate(data.get(pos..).ok_or("truncated gzip header")?, limit)
+}
+
+/// Decompresses raw DEFLATE data. Data decompressing to more than `limit` bytes is an
+/// error, so that a small hostile stream (a "deflate bomb") cannot exhaust the memory.
+pub fn inflate(data: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+    let mut reader = BitReader { data, pos: 0, bit: 0 };
+    let mut out = Vec::with_capacity(data.len().saturating_mul(4).min(limit));
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let header = data.get(reader.pos..reader.pos + 4).ok_or("truncated stored block")?;
+                let len = u16::from_le_bytes([header[0], header[1]]) as usize;
+                reader.pos += 4;
+                let block = data.get(reader.pos..reader.pos + len).ok_or("truncated stored block")?;
+                check_limit(&out, len, limit)?;
+                out.extend_from_slice(block);
+                reader.pos += len;
+            }
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                let literals = Huffman::new(&lengths);
+                let distances = Huffman::new(&[5; 30]);
+                block(&mut reader, &mut out, &literals, &distances, limit)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut reader)?;
+                block(&mut reader, &mut out, &literals, &distances, limit)?;
+            }
+            _ => return Err("invalid deflate block type".to_string()),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+/// Reads the code tables of a dynamic Huffman block.
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), String> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+    let mut code_lengths = [0u8; 19];
+    for &index in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[index] = reader.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths);
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (value, repeat) = match code_lengths.decode(reader)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (*lengths.last().ok_or("repeat with no previous length")?, 3 + reader.bits(2)?),
+            17 => (0, 3 + reader.bits(3)?),
+            _ => (0, 11 + reader.bits(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(value, repeat as usize));
+    }
+    if lengths.len() > literal_count + distance_count {
+        return Err("too many code lengths in deflate stream".to_string());
+    }
+    Ok((Huffman::new(&lengths[..literal_count]), Huffman::new(&lengths[literal_count..])))
+}
+
+/// Fails when `more` bytes added to `out` would take it over `limit`.
+fn check_limit(out: &[u8], more: usize, limit: usize) -> Result<(), String> {
+    if out.len() + more > limit {
+        return Err(format!("deflate stream decompresses to more than {} bytes", limit));
+    }
+    Ok(())
+}
+
+/// Decodes the symbols of a compressed block up to its end code.
+fn block(reader: &mut BitReader, out: &mut Vec<u8>, literals: &Huffman, distances: &Huffman, limit: usize) -> Result<(), String> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        match symbol {
+            0..=255 => {
+                check_limit(out, 1, limit)?;
+                out.push(symbol as u8);
+            }
+            256 => return Ok(()),
+            257..=285 => {
+                let index = symbol - 257;
+                let len = LENGTH_BASE[index] as usize + reader.bits(LENGTH_EXTRA[index] as u32)? as usize;
+                let index = distances.decode(reader)? as usize;
+                if index >= 30 {
+                    return Err("invalid distance in deflate stream".to_string());
+                }
+                let distance = DISTANCE_BASE[index] as usize + reader.bits(DISTANCE_EXTRA[index] as u32)? as usize;
+                if distance > out.len() {
+                    return Err("distance too far back in deflate stream".to_string());
+                }
+                check_limit(out, len, limit)?;
+                let start = out.len() - distance;
+                for i in 0..len {
+                    out.push(out[start + i]);
+                }
+            }
+            _ => return Err("invalid literal/length code in deflate stream".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Raw DEFLATE data of `1 + 258 * count` zeros, out of 13 bits per 258 zeros: a literal
+    /// zero, then back-references at distance 1, in a fixed Huffman block.
+    pub(crate) fn zeros(count: usize) -> Vec<u8> {
+        let mut bits: Vec<bool> = Vec::new();
+        let put = |bits: &mut Vec<bool>, code: u32, len: u32| (0..len).rev().for_each(|i| bits.push(code >> i & 1 == 1));
+        bits.extend([true, true, false]); // Last block, fixed codes
+        put(&mut bits, 0x30, 8); // Literal 0
+        for _ in 0..count {
+            put(&mut bits, 0b1100_0101, 8); // Length 258
+            put(&mut bits, 0, 5); // Distance 1
+        }
+        put(&mut bits, 0, 7); // End of block
+        bits.chunks(8).map(|byte| byte.iter().rev().fold(0u8, |acc, &bit| acc << 1 | bit as u8)).collect()
+    }
+
+    #[test]
+    fn back_references() {
+        assert_eq!(inflate(&zeros(4), 2000).unwrap(), vec![0; 1 + 258 * 4]);
+    }
+
+    // zlib.compress(b"hello hello hello"): a fixed Huffman block with a back-reference
+    const HELLO: [u8; 16] = [120, 156, 203, 72, 205, 201, 201, 87, 200, 64, 144, 0, 58, 46, 6, 125];
+    // zlib.compress(b"a" * 1000): a thousand bytes out of a few
+    const THOUSAND_A: [u8; 17] = [120, 156, 75, 76, 28, 5, 163, 96, 20, 12, 119, 0, 0, 249, 216, 122, 248];
+
+    #[test]
+    fn fixed_block() {
+        assert_eq!(zlib_decompress(&HELLO, 1024).unwrap(), b"hello hello hello");
+    }
+
+    #[test]
+    fn dynamic_block() {
+        // zlib.compress(text, 9) of an invoice line, which takes a dynamic Huffman block
+        let data = [
+            120, 218, 45, 205, 91, 10, 194, 48, 16, 70, 225, 173, 252, 11, 168, 113, 26, 99, 69, 223, 164, 180, 32, 20,
+            21, 47, 11, 152, 154, 81, 2, 177, 9, 99, 5, 151, 111, 5, 223, 63, 206, 105, 121, 124, 43, 195, 146, 117,
+            243, 114, 133, 25, 142, 156, 37, 178, 6, 70, 45, 195, 168, 28, 225, 5, 93, 120, 245, 137, 11, 116, 158, 13,
+            246, 187, 22, 75, 162, 117, 69, 228, 42, 131, 58, 134, 9, 202, 6, 205, 71, 158, 57, 38, 156, 183, 197, 223,
+            148, 206, 217, 73, 25, 92, 210, 56, 133, 74, 187, 40, 136, 208, 92, 79, 6, 135, 94, 195, 131, 125, 194, 239,
+            134, 172, 114, 23, 149, 225, 22, 216, 124, 1, 71, 211, 43, 83,
+        ];
+        let text = zlib_decompress(&data, 1024).unwrap();
+        assert!(text.starts_with(b"Fatura 2024/17 - Papelaria Central de Lisboa, Lda. NIF 500960046."));
+        assert!(text.ends_with(b"Obrigado pela preferencia."));
+    }
+
+    #[test]
+    fn output_limit() {
+        assert_eq!(zlib_decompress(&THOUSAND_A, 1000).unwrap(), vec![b'a'; 1000]);
+        let error = zlib_decompress(&THOUSAND_A, 999).unwrap_err();
+        assert!(error.contains("more than 999 bytes"), "{}", error);
+    }
+
+    #[test]
+    fn stored_block() {
+        let data = [1, 5, 0, 250, 255, b'N', b'I', b'F', b's', b'!'];
+        assert_eq!(inflate(&data, 5).unwrap(), b"NIFs!");
+        assert!(inflate(&data, 4).is_err());
+    }
+
+    #[test]
+    fn truncated() {
+        assert!(zlib_decompress(&HELLO[..8], 1024).is_err());
+        assert!(inflate(&[], 1024).is_err());
+    }
+
+    #[test]
+    fn gzip() {
+        // gzip.compress(b"hello hello hello", mtime=0)
+        let mut data = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 2, 255];
+        data.extend_from_slice(&HELLO[2..HELLO.len() - 4]);
+        data.extend_from_slice(&[0; 8]);
+        assert_eq!(gunzip(&data, 1024).unwrap(), b"hello hello hello");
+        assert!(gunzip(b"not gzip at all", 1024).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+// input.rs
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+
+use crate::csv::{self, detect_delimiter};
+use crate::import::{clean_nif, normalize_header};
+use crate::json::JsonValue;
+use crate::status::NifStatus;
+
+/// Reads a list of NIFs, one per line, from a file or from stdin when `path` is `-`.
+///
+/// Surrounding whitespace is trimmed; blank lines and lines starting with `#` are skipped.
+pub fn read_nif_list(path: &str) -> Result<Vec<String>, String> {
+    let reader: Box<dyn BufRead> = if path == "-" {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        Box::new(BufReader::new(
+            File::open(path).map_err(|e| format!("cannot open {}: {}", path, e))?,
+        ))
+    };
+    let mut nifs = Vec::new();
+    for line in reader.lines() {
+        let line = line.map_err(|e| format!("cannot read {}: {}", path, e))?;
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            nifs.push(line.to_string());
+        }
+    }
+    Ok(nifs)
+}
+
+/// Writes a list of NIFs readable by `read_nif_list`, below a `#` comment line.
+pub fn write_nif_list(path: &str, nifs: &[String], comment: &str) -> Result<(), String> {
+    let mut text = format!("# {}\n", comment);
+    for nif in nifs {
+        text += nif;
+        text.push('\n');
+    }
+    std::fs::write(path, text).map_err(|e| format!("cannot write {}: {}", path, e))
+}
+
+/// Reads a file, or stdin when `path` is `-`.
+pub fn read_bytes(path: &str) -> Result<Vec<u8>, String> {
+    if path == "-" {
+        let mut bytes = Vec::new();
+        io::stdin().read_to_end(&mut bytes).map(|_| bytes)
+    } else {
+        std::fs::read(path)
+    }
+    .map_err(|e| format!("cannot read {}: {}", path, e))
+}
+
+/// Decodes text read from a file. Text that is not UTF-8 is decoded as Latin-1, close
+/// enough to the Windows-1252 many Portuguese programs export.
+pub fn decode_text(bytes: Vec<u8>) -> String {
+    match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) => e.into_bytes().iter().map(|&b| b as char).collect(),
+    }
+}
+
+/// Reads a text file, or stdin when `path` is `-`; see `decode_text` for the encoding.
+pub fn read_text(path: &str) -> Result<String, String> {
+    read_bytes(path).map(decode_text)
+}
+
+/// Reads the NIFs to look up again from a file of failed lookups: the CSV of `--failures`, or
+/// results in JSON (`--format json` or `ndjson`) or CSV, of which those without an answer are
+/// taken. Rows lacking a status are taken as they are.
+pub fn read_failures(path: &str) -> Result<Vec<String>, String> {
+    let text = read_text(path)?;
+    let text = text.trim_start_matches('\u{feff}').trim_start();
+    let nifs = if text.starts_with('[') || text.starts_with('{') {
+        failures_in_json(text)
+    } else {
+        failures_in_csv(text)
+    };
+    nifs.map_err(|e| format!("cannot read the failures in {}: {}", path, e))
+}
+
+/// Tells whether the status label of a row means the lookup may do better another time;
+/// `http_error` carries no code in the label, so it is not known to `from_label`.
+fn is_failed(label: Option<&str>) -> bool {
+    match label.filter(|label| !label.is_empty()).map(NifStatus::from_label) {
+        Some(Some(status)) => !status.is_definitive() && status != NifStatus::WrongCategory,
+        Some(None) | None => true,
+    }
+}
+
+/// NIFs of a JSON array of results, or of one result object per line.
+fn failures_in_json(text: &str) -> Result<Vec<String>, String> {
+    let rows = if text.starts_with('[') {
+        JsonValue::parse(text)?.as_array().unwrap_or_default().to_vec()
+    } else {
+        let lines = text.lines().filter(|line| !line.trim().is_empty());
+        lines.map(JsonValue::parse).collect::<Result<_, _>>()?
+    };
+    let mut nifs = Vec::new();
+    for row in &rows {
+        let nif = row.str_field("nif").ok_or("a result has no nif")?;
+        if is_failed(row.str_field("status")) {
+            nifs.push(nif.to_string());
+        }
+    }
+    Ok(nifs)
+}
+
+/// NIFs of a CSV file with a header, from its `nif` column and, when there is one, its
+/// `error_class` or `status` column.
+fn failures_in_csv(text: &str) -> Result<Vec<String>, String> {
+    let delimiter = detect_delimiter(text.lines().next().unwrap_or_default());
+    let mut records = csv::Reader::new(text.as_bytes(), delimiter);
+    let header = records.next().transpose()?.unwrap_or_default();
+    let column = |name: &str| header.iter().position(|field| field.trim() == name);
+    let nif = column("nif").ok_or("no nif column in the header")?;
+    let status = column("error_class").or(column("status"));
+    let mut nifs = Vec::new();
+    for record in records {
+        let record = record?;
+        let Some(value) = record.get(nif).map(|value| value.trim()).filter(|value| !value.is_empty()) else {
+            continue;
+        };
+        if is_failed(status.and_then(|status| record.get(status)).map(String::as_str)) {
+            nifs.push(value.to_string());
+        }
+    }
+    Ok(nifs)
+}
+
+/// Reads the NIFs and expected company names of a CSV file with a header, e.g. a supplier
+/// list exported from an ERP: the NIF column is `nif`, `nipc` or `contribuinte`, the name
+/// column `expected_name`, `name`, `nome` or `denominacao` (case and accents ignored).
+/// Rows without a NIF or a name are skipped.
+pub fn read_expected_names(path: &str) -> Result<Vec<(String, String)>, String> {
+    let text = read_text(path)?;
+    let text = text.trim_start_matches('\u{feff}');
+    expected_names_in_csv(text).map_err(|e| format!("cannot read the expected names in {}: {}", path, e))
+}
+
+fn expected_names_in_csv(text: &str) -> Result<Vec<(String, String)>, String> {
+    let delimiter = detect_delimiter(text.lines().next().unwrap_or_default());
+    let mut records = csv::Reader::new(text.as_bytes(), delimiter);
+    let header: Vec<String> = records.next().transpose()?.unwrap_or_default().iter().map(|field| normalize_header(field)).collect();
+    let column = |names: &[&str]| names.iter().find_map(|name| header.iter().position(|field| field == name));
+    let nif = column(&["nif", "nipc", "contribuinte"]).ok_or("no nif column in the header")?;
+    let name = column(&["expected_name", "name", "nome", "denominacao"]).ok_or("no name column in the header")?;
+    let mut names = Vec::new();
+    for record in records {
+        let record = record?;
+        let value = |column: usize| record.get(column).map(|value| value.trim()).unwrap_or_default();
+        if !value(nif).is_empty() && !value(name).is_empty() {
+            names.push((clean_nif(value(nif)), value(name).to_string()));
+        }
+    }
+    Ok(names)
+}
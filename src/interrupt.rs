@@ -0,0 +1,42 @@
+// interrupt.rs
+
+use std::sync::atomic::{AtomicI32, Ordering};
+
+static SIGNAL: AtomicI32 = AtomicI32::new(0); // Number of the first signal received, 0 until then
+
+/// Catches SIGINT and SIGTERM, so a batch run can stop between two records instead of
+/// dying in the middle of one. `interrupted` then tells which signal came.
+///
+/// A second signal ends the process at once, for when the current lookup hangs.
+#[cfg(unix)]
+pub fn install() {
+    extern "C" fn handle(signal: libc::c_int) {
+        if SIGNAL.swap(signal, Ordering::SeqCst) != 0 {
+            // SAFETY: _exit is async-signal-safe
+            unsafe { libc::_exit(128 + signal) };
+        }
+    }
+    let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: the handler only touches an atomic and calls _exit
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install() {}
+
+/// Returns the signal received since `install`, if any.
+pub fn interrupted() -> Option<i32> {
+    Some(SIGNAL.load(Ordering::SeqCst)).filter(|&signal| signal != 0)
+}
+
+/// Names a signal returned by `interrupted`.
+pub fn signal_name(signal: i32) -> &'static str {
+    match signal {
+        2 => "SIGINT",
+        15 => "SIGTERM",
+        _ => "signal",
+    }
+}
@@ -0,0 +1,157 @@
+// invoice.rs
+
+use crate::import::clean_nif;
+use crate::input::read_text;
+use crate::xml::{XmlEvent, XmlReader};
+
+/// Which side of the invoice a party is on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvoiceRole {
+    Seller, // `AccountingSupplierParty`
+    Buyer,  // `AccountingCustomerParty`
+}
+
+impl InvoiceRole {
+    /// Lowercase name, for reports.
+    pub fn label(&self) -> &'static str {
+        match self {
+            InvoiceRole::Seller => "seller",
+            InvoiceRole::Buyer => "buyer",
+        }
+    }
+}
+
+/// A party of a UBL invoice or credit note, with where its tax ID was found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvoiceParty {
+    pub role: InvoiceRole,
+    pub invoice: Option<String>, // `cbc:ID` of the document
+    pub name: Option<String>,    // `PartyLegalEntity/RegistrationName`, else `PartyName/Name`
+    pub tax_id: String,          // As written, `PT` prefix included, trimmed
+    pub country: Option<String>, // Prefix of the tax ID, else `PostalAddress/Country/IdentificationCode`
+    pub line: usize,             // Line of the tax ID element
+    pub path: String,            // e.g. `/Invoice/AccountingCustomerParty/Party/PartyTaxScheme/CompanyID`
+}
+
+impl InvoiceParty {
+    /// Tells whether the tax ID must be a Portuguese NIF: the party is in Portugal, or
+    /// its country is not given.
+    pub fn is_portuguese(&self) -> bool {
+        self.country.as_deref().is_none_or(|country| country.eq_ignore_ascii_case("PT"))
+    }
+
+    /// The tax ID as a NIF: without the `PT` prefix, spaces and dots.
+    pub fn nif(&self) -> String {
+        clean_nif(&self.tax_id)
+    }
+}
+
+/// Tax ID of a party, with its location; a `PartyTaxScheme` one wins over the others.
+#[derive(Default)]
+struct TaxId {
+    value: String,
+    line: usize,
+    path: String,
+    from_tax_scheme: bool,
+}
+
+/// Reads the seller and buyer of every UBL 2.1 `Invoice` or `CreditNote` of a file, as
+/// sent under CIUS-PT. Namespace prefixes are ignored, so `cac:Party` and `Party` read alike.
+pub fn read_parties(text: &str) -> Result<Vec<InvoiceParty>, String> {
+    let mut reader = XmlReader::new(text);
+    let mut stack: Vec<(String, usize)> = Vec::new(); // Open elements, with their start line
+    let mut value = String::new(); // Text of the innermost element
+    let mut parties = Vec::new();
+    let mut invoice = None;
+    let mut current: Option<(InvoiceParty, TaxId, Option<String>)> = None; // Party, tax ID, `PartyName`
+
+    while let Some(event) = reader.next_event()? {
+        match event {
+            XmlEvent::Start(name) => {
+                let role = match name.as_str() {
+                    "AccountingSupplierParty" => Some(InvoiceRole::Seller),
+                    "AccountingCustomerParty" => Some(InvoiceRole::Buyer),
+                    _ => None,
+                };
+                if let Some(role) = role {
+                    let party = InvoiceParty {
+                        role,
+                        invoice: invoice.clone(),
+                        name: None,
+                        tax_id: String::new(),
+                        country: None,
+                        line: reader.line(),
+                        path: String::new(),
+                    };
+                    current = Some((party, TaxId::default(), None));
+                }
+                if matches!(name.as_str(), "Invoice" | "CreditNote") {
+                    invoice = None;
+                }
+                stack.push((name, reader.line()));
+                value.clear();
+            }
+            XmlEvent::Text(text) => value.push_str(&text),
+            XmlEvent::End(_) => {
+                let path: String = stack.iter().map(|(name, _)| format!("/{}", name)).collect();
+                let Some((name, line)) = stack.pop() else {
+                    break;
+                };
+                let parents: Vec<&str> = stack.iter().rev().take(2).map(|(name, _)| name.as_str()).collect();
+                let text = Some(value.trim().to_string()).filter(|text| !text.is_empty());
+                match (name.as_str(), parents.as_slice(), current.as_mut()) {
+                    ("ID", ["Invoice" | "CreditNote", ..], _) => invoice = text,
+                    ("AccountingSupplierParty" | "AccountingCustomerParty", _, _) => {
+                        if let Some((mut party, tax_id, party_name)) = current.take() {
+                            party.name = party.name.or(party_name);
+                            if tax_id.line > 0 {
+                                party.line = tax_id.line;
+                                party.path = tax_id.path;
+                            } else {
+                                party.path = path; // No tax ID, the party itself is reported
+                            }
+                            party.tax_id = tax_id.value;
+                            if let Some(prefix) = country_prefix(&party.tax_id) {
+                                party.country = Some(prefix);
+                            }
+                            parties.push(party);
+                        }
+                    }
+                    ("CompanyID", [scheme @ ("PartyTaxScheme" | "PartyLegalEntity"), "Party"], Some((_, tax_id, _))) => {
+                        let from_tax_scheme = *scheme == "PartyTaxScheme";
+                        if tax_id.line == 0 || (from_tax_scheme && !tax_id.from_tax_scheme) {
+                            *tax_id = TaxId {
+                                value: text.unwrap_or_default(),
+                                line,
+                                path,
+                                from_tax_scheme,
+                            };
+                        }
+                    }
+                    ("RegistrationName", ["PartyLegalEntity", "Party"], Some((party, _, _))) => party.name = text,
+                    ("Name", ["PartyName", "Party"], Some((_, _, party_name))) if party_name.is_none() => *party_name = text,
+                    ("IdentificationCode", ["Country", "PostalAddress"], Some((party, _, _)))
+                        if stack.iter().rev().nth(2).is_some_and(|(name, _)| name == "Party") =>
+                    {
+                        party.country = text
+                    }
+                    _ => {}
+                }
+                value.clear();
+            }
+        }
+    }
+    Ok(parties)
+}
+
+/// Country of a VAT number written with its prefix, e.g. `PT` in `PT500960046`.
+fn country_prefix(tax_id: &str) -> Option<String> {
+    let prefix = tax_id.get(..2)?;
+    prefix.chars().all(|c| c.is_ascii_alphabetic()).then(|| prefix.to_ascii_uppercase())
+}
+
+/// Reads an invoice file from disk, see `read_text` for the encoding.
+pub fn read_file(path: &str) -> Result<Vec<InvoiceParty>, String> {
+    let text = read_text(path)?;
+    read_parties(&text).map_err(|e| format!("{}: {}", path, e))
+}
@@ -0,0 +1,425 @@
+// jobs.rs
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use rand::Rng;
+
+use crate::json::JsonValue;
+use crate::logging;
+use crate::lookup::{lookup_nif, LookupOptions};
+use crate::time::{from_unix_secs, unix_secs};
+
+/// How long a finished job stays available for polling.
+const JOB_RETENTION: Duration = Duration::from_secs(3600);
+
+/// A batch of lookups running in the background.
+#[derive(Debug)]
+pub struct Job {
+    pub id: String,
+    pub owner: Option<String>, // API key name of the creator, who alone may read it
+    pub nifs: Vec<String>,
+    pub created_at: SystemTime,
+    progress: Mutex<Progress>,
+    changed: Condvar,             // Signalled after each result and when the job finishes
+    cancelled: AtomicBool,        // Set by `JobRegistry::cancel`, stops the lookups
+    journal: Option<Mutex<File>>, // Where results are appended, in a persistent registry
+}
+
+#[derive(Debug, Default)]
+struct Progress {
+    results: Vec<JsonValue>, // As `LookupResult::to_json`, the form they are served and kept in
+    finished_at: Option<SystemTime>,
+}
+
+impl Job {
+    /// Number of NIFs looked up so far.
+    pub fn done(&self) -> usize {
+        self.progress.lock().unwrap().results.len()
+    }
+
+    /// Tells whether every NIF was looked up, or the job was cancelled.
+    pub fn is_finished(&self) -> bool {
+        self.progress.lock().unwrap().finished_at.is_some()
+    }
+
+    /// Waits until there are more than `seen` results or the job finished, at most `timeout`.
+    ///
+    /// Returns the results after the first `seen` ones and whether the job is finished.
+    pub fn wait_for_results(&self, seen: usize, timeout: Duration) -> (Vec<JsonValue>, bool) {
+        let progress = self.progress.lock().unwrap();
+        let (progress, _) = self
+            .changed
+            .wait_timeout_while(progress, timeout, |p| p.results.len() <= seen && p.finished_at.is_none())
+            .unwrap();
+        let new = progress.results.get(seen..).unwrap_or_default().to_vec();
+        (new, progress.finished_at.is_some())
+    }
+
+    /// Serializes the job state, with the results obtained so far.
+    pub fn to_json(&self) -> JsonValue {
+        let results = self.progress.lock().unwrap().results.clone();
+        self.summary_json().with("results", results)
+    }
+
+    /// Serializes the job state without the results, as listed by `GET /jobs`.
+    pub fn summary_json(&self) -> JsonValue {
+        let progress = self.progress.lock().unwrap();
+        JsonValue::object()
+            .with("id", self.id.as_str())
+            .with("state", if progress.finished_at.is_some() { "finished" } else { "running" })
+            .with("created_at", unix_secs(self.created_at))
+            .with("total", self.nifs.len() as i64)
+            .with("done", progress.results.len() as i64)
+    }
+
+    /// Looks up the NIFs left, from the first one without a result, until done or cancelled.
+    fn run(&self, options: &LookupOptions) {
+        let start = self.done();
+        for nif in &self.nifs[start..] {
+            if self.cancelled.load(Ordering::Relaxed) {
+                break;
+            }
+            let result = lookup_nif(nif, options).to_json();
+            self.append(&JsonValue::object().with("result", result.clone()));
+            self.progress.lock().unwrap().results.push(result);
+            self.changed.notify_all();
+        }
+        let finished_at = SystemTime::now();
+        if !self.cancelled.load(Ordering::Relaxed) {
+            self.append(&JsonValue::object().with("finished_at", unix_secs(finished_at)));
+        }
+        self.progress.lock().unwrap().finished_at = Some(finished_at);
+        self.changed.notify_all();
+    }
+
+    /// Appends a line to the journal of the job, if it has one.
+    fn append(&self, line: &JsonValue) {
+        let Some(journal) = &self.journal else {
+            return;
+        };
+        if let Err(e) = writeln!(journal.lock().unwrap(), "{}", line) {
+            logging::warn(
+                "job_journal_failed",
+                &[("job_id", self.id.as_str().into()), ("error", e.to_string().into())],
+                format!("Cannot save the progress of job {}: {}", self.id, e),
+            );
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        let progress = self.progress.lock().unwrap();
+        progress.finished_at.is_some_and(|at| at.elapsed().unwrap_or_default() >= JOB_RETENTION)
+    }
+}
+
+/// Background batch jobs of the server, looked up by ID.
+///
+/// A registry opened on a directory keeps each job there as a JSON lines journal,
+/// `<id>.jsonl`: the job itself on the first line, then one line per result and a last
+/// one when it finishes. Jobs found there when the registry is opened are resumed.
+#[derive(Debug, Default)]
+pub struct JobRegistry {
+    jobs: Mutex<HashMap<String, Arc<Job>>>,
+    dir: Option<PathBuf>, // `None` keeps the jobs in memory only
+}
+
+impl JobRegistry {
+    /// Registry persisting its jobs in `dir`, created if missing. The jobs found there are
+    /// loaded, and those left unfinished by the previous run are resumed with `options`.
+    pub fn open(dir: impl AsRef<Path>, options: &LookupOptions) -> Result<Self, String> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).map_err(|e| format!("cannot create jobs directory {}: {}", dir.display(), e))?;
+        let entries = fs::read_dir(&dir).map_err(|e| format!("cannot read jobs directory {}: {}", dir.display(), e))?;
+        let mut jobs = HashMap::new();
+        for entry in entries {
+            let path = entry.map_err(|e| format!("cannot read jobs directory {}: {}", dir.display(), e))?.path();
+            if path.extension().is_none_or(|extension| extension != "jsonl") {
+                continue;
+            }
+            match load_job(&path) {
+                Ok(job) if job.is_expired() => remove_journal(&path),
+                Ok(job) => {
+                    jobs.insert(job.id.clone(), Arc::new(job));
+                }
+                Err(e) => logging::warn(
+                    "job_load_failed",
+                    &[("path", path.display().to_string().into()), ("error", e.as_str().into())],
+                    format!("Skipping job {}: {}", path.display(), e),
+                ),
+            }
+        }
+        let resumed: Vec<Arc<Job>> = jobs.values().filter(|job| !job.is_finished()).cloned().collect();
+        for job in resumed {
+            logging::info(
+                "job_resumed",
+                &[("job_id", job.id.as_str().into()), ("done", (job.done() as i64).into())],
+                format!("Resuming job {} at NIF {} of {}", job.id, job.done() + 1, job.nifs.len()),
+            );
+            let options = options.clone();
+            thread::spawn(move || job.run(&options));
+        }
+        Ok(JobRegistry {
+            jobs: Mutex::new(jobs),
+            dir: Some(dir),
+        })
+    }
+
+    /// Starts looking up `nifs` on a background thread and returns the new job.
+    pub fn start(&self, nifs: Vec<String>, owner: Option<String>, options: LookupOptions) -> Arc<Job> {
+        let id = random_id();
+        let created_at = SystemTime::now();
+        let journal = self.dir.as_ref().and_then(|dir| {
+            let header = JsonValue::object()
+                .with("id", id.as_str())
+                .with("owner", owner.as_deref())
+                .with("created_at", unix_secs(created_at))
+                .with("nifs", nifs.iter().map(|nif| JsonValue::from(nif.as_str())).collect::<Vec<_>>());
+            let path = journal_path(dir, &id);
+            let create = || -> std::io::Result<File> {
+                let mut file = OpenOptions::new().create_new(true).append(true).open(&path)?;
+                writeln!(file, "{}", header)?;
+                Ok(file)
+            };
+            match create() {
+                Ok(file) => Some(Mutex::new(file)),
+                Err(e) => {
+                    // The job still runs, it is only lost on restart
+                    logging::warn(
+                        "job_journal_failed",
+                        &[("job_id", id.as_str().into()), ("error", e.to_string().into())],
+                        format!("Cannot save job {} to {}: {}", id, path.display(), e),
+                    );
+                    None
+                }
+            }
+        });
+        let job = Arc::new(Job {
+            id: id.clone(),
+            owner,
+            nifs,
+            created_at,
+            progress: Mutex::new(Progress::default()),
+            changed: Condvar::new(),
+            cancelled: AtomicBool::new(false),
+            journal,
+        });
+
+        let mut jobs = self.jobs.lock().unwrap();
+        // Forget jobs finished long ago while we hold the lock anyway
+        jobs.retain(|id, job| {
+            let expired = job.is_expired();
+            if expired && let Some(dir) = &self.dir {
+                remove_journal(&journal_path(dir, id));
+            }
+            !expired
+        });
+        jobs.insert(id, job.clone());
+        drop(jobs);
+
+        let worker = job.clone();
+        // Logs of the job keep the ID of the request that started it
+        let request_id = logging::request_id();
+        thread::spawn(move || {
+            logging::set_request_id(request_id);
+            worker.run(&options);
+        });
+        job
+    }
+
+    /// Returns the job with this ID, if it still exists.
+    pub fn get(&self, id: &str) -> Option<Arc<Job>> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
+    /// Jobs created by `owner`, oldest first.
+    pub fn list(&self, owner: &Option<String>) -> Vec<Arc<Job>> {
+        let mut jobs: Vec<Arc<Job>> = self.jobs.lock().unwrap().values().filter(|job| job.owner == *owner).cloned().collect();
+        jobs.sort_by(|a, b| a.created_at.cmp(&b.created_at).then_with(|| a.id.cmp(&b.id)));
+        jobs
+    }
+
+    /// Stops the job with this ID after the lookup in progress, and forgets it with its
+    /// results. Returns whether there was such a job.
+    pub fn cancel(&self, id: &str) -> bool {
+        let Some(job) = self.jobs.lock().unwrap().remove(id) else {
+            return false;
+        };
+        job.cancelled.store(true, Ordering::Relaxed);
+        if let Some(dir) = &self.dir {
+            remove_journal(&journal_path(dir, id));
+        }
+        true
+    }
+}
+
+fn journal_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{}.jsonl", id))
+}
+
+fn remove_journal(path: &Path) {
+    if let Err(e) = fs::remove_file(path)
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        logging::warn(
+            "job_journal_failed",
+            &[("path", path.display().to_string().into()), ("error", e.to_string().into())],
+            format!("Cannot remove {}: {}", path.display(), e),
+        );
+    }
+}
+
+/// Reads a job back from its journal, which it goes on appending to.
+fn load_job(path: &Path) -> Result<Job, String> {
+    let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut lines = text.split_inclusive('\n');
+    let header = JsonValue::parse(lines.next().ok_or("empty journal")?.trim_end())?;
+    let id = header.str_field("id").ok_or("no job ID")?.to_string();
+    let nifs: Vec<String> = header
+        .get("nifs")
+        .and_then(JsonValue::as_array)
+        .ok_or("no NIFs")?
+        .iter()
+        .filter_map(|nif| nif.as_str().map(String::from))
+        .collect();
+    let mut progress = Progress::default();
+    let mut kept = text.len() - lines.clone().map(str::len).sum::<usize>();
+    for line in lines {
+        // Only the last line can be cut short, by a crash; its lookup is made again
+        let Some(Ok(json)) = line.strip_suffix('\n').map(JsonValue::parse) else {
+            break;
+        };
+        if let Some(result) = json.get("result") {
+            progress.results.push(result.clone());
+        } else if let Some(secs) = json.get("finished_at").and_then(JsonValue::as_i64) {
+            progress.finished_at = Some(from_unix_secs(secs));
+        }
+        kept += line.len();
+    }
+    if progress.results.len() >= nifs.len() && progress.finished_at.is_none() {
+        progress.finished_at = Some(SystemTime::now());
+    }
+    let journal = OpenOptions::new().append(true).open(path).map_err(|e| e.to_string())?;
+    // Drop the cut line, so that the next ones start on a line of their own
+    journal.set_len(kept as u64).map_err(|e| e.to_string())?;
+    Ok(Job {
+        id,
+        owner: header.str_field("owner").map(String::from),
+        nifs,
+        created_at: from_unix_secs(header.get("created_at").and_then(JsonValue::as_i64).unwrap_or_default()),
+        progress: Mutex::new(progress),
+        changed: Condvar::new(),
+        cancelled: AtomicBool::new(false),
+        journal: Some(Mutex::new(journal)),
+    })
+}
+
+/// Returns 16 random hex digits, used for job and request IDs.
+pub fn random_id() -> String {
+    let mut rng = rand::thread_rng();
+    (0..16).map(|_| format!("{:x}", rng.gen_range(0..16u8))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::NifCategory;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("check_nif-jobs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Options answering every company NIF locally, with a `wrong_category` result.
+    fn offline() -> LookupOptions {
+        let mut options = LookupOptions::default();
+        options.expect = vec![NifCategory::Person];
+        options
+    }
+
+    fn wait_finished(job: &Job) {
+        for _ in 0..100 {
+            if job.wait_for_results(job.done(), Duration::from_millis(100)).1 {
+                return;
+            }
+        }
+        panic!("job {} did not finish", job.id);
+    }
+
+    #[test]
+    fn unfinished_job_resumed_from_its_journal() {
+        let dir = scratch("resume");
+        let header = r#"{"id":"00000000000000aa","owner":"erp","created_at":1700000000,"nifs":["500960046","501442600","509442013"]}"#;
+        let first = r#"{"result":{"nif":"500960046","status":"valid_known"}}"#;
+        // The server died while writing the second result
+        fs::write(dir.join("00000000000000aa.jsonl"), format!("{}\n{}\n{{\"result\":{{\"nif\":\"5014", header, first)).unwrap();
+        let registry = JobRegistry::open(&dir, &offline()).unwrap();
+        let job = registry.get("00000000000000aa").unwrap();
+        assert_eq!(job.owner.as_deref(), Some("erp"));
+        assert_eq!(job.created_at, from_unix_secs(1_700_000_000));
+        wait_finished(&job);
+
+        let results = job.to_json();
+        let results = results.get("results").and_then(JsonValue::as_array).unwrap();
+        let statuses: Vec<_> = results.iter().map(|result| (result.str_field("nif").unwrap(), result.str_field("status").unwrap())).collect();
+        assert_eq!(statuses, [("500960046", "valid_known"), ("501442600", "wrong_category"), ("509442013", "wrong_category")]);
+
+        let journal = fs::read_to_string(dir.join("00000000000000aa.jsonl")).unwrap();
+        let lines: Vec<JsonValue> = journal.lines().map(|line| JsonValue::parse(line).unwrap()).collect();
+        assert_eq!(lines.len(), 5, "{}", journal);
+        assert_eq!(lines[1].to_string(), first);
+        assert!(lines[4].get("finished_at").is_some());
+
+        // Once finished, the job is loaded again without being run
+        let registry = JobRegistry::open(&dir, &offline()).unwrap();
+        let job = registry.get("00000000000000aa").unwrap();
+        assert!(job.is_finished());
+        assert_eq!(job.done(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn expired_and_broken_journals() {
+        let dir = scratch("expired");
+        let expired = [
+            r#"{"id":"00000000000000bb","created_at":1000,"nifs":["500960046"]}"#,
+            r#"{"result":{"nif":"500960046","status":"valid_known"}}"#,
+            r#"{"finished_at":1000}"#,
+        ];
+        fs::write(dir.join("00000000000000bb.jsonl"), expired.join("\n") + "\n").unwrap();
+        fs::write(dir.join("00000000000000cc.jsonl"), "not json\n").unwrap();
+        fs::write(dir.join("notes.txt"), "kept\n").unwrap();
+        let registry = JobRegistry::open(&dir, &offline()).unwrap();
+        assert!(registry.get("00000000000000bb").is_none());
+        assert!(!dir.join("00000000000000bb.jsonl").exists());
+        assert!(dir.join("00000000000000cc.jsonl").exists()); // Skipped, left for a look
+        assert!(dir.join("notes.txt").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn started_jobs_kept_and_cancelled() {
+        let dir = scratch("start");
+        let registry = JobRegistry::open(&dir, &offline()).unwrap();
+        let job = registry.start(vec!["500960046".to_string(), "501442600".to_string()], Some("erp".to_string()), offline());
+        wait_finished(&job);
+        assert_eq!(job.summary_json().str_field("state"), Some("finished"));
+        assert_eq!(registry.list(&Some("erp".to_string())).len(), 1);
+        assert!(registry.list(&None).is_empty());
+        let path = journal_path(&dir, &job.id);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 4);
+        assert!(registry.cancel(&job.id));
+        assert!(!registry.cancel(&job.id));
+        assert!(registry.get(&job.id).is_none());
+        assert!(!path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1042 @@
+// kafka.rs
+
+//! Kafka client: the producer publishing lookup results (`--kafka-brokers`), and the
+//! consumer reading the requests of `worker`.
+//!
+//! Speaks the Kafka protocol directly: `Metadata` (v4) to find the leader of each partition,
+//! then `Produce` (v3) with one record batch (v2, CRC32C) per result, waiting for every
+//! in-sync replica (`acks=all`). Records are keyed by NIF and partitioned as the Java
+//! client does (murmur2), so all the events of a NIF land in order on one partition.
+//! Consumers read with `Fetch` (v4) and keep their offsets with `OffsetCommit` (v2).
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::inflate::gunzip;
+use crate::logging;
+
+const CLIENT_ID: &str = "check_nif";
+const PRODUCE: i16 = 0;
+const FETCH: i16 = 1;
+const LIST_OFFSETS: i16 = 2;
+const METADATA: i16 = 3;
+const OFFSET_COMMIT: i16 = 8;
+const OFFSET_FETCH: i16 = 9;
+const FIND_COORDINATOR: i16 = 10;
+const OFFSET_OUT_OF_RANGE: i16 = 1;
+/// `ListOffsets` timestamp asking for the first offset of a partition.
+const EARLIEST: i64 = -2;
+/// Most bytes fetched at once, per partition and per request.
+const FETCH_MAX_BYTES: i32 = 1 << 20;
+const ACKS_ALL: i16 = -1;
+/// How long the broker may wait for the replicas before answering a produce request.
+const PRODUCE_TIMEOUT_MS: i32 = 10_000;
+const IO_TIMEOUT: Duration = Duration::from_secs(15);
+/// Tries of a record, the metadata being refreshed between them.
+const ATTEMPTS: u32 = 3;
+/// Largest response accepted, against a peer that is not a Kafka broker.
+const MAX_RESPONSE: usize = 16 << 20;
+/// Largest record batch once decompressed: no more than a response could hold
+/// uncompressed, so that one hostile gzip batch cannot exhaust the memory.
+const MAX_BATCH: usize = MAX_RESPONSE;
+
+/// Connections to the brokers of a cluster, and what is known of them.
+#[derive(Debug)]
+struct Cluster {
+    bootstrap: Vec<String>,             // `host:port` of the brokers asked for the metadata
+    brokers: HashMap<i32, String>,      // Node ID to `host:port`
+    connections: HashMap<i32, TcpStream>,
+    correlation_id: i32,
+}
+
+impl Cluster {
+    fn new(bootstrap: Vec<String>) -> Result<Self, String> {
+        if bootstrap.is_empty() {
+            return Err("no Kafka broker given".to_string());
+        }
+        Ok(Cluster {
+            bootstrap,
+            brokers: HashMap::new(),
+            connections: HashMap::new(),
+            correlation_id: 0,
+        })
+    }
+
+    /// Forgets the connections, after an error that may come from the cluster changing.
+    fn reset(&mut self) {
+        self.connections.clear();
+    }
+
+    fn next_correlation_id(&mut self) -> i32 {
+        self.correlation_id = self.correlation_id.wrapping_add(1);
+        self.correlation_id
+    }
+
+    /// Sends a request to the first bootstrap broker that answers it.
+    fn request_any(&mut self, api_key: i16, version: i16, body: &[u8]) -> Result<Vec<u8>, KafkaError> {
+        let mut last_error = KafkaError::retriable("no Kafka broker reachable".to_string());
+        for address in self.bootstrap.clone() {
+            let correlation_id = self.next_correlation_id();
+            match connect(&address).and_then(|mut stream| request(&mut stream, api_key, version, correlation_id, body)) {
+                Ok(response) => return Ok(response),
+                Err(e) if e.retriable => last_error = e,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Sends a request to a broker, over the connection kept open to it.
+    fn request_node(&mut self, node: i32, api_key: i16, version: i16, body: &[u8]) -> Result<Vec<u8>, KafkaError> {
+        if let Entry::Vacant(entry) = self.connections.entry(node) {
+            let address = self
+                .brokers
+                .get(&node)
+                .ok_or_else(|| KafkaError::retriable(format!("unknown Kafka broker {}", node)))?;
+            entry.insert(connect(address)?);
+        }
+        let correlation_id = self.next_correlation_id();
+        let stream = self.connections.get_mut(&node).unwrap();
+        let response = request(stream, api_key, version, correlation_id, body);
+        if response.is_err() {
+            self.connections.remove(&node);
+        }
+        response
+    }
+
+    /// Leader node of each partition of `topic`, by partition index; learns the brokers too.
+    fn topic_leaders(&mut self, topic: &str) -> Result<Vec<i32>, KafkaError> {
+        let mut body = Vec::new();
+        put_i32(&mut body, 1);
+        put_str(&mut body, topic);
+        body.push(1); // Let the broker create the topic, when it is configured to
+        let response = self.request_any(METADATA, 4, &body)?;
+
+        let mut reader = Reader::new(&response);
+        reader.i32()?; // Throttle time
+        for _ in 0..reader.i32()? {
+            let node_id = reader.i32()?;
+            let host = reader.string()?;
+            let port = reader.i32()?;
+            reader.nullable_string()?; // Rack
+            self.brokers.insert(node_id, format!("{}:{}", host, port));
+        }
+        reader.nullable_string()?; // Cluster ID
+        reader.i32()?; // Controller ID
+        for _ in 0..reader.i32()? {
+            let error_code = reader.i16()?;
+            let name = reader.string()?;
+            reader.i8()?; // Internal topic
+            let mut leaders = Vec::new();
+            for _ in 0..reader.i32()? {
+                reader.i16()?; // Partition error, e.g. no leader while it is elected
+                let index = reader.i32()?;
+                let leader = reader.i32()?;
+                reader.skip_i32_array()?; // Replicas
+                reader.skip_i32_array()?; // In-sync replicas
+                leaders.push((index, leader));
+            }
+            if name != topic {
+                continue;
+            }
+            if error_code != 0 {
+                return Err(KafkaError::from_code(error_code, &format!("no metadata for topic {}", topic)));
+            }
+            leaders.sort_unstable();
+            if leaders.is_empty() || leaders.iter().enumerate().any(|(n, (index, _))| *index != n as i32) {
+                return Err(KafkaError::retriable(format!("incomplete partitions for topic {}", topic)));
+            }
+            return Ok(leaders.into_iter().map(|(_, leader)| leader).collect());
+        }
+        Err(KafkaError::retriable(format!("no metadata for topic {}", topic)))
+    }
+}
+
+#[derive(Debug)]
+struct ProducerState {
+    cluster: Cluster,
+    leaders: Option<Vec<i32>>, // Leader of each partition of the topic, `None` until asked
+}
+
+/// Publishes records to one topic. Safe to share between threads; records are sent one
+/// at a time.
+#[derive(Debug)]
+pub struct KafkaProducer {
+    topic: String,
+    state: Mutex<ProducerState>,
+}
+
+impl KafkaProducer {
+    /// Producer for `topic`, on the cluster reachable through the `bootstrap` brokers.
+    /// Nothing is connected before the first record.
+    pub fn new(bootstrap: Vec<String>, topic: &str) -> Result<Self, String> {
+        check_topic(topic)?;
+        Ok(KafkaProducer {
+            topic: topic.to_string(),
+            state: Mutex::new(ProducerState {
+                cluster: Cluster::new(bootstrap)?,
+                leaders: None,
+            }),
+        })
+    }
+
+    /// Topic the records go to.
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Publishes one record and waits until the cluster stored it.
+    pub fn publish(&self, key: &str, value: &[u8], headers: &[(&str, &str)]) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        let mut last_error = String::new();
+        for attempt in 0..ATTEMPTS {
+            if attempt > 0 {
+                // Leadership moved or the topic is being created: ask again where to send
+                state.leaders = None;
+                state.cluster.reset();
+                std::thread::sleep(Duration::from_millis(200 << attempt));
+            }
+            match self.try_publish(&mut state, key, value, headers) {
+                Ok(()) => return Ok(()),
+                Err(e) if e.retriable => last_error = e.message,
+                Err(e) => return Err(e.message),
+            }
+        }
+        Err(last_error)
+    }
+
+    fn try_publish(&self, state: &mut ProducerState, key: &str, value: &[u8], headers: &[(&str, &str)]) -> Result<(), KafkaError> {
+        if state.leaders.is_none() {
+            state.leaders = Some(state.cluster.topic_leaders(&self.topic)?);
+        }
+        let leaders = state.leaders.as_ref().unwrap();
+        let partition = partition_for(key.as_bytes(), leaders.len());
+        let leader = leaders[partition];
+
+        let batch = record_batch(key.as_bytes(), value, headers, now_millis());
+        let mut body = Vec::new();
+        put_i16(&mut body, -1); // No transactional ID
+        put_i16(&mut body, ACKS_ALL);
+        put_i32(&mut body, PRODUCE_TIMEOUT_MS);
+        put_i32(&mut body, 1);
+        put_str(&mut body, &self.topic);
+        put_i32(&mut body, 1);
+        put_i32(&mut body, partition as i32);
+        put_i32(&mut body, batch.len() as i32);
+        body.extend_from_slice(&batch);
+        let response = state.cluster.request_node(leader, PRODUCE, 3, &body)?;
+
+        let mut reader = Reader::new(&response);
+        for _ in 0..reader.i32()? {
+            reader.string()?;
+            for _ in 0..reader.i32()? {
+                reader.i32()?; // Partition index
+                let error_code = reader.i16()?;
+                reader.i64()?; // Base offset
+                reader.i64()?; // Log append time
+                if error_code != 0 {
+                    return Err(KafkaError::from_code(error_code, &format!("cannot publish to {}", self.topic)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A record read from a topic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KafkaRecord {
+    pub partition: i32,
+    pub offset: i64,
+    pub key: Option<Vec<u8>>,
+    pub value: Option<Vec<u8>>,
+    pub headers: Vec<(String, Vec<u8>)>,
+}
+
+impl KafkaRecord {
+    /// Value of the first header called `name`, if it is text.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let (_, value) = self.headers.iter().find(|(header, _)| header == name)?;
+        std::str::from_utf8(value).ok()
+    }
+}
+
+/// Where the consumer is in a partition.
+#[derive(Debug, Clone, Copy)]
+struct Position {
+    partition: i32,
+    leader: i32,
+    offset: i64, // Next record to read
+}
+
+/// Reads the records of one topic, keeping its offsets in a consumer group.
+///
+/// The consumer does not join the group: it reads the partitions it is given (all by
+/// default) and commits its offsets under the group ID as a standalone consumer, so it
+/// resumes where it stopped. Consumers sharing a group ID must be given distinct partitions.
+#[derive(Debug)]
+pub struct KafkaConsumer {
+    cluster: Cluster,
+    topic: String,
+    group: String,
+    only: Option<Vec<i32>>,            // Partitions to read, `None` for all of them
+    positions: Option<Vec<Position>>,  // `None` until the offsets are fetched
+    leaders_stale: bool,               // Partition leaders to ask again, offsets kept
+    coordinator: Option<i32>,          // Broker keeping the offsets of the group
+}
+
+impl KafkaConsumer {
+    /// Consumer of `topic` for the consumer group `group`, reading the partitions in `only`
+    /// or else all of them. Nothing is connected before the first poll.
+    pub fn new(bootstrap: Vec<String>, topic: &str, group: &str, only: Option<Vec<i32>>) -> Result<Self, String> {
+        check_topic(topic)?;
+        if group.is_empty() {
+            return Err("the consumer group ID is empty".to_string());
+        }
+        Ok(KafkaConsumer {
+            cluster: Cluster::new(bootstrap)?,
+            topic: topic.to_string(),
+            group: group.to_string(),
+            only,
+            positions: None,
+            leaders_stale: false,
+            coordinator: None,
+        })
+    }
+
+    /// Waits up to `max_wait` for records, and returns those read, in offset order within
+    /// each partition. Cluster changes are retried on the next poll, which then returns no
+    /// record; only lasting errors are returned.
+    pub fn poll(&mut self, max_wait: Duration) -> Result<Vec<KafkaRecord>, String> {
+        match self.try_poll(max_wait) {
+            Ok(records) => Ok(records),
+            Err(e) if e.retriable => {
+                logging::warn(
+                    "kafka_poll_failed",
+                    &[("topic", self.topic.as_str().into()), ("error", e.message.as_str().into())],
+                    format!("Kafka poll failed, retrying: {}", e.message),
+                );
+                self.leaders_stale = true;
+                self.coordinator = None;
+                self.cluster.reset();
+                std::thread::sleep(max_wait.min(Duration::from_secs(1)));
+                Ok(Vec::new())
+            }
+            Err(e) => Err(e.message),
+        }
+    }
+
+    /// Records that `record` was handled: a restarted consumer goes on with the next one.
+    pub fn commit(&mut self, record: &KafkaRecord) -> Result<(), String> {
+        let mut last_error = String::new();
+        for _ in 0..ATTEMPTS {
+            match self.try_commit(record) {
+                Ok(()) => return Ok(()),
+                Err(e) if e.retriable => {
+                    last_error = e.message;
+                    self.coordinator = None;
+                    self.cluster.reset();
+                }
+                Err(e) => return Err(e.message),
+            }
+        }
+        Err(last_error)
+    }
+
+    fn try_poll(&mut self, max_wait: Duration) -> Result<Vec<KafkaRecord>, KafkaError> {
+        if self.positions.is_none() {
+            self.positions = Some(self.assign()?);
+            self.leaders_stale = false;
+        }
+        if self.leaders_stale {
+            let leaders = self.cluster.topic_leaders(&self.topic)?;
+            for position in self.positions.iter_mut().flatten() {
+                position.leader = *leaders.get(position.partition as usize).unwrap_or(&position.leader);
+            }
+            self.leaders_stale = false;
+        }
+        let positions = self.positions.clone().unwrap();
+        let mut leaders: Vec<i32> = positions.iter().map(|position| position.leader).collect();
+        leaders.sort_unstable();
+        leaders.dedup();
+        // The wait is shared between the leaders, asked one after the other
+        let wait = (max_wait.as_millis() as usize / leaders.len().max(1)) as i32;
+        let mut records = Vec::new();
+        for leader in leaders {
+            let partitions: Vec<Position> = positions.iter().filter(|position| position.leader == leader).copied().collect();
+            records.extend(self.fetch(leader, &partitions, wait)?);
+        }
+        Ok(records)
+    }
+
+    /// Partitions to read, each from its committed offset, or from its first record.
+    fn assign(&mut self) -> Result<Vec<Position>, KafkaError> {
+        let leaders = self.cluster.topic_leaders(&self.topic)?;
+        let partitions: Vec<i32> = match &self.only {
+            Some(only) => {
+                if let Some(missing) = only.iter().find(|partition| **partition < 0 || **partition as usize >= leaders.len()) {
+                    let message = format!("topic {} has no partition {} ({} partitions)", self.topic, missing, leaders.len());
+                    return Err(KafkaError::fatal(message));
+                }
+                only.clone()
+            }
+            None => (0..leaders.len() as i32).collect(),
+        };
+        let committed = self.committed_offsets(&partitions)?;
+        let mut positions = Vec::new();
+        for partition in partitions {
+            let leader = leaders[partition as usize];
+            let offset = match committed.get(&partition) {
+                Some(offset) if *offset >= 0 => *offset,
+                _ => self.earliest_offset(leader, partition)?,
+            };
+            positions.push(Position { partition, leader, offset });
+        }
+        Ok(positions)
+    }
+
+    fn coordinator(&mut self) -> Result<i32, KafkaError> {
+        if let Some(node) = self.coordinator {
+            return Ok(node);
+        }
+        let mut body = Vec::new();
+        put_str(&mut body, &self.group);
+        body.push(0); // Key type: group
+        let response = self.cluster.request_any(FIND_COORDINATOR, 1, &body)?;
+        let mut reader = Reader::new(&response);
+        reader.i32()?; // Throttle time
+        let error_code = reader.i16()?;
+        reader.nullable_string()?; // Error message
+        let node = reader.i32()?;
+        let host = reader.string()?;
+        let port = reader.i32()?;
+        if error_code != 0 {
+            return Err(KafkaError::from_code(error_code, &format!("no coordinator for group {}", self.group)));
+        }
+        self.cluster.brokers.insert(node, format!("{}:{}", host, port));
+        self.coordinator = Some(node);
+        Ok(node)
+    }
+
+    /// Offsets committed by the group, by partition; -1 when none is.
+    fn committed_offsets(&mut self, partitions: &[i32]) -> Result<HashMap<i32, i64>, KafkaError> {
+        let coordinator = self.coordinator()?;
+        let mut body = Vec::new();
+        put_str(&mut body, &self.group);
+        put_i32(&mut body, 1);
+        put_str(&mut body, &self.topic);
+        put_i32(&mut body, partitions.len() as i32);
+        for partition in partitions {
+            put_i32(&mut body, *partition);
+        }
+        let response = self.cluster.request_node(coordinator, OFFSET_FETCH, 1, &body)?;
+        let mut reader = Reader::new(&response);
+        let mut offsets = HashMap::new();
+        for _ in 0..reader.i32()? {
+            reader.string()?;
+            for _ in 0..reader.i32()? {
+                let partition = reader.i32()?;
+                let offset = reader.i64()?;
+                reader.nullable_string()?; // Metadata
+                let error_code = reader.i16()?;
+                if error_code != 0 {
+                    return Err(KafkaError::from_code(error_code, &format!("cannot read the offsets of group {}", self.group)));
+                }
+                offsets.insert(partition, offset);
+            }
+        }
+        Ok(offsets)
+    }
+
+    fn earliest_offset(&mut self, leader: i32, partition: i32) -> Result<i64, KafkaError> {
+        let mut body = Vec::new();
+        put_i32(&mut body, -1); // Replica ID: a consumer
+        put_i32(&mut body, 1);
+        put_str(&mut body, &self.topic);
+        put_i32(&mut body, 1);
+        put_i32(&mut body, partition);
+        put_i64(&mut body, EARLIEST);
+        let response = self.cluster.request_node(leader, LIST_OFFSETS, 1, &body)?;
+        // One topic with one partition was asked
+        let mut reader = Reader::new(&response);
+        if reader.i32()? > 0 {
+            reader.string()?;
+            if reader.i32()? > 0 {
+                reader.i32()?; // Partition index
+                let error_code = reader.i16()?;
+                reader.i64()?; // Timestamp
+                let offset = reader.i64()?;
+                if error_code != 0 {
+                    return Err(KafkaError::from_code(error_code, &format!("no offsets for {} partition {}", self.topic, partition)));
+                }
+                return Ok(offset);
+            }
+        }
+        Err(KafkaError::retriable(format!("no offsets for {} partition {}", self.topic, partition)))
+    }
+
+    /// Reads the next records of `partitions`, all led by `leader`, and moves past them.
+    fn fetch(&mut self, leader: i32, partitions: &[Position], wait_ms: i32) -> Result<Vec<KafkaRecord>, KafkaError> {
+        let mut body = Vec::new();
+        put_i32(&mut body, -1); // Replica ID: a consumer
+        put_i32(&mut body, wait_ms);
+        put_i32(&mut body, 1); // Min bytes: answer as soon as there is a record
+        put_i32(&mut body, FETCH_MAX_BYTES);
+        body.push(1); // Isolation: committed records only
+        put_i32(&mut body, 1);
+        put_str(&mut body, &self.topic);
+        put_i32(&mut body, partitions.len() as i32);
+        for position in partitions {
+            put_i32(&mut body, position.partition);
+            put_i64(&mut body, position.offset);
+            put_i32(&mut body, FETCH_MAX_BYTES);
+        }
+        let response = self.cluster.request_node(leader, FETCH, 4, &body)?;
+
+        let mut reader = Reader::new(&response);
+        reader.i32()?; // Throttle time
+        let mut records = Vec::new();
+        for _ in 0..reader.i32()? {
+            reader.string()?;
+            for _ in 0..reader.i32()? {
+                let partition = reader.i32()?;
+                let error_code = reader.i16()?;
+                reader.i64()?; // High watermark
+                reader.i64()?; // Last stable offset
+                for _ in 0..reader.i32()? {
+                    reader.i64()?; // Producer ID of an aborted transaction
+                    reader.i64()?; // Its first offset
+                }
+                let data = reader.nullable_bytes()?;
+                let Some(position) = self.position_mut(partition) else {
+                    continue;
+                };
+                match error_code {
+                    0 => {}
+                    OFFSET_OUT_OF_RANGE => {
+                        // The records were deleted by retention before being read
+                        let skipped_from = position.offset;
+                        let earliest = self.earliest_offset(leader, partition)?;
+                        logging::warn(
+                            "kafka_offset_reset",
+                            &[("topic", self.topic.as_str().into()), ("partition", i64::from(partition).into())],
+                            format!("Offset {} of {} partition {} is gone, going on from {}", skipped_from, self.topic, partition, earliest),
+                        );
+                        self.position_mut(partition).unwrap().offset = earliest;
+                        continue;
+                    }
+                    code => return Err(KafkaError::from_code(code, &format!("cannot read {} partition {}", self.topic, partition))),
+                }
+                let first = position.offset;
+                let read: Vec<KafkaRecord> = decode_batches(data.unwrap_or_default(), partition)?
+                    .into_iter()
+                    .filter(|record| record.offset >= first)
+                    .collect();
+                if let Some(last) = read.last() {
+                    position.offset = last.offset + 1;
+                }
+                records.extend(read);
+            }
+        }
+        Ok(records)
+    }
+
+    fn position_mut(&mut self, partition: i32) -> Option<&mut Position> {
+        self.positions.as_mut()?.iter_mut().find(|position| position.partition == partition)
+    }
+
+    fn try_commit(&mut self, record: &KafkaRecord) -> Result<(), KafkaError> {
+        let coordinator = self.coordinator()?;
+        let mut body = Vec::new();
+        put_str(&mut body, &self.group);
+        put_i32(&mut body, -1); // Generation: not a group member
+        put_str(&mut body, "");
+        put_i64(&mut body, -1); // Retention: the broker default
+        put_i32(&mut body, 1);
+        put_str(&mut body, &self.topic);
+        put_i32(&mut body, 1);
+        put_i32(&mut body, record.partition);
+        put_i64(&mut body, record.offset + 1);
+        put_i16(&mut body, -1); // No metadata
+        let response = self.cluster.request_node(coordinator, OFFSET_COMMIT, 2, &body)?;
+        let mut reader = Reader::new(&response);
+        for _ in 0..reader.i32()? {
+            reader.string()?;
+            for _ in 0..reader.i32()? {
+                reader.i32()?; // Partition index
+                let error_code = reader.i16()?;
+                if error_code != 0 {
+                    return Err(KafkaError::from_code(error_code, &format!("cannot commit the offset of group {}", self.group)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check_topic(topic: &str) -> Result<(), String> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-');
+    if topic.is_empty() || topic.len() > 249 || !topic.chars().all(valid) {
+        return Err(format!("invalid Kafka topic '{}'", topic));
+    }
+    Ok(())
+}
+
+/// Records of the record batches in `data`; a batch cut at the end, as fetch responses
+/// may have, is left for the next fetch.
+fn decode_batches(data: &[u8], partition: i32) -> Result<Vec<KafkaRecord>, KafkaError> {
+    let mut records = Vec::new();
+    let mut rest = data;
+    while rest.len() >= 12 {
+        let base_offset = i64::from_be_bytes(rest[..8].try_into().unwrap());
+        let length = i32::from_be_bytes(rest[8..12].try_into().unwrap()).max(0) as usize;
+        let Some(batch) = rest.get(12..12 + length) else {
+            break;
+        };
+        rest = &rest[12 + length..];
+        let mut reader = Reader::new(batch);
+        reader.i32()?; // Partition leader epoch
+        let magic = reader.i8()?;
+        if magic != 2 {
+            return Err(KafkaError::fatal(format!("unsupported Kafka message format v{}", magic)));
+        }
+        let crc = reader.i32()? as u32;
+        if crc32c(&batch[9..]) != crc {
+            return Err(KafkaError::fatal(format!("corrupt Kafka record batch at offset {}", base_offset)));
+        }
+        let attributes = reader.i16()?;
+        reader.take(4 + 8 + 8 + 8 + 2 + 4)?; // Offset delta, timestamps, producer
+        let count = reader.i32()?;
+        if attributes & 0x20 != 0 {
+            continue; // Control batch, e.g. a transaction marker
+        }
+        let body = match attributes & 0x07 {
+            0 => batch[reader.pos..].to_vec(),
+            1 => gunzip(&batch[reader.pos..], MAX_BATCH).map_err(|e| KafkaError::fatal(format!("bad gzip Kafka batch: {}", e)))?,
+            codec => {
+                let name = ["none", "gzip", "snappy", "lz4", "zstd"].get(codec as usize).unwrap_or(&"unknown");
+                return Err(KafkaError::fatal(format!("unsupported Kafka compression {}, produce with gzip or none", name)));
+            }
+        };
+        let mut reader = Reader::new(&body);
+        for _ in 0..count {
+            reader.varint()?; // Length
+            reader.i8()?; // Attributes
+            reader.varint()?; // Timestamp delta
+            let offset_delta = reader.varint()?;
+            let key = reader.varint_bytes()?;
+            let value = reader.varint_bytes()?;
+            let mut headers = Vec::new();
+            for _ in 0..reader.varint()? {
+                let name = String::from_utf8_lossy(&reader.varint_bytes()?.unwrap_or_default()).into_owned();
+                headers.push((name, reader.varint_bytes()?.unwrap_or_default()));
+            }
+            records.push(KafkaRecord {
+                partition,
+                offset: base_offset + offset_delta,
+                key,
+                value,
+                headers,
+            });
+        }
+    }
+    Ok(records)
+}
+
+#[derive(Debug)]
+struct KafkaError {
+    message: String,
+    retriable: bool, // Worth another try once the metadata is refreshed
+}
+
+impl KafkaError {
+    fn retriable(message: String) -> Self {
+        KafkaError { message, retriable: true }
+    }
+
+    fn fatal(message: String) -> Self {
+        KafkaError { message, retriable: false }
+    }
+
+    /// Error of a broker answer, by its Kafka error code.
+    fn from_code(code: i16, context: &str) -> Self {
+        let (name, retriable) = match code {
+            1 => ("OFFSET_OUT_OF_RANGE", false),
+            3 => ("UNKNOWN_TOPIC_OR_PARTITION", true),
+            5 => ("LEADER_NOT_AVAILABLE", true),
+            6 => ("NOT_LEADER_OR_FOLLOWER", true),
+            7 => ("REQUEST_TIMED_OUT", true),
+            10 => ("MESSAGE_TOO_LARGE", false),
+            13 => ("NETWORK_EXCEPTION", true),
+            14 => ("COORDINATOR_LOAD_IN_PROGRESS", true),
+            15 => ("COORDINATOR_NOT_AVAILABLE", true),
+            16 => ("NOT_COORDINATOR", true),
+            17 => ("INVALID_TOPIC_EXCEPTION", false),
+            19 => ("NOT_ENOUGH_REPLICAS", true),
+            20 => ("NOT_ENOUGH_REPLICAS_AFTER_APPEND", true),
+            22 => ("ILLEGAL_GENERATION, the group has members: use a group ID of its own", false),
+            25 => ("UNKNOWN_MEMBER_ID, the group has members: use a group ID of its own", false),
+            29 => ("TOPIC_AUTHORIZATION_FAILED", false),
+            30 => ("GROUP_AUTHORIZATION_FAILED", false),
+            31 => ("CLUSTER_AUTHORIZATION_FAILED", false),
+            _ => ("error", false),
+        };
+        KafkaError {
+            message: format!("{}: {} (code {})", context, name, code),
+            retriable,
+        }
+    }
+}
+
+fn connect(address: &str) -> Result<TcpStream, KafkaError> {
+    let fail = |e: std::io::Error| KafkaError::retriable(format!("cannot connect to Kafka broker {}: {}", address, e));
+    let socket = address
+        .to_socket_addrs()
+        .map_err(fail)?
+        .next()
+        .ok_or_else(|| KafkaError::fatal(format!("invalid Kafka broker address '{}'", address)))?;
+    let stream = TcpStream::connect_timeout(&socket, IO_TIMEOUT).map_err(fail)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT)).map_err(fail)?;
+    stream.set_write_timeout(Some(IO_TIMEOUT)).map_err(fail)?;
+    Ok(stream)
+}
+
+/// Sends a request (header v1) and returns the body of its response (header v0).
+fn request(stream: &mut TcpStream, api_key: i16, version: i16, correlation_id: i32, body: &[u8]) -> Result<Vec<u8>, KafkaError> {
+    let mut message = Vec::with_capacity(body.len() + 32);
+    put_i32(&mut message, 0); // Size, set below
+    put_i16(&mut message, api_key);
+    put_i16(&mut message, version);
+    put_i32(&mut message, correlation_id);
+    put_str(&mut message, CLIENT_ID);
+    message.extend_from_slice(body);
+    let size = (message.len() - 4) as i32;
+    message[..4].copy_from_slice(&size.to_be_bytes());
+
+    let fail = |e: std::io::Error| KafkaError::retriable(format!("Kafka request failed: {}", e));
+    stream.write_all(&message).map_err(fail)?;
+    let mut size = [0; 4];
+    stream.read_exact(&mut size).map_err(fail)?;
+    let size = i32::from_be_bytes(size);
+    if size < 4 || size as usize > MAX_RESPONSE {
+        return Err(KafkaError::fatal(format!("invalid Kafka response size {}", size)));
+    }
+    let mut response = vec![0; size as usize];
+    stream.read_exact(&mut response).map_err(fail)?;
+    if response[..4] != correlation_id.to_be_bytes() {
+        return Err(KafkaError::retriable("Kafka response to another request".to_string()));
+    }
+    response.drain(..4);
+    Ok(response)
+}
+
+/// Record batch (message format v2) holding one record.
+fn record_batch(key: &[u8], value: &[u8], headers: &[(&str, &str)], timestamp: i64) -> Vec<u8> {
+    let mut record = Vec::new();
+    record.push(0); // Attributes
+    put_varint(&mut record, 0); // Timestamp delta
+    put_varint(&mut record, 0); // Offset delta
+    put_varint(&mut record, key.len() as i64);
+    record.extend_from_slice(key);
+    put_varint(&mut record, value.len() as i64);
+    record.extend_from_slice(value);
+    put_varint(&mut record, headers.len() as i64);
+    for (name, value) in headers {
+        put_varint(&mut record, name.len() as i64);
+        record.extend_from_slice(name.as_bytes());
+        put_varint(&mut record, value.len() as i64);
+        record.extend_from_slice(value.as_bytes());
+    }
+
+    // What the CRC covers: from the attributes to the end
+    let mut tail = Vec::new();
+    put_i16(&mut tail, 0); // Attributes: no compression, create time, not transactional
+    put_i32(&mut tail, 0); // Last offset delta
+    put_i64(&mut tail, timestamp); // First timestamp
+    put_i64(&mut tail, timestamp); // Max timestamp
+    put_i64(&mut tail, -1); // Producer ID
+    put_i16(&mut tail, -1); // Producer epoch
+    put_i32(&mut tail, -1); // Base sequence
+    put_i32(&mut tail, 1); // Records
+    put_varint(&mut tail, record.len() as i64);
+    tail.extend_from_slice(&record);
+
+    let mut batch = Vec::with_capacity(tail.len() + 21);
+    put_i64(&mut batch, 0); // Base offset, assigned by the broker
+    put_i32(&mut batch, (tail.len() + 9) as i32); // Length after this field
+    put_i32(&mut batch, -1); // Partition leader epoch
+    batch.push(2); // Magic
+    batch.extend_from_slice(&crc32c(&tail).to_be_bytes());
+    batch.extend_from_slice(&tail);
+    batch
+}
+
+/// Partition of a key, as chosen by the default partitioner of the Java client.
+fn partition_for(key: &[u8], partitions: usize) -> usize {
+    (murmur2(key) & 0x7fff_ffff) as usize % partitions
+}
+
+/// MurmurHash2, 32-bit, with the seed used by Kafka.
+fn murmur2(data: &[u8]) -> u32 {
+    const M: u32 = 0x5bd1_e995;
+    let mut h = 0x9747_b28c ^ data.len() as u32;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> 24;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M) ^ k;
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (n, byte) in rest.iter().enumerate() {
+            h ^= u32::from(*byte) << (8 * n);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^ (h >> 15)
+}
+
+/// CRC-32C (Castagnoli), as required by record batches.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64
+}
+
+fn put_i16(out: &mut Vec<u8>, value: i16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_i32(out: &mut Vec<u8>, value: i32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_i64(out: &mut Vec<u8>, value: i64) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_str(out: &mut Vec<u8>, value: &str) {
+    put_i16(out, value.len() as i16);
+    out.extend_from_slice(value.as_bytes());
+}
+
+/// Zigzag varint, as used inside records.
+fn put_varint(out: &mut Vec<u8>, value: i64) {
+    let mut zigzag = ((value << 1) ^ (value >> 63)) as u64;
+    while zigzag >= 0x80 {
+        out.push(zigzag as u8 | 0x80);
+        zigzag >>= 7;
+    }
+    out.push(zigzag as u8);
+}
+
+/// Reads the fields of a response in order; running past the end is an error.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], KafkaError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| KafkaError::fatal("truncated Kafka response".to_string()))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn i8(&mut self) -> Result<i8, KafkaError> {
+        Ok(self.take(1)?[0] as i8)
+    }
+
+    fn i16(&mut self) -> Result<i16, KafkaError> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, KafkaError> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> Result<i64, KafkaError> {
+        Ok(i64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn nullable_string(&mut self) -> Result<Option<String>, KafkaError> {
+        let len = self.i16()?;
+        if len < 0 {
+            return Ok(None);
+        }
+        Ok(Some(String::from_utf8_lossy(self.take(len as usize)?).into_owned()))
+    }
+
+    fn string(&mut self) -> Result<String, KafkaError> {
+        Ok(self.nullable_string()?.unwrap_or_default())
+    }
+
+    fn nullable_bytes(&mut self) -> Result<Option<&'a [u8]>, KafkaError> {
+        let len = self.i32()?;
+        if len < 0 {
+            return Ok(None);
+        }
+        self.take(len as usize).map(Some)
+    }
+
+    /// Zigzag varint, as used inside records.
+    fn varint(&mut self) -> Result<i64, KafkaError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok((value >> 1) as i64 ^ -((value & 1) as i64));
+            }
+        }
+        Err(KafkaError::fatal("invalid varint in Kafka record".to_string()))
+    }
+
+    /// Bytes prefixed by their varint length, -1 meaning null.
+    fn varint_bytes(&mut self) -> Result<Option<Vec<u8>>, KafkaError> {
+        let len = self.varint()?;
+        if len < 0 {
+            return Ok(None);
+        }
+        Ok(Some(self.take(len as usize)?.to_vec()))
+    }
+
+    fn skip_i32_array(&mut self) -> Result<(), KafkaError> {
+        let len = self.i32()?.max(0) as usize;
+        self.take(len * 4).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `batch` given the attributes `attributes` and the records `body` (of `count` records),
+    /// with the CRC recomputed.
+    fn with_body(attributes: i16, count: i32, body: &[u8]) -> Vec<u8> {
+        let mut tail = Vec::new();
+        put_i16(&mut tail, attributes);
+        tail.extend_from_slice(&[0; 4 + 8 + 8 + 8 + 2 + 4]);
+        put_i32(&mut tail, count);
+        tail.extend_from_slice(body);
+        let mut batch = Vec::new();
+        put_i64(&mut batch, 40);
+        put_i32(&mut batch, (tail.len() + 9) as i32);
+        put_i32(&mut batch, -1);
+        batch.push(2);
+        batch.extend_from_slice(&crc32c(&tail).to_be_bytes());
+        batch.extend_from_slice(&tail);
+        batch
+    }
+
+    fn gzip(deflate: &[u8]) -> Vec<u8> {
+        let mut data = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+        data.extend_from_slice(deflate);
+        data.extend_from_slice(&[0; 8]);
+        data
+    }
+
+    #[test]
+    fn record_batch_round_trip() {
+        let mut data = record_batch(b"500960046", b"{\"nif\":\"500960046\"}", &[("source", "erp")], 1_700_000_000_000);
+        data.extend(record_batch(b"501442600", b"{}", &[], 1_700_000_000_000));
+        let records = decode_batches(&data, 3).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].partition, 3);
+        assert_eq!(records[0].key.as_deref(), Some(&b"500960046"[..]));
+        assert_eq!(records[0].value.as_deref(), Some(&b"{\"nif\":\"500960046\"}"[..]));
+        assert_eq!(records[0].header("source"), Some("erp"));
+        assert_eq!(records[1].key.as_deref(), Some(&b"501442600"[..]));
+        assert_eq!(records[1].header("source"), None);
+    }
+
+    #[test]
+    fn truncated_batch_left_for_next_fetch() {
+        let mut data = record_batch(b"500960046", b"{}", &[], 0);
+        let whole = data.len();
+        data.extend(record_batch(b"501442600", b"{}", &[], 0));
+        data.truncate(whole + 20);
+        assert_eq!(decode_batches(&data, 0).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn corrupt_batch() {
+        let mut data = record_batch(b"500960046", b"{}", &[], 0);
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        let error = decode_batches(&data, 0).unwrap_err();
+        assert!(error.message.contains("corrupt"), "{}", error.message);
+        assert!(!error.retriable);
+    }
+
+    #[test]
+    fn gzip_batch() {
+        let plain = record_batch(b"500960046", b"{}", &[], 0);
+        let records = &plain[61..]; // After the header, the attributes and the record count
+        let mut stored = vec![1];
+        stored.extend_from_slice(&(records.len() as u16).to_le_bytes());
+        stored.extend_from_slice(&(!(records.len() as u16)).to_le_bytes());
+        stored.extend_from_slice(records);
+        let decoded = decode_batches(&with_body(1, 1, &gzip(&stored)), 0).unwrap();
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[0].offset, 40);
+        assert_eq!(decoded[0].key.as_deref(), Some(&b"500960046"[..]));
+    }
+
+    #[test]
+    fn gzip_bomb() {
+        // Some 20 MB of zeros out of 80 kB, more than `MAX_BATCH`
+        let error = decode_batches(&with_body(1, 1, &gzip(&crate::inflate::tests::zeros(80_000))), 0).unwrap_err();
+        assert!(error.message.contains("more than"), "{}", error.message);
+    }
+
+    #[test]
+    fn unsupported_compression() {
+        let error = decode_batches(&with_body(2, 1, b""), 0).unwrap_err();
+        assert!(error.message.contains("snappy"), "{}", error.message);
+    }
+
+    #[test]
+    fn murmur2_as_java_client() {
+        // Cases of the Java client's own tests
+        for (key, hash) in [
+            (&b"21"[..], -973_932_308),
+            (b"foobar", -790_332_482),
+            (b"a-little-bit-long-string", -985_981_536),
+            (b"a-little-bit-longer-string", -1_486_304_829),
+            (b"lkjh234lh9fiuh90y23oiuhsafujhadof229phr9h19h89h8", -58_897_971),
+            (b"abc", 479_470_107),
+        ] {
+            assert_eq!(murmur2(key) as i32, hash, "{}", String::from_utf8_lossy(key));
+        }
+    }
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+}
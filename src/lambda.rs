@@ -0,0 +1,158 @@
+// lambda.rs
+
+//! AWS Lambda handler, for `--features lambda`. `handle` answers one invocation event, and
+//! `run_runtime` serves it as a custom runtime through the Lambda Runtime API, for the
+//! `bootstrap` of a `provided.al2023` function (`check_nif lambda`).
+//!
+//! `handle` works on the JSON text of the event, so it also fits a `lambda_runtime`
+//! `service_fn` that passes the `serde_json::Value` through `to_string`.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use reqwest::blocking::Client;
+
+use crate::json::JsonValue;
+use crate::logging;
+use crate::lookup::{lookup_nif, LookupOptions};
+use crate::validation::normalize_nif;
+
+/// Most NIFs of a batch event; Lambda stops a function after 15 minutes at most.
+pub const BATCH_LIMIT: usize = 1000;
+
+/// Version of the Lambda Runtime API used.
+const API_VERSION: &str = "2018-06-01";
+
+/// Time kept before the deadline of an invocation to post its response.
+const RESPONSE_MARGIN: Duration = Duration::from_millis(500);
+
+/// An event that is not a NIF check, returned to the caller as the error of the invocation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidEvent(pub String);
+
+/// Answers one event:
+///
+/// - `"500960046"` or `{"nif": "500960046"}`: the result JSON of the NIF;
+/// - `["500960046", ...]` or `{"nifs": [...]}`: `{"results": [...]}`, in order.
+///
+/// NIFs may be strings or numbers.
+pub fn handle(event: &str, options: &LookupOptions) -> Result<JsonValue, InvalidEvent> {
+    handle_until(event, options, None)
+}
+
+/// Answers one event like [`handle`], every lookup ending by `deadline` at the latest.
+pub fn handle_until(event: &str, options: &LookupOptions, deadline: Option<SystemTime>) -> Result<JsonValue, InvalidEvent> {
+    let lookup = |nif: &str| lookup_until(nif, options, deadline);
+    let event = JsonValue::parse(event).map_err(|e| InvalidEvent(format!("the event is not JSON: {}", e)))?;
+    let batch = match &event {
+        JsonValue::Array(nifs) => nifs.as_slice(),
+        JsonValue::Object(_) => match (event.get("nif"), event.get("nifs")) {
+            (Some(nif), None) => return Ok(lookup(&nif_of(nif)?)),
+            (None, Some(JsonValue::Array(nifs))) => nifs.as_slice(),
+            _ => return Err(InvalidEvent("the event needs either \"nif\" or a \"nifs\" array".to_string())),
+        },
+        nif => return Ok(lookup(&nif_of(nif)?)),
+    };
+    if batch.len() > BATCH_LIMIT {
+        return Err(InvalidEvent(format!("batches are limited to {} NIFs", BATCH_LIMIT)));
+    }
+    let nifs = batch.iter().map(nif_of).collect::<Result<Vec<String>, InvalidEvent>>()?;
+    let results: Vec<JsonValue> = nifs.iter().map(|nif| lookup(nif)).collect();
+    Ok(JsonValue::object().with("results", results))
+}
+
+/// Looks a NIF up, its deadline cut to the time left before `deadline`.
+fn lookup_until(nif: &str, options: &LookupOptions, deadline: Option<SystemTime>) -> JsonValue {
+    let Some(deadline) = deadline else {
+        return lookup_nif(nif, options).to_json();
+    };
+    let left = deadline.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO);
+    let mut options = options.clone();
+    options.timeouts.deadline = Some(options.timeouts.deadline.map_or(left, |own| own.min(left)));
+    lookup_nif(nif, &options).to_json()
+}
+
+/// Reads a NIF: a string, or a number restored to 9 digits.
+fn nif_of(value: &JsonValue) -> Result<String, InvalidEvent> {
+    match value {
+        JsonValue::String(nif) if !normalize_nif(nif).is_empty() => Ok(normalize_nif(nif).to_string()),
+        JsonValue::Int(nif) if *nif >= 0 => Ok(format!("{:09}", nif)),
+        JsonValue::String(nif) => Err(InvalidEvent(format!("not a NIF: {}", logging::display_nif(nif)))),
+        other => Err(InvalidEvent(format!("not a NIF: {}", logging::display_nif(&other.to_string())))),
+    }
+}
+
+/// Client of the Lambda Runtime API, at `AWS_LAMBDA_RUNTIME_API`.
+#[derive(Debug)]
+pub struct LambdaRuntime {
+    base: String, // http://host:port/2018-06-01/runtime
+    client: Client,
+}
+
+impl LambdaRuntime {
+    /// Runtime of the function this process runs in; fails outside Lambda.
+    pub fn from_env() -> Result<Self, String> {
+        let api = std::env::var("AWS_LAMBDA_RUNTIME_API")
+            .map_err(|_| "AWS_LAMBDA_RUNTIME_API is not set: not running in AWS Lambda".to_string())?;
+        // No timeout: asking for the next invocation waits until there is one
+        let client = Client::builder()
+            .timeout(None::<Duration>)
+            .build()
+            .map_err(|e| format!("cannot build the Lambda runtime client: {}", e))?;
+        Ok(LambdaRuntime {
+            base: format!("http://{}/{}/runtime", api, API_VERSION),
+            client,
+        })
+    }
+
+    /// Reports that the function could not start; Lambda then fails the invocation.
+    pub fn init_error(&self, message: &str) -> Result<(), String> {
+        self.post(&format!("{}/init/error", self.base), error_body("InitError", message))
+    }
+
+    /// Answers invocations one after the other, for as long as Lambda keeps the process.
+    pub fn run(&self, options: &LookupOptions) -> Result<(), String> {
+        loop {
+            let response = self
+                .client
+                .get(format!("{}/invocation/next", self.base))
+                .send()
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| format!("cannot get the next Lambda invocation: {}", e))?;
+            let header = |name: &str| response.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+            let request_id = header("lambda-runtime-aws-request-id").ok_or("Lambda invocation without a request ID")?;
+            // Milliseconds since the epoch when Lambda stops the function
+            let deadline = header("lambda-runtime-deadline-ms")
+                .and_then(|ms| ms.parse().ok())
+                .map(|ms| UNIX_EPOCH + Duration::from_millis(ms))
+                .map(|at| at.checked_sub(RESPONSE_MARGIN).unwrap_or(at));
+            let event = response.text().map_err(|e| format!("cannot read the Lambda event: {}", e))?;
+            match handle_until(&event, options, deadline) {
+                Ok(result) => self.post(&format!("{}/invocation/{}/response", self.base, request_id), result)?,
+                Err(InvalidEvent(message)) => {
+                    logging::warn(
+                        "lambda_invalid_event",
+                        &[("request_id", request_id.as_str().into())],
+                        format!("Invalid event: {}", message),
+                    );
+                    let body = error_body("InvalidEvent", &message);
+                    self.post(&format!("{}/invocation/{}/error", self.base, request_id), body)?
+                }
+            }
+        }
+    }
+
+    fn post(&self, url: &str, body: JsonValue) -> Result<(), String> {
+        self.client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .body(body.to_string())
+            .send()
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+            .map_err(|e| format!("cannot post to the Lambda runtime: {}", e))
+    }
+}
+
+fn error_body(error_type: &str, message: &str) -> JsonValue {
+    JsonValue::object().with("errorType", error_type).with("errorMessage", message)
+}
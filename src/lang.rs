@@ -0,0 +1,68 @@
+// lang.rs
+
+//! Language of the messages written for people: the text output, status titles and reports.
+//! Machine-readable outputs (status labels, CSV, JSON...) and log messages stay in English
+//! whatever the language, so scripts and log queries keep working.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::validation::NifCategory;
+
+/// Supported languages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lang {
+    En, // English, the default
+    Pt, // European Portuguese
+}
+
+impl Lang {
+    /// Parses a `--lang` value.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "en" => Ok(Lang::En),
+            "pt" => Ok(Lang::Pt),
+            other => Err(format!("unknown language '{}', expected pt or en", other)),
+        }
+    }
+
+    /// ISO 639-1 code, e.g. for the `lang` attribute of HTML reports.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Lang::En => "en",
+            Lang::Pt => "pt",
+        }
+    }
+}
+
+static LANG: AtomicU8 = AtomicU8::new(0);
+
+/// Selects the language of messages for the whole process.
+pub fn set_lang(lang: Lang) {
+    LANG.store(lang as u8, Ordering::Relaxed);
+}
+
+/// Returns the language of messages currently in use.
+pub fn lang() -> Lang {
+    match LANG.load(Ordering::Relaxed) {
+        1 => Lang::Pt,
+        _ => Lang::En,
+    }
+}
+
+/// Picks the text of the current language.
+pub fn tr(en: &'static str, pt: &'static str) -> &'static str {
+    match lang() {
+        Lang::En => en,
+        Lang::Pt => pt,
+    }
+}
+
+/// Plural name of the NIFs of a category, as in "company NIFs are not expected".
+pub fn category_name(category: NifCategory) -> &'static str {
+    match category {
+        NifCategory::Person => tr("person", "pessoas singulares"),
+        NifCategory::Company => tr("company", "empresas"),
+        NifCategory::Public => tr("public", "entidades públicas"),
+        NifCategory::Other => tr("other", "outras entidades"),
+    }
+}
@@ -0,0 +1,50 @@
+// layout.rs
+
+use scraper::{Html, Selector};
+
+/// Selectors of one known nif.pt page layout.
+///
+/// When nif.pt changes its markup, the new layout is added in front of `LAYOUTS`; pages
+/// still served in the old one (cached by a CDN, or during a rollout) keep parsing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub name: &'static str,                 // Shown in logs
+    pub markers: &'static [&'static str],   // A page matching any of these is in this layout
+    pub error: &'static str,                // Error message, the NIF is invalid
+    pub success: &'static str,              // Success message, the NIF is valid
+    pub valid_unknown_text: &'static str,   // Text of the success message when the entity is unknown
+    pub search_results: &'static str,       // List of results, for several companies
+    pub search_title: &'static str,         // Company name, in the list or on an entity page
+    pub entity_marker: &'static str,        // The big NIF heading of an entity page
+    pub detail: &'static str,               // Entity details, one item per line
+}
+
+/// Layouts the scraper knows, newest first.
+pub const LAYOUTS: &[Layout] = &[Layout {
+    name: "alert-message",
+    markers: &[".alert-message", "#search-results", ".big-nif"],
+    error: ".alert-message.error.block-message",
+    success: ".alert-message.success.block-message",
+    valid_unknown_text: "O NIF indicado é válido mas não conseguimos determinar a entidade associada.",
+    search_results: "#search-results",
+    search_title: ".search-title",
+    entity_marker: ".big-nif",
+    detail: ".detail",
+}];
+
+impl Layout {
+    /// Returns the first known layout whose markers are found in `document`.
+    pub fn detect(document: &Html) -> Option<&'static Layout> {
+        LAYOUTS.iter().find(|layout| {
+            layout
+                .markers
+                .iter()
+                .any(|marker| document.select(&selector(marker)).next().is_some())
+        })
+    }
+}
+
+/// Compiles one of the selectors above, which are all valid.
+pub fn selector(css: &str) -> Selector {
+    Selector::parse(css).unwrap_or_else(|e| panic!("invalid layout selector '{}': {:?}", css, e))
+}
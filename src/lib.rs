@@ -0,0 +1,149 @@
+// lib.rs
+
+//! Checks Portuguese NIFs (Número de Identificação Fiscal), either locally with the
+//! check digit algorithm or online through nif.pt.
+//!
+//! Everything but the portable core (`validation`, `status`, `entity`, `json`, `scan`)
+//! needs the default `client` feature.
+
+#[cfg(feature = "arrow")]
+pub mod arrow;
+#[cfg(feature = "client")]
+pub mod auth;
+#[cfg(feature = "client")]
+pub mod breaker;
+#[cfg(feature = "client")]
+pub mod cache;
+#[cfg(feature = "client")]
+pub mod cancel;
+#[cfg(feature = "client")]
+pub mod cassette;
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "client")]
+pub mod compare;
+#[cfg(feature = "client")]
+pub mod config;
+#[cfg(feature = "client")]
+pub mod cors;
+#[cfg(feature = "client")]
+pub mod csv;
+#[cfg(feature = "client")]
+pub mod dashboard;
+#[cfg(feature = "client")]
+pub mod dns;
+#[cfg(feature = "client")]
+pub mod encoding;
+pub mod entity;
+#[cfg(feature = "client")]
+pub mod fallback;
+#[cfg(feature = "client")]
+pub mod generate;
+#[cfg(feature = "geocode")]
+pub mod geocode;
+#[cfg(feature = "graphql")]
+pub mod graphql;
+#[cfg(feature = "client")]
+pub mod health;
+#[cfg(feature = "client")]
+pub mod hooks;
+#[cfg(feature = "client")]
+pub mod http;
+#[cfg(feature = "client")]
+pub mod import;
+#[cfg(any(feature = "pdf", feature = "kafka"))]
+pub mod inflate;
+#[cfg(feature = "client")]
+pub mod input;
+#[cfg(feature = "client")]
+pub mod interrupt;
+#[cfg(feature = "client")]
+pub mod invoice;
+#[cfg(feature = "lambda")]
+pub mod lambda;
+#[cfg(feature = "client")]
+pub mod jobs;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "client")]
+pub mod lang;
+#[cfg(feature = "client")]
+pub mod layout;
+pub mod json;
+#[cfg(feature = "client")]
+pub mod logging;
+#[cfg(feature = "client")]
+pub mod lookup;
+#[cfg(feature = "client")]
+pub mod mail;
+#[cfg(feature = "client")]
+pub mod msgpack;
+#[cfg(feature = "client")]
+pub mod names;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+#[cfg(feature = "client")]
+pub mod output;
+#[cfg(feature = "client")]
+pub mod page;
+#[cfg(feature = "client")]
+pub mod page_cache;
+#[cfg(feature = "pdf")]
+pub mod pdf;
+#[cfg(feature = "client")]
+pub mod pipeline;
+#[cfg(feature = "client")]
+pub mod postal;
+#[cfg(feature = "client")]
+pub mod progress;
+#[cfg(feature = "client")]
+pub mod proto;
+#[cfg(feature = "client")]
+pub mod queue;
+#[cfg(feature = "client")]
+pub mod ratelimit;
+#[cfg(feature = "client")]
+pub mod redis_cache;
+#[cfg(feature = "client")]
+pub mod reference;
+#[cfg(feature = "client")]
+pub mod report;
+#[cfg(feature = "client")]
+pub mod request_lock;
+#[cfg(feature = "client")]
+pub mod retry;
+#[cfg(feature = "client")]
+pub mod saft;
+pub mod scan;
+#[cfg(feature = "client")]
+pub mod schema;
+#[cfg(feature = "client")]
+pub mod server;
+#[cfg(feature = "client")]
+pub mod statsd;
+pub mod status;
+#[cfg(feature = "client")]
+pub mod store;
+#[cfg(feature = "client")]
+pub mod systemd;
+#[cfg(feature = "client")]
+pub mod time;
+#[cfg(feature = "client")]
+pub mod tls;
+pub mod validation;
+#[cfg(feature = "client")]
+pub mod vcard;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "client")]
+pub mod xml;
+
+#[cfg(feature = "client")]
+pub use cancel::CancellationToken;
+#[cfg(feature = "client")]
+pub use client::{LookupIter, NifClient, NifClientBuilder};
+pub use entity::NifEntity;
+#[cfg(feature = "client")]
+pub use lookup::{check_nif_status, check_nif_status_with, lookup_nif, LookupOptions, LookupResult, LookupSource};
+pub use status::NifStatus;
+pub use validation::is_nif_valid_local;
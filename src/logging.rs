@@ -0,0 +1,248 @@
+// logging.rs
+
+use std::cell::RefCell;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::time::{Duration, SystemTime};
+
+use crate::json::JsonValue;
+use crate::status::NifStatus;
+use crate::time::format_rfc3339;
+
+/// How diagnostic messages are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text, // Human-readable lines: info on stdout (unless redirected), warnings and errors on stderr
+    Json, // One JSON object per line on stderr, NIFs hashed, for Loki/ELK ingestion
+}
+
+impl LogFormat {
+    /// Parses a `--log-format` value.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("unknown log format '{}', expected text or json", other)),
+        }
+    }
+}
+
+static FORMAT: AtomicU8 = AtomicU8::new(0);
+
+/// Selects the log format for the whole process.
+pub fn set_format(format: LogFormat) {
+    FORMAT.store(format as u8, Ordering::Relaxed);
+}
+
+/// Returns the log format currently in use.
+pub fn format() -> LogFormat {
+    match FORMAT.load(Ordering::Relaxed) {
+        1 => LogFormat::Json,
+        _ => LogFormat::Text,
+    }
+}
+
+static INFO_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Sends informational text messages to stderr too, keeping stdout for machine-readable
+/// output such as `--format json`.
+pub fn set_info_to_stderr(enabled: bool) {
+    INFO_TO_STDERR.store(enabled, Ordering::Relaxed);
+}
+
+/// How NIFs appear in logs, metrics and traces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NifPrivacy {
+    Clear, // The NIF itself; the default of text logs
+    Hash,  // A truncated HMAC-SHA256, still correlatable; the default of JSON logs
+    Mask,  // The first 4 digits only, e.g. `5009*****`
+}
+
+impl NifPrivacy {
+    /// Parses a `--nif-privacy` value.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "clear" => Ok(NifPrivacy::Clear),
+            "hash" => Ok(NifPrivacy::Hash),
+            "mask" => Ok(NifPrivacy::Mask),
+            other => Err(format!("unknown NIF privacy '{}', expected clear, hash or mask", other)),
+        }
+    }
+}
+
+static PRIVACY: AtomicU8 = AtomicU8::new(0); // 0 until set: depends on the log format
+
+/// Selects how NIFs appear in diagnostics for the whole process.
+pub fn set_nif_privacy(privacy: NifPrivacy) {
+    PRIVACY.store(privacy as u8 + 1, Ordering::Relaxed);
+}
+
+/// Returns how NIFs appear in diagnostics: as set, else clear in text logs and hashed in JSON.
+pub fn nif_privacy() -> NifPrivacy {
+    match PRIVACY.load(Ordering::Relaxed) {
+        1 => NifPrivacy::Clear,
+        2 => NifPrivacy::Hash,
+        3 => NifPrivacy::Mask,
+        _ if format() == LogFormat::Json => NifPrivacy::Hash,
+        _ => NifPrivacy::Clear,
+    }
+}
+
+thread_local! {
+    static REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Sets the ID of the request handled by the current thread, added to every JSON log line
+/// (as `request_id`) and trace span it emits; `None` clears it.
+pub fn set_request_id(id: Option<String>) {
+    REQUEST_ID.with(|current| *current.borrow_mut() = id);
+}
+
+/// Returns the ID of the request handled by the current thread, if any.
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.with(|current| current.borrow().clone())
+}
+
+/// Logs an informational message.
+///
+/// In text mode `text` is printed as is. In JSON mode it is dropped in favour of the
+/// stable `event` name and the structured `fields`, so that NIFs embedded in the human
+/// message never reach the log pipeline.
+pub fn info(event: &str, fields: &[(&str, JsonValue)], text: String) {
+    match format() {
+        LogFormat::Text if INFO_TO_STDERR.load(Ordering::Relaxed) => eprintln!("{}", text),
+        LogFormat::Text => println!("{}", text),
+        LogFormat::Json => emit("info", event, fields),
+    }
+}
+
+/// Logs a warning, see `info`.
+pub fn warn(event: &str, fields: &[(&str, JsonValue)], text: String) {
+    match format() {
+        LogFormat::Text => eprintln!("{}", text),
+        LogFormat::Json => emit("warn", event, fields),
+    }
+}
+
+/// Logs an error, see `info`.
+pub fn error(event: &str, fields: &[(&str, JsonValue)], text: String) {
+    match format() {
+        LogFormat::Text => eprintln!("{}", text),
+        LogFormat::Json => emit("error", event, fields),
+    }
+}
+
+/// Logs the summary of one finished lookup. Only JSON logs get it, the text mode already
+/// prints a status line per NIF.
+pub fn lookup_summary(nif: &str, status: &NifStatus, latency: Duration, backend: &str, cache_hit: bool) {
+    if format() != LogFormat::Json {
+        return;
+    }
+    emit(
+        "info",
+        "lookup",
+        &[
+            nif_field(nif),
+            ("status", status.label().into()),
+            ("http_status", status.http_status().into()),
+            ("latency_ms", (latency.as_millis() as i64).into()),
+            ("backend", backend.into()),
+            ("cache_hit", cache_hit.into()),
+        ],
+    );
+}
+
+/// Builds the field identifying a NIF in structured logs and traces, following
+/// `nif_privacy`: `nif`, `nif_hash` or `nif_masked`.
+pub fn nif_field(nif: &str) -> (&'static str, JsonValue) {
+    match nif_privacy() {
+        NifPrivacy::Clear => ("nif", nif.into()),
+        NifPrivacy::Hash => ("nif_hash", hash_nif(nif).into()),
+        NifPrivacy::Mask => ("nif_masked", mask_nif(nif).into()),
+    }
+}
+
+/// Returns the NIF as it may appear in human-readable diagnostics, following `nif_privacy`.
+pub fn display_nif(nif: &str) -> String {
+    match nif_privacy() {
+        NifPrivacy::Clear => nif.to_string(),
+        NifPrivacy::Hash => hash_nif(nif),
+        NifPrivacy::Mask => mask_nif(nif),
+    }
+}
+
+/// Keeps the first 4 characters of a NIF and masks the rest, e.g. `5009*****`.
+pub fn mask_nif(nif: &str) -> String {
+    nif.chars().enumerate().map(|(i, c)| if i < 4 { c } else { '*' }).collect()
+}
+
+/// Environment variable holding the secret key of NIF hashes, when `--nif-hash-key` is not given.
+pub const NIF_HASH_KEY_ENV: &str = "CHECK_NIF_HASH_KEY";
+
+/// Fewest bytes of a NIF hash key: a short key is as guessable as the NIFs themselves.
+pub const MIN_NIF_HASH_KEY_LEN: usize = 16;
+
+static HASH_KEY: OnceLock<ring::hmac::Key> = OnceLock::new();
+
+/// Sets the secret key of `hash_nif` for the whole process, before any NIF is hashed.
+///
+/// Without a key, hashes use a random key of the process: stable within a run, but not
+/// across runs. Give the same key to runs whose logs must be correlated.
+pub fn set_nif_hash_key(key: &[u8]) -> Result<(), String> {
+    if key.len() < MIN_NIF_HASH_KEY_LEN {
+        return Err(format!("the NIF hash key must have at least {} bytes", MIN_NIF_HASH_KEY_LEN));
+    }
+    HASH_KEY
+        .set(ring::hmac::Key::new(ring::hmac::HMAC_SHA256, key))
+        .map_err(|_| "the NIF hash key was already in use".to_string())
+}
+
+/// Hashes a NIF into 16 hex digits, by HMAC-SHA256 with the key of `set_nif_hash_key`, so
+/// lookups can be correlated. There are only a billion NIFs, so a plain hash would be undone
+/// by hashing them all; the key is what keeps the NIF behind the hash.
+pub fn hash_nif(nif: &str) -> String {
+    let key = HASH_KEY.get_or_init(|| ring::hmac::Key::new(ring::hmac::HMAC_SHA256, &rand::random::<[u8; 32]>()));
+    let tag = ring::hmac::sign(key, nif.as_bytes());
+    tag.as_ref()[..8].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn emit(level: &str, event: &str, fields: &[(&str, JsonValue)]) {
+    let mut line = JsonValue::object()
+        .with("ts", format_rfc3339(SystemTime::now()))
+        .with("level", level)
+        .with("event", event);
+    if let Some(id) = request_id() {
+        line = line.with("request_id", id);
+    }
+    for (key, value) in fields {
+        line = line.with(key, value.clone());
+    }
+    eprintln!("{}", line);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mask() {
+        assert_eq!(mask_nif("500960046"), "5009*****");
+    }
+
+    #[test]
+    fn hash_is_keyed() {
+        let hash = hash_nif("500960046");
+        assert_eq!(hash.len(), 16);
+        assert_eq!(hash, hash_nif("500960046"));
+        assert_ne!(hash, hash_nif("501442600"));
+        // Not the bare SHA-256 of the NIF, which anyone can compute for every NIF
+        let digest = ring::digest::digest(&ring::digest::SHA256, b"500960046");
+        let unkeyed: String = digest.as_ref()[..8].iter().map(|b| format!("{:02x}", b)).collect();
+        assert_ne!(hash, unkeyed);
+    }
+
+    #[test]
+    fn short_hash_key() {
+        assert!(set_nif_hash_key(b"too short").unwrap_err().contains("at least 16 bytes"));
+    }
+}
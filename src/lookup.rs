@@ -0,0 +1,1125 @@
+// lookup.rs
+
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use reqwest::blocking::{Client, ClientBuilder}; // For making synchronous HTTP requests
+use reqwest::dns::Resolve;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Certificate, Proxy};
+
+use crate::breaker::CircuitBreaker;
+use crate::cache::{is_cacheable, Cache, CacheEntry};
+use crate::cancel::{is_cancelled, CancellationToken};
+use crate::cassette::{Cassette, CassetteMode, Interaction};
+use crate::client::NifClient;
+use crate::dns::SharedResolver;
+use crate::entity::{EntityCandidate, NifEntity};
+use crate::fallback::Fallback;
+use crate::health::BackendHealth;
+use crate::json::JsonValue;
+#[cfg(feature = "geocode")]
+use crate::geocode::Geocoder;
+#[cfg(feature = "otlp")]
+use crate::otlp::{AttributeValue, OtlpExporter, SpanData};
+use crate::logging::{self, display_nif, nif_field};
+pub use crate::page::{
+    parse_candidates, parse_page, parse_page_confidence, results_url, results_url_at, results_url_nif, results_url_nif_at, ParseConfidence,
+    NIF_PT_URL,
+    LOW_PARSE_CONFIDENCE,
+};
+use crate::page::response_status;
+use crate::page_cache::PageCache;
+use crate::names::{ExpectedNames, NameCheck};
+use crate::postal::{EMBEDDED, PostalCheck, PostalTable};
+use crate::queue::{Priority, RequestQueue};
+use crate::ratelimit::{retry_after, Throttle};
+use crate::request_lock::RequestLock;
+use crate::statsd::StatsdClient;
+use crate::status::NifStatus;
+use crate::store::ResultStore;
+use crate::time::format_rfc3339;
+use crate::tls;
+use crate::validation::{is_nif_valid_local, nif_category, normalize_nif, NifCategory};
+
+/// Settings used to build the HTTP client for remote lookups.
+#[derive(Clone, Default)]
+pub struct LookupOptions {
+    /// Static DNS overrides, like curl's `--resolve`: host name and the IP to use for it.
+    pub resolve: Vec<(String, IpAddr)>,
+    /// Resolver used for every host not covered by `resolve` (system DNS when `None`).
+    pub dns_resolver: Option<Arc<dyn Resolve>>,
+    /// Extra trusted root certificates, e.g. the CA of a TLS-intercepting corporate proxy.
+    pub ca_certificates: Vec<Certificate>,
+    /// Skips certificate validation entirely. Only meant as an escape hatch.
+    pub accept_invalid_certs: bool,
+    /// SHA-256 fingerprints of the certificates nif.pt may present; empty means no pinning.
+    pub pinned_certificates: Vec<[u8; 32]>,
+    /// Breaker shared by every lookup made with these options; `None` disables it.
+    pub circuit_breaker: Option<Arc<CircuitBreaker>>,
+    /// Where to push lookup counters and timings; `None` disables metrics.
+    pub statsd: Option<Arc<StatsdClient>>,
+    /// Cache consulted before, and filled after, every remote lookup; `None` disables it.
+    pub cache: Option<Arc<dyn Cache>>,
+    /// Local store answering lookups offline, consulted before the cache; `None` disables it.
+    pub store: Option<Arc<dyn ResultStore>>,
+    /// Store records older than this are looked up again instead; `None` never ignores them.
+    pub store_max_age: Option<Duration>,
+    /// Answers from the history of the store as it was at this time, without looking anything
+    /// up: NIFs it had no record of by then get `NifStatus::Unknown`. `None` answers from now.
+    pub as_of: Option<SystemTime>,
+    /// Ages at which answers of the cache and the store stop being fresh, then expire, as told
+    /// by `LookupResult::confidence`.
+    pub freshness: FreshnessPolicy,
+    /// Campaign the lookups belong to, e.g. `onboarding-2024-06`, copied to every result.
+    pub tag: Option<String>,
+    /// Categories of NIFs accepted; the others get `NifStatus::WrongCategory` without being
+    /// looked up. Empty accepts every category.
+    pub expect: Vec<NifCategory>,
+    /// How idle connections to nif.pt are kept for reuse.
+    pub pool: PoolOptions,
+    /// Time limits of the requests to nif.pt and the fallback sites.
+    pub timeouts: TimeoutOptions,
+    /// Caps the connections open to nif.pt at once, shared by every lookup made with these
+    /// options; `None` sets no limit.
+    pub connection_limit: Option<Arc<ConnectionLimit>>,
+    /// Spaces the requests to nif.pt, shared by every lookup made with these options; `None`
+    /// sends them as they come.
+    pub throttle: Option<Arc<Throttle>>,
+    /// Orders the lookups waiting for the rate limit and a connection slot by priority, shared
+    /// by every lookup made with these options; `None` lets them wait in any order.
+    pub request_queue: Option<Arc<RequestQueue>>,
+    /// Place of the lookups made with these options in `request_queue`.
+    pub priority: Priority,
+    /// Lock held around every remote request, shared with other processes so that their
+    /// requests go one after the other; `None` takes no lock.
+    pub request_lock: Option<Arc<RequestLock>>,
+    /// Proxy for every request, e.g. `http://proxy.example.pt:3128`; `None` uses the
+    /// `HTTPS_PROXY`/`ALL_PROXY` environment variables when set.
+    pub proxy: Option<String>,
+    /// `User-Agent` header of the requests; `None` sends none.
+    pub user_agent: Option<String>,
+    /// Directory where pages ending in `Unknown` or without parseable entity details are
+    /// saved, to diagnose selector breakage; `None` saves nothing.
+    pub debug_html: Option<PathBuf>,
+    /// Keeps every page fetched from nif.pt, to parse them again later; `None` keeps none.
+    pub page_cache: Option<Arc<PageCache>>,
+    /// Records the HTTP exchanges of the lookups, or answers them from a recording instead of
+    /// the sites; `None` talks to the sites.
+    pub cassette: Option<Arc<Cassette>>,
+    /// Sites asked in order when nif.pt gives no answer (down, rate limiting, open breaker).
+    pub fallbacks: Vec<Fallback>,
+    /// Settings of each site, overriding the general ones for its requests.
+    pub backends: BackendSettings,
+    /// Scores of the fallback sites, shared by every lookup made with these options, putting
+    /// the healthiest first; `None` asks them in the order of `fallbacks`.
+    pub backend_health: Option<Arc<BackendHealth>>,
+    /// Postal localities the entities found are checked against, in `LookupResult::postal_check`;
+    /// `None` checks nothing.
+    pub postal_codes: Option<Arc<PostalTable>>,
+    /// Names expected for some NIFs, compared with the registered ones in
+    /// `LookupResult::name_check`; `None` compares nothing.
+    pub expected_names: Option<Arc<ExpectedNames>>,
+    /// Abandons the lookups made with these options once cancelled, see `CancellationToken`;
+    /// `None` lets them run to the end.
+    pub cancel: Option<CancellationToken>,
+    /// Server giving the coordinates of the entities found, in `LookupResult::location`;
+    /// `None` leaves them out.
+    #[cfg(feature = "geocode")]
+    pub geocoder: Option<Arc<Geocoder>>,
+    /// Exporter receiving one trace span per lookup; `None` disables tracing.
+    #[cfg(feature = "otlp")]
+    pub tracer: Option<Arc<OtlpExporter>>,
+    /// Client built by the first lookup and reused by the next ones (and by clones of these
+    /// options), so connections are pooled. The network options must not change afterwards.
+    client: Arc<OnceLock<Client>>,
+    /// Client of the requests to nif.pt when certificates are pinned, checking the pins
+    /// during the handshake; built and shared like `client`.
+    pinned_client: Arc<OnceLock<Client>>,
+}
+
+/// Settings of the pool of idle connections kept for reuse; `None` keeps reqwest's default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolOptions {
+    pub max_idle_per_host: Option<usize>, // Idle connections kept per host
+    pub idle_timeout: Option<Duration>,   // Idle connections are closed after this long
+}
+
+/// Settings of one backend, from its block of the config file; `None` keeps the general
+/// setting.
+#[derive(Debug, Clone, Default)]
+pub struct BackendOptions {
+    pub base_url: Option<String>,        // Instead of the site's own, e.g. a mirror or a test server
+    pub timeout: Option<Duration>,       // Read timeout of its requests, instead of `TimeoutOptions::read`
+    pub throttle: Option<Arc<Throttle>>, // Own rate limit, on top of `LookupOptions::throttle` for nif.pt
+    pub enabled: Option<bool>,           // `Some(false)` never asks it
+}
+
+impl BackendOptions {
+    fn read_timeout(&self, timeouts: &TimeoutOptions) -> Duration {
+        self.timeout.unwrap_or(timeouts.read_timeout())
+    }
+}
+
+/// Settings of nif.pt and of each fallback site.
+#[derive(Debug, Clone, Default)]
+pub struct BackendSettings {
+    pub nif_pt: BackendOptions,
+    pub racius: BackendOptions,
+    pub einforma: BackendOptions,
+}
+
+impl BackendSettings {
+    /// Settings of a fallback site.
+    pub fn fallback(&self, fallback: Fallback) -> &BackendOptions {
+        match fallback {
+            Fallback::Racius => &self.racius,
+            Fallback::Einforma => &self.einforma,
+        }
+    }
+}
+
+/// Time limits of remote lookups; `None` keeps the defaults below.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimeoutOptions {
+    pub connect: Option<Duration>,  // Opening a connection, TLS handshake included (no limit)
+    pub read: Option<Duration>,     // Waiting for the response, then for its body (30s)
+    pub deadline: Option<Duration>, // Whole lookup, nif.pt and fallback sites together (no limit)
+}
+
+/// Read timeout when none is set, reqwest's own default.
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+impl TimeoutOptions {
+    fn read_timeout(&self) -> Duration {
+        self.read.unwrap_or(DEFAULT_READ_TIMEOUT)
+    }
+
+    /// Longest a request may last: connecting (taken as long as a read when unlimited), then
+    /// waiting for the response and for its body.
+    fn longest_request(&self) -> Duration {
+        self.connect.unwrap_or(self.read_timeout()) + self.read_timeout() * 2
+    }
+}
+
+/// When a lookup must be over, if it has a deadline.
+#[derive(Debug, Clone, Copy)]
+struct Deadline(Option<Instant>);
+
+impl Deadline {
+    fn expired(&self) -> bool {
+        self.0.is_some_and(|at| Instant::now() >= at)
+    }
+
+    /// Timeout of the next request: the read timeout, cut to the time left.
+    fn request_timeout(&self, read: Duration) -> Duration {
+        match self.0 {
+            Some(at) => read.min(at.saturating_duration_since(Instant::now())),
+            None => read,
+        }
+    }
+}
+
+/// Counting semaphore bounding how many requests, hence connections, are in flight.
+#[derive(Debug)]
+pub struct ConnectionLimit {
+    max: usize,
+    open: Mutex<usize>,
+    released: Condvar,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> Self {
+        ConnectionLimit {
+            max,
+            open: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Waits for a free connection slot, held until the permit is dropped.
+    pub fn acquire(&self) -> ConnectionPermit<'_> {
+        let mut open = self.open.lock().unwrap();
+        while *open >= self.max {
+            open = self.released.wait(open).unwrap();
+        }
+        *open += 1;
+        ConnectionPermit { limit: self }
+    }
+}
+
+/// A connection slot of a `ConnectionLimit`, released on drop.
+pub struct ConnectionPermit<'a> {
+    limit: &'a ConnectionLimit,
+}
+
+impl Drop for ConnectionPermit<'_> {
+    fn drop(&mut self) {
+        *self.limit.open.lock().unwrap() -= 1;
+        self.limit.released.notify_one();
+    }
+}
+
+impl LookupOptions {
+    /// Builds a blocking reqwest client honouring these options.
+    pub fn build_client(&self) -> Result<Client, reqwest::Error> {
+        self.build_client_with(|builder| builder)
+    }
+
+    /// Builds a client honouring these options, its builder finished by `finish`.
+    fn build_client_with(&self, finish: impl FnOnce(ClientBuilder) -> ClientBuilder) -> Result<Client, reqwest::Error> {
+        let mut builder = Client::builder();
+        for (host, ip) in &self.resolve {
+            // The port is ignored by reqwest, the one from the URL is used instead
+            builder = builder.resolve(host, SocketAddr::new(*ip, 443));
+        }
+        if let Some(resolver) = &self.dns_resolver {
+            builder = builder.dns_resolver(Arc::new(SharedResolver(resolver.clone())));
+        }
+        for cert in &self.ca_certificates {
+            builder = builder.add_root_certificate(cert.clone());
+        }
+        if let Some(max) = self.pool.max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        if let Some(timeout) = self.pool.idle_timeout {
+            builder = builder.pool_idle_timeout(timeout);
+        }
+        if let Some(timeout) = self.timeouts.connect {
+            builder = builder.connect_timeout(timeout);
+        }
+        builder = builder.timeout(self.timeouts.read_timeout());
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+        if let Some(user_agent) = &self.user_agent {
+            builder = builder.user_agent(user_agent);
+        }
+        builder = builder.danger_accept_invalid_certs(self.accept_invalid_certs);
+        finish(builder).build()
+    }
+
+    /// Builds the shared client now rather than on the first lookup, to report bad network
+    /// options (such as an invalid proxy URL) up front.
+    pub fn init_client(&self) -> Result<(), String> {
+        self.client().map_err(|e| format!("cannot build the HTTP client: {}", e))?;
+        self.nif_pt_client().map(|_| ())
+    }
+
+    /// Returns the shared client, building it on first use.
+    fn client(&self) -> Result<Client, reqwest::Error> {
+        if let Some(client) = self.client.get() {
+            return Ok(client.clone());
+        }
+        let client = self.build_client()?;
+        // Another thread may have won the race, either client will do
+        Ok(self.client.get_or_init(|| client).clone())
+    }
+
+    /// Returns the client of the requests to nif.pt: the shared one, or with pins the one
+    /// checking them, building it on first use.
+    fn nif_pt_client(&self) -> Result<Client, String> {
+        if self.pinned_certificates.is_empty() {
+            return self.client().map_err(|e| e.to_string());
+        }
+        if let Some(client) = self.pinned_client.get() {
+            return Ok(client.clone());
+        }
+        // The CA certificates added to the shared client are not used: TLS is set up here
+        let config = tls::pinned_config(&self.pinned_certificates, self.accept_invalid_certs)?;
+        let client = self.build_client_with(|builder| builder.use_preconfigured_tls(config)).map_err(|e| e.to_string())?;
+        Ok(self.pinned_client.get_or_init(|| client).clone())
+    }
+}
+
+/// Parses a `host:ip` DNS override, as accepted by `--resolve`.
+///
+/// IPv6 addresses may be given bare or in brackets (`nif.pt:[2001:db8::1]`).
+pub fn parse_resolve(spec: &str) -> Result<(String, IpAddr), String> {
+    let (host, ip) = spec
+        .split_once(':')
+        .ok_or_else(|| format!("invalid resolve entry '{}', expected HOST:IP", spec))?;
+    if host.is_empty() {
+        return Err(format!("invalid resolve entry '{}', host is empty", spec));
+    }
+    let ip = ip.trim_start_matches('[').trim_end_matches(']');
+    let ip: IpAddr = ip
+        .parse()
+        .map_err(|_| format!("invalid IP address '{}' in resolve entry '{}'", ip, spec))?;
+    Ok((host.to_string(), ip))
+}
+
+/// Queries nif.pt with a given NIF number and checks for success, error, or multiple results.
+///
+/// Returns:
+/// - `NifStatus::Success` if a valid company is found.
+/// - `NifStatus::Error` if an error message is found.
+/// - `NifStatus::MultipleResults` if multiple companies are listed, NIF unavailable.
+/// - `NifStatus::HttpError(code)` if nif.pt answered with a non-success HTTP status.
+/// - `NifStatus::CircuitOpen` if the circuit breaker refused to make the call.
+/// - `NifStatus::UnsupportedLayout` if the page matches no known nif.pt layout.
+/// - `NifStatus::Unknown` for request/parse errors or unhandled cases.
+///
+/// Lookups share the default client of the process, `NifClient::shared`, and its connections;
+/// build a `NifClient` for any other settings.
+pub fn check_nif_status(nif_number: &str) -> NifStatus {
+    NifClient::shared().status(nif_number)
+}
+
+/// Same as `check_nif_status`, but builds the HTTP client from the given options.
+pub fn check_nif_status_with(nif_number: &str, options: &LookupOptions) -> NifStatus {
+    lookup_nif(nif_number, options).status
+}
+
+/// Where the answer of a lookup came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LookupSource {
+    Store,              // Local store, e.g. an imported registry
+    Cache,              // Earlier answer of nif.pt
+    Remote,             // nif.pt, just now
+    Fallback(Fallback), // Another site, as nif.pt could not answer
+    Local,              // Nobody, the NIF itself was enough (e.g. a category not expected)
+}
+
+impl LookupSource {
+    /// Stable lowercase name, as used in JSON output.
+    pub fn label(&self) -> &'static str {
+        match self {
+            LookupSource::Store => "store",
+            LookupSource::Cache => "cache",
+            LookupSource::Remote => "remote",
+            LookupSource::Fallback(_) => "fallback",
+            LookupSource::Local => "local",
+        }
+    }
+
+    /// Name of the backend, as reported in logs and traces.
+    pub fn backend(&self) -> &'static str {
+        match self {
+            LookupSource::Store => "store",
+            LookupSource::Cache | LookupSource::Remote => "nif.pt",
+            LookupSource::Fallback(fallback) => fallback.name(),
+            LookupSource::Local => "local",
+        }
+    }
+}
+
+/// Answers younger than this are fresh, without a `FreshnessPolicy`.
+pub const DEFAULT_STALE_AFTER: Duration = Duration::from_secs(7 * 86_400);
+
+/// Answers at least this old have expired, without a `FreshnessPolicy`.
+pub const DEFAULT_EXPIRED_AFTER: Duration = Duration::from_secs(90 * 86_400);
+
+/// How much an answer can be trusted, from how long ago nif.pt gave it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Confidence {
+    Fresh,   // Recent enough to be trusted
+    Stale,   // Usable, but worth refreshing
+    Expired, // Too old to be trusted without a refresh
+}
+
+impl Confidence {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Confidence::Fresh => "fresh",
+            Confidence::Stale => "stale",
+            Confidence::Expired => "expired",
+        }
+    }
+}
+
+/// Ages at which an answer goes from fresh to stale, then to expired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreshnessPolicy {
+    pub stale_after: Duration,
+    pub expired_after: Duration,
+}
+
+impl Default for FreshnessPolicy {
+    fn default() -> Self {
+        FreshnessPolicy {
+            stale_after: DEFAULT_STALE_AFTER,
+            expired_after: DEFAULT_EXPIRED_AFTER,
+        }
+    }
+}
+
+impl FreshnessPolicy {
+    /// Confidence in an answer given `checked_at`; a time in the future counts as now.
+    pub fn confidence(&self, checked_at: SystemTime) -> Confidence {
+        let age = checked_at.elapsed().unwrap_or_default();
+        if age >= self.expired_after {
+            Confidence::Expired
+        } else if age >= self.stale_after {
+            Confidence::Stale
+        } else {
+            Confidence::Fresh
+        }
+    }
+}
+
+/// Coordinates of an address, in degrees (WGS 84).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Location {
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::object().with("latitude", self.latitude).with("longitude", self.longitude)
+    }
+}
+
+/// Outcome of a lookup, with the entity details when they are known.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LookupResult {
+    pub nif: String,
+    pub status: NifStatus,
+    pub entity: Option<NifEntity>,
+    /// Companies listed by nif.pt for `NifStatus::MultipleResults`; only known when the page
+    /// was fetched by this lookup, not for answers from the cache or the store.
+    pub candidates: Vec<EntityCandidate>,
+    /// Whether the postal code of the entity goes with its locality, when
+    /// `LookupOptions::postal_codes` is set and the entity has a postal code it covers.
+    pub postal_check: Option<PostalCheck>,
+    /// How the registered name compares with the one expected, when
+    /// `LookupOptions::expected_names` has one for the NIF and the entity is known.
+    pub name_check: Option<NameCheck>,
+    /// Coordinates of the entity address, when `LookupOptions::geocoder` is set and found it.
+    pub location: Option<Location>,
+    /// How much of the nif.pt page the parser could read, for answers parsed by this lookup;
+    /// `None` for those of the cache, the store and the fallback sites.
+    pub parse_confidence: Option<ParseConfidence>,
+    /// When nif.pt (or a fallback site) gave the answer: just now for remote answers, earlier
+    /// for those of the cache and the store. `None` for lookups that got no answer.
+    pub checked_at: Option<SystemTime>,
+    /// Trust in the answer from its age, by `LookupOptions::freshness`; `None` with `checked_at`.
+    pub confidence: Option<Confidence>,
+    pub tag: Option<String>, // As `LookupOptions::tag`
+    pub source: LookupSource,
+    pub report: LookupReport,
+}
+
+/// Where the time of one lookup went, to profile batch runs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LookupReport {
+    pub total: Duration,
+    pub fetch: Duration,       // Waiting for nif.pt and the fallback sites, bodies included
+    pub parse: Duration,       // Parsing their pages
+    pub retries: u32,          // Requests made after the one to nif.pt, to fallback sites
+    pub attempts: u32,         // Lookups made for the result, the retries of `NifClient` included
+    pub backend: &'static str, // As `LookupSource::backend`
+    pub cache_hit: bool,       // Answered by the store or the cache, without a request
+    pub error: Option<String>, // Why nif.pt gave no answer, when it did not
+}
+
+impl LookupReport {
+    /// Serializes the report, durations in milliseconds.
+    pub fn to_json(&self) -> JsonValue {
+        let millis = |duration: Duration| (duration.as_secs_f64() * 1_000_000.0).round() / 1000.0;
+        JsonValue::object()
+            .with("total_ms", millis(self.total))
+            .with("fetch_ms", millis(self.fetch))
+            .with("parse_ms", millis(self.parse))
+            .with("retries", self.retries)
+            .with("attempts", self.attempts)
+            .with("backend", self.backend)
+            .with("cache_hit", self.cache_hit)
+            .with("error", self.error.as_deref())
+    }
+}
+
+impl LookupResult {
+    /// Serializes the result as a JSON object, as returned by the REST server.
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::object()
+            .with("nif", self.nif.as_str())
+            .with("status", self.status.label())
+            .with("http_status", self.status.http_status())
+            .with("valid_locally", is_nif_valid_local(&self.nif))
+            .with("source", self.source.label())
+            .with("entity", self.entity.as_ref().map(NifEntity::to_json))
+            .with("candidates", self.candidates.iter().map(EntityCandidate::to_json).collect::<Vec<_>>())
+            .with("postal_check", self.postal_check.as_ref().map(PostalCheck::to_json))
+            .with("name_check", self.name_check.as_ref().map(NameCheck::to_json))
+            .with("location", self.location.as_ref().map(Location::to_json))
+            .with("parse_confidence", self.parse_confidence.as_ref().map(ParseConfidence::to_json))
+            .with("checked_at", self.checked_at.map(format_rfc3339))
+            .with("confidence", self.confidence.map(|confidence| confidence.label()))
+            .with("tag", self.tag.as_deref())
+            .with("report", self.report.to_json())
+    }
+}
+
+/// Looks a NIF up: in the local store first, then in the cache, and finally on nif.pt
+/// (or its fallbacks). The NIF may be written in the VAT form, the result has the nine digits.
+pub fn lookup_nif(nif_number: &str, options: &LookupOptions) -> LookupResult {
+    let nif_number = normalize_nif(nif_number);
+    let started = Instant::now();
+    #[cfg(feature = "otlp")]
+    let span = options.tracer.as_ref().map(|_| SpanData::start("nif.lookup"));
+    let mut report = LookupReport { attempts: 1, ..LookupReport::default() };
+    let deadline = Deadline(options.timeouts.deadline.map(|deadline| started + deadline));
+
+    let record = match (&options.store, options.as_of) {
+        (Some(store), Some(as_of)) => store.as_of(nif_number, as_of).unwrap_or_else(|e| {
+            report.error = Some(e);
+            None
+        }),
+        (store, _) => store
+            .as_ref()
+            .and_then(|store| store.get(nif_number))
+            .filter(|record| options.store_max_age.is_none_or(|max_age| record.age() < max_age)),
+    };
+    // Only locally valid NIFs have a category, the others are left to nif.pt to reject
+    let unexpected = nif_category(nif_number).filter(|category| !options.expect.is_empty() && !options.expect.contains(category));
+    let mut result = match (unexpected, record) {
+        (Some(category), _) => {
+            logging::info(
+                "wrong_category",
+                &[nif_field(nif_number), ("category", category.label().into())],
+                format!("NIF {} is of category {}, not looked up", display_nif(nif_number), category.label()),
+            );
+            LookupResult {
+                nif: nif_number.to_string(),
+                status: NifStatus::WrongCategory,
+                entity: None,
+                candidates: Vec::new(),
+                postal_check: None,
+                name_check: None,
+                location: None,
+                parse_confidence: None,
+                checked_at: None,
+                confidence: None,
+                tag: None,
+                source: LookupSource::Local,
+                report,
+            }
+        }
+        (None, Some(record)) => {
+            logging::info(
+                "store_hit",
+                &[nif_field(nif_number), ("status", record.status.label().into()), ("source", record.source.as_str().into())],
+                format!("Using local store record for NIF: {} ({})", display_nif(nif_number), record.source),
+            );
+            LookupResult {
+                nif: nif_number.to_string(),
+                status: record.status,
+                entity: record.current_entity().cloned(),
+                candidates: Vec::new(),
+                postal_check: None,
+                name_check: None,
+                location: None,
+                parse_confidence: None,
+                checked_at: Some(record.recorded_at),
+                confidence: None,
+                tag: None,
+                source: LookupSource::Store,
+                report,
+            }
+        }
+        (None, None) if let Some(as_of) = options.as_of => {
+            let date = format_rfc3339(as_of);
+            logging::info(
+                "store_miss_as_of",
+                &[nif_field(nif_number), ("as_of", date.clone().into())],
+                format!("No local store record for NIF {} as of {}", display_nif(nif_number), date),
+            );
+            report.error.get_or_insert(format!("no store record as of {}", date));
+            LookupResult {
+                nif: nif_number.to_string(),
+                status: NifStatus::Unknown,
+                entity: None,
+                candidates: Vec::new(),
+                postal_check: None,
+                name_check: None,
+                location: None,
+                parse_confidence: None,
+                checked_at: None,
+                confidence: None,
+                tag: None,
+                source: LookupSource::Store,
+                report,
+            }
+        }
+        (None, None) => {
+            let mut candidates = Vec::new();
+            let mut confidence = None;
+            let (status, entity, source, checked_at) =
+                cached_query(nif_number, options, &mut report, &mut candidates, &mut confidence, deadline);
+            LookupResult {
+                nif: nif_number.to_string(),
+                status,
+                entity,
+                candidates,
+                postal_check: None,
+                name_check: None,
+                location: None,
+                parse_confidence: confidence,
+                checked_at,
+                confidence: None,
+                tag: None,
+                source,
+                report,
+            }
+        }
+    };
+    result.confidence = result.checked_at.map(|checked_at| options.freshness.confidence(checked_at));
+    result.tag = options.tag.clone();
+    if let Some(entity) = &mut result.entity {
+        options.postal_codes.as_deref().unwrap_or(&EMBEDDED).place(entity);
+    }
+    if let (Some(table), Some(entity)) = (&options.postal_codes, &result.entity) {
+        result.postal_check = table.check(entity);
+        if let Some(check) = result.postal_check.as_ref().filter(|check| **check != PostalCheck::Consistent) {
+            let code = entity.postal_code.as_deref().unwrap_or_default();
+            logging::warn(
+                "postal_code_inconsistent",
+                &[nif_field(nif_number), ("postal_code", code.into()), ("check", check.label().into())],
+                format!("Postal code {} of NIF {} fails the check: {}", code, display_nif(nif_number), check.label()),
+            );
+        }
+    }
+    if let (Some(names), Some(entity)) = (&options.expected_names, &result.entity) {
+        result.name_check = names.check(nif_number, &entity.name);
+        if let Some(check) = result.name_check.as_ref().filter(|check| !check.matches) {
+            logging::warn(
+                "name_mismatch",
+                &[
+                    nif_field(nif_number),
+                    ("expected", check.expected.clone().into()),
+                    ("registered", entity.name.clone().into()),
+                    ("similarity", check.similarity.into()),
+                ],
+                format!(
+                    "NIF {} is registered as {}, not {} (similarity {:.2})",
+                    display_nif(nif_number),
+                    entity.name,
+                    check.expected,
+                    check.similarity
+                ),
+            );
+        }
+    }
+    #[cfg(feature = "geocode")]
+    if let (Some(geocoder), Some(entity)) = (&options.geocoder, &result.entity)
+        && !is_cancelled(options.cancel.as_ref())
+    {
+        let located = options
+            .client()
+            .map_err(request_error)
+            .and_then(|client| geocoder.locate(entity, &client, options.timeouts.read_timeout()));
+        match located {
+            Ok(location) => result.location = location,
+            Err(e) => logging::warn(
+                "geocode_failed",
+                &[nif_field(nif_number), ("geocoder", geocoder.url().into()), ("error", e.clone().into())],
+                format!("Cannot geocode the address of NIF {}: {}", display_nif(nif_number), e),
+            ),
+        }
+    }
+    let status = result.status;
+    // For metrics, anything not answered remotely just now counts as a hit
+    let cache_hit = !matches!(result.source, LookupSource::Remote | LookupSource::Fallback(_) | LookupSource::Local);
+    result.report.total = started.elapsed();
+    result.report.backend = result.source.backend();
+    result.report.cache_hit = cache_hit;
+
+    logging::lookup_summary(nif_number, &status, result.report.total, result.source.backend(), cache_hit);
+    if let Some(statsd) = &options.statsd {
+        statsd.record_lookup(&status, result.report.total, cache_hit);
+    }
+    #[cfg(feature = "otlp")]
+    if let (Some(tracer), Some(mut span)) = (&options.tracer, span) {
+        span.finish();
+        let (name, value) = nif_field(nif_number);
+        span.set(&format!("check_nif.{}", name), AttributeValue::String(value.as_str().unwrap_or_default().to_string()));
+        span.set("check_nif.backend", AttributeValue::String(result.source.backend().to_string()));
+        span.set("check_nif.status", AttributeValue::String(status.label().to_string()));
+        span.set("check_nif.cache_hit", AttributeValue::Bool(cache_hit));
+        span.set("check_nif.retries", AttributeValue::Int(result.report.retries.into()));
+        if let Some(id) = logging::request_id() {
+            span.set("http.request.header.x-request-id", AttributeValue::String(id));
+        }
+        if let Some(code) = status.http_status() {
+            span.set("http.response.status_code", AttributeValue::Int(code as i64));
+        }
+        span.error = status.is_retryable() || matches!(status, NifStatus::Unknown | NifStatus::UnsupportedLayout);
+        tracer.record(span);
+    }
+    result
+}
+
+/// Answers from the cache when possible, otherwise queries nif.pt and remembers the answer.
+///
+/// Returns the status, the entity details, where they came from and, for answers, when
+/// they were given.
+fn cached_query(
+    nif_number: &str,
+    options: &LookupOptions,
+    report: &mut LookupReport,
+    candidates: &mut Vec<EntityCandidate>, // Filled for `NifStatus::MultipleResults`
+    confidence: &mut Option<ParseConfidence>, // Set when a page of nif.pt is parsed
+    deadline: Deadline,
+) -> (NifStatus, Option<NifEntity>, LookupSource, Option<SystemTime>) {
+    if let Some(entry) = options.cache.as_ref().and_then(|cache| cache.get(nif_number)) {
+        logging::info(
+            "cache_hit",
+            &[nif_field(nif_number), ("status", entry.status.label().into())],
+            format!("Using cached result for NIF: {}", display_nif(nif_number)),
+        );
+        return (entry.status, entry.entity, LookupSource::Cache, Some(entry.fetched_at));
+    }
+    let (status, entity, source) = remote_query(nif_number, options, report, candidates, confidence, deadline);
+    if let Some(cache) = &options.cache
+        && is_cacheable(&status, source)
+    {
+        cache.put(nif_number, CacheEntry::now(status, entity.clone()));
+    }
+    let checked_at = status.is_definitive().then(SystemTime::now);
+    (status, entity, source, checked_at)
+}
+
+/// Queries nif.pt, then the fallback sites in order when nif.pt gave no answer.
+///
+/// A fallback only counts when it finds the company; otherwise nif.pt's failure stands.
+fn remote_query(
+    nif_number: &str,
+    options: &LookupOptions,
+    report: &mut LookupReport,
+    candidates: &mut Vec<EntityCandidate>, // Filled for `NifStatus::MultipleResults`
+    confidence: &mut Option<ParseConfidence>, // Set when a page of nif.pt is parsed
+    deadline: Deadline,
+) -> (NifStatus, Option<NifEntity>, LookupSource) {
+    let cancel = options.cancel.as_ref();
+    let (status, entity) = if is_cancelled(cancel) {
+        (NifStatus::Cancelled, None)
+    } else if options.backends.nif_pt.enabled == Some(false) {
+        report.error = Some("nif.pt is disabled".to_string());
+        (NifStatus::Unknown, None) // Only the fallback sites are asked
+    } else {
+        guarded_query(nif_number, options, report, candidates, confidence, deadline)
+    };
+    if status.is_definitive() || status == NifStatus::Cancelled || options.fallbacks.is_empty() {
+        return (status, entity, LookupSource::Remote);
+    }
+    let Ok(client) = options.client() else {
+        return (status, entity, LookupSource::Remote);
+    };
+    let order = match &options.backend_health {
+        Some(health) => health.order(&options.fallbacks),
+        None => options.fallbacks.clone(),
+    };
+    for fallback in order {
+        let backend = options.backends.fallback(fallback);
+        if let Some(throttle) = &backend.throttle
+            && !throttle.wait_or_cancel(cancel)
+        {
+            return (NifStatus::Cancelled, None, LookupSource::Remote);
+        }
+        let _permit = options.connection_limit.as_ref().map(|limit| limit.acquire());
+        let _turn = options.request_lock.as_ref().and_then(|lock| lock.acquire(options.timeouts.longest_request()));
+        if is_cancelled(cancel) {
+            return (NifStatus::Cancelled, None, LookupSource::Remote);
+        }
+        if deadline.expired() {
+            deadline_exceeded(nif_number, fallback.name());
+            break;
+        }
+        report.retries += 1;
+        let timeout = deadline.request_timeout(backend.read_timeout(&options.timeouts));
+        let base_url = backend.base_url.as_deref();
+        let started = Instant::now();
+        let answer = fallback.query(nif_number, base_url, &client, timeout, report, options.cassette.as_deref());
+        if let Some(health) = &options.backend_health {
+            health.record(fallback, answer.is_ok(), started.elapsed());
+        }
+        if let Ok(Some(entity)) = answer {
+            *confidence = None; // Of the page of nif.pt, not of the answer
+            return (NifStatus::ValidKnown, Some(entity), LookupSource::Fallback(fallback));
+        }
+    }
+    (status, entity, LookupSource::Remote)
+}
+
+/// Runs the remote query through the circuit breaker, when there is one.
+fn guarded_query(
+    nif_number: &str,
+    options: &LookupOptions,
+    report: &mut LookupReport,
+    candidates: &mut Vec<EntityCandidate>, // Filled for `NifStatus::MultipleResults`
+    confidence: &mut Option<ParseConfidence>, // Set when a page of nif.pt is parsed
+    deadline: Deadline,
+) -> (NifStatus, Option<NifEntity>) {
+    let Some(breaker) = &options.circuit_breaker else {
+        return query_nif_pt(nif_number, options, report, candidates, confidence, deadline);
+    };
+    if !breaker.allow() {
+        logging::info(
+            "circuit_open_skip",
+            &[nif_field(nif_number)],
+            format!("Circuit breaker open, skipping remote lookup for NIF: {}", display_nif(nif_number)),
+        );
+        report.error = Some("circuit breaker open".to_string());
+        return (NifStatus::CircuitOpen, None);
+    }
+    let (status, entity) = query_nif_pt(nif_number, options, report, candidates, confidence, deadline);
+    // A cancelled lookup tells nothing about the health of nif.pt
+    if status != NifStatus::Cancelled {
+        breaker.record(&status);
+    }
+    (status, entity)
+}
+
+/// Performs the actual request to nif.pt and interprets the page.
+///
+/// The entity details are returned for known entities only.
+fn query_nif_pt(
+    nif_number: &str,
+    options: &LookupOptions,
+    report: &mut LookupReport,
+    candidates: &mut Vec<EntityCandidate>, // Filled for `NifStatus::MultipleResults`
+    confidence: &mut Option<ParseConfidence>, // Set when a page of nif.pt is parsed
+    deadline: Deadline,
+) -> (NifStatus, Option<NifEntity>) {
+    // Construct the URL for the NIF query
+    let backend = &options.backends.nif_pt;
+    let base_url = backend.base_url.as_deref().unwrap_or(NIF_PT_URL);
+    let url = results_url_at(base_url, nif_number);
+    // The URL as logged, with the NIF as `--nif-privacy` shows it
+    let shown_url = results_url_at(base_url, &display_nif(nif_number));
+    logging::info("query", &[nif_field(nif_number)], format!("Querying URL: {}", shown_url));
+
+    // Wait, most urgent lookups first, for the rate limit then a connection slot
+    let turn = options.request_queue.as_ref().map(|queue| queue.enter(options.priority));
+    for throttle in [&options.throttle, &backend.throttle].into_iter().flatten() {
+        if !throttle.wait_or_cancel(options.cancel.as_ref()) {
+            return (NifStatus::Cancelled, None);
+        }
+    }
+    // The connection slot is held until the page is read
+    let _permit = options.connection_limit.as_ref().map(|limit| limit.acquire());
+    drop(turn);
+    let client = match options.nif_pt_client() {
+        Ok(client) => client,
+        Err(e) => {
+            logging::error(
+                "client_build_failed",
+                &[("error", e.clone().into())],
+                format!("Error building HTTP client: {}", e),
+            );
+            report.error = Some(format!("cannot build the HTTP client: {}", e));
+            return (NifStatus::Unknown, None);
+        }
+    };
+
+    // Take turns with the other processes sharing the lock, until the page is read
+    let _turn = options.request_lock.as_ref().and_then(|lock| lock.acquire(options.timeouts.longest_request()));
+
+    if is_cancelled(options.cancel.as_ref()) {
+        return (NifStatus::Cancelled, None);
+    }
+    // The waits for a connection slot and the lock may have eaten the time of the lookup
+    if deadline.expired() {
+        deadline_exceeded(nif_number, "nif.pt");
+        report.error = Some("lookup deadline exceeded".to_string());
+        return (NifStatus::Unknown, None);
+    }
+    let fetch_started = Instant::now();
+    let timeout = deadline.request_timeout(backend.read_timeout(&options.timeouts));
+    let body = fetch_page(&client, &url, &shown_url, timeout, nif_number, options);
+    report.fetch += fetch_started.elapsed();
+    let body = match body {
+        Ok(body) => body,
+        Err((status, error)) => {
+            report.error = Some(error);
+            return (status, None);
+        }
+    };
+    if let Some(pages) = &options.page_cache
+        && let Err(e) = pages.put(&url, &body)
+    {
+        let text = format!("Cannot keep the page of NIF {}: {}", display_nif(nif_number), e);
+        logging::warn("page_cache_failed", &[nif_field(nif_number), ("error", e.into())], text);
+    }
+
+    let parse_started = Instant::now();
+    let (status, entity, parsed) = parse_page_confidence(&body, nif_number);
+    if parsed.needs_review() && status.is_definitive() {
+        logging::warn(
+            "parse_low_confidence",
+            &[nif_field(nif_number), ("score", parsed.score().into()), ("missing", parsed.missing.join(",").into())],
+            format!("The page of NIF {} was read with low confidence, missing: {}", display_nif(nif_number), parsed.missing.join(", ")),
+        );
+    }
+    *confidence = Some(parsed);
+    if status == NifStatus::MultipleResults {
+        *candidates = parse_candidates(&body);
+    }
+    report.parse += parse_started.elapsed();
+    // Keep the pages the selectors could not make sense of, to see what changed on the site
+    let parse_failed = matches!(status, NifStatus::Unknown | NifStatus::UnsupportedLayout)
+        || (status == NifStatus::ValidKnown && entity.is_none());
+    if parse_failed {
+        report.error = Some(match status {
+            NifStatus::UnsupportedLayout => "unsupported page layout".to_string(),
+            _ => "the page could not be read".to_string(),
+        });
+        if let Some(dir) = &options.debug_html {
+            save_debug_html(dir, nif_number, &body);
+        }
+    }
+    (status, entity)
+}
+
+/// Fetches the results page of nif.pt at `url`, logged as `shown_url`; on failure, returns
+/// the status to report and why.
+fn fetch_page(
+    client: &Client,
+    url: &str,
+    shown_url: &str,
+    timeout: Duration,
+    nif_number: &str,
+    options: &LookupOptions,
+) -> Result<String, (NifStatus, String)> {
+    if let Some(cassette) = options.cassette.as_ref().filter(|cassette| cassette.mode() == CassetteMode::Replay) {
+        let Some(interaction) = cassette.play("GET", url) else {
+            logging::error(
+                "cassette_miss",
+                &[nif_field(nif_number)],
+                format!("No recorded answer for {} in {}", shown_url, cassette.path().display()),
+            );
+            return Err((NifStatus::Unknown, format!("no recorded answer in {}", cassette.path().display())));
+        };
+        return match http_error(nif_number, interaction.status) {
+            Some(status) => Err((status, status_text(interaction.status))),
+            None => Ok(interaction.body),
+        };
+    }
+
+    // Make the GET request to the constructed URL
+    let response = match client.get(url).timeout(timeout).send() {
+        Ok(resp) => resp,
+        // The pins are checked during the handshake, so nothing was sent to a mismatching server
+        Err(ref e) if let Some(mismatch) = tls::pin_mismatch(e) => {
+            let error = mismatch.to_string();
+            logging::error(
+                "pin_mismatch",
+                &[nif_field(nif_number), ("error", error.clone().into())],
+                format!("TLS pinning failed for {}: {}", shown_url, error),
+            );
+            return Err((NifStatus::Unknown, error));
+        }
+        Err(e) => {
+            // The URL holds the NIF, keep it out of the logs unless NIFs are logged in clear
+            let error = request_error(e);
+            let text = format!("Error making request to {}: {}", shown_url, error);
+            logging::error("request_failed", &[nif_field(nif_number), ("error", error.clone().into())], text);
+            return Err((NifStatus::Unknown, error));
+        }
+    };
+
+    // Check if the request was successful
+    let code = response.status().as_u16();
+    if let Some(status) = http_error(nif_number, code) {
+        // Keep every lookup sharing the rate limit away for as long as nif.pt asks
+        let retry_after = response.headers().get(RETRY_AFTER).and_then(|value| value.to_str().ok()).and_then(retry_after);
+        if matches!(code, 429 | 503)
+            && let Some(until) = retry_after
+        {
+            for throttle in [&options.throttle, &options.backends.nif_pt.throttle].into_iter().flatten() {
+                throttle.hold_until(until);
+            }
+        }
+        record_exchange(options.cassette.as_deref(), nif_number, url, code, &response.text().unwrap_or_default());
+        return Err((status, status_text(code)));
+    }
+
+    // Read the response body as text
+    let body = response.text().map_err(|e| {
+        let error = request_error(e);
+        let text = format!("Error reading response body: {}", error);
+        logging::error("body_read_failed", &[nif_field(nif_number), ("error", error.clone().into())], text);
+        (NifStatus::Unknown, error)
+    })?;
+    record_exchange(options.cassette.as_deref(), nif_number, url, code, &body);
+    Ok(body)
+}
+
+/// Logs an answer of nif.pt with the HTTP status `code` when it is a failure, and returns
+/// the status of the lookup then.
+fn http_error(nif_number: &str, code: u16) -> Option<NifStatus> {
+    let status = response_status(code)?;
+    logging::error(
+        "http_error",
+        &[nif_field(nif_number), ("http_status", code.into())],
+        format!("Request failed with status: {}", status_text(code)),
+    );
+    Some(status)
+}
+
+/// HTTP status with its reason, e.g. `429 Too Many Requests`.
+pub(crate) fn status_text(code: u16) -> String {
+    match reqwest::StatusCode::from_u16(code) {
+        Ok(status) => status.to_string(),
+        Err(_) => code.to_string(),
+    }
+}
+
+/// Appends an exchange to the cassette, when one is being recorded; a failure is only logged.
+pub(crate) fn record_exchange(cassette: Option<&Cassette>, nif_number: &str, url: &str, status: u16, body: &str) {
+    let Some(cassette) = cassette.filter(|cassette| cassette.mode() == CassetteMode::Record) else {
+        return;
+    };
+    let interaction = Interaction {
+        method: "GET".to_string(),
+        url: url.to_string(),
+        status,
+        body: body.to_string(),
+    };
+    if let Err(e) = cassette.add(&interaction) {
+        let text = format!("Cannot record the answer for NIF {}: {}", display_nif(nif_number), e);
+        logging::warn("cassette_write_failed", &[nif_field(nif_number), ("error", e.into())], text);
+    }
+}
+
+/// Describes a failed request without its URL, which holds the NIF; timeouts say so, as
+/// reqwest's message for them is only "error sending request".
+pub(crate) fn request_error(e: reqwest::Error) -> String {
+    if e.is_timeout() {
+        "timed out".to_string()
+    } else {
+        e.without_url().to_string()
+    }
+}
+
+/// Logs that a lookup ran out of time before asking `backend`.
+fn deadline_exceeded(nif_number: &str, backend: &str) {
+    logging::warn(
+        "deadline_exceeded",
+        &[nif_field(nif_number), ("backend", backend.into())],
+        format!("Lookup deadline exceeded for NIF {}, {} not asked", display_nif(nif_number), backend),
+    );
+}
+
+/// Writes `body` to `<dir>/<nif>-<unix time>.html`; a failure is only logged.
+fn save_debug_html(dir: &Path, nif_number: &str, body: &str) {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let path = dir.join(format!("{}-{}.html", nif_number, secs));
+    let saved = std::fs::create_dir_all(dir).and_then(|_| std::fs::write(&path, body));
+    match saved {
+        Ok(()) => logging::info(
+            "debug_html_saved",
+            &[nif_field(nif_number), ("path", path.display().to_string().into())],
+            format!("Saved the page to {}", path.display()),
+        ),
+        Err(e) => logging::warn(
+            "debug_html_failed",
+            &[nif_field(nif_number), ("error", e.to_string().into())],
+            format!("Cannot save the page to {}: {}", path.display(), e),
+        ),
+    }
+}
@@ -1,138 +1,56 @@
 // main.rs
 
-use reqwest::blocking::Client; // For making synchronous HTTP requests
-use scraper::{Html, Selector}; // For parsing HTML
+mod cli;
+mod commands;
 
-/// Represents the possible outcomes of a NIF query.
-#[derive(Debug)]
-pub enum NifStatus {
-    ValidKnown,      // Valid NIF and known entity
-    ValidUnknown,    // Valid NIF but unknown entity
-    Error,           // Error message found (invalid NIF)
-    MultipleResults, // Multiple companies, NIF not available [Only seen with "000000000"]
-    Unknown,         // Could not determine status
-}
-
-/// Queries nif.pt with a given NIF number and checks for success, error, or multiple results.
-///
-/// Returns:
-/// - `NifStatus::Success` if a valid company is found.
-/// - `NifStatus::Error` if an error message is found.
-/// - `NifStatus::MultipleResults` if multiple companies are listed, NIF unavailable.
-/// - `NifStatus::Unknown` for request/parse errors or unhandled cases.
-pub fn check_nif_status(nif_number: &str) -> NifStatus {
-    // Construct the URL for the NIF query
-    let url = format!("https://www.nif.pt/?q={}", nif_number);
-    println!("Querying URL: {}", url);
-
-    // Create a new HTTP client
-    let client = Client::new();
-
-    // Make the GET request to the constructed URL
-    let response = match client.get(&url).send() {
-        Ok(resp) => resp,
-        Err(e) => {
-            eprintln!("Error making request to {}: {}", url, e);
-            return NifStatus::Unknown;
-        }
-    };
-
-    // Check if the request was successful
-    if !response.status().is_success() {
-        eprintln!("Request failed with status: {}", response.status());
-        return NifStatus::Unknown;
-    }
-
-    // Read the response body as text
-    let body = match response.text() {
-        Ok(text) => text,
-        Err(e) => {
-            eprintln!("Error reading response body: {}", e);
-            return NifStatus::Unknown;
-        }
-    };
-
-    // Parse the HTML document
-    let document = Html::parse_document(&body);
-
-    // Error message selector
-    let error_selector = Selector::parse(".alert-message.error.block-message").unwrap();
-    if document.select(&error_selector).next().is_some() {
-        println!("Found error message for NIF: {}", nif_number);
-        return NifStatus::Error;
-    }
-
-    // Success message selector
-    let success_selector = Selector::parse(".alert-message.success.block-message").unwrap();
-    if let Some(success_div) = document.select(&success_selector).next() {
-        let text = success_div.text().collect::<String>();
-        if text.contains("O NIF indicado é válido mas não conseguimos determinar a entidade associada.") {
-            println!("NIF is valid but entity is unknown: {}", nif_number);
-            return NifStatus::ValidUnknown;
-        } else {
-            println!("Found success message for NIF: {}", nif_number);
-            // Continue to check for known entity below
-        }
-    }
-
-    // Multiple results: look for #search-results
-    let search_results_selector = Selector::parse("#search-results").unwrap();
-    if let Some(search_results) = document.select(&search_results_selector).next() {
-        let company_selector = Selector::parse(".search-title").unwrap();
-        if search_results.select(&company_selector).next().is_some() {
-            println!("Found multiple companies for NIF: {}", nif_number);
-            return NifStatus::MultipleResults;
-        }
-    }
+use std::path::Path;
+use std::time::SystemTime;
 
-    // Valid and known entity: look for .big-nif and .search-title
-    let big_nif_selector = Selector::parse(".big-nif").unwrap();
-    let company_selector = Selector::parse(".search-title").unwrap();
-    if document.select(&big_nif_selector).next().is_some() &&
-       document.select(&company_selector).next().is_some() {
-        println!("Found known entity for NIF: {}", nif_number);
-        return NifStatus::ValidKnown;
-    }
+use check_nif::hooks::{ChangedWriter, HookWriter, StoreWriter};
+use check_nif::input::write_nif_list;
+use check_nif::interrupt;
+use check_nif::lang::{lang, Lang};
+use check_nif::mail::{self, Attachment, Message, SmtpConfig};
+use check_nif::report::{render_csv, render_duplicates, render_failures, render_text_summary, BatchReport, ReportFormat};
+use check_nif::output::{status_line, OutputWriter};
+use check_nif::{check_nif_status, is_nif_valid_local, lookup_nif, NifStatus};
 
-    // If none of the above, check if the page says "NIF não encontrado" or similar
-    println!("Could not determine status for NIF: {}", nif_number);
-    NifStatus::Unknown
+/// Prints a human-readable line describing the status of a NIF query.
+fn print_status(nif: &str, status: &NifStatus) {
+    println!("{}", status_line(nif, status));
 }
 
-/// Validates a Portuguese NIF using only the mathematical algorithm (no external lookup).
-pub fn is_nif_valid_local(nif: &str) -> bool {
-    // Checks if it has 9 digits
-    if nif.len() != 9 || !nif.chars().all(|c| c.is_digit(10)) {
-        return false;
-    }
-
-    // Checks if the first digit is allowed
-    let first = &nif[0..1];
-    let first_two = &nif[0..2];
-    let valid_first = matches!(
-        first,
-        "1" | "2" | "3" | "5" | "6" | "7" | "8" | "9"
-    ) || first_two == "45";
-    if !valid_first {
-        return false;
+/// Emails the summary of a run, attaching the report file and, if asked, every result as CSV.
+fn email_report(config: &SmtpConfig, report: &BatchReport, report_path: Option<&str>, csv: bool) -> Result<(), String> {
+    let mut message = Message {
+        subject: match lang() {
+            Lang::En => format!("NIF check: {} checked, {} failed", report.results.len(), report.failures().len()),
+            Lang::Pt => format!("Verificação de NIFs: {} verificados, {} falhados", report.results.len(), report.failures().len()),
+        },
+        body: render_text_summary(report),
+        attachments: Vec::new(),
+    };
+    if let Some(path) = report_path {
+        let content_type = match ReportFormat::for_path(path) {
+            ReportFormat::Html => "text/html; charset=utf-8",
+            ReportFormat::Markdown => "text/markdown; charset=utf-8",
+        };
+        message.attachments.push(Attachment {
+            filename: Path::new(path).file_name().unwrap_or_default().to_string_lossy().into_owned(),
+            content_type: content_type.to_string(),
+            data: std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))?,
+        });
     }
-
-    // Extracts the digits
-    let digits: Vec<u32> = nif.chars().map(|c| c.to_digit(10).unwrap()).collect();
-
-    // Calculates the check digit
-    let mut sum = 0;
-    for (i, d) in digits.iter().take(8).enumerate() {
-        sum += d * (9 - i as u32);
+    if csv {
+        message.attachments.push(Attachment {
+            filename: "results.csv".to_string(),
+            content_type: "text/csv; charset=utf-8".to_string(),
+            data: render_csv(report).into_bytes(),
+        });
     }
-    let resto = sum % 11;
-    let check_digit = if resto == 0 || resto == 1 { 0 } else { 11 - resto };
-
-    // Compares with the 9th digit
-    check_digit == digits[8]
+    mail::send(config, &message)
 }
 
-
 /*
     Test on your own with known NIFs or random numbers
     The relevant code is above
@@ -148,13 +66,7 @@ fn main() {
 
         for nif in &[nif_to_check_success, nif_to_check_error, nif_to_check_multiple] {
             println!("\n--- Checking NIF: {} ---", nif);
-            match check_nif_status(nif) {   
-                NifStatus::ValidKnown => println!("NIF {} status: Valid and known entity.", nif),
-                NifStatus::ValidUnknown => println!("NIF {} status: Valid but unknown entity.", nif),
-                NifStatus::Error => println!("NIF {} status: Invalid (Error message).", nif),
-                NifStatus::MultipleResults => println!("NIF {} status: Multiple companies found, NIF unavailable.", nif),
-                NifStatus::Unknown => println!("NIF {} status: Unknown or could not determine.", nif),
-            }
+            print_status(nif, &check_nif_status(nif));
         }
 
         // Example of local validation (no external lookup)
@@ -174,21 +86,206 @@ fn main() {
     } else {
         // Command line argument mode
         let args: Vec<String> = std::env::args().collect();
-        if args.len() < 2 {
-            eprintln!("Usage: {} <NIF_NUMBER>", args[0]);
+        if let Some(command) = args.get(1).and_then(|name| cli::find_command(name)) {
+            std::process::exit(commands::run(command, &args[0], &args[2..]));
+        }
+        if cli::wants_help(&args[1..]) {
+            print!("{}", cli::usage(&args[0]));
             return;
         }
-        let nif_from_args = &args[1];
-        println!("\n--- Checking NIF from arguments: {} ---", nif_from_args);
-        match check_nif_status(nif_from_args) {
-            NifStatus::ValidKnown => println!("NIF {} status: Valid and known entity.", nif_from_args),
-            NifStatus::ValidUnknown => println!("NIF {} status: Valid but unknown entity.", nif_from_args),
-            NifStatus::Error => println!("NIF {} status: Invalid (Error message).", nif_from_args),
-            NifStatus::MultipleResults => println!("NIF {} status: Multiple companies found, NIF unavailable.", nif_from_args),
-            NifStatus::Unknown => println!("NIF {} status: Unknown or could not determine.", nif_from_args),
-        }
-        // Local validation for argument
-        let valido = is_nif_valid_local(nif_from_args);
-        println!("NIF {} is {} (local)", nif_from_args, if valido { "valid" } else { "invalid" });
+        let mut parsed = match cli::parse_args(&args[1..], cli::LOOKUP_OPTIONS) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                eprintln!("{}\n\n{}", e, cli::usage(&args[0]));
+                std::process::exit(2);
+            }
+        };
+        if let Err(e) = cli::apply_profile(&mut parsed, cli::LOOKUP_OPTIONS).and_then(|_| cli::apply_log_format(&parsed)) {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+        let nifs = match cli::batch_nifs(&parsed) {
+            Ok(nifs) => nifs,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            }
+        };
+        if nifs.is_empty() {
+            eprint!("{}", cli::usage(&args[0]));
+            return;
+        }
+        let mut options = match cli::lookup_options(&parsed) {
+            Ok(options) => options,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            }
+        };
+        let mut writers = match cli::output_writer(&parsed) {
+            Ok(writer) => vec![writer],
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            }
+        };
+        let hooks = match cli::hooks(&parsed) {
+            Ok(hooks) => hooks,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            }
+        };
+        let changed_only = parsed.flag("changed-only");
+        if changed_only && options.store.is_none() {
+            eprintln!("--changed-only needs a store to compare with, it cannot go with --no-store");
+            std::process::exit(2);
+        }
+        if changed_only && options.as_of.is_some() {
+            eprintln!("--changed-only compares fresh lookups with the store, it cannot go with --as-of");
+            std::process::exit(2);
+        }
+        // Changes are told by fresh lookups compared with the store, which then records them
+        let compared_store = options
+            .store
+            .clone()
+            .filter(|_| (changed_only || hooks.tracks_changes()) && options.as_of.is_none());
+        if compared_store.is_some() {
+            options.store = None;
+        }
+        if let Some(store) = compared_store.clone().filter(|_| changed_only) {
+            let writer = writers.remove(0);
+            writers.push(Box::new(ChangedWriter::new(writer, store)));
+        }
+        if !hooks.is_empty() {
+            let store = compared_store.clone().or_else(|| options.store.clone());
+            writers.push(Box::new(HookWriter::new(hooks, store)));
+        }
+        if let Some(store) = compared_store {
+            writers.push(Box::new(StoreWriter::new(store)));
+        }
+        // Counts the lookups, also those whose result a WASM plugin drops
+        let mut progress = match cli::progress_writer(&parsed, nifs.len()) {
+            Ok(progress) => progress,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            }
+        };
+        #[cfg(feature = "wasm")]
+        let mut wasm_hooks = match cli::wasm_hooks(&parsed) {
+            Ok(wasm_hooks) => wasm_hooks,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            }
+        };
+        #[cfg(not(feature = "wasm"))]
+        if let Some(path) = parsed.value("wasm-hook") {
+            eprintln!("cannot load {}: built without the wasm feature", path);
+            std::process::exit(2);
+        }
+        let email = match cli::email_config(&parsed) {
+            Ok(email) => email,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            }
+        };
+        interrupt::install();
+        let started_at = SystemTime::now();
+        let mut results = Vec::with_capacity(nifs.len());
+        let mut checked = 0;
+        for nif in &nifs {
+            if interrupt::interrupted().is_some() {
+                break;
+            }
+            let written = writers.iter_mut().try_for_each(|writer| writer.before_lookup(nif)).and_then(|_| {
+                let result = lookup_nif(nif, &options);
+                if let Some(progress) = &mut progress {
+                    progress.write(&result)?;
+                }
+                #[cfg(feature = "wasm")]
+                let Some(result) = check_nif::wasm::apply(&mut wasm_hooks, result) else {
+                    return Ok(());
+                };
+                writers.iter_mut().try_for_each(|writer| writer.write(&result))?;
+                results.push(result);
+                Ok(())
+            });
+            if let Err(e) = written {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+            checked += 1;
+        }
+        let finished = writers
+            .iter_mut()
+            .try_for_each(|writer| writer.finish())
+            .and_then(|_| progress.as_mut().map_or(Ok(()), |progress| progress.finish()));
+        if let Err(e) = finished {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        let report = BatchReport {
+            results,
+            started_at,
+            finished_at: SystemTime::now(),
+        };
+        // Also for an interrupted run, whose failures the checkpoint does not hold
+        if let Some(path) = parsed.value("failures") {
+            if let Err(e) = std::fs::write(path, render_failures(&report)) {
+                eprintln!("Error: cannot write {}: {}", path, e);
+                std::process::exit(1);
+            }
+            eprintln!("Wrote the failed lookups to {}", path);
+        }
+        if let Some(path) = parsed.value("duplicates") {
+            if let Err(e) = std::fs::write(path, render_duplicates(&report)) {
+                eprintln!("Error: cannot write {}: {}", path, e);
+                std::process::exit(1);
+            }
+            eprintln!("Wrote {} groups of probable duplicates to {}", report.duplicates().len(), path);
+        }
+        if let Some(signal) = interrupt::interrupted() {
+            // Only a complete run gets a report and an email
+            let left = &nifs[checked..];
+            let path = parsed.value("checkpoint").unwrap_or(cli::DEFAULT_CHECKPOINT);
+            let comment = format!(
+                "check_nif checkpoint: {} of {} NIFs checked before {}",
+                checked,
+                nifs.len(),
+                interrupt::signal_name(signal)
+            );
+            if let Err(e) = write_nif_list(path, left, &comment) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+            eprintln!(
+                "Interrupted after {} of {} NIFs, the {} left are saved in {}.\n\
+                 To resume, run the same command with --input {} instead of the NIFs already given.",
+                checked,
+                nifs.len(),
+                left.len(),
+                path,
+                path
+            );
+            std::process::exit(128 + signal);
+        }
+        let report_path = parsed.value("report");
+        if let Some(path) = report_path {
+            if let Err(e) = std::fs::write(path, ReportFormat::for_path(path).render(&report)) {
+                eprintln!("Error: cannot write {}: {}", path, e);
+                std::process::exit(1);
+            }
+            eprintln!("Wrote report {}", path);
+        }
+        if let Some(config) = email {
+            if let Err(e) = email_report(&config, &report, report_path, parsed.flag("email-csv")) {
+                eprintln!("Error: cannot email the report: {}", e);
+                std::process::exit(1);
+            }
+            eprintln!("Emailed the report to {}", config.to.join(", "));
+        }
     }
 }
@@ -0,0 +1,139 @@
+// msgpack.rs
+
+//! MessagePack encoding of JSON values, for `--format msgpack`. A result is encoded from its
+//! `to_json` form, so both outputs always carry the same maps under the same keys.
+
+use crate::json::JsonValue;
+
+/// Encodes a value as one MessagePack object, with the smallest representation of each part.
+pub fn encode(value: &JsonValue) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_value(&mut out, value);
+    out
+}
+
+fn write_value(out: &mut Vec<u8>, value: &JsonValue) {
+    match value {
+        JsonValue::Null => out.push(0xc0),
+        JsonValue::Bool(value) => out.push(if *value { 0xc3 } else { 0xc2 }),
+        JsonValue::Int(value) => write_int(out, *value),
+        JsonValue::Float(value) => {
+            out.push(0xcb);
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+        JsonValue::String(value) => write_str(out, value),
+        JsonValue::Array(items) => {
+            write_length(out, items.len(), 0x90, 0xdc);
+            for item in items {
+                write_value(out, item);
+            }
+        }
+        JsonValue::Object(fields) => {
+            write_length(out, fields.len(), 0x80, 0xde);
+            for (key, value) in fields {
+                write_str(out, key);
+                write_value(out, value);
+            }
+        }
+    }
+}
+
+fn write_int(out: &mut Vec<u8>, value: i64) {
+    match value {
+        0..=0x7f => out.push(value as u8), // Positive fixint
+        -32..=-1 => out.push(value as i8 as u8), // Negative fixint
+        0x80..=0xff => out.extend_from_slice(&[0xcc, value as u8]),
+        0x100..=0xffff => {
+            out.push(0xcd);
+            out.extend_from_slice(&(value as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(0xce);
+            out.extend_from_slice(&(value as u32).to_be_bytes());
+        }
+        -0x80..=-33 => out.extend_from_slice(&[0xd0, value as i8 as u8]),
+        -0x8000..=-0x81 => {
+            out.push(0xd1);
+            out.extend_from_slice(&(value as i16).to_be_bytes());
+        }
+        -0x8000_0000..=-0x8001 => {
+            out.push(0xd2);
+            out.extend_from_slice(&(value as i32).to_be_bytes());
+        }
+        _ if value > 0 => {
+            out.push(0xcf);
+            out.extend_from_slice(&(value as u64).to_be_bytes());
+        }
+        _ => {
+            out.push(0xd3);
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+}
+
+fn write_str(out: &mut Vec<u8>, value: &str) {
+    let len = value.len();
+    match len {
+        0..=31 => out.push(0xa0 | len as u8), // Fixstr
+        32..=0xff => out.extend_from_slice(&[0xd9, len as u8]),
+        0x100..=0xffff => {
+            out.push(0xda);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        _ => {
+            out.push(0xdb);
+            out.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(value.as_bytes());
+}
+
+/// Header of an array or map of `len` items: the fix form up to 15 items, `wide` (16-bit
+/// length) above, and the 32-bit form after it beyond that.
+fn write_length(out: &mut Vec<u8>, len: usize, fix: u8, wide: u8) {
+    if len <= 15 {
+        out.push(fix | len as u8);
+    } else if len <= 0xffff {
+        out.push(wide);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        out.push(wide + 1);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ints_take_the_smallest_form() {
+        for (value, bytes) in [
+            (0, &[0x00][..]),
+            (127, &[0x7F]),
+            (128, &[0xCC, 0x80]),
+            (256, &[0xCD, 0x01, 0x00]),
+            (65536, &[0xCE, 0x00, 0x01, 0x00, 0x00]),
+            (1 << 32, &[0xCF, 0, 0, 0, 1, 0, 0, 0, 0]),
+            (-1, &[0xFF]),
+            (-32, &[0xE0]),
+            (-33, &[0xD0, 0xDF]),
+            (-129, &[0xD1, 0xFF, 0x7F]),
+            (-32769, &[0xD2, 0xFF, 0xFF, 0x7F, 0xFF]),
+            (i64::MIN, &[0xD3, 0x80, 0, 0, 0, 0, 0, 0, 0]),
+        ] {
+            assert_eq!(encode(&JsonValue::Int(value)), bytes, "{}", value);
+        }
+    }
+
+    #[test]
+    fn strings_and_containers() {
+        let value = JsonValue::object().with("nif", "500960046").with("ok", true).with("tag", JsonValue::Null);
+        assert_eq!(encode(&value), [&[0x83, 0xA3][..], b"nif", &[0xA9], b"500960046", &[0xA2], b"ok", &[0xC3, 0xA3], b"tag", &[0xC0]].concat());
+        assert_eq!(encode(&JsonValue::Float(1.5)), [&[0xCB][..], &1.5f64.to_be_bytes()].concat());
+        assert_eq!(encode(&JsonValue::from("x".repeat(32)))[..2], [0xD9, 32]);
+        assert_eq!(encode(&JsonValue::from("x".repeat(256)))[..3], [0xDA, 0x01, 0x00]);
+        assert_eq!(encode(&JsonValue::Array(vec![JsonValue::Null; 16]))[..3], [0xDC, 0x00, 0x10]);
+        assert_eq!(encode(&JsonValue::Array(vec![JsonValue::Null; 70000]))[..5], [0xDD, 0x00, 0x01, 0x11, 0x70]);
+    }
+}
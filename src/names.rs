@@ -0,0 +1,255 @@
+// names.rs
+
+//! Comparison of company names as other systems have them (an ERP, a supplier file, an
+//! invoice) with the names registered on nif.pt, to catch NIFs typed against the wrong
+//! company. The normalization behind it (`normalize_name`, `strip_accents`) is public, so
+//! applications comparing names themselves get the same answers as the checks.
+
+use std::collections::HashMap;
+
+use crate::json::JsonValue;
+
+/// Similarity below which an expected name is flagged as a mismatch.
+pub const DEFAULT_MIN_SIMILARITY: f64 = 0.8;
+
+/// Words of company names that say nothing about which company it is: legal forms, once
+/// their dots are dropped (`S.A.` and `S. A.` are both `sa`, see `name_words`), and
+/// connectives.
+const LEGAL_FORMS: &[&str] = &[
+    "lda", "limitada", "sa", "unipessoal", "sociedade", "anonima", "sgps", "crl", "e", "de", "da", "do", "das", "dos",
+];
+
+/// Replaces accented letters by their plain form, keeping the case: `Sá Cônsul` →
+/// `Sa Consul`. Other characters are kept as they are.
+pub fn strip_accents(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            'á' | 'à' | 'â' | 'ã' | 'ä' => 'a',
+            'Á' | 'À' | 'Â' | 'Ã' | 'Ä' => 'A',
+            'é' | 'è' | 'ê' | 'ë' => 'e',
+            'É' | 'È' | 'Ê' | 'Ë' => 'E',
+            'í' | 'ì' | 'î' | 'ï' => 'i',
+            'Í' | 'Ì' | 'Î' | 'Ï' => 'I',
+            'ó' | 'ò' | 'ô' | 'õ' | 'ö' => 'o',
+            'Ó' | 'Ò' | 'Ô' | 'Õ' | 'Ö' => 'O',
+            'ú' | 'ù' | 'û' | 'ü' => 'u',
+            'Ú' | 'Ù' | 'Û' | 'Ü' => 'U',
+            'ç' => 'c',
+            'Ç' => 'C',
+            'ñ' => 'n',
+            'Ñ' => 'N',
+            c => c,
+        })
+        .collect()
+}
+
+/// Tells whether `word`, lowercased and without accents or dots, is a legal form or a
+/// connective, ignored when comparing names (`lda`, `sa`, `unipessoal`, `de`...).
+pub fn is_legal_form(word: &str) -> bool {
+    LEGAL_FORMS.contains(&word)
+}
+
+/// Words of a company name, lowercased, without accents, punctuation and legal forms:
+/// `Papelaria Central de Lisboa, Lda.` gives `papelaria`, `central` and `lisboa`.
+///
+/// Dots within a word are dropped (`S.A.` is `sa`), and single letters written with dots
+/// make one word (`S. A.` is `sa` too). A single letter without a dot stays a word of its
+/// own: the `A` of `A Padaria` is part of the name.
+pub fn name_words(name: &str) -> Vec<String> {
+    let plain: Vec<char> = strip_accents(name).to_lowercase().chars().chain([' ']).collect();
+    let mut words: Vec<String> = Vec::new();
+    let mut word = String::new();
+    let mut initials = false; // The last word is a letter written with a dot, the next letter joins it
+    for (i, &c) in plain.iter().enumerate() {
+        if c.is_ascii_alphanumeric() {
+            word.push(c);
+        } else if c == '.' && plain.get(i + 1).is_some_and(char::is_ascii_alphanumeric) {
+            // A dot within a word
+        } else if !word.is_empty() {
+            let letter = word.len() == 1;
+            match words.last_mut() {
+                Some(last) if letter && initials => last.push_str(&word),
+                _ => words.push(word.clone()),
+            }
+            word.clear();
+            initials = letter && c == '.';
+        }
+    }
+    words.retain(|word| !is_legal_form(word));
+    words
+}
+
+/// The form in which company names are compared: `name_words` separated by single spaces.
+/// Two names with the same form are taken for the same name, e.g. `Exemplo, Lda.` and
+/// `EXEMPLO LIMITADA` are both `exemplo`.
+pub fn normalize_name(name: &str) -> String {
+    name_words(name).join(" ")
+}
+
+/// Tells whether a name given by someone (on an invoice, in a supplier file) is the
+/// registered name of the company.
+///
+/// The names are compared in their `normalize_name` form, and one may leave out words of
+/// the other: `Papelaria Central` matches `PAPELARIA CENTRAL DE LISBOA, LDA`.
+pub fn names_match(given_name: &str, registered_name: &str) -> bool {
+    let given = name_words(given_name);
+    let registered = name_words(registered_name);
+    if given.is_empty() || registered.is_empty() {
+        return given == registered;
+    }
+    let (shorter, longer) = if given.len() <= registered.len() { (&given, &registered) } else { (&registered, &given) };
+    shorter.iter().all(|word| longer.contains(word))
+}
+
+/// Similarity of two company names, from 0 (nothing in common) to 1 (the same
+/// `normalize_name` form).
+///
+/// This is the Sørensen–Dice coefficient of the letter pairs of both names, so a typo or a
+/// missing word lowers the score a little while a different company scores near zero.
+pub fn similarity(expected: &str, registered: &str) -> f64 {
+    let expected = normalize_name(expected);
+    let registered = normalize_name(registered);
+    if expected == registered {
+        return 1.0;
+    }
+    let mut pairs: HashMap<(char, char), usize> = HashMap::new();
+    let expected_pairs = letter_pairs(&expected);
+    let registered_pairs = letter_pairs(&registered);
+    if expected_pairs.is_empty() || registered_pairs.is_empty() {
+        return 0.0;
+    }
+    for pair in &expected_pairs {
+        *pairs.entry(*pair).or_default() += 1;
+    }
+    let mut shared = 0;
+    for pair in &registered_pairs {
+        if let Some(count) = pairs.get_mut(pair).filter(|count| **count > 0) {
+            *count -= 1;
+            shared += 1;
+        }
+    }
+    let score = 2.0 * shared as f64 / (expected_pairs.len() + registered_pairs.len()) as f64;
+    (score * 1000.0).round() / 1000.0
+}
+
+/// Consecutive pairs of characters of `text`.
+fn letter_pairs(text: &str) -> Vec<(char, char)> {
+    let chars: Vec<char> = text.chars().collect();
+    chars.windows(2).map(|pair| (pair[0], pair[1])).collect()
+}
+
+/// How the name registered for a NIF compares with the one expected for it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameCheck {
+    pub expected: String,
+    pub similarity: f64, // See `similarity`
+    pub matches: bool,   // The similarity reaches `ExpectedNames::min_similarity`
+}
+
+impl NameCheck {
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::object()
+            .with("expected", self.expected.as_str())
+            .with("similarity", self.similarity)
+            .with("match", self.matches)
+    }
+}
+
+/// Names expected for some NIFs, e.g. read from a supplier file by `input::read_expected_names`.
+#[derive(Debug, Clone)]
+pub struct ExpectedNames {
+    names: HashMap<String, String>,
+    min_similarity: f64,
+}
+
+impl ExpectedNames {
+    /// No names yet; those scoring under `min_similarity` are mismatches.
+    pub fn new(min_similarity: f64) -> Self {
+        ExpectedNames { names: HashMap::new(), min_similarity }
+    }
+
+    /// Expects `name` for `nif` (nine digits), replacing the name expected before.
+    pub fn insert(&mut self, nif: impl Into<String>, name: impl Into<String>) {
+        self.names.insert(nif.into(), name.into());
+    }
+
+    pub fn min_similarity(&self) -> f64 {
+        self.min_similarity
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Compares `registered` with the name expected for `nif`; `None` when none is.
+    pub fn check(&self, nif: &str, registered: &str) -> Option<NameCheck> {
+        let expected = self.names.get(nif)?;
+        let similarity = similarity(expected, registered);
+        Some(NameCheck {
+            expected: expected.clone(),
+            similarity,
+            matches: similarity >= self.min_similarity,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legal_forms_and_connectives_dropped() {
+        assert_eq!(name_words("Papelaria Central de Lisboa, Lda."), ["papelaria", "central", "lisboa"]);
+        assert_eq!(normalize_name("Exemplo, Lda."), "exemplo");
+        assert_eq!(normalize_name("Exemplo, Lda."), normalize_name("EXEMPLO LIMITADA"));
+        assert_eq!(normalize_name("Cônsul & Filhos, Unipessoal"), "consul filhos");
+    }
+
+    #[test]
+    fn dotted_sa_is_a_legal_form() {
+        for name in ["Exemplo S.A.", "Exemplo, S. A.", "EXEMPLO SA", "Exemplo - Sociedade Anónima"] {
+            assert_eq!(normalize_name(name), "exemplo", "{}", name);
+        }
+    }
+
+    #[test]
+    fn initials_kept() {
+        assert_eq!(name_words("A Padaria, Lda."), ["a", "padaria"]);
+        assert_eq!(name_words("Padaria S A"), ["padaria", "s", "a"]);
+        assert_eq!(normalize_name("J. A. Silva"), normalize_name("J.A. Silva"));
+        assert!(!names_match("A Padaria", "Padaria Central, Lda."));
+        assert!(names_match("A Padaria", "A PADARIA, S.A."));
+    }
+
+    #[test]
+    fn words_left_out_still_match() {
+        assert!(names_match("Papelaria Central", "PAPELARIA CENTRAL DE LISBOA, LDA"));
+        assert!(names_match("PAPELARIA CENTRAL DE LISBOA, LDA", "Papelaria Central"));
+        assert!(!names_match("Papelaria do Norte", "PAPELARIA CENTRAL DE LISBOA, LDA"));
+        assert!(names_match("Lda.", "S.A."));
+        assert!(!names_match("Exemplo", "Lda."));
+    }
+
+    #[test]
+    fn similarity_scores() {
+        assert_eq!(similarity("Exemplo, Lda.", "EXEMPLO LIMITADA"), 1.0);
+        assert_eq!(similarity("Papelaria Central", "Papelaria Centrl"), 0.903);
+        assert!(similarity("Papelaria Central", "Oficina Auto Norte") < 0.2);
+        assert_eq!(similarity("Lda.", "Exemplo"), 0.0);
+    }
+
+    #[test]
+    fn expected_names_checked() {
+        let mut expected = ExpectedNames::new(DEFAULT_MIN_SIMILARITY);
+        expected.insert("500960046", "Exemplo, Lda.");
+        let check = expected.check("500960046", "EXEMPLO LIMITADA").unwrap();
+        assert!(check.matches);
+        assert_eq!(check.similarity, 1.0);
+        assert!(!expected.check("500960046", "Outra Empresa SA").unwrap().matches);
+        assert_eq!(expected.check("509442013", "Exemplo"), None);
+    }
+}
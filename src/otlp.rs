@@ -0,0 +1,195 @@
+// otlp.rs
+
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use reqwest::blocking::Client;
+
+use crate::json::JsonValue;
+use crate::logging;
+
+/// Number of finished spans buffered before they are sent in one export request.
+const BATCH_SIZE: usize = 32;
+
+/// Attribute value attached to a span.
+#[derive(Debug, Clone)]
+pub enum AttributeValue {
+    String(String),
+    Bool(bool),
+    Int(i64),
+}
+
+/// A finished span waiting to be exported.
+#[derive(Debug, Clone)]
+pub struct SpanData {
+    pub name: String,
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub error: bool,
+    pub attributes: Vec<(String, AttributeValue)>,
+}
+
+impl SpanData {
+    /// Starts a span in a new trace; `end` is set to `start` until `finish` is called.
+    pub fn start(name: &str) -> Self {
+        let now = SystemTime::now();
+        SpanData {
+            name: name.to_string(),
+            trace_id: rand::random(),
+            span_id: rand::random(),
+            start: now,
+            end: now,
+            error: false,
+            attributes: Vec::new(),
+        }
+    }
+
+    /// Adds an attribute to the span.
+    pub fn set(&mut self, key: &str, value: AttributeValue) {
+        self.attributes.push((key.to_string(), value));
+    }
+
+    /// Marks the span as finished now.
+    pub fn finish(&mut self) {
+        self.end = SystemTime::now();
+    }
+}
+
+/// Exports spans to an OpenTelemetry collector using OTLP over HTTP with JSON encoding.
+///
+/// Spans are buffered and sent in batches; whatever is left is flushed on drop.
+#[derive(Debug)]
+pub struct OtlpExporter {
+    endpoint: String,
+    service_name: String,
+    client: Client,
+    pending: Mutex<Vec<SpanData>>,
+}
+
+impl OtlpExporter {
+    /// Creates an exporter for a collector base URL such as `http://localhost:4318`.
+    pub fn new(endpoint: &str, service_name: &str) -> Result<Self, String> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()
+            .map_err(|e| format!("cannot build OTLP client: {}", e))?;
+        let endpoint = endpoint.trim_end_matches('/');
+        let endpoint = if endpoint.ends_with("/v1/traces") {
+            endpoint.to_string()
+        } else {
+            format!("{}/v1/traces", endpoint)
+        };
+        Ok(OtlpExporter {
+            endpoint,
+            service_name: service_name.to_string(),
+            client,
+            pending: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Queues a finished span, exporting the batch once it is full.
+    pub fn record(&self, span: SpanData) {
+        let batch = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.push(span);
+            if pending.len() < BATCH_SIZE {
+                return;
+            }
+            std::mem::take(&mut *pending)
+        };
+        self.export(batch);
+    }
+
+    /// Sends every queued span now.
+    pub fn flush(&self) {
+        let batch = std::mem::take(&mut *self.pending.lock().unwrap());
+        if !batch.is_empty() {
+            self.export(batch);
+        }
+    }
+
+    fn export(&self, spans: Vec<SpanData>) {
+        let body = self.encode(&spans).to_string();
+        let result = self
+            .client
+            .post(&self.endpoint)
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send();
+        // Tracing is best effort, a broken collector must not break lookups
+        match result {
+            Ok(resp) if !resp.status().is_success() => logging::warn(
+                "otlp_export_failed",
+                &[("http_status", resp.status().as_u16().into())],
+                format!("OTLP export to {} failed with status: {}", self.endpoint, resp.status()),
+            ),
+            Err(e) => logging::warn(
+                "otlp_export_failed",
+                &[("error", e.to_string().into())],
+                format!("OTLP export to {} failed: {}", self.endpoint, e),
+            ),
+            Ok(_) => {}
+        }
+    }
+
+    /// Builds the `ExportTraceServiceRequest` JSON document.
+    fn encode(&self, spans: &[SpanData]) -> JsonValue {
+        let spans: Vec<JsonValue> = spans.iter().map(encode_span).collect();
+        let resource = JsonValue::object().with(
+            "attributes",
+            vec![encode_attribute("service.name", &AttributeValue::String(self.service_name.clone()))],
+        );
+        let scope = JsonValue::object()
+            .with("scope", JsonValue::object().with("name", env!("CARGO_PKG_NAME")))
+            .with("spans", spans);
+        let resource_spans = JsonValue::object()
+            .with("resource", resource)
+            .with("scopeSpans", vec![scope]);
+        JsonValue::object().with("resourceSpans", vec![resource_spans])
+    }
+}
+
+impl Drop for OtlpExporter {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+fn encode_span(span: &SpanData) -> JsonValue {
+    let status_code = if span.error { 2 } else { 1 }; // STATUS_CODE_ERROR / STATUS_CODE_OK
+    JsonValue::object()
+        .with("traceId", hex(&span.trace_id))
+        .with("spanId", hex(&span.span_id))
+        .with("name", span.name.as_str())
+        .with("kind", 3i64) // SPAN_KIND_CLIENT
+        .with("startTimeUnixNano", unix_nanos(span.start))
+        .with("endTimeUnixNano", unix_nanos(span.end))
+        .with(
+            "attributes",
+            span.attributes
+                .iter()
+                .map(|(key, value)| encode_attribute(key, value))
+                .collect::<Vec<_>>(),
+        )
+        .with("status", JsonValue::object().with("code", status_code as i64))
+}
+
+fn encode_attribute(key: &str, value: &AttributeValue) -> JsonValue {
+    let value = match value {
+        AttributeValue::String(s) => JsonValue::object().with("stringValue", s.as_str()),
+        AttributeValue::Bool(b) => JsonValue::object().with("boolValue", *b),
+        // 64-bit integers are encoded as strings in OTLP/JSON
+        AttributeValue::Int(n) => JsonValue::object().with("intValue", n.to_string()),
+    };
+    JsonValue::object().with("key", key).with("value", value)
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,813 @@
+// output.rs
+
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::csv;
+use crate::lang::{category_name, lang, tr, Lang};
+use crate::msgpack;
+use crate::proto;
+use crate::lookup::{Confidence, LookupResult, LookupSource};
+use crate::postal::PostalCheck;
+use crate::report::status_title;
+use crate::status::NifStatus;
+use crate::time::format_rfc3339;
+use crate::validation::{is_nif_valid_local, nif_category, vat_number};
+use crate::vcard::format_vcard;
+
+/// Destination of the results of a run: a format, a file, a database...
+///
+/// The batch loop calls `before_lookup` and `write` for each NIF in order, then `finish`
+/// once. Library users can implement it to send results to their own sinks.
+pub trait OutputWriter {
+    /// Called before each lookup starts, e.g. to print a heading above its log lines.
+    fn before_lookup(&mut self, _nif: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Writes the result of one lookup.
+    fn write(&mut self, result: &LookupResult) -> Result<(), String>;
+
+    /// Completes the output (closing brackets, flushing) after the last result.
+    fn finish(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Built-in output formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,    // Human-readable lines
+    Csv,     // One row per result, with a header
+    Json,    // One array of result objects
+    Ndjson,  // One result object per line
+    Xml,     // One <result> element per result
+    Vcard,   // One .vcf file per resolved entity
+    Proto,   // Length-delimited protobuf messages
+    Msgpack, // One MessagePack map per result
+    #[cfg(feature = "arrow")]
+    Arrow, // Arrow IPC stream of record batches
+}
+
+impl OutputFormat {
+    /// Parses a `--format` value.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "text" => Ok(OutputFormat::Text),
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            "xml" => Ok(OutputFormat::Xml),
+            "vcard" => Ok(OutputFormat::Vcard),
+            "proto" => Ok(OutputFormat::Proto),
+            "msgpack" => Ok(OutputFormat::Msgpack),
+            #[cfg(feature = "arrow")]
+            "arrow" => Ok(OutputFormat::Arrow),
+            #[cfg(not(feature = "arrow"))]
+            "arrow" => Err("cannot write arrow: built without the arrow feature".to_string()),
+            other => Err(format!(
+                "unknown output format '{}', expected text, csv, json, ndjson, xml, vcard, proto, msgpack or arrow",
+                other
+            )),
+        }
+    }
+
+    /// Builds the writer of this format. Every format writes to `out` except vCard,
+    /// which writes files into `dir`.
+    pub fn writer(self, out: Box<dyn Write>, dir: PathBuf) -> Box<dyn OutputWriter> {
+        match self {
+            OutputFormat::Text => Box::new(TextWriter::new(out)),
+            OutputFormat::Csv => Box::new(CsvWriter::new(out)),
+            OutputFormat::Json => Box::new(JsonWriter::new(out)),
+            OutputFormat::Ndjson => Box::new(NdjsonWriter::new(out)),
+            OutputFormat::Xml => Box::new(XmlWriter::new(out)),
+            OutputFormat::Vcard => Box::new(VcardWriter::new(dir)),
+            OutputFormat::Proto => Box::new(ProtoWriter::new(out)),
+            OutputFormat::Msgpack => Box::new(MsgpackWriter::new(out)),
+            #[cfg(feature = "arrow")]
+            OutputFormat::Arrow => Box::new(crate::arrow::ArrowWriter::new(out)),
+        }
+    }
+}
+
+/// How NIFs are written in the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NifFormat {
+    Plain, // Nine digits, e.g. `500960046`
+    Vat,   // EU VAT form, e.g. `PT500960046`
+}
+
+impl NifFormat {
+    /// Parses a `--nif-format` value.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "plain" => Ok(NifFormat::Plain),
+            "vat" => Ok(NifFormat::Vat),
+            other => Err(format!("unknown NIF format '{}', expected plain or vat", other)),
+        }
+    }
+}
+
+/// Passes results on to another writer with their NIFs in the VAT form.
+pub struct VatNifWriter {
+    inner: Box<dyn OutputWriter>,
+}
+
+impl VatNifWriter {
+    pub fn new(inner: Box<dyn OutputWriter>) -> Self {
+        VatNifWriter { inner }
+    }
+}
+
+impl OutputWriter for VatNifWriter {
+    fn before_lookup(&mut self, nif: &str) -> Result<(), String> {
+        self.inner.before_lookup(&vat_number(nif))
+    }
+
+    fn write(&mut self, result: &LookupResult) -> Result<(), String> {
+        let mut result = result.clone();
+        result.nif = vat_number(&result.nif);
+        if let Some(entity) = &mut result.entity {
+            entity.nif = vat_number(&entity.nif);
+        }
+        for nif in result.candidates.iter_mut().filter_map(|candidate| candidate.nif.as_mut()) {
+            *nif = vat_number(nif);
+        }
+        self.inner.write(&result)
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        self.inner.finish()
+    }
+}
+
+/// Statuses that `--only` and `--exclude` accept, with the statuses each one covers.
+const STATUS_FILTERS: &[(&str, &[&str])] = &[
+    ("valid", &["valid_known", "valid_unknown"]),
+    ("invalid", &["error"]),
+    ("failed", &["http_error", "circuit_open", "unsupported_layout", "wrong_category", "cancelled", "unknown"]),
+];
+
+/// Which results make it to the output, by status.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatusFilter {
+    only: Vec<&'static str>,    // Status labels kept; empty keeps all
+    exclude: Vec<&'static str>, // Status labels dropped
+}
+
+impl StatusFilter {
+    /// Builds the filter from `--only` and `--exclude` values: status labels, with `-` or
+    /// `_` (`valid-known`), or the groups `valid`, `invalid` (nif.pt rejects it) and
+    /// `failed` (no answer). Values may be comma-separated.
+    pub fn parse(only: &[&str], exclude: &[&str]) -> Result<Self, String> {
+        Ok(StatusFilter {
+            only: Self::labels(only)?,
+            exclude: Self::labels(exclude)?,
+        })
+    }
+
+    fn labels(values: &[&str]) -> Result<Vec<&'static str>, String> {
+        let mut labels = Vec::new();
+        for name in values.iter().flat_map(|value| value.split(',')) {
+            let name = name.trim().replace('-', "_");
+            if let Some((_, group)) = STATUS_FILTERS.iter().find(|(group, _)| *group == name) {
+                labels.extend_from_slice(group);
+                continue;
+            }
+            match NifStatus::from_label(&name) {
+                Some(status) => labels.push(status.label()),
+                None if name == "http_error" => labels.push("http_error"),
+                None => return Err(format!("unknown status '{}' to filter on", name)),
+            }
+        }
+        Ok(labels)
+    }
+
+    /// Tells whether the filter keeps everything.
+    pub fn is_empty(&self) -> bool {
+        self.only.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Tells whether a result with this status is written.
+    pub fn keeps(&self, status: &NifStatus) -> bool {
+        let label = status.label();
+        (self.only.is_empty() || self.only.contains(&label)) && !self.exclude.contains(&label)
+    }
+}
+
+/// Passes on to another writer only the results kept by a `StatusFilter`.
+///
+/// The heading of a lookup is only known to be wanted once its result is in, so
+/// `before_lookup` is passed on right before `write`.
+pub struct FilterWriter {
+    inner: Box<dyn OutputWriter>,
+    filter: StatusFilter,
+}
+
+impl FilterWriter {
+    pub fn new(inner: Box<dyn OutputWriter>, filter: StatusFilter) -> Self {
+        FilterWriter { inner, filter }
+    }
+}
+
+impl OutputWriter for FilterWriter {
+    fn write(&mut self, result: &LookupResult) -> Result<(), String> {
+        if !self.filter.keeps(&result.status) {
+            return Ok(());
+        }
+        self.inner.before_lookup(&result.nif)?;
+        self.inner.write(result)
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        self.inner.finish()
+    }
+}
+
+/// Orders in which `--sort` writes the results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Status, // Valid ones first, then rejected ones, then failures; by NIF within a status
+    Nif,
+    Name, // Entity name, ignoring case; results without an entity last, by NIF
+}
+
+impl SortKey {
+    /// Parses a `--sort` value.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "status" => Ok(SortKey::Status),
+            "nif" => Ok(SortKey::Nif),
+            "name" => Ok(SortKey::Name),
+            other => Err(format!("unknown sort key '{}', expected status, nif or name", other)),
+        }
+    }
+
+    /// Sorts results by this key; the sort is stable.
+    pub fn sort(self, results: &mut [LookupResult]) {
+        match self {
+            SortKey::Status => results.sort_by(|a, b| status_rank(&a.status).cmp(&status_rank(&b.status)).then(a.nif.cmp(&b.nif))),
+            SortKey::Nif => results.sort_by(|a, b| a.nif.cmp(&b.nif)),
+            SortKey::Name => results.sort_by_cached_key(|result| {
+                let name = result.entity.as_ref().map(|entity| entity.name.to_lowercase());
+                (name.is_none(), name, result.nif.clone())
+            }),
+        }
+    }
+}
+
+/// Position of a status in sorted and grouped output.
+pub fn status_rank(status: &NifStatus) -> u8 {
+    match status {
+        NifStatus::ValidKnown => 0,
+        NifStatus::ValidUnknown => 1,
+        NifStatus::MultipleResults => 2,
+        NifStatus::Error => 3,
+        NifStatus::WrongCategory => 4,
+        NifStatus::HttpError(_) => 5,
+        NifStatus::CircuitOpen => 6,
+        NifStatus::UnsupportedLayout => 7,
+        NifStatus::Cancelled => 8,
+        NifStatus::Unknown => 9,
+    }
+}
+
+/// Holds every result until the end of the run, then passes them on sorted to another writer.
+pub struct SortWriter {
+    inner: Box<dyn OutputWriter>,
+    key: SortKey,
+    results: Vec<LookupResult>,
+}
+
+impl SortWriter {
+    pub fn new(inner: Box<dyn OutputWriter>, key: SortKey) -> Self {
+        SortWriter {
+            inner,
+            key,
+            results: Vec::new(),
+        }
+    }
+}
+
+impl OutputWriter for SortWriter {
+    fn write(&mut self, result: &LookupResult) -> Result<(), String> {
+        self.results.push(result.clone());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        self.key.sort(&mut self.results);
+        for result in &self.results {
+            self.inner.before_lookup(&result.nif)?;
+            self.inner.write(result)?;
+        }
+        self.inner.finish()
+    }
+}
+
+pub(crate) fn write_error(e: std::io::Error) -> String {
+    format!("cannot write output: {}", e)
+}
+
+/// Describes the status of a NIF query in one human-readable line, in the `--lang` language.
+pub fn status_line(nif: &str, status: &NifStatus) -> String {
+    if lang() == Lang::Pt {
+        return status_line_pt(nif, status);
+    }
+    match status {
+        NifStatus::ValidKnown => format!("NIF {} status: Valid and known entity.", nif),
+        NifStatus::ValidUnknown => format!("NIF {} status: Valid but unknown entity.", nif),
+        NifStatus::Error => format!("NIF {} status: Invalid (Error message).", nif),
+        NifStatus::MultipleResults => format!("NIF {} status: Multiple companies found, NIF unavailable.", nif),
+        NifStatus::HttpError(code) => {
+            let hint = if status.is_retryable() { " Retry later." } else { "" };
+            format!("NIF {} status: HTTP error {} ({}).{}", nif, code, http_reason(*code), hint)
+        }
+        NifStatus::CircuitOpen => format!("NIF {} status: Not checked remotely (nif.pt keeps failing).", nif),
+        NifStatus::UnsupportedLayout => format!("NIF {} status: Unsupported nif.pt page layout, the parser needs an update.", nif),
+        NifStatus::WrongCategory => {
+            let category = nif_category(nif).map_or("unexpected", category_name);
+            format!("NIF {} status: Rejected, {} NIFs are not expected.", nif, category)
+        }
+        NifStatus::Cancelled => format!("NIF {} status: Lookup cancelled.", nif),
+        NifStatus::Unknown => format!("NIF {} status: Unknown or could not determine.", nif),
+    }
+}
+
+fn status_line_pt(nif: &str, status: &NifStatus) -> String {
+    match status {
+        NifStatus::ValidKnown => format!("Estado do NIF {}: Válido e entidade conhecida.", nif),
+        NifStatus::ValidUnknown => format!("Estado do NIF {}: Válido mas entidade desconhecida.", nif),
+        NifStatus::Error => format!("Estado do NIF {}: Inválido (mensagem de erro).", nif),
+        NifStatus::MultipleResults => format!("Estado do NIF {}: Várias empresas encontradas, NIF indisponível.", nif),
+        NifStatus::HttpError(code) => {
+            let hint = if status.is_retryable() { " Tente mais tarde." } else { "" };
+            format!("Estado do NIF {}: Erro HTTP {} ({}).{}", nif, code, http_reason(*code), hint)
+        }
+        NifStatus::CircuitOpen => format!("Estado do NIF {}: Não verificado remotamente (o nif.pt continua a falhar).", nif),
+        NifStatus::UnsupportedLayout => {
+            format!("Estado do NIF {}: Página do nif.pt com formato não suportado, o analisador precisa de ser atualizado.", nif)
+        }
+        NifStatus::WrongCategory => {
+            let category = nif_category(nif).map_or("desta categoria", category_name);
+            format!("Estado do NIF {}: Rejeitado, não se esperam NIFs de {}.", nif, category)
+        }
+        NifStatus::Cancelled => format!("Estado do NIF {}: Consulta cancelada.", nif),
+        NifStatus::Unknown => format!("Estado do NIF {}: Desconhecido ou impossível de determinar.", nif),
+    }
+}
+
+/// Canonical reason of an HTTP status (e.g. "Too Many Requests"), when reqwest knows it.
+fn http_reason(code: u16) -> &'static str {
+    reqwest::StatusCode::from_u16(code)
+        .ok()
+        .and_then(|s| s.canonical_reason())
+        .unwrap_or("Unrecognized status")
+}
+
+/// The classic human-readable output: a heading per NIF, its status, entity and local validity.
+pub struct TextWriter {
+    out: Box<dyn Write>,
+}
+
+impl TextWriter {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        TextWriter { out }
+    }
+}
+
+impl OutputWriter for TextWriter {
+    fn before_lookup(&mut self, nif: &str) -> Result<(), String> {
+        writeln!(self.out, "\n--- {}: {} ---", tr("Checking NIF from arguments", "A verificar o NIF dos argumentos"), nif)
+            .and_then(|_| self.out.flush())
+            .map_err(write_error)
+    }
+
+    fn write(&mut self, result: &LookupResult) -> Result<(), String> {
+        let mut text = status_line(&result.nif, &result.status) + "\n";
+        if let Some(entity) = &result.entity {
+            text += &format!("{}: {}\n", tr("Entity", "Entidade"), entity.name);
+            if let Some(address) = entity.full_address() {
+                text += &format!("{}: {}\n", tr("Address", "Morada"), address);
+            }
+            if let Some(location) = &result.location {
+                text += &format!("{}: {}, {}\n", tr("Location", "Localização"), location.latitude, location.longitude);
+            }
+        }
+        if let (Some(check), Some(entity)) = (&result.postal_check, &result.entity) {
+            let code = entity.postal_code.as_deref().unwrap_or_default();
+            let locality = entity.locality.as_deref().unwrap_or_default();
+            text += &match (check, lang()) {
+                (PostalCheck::Consistent, _) => String::new(),
+                (PostalCheck::Malformed, Lang::En) => format!("Postal code {} is malformed\n", code),
+                (PostalCheck::Malformed, Lang::Pt) => format!("O código postal {} está mal formado\n", code),
+                (PostalCheck::UnknownCode, Lang::En) => format!("Postal code {} does not exist\n", code),
+                (PostalCheck::UnknownCode, Lang::Pt) => format!("O código postal {} não existe\n", code),
+                (PostalCheck::LocalityMismatch { expected }, Lang::En) => {
+                    format!("Postal code {} belongs to {}, not {}\n", code, expected, locality)
+                }
+                (PostalCheck::LocalityMismatch { expected }, Lang::Pt) => {
+                    format!("O código postal {} pertence a {}, não a {}\n", code, expected, locality)
+                }
+            };
+        }
+        if let Some(parsed) = result.parse_confidence.as_ref().filter(|parsed| parsed.needs_review()) {
+            let missing = parsed.missing.join(", ");
+            text += &match lang() {
+                Lang::En => format!("Page read with low confidence ({:.2}), missing: {}\n", parsed.score(), missing),
+                Lang::Pt => format!("Página lida com pouca confiança ({:.2}), em falta: {}\n", parsed.score(), missing),
+            };
+        }
+        if let Some(check) = &result.name_check {
+            text += &match (check.matches, lang()) {
+                (true, Lang::En) => format!("Expected name {} matches (similarity {:.2})\n", check.expected, check.similarity),
+                (true, Lang::Pt) => format!("O nome esperado {} corresponde (semelhança {:.2})\n", check.expected, check.similarity),
+                (false, Lang::En) => format!("Expected name {} does not match (similarity {:.2})\n", check.expected, check.similarity),
+                (false, Lang::Pt) => format!("O nome esperado {} não corresponde (semelhança {:.2})\n", check.expected, check.similarity),
+            };
+        }
+        for candidate in &result.candidates {
+            let details: Vec<&str> = [&candidate.nif, &candidate.locality].into_iter().flatten().map(String::as_str).collect();
+            if details.is_empty() {
+                text += &format!("  - {}\n", candidate.name);
+            } else {
+                text += &format!("  - {} ({})\n", candidate.name, details.join(", "));
+            }
+        }
+        match (result.source, lang()) {
+            (LookupSource::Store, Lang::En) => text += "(answered from the local store)\n",
+            (LookupSource::Store, Lang::Pt) => text += "(respondido pelo arquivo local)\n",
+            (LookupSource::Fallback(fallback), Lang::En) => text += &format!("(answered by {}, nif.pt did not answer)\n", fallback.name()),
+            (LookupSource::Fallback(fallback), Lang::Pt) => text += &format!("(respondido por {}, o nif.pt não respondeu)\n", fallback.name()),
+            (LookupSource::Cache | LookupSource::Remote | LookupSource::Local, _) => {}
+        }
+        if let (Some(checked_at), Some(confidence)) = (result.checked_at, result.confidence) {
+            let date = &format_rfc3339(checked_at)[..10];
+            text += &match (confidence, lang()) {
+                (Confidence::Fresh, _) => String::new(),
+                (Confidence::Stale, Lang::En) => format!("(checked on {}, stale: worth refreshing)\n", date),
+                (Confidence::Stale, Lang::Pt) => format!("(verificado em {}, desatualizado: convém atualizar)\n", date),
+                (Confidence::Expired, Lang::En) => format!("(checked on {}, expired: refresh before trusting it)\n", date),
+                (Confidence::Expired, Lang::Pt) => format!("(verificado em {}, expirado: atualize antes de confiar)\n", date),
+            };
+        }
+        let valid = is_nif_valid_local(&result.nif);
+        text += &match lang() {
+            Lang::En => format!("NIF {} is {} (local)\n", result.nif, if valid { "valid" } else { "invalid" }),
+            Lang::Pt => format!("O NIF {} é {} (local)\n", result.nif, if valid { "válido" } else { "inválido" }),
+        };
+        self.out.write_all(text.as_bytes()).and_then(|_| self.out.flush()).map_err(write_error)
+    }
+}
+
+/// Text output for review: one section per status, with one line per result. Results are
+/// written at the end of the run, in the order they came within each section.
+pub struct GroupedTextWriter {
+    out: Box<dyn Write>,
+    results: Vec<LookupResult>,
+}
+
+impl GroupedTextWriter {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        GroupedTextWriter {
+            out,
+            results: Vec::new(),
+        }
+    }
+}
+
+impl OutputWriter for GroupedTextWriter {
+    fn write(&mut self, result: &LookupResult) -> Result<(), String> {
+        self.results.push(result.clone());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        self.results.sort_by_key(|result| status_rank(&result.status)); // Stable, keeps the order within a status
+        let mut text = String::new();
+        for (index, result) in self.results.iter().enumerate() {
+            if index == 0 || self.results[index - 1].status.label() != result.status.label() {
+                let count = self.results.iter().filter(|other| other.status.label() == result.status.label()).count();
+                text += &format!("\n== {} ({}) ==\n", status_title(result.status.label()), count);
+            }
+            let mut line = result.nif.clone();
+            if let NifStatus::HttpError(code) = result.status {
+                line += &format!("  HTTP {}", code);
+            }
+            if let Some(entity) = &result.entity {
+                line += &format!("  {}", entity.name);
+                if let Some(address) = entity.full_address() {
+                    line += &format!(", {}", address);
+                }
+            }
+            text += &line;
+            text.push('\n');
+        }
+        self.out.write_all(text.as_bytes()).and_then(|_| self.out.flush()).map_err(write_error)
+    }
+}
+
+/// Columns of the CSV output.
+pub const CSV_HEADER: &str = "nif,status,http_status,name,address,source,district,municipality,latitude,longitude,checked_at,confidence,tag,expected_name,name_similarity,name_match,parse_confidence";
+
+/// Formats a result as a CSV record with the `CSV_HEADER` columns.
+pub fn csv_record(result: &LookupResult) -> String {
+    let entity = result.entity.as_ref();
+    let http_status = result.status.http_status().map(|code| code.to_string()).unwrap_or_default();
+    let check = result.name_check.as_ref();
+    csv::format_record(&[
+        result.nif.as_str(),
+        result.status.label(),
+        &http_status,
+        entity.map(|e| e.name.as_str()).unwrap_or_default(),
+        &entity.and_then(|e| e.full_address()).unwrap_or_default(),
+        result.source.label(),
+        entity.and_then(|e| e.district.as_deref()).unwrap_or_default(),
+        entity.and_then(|e| e.municipality.as_deref()).unwrap_or_default(),
+        &result.location.map(|location| location.latitude.to_string()).unwrap_or_default(),
+        &result.location.map(|location| location.longitude.to_string()).unwrap_or_default(),
+        &result.checked_at.map(format_rfc3339).unwrap_or_default(),
+        result.confidence.map(|confidence| confidence.label()).unwrap_or_default(),
+        result.tag.as_deref().unwrap_or_default(),
+        check.map(|check| check.expected.as_str()).unwrap_or_default(),
+        &check.map(|check| check.similarity.to_string()).unwrap_or_default(),
+        check.map(|check| if check.matches { "true" } else { "false" }).unwrap_or_default(),
+        &result.parse_confidence.as_ref().map(|parsed| parsed.score().to_string()).unwrap_or_default(),
+    ])
+}
+
+/// CSV with a header line, then one record per result.
+pub struct CsvWriter<W> {
+    out: W,
+    header_written: bool,
+}
+
+impl<W: Write> CsvWriter<W> {
+    pub fn new(out: W) -> Self {
+        CsvWriter {
+            out,
+            header_written: false,
+        }
+    }
+
+    fn write_header(&mut self) -> Result<(), String> {
+        if !self.header_written {
+            self.header_written = true;
+            writeln!(self.out, "{}", CSV_HEADER).map_err(write_error)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> OutputWriter for CsvWriter<W> {
+    fn write(&mut self, result: &LookupResult) -> Result<(), String> {
+        self.write_header()?;
+        writeln!(self.out, "{}", csv_record(result)).map_err(write_error)
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        self.write_header()?; // Even without results, so the file has its columns
+        self.out.flush().map_err(write_error)
+    }
+}
+
+/// A JSON array of `LookupResult::to_json` objects, one per line.
+pub struct JsonWriter<W> {
+    out: W,
+    count: usize,
+}
+
+impl<W: Write> JsonWriter<W> {
+    pub fn new(out: W) -> Self {
+        JsonWriter { out, count: 0 }
+    }
+}
+
+impl<W: Write> OutputWriter for JsonWriter<W> {
+    fn write(&mut self, result: &LookupResult) -> Result<(), String> {
+        let separator = if self.count == 0 { "[\n  " } else { ",\n  " };
+        self.count += 1;
+        write!(self.out, "{}{}", separator, result.to_json()).map_err(write_error)
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        let end = if self.count == 0 { "[]\n" } else { "\n]\n" };
+        self.out.write_all(end.as_bytes()).and_then(|_| self.out.flush()).map_err(write_error)
+    }
+}
+
+/// One `LookupResult::to_json` object per line (newline-delimited JSON).
+pub struct NdjsonWriter<W> {
+    out: W,
+}
+
+impl<W: Write> NdjsonWriter<W> {
+    pub fn new(out: W) -> Self {
+        NdjsonWriter { out }
+    }
+}
+
+impl<W: Write> OutputWriter for NdjsonWriter<W> {
+    fn write(&mut self, result: &LookupResult) -> Result<(), String> {
+        // Flushed per line, so consumers of a pipe see results as they come
+        writeln!(self.out, "{}", result.to_json())
+            .and_then(|_| self.out.flush())
+            .map_err(write_error)
+    }
+}
+
+/// `check_nif.v1.LookupResult` protobuf messages, each preceded by its length as a varint.
+pub struct ProtoWriter<W> {
+    out: W,
+}
+
+impl<W: Write> ProtoWriter<W> {
+    pub fn new(out: W) -> Self {
+        ProtoWriter { out }
+    }
+}
+
+impl<W: Write> OutputWriter for ProtoWriter<W> {
+    fn write(&mut self, result: &LookupResult) -> Result<(), String> {
+        // Flushed per message, like NDJSON, for consumers reading a pipe
+        self.out
+            .write_all(&proto::encode_delimited(result))
+            .and_then(|_| self.out.flush())
+            .map_err(write_error)
+    }
+}
+
+/// One MessagePack map per result, the `to_json` object, back to back.
+pub struct MsgpackWriter<W> {
+    out: W,
+}
+
+impl<W: Write> MsgpackWriter<W> {
+    pub fn new(out: W) -> Self {
+        MsgpackWriter { out }
+    }
+}
+
+impl<W: Write> OutputWriter for MsgpackWriter<W> {
+    fn write(&mut self, result: &LookupResult) -> Result<(), String> {
+        self.out
+            .write_all(&msgpack::encode(&result.to_json()))
+            .and_then(|_| self.out.flush())
+            .map_err(write_error)
+    }
+}
+
+/// XML document with one `<result>` element per result.
+pub struct XmlWriter<W> {
+    out: W,
+    started: bool,
+}
+
+impl<W: Write> XmlWriter<W> {
+    pub fn new(out: W) -> Self {
+        XmlWriter { out, started: false }
+    }
+
+    fn start(&mut self) -> Result<(), String> {
+        if !self.started {
+            self.started = true;
+            self.out
+                .write_all(b"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<results>\n")
+                .map_err(write_error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Escapes text for XML content and attribute values.
+fn escape_xml(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c if c.is_control() && !matches!(c, '\t' | '\n' | '\r') => {} // Not allowed in XML 1.0
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+impl<W: Write> OutputWriter for XmlWriter<W> {
+    fn write(&mut self, result: &LookupResult) -> Result<(), String> {
+        self.start()?;
+        let mut xml = format!(
+            "  <result nif=\"{}\" status=\"{}\" valid_locally=\"{}\" source=\"{}\"",
+            escape_xml(&result.nif),
+            result.status.label(),
+            is_nif_valid_local(&result.nif),
+            result.source.label()
+        );
+        if let Some(code) = result.status.http_status() {
+            xml += &format!(" http_status=\"{}\"", code);
+        }
+        if let (Some(checked_at), Some(confidence)) = (result.checked_at, result.confidence) {
+            xml += &format!(" checked_at=\"{}\" confidence=\"{}\"", format_rfc3339(checked_at), confidence.label());
+        }
+        if let Some(tag) = &result.tag {
+            xml += &format!(" tag=\"{}\"", escape_xml(tag));
+        }
+        if let Some(parsed) = &result.parse_confidence {
+            xml += &format!(" parse_confidence=\"{}\"", parsed.score());
+        }
+        let mut children = String::new();
+        if let Some(entity) = &result.entity {
+            children += "    <entity";
+            if let Some(check) = &result.postal_check {
+                children += &format!(" postal_check=\"{}\"", check.label());
+                if let PostalCheck::LocalityMismatch { expected } = check {
+                    children += &format!(" expected_locality=\"{}\"", escape_xml(expected));
+                }
+            }
+            if let Some(check) = &result.name_check {
+                children += &format!(
+                    " expected_name=\"{}\" name_similarity=\"{}\" name_match=\"{}\"",
+                    escape_xml(&check.expected),
+                    check.similarity,
+                    check.matches
+                );
+            }
+            children += ">\n";
+            let fields = [
+                ("name", Some(&entity.name)),
+                ("address", entity.address.as_ref()),
+                ("postal_code", entity.postal_code.as_ref()),
+                ("locality", entity.locality.as_ref()),
+                ("phone", entity.phone.as_ref()),
+                ("email", entity.email.as_ref()),
+                ("district", entity.district.as_ref()),
+                ("municipality", entity.municipality.as_ref()),
+            ];
+            for (name, value) in fields {
+                if let Some(value) = value {
+                    children += &format!("      <{0}>{1}</{0}>\n", name, escape_xml(value));
+                }
+            }
+            if let Some(location) = &result.location {
+                children += &format!("      <location latitude=\"{}\" longitude=\"{}\"/>\n", location.latitude, location.longitude);
+            }
+            children += "    </entity>\n";
+        }
+        for candidate in &result.candidates {
+            children += "    <candidate";
+            for (name, value) in [("nif", &candidate.nif), ("locality", &candidate.locality)] {
+                if let Some(value) = value {
+                    children += &format!(" {}=\"{}\"", name, escape_xml(value));
+                }
+            }
+            children += &format!(">{}</candidate>\n", escape_xml(&candidate.name));
+        }
+        if children.is_empty() {
+            xml += "/>\n";
+        } else {
+            xml += &format!(">\n{}  </result>\n", children);
+        }
+        self.out.write_all(xml.as_bytes()).map_err(write_error)
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        self.start()?;
+        self.out
+            .write_all(b"</results>\n")
+            .and_then(|_| self.out.flush())
+            .map_err(write_error)
+    }
+}
+
+/// Writes `<dir>/<nif>.vcf` for each resolved entity; others are noted on stderr.
+pub struct VcardWriter {
+    dir: PathBuf,
+}
+
+impl VcardWriter {
+    pub fn new(dir: PathBuf) -> Self {
+        VcardWriter { dir }
+    }
+}
+
+impl OutputWriter for VcardWriter {
+    fn write(&mut self, result: &LookupResult) -> Result<(), String> {
+        let Some(entity) = &result.entity else {
+            eprintln!("NIF {}: no entity details ({}), no vCard written", result.nif, result.status.label());
+            return Ok(());
+        };
+        std::fs::create_dir_all(&self.dir).map_err(|e| format!("cannot create {}: {}", self.dir.display(), e))?;
+        let path = self.dir.join(format!("{}.vcf", result.nif));
+        std::fs::write(&path, format_vcard(entity)).map_err(|e| format!("cannot write {}: {}", path.display(), e))?;
+        println!("Wrote {}", path.display());
+        Ok(())
+    }
+}
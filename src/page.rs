@@ -0,0 +1,312 @@
+// page.rs
+
+//! Reading of the nif.pt results pages, apart from how they are fetched: the URL of a query,
+//! what an HTTP answer means, and the status and entity a page gives.
+
+use scraper::{ElementRef, Html}; // For parsing HTML
+
+use crate::encoding::percent_encode;
+use crate::entity::{split_postal_code, EntityCandidate, NifEntity};
+use crate::json::JsonValue;
+use crate::layout::{selector, Layout};
+use crate::logging::{self, display_nif, nif_field};
+use crate::scan::find_candidates;
+use crate::status::NifStatus;
+
+/// Scheme and host of nif.pt, unless `[backend.nifpt] base_url` replaces them.
+pub const NIF_PT_URL: &str = "https://www.nif.pt";
+
+/// URL of the results page of nif.pt for a NIF.
+pub fn results_url(nif_number: &str) -> String {
+    results_url_at(NIF_PT_URL, nif_number)
+}
+
+/// URL of the results page for a NIF on a server answering like nif.pt at `base_url`. The
+/// NIF is percent-encoded, so whatever it holds stays in the `q` parameter.
+pub fn results_url_at(base_url: &str, nif_number: &str) -> String {
+    format!("{}/?q={}", base_url.trim_end_matches('/'), percent_encode(nif_number))
+}
+
+/// NIF asked by a results page URL of nif.pt, the reverse of [`results_url`].
+pub fn results_url_nif(url: &str) -> Option<&str> {
+    results_url_nif_at(NIF_PT_URL, url)
+}
+
+/// NIF asked by a results page URL of the server at `base_url`, the reverse of
+/// [`results_url_at`].
+pub fn results_url_nif_at<'a>(base_url: &str, url: &'a str) -> Option<&'a str> {
+    url.strip_prefix(base_url.trim_end_matches('/'))?.strip_prefix("/?q=")
+}
+
+/// Status of a lookup whose request to nif.pt got the HTTP status `code`; `None` when the
+/// page must be read to know it.
+pub fn response_status(code: u16) -> Option<NifStatus> {
+    if (200..300).contains(&code) {
+        None
+    } else {
+        Some(NifStatus::HttpError(code))
+    }
+}
+
+/// Score under which a parse needs review, see `ParseConfidence::needs_review`.
+pub const LOW_PARSE_CONFIDENCE: f64 = 0.8;
+
+/// How much of what the parser looks for it found on a page: the selectors of the layout
+/// that matched, and the entity fields an entity page is expected to give.
+///
+/// A page whose markup drifted can still give a status, with an entity missing half of its
+/// details; the score tells such parses from the complete ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParseConfidence {
+    pub layout: Option<&'static str>, // Name of the layout detected
+    pub matched: Vec<&'static str>,   // Selectors and entity fields found, e.g. `entity_marker`, `address`
+    pub missing: Vec<&'static str>,   // Those expected for the status read but not found
+}
+
+impl ParseConfidence {
+    /// Share of the expected selectors and fields found, from 0 to 1.
+    pub fn score(&self) -> f64 {
+        let expected = self.matched.len() + self.missing.len();
+        if expected == 0 {
+            return 0.0;
+        }
+        (self.matched.len() as f64 * 100.0 / expected as f64).round() / 100.0
+    }
+
+    /// Tells whether the score is under `LOW_PARSE_CONFIDENCE`, so the answer should be
+    /// checked by hand before being trusted.
+    pub fn needs_review(&self) -> bool {
+        self.score() < LOW_PARSE_CONFIDENCE
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let names = |names: &[&'static str]| names.iter().map(|name| JsonValue::from(*name)).collect::<Vec<_>>();
+        JsonValue::object()
+            .with("score", self.score())
+            .with("needs_review", self.needs_review())
+            .with("layout", self.layout)
+            .with("matched", names(&self.matched))
+            .with("missing", names(&self.missing))
+    }
+
+    fn check(&mut self, name: &'static str, found: bool) {
+        if found {
+            self.matched.push(name);
+        } else {
+            self.missing.push(name);
+        }
+    }
+}
+
+/// Interprets a results page of nif.pt, with the selectors of the layout it matches.
+pub fn parse_page(body: &str, nif_number: &str) -> (NifStatus, Option<NifEntity>) {
+    let (status, entity, _) = parse_page_confidence(body, nif_number);
+    (status, entity)
+}
+
+/// Interprets a results page like `parse_page`, also telling how confident the parse is.
+pub fn parse_page_confidence(body: &str, nif_number: &str) -> (NifStatus, Option<NifEntity>, ParseConfidence) {
+    let mut confidence = ParseConfidence::default();
+    // Parse the HTML document
+    let document = Html::parse_document(body);
+    let Some(layout) = Layout::detect(&document) else {
+        logging::warn(
+            "unsupported_layout",
+            &[nif_field(nif_number)],
+            format!("The page of NIF {} matches no known nif.pt layout", display_nif(nif_number)),
+        );
+        confidence.check("layout", false);
+        return (NifStatus::UnsupportedLayout, None, confidence);
+    };
+    confidence.layout = Some(layout.name);
+    confidence.check("layout", true);
+
+    // Error message selector
+    if document.select(&selector(layout.error)).next().is_some() {
+        logging::info(
+            "parsed_error",
+            &[nif_field(nif_number)],
+            format!("Found error message for NIF: {}", display_nif(nif_number)),
+        );
+        confidence.check("error", true);
+        return (NifStatus::Error, None, confidence);
+    }
+
+    // Success message selector
+    if let Some(success_div) = document.select(&selector(layout.success)).next() {
+        confidence.check("success", true);
+        let text = success_div.text().collect::<String>();
+        if text.contains(layout.valid_unknown_text) {
+            logging::info(
+                "parsed_valid_unknown",
+                &[nif_field(nif_number)],
+                format!("NIF is valid but entity is unknown: {}", display_nif(nif_number)),
+            );
+            confidence.check("valid_unknown_text", true);
+            return (NifStatus::ValidUnknown, None, confidence);
+        } else {
+            logging::info(
+                "parsed_success",
+                &[nif_field(nif_number)],
+                format!("Found success message for NIF: {}", display_nif(nif_number)),
+            );
+            // Continue to check for known entity below
+        }
+    }
+
+    // Multiple results: company titles inside the result list
+    let company_selector = selector(layout.search_title);
+    if let Some(search_results) = document.select(&selector(layout.search_results)).next()
+        && search_results.select(&company_selector).next().is_some()
+    {
+        logging::info(
+            "parsed_multiple_results",
+            &[nif_field(nif_number)],
+            format!("Found multiple companies for NIF: {}", display_nif(nif_number)),
+        );
+        confidence.check("search_results", true);
+        confidence.check("search_title", true);
+        return (NifStatus::MultipleResults, None, confidence);
+    }
+
+    // Valid and known entity: the NIF heading and a company title
+    if document.select(&selector(layout.entity_marker)).next().is_some() &&
+       document.select(&company_selector).next().is_some() {
+        logging::info(
+            "parsed_valid_known",
+            &[nif_field(nif_number)],
+            format!("Found known entity for NIF: {}", display_nif(nif_number)),
+        );
+        confidence.check("entity_marker", true);
+        confidence.check("search_title", true);
+        let entity = parse_entity(&document, layout, nif_number);
+        confidence.check("detail", document.select(&selector(layout.detail)).next().is_some());
+        let fields = entity.as_ref();
+        confidence.check("name", fields.is_some_and(|entity| !entity.name.is_empty()));
+        confidence.check("address", fields.is_some_and(|entity| entity.address.is_some()));
+        confidence.check("postal_code", fields.is_some_and(|entity| entity.postal_code.is_some()));
+        confidence.check("locality", fields.is_some_and(|entity| entity.locality.is_some()));
+        return (NifStatus::ValidKnown, entity, confidence);
+    }
+
+    // A known layout, but none of the above, check if the page says "NIF não encontrado" or similar
+    logging::warn(
+        "parse_inconclusive",
+        &[nif_field(nif_number), ("layout", layout.name.into())],
+        format!("Could not determine status for NIF: {}", display_nif(nif_number)),
+    );
+    confidence.check("status", false);
+    (NifStatus::Unknown, None, confidence)
+}
+
+/// Extracts the entity details from a nif.pt entity page.
+///
+/// The name is the first company title; the detail block holds one item per line
+/// (street, postal code and locality, phone, email), sometimes with a `Label:` prefix.
+fn parse_entity(document: &Html, layout: &Layout, nif_number: &str) -> Option<NifEntity> {
+    let name = document.select(&selector(layout.search_title)).next()?.text().collect::<String>();
+    let mut entity = NifEntity {
+        nif: nif_number.to_string(),
+        name: name.split_whitespace().collect::<Vec<_>>().join(" "),
+        ..NifEntity::default()
+    };
+
+    let mut street = Vec::new();
+    for detail in document.select(&selector(layout.detail)) {
+        for line in detail.text() {
+            let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+            let (label, value) = match line.split_once(':') {
+                Some((label, value)) if label.len() <= 20 => (label.trim().to_lowercase(), value.trim().to_string()),
+                _ => (String::new(), line.clone()),
+            };
+            if value.is_empty() {
+                continue;
+            }
+            if label.starts_with("tel") {
+                entity.phone.get_or_insert(value);
+            } else if label.starts_with("email") || label.starts_with("e-mail") || value.contains('@') {
+                entity.email.get_or_insert(value);
+            } else if label.starts_with("fax") || label.starts_with("website") || label.starts_with("capital") {
+                continue;
+            } else if let Some((code, locality)) = split_postal_code(&value) {
+                entity.postal_code = Some(code);
+                entity.locality = (!locality.is_empty()).then_some(locality);
+            } else {
+                street.push(value);
+            }
+        }
+    }
+    if !street.is_empty() {
+        entity.address = Some(street.join(", "));
+    }
+    Some(entity)
+}
+
+/// Lists the companies of a page giving `NifStatus::MultipleResults`, in the order shown.
+///
+/// Each company title of the result list is a candidate. Its NIF comes from the link to its
+/// page or from the text around the title, its locality from a postal code line there.
+pub fn parse_candidates(body: &str) -> Vec<EntityCandidate> {
+    let document = Html::parse_document(body);
+    let Some(layout) = Layout::detect(&document) else {
+        return Vec::new();
+    };
+    let Some(search_results) = document.select(&selector(layout.search_results)).next() else {
+        return Vec::new();
+    };
+    let links = selector("a[href]");
+    search_results
+        .select(&selector(layout.search_title))
+        .map(|title| {
+            let href = title.value().attr("href").into_iter().chain(title.select(&links).filter_map(|link| link.value().attr("href")));
+            // The element holding the title and its details, unless the titles sit right in the list
+            let item = title
+                .parent()
+                .and_then(ElementRef::wrap)
+                .filter(|parent| parent.id() != search_results.id());
+            let text: Vec<String> = item
+                .map(|item| item.text().map(|line| line.split_whitespace().collect::<Vec<_>>().join(" ")).collect())
+                .unwrap_or_default();
+            let nif = href
+                .chain(text.iter().map(String::as_str))
+                .flat_map(find_candidates)
+                .find(|candidate| candidate.is_valid())
+                .map(|candidate| candidate.nif);
+            let locality = text
+                .iter()
+                .find_map(|line| split_postal_code(line))
+                .map(|(_, locality)| locality)
+                .filter(|locality| !locality.is_empty());
+            EntityCandidate {
+                name: title.text().collect::<String>().split_whitespace().collect::<Vec<_>>().join(" "),
+                nif,
+                locality,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn results_urls() {
+        assert_eq!(results_url("500960046"), "https://www.nif.pt/?q=500960046");
+        assert_eq!(results_url_nif("https://www.nif.pt/?q=500960046"), Some("500960046"));
+        let url = results_url_at("http://127.0.0.1:8080/", "500960046");
+        assert_eq!(url, "http://127.0.0.1:8080/?q=500960046");
+        assert_eq!(results_url_nif_at("http://127.0.0.1:8080/", &url), Some("500960046"));
+        assert_eq!(results_url_nif_at("http://127.0.0.1:8080", &url), Some("500960046"));
+        assert_eq!(results_url_nif(&url), None);
+        assert_eq!(results_url_nif_at("http://127.0.0.1:8080", "http://127.0.0.1:8080/pesquisa/?q=500960046"), None);
+        assert_eq!(results_url("1&key=x#"), "https://www.nif.pt/?q=1%26key%3Dx%23");
+    }
+
+    #[test]
+    fn http_statuses() {
+        assert_eq!(response_status(200), None);
+        assert_eq!(response_status(429), Some(NifStatus::HttpError(429)));
+        assert_eq!(response_status(503), Some(NifStatus::HttpError(503)));
+    }
+}
@@ -0,0 +1,146 @@
+// page_cache.rs
+
+//! On-disk cache of the raw pages fetched from nif.pt, kept apart from the cache of parsed
+//! results so the pages can be parsed again after the selectors change.
+//!
+//! Bodies are stored by content, under `content/sha256/<2 hex digits>/<62 hex digits>`, so
+//! identical pages share one file; `index.tsv` maps each URL to the digest of its latest
+//! body, one `unix_seconds<TAB>digest<TAB>url` line per fetch, the last line of a URL winning.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A page of the cache: where it came from, when, and the digest of its body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageEntry {
+    pub url: String,
+    pub digest: String, // SHA-256 of the body, in hex
+    pub stored_at: SystemTime,
+}
+
+impl PageEntry {
+    fn encode(&self) -> String {
+        let secs = self.stored_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        format!("{}\t{}\t{}", secs, self.digest, self.url)
+    }
+
+    fn decode(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(3, '\t');
+        let secs = fields.next()?.parse().ok()?;
+        let digest = fields.next()?;
+        let url = fields.next()?;
+        if digest.len() != 64 || !digest.bytes().all(|b| b.is_ascii_hexdigit()) || url.is_empty() {
+            return None;
+        }
+        Some(PageEntry {
+            url: url.to_string(),
+            digest: digest.to_string(),
+            stored_at: UNIX_EPOCH + Duration::from_secs(secs),
+        })
+    }
+}
+
+/// Content-addressed store of page bodies, indexed by URL.
+#[derive(Debug)]
+pub struct PageCache {
+    dir: PathBuf,
+    index: Mutex<()>, // Serializes the appends of this process to the index
+}
+
+impl PageCache {
+    /// Opens (or creates) the page cache in `dir`.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, String> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(dir.join("content"))
+            .map_err(|e| format!("cannot create page cache {}: {}", dir.display(), e))?;
+        Ok(PageCache { dir, index: Mutex::new(()) })
+    }
+
+    /// Directory of the cache.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Stores the body fetched from `url` and returns its entry.
+    pub fn put(&self, url: &str, body: &str) -> Result<PageEntry, String> {
+        let digest = hex_digest(body.as_bytes());
+        let path = self.content_path(&digest);
+        if !path.exists() {
+            self.write_content(&path, body)
+                .map_err(|e| format!("cannot write {}: {}", path.display(), e))?;
+        }
+        let entry = PageEntry {
+            url: url.to_string(),
+            digest,
+            stored_at: SystemTime::now(),
+        };
+        let _guard = self.index.lock().unwrap();
+        let index = self.index_path();
+        let append = || -> std::io::Result<()> {
+            let mut file = OpenOptions::new().create(true).append(true).open(&index)?;
+            writeln!(file, "{}", entry.encode())
+        };
+        append().map_err(|e| format!("cannot write {}: {}", index.display(), e))?;
+        Ok(entry)
+    }
+
+    /// Latest entry of every URL, sorted by URL.
+    pub fn entries(&self) -> Result<Vec<PageEntry>, String> {
+        let index = self.index_path();
+        let mut latest: HashMap<String, PageEntry> = HashMap::new();
+        match File::open(&index) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line.map_err(|e| format!("cannot read {}: {}", index.display(), e))?;
+                    // Skip lines that do not parse, e.g. a write cut short by a crash
+                    if let Some(entry) = PageEntry::decode(&line) {
+                        latest.insert(entry.url.clone(), entry);
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("cannot open {}: {}", index.display(), e)),
+        }
+        let mut entries: Vec<PageEntry> = latest.into_values().collect();
+        entries.sort_by(|a, b| a.url.cmp(&b.url));
+        Ok(entries)
+    }
+
+    /// Reads the body of an entry, checking it still matches its digest.
+    pub fn read(&self, entry: &PageEntry) -> Result<String, String> {
+        let path = self.content_path(&entry.digest);
+        let body = fs::read(&path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        if hex_digest(&body) != entry.digest {
+            return Err(format!("{} is corrupted, its digest does not match", path.display()));
+        }
+        String::from_utf8(body).map_err(|_| format!("{} is not UTF-8", path.display()))
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.dir.join("index.tsv")
+    }
+
+    fn content_path(&self, digest: &str) -> PathBuf {
+        self.dir.join("content").join("sha256").join(&digest[..2]).join(&digest[2..])
+    }
+
+    /// Writes a body next to its final path and renames it, so readers never see half a page.
+    fn write_content(&self, path: &Path, body: &str) -> std::io::Result<()> {
+        let dir = path.parent().expect("content paths have a parent");
+        fs::create_dir_all(dir)?;
+        let tmp = dir.join(format!(".tmp-{:016x}", rand::random::<u64>()));
+        let mut file = File::create(&tmp)?;
+        file.write_all(body.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    }
+}
+
+fn hex_digest(data: &[u8]) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, data);
+    digest.as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+}
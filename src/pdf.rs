@@ -0,0 +1,861 @@
+// pdf.rs
+
+//! Text extraction from PDF files, enough to find the NIFs of invoices and contracts.
+//!
+//! Only text drawn with fonts is seen: scanned documents need OCR first. Fonts are decoded
+//! through their `ToUnicode` map when they have one, as Latin-1 otherwise.
+
+use std::collections::HashMap;
+
+use crate::inflate::zlib_decompress;
+
+/// Largest decoded stream: larger ones, e.g. deflate bombs, are left out like streams with
+/// unsupported filters.
+const MAX_STREAM_LEN: usize = 64 << 20;
+
+/// Extracts the text of each page of a PDF file, in page order.
+pub fn page_texts(data: &[u8]) -> Result<Vec<String>, String> {
+    if !data.windows(5).take(1024).any(|window| window == b"%PDF-") {
+        return Err("not a PDF file".to_string());
+    }
+    if data.windows(8).any(|window| window == b"/Encrypt") {
+        return Err("encrypted PDF files are not supported".to_string());
+    }
+    let document = Document::parse(data);
+    let pages = document.pages();
+    if pages.is_empty() {
+        return Err("no pages found in the PDF file".to_string());
+    }
+    Ok(pages
+        .iter()
+        .map(|(page, resources)| {
+            let mut extractor = TextExtractor::new(&document);
+            extractor.run(&document.contents(page), resources, 0);
+            extractor.text.trim().to_string()
+        })
+        .collect())
+}
+
+/// PDF object. Operators of content streams come out as keywords.
+#[derive(Debug, Clone, PartialEq)]
+enum Object {
+    Null,
+    Number(f64),
+    Name(String),
+    String(Vec<u8>),
+    Array(Vec<Object>),
+    Dict(Vec<(String, Object)>),
+    Ref(u32),        // Object number; generations are not told apart
+    Keyword(String), // `stream`, `R` while parsing, operators in content streams
+}
+
+impl Object {
+    fn get(&self, key: &str) -> Option<&Object> {
+        match self {
+            Object::Dict(entries) => entries.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn as_name(&self) -> Option<&str> {
+        match self {
+            Object::Name(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    fn as_number(&self) -> Option<f64> {
+        match self {
+            Object::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+}
+
+/// Tokenizer and parser of PDF syntax, for file bodies and content streams alike.
+struct Lexer<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+fn is_whitespace(byte: u8) -> bool {
+    matches!(byte, b' ' | b'\t' | b'\r' | b'\n' | b'\x0c' | b'\0')
+}
+
+fn is_delimiter(byte: u8) -> bool {
+    matches!(byte, b'(' | b')' | b'<' | b'>' | b'[' | b']' | b'{' | b'}' | b'/' | b'%')
+}
+
+impl<'a> Lexer<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Lexer { data, pos }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(&byte) = self.data.get(self.pos) {
+            if is_whitespace(byte) {
+                self.pos += 1;
+            } else if byte == b'%' {
+                while self.data.get(self.pos).is_some_and(|&b| b != b'\n' && b != b'\r') {
+                    self.pos += 1;
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Reads a regular token: number, keyword or the text of a name.
+    fn word(&mut self) -> &'a [u8] {
+        let start = self.pos;
+        while self.data.get(self.pos).is_some_and(|&b| !is_whitespace(b) && !is_delimiter(b)) {
+            self.pos += 1;
+        }
+        &self.data[start..self.pos]
+    }
+
+    /// Parses the next object, `None` at the end of the data or on a closing bracket.
+    fn object(&mut self) -> Option<Object> {
+        self.skip_whitespace();
+        let byte = *self.data.get(self.pos)?;
+        match byte {
+            b'/' => {
+                self.pos += 1;
+                Some(Object::Name(decode_name(self.word())))
+            }
+            b'(' => Some(Object::String(self.literal_string())),
+            b'<' if self.data.get(self.pos + 1) == Some(&b'<') => {
+                self.pos += 2;
+                let mut entries = Vec::new();
+                while let Some(key) = self.object() {
+                    let Object::Name(key) = key else {
+                        continue;
+                    };
+                    let value = self.object().unwrap_or(Object::Null);
+                    entries.push((key, value));
+                }
+                self.pos += 2; // `>>`
+                Some(Object::Dict(entries))
+            }
+            b'<' => Some(Object::String(self.hex_string())),
+            b'[' => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                while let Some(item) = self.object() {
+                    items.push(item);
+                }
+                self.pos += 1; // `]`
+                Some(Object::Array(items))
+            }
+            b']' | b'>' => None,
+            b')' | b'{' | b'}' => {
+                self.pos += 1;
+                Some(Object::Null)
+            }
+            _ => {
+                let word = self.word();
+                let text = String::from_utf8_lossy(word).into_owned();
+                if let Ok(number) = text.parse::<f64>() {
+                    // `12 0 R` is a reference
+                    let after = self.pos;
+                    if text.bytes().all(|b| b.is_ascii_digit()) {
+                        self.skip_whitespace();
+                        let generation = self.word();
+                        if !generation.is_empty() && generation.iter().all(u8::is_ascii_digit) {
+                            self.skip_whitespace();
+                            if self.word() == b"R" {
+                                return Some(Object::Ref(number as u32));
+                            }
+                        }
+                    }
+                    self.pos = after;
+                    return Some(Object::Number(number));
+                }
+                Some(match word {
+                    b"null" => Object::Null,
+                    _ => Object::Keyword(text), // `true` and `false` too, never needed
+                })
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut depth = 0;
+        self.pos += 1;
+        while let Some(&byte) = self.data.get(self.pos) {
+            self.pos += 1;
+            match byte {
+                b'(' => {
+                    depth += 1;
+                    out.push(byte);
+                }
+                b')' if depth == 0 => break,
+                b')' => {
+                    depth -= 1;
+                    out.push(byte);
+                }
+                b'\\' => {
+                    let Some(&escaped) = self.data.get(self.pos) else {
+                        break;
+                    };
+                    self.pos += 1;
+                    match escaped {
+                        b'n' => out.push(b'\n'),
+                        b'r' => out.push(b'\r'),
+                        b't' => out.push(b'\t'),
+                        b'b' => out.push(b'\x08'),
+                        b'f' => out.push(b'\x0c'),
+                        b'\r' => {
+                            if self.data.get(self.pos) == Some(&b'\n') {
+                                self.pos += 1;
+                            }
+                        }
+                        b'\n' => {}
+                        b'0'..=b'7' => {
+                            let mut value = u32::from(escaped - b'0');
+                            for _ in 0..2 {
+                                match self.data.get(self.pos) {
+                                    Some(&digit @ b'0'..=b'7') => {
+                                        value = value * 8 + u32::from(digit - b'0');
+                                        self.pos += 1;
+                                    }
+                                    _ => break,
+                                }
+                            }
+                            out.push(value as u8);
+                        }
+                        other => out.push(other),
+                    }
+                }
+                _ => out.push(byte),
+            }
+        }
+        out
+    }
+
+    fn hex_string(&mut self) -> Vec<u8> {
+        self.pos += 1;
+        let start = self.pos;
+        while self.data.get(self.pos).is_some_and(|&b| b != b'>') {
+            self.pos += 1;
+        }
+        let digits = &self.data[start..self.pos];
+        self.pos += 1;
+        decode_hex(digits)
+    }
+
+    /// Skips the data of an inline image, after its `ID` operator.
+    fn skip_inline_image(&mut self) {
+        while self.pos + 2 < self.data.len() {
+            if is_whitespace(self.data[self.pos])
+                && &self.data[self.pos + 1..self.pos + 3] == b"EI"
+                && self.data.get(self.pos + 3).is_none_or(|&b| is_whitespace(b))
+            {
+                self.pos += 3;
+                return;
+            }
+            self.pos += 1;
+        }
+        self.pos = self.data.len();
+    }
+}
+
+/// Decodes the `#xx` escapes of a name.
+fn decode_name(raw: &[u8]) -> String {
+    let mut out = Vec::with_capacity(raw.len());
+    let mut i = 0;
+    while i < raw.len() {
+        if raw[i] == b'#'
+            && let Some(byte) = raw.get(i + 1..i + 3).and_then(|hex| std::str::from_utf8(hex).ok()).and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            out.push(byte);
+            i += 3;
+        } else {
+            out.push(raw[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Decodes hex digits, whitespace aside; an odd last digit is padded with 0.
+fn decode_hex(digits: &[u8]) -> Vec<u8> {
+    let nibbles: Vec<u8> = digits.iter().filter_map(|&b| (b as char).to_digit(16)).map(|d| d as u8).collect();
+    nibbles.chunks(2).map(|pair| pair[0] << 4 | pair.get(1).copied().unwrap_or(0)).collect()
+}
+
+/// Decodes ASCII85 data, up to its `~>` end marker.
+fn decode_ascii85(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut group = Vec::with_capacity(5);
+    let flush = |group: &[u8], out: &mut Vec<u8>| {
+        let mut value: u32 = 0;
+        for i in 0..5 {
+            value = value.wrapping_mul(85).wrapping_add(u32::from(*group.get(i).unwrap_or(&84)));
+        }
+        out.extend_from_slice(&value.to_be_bytes()[..group.len() - 1]);
+    };
+    for &byte in data {
+        match byte {
+            b'~' => break,
+            b'z' if group.is_empty() => out.extend_from_slice(&[0; 4]),
+            b'!'..=b'u' => {
+                group.push(byte - b'!');
+                if group.len() == 5 {
+                    flush(&group, &mut out);
+                    group.clear();
+                }
+            }
+            _ => {}
+        }
+    }
+    if group.len() > 1 {
+        flush(&group, &mut out);
+    }
+    out
+}
+
+/// The objects of a PDF file, read by scanning for `N G obj` so that damaged cross-reference
+/// tables do not matter. Objects defined again by incremental updates take the last value.
+struct Document {
+    objects: HashMap<u32, (Object, Option<Vec<u8>>)>, // Value, and raw data of streams
+}
+
+impl Document {
+    fn parse(data: &[u8]) -> Self {
+        let mut objects = HashMap::new();
+        let mut search = 0;
+        while let Some(found) = find(data, b"obj", search) {
+            search = found + 3;
+            if data.get(found + 3).is_some_and(|&b| !is_whitespace(b) && !is_delimiter(b)) {
+                continue;
+            }
+            let Some(number) = object_number(data, found) else {
+                continue;
+            };
+            let mut lexer = Lexer::new(data, found + 3);
+            let Some(value) = lexer.object() else {
+                continue;
+            };
+            lexer.skip_whitespace();
+            let stream = if data[lexer.pos..].starts_with(b"stream") {
+                stream_data(data, lexer.pos + 6, &value)
+            } else {
+                None
+            };
+            if let Some(raw) = &stream {
+                search = search.max(raw.1);
+            }
+            objects.insert(number, (value, stream.map(|(raw, _)| raw)));
+        }
+        let mut document = Document { objects };
+        document.expand_object_streams();
+        document
+    }
+
+    /// Adds the objects stored compressed in `/Type /ObjStm` streams.
+    fn expand_object_streams(&mut self) {
+        let streams: Vec<u32> = self
+            .objects
+            .iter()
+            .filter(|(_, (value, raw))| raw.is_some() && value.get("Type").and_then(Object::as_name) == Some("ObjStm"))
+            .map(|(number, _)| *number)
+            .collect();
+        for number in streams {
+            let Some(data) = self.stream(number) else {
+                continue;
+            };
+            let dict = &self.objects[&number].0;
+            let count = dict.get("N").and_then(Object::as_number).unwrap_or(0.0) as usize;
+            let first = dict.get("First").and_then(Object::as_number).unwrap_or(0.0) as usize;
+            let mut header = Lexer::new(&data, 0);
+            let mut entries = Vec::new();
+            for _ in 0..count {
+                match (header.object(), header.object()) {
+                    (Some(Object::Number(id)), Some(Object::Number(offset))) => entries.push((id as u32, offset as usize)),
+                    _ => break,
+                }
+            }
+            for (id, offset) in entries {
+                let Some(start) = first.checked_add(offset).filter(|&start| start < data.len()) else {
+                    continue;
+                };
+                if self.objects.contains_key(&id) {
+                    continue;
+                }
+                if let Some(value) = Lexer::new(&data, start).object() {
+                    self.objects.insert(id, (value, None));
+                }
+            }
+        }
+    }
+
+    /// Follows a reference.
+    fn resolve<'b>(&'b self, object: &'b Object) -> &'b Object {
+        match object {
+            Object::Ref(number) => self.objects.get(number).map_or(&Object::Null, |(value, _)| value),
+            other => other,
+        }
+    }
+
+    /// Looks up `key` in a dictionary and follows the reference.
+    fn get<'b>(&'b self, object: &'b Object, key: &str) -> Option<&'b Object> {
+        self.resolve(object).get(key).map(|value| self.resolve(value))
+    }
+
+    /// Decoded data of a stream object; `None` for unsupported filters.
+    fn stream(&self, number: u32) -> Option<Vec<u8>> {
+        let (dict, raw) = self.objects.get(&number)?;
+        let mut data = raw.clone()?;
+        let filters = match dict.get("Filter").map(|filter| self.resolve(filter)) {
+            Some(Object::Array(filters)) => filters.clone(),
+            Some(filter) => vec![filter.clone()],
+            None => Vec::new(),
+        };
+        for filter in filters {
+            data = match self.resolve(&filter).as_name()? {
+                "FlateDecode" | "Fl" => zlib_decompress(&data, MAX_STREAM_LEN).ok()?,
+                "ASCIIHexDecode" | "AHx" => decode_hex(data.split(|&b| b == b'>').next().unwrap_or_default()),
+                "ASCII85Decode" | "A85" => decode_ascii85(&data),
+                _ => return None,
+            };
+        }
+        Some(data)
+    }
+
+    /// Pages in order, each with the resources it inherits.
+    fn pages(&self) -> Vec<(Object, Object)> {
+        let mut pages = Vec::new();
+        let root = self
+            .objects
+            .values()
+            .find(|(value, _)| value.get("Type").and_then(Object::as_name) == Some("Catalog"))
+            .and_then(|(catalog, _)| self.get(catalog, "Pages"));
+        if let Some(root) = root {
+            self.collect_pages(root, &Object::Null, &mut pages, 0);
+        }
+        if pages.is_empty() {
+            // No usable page tree: take the page objects in numbering order
+            let mut numbers: Vec<&u32> = self
+                .objects
+                .iter()
+                .filter(|(_, (value, _))| value.get("Type").and_then(Object::as_name) == Some("Page"))
+                .map(|(number, _)| number)
+                .collect();
+            numbers.sort();
+            for number in numbers {
+                let page = self.objects[number].0.clone();
+                let resources = self.get(&page, "Resources").cloned().unwrap_or(Object::Null);
+                pages.push((page, resources));
+            }
+        }
+        pages
+    }
+
+    fn collect_pages(&self, node: &Object, inherited: &Object, pages: &mut Vec<(Object, Object)>, depth: usize) {
+        if depth > 32 {
+            return;
+        }
+        let resources = self.get(node, "Resources").unwrap_or(inherited);
+        match self.get(node, "Kids") {
+            Some(Object::Array(kids)) => {
+                for kid in kids {
+                    self.collect_pages(self.resolve(kid), resources, pages, depth + 1);
+                }
+            }
+            _ => pages.push((node.clone(), resources.clone())),
+        }
+    }
+
+    /// Decoded content of a page, its streams joined as they may split an operator.
+    fn contents(&self, page: &Object) -> Vec<u8> {
+        let references = match page.get("Contents") {
+            Some(Object::Array(items)) => items.clone(),
+            Some(Object::Ref(number)) => match self.objects.get(number) {
+                Some((Object::Array(items), None)) => items.clone(),
+                _ => vec![Object::Ref(*number)],
+            },
+            _ => Vec::new(),
+        };
+        let mut joined = Vec::new();
+        for reference in references {
+            if let Object::Ref(number) = reference
+                && let Some(data) = self.stream(number)
+            {
+                joined.extend_from_slice(&data);
+                joined.push(b'\n');
+            }
+        }
+        joined
+    }
+
+    /// Decoded data of the stream `object` refers to.
+    fn stream_of(&self, object: &Object) -> Option<Vec<u8>> {
+        match object {
+            Object::Ref(number) => self.stream(*number),
+            _ => None,
+        }
+    }
+}
+
+/// Finds `needle` in `data` from `start`.
+fn find(data: &[u8], needle: &[u8], start: usize) -> Option<usize> {
+    data.get(start..)?.windows(needle.len()).position(|window| window == needle).map(|i| i + start)
+}
+
+/// Reads the object number of the `N G obj` whose keyword is at `pos`.
+fn object_number(data: &[u8], pos: usize) -> Option<u32> {
+    let mut end = pos;
+    let mut number = None;
+    for _ in 0..2 {
+        let spaces = data[..end].iter().rev().take_while(|&&b| is_whitespace(b)).count();
+        let digits = data[..end - spaces].iter().rev().take_while(|b| b.is_ascii_digit()).count();
+        if spaces == 0 || digits == 0 {
+            return None;
+        }
+        end -= spaces + digits;
+        number = std::str::from_utf8(&data[end..end + digits]).ok()?.parse().ok();
+    }
+    number
+}
+
+/// Finds the raw data of a stream starting after the `stream` keyword at `start`; returns
+/// it with the position past `endstream`.
+fn stream_data(data: &[u8], start: usize, dict: &Object) -> Option<(Vec<u8>, usize)> {
+    let start = match data.get(start..start + 2)? {
+        [b'\r', b'\n'] => start + 2,
+        [b'\n' | b'\r', _] => start + 1,
+        _ => start,
+    };
+    // `/Length` when it is direct and right, else the next `endstream`
+    if let Some(length) = dict.get("Length").and_then(Object::as_number) {
+        let end = start.checked_add(length as usize).map_or(data.len(), |end| end.min(data.len()));
+        if let Some(rest) = data.get(end..) {
+            let skipped = rest.iter().take_while(|&&b| is_whitespace(b)).count();
+            if rest[skipped..].starts_with(b"endstream") {
+                return Some((data[start..end].to_vec(), end + skipped + 9));
+            }
+        }
+    }
+    let end = find(data, b"endstream", start)?;
+    let mut trimmed = end;
+    while trimmed > start && matches!(data[trimmed - 1], b'\r' | b'\n') {
+        trimmed -= 1;
+    }
+    Some((data[start..trimmed].to_vec(), end + 9))
+}
+
+/// Maps the character codes of a font to text.
+#[derive(Default)]
+struct FontMap {
+    code_len: usize,                   // Bytes per character code
+    chars: HashMap<u32, String>,       // `bfchar` entries
+    ranges: Vec<(u32, u32, Vec<u16>)>, // `bfrange` entries with a start value
+    arrays: Vec<(u32, Vec<String>)>,   // `bfrange` entries with one value per code
+    has_map: bool,                     // A `ToUnicode` map was read
+}
+
+impl FontMap {
+    fn new(document: &Document, font: &Object) -> Self {
+        let two_bytes = document.get(font, "Subtype").and_then(Object::as_name) == Some("Type0");
+        let mut map = FontMap {
+            code_len: if two_bytes { 2 } else { 1 },
+            ..FontMap::default()
+        };
+        if let Some(data) = document.resolve(font).get("ToUnicode").and_then(|cmap| document.stream_of(cmap)) {
+            map.read_cmap(&data);
+        }
+        map
+    }
+
+    fn read_cmap(&mut self, data: &[u8]) {
+        let mut lexer = Lexer::new(data, 0);
+        let code = |bytes: &[u8]| bytes.iter().fold(0u32, |acc, &b| acc << 8 | u32::from(b));
+        let mut ops: Vec<Object> = Vec::new();
+        while lexer.pos < data.len() {
+            let Some(object) = lexer.object() else {
+                lexer.pos += 1;
+                continue;
+            };
+            let Object::Keyword(keyword) = &object else {
+                ops.push(object);
+                continue;
+            };
+            match keyword.as_str() {
+                "endcodespacerange" => {
+                    if let Some(Object::String(low)) = ops.first()
+                        && !low.is_empty()
+                    {
+                        self.code_len = low.len();
+                    }
+                }
+                "endbfchar" => {
+                    for pair in ops.chunks(2) {
+                        if let [Object::String(source), Object::String(target)] = pair {
+                            self.chars.insert(code(source), utf16(target));
+                            self.has_map = true;
+                        }
+                    }
+                }
+                "endbfrange" => {
+                    for triple in ops.chunks(3) {
+                        match triple {
+                            [Object::String(low), Object::String(high), Object::String(target)] => {
+                                let units = target.chunks(2).map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])).collect();
+                                self.ranges.push((code(low), code(high), units));
+                                self.has_map = true;
+                            }
+                            [Object::String(low), Object::String(_), Object::Array(targets)] => {
+                                let texts = targets
+                                    .iter()
+                                    .map(|target| match target {
+                                        Object::String(target) => utf16(target),
+                                        _ => String::new(),
+                                    })
+                                    .collect();
+                                self.arrays.push((code(low), texts));
+                                self.has_map = true;
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+            ops.clear();
+        }
+    }
+
+    fn decode(&self, bytes: &[u8]) -> String {
+        if !self.has_map {
+            // Single-byte fonts are close enough to Latin-1 for digits and letters; codes of
+            // two-byte fonts mean nothing without a map
+            return if self.code_len == 1 { bytes.iter().map(|&b| b as char).collect() } else { String::new() };
+        }
+        let mut out = String::new();
+        for chunk in bytes.chunks(self.code_len) {
+            let code = chunk.iter().fold(0u32, |acc, &b| acc << 8 | u32::from(b));
+            if let Some(text) = self.chars.get(&code) {
+                out += text;
+            } else if let Some((low, _, units)) = self.ranges.iter().find(|(low, high, _)| (*low..=*high).contains(&code)) {
+                let mut units = units.clone();
+                if let Some(last) = units.last_mut() {
+                    *last = last.wrapping_add((code - low) as u16);
+                }
+                out += &String::from_utf16_lossy(&units);
+            } else if let Some(text) = self.arrays.iter().find_map(|(low, texts)| texts.get(code.checked_sub(*low)? as usize)) {
+                out += text;
+            } else if self.code_len == 1 {
+                out.push(code as u8 as char);
+            }
+        }
+        out
+    }
+}
+
+/// Decodes UTF-16BE, as found in `ToUnicode` maps.
+fn utf16(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes.chunks(2).map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])).collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// Runs content streams, keeping the text they show and where lines break.
+struct TextExtractor<'a> {
+    document: &'a Document,
+    fonts: HashMap<String, FontMap>, // By font resource name, for the current resources
+    font: Option<String>,
+    font_size: f64,
+    scale: f64,        // Of the text matrix
+    origin: (f64, f64), // Of the current line, in user space
+    text: String,
+}
+
+impl<'a> TextExtractor<'a> {
+    fn new(document: &'a Document) -> Self {
+        TextExtractor {
+            document,
+            fonts: HashMap::new(),
+            font: None,
+            font_size: 1.0,
+            scale: 1.0,
+            origin: (0.0, 0.0),
+            text: String::new(),
+        }
+    }
+
+    /// Adds a space or line break, unless the text already ends with one.
+    fn separate(&mut self, separator: char) {
+        match self.text.chars().next_back() {
+            None | Some('\n') => {}
+            Some(' ') if separator == '\n' => {
+                self.text.pop();
+                self.text.push('\n');
+            }
+            Some(' ') => {}
+            Some(_) => self.text.push(separator),
+        }
+    }
+
+    /// Moves the line origin, breaking the text as the move suggests: a new line when it
+    /// goes up or down, a space for a gap wider than a character, nothing for the moves of
+    /// documents that place each glyph on its own.
+    fn move_to(&mut self, x: f64, y: f64) {
+        let (dx, dy) = (x - self.origin.0, y - self.origin.1);
+        let em = (self.font_size * self.scale).abs().max(f64::EPSILON);
+        if dy.abs() > em * 0.3 {
+            self.separate('\n');
+        } else if dx > em || dx < 0.0 {
+            self.separate(' ');
+        }
+        self.origin = (x, y);
+    }
+
+    fn show(&mut self, bytes: &[u8], resources: &Object) {
+        let Some(font) = self.font.clone() else {
+            self.text.extend(bytes.iter().map(|&b| b as char));
+            return;
+        };
+        if !self.fonts.contains_key(&font) {
+            let object = self
+                .document
+                .get(resources, "Font")
+                .and_then(|fonts| self.document.get(fonts, &font))
+                .cloned()
+                .unwrap_or(Object::Null);
+            self.fonts.insert(font.clone(), FontMap::new(self.document, &object));
+        }
+        let decoded = self.fonts[&font].decode(bytes);
+        self.text += &decoded;
+    }
+
+    fn run(&mut self, content: &[u8], resources: &Object, depth: usize) {
+        let saved_fonts = std::mem::take(&mut self.fonts);
+        let mut lexer = Lexer::new(content, 0);
+        let mut operands: Vec<Object> = Vec::new();
+        while lexer.pos < content.len() {
+            let Some(object) = lexer.object() else {
+                lexer.pos += 1;
+                continue;
+            };
+            let Object::Keyword(operator) = object else {
+                operands.push(object);
+                continue;
+            };
+            let number = |i: usize| operands.get(i).and_then(Object::as_number).unwrap_or(0.0);
+            match operator.as_str() {
+                "BT" => {
+                    self.scale = 1.0;
+                    self.origin = (0.0, 0.0);
+                }
+                "ET" => self.separate('\n'),
+                "Tf" => {
+                    self.font = operands.first().and_then(Object::as_name).map(str::to_string);
+                    self.font_size = number(1);
+                }
+                "Td" | "TD" => {
+                    let (x, y) = (self.origin.0 + number(0) * self.scale, self.origin.1 + number(1) * self.scale);
+                    self.move_to(x, y);
+                }
+                "Tm" => {
+                    self.scale = number(0).hypot(number(1));
+                    self.move_to(number(4), number(5));
+                }
+                "T*" => self.separate('\n'),
+                "Tj" | "'" | "\"" => {
+                    if operator != "Tj" {
+                        self.separate('\n');
+                    }
+                    if let Some(Object::String(bytes)) = operands.last() {
+                        let bytes = bytes.clone();
+                        self.show(&bytes, resources);
+                    }
+                }
+                "TJ" => {
+                    if let Some(Object::Array(items)) = operands.last() {
+                        for item in items.clone() {
+                            match item {
+                                Object::String(bytes) => self.show(&bytes, resources),
+                                // Adjustments are in thousandths of an em; a wide one is a space
+                                Object::Number(adjust) if adjust < -250.0 => self.separate(' '),
+                                _ => {}
+                            }
+                        }
+                    }
+                }
+                "Do" if depth < 8 => {
+                    let form = operands.first().and_then(Object::as_name).and_then(|name| {
+                        let xobjects = self.document.get(resources, "XObject")?;
+                        match self.document.resolve(xobjects).get(name)? {
+                            Object::Ref(number) => Some(*number),
+                            _ => None,
+                        }
+                    });
+                    if let Some(number) = form
+                        && let Some((dict, _)) = self.document.objects.get(&number)
+                        && dict.get("Subtype").and_then(Object::as_name) == Some("Form")
+                        && let Some(data) = self.document.stream(number)
+                    {
+                        let form_resources = self.document.get(dict, "Resources").cloned().unwrap_or_else(|| resources.clone());
+                        self.run(&data, &form_resources, depth + 1);
+                    }
+                }
+                "ID" => lexer.skip_inline_image(),
+                _ => {}
+            }
+            operands.clear();
+        }
+        self.fonts = saved_fonts;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A one-page PDF file drawing `content` with a Latin-1 font; `length` is the `/Length`
+    /// of the content stream as written.
+    fn pdf(content: &[u8], length: &str, filter: &str) -> Vec<u8> {
+        let mut data = b"%PDF-1.4\n1 0 obj\n<< /Type /Catalog /Pages 2 0 R >>\nendobj\n".to_vec();
+        data.extend_from_slice(b"2 0 obj\n<< /Type /Pages /Kids [3 0 R] /Count 1 >>\nendobj\n");
+        data.extend_from_slice(b"3 0 obj\n<< /Type /Page /Parent 2 0 R /Contents 4 0 R /Resources << /Font << /F1 5 0 R >> >> >>\nendobj\n");
+        data.extend_from_slice(format!("4 0 obj\n<< /Length {}{} >>\nstream\n", length, filter).as_bytes());
+        data.extend_from_slice(content);
+        data.extend_from_slice(b"\nendstream\nendobj\n");
+        data.extend_from_slice(b"5 0 obj\n<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica >>\nendobj\n%%EOF\n");
+        data
+    }
+
+    const CONTENT: &[u8] = b"BT /F1 12 Tf 72 700 Td (Fornecedor: NIF 500960046) Tj ET";
+
+    #[test]
+    fn page_text() {
+        let texts = page_texts(&pdf(CONTENT, &CONTENT.len().to_string(), "")).unwrap();
+        assert_eq!(texts, ["Fornecedor: NIF 500960046"]);
+    }
+
+    #[test]
+    fn wrong_length() {
+        // Lengths past the end of the file, negative or too large for a usize fall back to
+        // the next `endstream`
+        for length in ["18446744073709551615", "1e300", "99999", "-5", "3"] {
+            let texts = page_texts(&pdf(CONTENT, length, "")).unwrap();
+            assert_eq!(texts, ["Fornecedor: NIF 500960046"], "/Length {}", length);
+        }
+    }
+
+    #[test]
+    fn deflate_bomb() {
+        // About 100 MB of zeros, past `MAX_STREAM_LEN`
+        let mut stream = vec![0x78, 0x9c];
+        stream.extend(crate::inflate::tests::zeros(400_000));
+        assert!(crate::inflate::zlib_decompress(&stream, MAX_STREAM_LEN).unwrap_err().contains("more than"));
+        let texts = page_texts(&pdf(&stream, &stream.len().to_string(), " /Filter /FlateDecode")).unwrap();
+        assert_eq!(texts, [""]);
+    }
+
+    #[test]
+    fn not_a_pdf() {
+        assert!(page_texts(b"plain text").is_err());
+    }
+}
@@ -0,0 +1,155 @@
+// pipeline.rs
+
+use std::io::{BufRead, Write};
+
+use crate::json::JsonValue;
+use crate::logging;
+use crate::lookup::{lookup_nif, LookupOptions};
+use crate::validation::{is_nif_valid_local, normalize_nif};
+
+/// One step of a `JsonPath`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathStep {
+    Field(String), // `.name` or `['name']`
+    Index(usize),  // `[0]`
+}
+
+/// A JSONPath restricted to a single value: `$.supplier.nif`, `$.lines[0].vat`,
+/// `$['tax id']`. The leading `$` is optional.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonPath {
+    text: String,
+    steps: Vec<PathStep>,
+}
+
+impl JsonPath {
+    /// Parses a path; wildcards, filters and recursive descent are not supported.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let invalid = |reason: &str| format!("invalid JSON path '{}': {}", text, reason);
+        let body = text.trim().strip_prefix('$').unwrap_or(text.trim());
+        // A bare `supplier.nif` is read as `$.supplier.nif`
+        let dotted = format!(".{}", body);
+        let mut rest = if body.is_empty() || body.starts_with(['.', '[']) { body } else { &dotted };
+        let mut steps = Vec::new();
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix('.') {
+                let end = after.find(['.', '[']).unwrap_or(after.len());
+                let name = &after[..end];
+                if name.is_empty() || name == "*" {
+                    return Err(invalid("expected a field name after '.'"));
+                }
+                steps.push(PathStep::Field(name.to_string()));
+                rest = &after[end..];
+            } else if let Some(after) = rest.strip_prefix("['") {
+                let end = after.find("']").ok_or_else(|| invalid("unclosed ['...']"))?;
+                steps.push(PathStep::Field(after[..end].to_string()));
+                rest = &after[end + 2..];
+            } else if let Some(after) = rest.strip_prefix('[') {
+                let end = after.find(']').ok_or_else(|| invalid("unclosed [...]"))?;
+                let index = after[..end].parse().map_err(|_| invalid("expected an array index in [...]"))?;
+                steps.push(PathStep::Index(index));
+                rest = &after[end + 1..];
+            } else {
+                return Err(invalid("expected '.' or '['"));
+            }
+        }
+        if steps.is_empty() {
+            return Err(invalid("the path selects the whole record"));
+        }
+        Ok(JsonPath {
+            text: text.to_string(),
+            steps,
+        })
+    }
+
+    /// Returns the value at this path in `value`.
+    pub fn get<'a>(&self, value: &'a JsonValue) -> Option<&'a JsonValue> {
+        self.steps.iter().try_fold(value, |current, step| match step {
+            PathStep::Field(name) => current.get(name),
+            PathStep::Index(index) => current.as_array()?.get(*index),
+        })
+    }
+}
+
+impl std::fmt::Display for JsonPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// Settings of `run_pipeline`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PipelineConfig {
+    pub nif_path: JsonPath,   // Where the NIF is in each record
+    pub output_field: String, // Top-level field receiving the result
+    pub validate_only: bool,  // Local validation only, no lookup
+}
+
+/// Counters of a pipeline run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PipelineStats {
+    pub records: usize,        // Records written out
+    pub missing: usize,        // Records without a NIF at the path
+    pub passed_through: usize, // Input lines that are not JSON objects, written out unchanged
+}
+
+/// Reads the NIF of a record: a string, or a number restored to 9 digits.
+fn nif_of(value: &JsonValue) -> Option<String> {
+    match value {
+        JsonValue::String(nif) => Some(normalize_nif(nif).to_string()).filter(|nif| !nif.is_empty()),
+        JsonValue::Int(nif) if *nif >= 0 => Some(format!("{:09}", nif)),
+        _ => None,
+    }
+}
+
+/// Reads NDJSON records from `input` and writes each one to `output` with the check of its
+/// NIF added as `output_field`, one line at a time so it can sit inside a streaming ETL.
+///
+/// Records without a NIF get `{"error": ...}` in that field. Lines that are not JSON
+/// objects are logged and written out unchanged, so nothing is lost downstream; blank lines
+/// are ignored.
+pub fn run_pipeline(
+    input: impl BufRead,
+    mut output: impl Write,
+    config: &PipelineConfig,
+    options: &LookupOptions,
+) -> Result<PipelineStats, String> {
+    let mut stats = PipelineStats::default();
+    for (index, line) in input.lines().enumerate() {
+        let line = line.map_err(|e| format!("cannot read input: {}", e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut record = match JsonValue::parse(&line) {
+            Ok(record @ JsonValue::Object(_)) => record,
+            Ok(_) | Err(_) => {
+                stats.passed_through += 1;
+                logging::warn(
+                    "pipeline_invalid_record",
+                    &[("line", (index as i64 + 1).into())],
+                    format!("Line {}: not a JSON object, passed through unchanged", index + 1),
+                );
+                writeln!(output, "{}", line)
+                    .and_then(|_| output.flush())
+                    .map_err(|e| format!("cannot write output: {}", e))?;
+                continue;
+            }
+        };
+        let check = match config.nif_path.get(&record).and_then(nif_of) {
+            None => {
+                stats.missing += 1;
+                JsonValue::object().with("error", format!("no NIF at {}", config.nif_path))
+            }
+            Some(nif) if config.validate_only => JsonValue::object()
+                .with("nif", nif.as_str())
+                .with("valid_locally", is_nif_valid_local(&nif)),
+            Some(nif) => lookup_nif(&nif, options).to_json(),
+        };
+        record.set(&config.output_field, check);
+        writeln!(output, "{}", record)
+            .and_then(|_| output.flush())
+            .map_err(|e| format!("cannot write output: {}", e))?;
+        stats.records += 1;
+    }
+    Ok(stats)
+}
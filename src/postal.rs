@@ -0,0 +1,294 @@
+// postal.rs
+
+//! Portuguese postal codes (`NNNN-NNN`): their format, the postal locality (the CTT
+//! "designação postal") and the municipality each one belongs to, to flag entity records
+//! whose postal code and locality do not go together and to place entities on the map of
+//! districts and municipalities.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::entity::NifEntity;
+use crate::names::strip_accents;
+use crate::json::JsonValue;
+
+/// Range of four-digit postal codes (CP4) whose addresses all share one postal locality.
+struct PostalArea {
+    first: u16,
+    last: u16,
+    locality: &'static str,     // As CTT writes it
+    municipality: &'static str, // DICO code, the district code then the municipality number
+}
+
+/// The areas of the district capitals and the larger towns, checked without the CTT file.
+/// Codes outside them are not checked; narrow ranges keep a town from claiming the codes
+/// of its neighbours.
+const POSTAL_AREAS: &[PostalArea] = &[
+    area(1000, 1449, "LISBOA", "1106"),
+    area(1500, 1649, "LISBOA", "1106"),
+    area(1700, 1849, "LISBOA", "1106"),
+    area(1900, 1999, "LISBOA", "1106"),
+    area(2000, 2005, "SANTARÉM", "1416"),
+    area(2300, 2304, "TOMAR", "1418"),
+    area(2400, 2414, "LEIRIA", "1009"),
+    area(2500, 2504, "CALDAS DA RAINHA", "1005"),
+    area(2560, 2564, "TORRES VEDRAS", "1113"),
+    area(2600, 2604, "VILA FRANCA DE XIRA", "1114"),
+    area(2640, 2644, "MAFRA", "1109"),
+    area(2670, 2674, "LOURES", "1107"),
+    area(2700, 2704, "AMADORA", "1115"),
+    area(2710, 2714, "SINTRA", "1111"),
+    area(2750, 2754, "CASCAIS", "1105"),
+    area(2780, 2784, "OEIRAS", "1110"),
+    area(2800, 2809, "ALMADA", "1503"),
+    area(2830, 2834, "BARREIRO", "1504"),
+    area(2900, 2914, "SETÚBAL", "1512"),
+    area(3000, 3049, "COIMBRA", "0603"),
+    area(3080, 3084, "FIGUEIRA DA FOZ", "0605"),
+    area(3500, 3514, "VISEU", "1823"),
+    area(3700, 3704, "SÃO JOÃO DA MADEIRA", "0116"),
+    area(3800, 3814, "AVEIRO", "0105"),
+    area(4000, 4369, "PORTO", "1312"),
+    area(4400, 4404, "VILA NOVA DE GAIA", "1317"),
+    area(4420, 4424, "GONDOMAR", "1304"),
+    area(4430, 4434, "VILA NOVA DE GAIA", "1317"),
+    area(4440, 4444, "VALONGO", "1315"),
+    area(4450, 4454, "MATOSINHOS", "1308"),
+    area(4470, 4479, "MAIA", "1306"),
+    area(4480, 4484, "VILA DO CONDE", "1316"),
+    area(4490, 4494, "PÓVOA DE VARZIM", "1313"),
+    area(4700, 4719, "BRAGA", "0303"),
+    area(4750, 4754, "BARCELOS", "0302"),
+    area(4760, 4764, "VILA NOVA DE FAMALICÃO", "0312"),
+    area(4800, 4814, "GUIMARÃES", "0308"),
+    area(4900, 4904, "VIANA DO CASTELO", "1609"),
+    area(5000, 5004, "VILA REAL", "1714"),
+    area(5300, 5301, "BRAGANÇA", "0402"),
+    area(6000, 6004, "CASTELO BRANCO", "0502"),
+    area(6300, 6304, "GUARDA", "0907"),
+    area(7000, 7009, "ÉVORA", "0705"),
+    area(7300, 7304, "PORTALEGRE", "1214"),
+    area(7800, 7804, "BEJA", "0205"),
+    area(8000, 8009, "FARO", "0805"),
+    area(8200, 8204, "ALBUFEIRA", "0801"),
+    area(8500, 8504, "PORTIMÃO", "0811"),
+    area(8600, 8604, "LAGOS", "0807"),
+    area(8700, 8704, "OLHÃO", "0810"),
+    area(8800, 8804, "TAVIRA", "0814"),
+    area(9000, 9064, "FUNCHAL", "3103"),
+    area(9500, 9504, "PONTA DELGADA", "4203"),
+    area(9700, 9704, "ANGRA DO HEROÍSMO", "4301"),
+];
+
+const fn area(first: u16, last: u16, locality: &'static str, municipality: &'static str) -> PostalArea {
+    PostalArea {
+        first,
+        last,
+        locality,
+        municipality,
+    }
+}
+
+/// Districts of the mainland and islands of the autonomous regions, by code, as in the
+/// first two digits of the municipality codes.
+const DISTRICTS: &[(&str, &str)] = &[
+    ("01", "Aveiro"),
+    ("02", "Beja"),
+    ("03", "Braga"),
+    ("04", "Bragança"),
+    ("05", "Castelo Branco"),
+    ("06", "Coimbra"),
+    ("07", "Évora"),
+    ("08", "Faro"),
+    ("09", "Guarda"),
+    ("10", "Leiria"),
+    ("11", "Lisboa"),
+    ("12", "Portalegre"),
+    ("13", "Porto"),
+    ("14", "Santarém"),
+    ("15", "Setúbal"),
+    ("16", "Viana do Castelo"),
+    ("17", "Vila Real"),
+    ("18", "Viseu"),
+    ("31", "Ilha da Madeira"),
+    ("32", "Ilha de Porto Santo"),
+    ("41", "Ilha de Santa Maria"),
+    ("42", "Ilha de São Miguel"),
+    ("43", "Ilha Terceira"),
+    ("44", "Ilha da Graciosa"),
+    ("45", "Ilha de São Jorge"),
+    ("46", "Ilha do Pico"),
+    ("47", "Ilha do Faial"),
+    ("48", "Ilha das Flores"),
+    ("49", "Ilha do Corvo"),
+];
+
+/// Name of a district (or island) code, e.g. `Lisboa` for `11`.
+pub fn district_name(code: &str) -> Option<&'static str> {
+    DISTRICTS.iter().find(|(number, _)| *number == code).map(|(_, name)| *name)
+}
+
+/// Tells whether `code` is written as a Portuguese postal code, `NNNN-NNN` with a first
+/// digit from 1 to 9.
+pub fn is_postal_code(code: &str) -> bool {
+    let bytes = code.as_bytes();
+    bytes.len() == 8
+        && (b'1'..=b'9').contains(&bytes[0])
+        && bytes[1..4].iter().all(u8::is_ascii_digit)
+        && bytes[4] == b'-'
+        && bytes[5..].iter().all(u8::is_ascii_digit)
+}
+
+/// Outcome of checking the postal code of an entity against its locality.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PostalCheck {
+    Consistent,
+    Malformed,                             // Not `NNNN-NNN`
+    UnknownCode,                           // Missing from the CTT file
+    LocalityMismatch { expected: String }, // The code belongs to another postal locality
+}
+
+impl PostalCheck {
+    pub fn label(&self) -> &'static str {
+        match self {
+            PostalCheck::Consistent => "consistent",
+            PostalCheck::Malformed => "malformed",
+            PostalCheck::UnknownCode => "unknown_code",
+            PostalCheck::LocalityMismatch { .. } => "locality_mismatch",
+        }
+    }
+
+    /// Serializes the outcome, with the expected locality for `LocalityMismatch`.
+    pub fn to_json(&self) -> JsonValue {
+        let json = JsonValue::object().with("status", self.label());
+        match self {
+            PostalCheck::LocalityMismatch { expected } => json.with("expected", expected.as_str()),
+            _ => json,
+        }
+    }
+}
+
+/// What the CTT file tells about one postal code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PostalCode {
+    locality: String,
+    municipality: String, // DICO code
+}
+
+/// Postal codes to check and place entities with: the embedded areas, or every code of the
+/// CTT file once loaded.
+#[derive(Debug, Clone, Default)]
+pub struct PostalTable {
+    codes: Option<HashMap<String, PostalCode>>, // By `NNNN-NNN`, from the CTT file
+}
+
+/// The table of the embedded areas, placing entities when no table is configured.
+pub static EMBEDDED: PostalTable = PostalTable { codes: None };
+
+impl PostalTable {
+    /// Table of the embedded areas only.
+    pub fn embedded() -> Self {
+        Self::default()
+    }
+
+    /// Loads `todos_cp.txt`, the list of every postal code published by CTT: lines of
+    /// `;`-separated fields starting with the district and municipality codes and ending with
+    /// CP4, CP3 and the postal locality. Files in Latin-1, as CTT used to publish them, are
+    /// read too.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let bytes = fs::read(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        let text = String::from_utf8(bytes).unwrap_or_else(|e| e.into_bytes().iter().map(|&b| char::from(b)).collect());
+        let mut codes = HashMap::new();
+        for (index, line) in text.lines().enumerate() {
+            let fields: Vec<&str> = line.trim_end_matches('\r').split(';').map(str::trim).collect();
+            if fields.len() < 5 {
+                continue;
+            }
+            let [district, number] = [fields[0], fields[1]];
+            let [cp4, cp3, locality] = [fields.len() - 3, fields.len() - 2, fields.len() - 1].map(|i| fields[i]);
+            let code = format!("{}-{}", cp4, cp3);
+            let two_digits = |field: &str| field.len() == 2 && field.bytes().all(|b| b.is_ascii_digit());
+            if !is_postal_code(&code) || locality.is_empty() || !two_digits(district) || !two_digits(number) {
+                if index == 0 {
+                    continue; // A header line
+                }
+                return Err(format!(
+                    "{}:{}: expected a line starting with DD;CC and ending with CP4;CP3;locality",
+                    path.display(),
+                    index + 1
+                ));
+            }
+            let postal_code = PostalCode {
+                locality: locality.to_string(),
+                municipality: format!("{}{}", district, number),
+            };
+            codes.insert(code, postal_code);
+        }
+        if codes.is_empty() {
+            return Err(format!("{}: no postal code found", path.display()));
+        }
+        Ok(PostalTable { codes: Some(codes) })
+    }
+
+    /// Postal locality of `code`; `None` when the table does not know it.
+    pub fn locality(&self, code: &str) -> Option<&str> {
+        match &self.codes {
+            Some(codes) => codes.get(code).map(|postal_code| postal_code.locality.as_str()),
+            None => embedded_area(code).map(|area| area.locality),
+        }
+    }
+
+    /// Municipality (DICO code, e.g. `1106` for Lisboa) of `code`; `None` when the table does
+    /// not know it. Its first two digits are the district.
+    pub fn municipality(&self, code: &str) -> Option<&str> {
+        match &self.codes {
+            Some(codes) => codes.get(code).map(|postal_code| postal_code.municipality.as_str()),
+            None => embedded_area(code).map(|area| area.municipality),
+        }
+    }
+
+    /// Fills the district and municipality codes of `entity` from its postal code, when it
+    /// has none yet and the table knows the code.
+    pub fn place(&self, entity: &mut NifEntity) {
+        if entity.municipality.is_some() {
+            return;
+        }
+        let Some(municipality) = entity.postal_code.as_deref().and_then(|code| self.municipality(code)) else {
+            return;
+        };
+        entity.district = Some(municipality[..2].to_string());
+        entity.municipality = Some(municipality.to_string());
+    }
+
+    /// Checks the postal code of `entity` against its locality; `None` when the entity has no
+    /// postal code, or one outside the embedded areas.
+    pub fn check(&self, entity: &NifEntity) -> Option<PostalCheck> {
+        let code = entity.postal_code.as_deref()?;
+        if !is_postal_code(code) {
+            return Some(PostalCheck::Malformed);
+        }
+        let Some(expected) = self.locality(code) else {
+            return self.codes.is_some().then_some(PostalCheck::UnknownCode);
+        };
+        match &entity.locality {
+            Some(locality) if fold(locality) != fold(expected) => Some(PostalCheck::LocalityMismatch {
+                expected: expected.to_string(),
+            }),
+            _ => Some(PostalCheck::Consistent),
+        }
+    }
+}
+
+fn embedded_area(code: &str) -> Option<&'static PostalArea> {
+    let cp4: u16 = code.get(..4)?.parse().ok()?;
+    POSTAL_AREAS.iter().find(|area| (area.first..=area.last).contains(&cp4))
+}
+
+/// Form of a locality name compared by `check`: upper case, without accents, with hyphens
+/// and runs of spaces as single spaces (`Póvoa-de-Varzim` is `POVOA DE VARZIM`).
+fn fold(locality: &str) -> String {
+    let plain = strip_accents(locality).to_ascii_uppercase().replace('-', " ");
+    plain.split_whitespace().collect::<Vec<_>>().join(" ")
+}
@@ -0,0 +1,128 @@
+// progress.rs
+
+//! Progress of a batch run on stderr, for operators watching it (`text`) or for wrapper UIs
+//! and orchestrators (`json`, one event object per line).
+
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::json::JsonValue;
+use crate::lookup::LookupResult;
+use crate::output::OutputWriter;
+use crate::time::format_rfc3339;
+
+/// Time between two progress events when nothing else is configured.
+pub const DEFAULT_PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How progress events are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressFormat {
+    Text, // `Progress: 120/500 NIFs (24.0/min, ETA 15m 50s)`
+    Json, // `{"event": "progress", "processed": 120, "remaining": 380, ...}`
+}
+
+impl ProgressFormat {
+    /// Parses a `--progress-format` value.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "text" => Ok(ProgressFormat::Text),
+            "json" => Ok(ProgressFormat::Json),
+            other => Err(format!("invalid progress format '{}', expected text or json", other)),
+        }
+    }
+}
+
+/// Output writer reporting how far the run got, at most once per interval and when it ends.
+pub struct ProgressWriter {
+    format: ProgressFormat,
+    interval: Duration,
+    total: usize,
+    processed: usize,
+    failed: usize, // Lookups that got no definitive answer
+    started: Instant,
+    last_event: Instant,
+}
+
+impl ProgressWriter {
+    /// Reports the progress over `total` NIFs, every `interval`.
+    pub fn new(format: ProgressFormat, interval: Duration, total: usize) -> Self {
+        let now = Instant::now();
+        ProgressWriter {
+            format,
+            interval,
+            total,
+            processed: 0,
+            failed: 0,
+            started: now,
+            last_event: now,
+        }
+    }
+
+    /// Lookups a minute since the start; zero before the first one.
+    fn per_minute(&self, elapsed: Duration) -> f64 {
+        match elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.processed as f64 * 60.0 / secs,
+            _ => 0.0,
+        }
+    }
+
+    /// Writes one event; `done` once every NIF was checked.
+    fn emit(&mut self, done: bool) {
+        self.last_event = Instant::now();
+        let elapsed = self.started.elapsed();
+        let remaining = self.total.saturating_sub(self.processed);
+        let per_minute = self.per_minute(elapsed);
+        // Unknown until some NIF was checked
+        let eta = (per_minute > 0.0).then(|| Duration::from_secs_f64(remaining as f64 * 60.0 / per_minute));
+        match self.format {
+            ProgressFormat::Json => {
+                let event = JsonValue::object()
+                    .with("ts", format_rfc3339(SystemTime::now()))
+                    .with("event", "progress")
+                    .with("processed", self.processed as i64)
+                    .with("remaining", remaining as i64)
+                    .with("total", self.total as i64)
+                    .with("failed", self.failed as i64)
+                    .with("rate_per_minute", (per_minute * 10.0).round() / 10.0)
+                    .with("elapsed_secs", elapsed.as_secs() as i64)
+                    .with("eta_secs", eta.map(|eta| eta.as_secs() as i64))
+                    .with("done", done);
+                eprintln!("{}", event);
+            }
+            ProgressFormat::Text => {
+                let eta = match eta {
+                    _ if done => format!("done in {}", format_span(elapsed)),
+                    Some(eta) => format!("ETA {}", format_span(eta)),
+                    None => "ETA unknown".to_string(),
+                };
+                eprintln!("Progress: {}/{} NIFs ({:.1}/min, {})", self.processed, self.total, per_minute, eta);
+            }
+        }
+    }
+}
+
+impl OutputWriter for ProgressWriter {
+    fn write(&mut self, result: &LookupResult) -> Result<(), String> {
+        self.processed += 1;
+        self.failed += usize::from(!result.status.is_definitive());
+        if self.last_event.elapsed() >= self.interval && self.processed < self.total {
+            self.emit(false);
+        }
+        Ok(())
+    }
+
+    /// Writes the last event, also when the run was interrupted.
+    fn finish(&mut self) -> Result<(), String> {
+        self.emit(self.processed >= self.total);
+        Ok(())
+    }
+}
+
+/// Formats a span as hours, minutes and seconds, e.g. `1h 5m` or `15m 50s`.
+fn format_span(span: Duration) -> String {
+    let secs = span.as_secs();
+    match (secs / 3600, secs % 3600 / 60, secs % 60) {
+        (0, 0, seconds) => format!("{}s", seconds),
+        (0, minutes, seconds) => format!("{}m {}s", minutes, seconds),
+        (hours, minutes, _) => format!("{}h {}m", hours, minutes),
+    }
+}
@@ -0,0 +1,299 @@
+// proto.rs
+
+//! Protocol Buffers encoding of results, following `proto/check_nif.proto`. The messages are
+//! small and fixed, so they are encoded by hand rather than with generated code.
+
+use std::time::Duration;
+
+use crate::entity::{EntityCandidate, NifEntity};
+use crate::lookup::{Confidence, LookupReport, LookupResult, LookupSource};
+use crate::postal::PostalCheck;
+use crate::status::NifStatus;
+use crate::time::format_rfc3339;
+use crate::validation::is_nif_valid_local;
+
+/// The message definitions, to be compiled by consumers.
+pub const PROTO_DEFINITION: &str = include_str!("../proto/check_nif.proto");
+
+/// Encodes a result as a `check_nif.v1.LookupResult` message.
+pub fn encode_result(result: &LookupResult) -> Vec<u8> {
+    let mut message = Message::default();
+    message.string(1, &result.nif);
+    message.varint(2, status_number(&result.status));
+    message.varint(3, result.status.http_status().map_or(0, u64::from));
+    message.bool(4, is_nif_valid_local(&result.nif));
+    message.varint(5, source_number(&result.source));
+    if let Some(entity) = &result.entity {
+        message.message(6, &encode_entity(entity));
+    }
+    message.message(7, &encode_report(&result.report));
+    for candidate in &result.candidates {
+        message.message(8, &encode_candidate(candidate));
+    }
+    if let Some(check) = &result.postal_check {
+        message.message(9, &encode_postal_check(check));
+    }
+    if let Some(location) = &result.location {
+        let mut encoded = Message::default();
+        encoded.double(1, location.latitude);
+        encoded.double(2, location.longitude);
+        message.message(10, &encoded.0);
+    }
+    if let Some(checked_at) = result.checked_at {
+        message.string(11, &format_rfc3339(checked_at));
+    }
+    message.varint(
+        12,
+        match result.confidence {
+            None => 0,
+            Some(Confidence::Fresh) => 1,
+            Some(Confidence::Stale) => 2,
+            Some(Confidence::Expired) => 3,
+        },
+    );
+    if let Some(tag) = &result.tag {
+        message.string(13, tag);
+    }
+    if let Some(check) = &result.name_check {
+        let mut encoded = Message::default();
+        encoded.string(1, &check.expected);
+        encoded.double(2, check.similarity);
+        encoded.bool(3, check.matches);
+        message.message(14, &encoded.0);
+    }
+    if let Some(parsed) = &result.parse_confidence {
+        let mut encoded = Message::default();
+        encoded.double(1, parsed.score());
+        encoded.bool(2, parsed.needs_review());
+        encoded.string(3, parsed.layout.unwrap_or_default());
+        for name in &parsed.matched {
+            encoded.string(4, name);
+        }
+        for name in &parsed.missing {
+            encoded.string(5, name);
+        }
+        message.message(15, &encoded.0);
+    }
+    message.0
+}
+
+/// Encodes a result preceded by its length as a varint, as in a `--format proto` stream.
+pub fn encode_delimited(result: &LookupResult) -> Vec<u8> {
+    let message = encode_result(result);
+    let mut framed = Message::default();
+    framed.raw_varint(message.len() as u64);
+    framed.0.extend_from_slice(&message);
+    framed.0
+}
+
+fn encode_entity(entity: &NifEntity) -> Vec<u8> {
+    let mut message = Message::default();
+    message.string(1, &entity.nif);
+    message.string(2, &entity.name);
+    let optional = [&entity.address, &entity.postal_code, &entity.locality, &entity.phone, &entity.email, &entity.district, &entity.municipality];
+    for (number, value) in (3..).zip(optional) {
+        // Explicit presence: a set field is written even when empty
+        if let Some(value) = value {
+            message.key(number, WIRE_LEN);
+            message.bytes(value.as_bytes());
+        }
+    }
+    message.0
+}
+
+fn encode_candidate(candidate: &EntityCandidate) -> Vec<u8> {
+    let mut message = Message::default();
+    message.string(1, &candidate.name);
+    for (number, value) in (2..).zip([&candidate.nif, &candidate.locality]) {
+        if let Some(value) = value {
+            message.key(number, WIRE_LEN);
+            message.bytes(value.as_bytes());
+        }
+    }
+    message.0
+}
+
+fn encode_postal_check(check: &PostalCheck) -> Vec<u8> {
+    let mut message = Message::default();
+    let status = match check {
+        PostalCheck::Consistent => 1,
+        PostalCheck::Malformed => 2,
+        PostalCheck::UnknownCode => 3,
+        PostalCheck::LocalityMismatch { .. } => 4,
+    };
+    message.varint(1, status);
+    if let PostalCheck::LocalityMismatch { expected } = check {
+        message.string(2, expected);
+    }
+    message.0
+}
+
+fn encode_report(report: &LookupReport) -> Vec<u8> {
+    let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    let mut message = Message::default();
+    message.double(1, millis(report.total));
+    message.double(2, millis(report.fetch));
+    message.double(3, millis(report.parse));
+    message.varint(4, u64::from(report.retries));
+    message.string(5, report.backend);
+    message.bool(6, report.cache_hit);
+    message.varint(7, u64::from(report.attempts));
+    if let Some(error) = &report.error {
+        message.key(8, WIRE_LEN);
+        message.bytes(error.as_bytes());
+    }
+    message.0
+}
+
+/// Number of a status in the `Status` enum.
+fn status_number(status: &NifStatus) -> u64 {
+    match status {
+        NifStatus::ValidKnown => 1,
+        NifStatus::ValidUnknown => 2,
+        NifStatus::Error => 3,
+        NifStatus::MultipleResults => 4,
+        NifStatus::HttpError(_) => 5,
+        NifStatus::CircuitOpen => 6,
+        NifStatus::UnsupportedLayout => 7,
+        NifStatus::WrongCategory => 8,
+        NifStatus::Unknown => 9,
+        NifStatus::Cancelled => 10,
+    }
+}
+
+/// Number of a source in the `Source` enum.
+fn source_number(source: &LookupSource) -> u64 {
+    match source {
+        LookupSource::Store => 1,
+        LookupSource::Cache => 2,
+        LookupSource::Remote => 3,
+        LookupSource::Fallback(_) => 4,
+        LookupSource::Local => 5,
+    }
+}
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LEN: u64 = 2;
+
+/// Message being encoded. Scalar fields at their default value are left out, as proto3 does.
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn raw_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push((value as u8 & 0x7f) | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn key(&mut self, number: u64, wire_type: u64) {
+        self.raw_varint(number << 3 | wire_type);
+    }
+
+    fn bytes(&mut self, value: &[u8]) {
+        self.raw_varint(value.len() as u64);
+        self.0.extend_from_slice(value);
+    }
+
+    fn varint(&mut self, number: u64, value: u64) {
+        if value != 0 {
+            self.key(number, WIRE_VARINT);
+            self.raw_varint(value);
+        }
+    }
+
+    fn bool(&mut self, number: u64, value: bool) {
+        self.varint(number, u64::from(value));
+    }
+
+    fn double(&mut self, number: u64, value: f64) {
+        if value != 0.0 {
+            self.key(number, WIRE_FIXED64);
+            self.0.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn string(&mut self, number: u64, value: &str) {
+        if !value.is_empty() {
+            self.key(number, WIRE_LEN);
+            self.bytes(value.as_bytes());
+        }
+    }
+
+    /// Embedded message, always written so its presence is known.
+    fn message(&mut self, number: u64, encoded: &[u8]) {
+        self.key(number, WIRE_LEN);
+        self.bytes(encoded);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result() -> LookupResult {
+        LookupResult {
+            nif: "500960046".to_string(),
+            status: NifStatus::ValidKnown,
+            entity: Some(NifEntity {
+                nif: "500960046".to_string(),
+                name: "Exemplo".to_string(),
+                email: Some(String::new()),
+                ..NifEntity::default()
+            }),
+            candidates: Vec::new(),
+            postal_check: None,
+            name_check: None,
+            location: None,
+            parse_confidence: None,
+            checked_at: None,
+            confidence: None,
+            tag: None,
+            source: LookupSource::Remote,
+            report: LookupReport { attempts: 300, ..LookupReport::default() },
+        }
+    }
+
+    #[test]
+    fn result_encoded() {
+        let entity = [&[0x0A, 9][..], b"500960046", &[0x12, 7], b"Exemplo", &[0x3A, 0]].concat(); // An empty email is still written
+        let expected = [
+            &[0x0A, 9][..],
+            b"500960046",
+            &[0x10, 1],       // Status ValidKnown, no HTTP status
+            &[0x20, 1],       // Valid locally
+            &[0x28, 3],       // Source Remote
+            &[0x32, entity.len() as u8],
+            &entity,
+            &[0x3A, 3, 0x38, 0xAC, 0x02], // Report, only its 300 attempts
+        ]
+        .concat();
+        assert_eq!(encode_result(&result()), expected);
+        assert_eq!(encode_delimited(&result()), [&[expected.len() as u8][..], &expected].concat());
+    }
+
+    #[test]
+    fn defaults_left_out() {
+        let mut message = Message::default();
+        message.varint(1, 0);
+        message.bool(2, false);
+        message.double(3, 0.0);
+        message.string(4, "");
+        assert!(message.0.is_empty());
+        message.message(5, &[]);
+        message.double(6, 1.5);
+        assert_eq!(message.0, [&[0x2A, 0, 0x31][..], &1.5f64.to_le_bytes()].concat());
+    }
+
+    #[test]
+    fn varints() {
+        for (value, bytes) in [(0, &[0x00][..]), (127, &[0x7F]), (128, &[0x80, 0x01]), (u64::MAX, &[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01])] {
+            let mut message = Message::default();
+            message.raw_varint(value);
+            assert_eq!(message.0, bytes, "{}", value);
+        }
+    }
+}
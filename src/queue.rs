@@ -0,0 +1,66 @@
+// queue.rs
+
+//! Priority order of the lookups waiting to send a request. Lookups sharing one client wait
+//! for the rate limit and for a connection slot one at a time, and the next one to wait is
+//! always the most urgent one, so an interactive lookup jumps ahead of the batch traffic
+//! already queued (e.g. `serve` answering `GET /nif/{nif}` while a job is running).
+
+use std::collections::BTreeSet;
+use std::sync::{Condvar, Mutex};
+
+/// How urgent a lookup is; lower goes first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    #[default]
+    Interactive, // Someone is waiting for the answer
+    Batch,       // Background traffic: jobs, warm-ups, long batches
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    busy: bool,                         // A lookup holds the turn
+    waiting: BTreeSet<(Priority, u64)>, // By priority, then in order of arrival
+    next_ticket: u64,
+}
+
+/// Hands the turn to wait for the request resources (rate limit, connection slot) to one
+/// lookup at a time, by priority and then in order of arrival.
+#[derive(Debug, Default)]
+pub struct RequestQueue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+}
+
+impl RequestQueue {
+    pub fn new() -> Self {
+        RequestQueue::default()
+    }
+
+    /// Waits until no lookup holds the turn and no more urgent (or older, same priority)
+    /// lookup waits for it. The turn is held until the returned value is dropped.
+    pub fn enter(&self, priority: Priority) -> QueueTurn<'_> {
+        let mut state = self.state.lock().unwrap();
+        let ticket = (priority, state.next_ticket);
+        state.next_ticket += 1;
+        state.waiting.insert(ticket);
+        while state.busy || state.waiting.first() != Some(&ticket) {
+            state = self.changed.wait(state).unwrap();
+        }
+        state.waiting.remove(&ticket);
+        state.busy = true;
+        QueueTurn { queue: self }
+    }
+}
+
+/// The turn of a `RequestQueue`, passed on to the next lookup on drop.
+pub struct QueueTurn<'a> {
+    queue: &'a RequestQueue,
+}
+
+impl Drop for QueueTurn<'_> {
+    fn drop(&mut self) {
+        self.queue.state.lock().unwrap().busy = false;
+        // Every waiter checks whether it is first; only one of them goes
+        self.queue.changed.notify_all();
+    }
+}
@@ -0,0 +1,534 @@
+// ratelimit.rs
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::cancel::{self, CancellationToken};
+use crate::logging;
+use crate::status::NifStatus;
+use crate::time::parse_http_date;
+
+/// Quota window of `ClientLimiter`.
+const QUOTA_WINDOW: Duration = Duration::from_secs(86_400);
+
+/// Past this many tracked clients, idle ones are forgotten.
+const MAX_TRACKED: usize = 10_000;
+
+/// Lookups per minute of an adaptive `JobRate` when the job starts.
+const ADAPTIVE_START: f64 = 20.0;
+
+/// Bounds of an adaptive `JobRate`, in lookups per minute.
+const ADAPTIVE_MIN: f64 = 1.0;
+const ADAPTIVE_MAX: f64 = 120.0;
+
+/// Limits applied to each client of the server, independently of each other.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientLimits {
+    pub per_minute: Option<u32>, // Sustained request rate, bursts of the same size allowed
+    pub per_day: Option<u64>,    // Requests allowed in each 24-hour window
+}
+
+/// Per-client token buckets and daily quotas.
+///
+/// Clients are identified by API key name when authenticated, by IP address otherwise.
+#[derive(Debug)]
+pub struct ClientLimiter {
+    limits: ClientLimits,
+    clients: Mutex<HashMap<String, ClientState>>,
+}
+
+#[derive(Debug)]
+struct ClientState {
+    tokens: f64,
+    refilled_at: Instant,
+    window_start: Instant,
+    window_count: u64,
+}
+
+impl ClientLimiter {
+    pub fn new(limits: ClientLimits) -> Self {
+        ClientLimiter {
+            limits,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a request of `client`, or returns how long it must wait when over a limit.
+    pub fn check(&self, client: &str) -> Result<(), Duration> {
+        self.check_n(client, 1)
+    }
+
+    /// Counts `n` requests of `client` at once, e.g. the NIFs of a batch, or returns how long
+    /// it must wait when they do not fit in its limits. Nothing is counted then.
+    ///
+    /// A cost above the burst is let through once the bucket is full, leaving it in debt
+    /// until refilled. A cost above the daily quota never fits, see `limits`.
+    pub fn check_n(&self, client: &str, n: u64) -> Result<(), Duration> {
+        if n == 0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= MAX_TRACKED && !clients.contains_key(client) {
+            self.forget_idle(&mut clients, now);
+        }
+        let burst = self.limits.per_minute.unwrap_or(0) as f64;
+        let state = clients.entry(client.to_string()).or_insert(ClientState {
+            tokens: burst,
+            refilled_at: now,
+            window_start: now,
+            window_count: 0,
+        });
+
+        if let Some(per_day) = self.limits.per_day {
+            if now.duration_since(state.window_start) >= QUOTA_WINDOW {
+                state.window_start = now;
+                state.window_count = 0;
+            }
+            if state.window_count.saturating_add(n) > per_day {
+                return Err(QUOTA_WINDOW - now.duration_since(state.window_start));
+            }
+        }
+        if let Some(per_minute) = self.limits.per_minute {
+            let rate = per_minute as f64 / 60.0; // Tokens per second
+            let elapsed = now.duration_since(state.refilled_at).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * rate).min(burst);
+            state.refilled_at = now;
+            let needed = (n as f64).min(burst);
+            if state.tokens < needed {
+                return Err(Duration::from_secs_f64((needed - state.tokens) / rate));
+            }
+            state.tokens -= n as f64;
+        }
+        state.window_count = state.window_count.saturating_add(n);
+        Ok(())
+    }
+
+    /// Limits applied to each client.
+    pub fn limits(&self) -> ClientLimits {
+        self.limits
+    }
+
+    /// Drops clients whose bucket is full again and whose quota window is over,
+    /// i.e. those for which forgetting changes nothing.
+    fn forget_idle(&self, clients: &mut HashMap<String, ClientState>, now: Instant) {
+        let burst = self.limits.per_minute.unwrap_or(0) as f64;
+        let rate = self.limits.per_minute.map(|per_minute| per_minute as f64 / 60.0);
+        let has_quota = self.limits.per_day.is_some();
+        clients.retain(|_, state| {
+            let elapsed = now.duration_since(state.refilled_at).as_secs_f64();
+            // A bucket left in debt by a batch takes longer than a minute to fill up
+            rate.is_some_and(|rate| state.tokens + elapsed * rate < burst)
+                || (has_quota && now.duration_since(state.window_start) < QUOTA_WINDOW)
+        });
+    }
+}
+
+/// Spaces requests evenly, at most a given number per minute, across every thread sharing it.
+///
+/// With a state file, the schedule is shared by every process using the same file as well:
+/// back-to-back runs then respect the rate together instead of each starting afresh. The
+/// file holds one `next_unix_ms<TAB>blocked_until_unix_ms` line, and is locked while read
+/// and updated.
+#[derive(Debug)]
+pub struct Throttle {
+    interval: Duration,
+    state: Mutex<ThrottleState>,
+    state_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct ThrottleState {
+    next: Option<SystemTime>,          // When the next request may start; `None` before the first
+    blocked_until: Option<SystemTime>, // No request before then, as asked by a `Retry-After`
+}
+
+impl ThrottleState {
+    fn encode(&self) -> String {
+        let millis = |time: Option<SystemTime>| {
+            time.map_or(0, |time| time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis())
+        };
+        format!("{}\t{}\n", millis(self.next), millis(self.blocked_until))
+    }
+
+    fn decode(text: &str) -> Option<Self> {
+        let (next, blocked_until) = text.trim().split_once('\t')?;
+        let time = |millis: &str| -> Option<Option<SystemTime>> {
+            let millis: u64 = millis.parse().ok()?;
+            Some((millis > 0).then(|| UNIX_EPOCH + Duration::from_millis(millis)))
+        };
+        Some(ThrottleState {
+            next: time(next)?,
+            blocked_until: time(blocked_until)?,
+        })
+    }
+
+    /// The later of two schedules, field by field.
+    fn merge(self, other: ThrottleState) -> Self {
+        ThrottleState {
+            next: self.next.max(other.next),
+            blocked_until: self.blocked_until.max(other.blocked_until),
+        }
+    }
+}
+
+impl Throttle {
+    /// Allows `per_minute` requests a minute; must be at least 1.
+    pub fn per_minute(per_minute: u32) -> Self {
+        Throttle {
+            interval: Duration::from_secs_f64(60.0 / per_minute.max(1) as f64),
+            state: Mutex::new(ThrottleState::default()),
+            state_file: None,
+        }
+    }
+
+    /// Shares the schedule with the other processes using `path`.
+    pub fn with_state_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.state_file = Some(path.into());
+        self
+    }
+
+    /// Sleeps until the next request may start, booking its slot.
+    pub fn wait(&self) {
+        self.wait_or_cancel(None);
+    }
+
+    /// Like `wait`, cut short by `cancel`. Returns false if cancelled; the booked slot then
+    /// goes unused.
+    pub fn wait_or_cancel(&self, cancel: Option<&CancellationToken>) -> bool {
+        let (start, blocked) = self.update(|state, now| {
+            let blocked = state.blocked_until.filter(|until| *until > now);
+            let start = [Some(now), state.next, blocked].into_iter().flatten().max().unwrap_or(now);
+            state.next = Some(start + self.interval);
+            (start, blocked.is_some())
+        });
+        let wait = start.duration_since(SystemTime::now()).unwrap_or_default();
+        if blocked && wait >= Duration::from_secs(1) {
+            logging::info(
+                "retry_after_wait",
+                &[("wait_ms", (wait.as_millis() as i64).into())],
+                format!("nif.pt asked to retry later, waiting {:.0?}", wait),
+            );
+        }
+        cancel::sleep(wait, cancel)
+    }
+
+    /// Holds every request back until `until`, as asked by a `Retry-After` header.
+    pub fn hold_until(&self, until: SystemTime) {
+        self.update(|state, _| state.blocked_until = state.blocked_until.max(Some(until)));
+    }
+
+    /// Applies `change` to the schedule, merged first with the one of the state file when
+    /// there is one, and saves the result there. Problems with the file are only logged: the
+    /// schedule of this process is used alone then.
+    fn update<T>(&self, change: impl FnOnce(&mut ThrottleState, SystemTime) -> T) -> T {
+        let mut state = self.state.lock().unwrap();
+        let Some(path) = &self.state_file else {
+            return change(&mut state, SystemTime::now());
+        };
+        let file = open_state(path).map(|(file, saved)| {
+            if let Some(saved) = saved {
+                *state = state.merge(saved);
+            }
+            file
+        });
+        let result = change(&mut state, SystemTime::now());
+        // The lock of the file is released when it is dropped, after the new state is written
+        if let Err(e) = file.and_then(|mut file| save_state(&mut file, &state)) {
+            logging::warn(
+                "rate_state_failed",
+                &[("path", path.display().to_string().into()), ("error", e.to_string().into())],
+                format!("Cannot share the rate limit through {}: {}", path.display(), e),
+            );
+        }
+        result
+    }
+}
+
+/// Opens and locks a state file, and reads the schedule it holds.
+fn open_state(path: &Path) -> io::Result<(File, Option<ThrottleState>)> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+    file.lock()?;
+    let mut text = String::new();
+    file.read_to_string(&mut text)?;
+    Ok((file, ThrottleState::decode(&text)))
+}
+
+fn save_state(file: &mut File, state: &ThrottleState) -> io::Result<()> {
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(state.encode().as_bytes())
+}
+
+/// Default state file of the rate limit: `$XDG_CACHE_HOME/check_nif/ratelimit`,
+/// falling back to `~/.cache/check_nif/ratelimit`.
+pub fn default_state_path() -> PathBuf {
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir);
+    base.join("check_nif").join("ratelimit")
+}
+
+/// When a `Retry-After` header asks to come back: after a number of seconds, or at an HTTP date.
+pub fn retry_after(value: &str) -> Option<SystemTime> {
+    match value.trim().parse::<u64>() {
+        Ok(secs) => Some(SystemTime::now() + Duration::from_secs(secs)),
+        Err(_) => parse_http_date(value),
+    }
+}
+
+/// Pace of the remote lookups of a batch job (`--rate`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobRate {
+    Fixed(u32), // Lookups per minute
+    Adaptive,   // Tuned to nif.pt's answers, see `Pacer`
+}
+
+impl JobRate {
+    /// Parses a `--rate` value: a number of lookups per minute, or `auto`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "auto" => Ok(JobRate::Adaptive),
+            text => match text.parse() {
+                Ok(0) => Err("--rate must be at least 1".to_string()),
+                Ok(per_minute) => Ok(JobRate::Fixed(per_minute)),
+                Err(_) => Err(format!("invalid value '{}' for --rate, expected a number or auto", text)),
+            },
+        }
+    }
+}
+
+/// Spaces the remote lookups of a batch job evenly, instead of bursting.
+///
+/// An adaptive pacer tunes its rate the AIMD way: one more lookup per minute after each
+/// definitive answer, half the rate when nif.pt throttles us, with HTTP 429 or a page
+/// that cannot be parsed (such as a captcha challenge, in no known layout). Other
+/// failures keep the rate.
+#[derive(Debug)]
+pub struct Pacer {
+    per_minute: f64,
+    adaptive: bool,
+    last_request: Option<Instant>,
+}
+
+impl Pacer {
+    pub fn new(rate: JobRate) -> Self {
+        let (per_minute, adaptive) = match rate {
+            JobRate::Fixed(per_minute) => (per_minute as f64, false),
+            JobRate::Adaptive => (ADAPTIVE_START, true),
+        };
+        Pacer {
+            per_minute,
+            adaptive,
+            last_request: None,
+        }
+    }
+
+    /// Current rate, in lookups per minute.
+    pub fn per_minute(&self) -> f64 {
+        self.per_minute
+    }
+
+    /// Sleeps until the next lookup may start.
+    pub fn wait(&mut self) {
+        let interval = Duration::from_secs_f64(60.0 / self.per_minute);
+        if let Some(last) = self.last_request {
+            let elapsed = last.elapsed();
+            if elapsed < interval {
+                std::thread::sleep(interval - elapsed);
+            }
+        }
+        self.last_request = Some(Instant::now());
+    }
+
+    /// Adapts the rate to the answer of the last lookup.
+    pub fn record(&mut self, status: &NifStatus) {
+        if !self.adaptive {
+            return;
+        }
+        if status.is_definitive() {
+            self.per_minute = (self.per_minute + 1.0).min(ADAPTIVE_MAX);
+        } else if matches!(status, NifStatus::HttpError(429) | NifStatus::Unknown | NifStatus::UnsupportedLayout) {
+            let previous = self.per_minute;
+            self.per_minute = (self.per_minute / 2.0).max(ADAPTIVE_MIN);
+            logging::warn(
+                "rate_decreased",
+                &[("status", status.label().into()), ("per_minute", self.per_minute.into())],
+                format!(
+                    "nif.pt is throttling ({}), slowing down from {:.0} to {:.0} lookups per minute",
+                    status.label(),
+                    previous,
+                    self.per_minute
+                ),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(per_minute: Option<u32>, per_day: Option<u64>) -> ClientLimiter {
+        ClientLimiter::new(ClientLimits { per_minute, per_day })
+    }
+
+    #[test]
+    fn batch_uses_up_the_daily_quota() {
+        let limiter = limiter(None, Some(10));
+        assert!(limiter.check_n("key:erp", 10).is_ok());
+        let wait = limiter.check("key:erp").unwrap_err();
+        assert!(wait > QUOTA_WINDOW - Duration::from_secs(60));
+        assert!(limiter.check("key:other").is_ok());
+    }
+
+    #[test]
+    fn batch_over_the_remaining_quota_counts_nothing() {
+        let limiter = limiter(None, Some(10));
+        assert!(limiter.check_n("key:erp", 3).is_ok());
+        assert!(limiter.check_n("key:erp", 8).is_err());
+        assert!(limiter.check_n("key:erp", 7).is_ok());
+        assert!(limiter.check("key:erp").is_err());
+    }
+
+    #[test]
+    fn batch_leaves_the_bucket_in_debt() {
+        let limiter = limiter(Some(60), None);
+        assert!(limiter.check_n("ip:10.0.0.1", 100).is_ok());
+        // 40 tokens owed, and one more for the next request, at one a second
+        let wait = limiter.check("ip:10.0.0.1").unwrap_err();
+        assert!(wait > Duration::from_secs(40) && wait <= Duration::from_secs(41), "{:?}", wait);
+    }
+
+    #[test]
+    fn batch_above_the_burst_waits_for_a_full_bucket() {
+        let limiter = limiter(Some(60), None);
+        assert!(limiter.check("ip:10.0.0.1").is_ok());
+        let wait = limiter.check_n("ip:10.0.0.1", 100).unwrap_err();
+        assert!(wait <= Duration::from_secs(1), "{:?}", wait);
+        assert!(limiter.check_n("ip:10.0.0.1", 59).is_ok());
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("check_nif-ratelimit-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn throttle_spaces_requests() {
+        let throttle = Throttle::per_minute(600);
+        let started = Instant::now();
+        for _ in 0..4 {
+            throttle.wait();
+        }
+        assert!(started.elapsed() >= Duration::from_millis(300), "{:?}", started.elapsed());
+    }
+
+    #[test]
+    fn throttle_holds_for_retry_after() {
+        let throttle = Throttle::per_minute(60_000);
+        throttle.wait();
+        let started = Instant::now();
+        throttle.hold_until(SystemTime::now() + Duration::from_millis(200));
+        throttle.hold_until(SystemTime::now()); // An earlier hold does not shorten it
+        throttle.wait();
+        assert!(started.elapsed() >= Duration::from_millis(190), "{:?}", started.elapsed());
+    }
+
+    #[test]
+    fn throttle_wait_cancelled() {
+        let throttle = Throttle::per_minute(1);
+        throttle.wait();
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let started = Instant::now();
+        assert!(!throttle.wait_or_cancel(Some(&cancel)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn throttle_schedule_shared_through_the_state_file() {
+        let dir = scratch("shared");
+        let path = dir.join("ratelimit");
+        let (first, second) = (Throttle::per_minute(600).with_state_file(&path), Throttle::per_minute(600).with_state_file(&path));
+        let started = Instant::now();
+        for _ in 0..2 {
+            first.wait();
+            second.wait();
+        }
+        // The file keeps whole milliseconds, each process may start a fraction of one early
+        assert!(started.elapsed() >= Duration::from_millis(295), "{:?}", started.elapsed());
+        let saved = ThrottleState::decode(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert!(saved.next.is_some() && saved.blocked_until.is_none(), "{:?}", saved);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn throttle_state_encoding() {
+        let state = ThrottleState {
+            next: Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_123)),
+            blocked_until: None,
+        };
+        assert_eq!(state.encode(), "1700000000123\t0\n");
+        assert_eq!(ThrottleState::decode(&state.encode()), Some(state));
+        assert_eq!(ThrottleState::decode(""), None);
+        assert_eq!(ThrottleState::decode("soon\t0"), None);
+    }
+
+    #[test]
+    fn retry_after_values() {
+        let wait = retry_after(" 120 ").unwrap().duration_since(SystemTime::now()).unwrap();
+        assert!(wait > Duration::from_secs(119) && wait <= Duration::from_secs(120), "{:?}", wait);
+        assert_eq!(retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), Some(UNIX_EPOCH + Duration::from_secs(1_445_412_480)));
+        assert_eq!(retry_after("later"), None);
+    }
+
+    #[test]
+    fn job_rates() {
+        assert_eq!(JobRate::parse("auto"), Ok(JobRate::Adaptive));
+        assert_eq!(JobRate::parse("30"), Ok(JobRate::Fixed(30)));
+        assert!(JobRate::parse("0").is_err());
+        assert!(JobRate::parse("fast").is_err());
+    }
+
+    #[test]
+    fn adaptive_pacer() {
+        let mut pacer = Pacer::new(JobRate::Adaptive);
+        assert_eq!(pacer.per_minute(), ADAPTIVE_START);
+        pacer.record(&NifStatus::ValidKnown);
+        pacer.record(&NifStatus::ValidUnknown);
+        assert_eq!(pacer.per_minute(), ADAPTIVE_START + 2.0);
+        pacer.record(&NifStatus::HttpError(503)); // Not throttling
+        assert_eq!(pacer.per_minute(), ADAPTIVE_START + 2.0);
+        pacer.record(&NifStatus::HttpError(429));
+        assert_eq!(pacer.per_minute(), (ADAPTIVE_START + 2.0) / 2.0);
+        for _ in 0..10 {
+            pacer.record(&NifStatus::UnsupportedLayout);
+        }
+        assert_eq!(pacer.per_minute(), ADAPTIVE_MIN);
+        for _ in 0..500 {
+            pacer.record(&NifStatus::ValidKnown);
+        }
+        assert_eq!(pacer.per_minute(), ADAPTIVE_MAX);
+    }
+
+    #[test]
+    fn fixed_pacer() {
+        let mut pacer = Pacer::new(JobRate::Fixed(600));
+        pacer.record(&NifStatus::HttpError(429));
+        assert_eq!(pacer.per_minute(), 600.0);
+        let started = Instant::now();
+        pacer.wait();
+        pacer.wait();
+        assert!(started.elapsed() >= Duration::from_millis(100), "{:?}", started.elapsed());
+    }
+}
@@ -0,0 +1,31 @@
+// reference.rs
+
+//! Embedded corpus of publicly known NIFs of long-lived Portuguese institutions, whose
+//! answers do not change: fixtures for the check digit code and, through `selftest
+//! --reference`, for the scraper end to end.
+
+use crate::validation::NifCategory;
+
+/// A NIF with a known holder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReferenceNif {
+    pub nif: &'static str,
+    pub name: &'static str,    // Usual name of the holder, not necessarily as nif.pt writes it
+    pub category: NifCategory, // What `nif_category` must say of it
+}
+
+const REFERENCE_NIFS: &[ReferenceNif] = &[
+    ReferenceNif { nif: "500960046", name: "Caixa Geral de Depósitos", category: NifCategory::Company },
+    ReferenceNif { nif: "500792771", name: "Banco de Portugal", category: NifCategory::Company },
+    ReferenceNif { nif: "500051070", name: "Município de Lisboa", category: NifCategory::Company },
+    ReferenceNif { nif: "501306099", name: "Município do Porto", category: NifCategory::Company },
+    ReferenceNif { nif: "501617582", name: "Universidade de Coimbra", category: NifCategory::Company },
+    ReferenceNif { nif: "501413197", name: "Universidade do Porto", category: NifCategory::Company },
+    ReferenceNif { nif: "505305500", name: "Instituto da Segurança Social", category: NifCategory::Company },
+    ReferenceNif { nif: "600084779", name: "Autoridade Tributária e Aduaneira", category: NifCategory::Public },
+];
+
+/// The reference NIFs. All are valid, and nif.pt knows each as an entity.
+pub fn reference_nifs() -> &'static [ReferenceNif] {
+    REFERENCE_NIFS
+}
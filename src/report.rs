@@ -0,0 +1,495 @@
+// report.rs
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::time::SystemTime;
+
+use crate::csv;
+use crate::import::normalize_header;
+use crate::lang::{lang, tr, Lang};
+use crate::lookup::LookupResult;
+use crate::output::{CsvWriter, OutputWriter};
+use crate::status::NifStatus;
+use crate::time::format_rfc3339;
+
+/// Distinct NIFs of a run whose entities have the same name or address, e.g. a supplier
+/// registered twice in an ERP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateGroup {
+    pub field: &'static str, // `name` or `address`
+    pub value: String,       // As written on the first of the entities
+    pub nifs: Vec<String>,   // In the order of the results
+}
+
+/// Results of a batch run, with what is needed to summarize them.
+#[derive(Debug, Clone)]
+pub struct BatchReport {
+    pub results: Vec<LookupResult>,
+    pub started_at: SystemTime,
+    pub finished_at: SystemTime,
+}
+
+impl BatchReport {
+    /// Number of results per status label, most frequent first.
+    pub fn counts(&self) -> Vec<(&'static str, usize)> {
+        let mut counts: Vec<(&'static str, usize)> = Vec::new();
+        for result in &self.results {
+            let label = result.status.label();
+            match counts.iter_mut().find(|(l, _)| *l == label) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((label, 1)),
+            }
+        }
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        counts
+    }
+
+    /// Lookups that did not get an answer (HTTP errors, open breaker, unknown), to be retried.
+    pub fn failures(&self) -> Vec<&LookupResult> {
+        self.results.iter().filter(|result| is_failure(&result.status)).collect()
+    }
+
+    /// Probable duplicates: the entities sharing a name, then those sharing an address.
+    ///
+    /// Values are compared without case, accents and punctuation, so `Exemplo, Lda.` and
+    /// `EXEMPLO LDA` are the same name. A NIF checked twice is not its own duplicate.
+    pub fn duplicates(&self) -> Vec<DuplicateGroup> {
+        let mut groups = Vec::new();
+        for field in ["name", "address"] {
+            let mut found: BTreeMap<String, DuplicateGroup> = BTreeMap::new();
+            for result in &self.results {
+                let Some(entity) = &result.entity else {
+                    continue;
+                };
+                let Some(value) = (if field == "name" { Some(entity.name.clone()) } else { entity.full_address() }) else {
+                    continue;
+                };
+                let key = normalize_header(&value);
+                if key.is_empty() {
+                    continue;
+                }
+                let group = found.entry(key).or_insert_with(|| DuplicateGroup {
+                    field,
+                    value,
+                    nifs: Vec::new(),
+                });
+                if !group.nifs.contains(&result.nif) {
+                    group.nifs.push(result.nif.clone());
+                }
+            }
+            groups.extend(found.into_values().filter(|group| group.nifs.len() > 1));
+        }
+        groups
+    }
+
+    /// Wall-clock duration of the run, in seconds.
+    pub fn duration_secs(&self) -> f64 {
+        self.finished_at
+            .duration_since(self.started_at)
+            .unwrap_or_default()
+            .as_secs_f64()
+    }
+}
+
+/// Tells whether a status means the lookup failed, rather than giving an answer.
+pub fn is_failure(status: &NifStatus) -> bool {
+    !status.is_definitive()
+}
+
+/// Human-readable name of a status label, as shown in reports, in the `--lang` language.
+pub fn status_title(label: &str) -> &'static str {
+    match label {
+        "valid_known" => tr("Valid, known entity", "Válido, entidade conhecida"),
+        "valid_unknown" => tr("Valid, unknown entity", "Válido, entidade desconhecida"),
+        "error" => tr("Invalid", "Inválido"),
+        "multiple_results" => tr("Multiple results", "Vários resultados"),
+        "http_error" => tr("HTTP error", "Erro HTTP"),
+        "circuit_open" => tr("Not checked (breaker open)", "Não verificado (disjuntor aberto)"),
+        "unsupported_layout" => tr("Unsupported page layout", "Formato de página não suportado"),
+        "wrong_category" => tr("Wrong category", "Categoria não esperada"),
+        "cancelled" => tr("Cancelled", "Cancelado"),
+        _ => tr("Unknown", "Desconhecido"),
+    }
+}
+
+/// The sentence opening every report: how many NIFs were checked, when, and how many failed.
+fn run_sentence(report: &BatchReport, failed: &str) -> String {
+    let (started, finished) = (format_rfc3339(report.started_at), format_rfc3339(report.finished_at));
+    let (total, duration) = (report.results.len(), report.duration_secs());
+    match lang() {
+        Lang::En => format!("{} NIFs checked between {} and {} ({:.1} s), {} lookups failed.", total, started, finished, duration, failed),
+        Lang::Pt => format!("{} NIFs verificados entre {} e {} ({:.1} s), {} consultas falharam.", total, started, finished, duration, failed),
+    }
+}
+
+/// Colour of a status in the HTML report.
+fn status_colour(label: &str) -> &'static str {
+    match label {
+        "valid_known" => "#2e7d32",
+        "valid_unknown" => "#66bb6a",
+        "error" => "#ef6c00",
+        "multiple_results" => "#1565c0",
+        _ => "#c62828",
+    }
+}
+
+/// Escapes text for HTML element content and attribute values.
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+const STYLE: &str = "body{font-family:system-ui,sans-serif;margin:2em;color:#222}\
+h1{font-size:1.5em}table{border-collapse:collapse;width:100%}\
+th,td{border-bottom:1px solid #ddd;padding:.4em .6em;text-align:left}\
+th{background:#f5f5f5}tr.failure td{background:#fdecea}\
+.bar{height:1.2em;display:inline-block;vertical-align:middle}\
+.chart td{border:none;padding:.2em .6em}.filters{margin:1em 0}\
+.filters input,.filters select{padding:.3em;margin-right:.5em}\
+.badge{color:#fff;border-radius:3px;padding:.1em .4em;font-size:.85em}";
+
+const SCRIPT: &str = "function applyFilter(){\
+var q=document.getElementById('q').value.toLowerCase();\
+var s=document.getElementById('s').value;\
+document.querySelectorAll('#results tbody tr').forEach(function(r){\
+var ok=r.textContent.toLowerCase().indexOf(q)>=0&&(s===''||r.dataset.status===s);\
+r.style.display=ok?'':'none';});}";
+
+/// Renders a self-contained HTML page (no external resources): a summary with a bar
+/// chart per status, the failures, the probable duplicates, and a results table filterable
+/// by text and status.
+pub fn render_html(report: &BatchReport) -> String {
+    let total = report.results.len();
+    let counts = report.counts();
+    let failures = report.failures();
+    let mut html = String::new();
+
+    let title = tr("NIF check report", "Relatório de verificação de NIFs");
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html lang=\"{}\"><head><meta charset=\"utf-8\">\
+         <title>{}</title><style>{}</style><script>{}</script></head><body>\n",
+        lang().code(),
+        title,
+        STYLE,
+        SCRIPT
+    );
+    let _ = write!(
+        html,
+        "<h1>{}</h1>\n<p>{}</p>\n",
+        title,
+        run_sentence(report, &format!("<strong>{}</strong>", failures.len()))
+    );
+
+    let _ = write!(html, "<h2>{}</h2>\n<table class=\"chart\">\n", tr("Summary", "Resumo"));
+    for (label, count) in &counts {
+        let width = count * 300 / total; // Counts only exist when total > 0
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td><span class=\"bar\" style=\"width:{}px;background:{}\"></span> {} ({:.0}%)</td></tr>",
+            status_title(label),
+            width.max(1),
+            status_colour(label),
+            count,
+            *count as f64 * 100.0 / total as f64
+        );
+    }
+    html.push_str("</table>\n");
+
+    if !failures.is_empty() {
+        let _ = write!(html, "<h2>{}</h2>\n<p>{}</p>\n<ul>\n", tr("Failures", "Falhas"), retry_sentence());
+        for result in &failures {
+            let _ = writeln!(html, "<li><strong>{}</strong>: {}</li>", escape_html(&result.nif), describe(result));
+        }
+        html.push_str("</ul>\n");
+    }
+
+    let duplicates = report.duplicates();
+    if !duplicates.is_empty() {
+        let _ = write!(html, "<h2>{}</h2>\n<ul>\n", duplicates_title());
+        for group in &duplicates {
+            let nifs: Vec<String> = group.nifs.iter().map(|nif| format!("<strong>{}</strong>", escape_html(nif))).collect();
+            let _ = writeln!(html, "<li>{}: {}</li>", nifs.join(", "), escape_html(&describe_duplicate(group)));
+        }
+        html.push_str("</ul>\n");
+    }
+
+    let _ = write!(
+        html,
+        "<h2>{}</h2>\n<div class=\"filters\">\
+         <input id=\"q\" type=\"search\" placeholder=\"{}\" oninput=\"applyFilter()\">\
+         <select id=\"s\" onchange=\"applyFilter()\"><option value=\"\">{}</option>",
+        tr("Results", "Resultados"),
+        tr("Filter by NIF or name", "Filtrar por NIF ou nome"),
+        tr("All statuses", "Todos os estados")
+    );
+    for (label, _) in &counts {
+        let _ = write!(html, "<option value=\"{}\">{}</option>", label, status_title(label));
+    }
+    let [status, entity, address, source] = columns();
+    let _ = write!(
+        html,
+        "</select></div>\n<table id=\"results\"><thead><tr><th>NIF</th><th>{}</th>\
+         <th>{}</th><th>{}</th><th>{}</th></tr></thead><tbody>\n",
+        status, entity, address, source
+    );
+    for result in &report.results {
+        let label = result.status.label();
+        let entity = result.entity.as_ref();
+        let _ = writeln!(
+            html,
+            "<tr data-status=\"{}\"{}><td>{}</td><td><span class=\"badge\" style=\"background:{}\">{}</span></td>\
+             <td>{}</td><td>{}</td><td>{}</td></tr>",
+            label,
+            if is_failure(&result.status) { " class=\"failure\"" } else { "" },
+            escape_html(&result.nif),
+            status_colour(label),
+            describe(result),
+            entity.map(|e| escape_html(&e.name)).unwrap_or_default(),
+            entity.and_then(|e| e.full_address()).map(|a| escape_html(&a)).unwrap_or_default(),
+            result.source.label()
+        );
+    }
+    html.push_str("</tbody></table>\n</body></html>\n");
+    html
+}
+
+/// Status title, with the HTTP code for HTTP errors.
+pub fn describe(result: &LookupResult) -> String {
+    match result.status {
+        NifStatus::HttpError(code) => format!("{} {}", status_title("http_error"), code),
+        status => status_title(status.label()).to_string(),
+    }
+}
+
+/// Titles of the status, entity, address and source columns of the report tables.
+fn columns() -> [&'static str; 4] {
+    match lang() {
+        Lang::En => ["Status", "Entity", "Address", "Source"],
+        Lang::Pt => ["Estado", "Entidade", "Morada", "Origem"],
+    }
+}
+
+/// Title of the duplicates section of the reports.
+fn duplicates_title() -> &'static str {
+    tr("Probable duplicates", "Prováveis duplicados")
+}
+
+/// What the NIFs of a duplicate group share, e.g. `same name: EXEMPLO LDA`.
+fn describe_duplicate(group: &DuplicateGroup) -> String {
+    match (group.field, lang()) {
+        ("name", Lang::En) => format!("same name: {}", group.value),
+        ("name", Lang::Pt) => format!("mesmo nome: {}", group.value),
+        (_, Lang::En) => format!("same address: {}", group.value),
+        (_, Lang::Pt) => format!("mesma morada: {}", group.value),
+    }
+}
+
+fn retry_sentence() -> &'static str {
+    tr(
+        "These lookups got no answer and should be retried:",
+        "Estas consultas não obtiveram resposta e devem ser repetidas:",
+    )
+}
+
+/// Escapes text for a Markdown table cell.
+fn escape_markdown(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '|' | '\\' | '*' | '_' | '`' | '[' | ']' | '<' | '>' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\n' | '\r' => out.push(' '),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Renders a Markdown summary (GitHub/GitLab flavour) to paste into tickets and merge
+/// requests: counts per status, the failures and probable duplicates, then the results
+/// grouped by status.
+pub fn render_markdown(report: &BatchReport) -> String {
+    let total = report.results.len();
+    let counts = report.counts();
+    let failures = report.failures();
+    let mut md = String::new();
+
+    let [status, entity, address, source] = columns();
+    let _ = writeln!(md, "# {}\n", tr("NIF check report", "Relatório de verificação de NIFs"));
+    let _ = writeln!(md, "{}\n", run_sentence(report, &format!("**{}**", failures.len())));
+
+    let _ = write!(
+        md,
+        "## {}\n\n| {} | NIFs | {} |\n|---|---:|---:|\n",
+        tr("Summary", "Resumo"),
+        status,
+        tr("Share", "Percentagem")
+    );
+    for (label, count) in &counts {
+        let _ = writeln!(
+            md,
+            "| {} | {} | {:.0}% |",
+            status_title(label),
+            count,
+            *count as f64 * 100.0 / total as f64
+        );
+    }
+
+    if !failures.is_empty() {
+        let _ = write!(md, "\n## {}\n\n{}\n\n", tr("Failures", "Falhas"), retry_sentence());
+        for result in &failures {
+            let _ = writeln!(md, "- `{}`: {}", result.nif.replace('`', ""), describe(result));
+        }
+    }
+
+    let duplicates = report.duplicates();
+    if !duplicates.is_empty() {
+        let _ = write!(md, "\n## {}\n\n", duplicates_title());
+        for group in &duplicates {
+            let nifs: Vec<String> = group.nifs.iter().map(|nif| format!("`{}`", nif.replace('`', ""))).collect();
+            let _ = writeln!(md, "- {}: {}", nifs.join(", "), escape_markdown(&describe_duplicate(group)));
+        }
+    }
+
+    for (label, count) in &counts {
+        let _ = writeln!(
+            md,
+            "\n## {} ({})\n\n| NIF | {} | {} | {} |\n|---|---|---|---|",
+            status_title(label),
+            count,
+            entity,
+            address,
+            source
+        );
+        for result in report.results.iter().filter(|result| result.status.label() == *label) {
+            let entity = result.entity.as_ref();
+            let _ = writeln!(
+                md,
+                "| {} | {} | {} | {} |",
+                escape_markdown(&result.nif),
+                entity.map(|e| escape_markdown(&e.name)).unwrap_or_default(),
+                entity.and_then(|e| e.full_address()).map(|a| escape_markdown(&a)).unwrap_or_default(),
+                result.source.label()
+            );
+        }
+    }
+    md
+}
+
+/// Report formats, chosen from the extension of the report file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Html,     // Self-contained page with charts and a filterable table
+    Markdown, // Tables to paste into tickets (`.md`, `.markdown`)
+}
+
+impl ReportFormat {
+    /// Format for a report written to `path`: Markdown for `.md` files, HTML otherwise.
+    pub fn for_path(path: &str) -> Self {
+        let lower = path.to_ascii_lowercase();
+        if lower.ends_with(".md") || lower.ends_with(".markdown") {
+            ReportFormat::Markdown
+        } else {
+            ReportFormat::Html
+        }
+    }
+
+    /// Renders `report` in this format.
+    pub fn render(self, report: &BatchReport) -> String {
+        match self {
+            ReportFormat::Html => render_html(report),
+            ReportFormat::Markdown => render_markdown(report),
+        }
+    }
+}
+
+/// Renders a short plain-text summary, used as the body of emailed reports.
+pub fn render_text_summary(report: &BatchReport) -> String {
+    let failures = report.failures();
+    let mut text = String::new();
+    let _ = writeln!(text, "{}\n", run_sentence(report, &failures.len().to_string()));
+    for (label, count) in report.counts() {
+        let _ = writeln!(text, "  {:<28} {:>6}", status_title(label), count);
+    }
+    if !failures.is_empty() {
+        let _ = writeln!(text, "\n{}", tr("Failed lookups, to be retried:", "Consultas falhadas, a repetir:"));
+        for result in &failures {
+            let _ = writeln!(text, "  {}: {}", result.nif, describe(result));
+        }
+    }
+    let duplicates = report.duplicates();
+    if !duplicates.is_empty() {
+        let _ = writeln!(text, "\n{}:", duplicates_title());
+        for group in &duplicates {
+            let _ = writeln!(text, "  {}: {}", group.nifs.join(", "), describe_duplicate(group));
+        }
+    }
+    text
+}
+
+/// Columns of the failures file.
+pub const FAILURES_HEADER: &str = "nif,error_class,http_status,attempts,error";
+
+/// Renders the failed lookups as CSV, in the `FAILURES_HEADER` columns: the status label as
+/// the class of error, then the HTTP status, attempts and last error when known. NIFs of an
+/// unexpected category are left out, looking them up again would not change their answer.
+pub fn render_failures(report: &BatchReport) -> String {
+    let mut text = format!("{}\n", FAILURES_HEADER);
+    for result in report.failures().into_iter().filter(|result| result.status != NifStatus::WrongCategory) {
+        let http_status = result.status.http_status().map(|code| code.to_string()).unwrap_or_default();
+        text += &csv::format_record(&[
+            result.nif.as_str(),
+            result.status.label(),
+            &http_status,
+            &result.report.attempts.to_string(),
+            result.report.error.as_deref().unwrap_or_default(),
+        ]);
+        text.push('\n');
+    }
+    text
+}
+
+/// Columns of the duplicates file.
+pub const DUPLICATES_HEADER: &str = "group,field,value,nif,name";
+
+/// Renders the probable duplicates as CSV, in the `DUPLICATES_HEADER` columns: one row per
+/// NIF of each group, the groups numbered from 1, with the registered name of the NIF.
+pub fn render_duplicates(report: &BatchReport) -> String {
+    let mut text = format!("{}\n", DUPLICATES_HEADER);
+    for (index, group) in report.duplicates().iter().enumerate() {
+        for nif in &group.nifs {
+            let name = report
+                .results
+                .iter()
+                .find(|result| result.nif == *nif)
+                .and_then(|result| result.entity.as_ref())
+                .map_or("", |entity| entity.name.as_str());
+            text += &csv::format_record(&[&(index + 1).to_string(), group.field, &group.value, nif, name]);
+            text.push('\n');
+        }
+    }
+    text
+}
+
+/// Renders every result as CSV, in the `--format csv` columns.
+pub fn render_csv(report: &BatchReport) -> String {
+    let mut writer = CsvWriter::new(Vec::new());
+    for result in &report.results {
+        let _ = writer.write(result); // Writing to memory cannot fail
+    }
+    let _ = writer.finish();
+    String::from_utf8(writer.into_inner()).unwrap_or_default()
+}
@@ -0,0 +1,213 @@
+// request_lock.rs
+
+//! Lock taken around every remote request, so that concurrent processes send their requests
+//! one after the other instead of adding up their rates without knowing of each other.
+//!
+//! The lock is a file locked with `flock`, for the processes of one machine, or a Redis key
+//! set with `SET NX PX`, for several machines. A Redis key expires on its own, so a process
+//! dying with the lock cannot block the others for longer than a request may last.
+
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::logging;
+use crate::redis_cache::{RedisClient, RedisReply, KEY_PREFIX};
+
+/// Pause between two attempts at taking a Redis lock held by another process.
+const REDIS_POLL: Duration = Duration::from_millis(50);
+
+/// Deletes the lock key only when it still holds our token, i.e. the lock did not expire and
+/// get taken by another process meanwhile.
+const REDIS_RELEASE: &str = "if redis.call('get', KEYS[1]) == ARGV[1] then return redis.call('del', KEYS[1]) else return 0 end";
+
+/// Where the processes sharing the lock take turns.
+#[derive(Debug)]
+pub enum RequestLock {
+    File(PathBuf),
+    Redis(RedisClient, String), // Server, and key of the lock
+}
+
+impl RequestLock {
+    /// Opens a lock location: a `redis://` URL, or the path of a lock file.
+    pub fn open(location: &str) -> Result<Self, String> {
+        if location.starts_with("redis://") {
+            let client = RedisClient::connect(location)?;
+            return Ok(RequestLock::Redis(client, format!("{}request-lock", KEY_PREFIX)));
+        }
+        let path = PathBuf::from(location);
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+        }
+        Ok(RequestLock::File(path))
+    }
+
+    /// Human-readable description of the lock location.
+    pub fn location(&self) -> String {
+        match self {
+            RequestLock::File(path) => path.display().to_string(),
+            RequestLock::Redis(client, key) => format!("{} key {}", client.location(), key),
+        }
+    }
+
+    /// Waits for the lock, held until the guard is dropped. `hold` is the longest the lock
+    /// may be kept, after which a Redis lock expires.
+    ///
+    /// The lock must never fail a lookup: when it cannot be taken, the problem is logged and
+    /// the request goes ahead without it.
+    pub fn acquire(&self, hold: Duration) -> Option<RequestLockGuard<'_>> {
+        let started = Instant::now();
+        let guard = match self {
+            RequestLock::File(path) => lock_file(path).map(RequestLockGuard::File),
+            RequestLock::Redis(client, key) => lock_redis(client, key, hold).map(|token| RequestLockGuard::Redis(self, token)),
+        };
+        match guard {
+            Ok(guard) => {
+                let waited = started.elapsed();
+                if waited >= Duration::from_secs(1) {
+                    logging::info(
+                        "request_lock_wait",
+                        &[("wait_ms", (waited.as_millis() as i64).into())],
+                        format!("Waited {:.1?} for the request lock {}", waited, self.location()),
+                    );
+                }
+                Some(guard)
+            }
+            Err(e) => {
+                logging::warn(
+                    "request_lock_failed",
+                    &[("error", e.as_str().into())],
+                    format!("Cannot take the request lock {}, going ahead without it: {}", self.location(), e),
+                );
+                None
+            }
+        }
+    }
+}
+
+/// A held `RequestLock`, released on drop.
+#[derive(Debug)]
+pub enum RequestLockGuard<'a> {
+    File(File), // Unlocked when closed
+    Redis(&'a RequestLock, String),
+}
+
+impl Drop for RequestLockGuard<'_> {
+    fn drop(&mut self) {
+        if let RequestLockGuard::Redis(RequestLock::Redis(client, key), token) = self {
+            let release = client.command(&[b"EVAL", REDIS_RELEASE.as_bytes(), b"1", key.as_bytes(), token.as_bytes()]);
+            if let Err(e) = release {
+                // The key expires on its own, the others only wait longer
+                logging::warn(
+                    "request_lock_failed",
+                    &[("error", e.as_str().into())],
+                    format!("Cannot release the request lock {}: {}", key, e),
+                );
+            }
+        }
+    }
+}
+
+fn lock_file(path: &Path) -> Result<File, String> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| format!("cannot open {}: {}", path.display(), e))?;
+    file.lock().map_err(|e| format!("cannot lock {}: {}", path.display(), e))?;
+    Ok(file)
+}
+
+/// Sets the lock key to a token of ours once it is free, and returns the token.
+fn lock_redis(client: &RedisClient, key: &str, hold: Duration) -> Result<String, String> {
+    let token = format!("{}-{:016x}", std::process::id(), rand::random::<u64>());
+    let millis = hold.as_millis().max(1).to_string();
+    loop {
+        match client.command(&[b"SET", key.as_bytes(), token.as_bytes(), b"NX", b"PX", millis.as_bytes()])? {
+            RedisReply::Status(_) => return Ok(token),
+            RedisReply::Bulk(None) => std::thread::sleep(REDIS_POLL),
+            other => return Err(format!("unexpected reply to SET: {:?}", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("check_nif-request-lock-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn file_lock_waits_for_the_holder() {
+        let dir = scratch("file");
+        let path = dir.join("sub/requests.lock").to_str().unwrap().to_string();
+        let lock = RequestLock::open(&path).unwrap();
+        let guard = lock.acquire(Duration::from_secs(1)).unwrap();
+        let waiter = thread::spawn(move || {
+            let other = RequestLock::open(&path).unwrap();
+            let started = Instant::now();
+            let _guard = other.acquire(Duration::from_secs(1)).unwrap();
+            started.elapsed()
+        });
+        thread::sleep(Duration::from_millis(200));
+        drop(guard);
+        assert!(waiter.join().unwrap() >= Duration::from_millis(150));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A Redis server answering `replies` to the commands after the PING, in turn; returns
+    /// its port, and the commands it got once the client is gone.
+    fn server(replies: &'static [&'static str]) -> (u16, thread::JoinHandle<Vec<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut commands = Vec::new();
+            let mut replies = ["+PONG\r\n"].iter().chain(replies);
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                let count: usize = line.trim_end().trim_start_matches('*').parse().unwrap();
+                let mut args = Vec::new();
+                for _ in 0..count * 2 {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                    if !line.starts_with('$') {
+                        args.push(line.trim_end().to_string());
+                    }
+                }
+                commands.push(args);
+                writer.write_all(replies.next().unwrap_or(&"+OK\r\n").as_bytes()).unwrap();
+                line.clear();
+            }
+            commands
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn redis_lock_polls_and_releases_its_own_token() {
+        let (port, server) = server(&["$-1\r\n", "+OK\r\n", ":1\r\n"]);
+        let lock = RequestLock::open(&format!("redis://127.0.0.1:{}", port)).unwrap();
+        assert_eq!(lock.location(), format!("redis://127.0.0.1:{}/0 key {}request-lock", port, KEY_PREFIX));
+        drop(lock.acquire(Duration::from_millis(1500)).unwrap());
+        drop(lock);
+        let commands = server.join().unwrap();
+        assert_eq!(commands.len(), 4, "{:?}", commands);
+        let key = format!("{}request-lock", KEY_PREFIX);
+        let token = &commands[1][2];
+        for set in &commands[1..3] {
+            assert_eq!(set, &["SET", &key, token, "NX", "PX", "1500"]);
+        }
+        assert_eq!(commands[3], ["EVAL", REDIS_RELEASE, "1", &key, token]);
+    }
+}
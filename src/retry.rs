@@ -0,0 +1,149 @@
+// retry.rs
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::status::NifStatus;
+
+/// Wait before the first retry of a failed lookup, doubled before each of the next ones.
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Retries a `RetryBudget` allows on top of its share of the lookups, so the first failures
+/// of a batch are retried even before it has made many lookups.
+pub const DEFAULT_RETRY_BUDGET_MINIMUM: u64 = 10;
+
+/// When, and after how long, a lookup that got no answer is tried again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub retries: u32,    // Extra attempts after the first one
+    pub delay: Duration, // Wait before the first retry
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 0,
+            delay: DEFAULT_RETRY_DELAY,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Tells whether a lookup ending in `status` may be tried again: rate limiting, server
+    /// errors and lookups that got no answer at all (network errors, timeouts).
+    pub fn should_retry(status: &NifStatus) -> bool {
+        status.is_retryable() || *status == NifStatus::Unknown
+    }
+
+    /// Wait before retry number `attempt`, counted from 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.delay.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+    }
+}
+
+/// Cap on the retries of the lookups sharing it, as a share of those lookups, so a systemic
+/// outage does not double or triple the requests sent to nif.pt: with a ratio of 0.1, at most
+/// one retry is made for every ten lookups (plus `minimum`), and once the budget is spent
+/// failed lookups are given up at once.
+#[derive(Debug)]
+pub struct RetryBudget {
+    ratio: f64,
+    minimum: u64,
+    lookups: AtomicU64,
+    retries: AtomicU64,
+    exhausted: AtomicBool, // Whether a retry was refused yet, to warn once
+}
+
+impl RetryBudget {
+    /// Budget of `ratio` retries per lookup, between 0 and 1, plus `minimum` retries.
+    pub fn new(ratio: f64, minimum: u64) -> Result<Self, String> {
+        if !(0.0..=1.0).contains(&ratio) {
+            return Err(format!("invalid retry budget {}, expected a share between 0 and 1", ratio));
+        }
+        Ok(RetryBudget {
+            ratio,
+            minimum,
+            lookups: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            exhausted: AtomicBool::new(false),
+        })
+    }
+
+    /// Counts a lookup, which adds its share to the budget.
+    pub fn record_lookup(&self) {
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Takes one retry from the budget; false when it is spent.
+    pub fn try_retry(&self) -> bool {
+        let allowed = self.minimum + (self.lookups.load(Ordering::Relaxed) as f64 * self.ratio) as u64;
+        self.retries
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |retries| (retries < allowed).then_some(retries + 1))
+            .is_ok()
+    }
+
+    /// Marks the budget as exhausted; true the first time only.
+    pub(crate) fn exhaust(&self) -> bool {
+        !self.exhausted.swap(true, Ordering::Relaxed)
+    }
+
+    /// Lookups counted so far.
+    pub fn lookups(&self) -> u64 {
+        self.lookups.load(Ordering::Relaxed)
+    }
+
+    /// Retries taken so far.
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_double() {
+        let policy = RetryPolicy { retries: 3, delay: Duration::from_millis(500) };
+        assert_eq!(policy.delay(1), Duration::from_millis(500));
+        assert_eq!(policy.delay(2), Duration::from_secs(1));
+        assert_eq!(policy.delay(4), Duration::from_secs(4));
+        assert_eq!(policy.delay(200), policy.delay(40)); // Saturates instead of overflowing
+        assert!(RetryPolicy::should_retry(&NifStatus::Unknown));
+        assert!(RetryPolicy::should_retry(&NifStatus::HttpError(429)));
+        assert!(!RetryPolicy::should_retry(&NifStatus::ValidUnknown));
+    }
+
+    #[test]
+    fn budget_grows_with_the_lookups() {
+        let budget = RetryBudget::new(0.1, 2).unwrap();
+        assert!(budget.try_retry());
+        assert!(budget.try_retry());
+        assert!(!budget.try_retry());
+        for _ in 0..19 {
+            budget.record_lookup();
+        }
+        assert!(budget.try_retry()); // 2 + 19 * 0.1 = 3 retries
+        assert!(!budget.try_retry());
+        budget.record_lookup();
+        assert!(budget.try_retry());
+        assert_eq!((budget.lookups(), budget.retries()), (20, 4));
+    }
+
+    #[test]
+    fn budget_shared_and_exhausted_once() {
+        let budget = std::sync::Arc::new(RetryBudget::new(0.0, 100).unwrap());
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let budget = budget.clone();
+                std::thread::spawn(move || (0..50).filter(|_| budget.try_retry()).count())
+            })
+            .collect();
+        let taken: usize = threads.into_iter().map(|thread| thread.join().unwrap()).sum();
+        assert_eq!(taken, 100);
+        assert!(budget.exhaust());
+        assert!(!budget.exhaust());
+        assert!(RetryBudget::new(1.5, 0).is_err());
+        assert!(RetryBudget::new(-0.1, 0).is_err());
+    }
+}
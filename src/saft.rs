@@ -0,0 +1,230 @@
+// saft.rs
+
+use crate::input::read_text;
+use crate::xml::{XmlEvent, XmlReader};
+
+/// NIF used in SAF-T (PT) for sales to final consumers who gave none ("Consumidor final").
+pub const FINAL_CONSUMER_NIF: &str = "999999990";
+
+/// Whose tax ID it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartyKind {
+    Company,  // The company the file belongs to (`Header/TaxRegistrationNumber`)
+    Customer, // `MasterFiles/Customer`
+    Supplier, // `MasterFiles/Supplier`
+}
+
+impl PartyKind {
+    /// Lowercase name, for reports.
+    pub fn label(&self) -> &'static str {
+        match self {
+            PartyKind::Company => "company",
+            PartyKind::Customer => "customer",
+            PartyKind::Supplier => "supplier",
+        }
+    }
+}
+
+/// A tax ID found in a SAF-T file, with where it was found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SaftParty {
+    pub kind: PartyKind,
+    pub id: Option<String>,      // `CustomerID` or `SupplierID`
+    pub name: Option<String>,    // `CompanyName`
+    pub tax_id: String,          // As written in the file, trimmed
+    pub country: Option<String>, // `BillingAddress/Country`
+    pub line: usize,             // Line of the tax ID element
+    pub path: String,            // e.g. `/AuditFile/MasterFiles/Customer[3]/CustomerTaxID`
+}
+
+impl SaftParty {
+    /// Tells whether the tax ID must be a Portuguese NIF: the party is in Portugal, or
+    /// its country is not given. Foreign tax IDs follow other rules.
+    pub fn is_portuguese(&self) -> bool {
+        self.country.as_deref().is_none_or(|country| country.eq_ignore_ascii_case("PT"))
+    }
+}
+
+/// Element being read, with the number of children of each name seen so far.
+struct Open {
+    name: String,
+    segment: String, // Path segment, `Customer[3]` for repeated records
+    line: usize,     // Line of the start tag
+    children: Vec<(String, usize)>,
+}
+
+/// Reads the company, customer and supplier tax IDs of a SAF-T (PT) file.
+pub fn read_parties(text: &str) -> Result<Vec<SaftParty>, String> {
+    let mut reader = XmlReader::new(text);
+    let mut stack: Vec<Open> = Vec::new();
+    let mut value = String::new(); // Text of the innermost element
+    let mut parties = Vec::new();
+    let mut current: Option<SaftParty> = None; // Customer or supplier being read
+    let mut company_name = None;
+
+    while let Some(event) = reader.next_event()? {
+        match event {
+            XmlEvent::Start(name) => {
+                let index = match stack.last_mut() {
+                    Some(parent) => match parent.children.iter_mut().find(|(child, _)| *child == name) {
+                        Some((_, count)) => {
+                            *count += 1;
+                            *count
+                        }
+                        None => {
+                            parent.children.push((name.clone(), 1));
+                            1
+                        }
+                    },
+                    None => 1,
+                };
+                let parent = stack.last().map(|open| open.name.as_str());
+                let kind = match (parent, name.as_str()) {
+                    (Some("MasterFiles"), "Customer") => Some(PartyKind::Customer),
+                    (Some("MasterFiles"), "Supplier") => Some(PartyKind::Supplier),
+                    _ => None,
+                };
+                let segment = match kind {
+                    Some(_) => format!("{}[{}]", name, index),
+                    None => name.clone(),
+                };
+                if let Some(kind) = kind {
+                    current = Some(SaftParty {
+                        kind,
+                        id: None,
+                        name: None,
+                        tax_id: String::new(),
+                        country: None,
+                        line: 0,
+                        path: String::new(),
+                    });
+                }
+                stack.push(Open {
+                    name,
+                    segment,
+                    line: reader.line(),
+                    children: Vec::new(),
+                });
+                value.clear();
+            }
+            XmlEvent::Text(text) => value.push_str(&text),
+            XmlEvent::End(_) => {
+                let path: String = stack.iter().map(|open| format!("/{}", open.segment)).collect();
+                let Some(open) = stack.pop() else {
+                    break;
+                };
+                let parent = stack.last().map(|open| open.name.as_str());
+                let text = Some(value.trim().to_string()).filter(|text| !text.is_empty());
+                match (parent, open.name.as_str(), current.as_mut()) {
+                    (Some("Header"), "CompanyName", _) => company_name = text,
+                    (Some("Header"), "TaxRegistrationNumber", _) => parties.push(SaftParty {
+                        kind: PartyKind::Company,
+                        id: None,
+                        name: company_name.clone(),
+                        tax_id: text.unwrap_or_default(),
+                        country: Some("PT".to_string()),
+                        line: open.line,
+                        path,
+                    }),
+                    (Some("MasterFiles"), "Customer" | "Supplier", _) => {
+                        if let Some(party) = current.take()
+                            && party.line > 0
+                        {
+                            parties.push(party);
+                        }
+                    }
+                    (Some("Customer" | "Supplier"), field, Some(party)) => match field {
+                        "CustomerID" | "SupplierID" => party.id = text,
+                        "CompanyName" => party.name = text,
+                        "CustomerTaxID" | "SupplierTaxID" => {
+                            party.tax_id = text.unwrap_or_default();
+                            party.line = open.line;
+                            party.path = path;
+                        }
+                        _ => {}
+                    },
+                    (Some("BillingAddress"), "Country", Some(party)) => party.country = text,
+                    _ => {}
+                }
+                value.clear();
+            }
+        }
+    }
+    Ok(parties)
+}
+
+/// Reads a SAF-T file from disk, see `read_text` for the encoding.
+pub fn read_file(path: &str) -> Result<Vec<SaftParty>, String> {
+    let text = read_text(path)?;
+    read_parties(&text).map_err(|e| format!("{}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AUDIT_FILE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<AuditFile xmlns="urn:OECD:StandardAuditFile-Tax:PT_1.04_01">
+  <Header>
+    <CompanyName>Exemplo, Lda.</CompanyName>
+    <TaxRegistrationNumber>500960046</TaxRegistrationNumber>
+  </Header>
+  <MasterFiles>
+    <Customer>
+      <CustomerID>C1</CustomerID>
+      <CustomerTaxID> 509442013 </CustomerTaxID>
+      <CompanyName>Papelaria Central</CompanyName>
+      <BillingAddress><Country>PT</Country></BillingAddress>
+    </Customer>
+    <Customer>
+      <CustomerID>C2</CustomerID>
+      <CompanyName>Sem NIF</CompanyName>
+    </Customer>
+    <Customer>
+      <CustomerID>C3</CustomerID>
+      <CustomerTaxID>ESB12345678</CustomerTaxID>
+      <BillingAddress><Country>ES</Country></BillingAddress>
+    </Customer>
+    <Supplier>
+      <SupplierID>F1</SupplierID>
+      <SupplierTaxID>999999990</SupplierTaxID>
+    </Supplier>
+  </MasterFiles>
+</AuditFile>
+"#;
+
+    #[test]
+    fn parties_read() {
+        let parties = read_parties(AUDIT_FILE).unwrap();
+        assert_eq!(parties.len(), 4);
+        assert_eq!(parties[0].kind, PartyKind::Company);
+        assert_eq!(parties[0].name.as_deref(), Some("Exemplo, Lda."));
+        assert_eq!(parties[0].tax_id, "500960046");
+        assert_eq!(parties[0].path, "/AuditFile/Header/TaxRegistrationNumber");
+        assert_eq!(
+            parties[1],
+            SaftParty {
+                kind: PartyKind::Customer,
+                id: Some("C1".to_string()),
+                name: Some("Papelaria Central".to_string()),
+                tax_id: "509442013".to_string(),
+                country: Some("PT".to_string()),
+                line: 10,
+                path: "/AuditFile/MasterFiles/Customer[1]/CustomerTaxID".to_string(),
+            }
+        );
+        // The customer without a tax ID is left out, but still counts for the paths
+        assert_eq!(parties[2].path, "/AuditFile/MasterFiles/Customer[3]/CustomerTaxID");
+        assert!(!parties[2].is_portuguese());
+        assert_eq!(parties[3].kind, PartyKind::Supplier);
+        assert_eq!(parties[3].path, "/AuditFile/MasterFiles/Supplier[1]/SupplierTaxID");
+        assert!(parties[3].is_portuguese());
+        assert_eq!(parties[3].tax_id, FINAL_CONSUMER_NIF);
+    }
+
+    #[test]
+    fn malformed_file() {
+        let error = read_parties("<AuditFile><Header></AuditFile>").unwrap_err();
+        assert!(error.contains("expected </Header>"), "{}", error);
+    }
+}
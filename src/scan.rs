@@ -0,0 +1,110 @@
+// scan.rs
+
+use crate::validation::is_nif_valid_local;
+
+/// A nine-digit number found in free text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NifCandidate {
+    pub nif: String,  // The nine digits
+    pub text: String, // As written, e.g. `500 960 046`
+    pub line: usize,  // Counting from 1
+    pub column: usize, // In characters, counting from 1
+}
+
+impl NifCandidate {
+    /// Tells whether the number passes the NIF check digit.
+    pub fn is_valid(&self) -> bool {
+        is_nif_valid_local(&self.nif)
+    }
+}
+
+/// Separators allowed between the groups of a NIF written as `NNN NNN NNN`.
+const GROUP_SEPARATORS: &[u8] = b" .-";
+
+/// Finds the nine-digit numbers of `text`: written in one run (`500960046`, `PT500960046`)
+/// or in three groups of three (`500 960 046`, `500.960.046`).
+///
+/// Numbers inside longer runs of digits or glued to words (IBANs, references) are not
+/// taken, nor numbers after the `+351`/`00351` dialling code, which are phone numbers.
+/// Candidates are returned whether they pass the check digit or not.
+pub fn find_candidates(text: &str) -> Vec<NifCandidate> {
+    let mut candidates = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let bytes = line.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            if !bytes[i].is_ascii_digit() || !starts_word(&line[..i]) {
+                i += 1;
+                continue;
+            }
+            let run = digit_run(bytes, i);
+            let found = match run {
+                9 => Some(9),
+                3 => grouped(bytes, i),
+                _ => None,
+            };
+            let Some(len) = found else {
+                i += run.max(1);
+                continue;
+            };
+            if !after_dialling_code(&line[..i]) && boundary_after(&line[i + len..]) {
+                let written = &line[i..i + len];
+                candidates.push(NifCandidate {
+                    nif: written.chars().filter(char::is_ascii_digit).collect(),
+                    text: written.to_string(),
+                    line: index + 1,
+                    column: line[..i].chars().count() + 1,
+                });
+            }
+            i += len;
+        }
+    }
+    candidates
+}
+
+/// Length of the run of digits at `start`.
+fn digit_run(bytes: &[u8], start: usize) -> usize {
+    bytes[start..].iter().take_while(|b| b.is_ascii_digit()).count()
+}
+
+/// Length of `NNN?NNN?NNN` at `start`, with the same separator twice, when no digit follows.
+fn grouped(bytes: &[u8], start: usize) -> Option<usize> {
+    let separator = *bytes.get(start + 3)?;
+    if !GROUP_SEPARATORS.contains(&separator) {
+        return None;
+    }
+    let second = start + 4;
+    let third = start + 8;
+    let ok = digit_run(bytes, second) == 3 && bytes.get(start + 7) == Some(&separator) && digit_run(bytes, third) == 3;
+    // `500 960 046 1` is some longer number
+    let glued = bytes.get(third + 3) == Some(&separator) && bytes.get(third + 4).is_some_and(u8::is_ascii_digit);
+    (ok && !glued).then_some(11)
+}
+
+/// Tells whether `before` ends at a word boundary.
+fn boundary_before(before: &str) -> bool {
+    before.chars().next_back().is_none_or(|c| !c.is_alphanumeric())
+}
+
+/// Tells whether `after` starts at a word boundary.
+fn boundary_after(after: &str) -> bool {
+    after.chars().next().is_none_or(|c| !c.is_alphanumeric())
+}
+
+/// Tells whether a number may start after `before`: at a word boundary, or right after a
+/// `PT` prefix standing at one.
+fn starts_word(before: &str) -> bool {
+    if boundary_before(before) {
+        return true;
+    }
+    match before.strip_suffix("PT").or_else(|| before.strip_suffix("pt")) {
+        Some(rest) => boundary_before(rest),
+        None => false,
+    }
+}
+
+/// Tells whether `before` ends with the Portuguese dialling code, spaces aside.
+fn after_dialling_code(before: &str) -> bool {
+    let before = before.trim_end();
+    before.ends_with("+351") || before.ends_with("00351")
+}
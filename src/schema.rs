@@ -0,0 +1,179 @@
+// schema.rs
+
+//! JSON Schemas (draft 2020-12) of the JSON outputs, the contract consumers can validate
+//! against and generate code from. Fields are only ever added to them, never renamed or
+//! removed; bump the schema `$id` if that ever has to change.
+
+/// Schema of one result object, the `LookupResult::to_json` form: each line of
+/// `--format ndjson`, the body of `GET /nif/{nif}`, and the items of the batch outputs.
+macro_rules! result_schema {
+    () => {
+        r##"{
+    "type": "object",
+    "required": ["nif", "status", "http_status", "valid_locally", "source", "entity", "report"],
+    "properties": {
+      "nif": {"type": "string", "pattern": "^(PT)?[0-9]{9}$", "description": "The NIF, with the PT prefix under --nif-format vat"},
+      "status": {
+        "enum": ["valid_known", "valid_unknown", "error", "multiple_results", "http_error", "circuit_open", "unsupported_layout", "wrong_category", "cancelled", "unknown"]
+      },
+      "http_status": {"type": ["integer", "null"], "minimum": 100, "maximum": 599, "description": "HTTP status of nif.pt, for http_error only"},
+      "valid_locally": {"type": "boolean", "description": "Whether the check digit is right"},
+      "source": {"enum": ["store", "cache", "remote", "fallback", "local"]},
+      "entity": {
+        "oneOf": [
+          {"type": "null"},
+          {
+            "type": "object",
+            "required": ["nif", "name"],
+            "properties": {
+              "nif": {"type": "string", "pattern": "^(PT)?[0-9]{9}$"},
+              "name": {"type": "string"},
+              "address": {"type": "string"},
+              "postal_code": {"type": "string"},
+              "locality": {"type": "string"},
+              "phone": {"type": "string"},
+              "email": {"type": "string"},
+              "district": {"type": "string", "pattern": "^[0-9]{2}$"},
+              "municipality": {"type": "string", "pattern": "^[0-9]{4}$"}
+            }
+          }
+        ]
+      },
+      "candidates": {
+        "type": "array",
+        "description": "Companies listed by nif.pt for multiple_results, when the page was fetched by this lookup",
+        "items": {
+          "type": "object",
+          "required": ["name"],
+          "properties": {
+            "name": {"type": "string"},
+            "nif": {"type": "string", "pattern": "^(PT)?[0-9]{9}$"},
+            "locality": {"type": "string"}
+          }
+        }
+      },
+      "postal_check": {
+        "oneOf": [
+          {"type": "null"},
+          {
+            "type": "object",
+            "required": ["status"],
+            "properties": {
+              "status": {"enum": ["consistent", "malformed", "unknown_code", "locality_mismatch"]},
+              "expected": {"type": "string", "description": "Postal locality of the code, for locality_mismatch"}
+            }
+          }
+        ],
+        "description": "Check of the entity postal code against its locality, under --check-postal-codes"
+      },
+      "name_check": {
+        "oneOf": [
+          {"type": "null"},
+          {
+            "type": "object",
+            "required": ["expected", "similarity", "match"],
+            "properties": {
+              "expected": {"type": "string", "description": "Name given for the NIF in --expected-names"},
+              "similarity": {"type": "number", "minimum": 0, "maximum": 1},
+              "match": {"type": "boolean", "description": "Whether the similarity reaches --min-similarity"}
+            }
+          }
+        ],
+        "description": "Comparison of the registered name with the expected one, under --expected-names"
+      },
+      "location": {
+        "oneOf": [
+          {"type": "null"},
+          {
+            "type": "object",
+            "required": ["latitude", "longitude"],
+            "properties": {
+              "latitude": {"type": "number"},
+              "longitude": {"type": "number"}
+            }
+          }
+        ],
+        "description": "Coordinates of the entity address (WGS 84), under --geocode"
+      },
+      "parse_confidence": {
+        "oneOf": [
+          {"type": "null"},
+          {
+            "type": "object",
+            "required": ["score", "needs_review", "matched", "missing"],
+            "properties": {
+              "score": {"type": "number", "minimum": 0, "maximum": 1},
+              "needs_review": {"type": "boolean", "description": "Whether the score is under 0.8"},
+              "layout": {"type": ["string", "null"]},
+              "matched": {"type": "array", "items": {"type": "string"}, "description": "Selectors and entity fields found"},
+              "missing": {"type": "array", "items": {"type": "string"}, "description": "Those expected but not found"}
+            }
+          }
+        ],
+        "description": "How much of the nif.pt page was read, for answers parsed by this lookup"
+      },
+      "checked_at": {
+        "type": ["string", "null"],
+        "format": "date-time",
+        "description": "When nif.pt gave the answer; earlier than now for answers of the cache or the store"
+      },
+      "confidence": {
+        "enum": ["fresh", "stale", "expired", null],
+        "description": "Trust in the answer from the age of checked_at, by --stale-after and --expired-after"
+      },
+      "tag": {
+        "type": ["string", "null"],
+        "description": "Campaign of the run, from --tag"
+      },
+      "report": {
+        "type": "object",
+        "required": ["total_ms", "fetch_ms", "parse_ms", "retries", "backend", "cache_hit"],
+        "properties": {
+          "total_ms": {"type": "number", "minimum": 0},
+          "fetch_ms": {"type": "number", "minimum": 0},
+          "parse_ms": {"type": "number", "minimum": 0},
+          "retries": {"type": "integer", "minimum": 0},
+          "attempts": {"type": "integer", "minimum": 1},
+          "backend": {"type": "string"},
+          "cache_hit": {"type": "boolean"},
+          "error": {"type": ["string", "null"], "description": "Why nif.pt gave no answer, when it did not"}
+        }
+      }
+    }
+  }"##
+    };
+}
+
+/// JSON Schema of one result.
+pub const RESULT_SCHEMA: &str = concat!(
+    r##"{
+  "$schema": "https://json-schema.org/draft/2020-12/schema",
+  "$id": "urn:check_nif:schema:result:1",
+  "title": "check_nif lookup result",
+  "$ref": "#/$defs/result",
+  "$defs": {
+    "result": "##,
+    result_schema!(),
+    r##"
+  }
+}
+"##
+);
+
+/// JSON Schema of a batch: the array written by `--format json`, also found under `results`
+/// in the answers of `POST /nif/batch` and `GET /jobs/{id}`.
+pub const BATCH_SCHEMA: &str = concat!(
+    r##"{
+  "$schema": "https://json-schema.org/draft/2020-12/schema",
+  "$id": "urn:check_nif:schema:batch:1",
+  "title": "check_nif batch results",
+  "type": "array",
+  "items": {"$ref": "#/$defs/result"},
+  "$defs": {
+    "result": "##,
+    result_schema!(),
+    r##"
+  }
+}
+"##
+);
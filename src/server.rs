@@ -0,0 +1,806 @@
+// server.rs
+
+use std::fmt;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::auth::ApiKeys;
+use crate::cors::CorsConfig;
+use crate::dashboard::{LookupHistory, DASHBOARD_PAGE};
+#[cfg(feature = "graphql")]
+use crate::graphql::{self, GraphqlRequest, Root};
+use crate::http::{self, Request, RequestError, Response};
+use crate::jobs::{random_id, Job, JobRegistry};
+use crate::json::JsonValue;
+use crate::logging;
+use crate::lookup::{lookup_nif, LookupOptions};
+use crate::queue::{Priority, RequestQueue};
+use crate::ratelimit::{ClientLimiter, ClientLimits};
+#[cfg(feature = "graphql")]
+use crate::store::StoreRecord;
+use crate::systemd;
+use crate::validation::is_nif_valid_local;
+#[cfg(feature = "graphql")]
+use crate::validation::normalize_nif;
+
+/// Connections served at the same time; further ones get a 503 right away.
+const MAX_CONNECTIONS: usize = 64;
+
+/// Event streams served at the same time. They last as long as their job, so they are
+/// counted apart from `MAX_CONNECTIONS` instead of holding its places.
+const MAX_EVENT_STREAMS: usize = 256;
+
+/// How long an event stream stays open without a new result; the client reconnects with
+/// `Last-Event-ID` to go on waiting.
+const EVENT_STREAM_IDLE: Duration = Duration::from_secs(600);
+
+/// How long writing to a client may block, so one that stopped reading does not keep its
+/// event stream forever.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a client may take to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest batch answered in the response itself; bigger ones become background jobs.
+const SYNC_BATCH_LIMIT: usize = 25;
+
+/// Largest batch accepted at all.
+const MAX_BATCH: usize = 10_000;
+
+/// Interval of the comments sent on idle event streams, so proxies keep them open.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Prefix of the `Server::bind` addresses naming a unix domain socket, e.g. `unix:/run/check_nif.sock`.
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// REST server answering `GET /nif/{nif}` with JSON lookup results.
+pub struct Server {
+    listener: Listener,
+    state: Arc<State>,
+}
+
+/// Socket the server accepts connections on.
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, bool), // True when we created the socket file, removed when the server is dropped
+}
+
+/// Address the server listens on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    /// Tells whether only this host can connect: a loopback address or a unix socket.
+    pub fn is_local(&self) -> bool {
+        match self {
+            ListenAddr::Tcp(address) => address.ip().is_loopback(),
+            ListenAddr::Unix(_) => true,
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(address) => write!(f, "http://{}", address),
+            ListenAddr::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// A connection of either kind of listener, as the request handling needs it.
+trait Connection: Send + 'static {
+    fn set_read_timeout(&self, timeout: Duration) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Duration) -> io::Result<()>;
+    /// Client address, used to rate-limit clients without API keys.
+    fn peer(&self) -> String;
+}
+
+impl Connection for TcpStream {
+    fn set_read_timeout(&self, timeout: Duration) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, Some(timeout))
+    }
+
+    fn set_write_timeout(&self, timeout: Duration) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, Some(timeout))
+    }
+
+    fn peer(&self) -> String {
+        self.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default()
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_read_timeout(&self, timeout: Duration) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, Some(timeout))
+    }
+
+    fn set_write_timeout(&self, timeout: Duration) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, Some(timeout))
+    }
+
+    fn peer(&self) -> String {
+        "unix".to_string() // Every local client shares one identity
+    }
+}
+
+/// Binds a unix socket, replacing a stale socket file left by a previous run.
+#[cfg(unix)]
+fn bind_unix(path: &str) -> Result<Listener, String> {
+    let path = PathBuf::from(path);
+    if path.exists() {
+        if UnixStream::connect(&path).is_ok() {
+            return Err(format!("cannot listen on {}: another server is using it", path.display()));
+        }
+        std::fs::remove_file(&path).map_err(|e| format!("cannot remove stale socket {}: {}", path.display(), e))?;
+    }
+    let listener = UnixListener::bind(&path).map_err(|e| format!("cannot listen on {}: {}", path.display(), e))?;
+    Ok(Listener::Unix(listener, true))
+}
+
+/// Takes the socket passed by systemd socket activation.
+#[cfg(unix)]
+fn systemd_listener(fd: i32) -> Listener {
+    use std::os::fd::FromRawFd;
+    // SAFETY: systemd passed this descriptor to us, and nothing else in the process uses it
+    let unix = unsafe { UnixListener::from_raw_fd(fd) };
+    if unix.local_addr().is_ok() {
+        return Listener::Unix(unix, false); // The socket file belongs to systemd
+    }
+    // Not a unix socket (getsockname failed on the family): reuse the descriptor as TCP
+    let fd = std::os::fd::IntoRawFd::into_raw_fd(unix);
+    // SAFETY: same descriptor, ownership handed back from the unix listener
+    Listener::Tcp(unsafe { TcpListener::from_raw_fd(fd) })
+}
+
+#[cfg(not(unix))]
+fn bind_unix(path: &str) -> Result<Listener, String> {
+    Err(format!("cannot listen on {}: unix sockets are not supported on this platform", path))
+}
+
+/// Access control and job persistence of the server.
+#[derive(Debug, Default)]
+pub struct ServerConfig {
+    /// Keys required by every endpoint but `/health`; `None` disables authentication.
+    pub api_keys: Option<ApiKeys>,
+    /// Limits applied to each client, by API key name or else by IP address.
+    pub client_limits: ClientLimits,
+    /// Cross-origin policy for browser clients; `None` sends no CORS headers.
+    pub cors: Option<CorsConfig>,
+    /// Directory keeping the batch jobs, so they survive restarts; `None` keeps them in memory.
+    pub jobs_dir: Option<PathBuf>,
+}
+
+/// Everything the request handlers share.
+struct State {
+    options: LookupOptions,
+    api_keys: Option<ApiKeys>,
+    limiter: Option<ClientLimiter>, // `None` when no client limit is configured
+    cors: Option<CorsConfig>,
+    jobs: JobRegistry,
+    history: LookupHistory, // Shown by the dashboard
+    connections: Arc<AtomicUsize>,
+    event_streams: Arc<AtomicUsize>,
+}
+
+/// A place among the `max` counted by `count`, given back when dropped.
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    /// Takes a place, unless all `max` are taken.
+    fn take(count: &Arc<AtomicUsize>, max: usize) -> Option<Slot> {
+        if count.fetch_add(1, Ordering::SeqCst) >= max {
+            count.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Slot(count.clone()))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Server {
+    /// Binds the listening socket: `HOST:PORT` for TCP, or `unix:PATH` for a unix socket.
+    pub fn bind(address: &str, options: LookupOptions, config: ServerConfig) -> Result<Self, String> {
+        let listener = match address.strip_prefix(UNIX_PREFIX) {
+            Some(path) => bind_unix(path)?,
+            None => Listener::Tcp(TcpListener::bind(address).map_err(|e| format!("cannot listen on {}: {}", address, e))?),
+        };
+        Server::new(listener, options, config)
+    }
+
+    /// Like `bind`, but uses the socket passed by systemd socket activation instead when
+    /// the process was started that way; `address` is then ignored.
+    pub fn activate_or_bind(address: &str, options: LookupOptions, config: ServerConfig) -> Result<Self, String> {
+        let fds = systemd::listen_fds();
+        match fds.as_slice() {
+            [] => Server::bind(address, options, config),
+            #[cfg(unix)]
+            [fd] => Server::new(systemd_listener(*fd), options, config),
+            _ => Err(format!("systemd passed {} sockets, expected one", fds.len())),
+        }
+    }
+
+    fn new(listener: Listener, mut options: LookupOptions, config: ServerConfig) -> Result<Self, String> {
+        // Single lookups go before the NIFs of running jobs
+        options.request_queue.get_or_insert_with(|| Arc::new(RequestQueue::new()));
+        let jobs = match &config.jobs_dir {
+            Some(dir) => JobRegistry::open(dir, &job_options(&options))?,
+            None => JobRegistry::default(),
+        };
+        let limits = config.client_limits;
+        let limited = limits.per_minute.is_some() || limits.per_day.is_some();
+        Ok(Server {
+            listener,
+            state: Arc::new(State {
+                options,
+                api_keys: config.api_keys,
+                limiter: limited.then(|| ClientLimiter::new(limits)),
+                cors: config.cors,
+                jobs,
+                history: LookupHistory::default(),
+                connections: Arc::default(),
+                event_streams: Arc::default(),
+            }),
+        })
+    }
+
+    /// Address the server listens on.
+    pub fn local_addr(&self) -> Result<ListenAddr, String> {
+        match &self.listener {
+            Listener::Tcp(listener) => listener.local_addr().map(ListenAddr::Tcp).map_err(|e| e.to_string()),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let address = listener.local_addr().map_err(|e| e.to_string())?;
+                Ok(ListenAddr::Unix(address.as_pathname().map(PathBuf::from).unwrap_or_default()))
+            }
+        }
+    }
+
+    /// Accepts connections forever, each served by its own thread.
+    pub fn run(self) -> Result<(), String> {
+        loop {
+            match &self.listener {
+                Listener::Tcp(listener) => spawn_connection(&self.state, listener.accept().map(|(stream, _)| stream)),
+                #[cfg(unix)]
+                Listener::Unix(listener, _) => spawn_connection(&self.state, listener.accept().map(|(stream, _)| stream)),
+            }
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix(listener, true) = &self.listener
+            && let Some(path) = listener.local_addr().ok().as_ref().and_then(|address| address.as_pathname())
+        {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Serves an accepted connection on its own thread, or turns it away when there are too many.
+fn spawn_connection<C>(state: &Arc<State>, accepted: io::Result<C>)
+where
+    C: Connection,
+    for<'a> &'a C: Read + Write,
+{
+    let stream = match accepted {
+        Ok(stream) => stream,
+        Err(e) => {
+            logging::warn(
+                "accept_failed",
+                &[("error", e.to_string().into())],
+                format!("Error accepting connection: {}", e),
+            );
+            return;
+        }
+    };
+    let Some(slot) = Slot::take(&state.connections, MAX_CONNECTIONS) else {
+        let _ = Response::error(503, "too many connections").write_to(&mut &stream);
+        return;
+    };
+    let state = state.clone();
+    thread::spawn(move || serve_connection(&state, stream, slot));
+}
+
+/// Reads one request, answers it and closes the connection, giving `slot` back. An event
+/// stream gives it back as soon as it starts, for a place among the event streams.
+fn serve_connection<C>(state: &State, stream: C, slot: Slot)
+where
+    C: Connection,
+    for<'a> &'a C: Read + Write,
+{
+    let started = Instant::now();
+    let _ = stream.set_read_timeout(READ_TIMEOUT);
+    let _ = stream.set_write_timeout(WRITE_TIMEOUT);
+    let mut reader = BufReader::new(&stream);
+    let request = match http::read_request(&mut reader) {
+        Ok(request) => request,
+        Err(RequestError::Closed | RequestError::Io(_)) => return,
+        Err(RequestError::Invalid(e)) => {
+            let _ = Response::error(400, &e).write_to(&mut &stream);
+            return;
+        }
+        Err(RequestError::TooLarge(e)) => {
+            let _ = Response::error(413, &e).write_to(&mut &stream);
+            return;
+        }
+    };
+
+    // Reuse the caller's request ID so both sides can correlate, or make one up
+    let request_id = request
+        .header("x-request-id")
+        .filter(|id| is_valid_request_id(id))
+        .map_or_else(random_id, str::to_string);
+    logging::set_request_id(Some(request_id.clone()));
+
+    let peer = stream.peer();
+    let (route, key_name, mut response) = handle(state, &request, &peer);
+    // An event stream trades its place among the connections for one among the event streams
+    let _stream_slot = if response.stream.is_some() {
+        let stream_slot = Slot::take(&state.event_streams, MAX_EVENT_STREAMS);
+        match stream_slot {
+            Some(_) => drop(slot),
+            None => response = Response::error(503, "too many event streams"),
+        }
+        stream_slot
+    } else {
+        None
+    };
+    if let Some(cors) = &state.cors {
+        response = cors.apply(&request, response);
+    }
+    response = response.with_header("X-Request-Id", request_id.as_str());
+    let status = response.status;
+    if let Err(e) = response.write_to(&mut &stream) {
+        logging::warn(
+            "response_failed",
+            &[("error", e.to_string().into())],
+            format!("Error writing response: {}", e),
+        );
+    }
+    // Log the route pattern, not the path: paths hold NIFs
+    logging::info(
+        "http_request",
+        &[
+            ("method", request.method.as_str().into()),
+            ("route", route.into()),
+            ("http_status", status.into()),
+            ("api_key", key_name.clone().into()),
+            ("latency_ms", (started.elapsed().as_millis() as i64).into()),
+        ],
+        format!(
+            "{} {} -> {}{} [{}]",
+            request.method,
+            route,
+            status,
+            key_name.map(|name| format!(" (key {})", name)).unwrap_or_default(),
+            request_id
+        ),
+    );
+    logging::set_request_id(None);
+}
+
+/// Accepts caller-provided request IDs of up to 128 letters, digits and `-_.:`,
+/// so they cannot inject anything into logs or headers.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 128
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
+
+/// Authenticates and routes a request.
+///
+/// Returns the matched route pattern and the API key name, for logging, with the response.
+fn handle(state: &State, request: &Request, peer: &str) -> (&'static str, Option<String>, Response) {
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    let route = match segments.as_slice() {
+        ["health"] => "/health",
+        ["ui"] => "/ui",
+        ["ui", "status"] => "/ui/status",
+        ["graphql"] => "/graphql",
+        ["stats"] => "/stats",
+        ["nif", "batch"] => "/nif/batch",
+        ["nif", _] => "/nif/{nif}",
+        ["jobs"] => "/jobs",
+        ["jobs", _] => "/jobs/{id}",
+        ["jobs", _, "events"] => "/jobs/{id}/events",
+        _ => "unknown",
+    };
+    // Preflights carry no credentials, they are answered before authentication
+    if let Some(cors) = &state.cors
+        && CorsConfig::is_preflight(request)
+    {
+        return (route, None, cors.preflight(request));
+    }
+    if route == "/health" {
+        let health = || Response::json(200, &JsonValue::object().with("status", "ok"));
+        return (route, None, only(request, "GET", health));
+    }
+    // The page holds no data, it asks for the key itself before calling the API
+    if route == "/ui" {
+        return (route, None, only(request, "GET", || Response::html(200, DASHBOARD_PAGE)));
+    }
+
+    let key_name = match &state.api_keys {
+        Some(keys) => match keys.authenticate(request) {
+            Some(name) => Some(name),
+            None => {
+                let response = Response::error(401, "missing or invalid API key")
+                    .with_header("WWW-Authenticate", "Bearer");
+                return (route, None, response);
+            }
+        },
+        None => None,
+    };
+
+    let client = match &key_name {
+        Some(name) => format!("key:{}", name),
+        None => format!("ip:{}", peer),
+    };
+    if let Some(limiter) = &state.limiter
+        && let Err(wait) = limiter.check(&client)
+    {
+        return (route, key_name, rate_limited(wait));
+    }
+
+    let response = match segments.as_slice() {
+        ["stats"] => only(request, "GET", || stats(state)),
+        #[cfg(feature = "graphql")]
+        ["graphql"] => graphql_endpoint(state, request, &key_name, &client),
+        ["ui", "status"] => only(request, "GET", || {
+            let breaker = state.options.circuit_breaker.as_deref();
+            Response::json(200, &state.history.to_json(&key_name, breaker))
+        }),
+        ["nif", "batch"] => only(request, "POST", || batch(state, request, &key_name, &client)),
+        ["nif", nif] => only(request, "GET", || {
+            // The segment is percent-decoded: only a well-formed NIF goes on to nif.pt
+            if !is_nif_valid_local(nif) {
+                return Response::error(400, "not a valid NIF");
+            }
+            let result = lookup_nif(nif, &state.options);
+            state.history.record(&key_name, &result);
+            Response::json(200, &result.to_json())
+        }),
+        ["jobs"] => only(request, "GET", || {
+            let jobs: Vec<JsonValue> = state.jobs.list(&key_name).iter().map(|job| job.summary_json()).collect();
+            Response::json(200, &JsonValue::object().with("jobs", jobs))
+        }),
+        // Jobs of other keys are as good as missing
+        ["jobs", id] => match (request.method.as_str(), state.jobs.get(id)) {
+            (_, Some(job)) if job.owner != key_name => Response::error(404, "no such job"),
+            (_, None) => Response::error(404, "no such job"),
+            ("GET", Some(job)) => Response::json(200, &job.to_json()),
+            ("DELETE", Some(job)) => {
+                state.jobs.cancel(&job.id);
+                Response::empty(204)
+            }
+            _ => Response::error(405, "method not allowed").with_header("Allow", "GET, DELETE"),
+        },
+        ["jobs", id, "events"] => only(request, "GET", || match state.jobs.get(id) {
+            Some(job) if job.owner == key_name => job_events(job, request),
+            _ => Response::error(404, "no such job"),
+        }),
+        _ => Response::error(404, "not found"),
+    };
+    (route, key_name, response)
+}
+
+/// `429 Too Many Requests` for a client that must wait `wait` before its next request.
+fn rate_limited(wait: Duration) -> Response {
+    let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    let body = JsonValue::object()
+        .with("error", "rate limit exceeded")
+        .with("retry_after", retry_after as i64);
+    Response::json(429, &body).with_header("Retry-After", retry_after.to_string())
+}
+
+/// Counts the lookups of a request beyond the one its arrival counted, or returns the
+/// response refusing them.
+fn charge(state: &State, client: &str, lookups: usize) -> Result<(), Response> {
+    let Some(limiter) = &state.limiter else {
+        return Ok(());
+    };
+    let lookups = lookups as u64;
+    if let Some(per_day) = limiter.limits().per_day
+        && lookups > per_day
+    {
+        let message = format!("the request makes {} lookups, more than the daily quota of {}", lookups, per_day);
+        return Err(Response::error(413, &message));
+    }
+    limiter.check_n(client, lookups.saturating_sub(1)).map_err(rate_limited)
+}
+
+/// Runs `handler` when the request uses `method`, answers 405 otherwise.
+fn only(request: &Request, method: &str, handler: impl FnOnce() -> Response) -> Response {
+    if request.method == method {
+        handler()
+    } else {
+        Response::error(405, "method not allowed").with_header("Allow", method)
+    }
+}
+
+/// `POST /nif/batch`: looks up a JSON array of NIFs.
+///
+/// Small batches are answered directly; big ones, or any batch posted with `?async=true`,
+/// start a background job and get `202` with its ID, to be polled at `/jobs/{id}`.
+fn batch(state: &State, request: &Request, key_name: &Option<String>, client: &str) -> Response {
+    let nifs = match parse_nif_array(&request.body) {
+        Ok(nifs) => nifs,
+        Err(e) => return Response::error(400, &e),
+    };
+    if nifs.is_empty() {
+        return Response::error(400, "the batch is empty");
+    }
+    if nifs.len() > MAX_BATCH {
+        return Response::error(413, &format!("batches are limited to {} NIFs", MAX_BATCH));
+    }
+    // Each NIF counts against the client limits, also when left to a job
+    if let Err(refused) = charge(state, client, nifs.len()) {
+        return refused;
+    }
+    if nifs.len() <= SYNC_BATCH_LIMIT && request.query_param("async") != Some("true") {
+        let results: Vec<JsonValue> = nifs
+            .iter()
+            .map(|nif| {
+                let result = lookup_nif(nif, &state.options);
+                state.history.record(key_name, &result);
+                result.to_json()
+            })
+            .collect();
+        return Response::json(200, &JsonValue::object().with("results", results));
+    }
+    let job = state.jobs.start(nifs, key_name.clone(), job_options(&state.options));
+    let status_url = format!("/jobs/{}", job.id);
+    let body = JsonValue::object()
+        .with("job_id", job.id.as_str())
+        .with("status_url", status_url.as_str())
+        .with("total", job.nifs.len() as i64);
+    Response::json(202, &body).with_header("Location", status_url)
+}
+
+/// `GET|POST /graphql`: runs a GraphQL request, see `graphql`. Mutations need `POST`.
+#[cfg(feature = "graphql")]
+fn graphql_endpoint(state: &State, request: &Request, key_name: &Option<String>, client: &str) -> Response {
+    let graphql_request = match request.method.as_str() {
+        "POST" => {
+            let parsed = std::str::from_utf8(&request.body)
+                .map_err(|_| "the body is not UTF-8".to_string())
+                .and_then(JsonValue::parse)
+                .and_then(|json| GraphqlRequest::from_json(&json));
+            match parsed {
+                Ok(graphql_request) => graphql_request,
+                Err(e) => return Response::error(400, &e),
+            }
+        }
+        "GET" => {
+            let variables = match request.query_param("variables").map(JsonValue::parse).transpose() {
+                Ok(variables) => variables,
+                Err(e) => return Response::error(400, &format!("invalid variables: {}", e)),
+            };
+            let graphql_request = GraphqlRequest {
+                query: request.query_param("query").unwrap_or_default().to_string(),
+                variables,
+                operation_name: request.query_param("operationName").map(String::from),
+            };
+            if graphql_request.is_mutation() {
+                return Response::error(405, "mutations need POST").with_header("Allow", "POST");
+            }
+            graphql_request
+        }
+        _ => return Response::error(405, "method not allowed").with_header("Allow", "GET, POST"),
+    };
+    let root = GraphqlRoot { state, key_name };
+    if let Err(refused) = charge(state, client, graphql::cost(&graphql_request, &root)) {
+        return refused;
+    }
+    Response::json(200, &graphql::execute(&graphql_request, &root))
+}
+
+/// Root fields of the GraphQL API, resolved for the client with the API key `key_name`.
+#[cfg(feature = "graphql")]
+struct GraphqlRoot<'a> {
+    state: &'a State,
+    key_name: &'a Option<String>,
+}
+
+#[cfg(feature = "graphql")]
+impl GraphqlRoot<'_> {
+    /// The job with this ID, if it belongs to the client.
+    fn own_job(&self, args: &JsonValue) -> Option<Arc<Job>> {
+        let id = match args.get("id")? {
+            JsonValue::Int(id) => id.to_string(),
+            id => id.as_str()?.to_string(),
+        };
+        self.state.jobs.get(&id).filter(|job| job.owner == *self.key_name)
+    }
+}
+
+#[cfg(feature = "graphql")]
+impl Root for GraphqlRoot<'_> {
+    fn query(&self, field: &str, args: &JsonValue) -> Result<JsonValue, String> {
+        let state = self.state;
+        match field {
+            "nif" => {
+                let result = lookup_nif(args.str_field("nif").unwrap_or_default(), &state.options);
+                state.history.record(self.key_name, &result);
+                Ok(result.to_json())
+            }
+            "nifs" => {
+                let nifs = nif_list(args)?;
+                if nifs.len() > SYNC_BATCH_LIMIT {
+                    return Err(format!("nifs takes at most {} NIFs, start a job for more", SYNC_BATCH_LIMIT));
+                }
+                let results = nifs.iter().map(|nif| {
+                    let result = lookup_nif(nif, &state.options);
+                    state.history.record(self.key_name, &result);
+                    result.to_json()
+                });
+                Ok(JsonValue::Array(results.collect()))
+            }
+            "stored" => {
+                let nif = normalize_nif(args.str_field("nif").unwrap_or_default());
+                let record = state.options.store.as_ref().and_then(|store| store.get(nif));
+                Ok(record.as_ref().map(StoreRecord::to_json).into())
+            }
+            "job" => Ok(self.own_job(args).map(|job| job.to_json()).into()),
+            "jobs" => Ok(JsonValue::Array(state.jobs.list(self.key_name).iter().map(|job| job.to_json()).collect())),
+            other => Err(format!("no resolver for field '{}'", other)),
+        }
+    }
+
+    fn mutate(&self, field: &str, args: &JsonValue) -> Result<JsonValue, String> {
+        let state = self.state;
+        match field {
+            "startJob" => {
+                let nifs = nif_list(args)?;
+                if nifs.is_empty() {
+                    return Err("the batch is empty".to_string());
+                }
+                if nifs.len() > MAX_BATCH {
+                    return Err(format!("batches are limited to {} NIFs", MAX_BATCH));
+                }
+                let job = state.jobs.start(nifs, self.key_name.clone(), job_options(&state.options));
+                Ok(job.to_json())
+            }
+            "cancelJob" => Ok(JsonValue::Bool(self.own_job(args).is_some_and(|job| state.jobs.cancel(&job.id)))),
+            other => Err(format!("no resolver for field '{}'", other)),
+        }
+    }
+
+    fn lookups(&self, field: &str, args: &JsonValue) -> usize {
+        match field {
+            "nif" => 1,
+            "nifs" => nif_list(args).map_or(0, |nifs| nifs.len()),
+            _ => 0,
+        }
+    }
+
+    fn cost(&self, field: &str, args: &JsonValue) -> usize {
+        match field {
+            "startJob" => nif_list(args).map_or(0, |nifs| nifs.len()),
+            _ => self.lookups(field, args),
+        }
+    }
+
+    // A document makes no more lookups than a synchronous batch, however many fields it aliases
+    fn max_lookups(&self) -> usize {
+        SYNC_BATCH_LIMIT
+    }
+}
+
+/// The `nifs` argument, a single NIF counting as a list of one.
+#[cfg(feature = "graphql")]
+fn nif_list(args: &JsonValue) -> Result<Vec<String>, String> {
+    match args.get("nifs") {
+        Some(JsonValue::Array(items)) => Ok(items.iter().filter_map(|nif| nif.as_str().map(|nif| nif.trim().to_string())).collect()),
+        Some(JsonValue::String(nif)) => Ok(vec![nif.trim().to_string()]),
+        _ => Err("argument 'nifs' is required".to_string()),
+    }
+}
+
+/// Options of the lookups of batch jobs, which let single lookups go first.
+fn job_options(options: &LookupOptions) -> LookupOptions {
+    let mut options = options.clone();
+    options.priority = Priority::Batch;
+    options
+}
+
+/// `GET /jobs/{id}/events`: streams the job as Server-Sent Events.
+///
+/// Each result is a `result` event whose ID is its position in the batch, so a client
+/// reconnecting with `Last-Event-ID` resumes where it stopped. A `finished` event ends
+/// the stream, which is also closed after `EVENT_STREAM_IDLE` without a result.
+fn job_events(job: Arc<Job>, request: &Request) -> Response {
+    let mut seen = request
+        .header("last-event-id")
+        .and_then(|id| id.parse::<usize>().ok())
+        .map_or(0, |id| id + 1);
+    Response::streaming("text/event-stream", move |out| {
+        let total = job.nifs.len();
+        let mut last_result = Instant::now();
+        loop {
+            let (results, finished) = job.wait_for_results(seen, KEEP_ALIVE);
+            if results.is_empty() && !finished {
+                if last_result.elapsed() >= EVENT_STREAM_IDLE {
+                    return Ok(());
+                }
+                out.write_all(b": keep-alive\n\n")?;
+            } else {
+                last_result = Instant::now();
+            }
+            for result in results {
+                let data = JsonValue::object()
+                    .with("index", seen as i64)
+                    .with("done", (seen + 1) as i64)
+                    .with("total", total as i64)
+                    .with("result", result);
+                write_event(out, "result", Some(seen), &data)?;
+                seen += 1;
+            }
+            if finished && seen >= total {
+                let data = JsonValue::object().with("done", seen as i64).with("total", total as i64);
+                return write_event(out, "finished", None, &data);
+            }
+            out.flush()?;
+        }
+    })
+}
+
+fn write_event(out: &mut dyn Write, event: &str, id: Option<usize>, data: &JsonValue) -> io::Result<()> {
+    writeln!(out, "event: {}", event)?;
+    if let Some(id) = id {
+        writeln!(out, "id: {}", id)?;
+    }
+    write!(out, "data: {}\n\n", data)?;
+    out.flush()
+}
+
+/// Reads the batch body: a JSON array of NIFs, as strings or numbers.
+fn parse_nif_array(body: &[u8]) -> Result<Vec<String>, String> {
+    let text = std::str::from_utf8(body).map_err(|_| "the body is not UTF-8".to_string())?;
+    let json = JsonValue::parse(text)?;
+    let items = json.as_array().ok_or("expected a JSON array of NIFs")?;
+    items
+        .iter()
+        .map(|item| match item {
+            JsonValue::String(nif) => Ok(nif.trim().to_string()),
+            JsonValue::Int(nif) => Ok(format!("{:09}", nif)),
+            other => Err(format!("expected a NIF, found {}", other)),
+        })
+        .collect()
+}
+
+/// `GET /stats`: requests counted per API key.
+fn stats(state: &State) -> Response {
+    let usage = state.api_keys.as_ref().map(ApiKeys::usage).unwrap_or_default();
+    let by_key = JsonValue::Object(
+        usage
+            .into_iter()
+            .map(|(name, count)| (name, JsonValue::Int(count as i64)))
+            .collect(),
+    );
+    let mut stats = JsonValue::object().with("requests_by_key", by_key);
+    if let Some(health) = &state.options.backend_health {
+        stats = stats.with("fallbacks", health.to_json());
+    }
+    Response::json(200, &stats)
+}
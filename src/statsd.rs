@@ -0,0 +1,70 @@
+// statsd.rs
+
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use crate::status::NifStatus;
+
+/// Fire-and-forget StatsD client, using the DogStatsD tag extension understood by Datadog.
+///
+/// Metrics sent for every lookup:
+/// - `<prefix>.lookups` counter, tagged with `status` and `cache_hit`.
+/// - `<prefix>.lookup.duration` timing in milliseconds, with the same tags.
+#[derive(Debug)]
+pub struct StatsdClient {
+    socket: UdpSocket,
+    prefix: String,
+    tags: Vec<String>,
+}
+
+impl StatsdClient {
+    /// Creates a client sending to `address` (`host:port`); metric names get `prefix.` prepended.
+    pub fn new(address: &str, prefix: &str, tags: Vec<String>) -> Result<Self, String> {
+        let target = address
+            .to_socket_addrs()
+            .map_err(|e| format!("invalid StatsD address {}: {}", address, e))?
+            .next()
+            .ok_or_else(|| format!("StatsD address {} did not resolve", address))?;
+        let bind_addr = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind_addr).map_err(|e| e.to_string())?;
+        socket.connect(target).map_err(|e| e.to_string())?;
+        Ok(StatsdClient {
+            socket,
+            prefix: prefix.trim_end_matches('.').to_string(),
+            tags,
+        })
+    }
+
+    /// Increments a counter.
+    pub fn count(&self, name: &str, value: i64, tags: &[&str]) {
+        self.send(name, &value.to_string(), "c", tags);
+    }
+
+    /// Records a timing, in milliseconds.
+    pub fn timing(&self, name: &str, duration: Duration, tags: &[&str]) {
+        self.send(name, &duration.as_millis().to_string(), "ms", tags);
+    }
+
+    /// Records the outcome and duration of one lookup.
+    pub fn record_lookup(&self, status: &NifStatus, duration: Duration, cache_hit: bool) {
+        let status_tag = format!("status:{}", status.label());
+        let cache_tag = format!("cache_hit:{}", cache_hit);
+        self.count("lookups", 1, &[&status_tag, &cache_tag]);
+        self.timing("lookup.duration", duration, &[&status_tag, &cache_tag]);
+    }
+
+    fn send(&self, name: &str, value: &str, kind: &str, tags: &[&str]) {
+        let mut line = if self.prefix.is_empty() {
+            format!("{}:{}|{}", name, value, kind)
+        } else {
+            format!("{}.{}:{}|{}", self.prefix, name, value, kind)
+        };
+        let all_tags: Vec<&str> = self.tags.iter().map(String::as_str).chain(tags.iter().copied()).collect();
+        if !all_tags.is_empty() {
+            line.push_str("|#");
+            line.push_str(&all_tags.join(","));
+        }
+        // Metrics must never break a lookup, so delivery errors are ignored
+        let _ = self.socket.send(line.as_bytes());
+    }
+}
@@ -0,0 +1,77 @@
+// status.rs
+
+/// Represents the possible outcomes of a NIF query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NifStatus {
+    ValidKnown,        // Valid NIF and known entity
+    ValidUnknown,      // Valid NIF but unknown entity
+    Error,             // Error message found (invalid NIF)
+    MultipleResults,   // Multiple companies, NIF not available [Only seen with "000000000"]
+    HttpError(u16),    // nif.pt answered with a non-success HTTP status (404, 429, 500, 503, ...)
+    CircuitOpen,       // Remote lookup skipped, nif.pt kept failing recently (local validation only)
+    UnsupportedLayout, // nif.pt answered with a page matching no known layout, the parser needs an update
+    WrongCategory,     // Remote lookup skipped, the NIF is of a category the run does not expect
+    Cancelled,         // Lookup abandoned through its `CancellationToken` before an answer came
+    Unknown,           // Could not determine status
+}
+
+impl NifStatus {
+    /// Short machine-friendly name of the status, used in metrics and logs.
+    pub fn label(&self) -> &'static str {
+        match self {
+            NifStatus::ValidKnown => "valid_known",
+            NifStatus::ValidUnknown => "valid_unknown",
+            NifStatus::Error => "error",
+            NifStatus::MultipleResults => "multiple_results",
+            NifStatus::HttpError(_) => "http_error",
+            NifStatus::CircuitOpen => "circuit_open",
+            NifStatus::UnsupportedLayout => "unsupported_layout",
+            NifStatus::WrongCategory => "wrong_category",
+            NifStatus::Cancelled => "cancelled",
+            NifStatus::Unknown => "unknown",
+        }
+    }
+
+    /// Parses a label produced by `label`. `http_error` is not accepted since it lacks the code.
+    pub fn from_label(label: &str) -> Option<NifStatus> {
+        match label {
+            "valid_known" => Some(NifStatus::ValidKnown),
+            "valid_unknown" => Some(NifStatus::ValidUnknown),
+            "error" => Some(NifStatus::Error),
+            "multiple_results" => Some(NifStatus::MultipleResults),
+            "circuit_open" => Some(NifStatus::CircuitOpen),
+            "unsupported_layout" => Some(NifStatus::UnsupportedLayout),
+            "wrong_category" => Some(NifStatus::WrongCategory),
+            "cancelled" => Some(NifStatus::Cancelled),
+            "unknown" => Some(NifStatus::Unknown),
+            _ => None,
+        }
+    }
+
+    /// Tells whether this is an actual answer from nif.pt about the NIF, worth caching.
+    pub fn is_definitive(&self) -> bool {
+        matches!(
+            self,
+            NifStatus::ValidKnown | NifStatus::ValidUnknown | NifStatus::Error | NifStatus::MultipleResults
+        )
+    }
+
+    /// Returns the HTTP status code carried by `NifStatus::HttpError`, if any.
+    pub fn http_status(&self) -> Option<u16> {
+        match self {
+            NifStatus::HttpError(code) => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// Tells whether repeating the same query later may give a different answer.
+    ///
+    /// Rate limiting (429) and server-side failures (5xx) are transient; a 404 or
+    /// any parsed answer from the site is not.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            NifStatus::HttpError(code) => *code == 429 || (500..600).contains(code),
+            _ => false,
+        }
+    }
+}
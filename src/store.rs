@@ -0,0 +1,647 @@
+// store.rs
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::encoding::{base64, decode_base64};
+use crate::entity::NifEntity;
+use crate::json::JsonValue;
+use crate::logging;
+use crate::status::NifStatus;
+use crate::time::{from_unix_secs, unix_secs};
+
+/// One answer kept by the local store, with where it came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoreRecord {
+    pub nif: String,
+    pub status: NifStatus,
+    pub entity: Option<NifEntity>,
+    pub source: String, // e.g. `import:empresas.csv`
+    pub recorded_at: SystemTime,
+    /// Set on tombstones: when nif.pt stopped knowing an entity, whose last known details
+    /// `entity` keeps.
+    pub removed_at: Option<SystemTime>,
+    pub tag: Option<String>, // Campaign of the record, e.g. from `store import --tag`
+}
+
+impl StoreRecord {
+    /// Serializes the record as one JSON object.
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::object()
+            .with("nif", self.nif.as_str())
+            .with("status", self.status.label())
+            .with("source", self.source.as_str())
+            .with("recorded_at", unix_secs(self.recorded_at))
+            .with("entity", self.entity.as_ref().map(NifEntity::to_json))
+            .with("removed_at", self.removed_at.map(unix_secs))
+            .with("tag", self.tag.as_deref())
+            .with("hash", self.content_hash())
+    }
+
+    /// `content_hash` of the record, of its current entity: a tombstone hashes as an answer
+    /// without entity.
+    pub fn content_hash(&self) -> String {
+        content_hash(self.status, self.current_entity())
+    }
+
+    /// Tells whether the record is a tombstone, kept after nif.pt stopped knowing the entity.
+    pub fn is_tombstone(&self) -> bool {
+        self.removed_at.is_some()
+    }
+
+    /// The entity as currently known: none for tombstones, whose entity is only a snapshot.
+    pub fn current_entity(&self) -> Option<&NifEntity> {
+        self.entity.as_ref().filter(|_| !self.is_tombstone())
+    }
+
+    /// The record replacing this one once nif.pt answered `status` and `entity`.
+    ///
+    /// Known entities keep the details the results page lacks. A known entity that nif.pt
+    /// now rejects or does not know becomes a tombstone: the new status, with the entity
+    /// kept as last known instead of overwritten; later answers without an entity keep it.
+    /// The tag of the record stays.
+    pub fn updated(&self, status: NifStatus, entity: Option<NifEntity>, source: String, recorded_at: SystemTime) -> StoreRecord {
+        let (entity, removed_at) = match status {
+            NifStatus::ValidKnown => (entity.or_else(|| self.entity.clone()), None),
+            _ if entity.is_none() && self.is_tombstone() => (self.entity.clone(), self.removed_at),
+            _ if entity.is_none() && is_removal(self.status, status) => (self.entity.clone(), Some(recorded_at)),
+            _ => (entity, None),
+        };
+        StoreRecord {
+            nif: self.nif.clone(),
+            status,
+            entity,
+            source,
+            recorded_at,
+            removed_at,
+            tag: self.tag.clone(),
+        }
+    }
+
+    /// Time elapsed since the record was written (zero if the clock went backwards).
+    pub fn age(&self) -> Duration {
+        self.recorded_at.elapsed().unwrap_or_default()
+    }
+
+    /// Parses the output of `to_json`.
+    pub fn from_json(json: &JsonValue) -> Option<Self> {
+        let secs = json.get("recorded_at")?.as_i64()?;
+        Some(StoreRecord {
+            nif: json.str_field("nif")?.to_string(),
+            status: NifStatus::from_label(json.str_field("status")?)?,
+            entity: json.get("entity").and_then(NifEntity::from_json),
+            source: json.str_field("source").unwrap_or_default().to_string(),
+            recorded_at: from_unix_secs(secs),
+            removed_at: json.get("removed_at").and_then(JsonValue::as_i64).map(from_unix_secs),
+            tag: json.str_field("tag").map(str::to_string),
+        })
+    }
+}
+
+/// Tells whether going from `previous` to `status` means nif.pt no longer knows an entity:
+/// a known NIF now rejected as invalid or without an entity.
+pub fn is_removal(previous: NifStatus, status: NifStatus) -> bool {
+    previous == NifStatus::ValidKnown && matches!(status, NifStatus::Error | NifStatus::ValidUnknown)
+}
+
+/// Fingerprint of an answer, equal for two answers telling the same thing: the SHA-256 (in
+/// hex) of the status and the entity details read from nif.pt, compared without case and with
+/// runs of whitespace as single spaces. District and municipality are derived from the
+/// postal code, so they are left out.
+pub fn content_hash(status: NifStatus, entity: Option<&NifEntity>) -> String {
+    let mut content = status.label().to_string();
+    if let Some(entity) = entity {
+        let fields = [Some(&entity.name), entity.address.as_ref(), entity.postal_code.as_ref(), entity.locality.as_ref(), entity.phone.as_ref(), entity.email.as_ref()];
+        for value in fields {
+            content.push('\u{1f}'); // Unit separator, so values cannot run into each other
+            if let Some(value) = value {
+                content += &value.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+            }
+        }
+    }
+    let digest = ring::digest::digest(&ring::digest::SHA256, content.as_bytes());
+    digest.as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// How much of the history of each NIF the store keeps, applied by `ResultStore::prune`.
+/// Current records are always kept, whatever their age; `store reverify` refreshes them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub keep: Option<Duration>,   // Delete the records superseded longer ago than this
+    pub keep_last: Option<usize>, // Keep at most this many records per NIF, the current one included
+}
+
+impl RetentionPolicy {
+    /// Tells whether the policy keeps everything.
+    pub fn is_empty(&self) -> bool {
+        self.keep.is_none() && self.keep_last.is_none()
+    }
+
+    /// Which records of `history`, oldest first, the policy keeps.
+    pub fn keeps(&self, history: &[StoreRecord]) -> Vec<bool> {
+        let first_kept = history.len().saturating_sub(self.keep_last.unwrap_or(usize::MAX).max(1));
+        (0..history.len())
+            .map(|i| {
+                // A record stopped being current when the next one was written
+                let recent = history.get(i + 1).is_none_or(|next| self.keep.is_none_or(|keep| next.age() < keep));
+                i >= first_kept && recent
+            })
+            .collect()
+    }
+}
+
+/// Time between two prunings by `spawn_pruner`.
+pub const PRUNE_INTERVAL: Duration = Duration::from_secs(86_400);
+
+/// Prunes `store` by `policy` now and then every `PRUNE_INTERVAL` from a background thread,
+/// for as long as the process lives, so long-running commands keep the history bounded.
+/// Failures are logged and retried at the next interval.
+pub fn spawn_pruner(store: Arc<dyn ResultStore>, policy: RetentionPolicy) {
+    std::thread::spawn(move || {
+        loop {
+            match store.prune(&policy) {
+                Ok(0) => {}
+                Ok(removed) => logging::info(
+                    "store_pruned",
+                    &[("removed", (removed as i64).into()), ("store", store.location().into())],
+                    format!("Pruned {} records from store {}", removed, store.location()),
+                ),
+                Err(e) => logging::warn(
+                    "store_prune_failed",
+                    &[("store", store.location().into()), ("error", e.clone().into())],
+                    format!("Cannot prune the store: {}", e),
+                ),
+            }
+            std::thread::sleep(PRUNE_INTERVAL);
+        }
+    });
+}
+
+/// Environment variable holding the key of an encrypted store, when `--store-key` is not given.
+pub const STORE_KEY_ENV: &str = "CHECK_NIF_STORE_KEY";
+
+/// Start of the lines of encrypted records, followed by the base64 of the nonce and the
+/// sealed record JSON.
+const ENCRYPTED_PREFIX: &str = "enc1:";
+
+/// Key of an encrypted `Store`: every record is sealed with AES-256-GCM on its own line,
+/// so the NIFs and the personal details of sole traders cannot be read off the file.
+pub struct StoreKey(ring::aead::LessSafeKey);
+
+impl StoreKey {
+    /// Parses a key written as 64 hex digits, e.g. by `openssl rand -hex 32`.
+    pub fn from_hex(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        let bytes: Option<Vec<u8>> = (text.len() == 64)
+            .then(|| (0..32).map(|i| u8::from_str_radix(text.get(2 * i..2 * i + 2)?, 16).ok()).collect())
+            .flatten();
+        let bytes = bytes.ok_or("invalid store key, expected 64 hex digits (openssl rand -hex 32)")?;
+        let key = ring::aead::UnboundKey::new(&ring::aead::AES_256_GCM, &bytes).map_err(|_| "invalid store key")?;
+        Ok(StoreKey(ring::aead::LessSafeKey::new(key)))
+    }
+
+    /// Encrypts one line of the store file, under a random nonce.
+    fn seal(&self, line: &str) -> String {
+        let nonce = rand::random::<[u8; ring::aead::NONCE_LEN]>();
+        let mut data = line.as_bytes().to_vec();
+        // Sealing only fails for inputs of dozens of gigabytes
+        let _ = self.0.seal_in_place_append_tag(ring::aead::Nonce::assume_unique_for_key(nonce), ring::aead::Aad::empty(), &mut data);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(data);
+        format!("{}{}", ENCRYPTED_PREFIX, base64(&sealed))
+    }
+
+    /// Decrypts a line written by `seal`; `None` with another key or a damaged line.
+    fn open(&self, line: &str) -> Option<String> {
+        let mut sealed = decode_base64(line.strip_prefix(ENCRYPTED_PREFIX)?)?;
+        if sealed.len() < ring::aead::NONCE_LEN {
+            return None;
+        }
+        let mut data = sealed.split_off(ring::aead::NONCE_LEN);
+        let nonce = ring::aead::Nonce::try_assume_unique_for_key(&sealed).ok()?;
+        let plain = self.0.open_in_place(nonce, ring::aead::Aad::empty(), &mut data).ok()?;
+        String::from_utf8(plain.to_vec()).ok()
+    }
+}
+
+impl std::fmt::Debug for StoreKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StoreKey(..)")
+    }
+}
+
+/// Default location of the store: `$XDG_DATA_HOME/check_nif/store.jsonl`,
+/// falling back to `~/.local/share/check_nif/store.jsonl`.
+pub fn default_store_path() -> PathBuf {
+    let base = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("share")))
+        .unwrap_or_else(std::env::temp_dir);
+    base.join("check_nif").join("store.jsonl")
+}
+
+/// Persistence of the answers looked up or imported, with the earlier answers of each NIF.
+///
+/// `Store` (a JSON lines file) is the one shipped; implement this trait to keep the
+/// records in another database, and pass it as `LookupOptions::store`.
+pub trait ResultStore: Send + Sync {
+    /// Returns the current record of `nif`, if any.
+    fn get(&self, nif: &str) -> Option<StoreRecord>;
+
+    /// Writes records, each becoming the current record of its NIF.
+    fn put(&self, records: Vec<StoreRecord>) -> Result<(), String>;
+
+    /// Every record kept for `nif`, oldest first, the current one last.
+    fn history(&self, nif: &str) -> Result<Vec<StoreRecord>, String>;
+
+    /// The record of `nif` that was current at `time`: the last one recorded by then.
+    fn as_of(&self, nif: &str, time: SystemTime) -> Result<Option<StoreRecord>, String> {
+        Ok(self.history(nif)?.into_iter().rev().find(|record| record.recorded_at <= time))
+    }
+
+    /// Tells whether the current record of `nif` tells something else than the one before
+    /// it, by their `content_hash`; false for NIFs with a single record or none.
+    fn has_changed(&self, nif: &str) -> bool {
+        match self.history(nif).unwrap_or_default().as_slice() {
+            [.., before, current] => before.content_hash() != current.content_hash(),
+            _ => false,
+        }
+    }
+
+    /// Deletes the records of the history that `policy` does not keep, never a current one,
+    /// and returns how many there were.
+    fn prune(&self, policy: &RetentionPolicy) -> Result<usize, String>;
+
+    /// Returns the current record of every NIF, in no particular order.
+    fn records(&self) -> Vec<StoreRecord>;
+
+    /// Number of NIFs in the store.
+    fn len(&self) -> usize {
+        self.records().len()
+    }
+
+    /// Tells whether the store holds no record.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Human-readable description of where the store lives.
+    fn location(&self) -> String;
+}
+
+/// Local database of known NIFs, e.g. imported from open-data company registries.
+///
+/// Unlike the cache its records never expire: lookups answered from the store do not
+/// touch the network. Persisted as JSON lines, the last record of a NIF winning; the
+/// earlier ones are its history, until pruned. Opened with a `StoreKey`, the lines are
+/// encrypted.
+#[derive(Debug)]
+pub struct Store {
+    path: PathBuf,
+    records: Mutex<HashMap<String, Vec<StoreRecord>>>, // Oldest first, per NIF
+    key: Option<StoreKey>,
+}
+
+impl Store {
+    /// Opens the store at `path`; a missing file is an empty store.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, String> {
+        Store::open_with_key(path, None)
+    }
+
+    /// Opens the store at `path`, encrypted with `key`: records are written encrypted and
+    /// read back with it; unencrypted records, written before the store had a key, are
+    /// still read, and encrypted by the next `prune`. Without a key, a store holding
+    /// encrypted records cannot be opened.
+    pub fn open_with_key(path: impl AsRef<Path>, key: Option<StoreKey>) -> Result<Self, String> {
+        let path = path.as_ref().to_path_buf();
+        let (records, _) = read_records(&path, key.as_ref())?;
+        Ok(Store {
+            path,
+            records: Mutex::new(records),
+            key,
+        })
+    }
+
+    /// The line of `record` in the file, encrypted if the store has a key.
+    fn line(&self, record: &StoreRecord) -> String {
+        let json = record.to_json().to_string();
+        match &self.key {
+            Some(key) => key.seal(&json),
+            None => json,
+        }
+    }
+
+    /// Path of the backing file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Locks the store against the other processes writing it, until the file returned is
+    /// dropped. The lock is a separate file, `store.jsonl.lock` next to `store.jsonl`, as
+    /// `prune` replaces the store file itself.
+    fn lock(&self) -> std::io::Result<File> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut lock_path = self.path.clone().into_os_string();
+        lock_path.push(".lock");
+        let file = OpenOptions::new().write(true).create(true).truncate(false).open(lock_path)?;
+        file.lock()?;
+        Ok(file)
+    }
+
+    /// Rewrites the file with exactly the given records, in a new file renamed over the old
+    /// one; the caller holds the lock.
+    fn rewrite(&self, records: &HashMap<String, Vec<StoreRecord>>) -> Result<(), String> {
+        let write = || -> std::io::Result<()> {
+            let tmp = self.path.with_extension("tmp");
+            let mut out = BufWriter::new(File::create(&tmp)?);
+            for record in records.values().flatten() {
+                writeln!(out, "{}", self.line(record))?;
+            }
+            out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            fs::rename(&tmp, &self.path)
+        };
+        write().map_err(|e| format!("cannot rewrite store {}: {}", self.path.display(), e))
+    }
+}
+
+/// Reads the records of the store file at `path`, oldest first per NIF, decrypting them
+/// with `key`; a missing file holds none. Also returns how many records were not encrypted.
+fn read_records(path: &Path, key: Option<&StoreKey>) -> Result<(HashMap<String, Vec<StoreRecord>>, usize), String> {
+    let mut records: HashMap<String, Vec<StoreRecord>> = HashMap::new();
+    let (mut plain, mut encrypted, mut decrypted) = (0, 0, 0);
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((records, 0)),
+        Err(e) => return Err(format!("cannot open store {}: {}", path.display(), e)),
+    };
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| format!("cannot read store {}: {}", path.display(), e))?;
+        let text = if line.starts_with(ENCRYPTED_PREFIX) {
+            let key = key.ok_or_else(|| format!("store {} is encrypted, its key is needed", path.display()))?;
+            encrypted += 1;
+            match key.open(&line) {
+                Some(text) => {
+                    decrypted += 1;
+                    text
+                }
+                None => continue, // Damaged, e.g. a write cut short by a crash
+            }
+        } else {
+            plain += 1;
+            line
+        };
+        // Skip lines that do not parse, e.g. a write cut short by a crash
+        if let Ok(json) = JsonValue::parse(&text)
+            && let Some(record) = StoreRecord::from_json(&json)
+        {
+            records.entry(record.nif.clone()).or_default().push(record);
+        }
+    }
+    if encrypted > 0 && decrypted == 0 {
+        return Err(format!("cannot decrypt store {}: wrong key", path.display()));
+    }
+    Ok((records, plain))
+}
+
+impl ResultStore for Store {
+    fn get(&self, nif: &str) -> Option<StoreRecord> {
+        self.records.lock().unwrap().get(nif).and_then(|history| history.last()).cloned()
+    }
+
+    fn put(&self, new_records: Vec<StoreRecord>) -> Result<(), String> {
+        let mut records = self.records.lock().unwrap();
+        let write = || -> std::io::Result<()> {
+            // Opened under the lock, so not a file that a pruning is replacing
+            let _lock = self.lock()?;
+            let mut file = OpenOptions::new().create(true).read(true).append(true).open(&self.path)?;
+            // A write cut short by a crash left a line without its newline: end it, so that the
+            // damage stays in that line
+            let mut last = [b'\n'];
+            if file.seek(SeekFrom::End(-1)).is_ok() {
+                file.read_exact(&mut last)?;
+            }
+            let mut out = BufWriter::new(file);
+            if last[0] != b'\n' {
+                writeln!(out)?;
+            }
+            for record in &new_records {
+                writeln!(out, "{}", self.line(record))?;
+            }
+            out.flush()
+        };
+        write().map_err(|e| format!("cannot write store {}: {}", self.path.display(), e))?;
+        for record in new_records {
+            records.entry(record.nif.clone()).or_default().push(record);
+        }
+        Ok(())
+    }
+
+    fn history(&self, nif: &str) -> Result<Vec<StoreRecord>, String> {
+        Ok(self.records.lock().unwrap().get(nif).cloned().unwrap_or_default())
+    }
+
+    /// Prunes the file as it is now, with the records other processes appended since the
+    /// store was opened, which then become part of this store too. With a key the file is
+    /// rewritten if it holds records not encrypted yet, even if none are pruned.
+    fn prune(&self, policy: &RetentionPolicy) -> Result<usize, String> {
+        let mut records = self.records.lock().unwrap();
+        let _lock = self.lock().map_err(|e| format!("cannot lock store {}: {}", self.path.display(), e))?;
+        let (mut pruned, plain) = read_records(&self.path, self.key.as_ref())?;
+        let mut removed = 0;
+        for history in pruned.values_mut() {
+            let before = history.len();
+            let mut kept = policy.keeps(history).into_iter();
+            history.retain(|_| kept.next().unwrap_or(true));
+            removed += before - history.len();
+        }
+        if removed > 0 || (self.key.is_some() && plain > 0) {
+            self.rewrite(&pruned)?;
+        }
+        *records = pruned;
+        Ok(removed)
+    }
+
+    fn records(&self) -> Vec<StoreRecord> {
+        self.records.lock().unwrap().values().filter_map(|history| history.last()).cloned().collect()
+    }
+
+    fn len(&self) -> usize {
+        self.records.lock().unwrap().len()
+    }
+
+    fn location(&self) -> String {
+        self.path.display().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Path of a store file in a directory of its own, removed first.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("check_nif-store-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("store.jsonl")
+    }
+
+    fn record(nif: &str, name: &str, days_ago: u64) -> StoreRecord {
+        let entity = NifEntity {
+            nif: nif.to_string(),
+            name: name.to_string(),
+            postal_code: Some("1000-001".to_string()),
+            ..NifEntity::default()
+        };
+        StoreRecord {
+            nif: nif.to_string(),
+            status: NifStatus::ValidKnown,
+            entity: Some(entity),
+            source: "test".to_string(),
+            recorded_at: from_unix_secs(unix_secs(SystemTime::now()) - (days_ago * 86_400) as i64),
+            removed_at: None,
+            tag: None,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = scratch("round-trip");
+        let store = Store::open(&path).unwrap();
+        assert!(store.is_empty());
+        let mut tagged = record("500960046", "Exemplo, Lda.", 1);
+        tagged.tag = Some("2024-audit".to_string());
+        store.put(vec![record("500960046", "Exemplo", 3), tagged.clone(), record("501442600", "Outra, SA", 2)]).unwrap();
+
+        let reopened = Store::open(&path).unwrap();
+        assert_eq!(reopened.len(), 2);
+        assert_eq!(reopened.get("500960046"), Some(tagged));
+        assert_eq!(reopened.history("500960046").unwrap().len(), 2);
+        assert!(reopened.has_changed("500960046"));
+        assert!(!reopened.has_changed("501442600"));
+        assert_eq!(reopened.get("123456789"), None);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn tombstone() {
+        let known = record("500960046", "Exemplo", 2);
+        let removed = known.updated(NifStatus::ValidUnknown, None, "lookup".to_string(), SystemTime::now());
+        assert!(removed.is_tombstone());
+        assert_eq!(removed.entity, known.entity);
+        assert_eq!(removed.current_entity(), None);
+        assert_eq!(removed.content_hash(), content_hash(NifStatus::ValidUnknown, None));
+        let parsed = StoreRecord::from_json(&JsonValue::parse(&removed.to_json().to_string()).unwrap()).unwrap();
+        assert!(parsed.is_tombstone());
+        assert_eq!(parsed.entity, known.entity);
+        assert_eq!(parsed.content_hash(), removed.content_hash());
+    }
+
+    #[test]
+    fn content_hash_ignores_case_and_spaces() {
+        let a = record("500960046", "Exemplo  Lda", 0);
+        let b = record("500960046", "EXEMPLO lda ", 0);
+        let c = record("500960046", "Outro Lda", 0);
+        assert_eq!(a.content_hash(), b.content_hash());
+        assert_ne!(a.content_hash(), c.content_hash());
+    }
+
+    #[test]
+    fn retention_policy() {
+        let history = [record("500960046", "A", 400), record("500960046", "B", 100), record("500960046", "C", 10), record("500960046", "D", 1)];
+        // Superseded 100, 10 and 1 days ago
+        let keep = RetentionPolicy { keep: Some(Duration::from_secs(30 * 86_400)), keep_last: None };
+        assert_eq!(keep.keeps(&history), [false, true, true, true]);
+        let last = RetentionPolicy { keep: None, keep_last: Some(2) };
+        assert_eq!(last.keeps(&history), [false, false, true, true]);
+        let both = RetentionPolicy { keep: Some(Duration::from_secs(5 * 86_400)), keep_last: Some(3) };
+        assert_eq!(both.keeps(&history), [false, false, true, true]);
+        // The current record whatever its age
+        assert_eq!(both.keeps(&history[..1]), [true]);
+        assert_eq!(RetentionPolicy { keep: None, keep_last: Some(0) }.keeps(&history[..1]), [true]);
+    }
+
+    #[test]
+    fn prune_keeps_records_appended_by_other_processes() {
+        let path = scratch("prune");
+        let server = Store::open(&path).unwrap();
+        server.put(vec![record("500960046", "A", 50), record("500960046", "B", 40), record("500960046", "C", 1)]).unwrap();
+        // Another process appends after the long-running one opened the store
+        let import = Store::open(&path).unwrap();
+        import.put(vec![record("501442600", "Outra", 0)]).unwrap();
+
+        let removed = server.prune(&RetentionPolicy { keep: None, keep_last: Some(1) }).unwrap();
+        assert_eq!(removed, 2);
+        let reopened = Store::open(&path).unwrap();
+        assert_eq!(reopened.len(), 2);
+        assert_eq!(reopened.history("500960046").unwrap().len(), 1);
+        assert_eq!(reopened.get("500960046").unwrap().entity.unwrap().name, "C");
+        assert!(reopened.get("501442600").is_some());
+        // The pruning store sees the other records too
+        assert!(server.get("501442600").is_some());
+        assert_eq!(server.prune(&RetentionPolicy { keep: None, keep_last: Some(1) }).unwrap(), 0);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn skips_damaged_lines() {
+        let path = scratch("damaged");
+        Store::open(&path).unwrap().put(vec![record("500960046", "A", 1)]).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        writeln!(file, "{{\"nif\":\"501442600\",\"sta").unwrap();
+        let store = Store::open(&path).unwrap();
+        assert_eq!(store.len(), 1);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const OTHER_KEY: &str = "ff0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[test]
+    fn encrypted_store() {
+        let path = scratch("encrypted");
+        let key = || Some(StoreKey::from_hex(KEY).unwrap());
+        Store::open_with_key(&path, key()).unwrap().put(vec![record("500960046", "Exemplo", 1)]).unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with(ENCRYPTED_PREFIX));
+        assert!(!text.contains("500960046") && !text.contains("Exemplo"));
+
+        let reopened = Store::open_with_key(&path, key()).unwrap();
+        assert_eq!(reopened.get("500960046").unwrap().entity.unwrap().name, "Exemplo");
+        assert!(Store::open(&path).unwrap_err().contains("encrypted"));
+        let wrong = Store::open_with_key(&path, Some(StoreKey::from_hex(OTHER_KEY).unwrap())).unwrap_err();
+        assert!(wrong.contains("wrong key"), "{}", wrong);
+
+        // A damaged line is skipped, and the next write starts a line of its own
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        write!(file, "{}AAAA", ENCRYPTED_PREFIX).unwrap();
+        reopened.put(vec![record("501442600", "Outra", 0)]).unwrap();
+        assert_eq!(Store::open_with_key(&path, key()).unwrap().len(), 2);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn prune_encrypts_plain_records() {
+        let path = scratch("encrypt-plain");
+        Store::open(&path).unwrap().put(vec![record("500960046", "Exemplo", 1)]).unwrap();
+        let store = Store::open_with_key(&path, Some(StoreKey::from_hex(KEY).unwrap())).unwrap();
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.prune(&RetentionPolicy { keep: None, keep_last: Some(10) }).unwrap(), 0);
+        assert!(!fs::read_to_string(&path).unwrap().contains("500960046"));
+        assert_eq!(store.get("500960046").unwrap().entity.unwrap().name, "Exemplo");
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn store_key() {
+        assert!(StoreKey::from_hex(&format!(" {}\n", KEY)).is_ok());
+        assert!(StoreKey::from_hex(&KEY[2..]).is_err());
+        assert!(StoreKey::from_hex(&KEY.replace('0', "g")).is_err());
+        assert_eq!(format!("{:?}", StoreKey::from_hex(KEY).unwrap()), "StoreKey(..)");
+    }
+}
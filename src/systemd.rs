@@ -0,0 +1,85 @@
+// systemd.rs
+
+use std::time::Duration;
+
+/// First file descriptor passed by socket activation (`SD_LISTEN_FDS_START`).
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Returns the sockets passed by systemd socket activation, if this process was
+/// started that way (`LISTEN_PID` is our PID and `LISTEN_FDS` at least 1).
+///
+/// The variables are only read: child processes (hooks) inherit them, but not the sockets,
+/// which are made close-on-exec, and `LISTEN_PID` tells them the variables are not theirs.
+#[cfg(unix)]
+pub fn listen_fds() -> Vec<i32> {
+    let for_us = std::env::var("LISTEN_PID").is_ok_and(|pid| pid.trim() == std::process::id().to_string());
+    let count: i32 = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.trim().parse().ok())
+        .unwrap_or(0);
+    if !for_us {
+        return Vec::new();
+    }
+    let fds: Vec<i32> = (LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count.max(0))).collect();
+    for &fd in &fds {
+        // SAFETY: fcntl on a descriptor number only changes its flags, or fails if unused
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    fds
+}
+
+#[cfg(not(unix))]
+pub fn listen_fds() -> Vec<i32> {
+    Vec::new()
+}
+
+/// Sends a state change such as `READY=1` to the service manager (`sd_notify`).
+///
+/// Returns `Ok(false)` when not running under systemd (`NOTIFY_SOCKET` unset).
+#[cfg(unix)]
+pub fn notify(state: &str) -> Result<bool, String> {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    let socket = UnixDatagram::unbound().map_err(|e| format!("cannot create notify socket: {}", e))?;
+    let sent = match path.to_str().and_then(|path| path.strip_prefix('@')) {
+        // Abstract socket namespace, Linux only
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())
+                .map_err(|e| format!("invalid NOTIFY_SOCKET: {}", e))?;
+            socket.send_to_addr(state.as_bytes(), &address)
+        }
+        _ => socket.send_to(state.as_bytes(), &path),
+    };
+    sent.map(|_| true).map_err(|e| format!("cannot notify systemd: {}", e))
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) -> Result<bool, String> {
+    Ok(false)
+}
+
+/// Interval at which systemd expects `WATCHDOG=1`, when `WatchdogSec=` is set for us.
+pub fn watchdog_interval() -> Option<Duration> {
+    let for_us = std::env::var("WATCHDOG_PID").map_or(true, |pid| pid.trim() == std::process::id().to_string());
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.trim().parse().ok()?;
+    (for_us && usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Pings the watchdog at half its interval from a background thread, for as long as
+/// the process lives. Does nothing if no watchdog is configured.
+pub fn spawn_watchdog() {
+    if let Some(interval) = watchdog_interval() {
+        std::thread::spawn(move || {
+            loop {
+                std::thread::sleep(interval / 2);
+                let _ = notify("WATCHDOG=1");
+            }
+        });
+    }
+}
@@ -0,0 +1,155 @@
+// time.rs
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Seconds since the Unix epoch, as stored in JSON files; 0 for earlier times.
+pub fn unix_secs(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64
+}
+
+/// The time `secs` seconds after the Unix epoch; negative values give the epoch.
+pub fn from_unix_secs(secs: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64)
+}
+
+/// Formats a point in time as an RFC 3339 UTC timestamp with millisecond precision,
+/// e.g. `2024-06-01T12:30:00.250Z`.
+pub fn format_rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let rem = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Formats a point in time as an RFC 2822 date in UTC, as used in email headers,
+/// e.g. `Sat, 01 Jun 2024 12:30:00 +0000`.
+pub fn format_rfc2822(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"]; // 1970-01-01 was a Thursday
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let days = (secs / 86_400) as i64;
+    let (year, month, day) = civil_from_days(days);
+    let rem = secs % 86_400;
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} +0000",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+/// Converts days since 1970-01-01 into a (year, month, day) civil date.
+///
+/// Howard Hinnant's `civil_from_days` algorithm, valid for the proleptic Gregorian calendar.
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Converts a (year, month, day) civil date into days since 1970-01-01, the reverse of
+/// `civil_from_days`.
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Parses an HTTP date in its preferred format, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn parse_http_date(text: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let (_, date) = text.trim().split_once(", ")?;
+    let fields: Vec<&str> = date.split(' ').collect();
+    let [day, month, year, clock, "GMT"] = fields[..] else {
+        return None;
+    };
+    let month = MONTHS.iter().position(|name| *name == month)? as u32 + 1;
+    let clock: Vec<u64> = clock.split(':').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    let [hours, minutes, seconds] = clock[..] else {
+        return None;
+    };
+    let days = days_from_civil(year.parse().ok()?, month, day.parse().ok()?);
+    let secs = u64::try_from(days).ok()? * 86_400 + hours * 3600 + minutes * 60 + seconds;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Parses a calendar date, `YYYY-MM-DD`, as the start of that day in UTC.
+pub fn parse_date(text: &str) -> Result<SystemTime, String> {
+    let invalid = || format!("invalid date '{}', expected YYYY-MM-DD", text);
+    let fields: Vec<&str> = text.trim().split('-').collect();
+    let [year, month, day] = fields[..] else {
+        return Err(invalid());
+    };
+    let (year, month, day): (i64, u32, u32) = match (year.parse(), month.parse(), day.parse()) {
+        (Ok(year), Ok(month), Ok(day)) if fields[0].len() == 4 && (1..=12).contains(&month) => (year, month, day),
+        _ => return Err(invalid()),
+    };
+    // Days past the end of the month, e.g. 2023-02-30, would roll over into the next one
+    let days = days_from_civil(year, month, day);
+    if civil_from_days(days) != (year, month, day) {
+        return Err(invalid());
+    }
+    let secs = u64::try_from(days).map_err(|_| invalid())? * 86_400;
+    Ok(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Parses a human duration such as `500ms`, `90s`, `15m`, `12h`, `30d`, `6w` or `2y` (365 days).
+/// A bare number is taken as seconds.
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let invalid = || format!("invalid duration '{}', expected e.g. 500ms, 90s, 15m, 12h, 30d, 2y", text);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let unit_millis = match unit {
+        "ms" => 1,
+        "" | "s" => 1000,
+        "m" => 60_000,
+        "h" => 3_600_000,
+        "d" => 86_400_000,
+        "w" => 7 * 86_400_000,
+        "y" => 365 * 86_400_000,
+        _ => return Err(invalid()),
+    };
+    number
+        .checked_mul(unit_millis)
+        .map(Duration::from_millis)
+        .ok_or_else(invalid)
+}
+
+/// Formats a duration in the largest unit of [`parse_duration`] that holds it whole, so
+/// `12h` stays `12h` and `30d` stays `30d` instead of rounding down to days.
+pub fn format_duration(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    let units = [("d", 86_400_000), ("h", 3_600_000), ("m", 60_000), ("s", 1000)];
+    match units.iter().find(|(_, unit_millis)| millis > 0 && millis.is_multiple_of(*unit_millis)) {
+        Some((unit, unit_millis)) => format!("{}{}", millis / unit_millis, unit),
+        None if millis == 0 => "0s".to_string(),
+        None => format!("{}ms", millis),
+    }
+}
@@ -0,0 +1,186 @@
+// tls.rs
+
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use reqwest::Certificate;
+use rustls::client::WebPkiServerVerifier;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{CertificateError, ClientConfig, DigitallySignedStruct, OtherError, RootCertStore, SignatureScheme};
+
+/// Reads a PEM file holding one or more CA certificates (e.g. a corporate bundle).
+pub fn load_ca_bundle(path: &str) -> Result<Vec<Certificate>, String> {
+    let pem = std::fs::read(path).map_err(|e| format!("cannot read CA bundle {}: {}", path, e))?;
+    let certs = Certificate::from_pem_bundle(&pem)
+        .map_err(|e| format!("invalid CA bundle {}: {}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("CA bundle {} contains no certificates", path));
+    }
+    Ok(certs)
+}
+
+/// Parses a SHA-256 certificate fingerprint given in hex, with or without colons
+/// (the format printed by `openssl x509 -noout -fingerprint -sha256`).
+pub fn parse_pin(spec: &str) -> Result<[u8; 32], String> {
+    let hex: String = spec
+        .trim_start_matches("sha256:")
+        .chars()
+        .filter(|c| *c != ':')
+        .collect();
+    let invalid = || format!("invalid certificate pin '{}', expected 64 hex digits", spec);
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    let mut pin = [0u8; 32];
+    for (i, byte) in pin.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(pin)
+}
+
+/// Computes the SHA-256 fingerprint of a DER encoded certificate.
+pub fn fingerprint(der: &[u8]) -> [u8; 32] {
+    let digest = ring::digest::digest(&ring::digest::SHA256, der);
+    let mut out = [0u8; 32];
+    out.copy_from_slice(digest.as_ref());
+    out
+}
+
+/// Formats a fingerprint as colon separated upper-case hex, matching `parse_pin`'s input.
+pub fn format_fingerprint(fingerprint: &[u8; 32]) -> String {
+    fingerprint
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// The certificate of nif.pt matches none of the pins. Carried by the TLS error of the
+/// handshake, for `pin_mismatch` to tell it from other failures.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PinMismatch(pub [u8; 32]);
+
+impl fmt::Display for PinMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "certificate fingerprint {} does not match any pinned certificate", format_fingerprint(&self.0))
+    }
+}
+
+impl std::error::Error for PinMismatch {}
+
+/// Finds the `PinMismatch` behind a failed request, through the errors of reqwest, hyper and
+/// rustls wrapping it.
+pub fn pin_mismatch<'a>(error: &'a (dyn std::error::Error + 'static)) -> Option<&'a PinMismatch> {
+    let mut current: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(error) = current {
+        if let Some(rustls::Error::InvalidCertificate(CertificateError::Other(other))) = error.downcast_ref() {
+            return other.0.downcast_ref();
+        }
+        // An io::Error hides the error it wraps from `source`
+        current = match error.downcast_ref::<std::io::Error>().and_then(std::io::Error::get_ref) {
+            Some(inner) => Some(inner),
+            None => error.source(),
+        };
+    }
+    None
+}
+
+/// TLS settings of the requests to nif.pt when certificates are pinned: the certificate
+/// presented is checked against the pins during the handshake, before the request is sent,
+/// then its chain against the system CA store unless `accept_invalid_certs`.
+pub fn pinned_config(pins: &[[u8; 32]], accept_invalid_certs: bool) -> Result<ClientConfig, String> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let chain = if accept_invalid_certs {
+        None
+    } else {
+        let verifier = WebPkiServerVerifier::builder_with_provider(Arc::new(system_roots()?), Arc::clone(&provider))
+            .build()
+            .map_err(|e| format!("cannot check certificate chains: {}", e))?;
+        Some(verifier)
+    };
+    let verifier = PinnedVerifier { pins: pins.to_vec(), chain, provider: Arc::clone(&provider) };
+    Ok(ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("cannot set up TLS: {}", e))?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth())
+}
+
+/// CA certificates of the system, from the file or directory OpenSSL uses.
+fn system_roots() -> Result<RootCertStore, String> {
+    let probe = openssl_probe::probe();
+    let mut files: Vec<PathBuf> = probe.cert_file.into_iter().collect();
+    if files.is_empty()
+        && let Some(dir) = probe.cert_dir
+        && let Ok(entries) = std::fs::read_dir(dir)
+    {
+        files.extend(entries.flatten().map(|entry| entry.path()));
+    }
+    let mut roots = RootCertStore::empty();
+    for file in files {
+        let Ok(certs) = CertificateDer::pem_file_iter(&file) else {
+            continue;
+        };
+        roots.add_parsable_certificates(certs.flatten());
+    }
+    if roots.is_empty() {
+        return Err("no system CA certificates found to check the certificate of nif.pt against".to_string());
+    }
+    Ok(roots)
+}
+
+/// Accepts the certificates matching one of the pins, with a valid chain when `chain` is set.
+#[derive(Debug)]
+struct PinnedVerifier {
+    pins: Vec<[u8; 32]>,
+    chain: Option<Arc<WebPkiServerVerifier>>, // None with --insecure: the pins alone decide
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let actual = fingerprint(end_entity);
+        if !self.pins.contains(&actual) {
+            let mismatch = OtherError(Arc::new(PinMismatch(actual)));
+            return Err(rustls::Error::InvalidCertificate(CertificateError::Other(mismatch)));
+        }
+        match &self.chain {
+            Some(chain) => chain.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now),
+            None => Ok(ServerCertVerified::assertion()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,158 @@
+// validation.rs
+
+/// Country prefix of Portuguese NIFs in their EU VAT form, e.g. `PT500960046`.
+pub const VAT_PREFIX: &str = "PT";
+
+/// Strips the surroundings of a NIF as typed: blanks, and the `PT` prefix of the VAT form.
+pub fn normalize_nif(input: &str) -> &str {
+    let input = input.trim();
+    match input.get(..2) {
+        Some(prefix) if prefix.eq_ignore_ascii_case(VAT_PREFIX) => input[2..].trim_start(),
+        _ => input,
+    }
+}
+
+/// Writes a NIF in the EU VAT form used by invoices and VIES, e.g. `PT500960046`.
+pub fn vat_number(nif: &str) -> String {
+    format!("{}{}", VAT_PREFIX, normalize_nif(nif))
+}
+
+/// Validates a Portuguese NIF using only the mathematical algorithm (no external lookup).
+/// The VAT form (`PT500960046`) is accepted too.
+pub fn is_nif_valid_local(nif: &str) -> bool {
+    let nif = normalize_nif(nif);
+    // Checks if it has 9 digits
+    if nif.len() != 9 || !nif.chars().all(|c| c.is_ascii_digit()) {
+        return false;
+    }
+
+    // Checks if the first digit is allowed
+    let first = &nif[0..1];
+    let first_two = &nif[0..2];
+    let valid_first = matches!(
+        first,
+        "1" | "2" | "3" | "5" | "6" | "7" | "8" | "9"
+    ) || first_two == "45";
+    if !valid_first {
+        return false;
+    }
+
+    // Extracts the digits
+    let digits: Vec<u32> = nif.chars().map(|c| c.to_digit(10).unwrap()).collect();
+
+    // Compares with the 9th digit
+    nif_check_digit(&digits[..8]) == digits[8]
+}
+
+/// Check digit of a NIF, from its first eight digits.
+pub fn nif_check_digit(digits: &[u32]) -> u32 {
+    let mut sum = 0;
+    for (i, d) in digits.iter().take(8).enumerate() {
+        sum += d * (9 - i as u32);
+    }
+    let resto = sum % 11;
+    if resto == 0 || resto == 1 { 0 } else { 11 - resto }
+}
+
+/// Kind of holder of a NIF, told by its first digits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NifCategory {
+    Person,  // Individuals, residents (1, 2, 3) or not (45), and sole traders (8)
+    Company, // Legal persons (5), non-resident ones (71) and civil companies (99)
+    Public,  // Public administration bodies (6)
+    Other,   // Undivided estates, investment funds, condominiums, NIFs assigned by the tax office...
+}
+
+impl NifCategory {
+    /// Parses an `--expect` value.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "person" => Ok(NifCategory::Person),
+            "company" => Ok(NifCategory::Company),
+            "public" => Ok(NifCategory::Public),
+            "other" => Ok(NifCategory::Other),
+            other => Err(format!("unknown NIF category '{}', expected person, company, public or other", other)),
+        }
+    }
+
+    /// Short machine-friendly name, as accepted by `parse`.
+    pub fn label(&self) -> &'static str {
+        match self {
+            NifCategory::Person => "person",
+            NifCategory::Company => "company",
+            NifCategory::Public => "public",
+            NifCategory::Other => "other",
+        }
+    }
+}
+
+/// Category of a NIF passing local validation; `None` for any other input.
+pub fn nif_category(nif: &str) -> Option<NifCategory> {
+    if !is_nif_valid_local(nif) {
+        return None;
+    }
+    let nif = normalize_nif(nif);
+    Some(match (&nif[..1], &nif[..2]) {
+        ("1" | "2" | "3" | "8", _) | (_, "45") => NifCategory::Person,
+        ("5", _) | (_, "71" | "99") => NifCategory::Company,
+        ("6", _) => NifCategory::Public,
+        _ => NifCategory::Other,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The valid NIF starting with `prefix`, zeros after it.
+    fn nif_with_prefix(prefix: &str) -> String {
+        let mut digits: Vec<u32> = format!("{:0<8}", prefix).chars().filter_map(|c| c.to_digit(10)).collect();
+        digits.push(nif_check_digit(&digits));
+        digits.iter().map(|digit| char::from_digit(*digit, 10).unwrap()).collect()
+    }
+
+    #[test]
+    fn categories_by_prefix() {
+        let table = [
+            ("1", NifCategory::Person),
+            ("2", NifCategory::Person),
+            ("3", NifCategory::Person),
+            ("45", NifCategory::Person),
+            ("8", NifCategory::Person),
+            ("5", NifCategory::Company),
+            ("71", NifCategory::Company),
+            ("99", NifCategory::Company),
+            ("6", NifCategory::Public),
+            ("70", NifCategory::Other),
+            ("72", NifCategory::Other),
+            ("74", NifCategory::Other),
+            ("75", NifCategory::Other),
+            ("77", NifCategory::Other),
+            ("78", NifCategory::Other),
+            ("79", NifCategory::Other),
+            ("90", NifCategory::Other),
+            ("91", NifCategory::Other),
+            ("98", NifCategory::Other),
+        ];
+        for (prefix, category) in table {
+            let nif = nif_with_prefix(prefix);
+            assert_eq!(nif_category(&nif), Some(category), "{}", nif);
+            assert_eq!(nif_category(&format!("PT{}", nif)), Some(category), "PT{}", nif);
+        }
+        // No category without a valid NIF: unused first digits, bad check digits, not digits
+        for nif in [nif_with_prefix("40"), nif_with_prefix("0"), "500960047".to_string(), "5009600".to_string(), "50096004a".to_string()] {
+            assert_eq!(nif_category(&nif), None, "{}", nif);
+        }
+    }
+
+    #[test]
+    fn category_names() {
+        for category in [NifCategory::Person, NifCategory::Company, NifCategory::Public, NifCategory::Other] {
+            assert_eq!(NifCategory::parse(category.label()), Ok(category));
+        }
+        assert_eq!(
+            NifCategory::parse("Company"),
+            Err("unknown NIF category 'Company', expected person, company, public or other".to_string())
+        );
+    }
+}
@@ -0,0 +1,70 @@
+// vcard.rs
+
+use crate::entity::NifEntity;
+
+/// Formats an entity as a vCard 3.0 (RFC 2426) card, with CRLF line endings.
+///
+/// The company name fills both `FN` and `ORG`, and the NIF goes in a note so it
+/// survives the import into contact managers that drop unknown properties.
+pub fn format_vcard(entity: &NifEntity) -> String {
+    let mut lines = vec![
+        "BEGIN:VCARD".to_string(),
+        "VERSION:3.0".to_string(),
+        format!("FN:{}", escape(&entity.name)),
+        format!("ORG:{}", escape(&entity.name)),
+    ];
+    if entity.address.is_some() || entity.postal_code.is_some() || entity.locality.is_some() {
+        let part = |value: &Option<String>| value.as_deref().map(escape).unwrap_or_default();
+        // ADR components: PO box; extended; street; locality; region; postal code; country
+        lines.push(format!(
+            "ADR;TYPE=WORK:;;{};{};;{};Portugal",
+            part(&entity.address),
+            part(&entity.locality),
+            part(&entity.postal_code)
+        ));
+    }
+    if let Some(phone) = &entity.phone {
+        lines.push(format!("TEL;TYPE=WORK,VOICE:{}", escape(phone)));
+    }
+    if let Some(email) = &entity.email {
+        lines.push(format!("EMAIL;TYPE=INTERNET:{}", escape(email)));
+    }
+    lines.push(format!("NOTE:NIF {}", escape(&entity.nif)));
+    lines.push("END:VCARD".to_string());
+
+    let mut card = String::new();
+    for line in &lines {
+        fold_line(&mut card, line);
+    }
+    card
+}
+
+/// Escapes the characters with a meaning in vCard values.
+fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            ',' => out.push_str("\\,"),
+            ';' => out.push_str("\\;"),
+            '\n' => out.push_str("\\n"),
+            '\r' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Appends `line`, folded at 75 octets as the RFC requires, without splitting characters.
+fn fold_line(out: &mut String, line: &str) {
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            width = 1;
+        }
+        out.push(c);
+        width += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
@@ -0,0 +1,1670 @@
+// wasm.rs
+
+//! WASM plugins post-processing lookup results, for `--features wasm` (`--wasm-hook`): a
+//! plugin sees each result and may drop it, give it another tag, or add entity details.
+//!
+//! The modules run in the interpreter below rather than a JIT runtime: WebAssembly 1.0 with
+//! the additions compilers emit by default (sign extension, saturating conversions, bulk
+//! memory copy and fill, multi-value). SIMD, threads, exceptions and the table instructions
+//! of reference types are not supported. Every call runs on a fuel of `FUEL_PER_CALL`
+//! instructions and the memory is capped at `MAX_MEMORY_PAGES`, so a plugin stuck in a loop
+//! or allocating without end fails that one call instead of the run.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::entity::NifEntity;
+use crate::json::JsonValue;
+use crate::logging::{self, nif_field};
+use crate::lookup::LookupResult;
+
+/// Instructions a plugin may run for one result.
+pub const FUEL_PER_CALL: u64 = 50_000_000;
+
+/// Pages of 64 KiB a plugin memory may grow to (64 MiB).
+pub const MAX_MEMORY_PAGES: u32 = 1024;
+
+const PAGE_SIZE: usize = 65_536;
+const MAX_CALL_DEPTH: usize = 10_000; // Nested calls, their frames kept on the heap
+const MAX_LOCALS: u64 = 50_000; // Per function, parameters included
+const MAX_TABLE_SIZE: u32 = 1_000_000;
+const NULL_REF: u64 = u64::MAX; // Value of `ref.null`, function references being their index
+
+/// A WASM module post-processing results.
+///
+/// The module exports its `memory`, `alloc(len: i32) -> i32`, giving the address of `len`
+/// free bytes, and `on_result(ptr: i32, len: i32) -> i64`, called with the result JSON
+/// written there. It returns 0 to keep the result as it is, or the address and length of
+/// its answer, a JSON object, as `ptr << 32 | len`:
+///
+/// - `"keep": false` drops the result;
+/// - `"tag"` replaces its tag (`null` removes it);
+/// - the fields of `"entity"` replace those of the entity details (`null` removes one).
+///
+/// It may import `env.log(ptr: i32, len: i32)`, logging the text given as `wasm_log`.
+/// The instance lives as long as the hook, so the module may keep state between results.
+pub struct WasmHook {
+    instance: Instance,
+}
+
+impl WasmHook {
+    /// Loads and starts the module of a `.wasm` file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        Self::from_bytes(&path.display().to_string(), &bytes)
+    }
+
+    /// Starts the module of `bytes`, named `name` in errors and logs.
+    pub fn from_bytes(name: &str, bytes: &[u8]) -> Result<Self, String> {
+        let module = Module::parse(bytes).map_err(|e| format!("invalid WASM module {}: {}", name, e))?;
+        for (export, params, results) in [("alloc", &[I32][..], &[I32][..]), ("on_result", &[I32, I32], &[I64])] {
+            let ty = module.export_type(export).ok_or_else(|| format!("{} does not export the function {}", name, export))?;
+            if ty.params != params || ty.results != results {
+                return Err(format!("{} exports {} with the wrong signature", name, export));
+            }
+        }
+        if !matches!(module.exports.get("memory"), Some(Export::Memory)) {
+            return Err(format!("{} does not export its memory", name));
+        }
+        let instance = Instance::new(name, module).map_err(|e| format!("cannot start {}: {}", name, e))?;
+        Ok(WasmHook { instance })
+    }
+
+    /// File or name of the module.
+    pub fn name(&self) -> &str {
+        &self.instance.name
+    }
+
+    /// Runs the plugin on `result`: `None` when it drops it.
+    pub fn process(&mut self, mut result: LookupResult) -> Result<Option<LookupResult>, String> {
+        let input = result.to_json().to_string();
+        let len = u32::try_from(input.len()).map_err(|_| "the result JSON is too large".to_string())?;
+        let ptr = self.instance.call("alloc", &[u64::from(len)])?[0];
+        self.instance.write(ptr, input.as_bytes())?;
+        let answer = self.instance.call("on_result", &[ptr, u64::from(len)])?[0];
+        if answer == 0 {
+            return Ok(Some(result));
+        }
+        let bytes = self.instance.read(answer >> 32, answer & 0xFFFF_FFFF)?;
+        let text = std::str::from_utf8(bytes).map_err(|_| "the answer is not UTF-8".to_string())?;
+        let answer = JsonValue::parse(text).map_err(|e| format!("the answer is not JSON: {}", e))?;
+        let JsonValue::Object(fields) = answer else {
+            return Err("the answer is not a JSON object".to_string());
+        };
+        for (key, value) in fields {
+            match (key.as_str(), value) {
+                ("keep", JsonValue::Bool(keep)) => {
+                    if !keep {
+                        return Ok(None);
+                    }
+                }
+                ("tag", JsonValue::String(tag)) => result.tag = Some(tag),
+                ("tag", JsonValue::Null) => result.tag = None,
+                ("entity", JsonValue::Object(fields)) => {
+                    let mut entity = result.entity.take().unwrap_or(NifEntity { nif: result.nif.clone(), ..NifEntity::default() });
+                    for (field, value) in fields {
+                        set_entity_field(&mut entity, &field, value)?;
+                    }
+                    if entity.name.is_empty() {
+                        return Err("the entity details need a name".to_string());
+                    }
+                    result.entity = Some(entity);
+                }
+                (key, value) => return Err(format!("invalid {} in the answer: {}", key, value)),
+            }
+        }
+        Ok(Some(result))
+    }
+}
+
+/// Runs `hooks` in turn on `result`: `None` once one drops it. A failing hook is logged and
+/// leaves the result as it was.
+pub fn apply(hooks: &mut [WasmHook], mut result: LookupResult) -> Option<LookupResult> {
+    for hook in hooks {
+        match hook.process(result.clone()) {
+            Ok(Some(processed)) => result = processed,
+            Ok(None) => return None,
+            Err(e) => logging::warn(
+                "wasm_hook_failed",
+                &[nif_field(&result.nif), ("plugin", hook.name().into()), ("error", e.clone().into())],
+                format!("WASM hook {} failed: {}", hook.name(), e),
+            ),
+        }
+    }
+    Some(result)
+}
+
+fn set_entity_field(entity: &mut NifEntity, field: &str, value: JsonValue) -> Result<(), String> {
+    let value = match value {
+        JsonValue::String(value) => Some(value),
+        JsonValue::Null => None,
+        other => return Err(format!("invalid entity {} in the answer: {}", field, other)),
+    };
+    let slot = match field {
+        "name" => {
+            entity.name = value.ok_or("the entity name cannot be removed")?;
+            return Ok(());
+        }
+        "nif" => return Err("the entity NIF cannot be changed".to_string()),
+        "address" => &mut entity.address,
+        "postal_code" => &mut entity.postal_code,
+        "locality" => &mut entity.locality,
+        "phone" => &mut entity.phone,
+        "email" => &mut entity.email,
+        "district" => &mut entity.district,
+        "municipality" => &mut entity.municipality,
+        other => return Err(format!("unknown entity field {} in the answer", other)),
+    };
+    *slot = value;
+    Ok(())
+}
+
+// Value types, by their byte in the binary format
+const I32: u8 = 0x7F;
+const I64: u8 = 0x7E;
+const F32: u8 = 0x7D;
+const F64: u8 = 0x7C;
+const FUNCREF: u8 = 0x70;
+const EXTERNREF: u8 = 0x6F;
+
+/// Reads the binary format: bytes, LEB128 integers and names.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        let byte = *self.data.get(self.pos).ok_or("unexpected end")?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.pos.checked_add(len).filter(|&end| end <= self.data.len()).ok_or("unexpected end")?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn leb(&mut self, bits: u32, signed: bool) -> Result<u64, String> {
+        let (mut value, mut shift) = (0u64, 0);
+        loop {
+            let byte = self.byte()?;
+            if shift >= bits.div_ceil(7) * 7 {
+                return Err("integer too long".to_string());
+            }
+            value |= u64::from(byte & 0x7F) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if signed && shift < 64 && byte & 0x40 != 0 {
+                    value |= u64::MAX << shift;
+                }
+                return Ok(value);
+            }
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        u32::try_from(self.leb(32, false)?).map_err(|_| "integer too large".to_string())
+    }
+
+    fn len(&mut self) -> Result<usize, String> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let len = self.len()?;
+        String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| "name is not UTF-8".to_string())
+    }
+
+    fn value_type(&mut self) -> Result<u8, String> {
+        match self.byte()? {
+            ty @ (I32 | I64 | F32 | F64 | FUNCREF | EXTERNREF) => Ok(ty),
+            0x7B => Err("SIMD is not supported".to_string()),
+            other => Err(format!("unknown value type 0x{:02x}", other)),
+        }
+    }
+
+    /// Reads the limits of a memory or table: the minimum, and the maximum when given.
+    fn limits(&mut self) -> Result<(u32, Option<u32>), String> {
+        match self.byte()? {
+            0 => Ok((self.u32()?, None)),
+            1 => Ok((self.u32()?, Some(self.u32()?))),
+            2 | 3 => Err("shared memories are not supported".to_string()),
+            other => Err(format!("invalid limits 0x{:02x}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FuncType {
+    params: Vec<u8>,
+    results: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Export {
+    Func(u32),
+    Table,
+    Memory,
+    Global,
+}
+
+/// Functions the host offers to the modules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HostFunc {
+    Log, // env.log(ptr, len)
+}
+
+#[derive(Debug)]
+enum Func {
+    Host(HostFunc),
+    Wasm { locals: usize, code: Arc<[Op]> }, // Locals declared after the parameters
+}
+
+/// Constant expression of a global initializer or a segment offset.
+#[derive(Debug, Clone, Copy)]
+enum ConstExpr {
+    Value(u64),
+    Global(u32),
+}
+
+#[derive(Debug)]
+struct DataSegment {
+    offset: Option<ConstExpr>, // `None` for passive segments, unused without memory.init
+    bytes: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct ElemSegment {
+    table: u32,
+    offset: ConstExpr,
+    funcs: Vec<u32>,
+}
+
+/// Instruction with its immediates decoded and its branch targets resolved.
+#[derive(Debug, Clone)]
+enum Op {
+    Unreachable,
+    Nop,
+    Block { params: usize, results: usize, end: usize },
+    Loop { params: usize },
+    If { params: usize, results: usize, else_at: Option<usize>, end: usize },
+    Else { end: usize },
+    End,
+    Br(u32),
+    BrIf(u32),
+    BrTable(Box<[u32]>, u32),
+    Return,
+    Call(u32),
+    CallIndirect(u32, u32), // Type, table
+    Drop,
+    Select,
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    Load(u8, u32),  // Opcode, offset
+    Store(u8, u32), // Opcode, offset
+    MemorySize,
+    MemoryGrow,
+    Const(u64),
+    Numeric(u8),   // Opcodes 0x45 to 0xC4, without immediates
+    TruncSat(u8),  // 0xFC 0 to 7
+    MemoryCopy,
+    MemoryFill,
+    RefNull,
+    RefIsNull,
+    RefFunc(u32),
+    Unsupported(&'static str), // Traps when run
+}
+
+/// A parsed module, before instantiation.
+#[derive(Debug, Default)]
+struct Module {
+    types: Vec<FuncType>,
+    func_types: Vec<u32>, // Type of every function, imports first
+    funcs: Vec<Func>,
+    tables: Vec<(u32, Option<u32>)>,
+    memory: Option<(u32, Option<u32>)>,
+    globals: Vec<ConstExpr>,
+    exports: HashMap<String, Export>,
+    start: Option<u32>,
+    elems: Vec<ElemSegment>,
+    data: Vec<DataSegment>,
+}
+
+impl Module {
+    fn parse(bytes: &[u8]) -> Result<Module, String> {
+        let mut reader = Reader::new(bytes);
+        if reader.bytes(4).ok() != Some(b"\0asm") {
+            return Err("not a WASM module".to_string());
+        }
+        if reader.bytes(4)? != [1, 0, 0, 0] {
+            return Err("unsupported WASM version".to_string());
+        }
+        let mut module = Module::default();
+        let mut bodies_expected = 0;
+        while !reader.at_end() {
+            let id = reader.byte()?;
+            let len = reader.len()?;
+            let mut section = Reader::new(reader.bytes(len)?);
+            match id {
+                0 => continue, // Custom sections (names, producers) are ignored
+                1 => module.parse_types(&mut section)?,
+                2 => module.parse_imports(&mut section)?,
+                3 => {
+                    for _ in 0..section.u32()? {
+                        let ty = section.u32()?;
+                        module.check_type(ty)?;
+                        module.func_types.push(ty);
+                        bodies_expected += 1;
+                    }
+                }
+                4 => {
+                    for _ in 0..section.u32()? {
+                        section.value_type()?;
+                        module.tables.push(section.limits()?);
+                    }
+                }
+                5 => {
+                    for _ in 0..section.u32()? {
+                        if module.memory.is_some() {
+                            return Err("more than one memory".to_string());
+                        }
+                        module.memory = Some(section.limits()?);
+                    }
+                }
+                6 => {
+                    for _ in 0..section.u32()? {
+                        section.value_type()?;
+                        section.byte()?; // Mutability, not enforced
+                        let init = module.const_expr(&mut section)?;
+                        module.globals.push(init);
+                    }
+                }
+                7 => {
+                    for _ in 0..section.u32()? {
+                        let name = section.name()?;
+                        let export = match (section.byte()?, section.u32()?) {
+                            (0, index) => Export::Func(index),
+                            (1, _) => Export::Table,
+                            (2, _) => Export::Memory,
+                            (3, _) => Export::Global,
+                            (kind, _) => return Err(format!("unknown export kind {}", kind)),
+                        };
+                        module.exports.insert(name, export);
+                    }
+                }
+                8 => module.start = Some(section.u32()?),
+                9 => module.parse_elems(&mut section)?,
+                10 => {
+                    let count = section.u32()?;
+                    if count != bodies_expected {
+                        return Err("function and code sections disagree".to_string());
+                    }
+                    for _ in 0..count {
+                        let len = section.len()?;
+                        let mut body = Reader::new(section.bytes(len)?);
+                        let ty = module.func_types[module.funcs.len()] as usize;
+                        let func = module.parse_body(&mut body, module.types[ty].params.len())?;
+                        module.funcs.push(func);
+                    }
+                }
+                11 => module.parse_data(&mut section)?,
+                12 => {
+                    section.u32()?; // Data count, for memory.init
+                }
+                other => return Err(format!("unknown section {}", other)),
+            }
+            if id != 0 && !section.at_end() {
+                return Err(format!("section {} is longer than its contents", id));
+            }
+        }
+        if module.funcs.len() != module.func_types.len() {
+            return Err("functions without code".to_string());
+        }
+        for export in module.exports.values() {
+            if let Export::Func(index) = export {
+                module.check_func(*index)?;
+            }
+        }
+        if let Some(start) = module.start {
+            module.check_func(start)?;
+        }
+        Ok(module)
+    }
+
+    fn check_type(&self, ty: u32) -> Result<(), String> {
+        if (ty as usize) < self.types.len() { Ok(()) } else { Err(format!("unknown type {}", ty)) }
+    }
+
+    fn check_func(&self, index: u32) -> Result<(), String> {
+        if (index as usize) < self.funcs.len() { Ok(()) } else { Err(format!("unknown function {}", index)) }
+    }
+
+    fn export_type(&self, name: &str) -> Option<&FuncType> {
+        match self.exports.get(name) {
+            Some(Export::Func(index)) => Some(&self.types[self.func_types[*index as usize] as usize]),
+            _ => None,
+        }
+    }
+
+    fn parse_types(&mut self, section: &mut Reader) -> Result<(), String> {
+        for _ in 0..section.u32()? {
+            if section.byte()? != 0x60 {
+                return Err("invalid function type".to_string());
+            }
+            let params = (0..section.u32()?).map(|_| section.value_type()).collect::<Result<_, _>>()?;
+            let results = (0..section.u32()?).map(|_| section.value_type()).collect::<Result<_, _>>()?;
+            self.types.push(FuncType { params, results });
+        }
+        Ok(())
+    }
+
+    fn parse_imports(&mut self, section: &mut Reader) -> Result<(), String> {
+        for _ in 0..section.u32()? {
+            let (module, name) = (section.name()?, section.name()?);
+            if section.byte()? != 0 {
+                return Err(format!("cannot import {}.{}: only functions can be imported", module, name));
+            }
+            let ty = section.u32()?;
+            self.check_type(ty)?;
+            let host = match (module.as_str(), name.as_str()) {
+                ("env", "log") => HostFunc::Log,
+                _ => return Err(format!("unknown import {}.{}, only env.log is offered", module, name)),
+            };
+            if self.types[ty as usize] != (FuncType { params: vec![I32, I32], results: vec![] }) {
+                return Err(format!("{}.{} is imported with the wrong signature", module, name));
+            }
+            self.func_types.push(ty);
+            self.funcs.push(Func::Host(host));
+        }
+        Ok(())
+    }
+
+    fn const_expr(&self, reader: &mut Reader) -> Result<ConstExpr, String> {
+        let expr = match reader.byte()? {
+            0x41 => ConstExpr::Value(u64::from(reader.leb(32, true)? as u32)),
+            0x42 => ConstExpr::Value(reader.leb(64, true)?),
+            0x43 => ConstExpr::Value(u64::from(u32::from_le_bytes(reader.bytes(4)?.try_into().unwrap()))),
+            0x44 => ConstExpr::Value(u64::from_le_bytes(reader.bytes(8)?.try_into().unwrap())),
+            0x23 => match reader.u32()? {
+                index if (index as usize) < self.globals.len() => ConstExpr::Global(index),
+                index => return Err(format!("unknown global {}", index)),
+            },
+            0xD0 => {
+                reader.byte()?;
+                ConstExpr::Value(NULL_REF)
+            }
+            0xD2 => ConstExpr::Value(u64::from(reader.u32()?)),
+            other => return Err(format!("unsupported constant expression 0x{:02x}", other)),
+        };
+        if reader.byte()? != 0x0B {
+            return Err("constant expression without end".to_string());
+        }
+        Ok(expr)
+    }
+
+    fn parse_elems(&mut self, section: &mut Reader) -> Result<(), String> {
+        for _ in 0..section.u32()? {
+            let flags = section.u32()?;
+            let (table, offset) = match flags {
+                0 => (0, Some(self.const_expr(section)?)),
+                2 => (section.u32()?, Some(self.const_expr(section)?)),
+                1 | 3 => (0, None), // Passive and declarative segments
+                other => return Err(format!("unsupported element segment {}", other)),
+            };
+            if flags != 0 && section.byte()? != 0 {
+                return Err("unsupported element kind".to_string());
+            }
+            let funcs: Vec<u32> = (0..section.u32()?).map(|_| section.u32()).collect::<Result<_, _>>()?;
+            if let Some(offset) = offset {
+                self.elems.push(ElemSegment { table, offset, funcs });
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_data(&mut self, section: &mut Reader) -> Result<(), String> {
+        for _ in 0..section.u32()? {
+            let offset = match section.u32()? {
+                0 => Some(self.const_expr(section)?),
+                1 => None,
+                2 => {
+                    section.u32()?;
+                    Some(self.const_expr(section)?)
+                }
+                other => return Err(format!("unsupported data segment {}", other)),
+            };
+            let len = section.len()?;
+            self.data.push(DataSegment { offset, bytes: section.bytes(len)?.to_vec() });
+        }
+        Ok(())
+    }
+
+    /// Reads a block type: its parameter and result counts.
+    fn block_type(&self, reader: &mut Reader) -> Result<(usize, usize), String> {
+        match reader.data.get(reader.pos).copied() {
+            Some(0x40) => {
+                reader.pos += 1;
+                Ok((0, 0))
+            }
+            Some(I32 | I64 | F32 | F64 | FUNCREF | EXTERNREF) => {
+                reader.pos += 1;
+                Ok((0, 1))
+            }
+            _ => {
+                let index = reader.leb(33, true)? as i64;
+                let ty = u32::try_from(index).map_err(|_| "invalid block type".to_string())?;
+                self.check_type(ty)?;
+                let ty = &self.types[ty as usize];
+                Ok((ty.params.len(), ty.results.len()))
+            }
+        }
+    }
+
+    /// Decodes a function body into `Op`s, matching each block with its `else` and `end`.
+    fn parse_body(&self, reader: &mut Reader, params: usize) -> Result<Func, String> {
+        let mut locals = 0u64;
+        for _ in 0..reader.u32()? {
+            locals += u64::from(reader.u32()?);
+            reader.value_type()?;
+        }
+        if locals + params as u64 > MAX_LOCALS {
+            return Err(format!("more than {} locals", MAX_LOCALS));
+        }
+        let mut code: Vec<Op> = Vec::new();
+        let mut open: Vec<usize> = Vec::new(); // Block, loop and if ops not ended yet
+        loop {
+            let at = code.len();
+            let op = match reader.byte()? {
+                0x00 => Op::Unreachable,
+                0x01 => Op::Nop,
+                0x02 => {
+                    let (params, results) = self.block_type(reader)?;
+                    open.push(at);
+                    Op::Block { params, results, end: 0 }
+                }
+                0x03 => {
+                    let (params, _) = self.block_type(reader)?;
+                    open.push(at);
+                    Op::Loop { params }
+                }
+                0x04 => {
+                    let (params, results) = self.block_type(reader)?;
+                    open.push(at);
+                    Op::If { params, results, else_at: None, end: 0 }
+                }
+                0x05 => {
+                    match open.last().map(|&start| &mut code[start]) {
+                        Some(Op::If { else_at: else_at @ None, .. }) => *else_at = Some(at),
+                        _ => return Err("else without if".to_string()),
+                    }
+                    Op::Else { end: 0 }
+                }
+                0x0B => match open.pop() {
+                    Some(start) => {
+                        match &mut code[start] {
+                            Op::Block { end, .. } => *end = at,
+                            Op::If { end, else_at, .. } => {
+                                *end = at;
+                                if let Some(else_at) = *else_at {
+                                    code[else_at] = Op::Else { end: at };
+                                }
+                            }
+                            _ => {}
+                        }
+                        Op::End
+                    }
+                    None => {
+                        code.push(Op::End);
+                        break;
+                    }
+                },
+                0x0C => Op::Br(reader.u32()?),
+                0x0D => Op::BrIf(reader.u32()?),
+                0x0E => {
+                    let labels = (0..reader.u32()?).map(|_| reader.u32()).collect::<Result<Vec<_>, _>>()?;
+                    Op::BrTable(labels.into_boxed_slice(), reader.u32()?)
+                }
+                0x0F => Op::Return,
+                0x10 => {
+                    let index = reader.u32()?;
+                    if index as usize >= self.func_types.len() {
+                        return Err(format!("unknown function {}", index));
+                    }
+                    Op::Call(index)
+                }
+                0x11 => {
+                    let ty = reader.u32()?;
+                    self.check_type(ty)?;
+                    Op::CallIndirect(ty, reader.u32()?)
+                }
+                0x1A => Op::Drop,
+                0x1B => Op::Select,
+                0x1C => {
+                    for _ in 0..reader.u32()? {
+                        reader.value_type()?;
+                    }
+                    Op::Select
+                }
+                0x20 => Op::LocalGet(reader.u32()?),
+                0x21 => Op::LocalSet(reader.u32()?),
+                0x22 => Op::LocalTee(reader.u32()?),
+                0x23 => Op::GlobalGet(reader.u32()?),
+                0x24 => Op::GlobalSet(reader.u32()?),
+                0x25 | 0x26 => {
+                    reader.u32()?;
+                    Op::Unsupported("table.get and table.set")
+                }
+                op @ 0x28..=0x35 => {
+                    reader.u32()?; // Alignment, a hint only
+                    Op::Load(op, reader.u32()?)
+                }
+                op @ 0x36..=0x3E => {
+                    reader.u32()?;
+                    Op::Store(op, reader.u32()?)
+                }
+                0x3F => {
+                    reader.byte()?;
+                    Op::MemorySize
+                }
+                0x40 => {
+                    reader.byte()?;
+                    Op::MemoryGrow
+                }
+                0x41 => Op::Const(u64::from(reader.leb(32, true)? as u32)),
+                0x42 => Op::Const(reader.leb(64, true)?),
+                0x43 => Op::Const(u64::from(u32::from_le_bytes(reader.bytes(4)?.try_into().unwrap()))),
+                0x44 => Op::Const(u64::from_le_bytes(reader.bytes(8)?.try_into().unwrap())),
+                op @ 0x45..=0xC4 => Op::Numeric(op),
+                0xD0 => {
+                    reader.byte()?;
+                    Op::RefNull
+                }
+                0xD1 => Op::RefIsNull,
+                0xD2 => Op::RefFunc(reader.u32()?),
+                0xFC => match reader.u32()? {
+                    op @ 0..=7 => Op::TruncSat(op as u8),
+                    8 => {
+                        reader.u32()?;
+                        reader.byte()?;
+                        Op::Unsupported("memory.init")
+                    }
+                    9 | 13 => {
+                        reader.u32()?; // data.drop and elem.drop change nothing here
+                        Op::Nop
+                    }
+                    10 => {
+                        reader.bytes(2)?;
+                        Op::MemoryCopy
+                    }
+                    11 => {
+                        reader.byte()?;
+                        Op::MemoryFill
+                    }
+                    12 | 14 => {
+                        reader.u32()?;
+                        reader.u32()?;
+                        Op::Unsupported("table.init and table.copy")
+                    }
+                    15..=17 => {
+                        reader.u32()?;
+                        Op::Unsupported("table.grow, table.size and table.fill")
+                    }
+                    other => return Err(format!("unknown instruction 0xfc {}", other)),
+                },
+                0xFD => return Err("SIMD is not supported".to_string()),
+                other => return Err(format!("unknown instruction 0x{:02x}", other)),
+            };
+            code.push(op);
+        }
+        if !reader.at_end() {
+            return Err("code after the end of a function".to_string());
+        }
+        Ok(Func::Wasm { locals: locals as usize, code: code.into() })
+    }
+}
+
+/// Target of a branch: the end of a block or `if`, or the start of a loop.
+#[derive(Debug, Clone, Copy)]
+struct Label {
+    height: usize, // Stack height below the values of the block
+    arity: usize,  // Values a branch carries: results of blocks, parameters of loops
+    target: usize, // Op run after the branch
+    is_loop: bool,
+}
+
+/// A function being run: its code, locals and blocks, and where it is.
+struct Frame {
+    code: Arc<[Op]>,
+    locals: Vec<u64>,   // Parameters, then the locals declared
+    labels: Vec<Label>, // Blocks entered, the function body first
+    pc: usize,
+    base: usize, // Stack height below the values of the function
+    results: usize,
+}
+
+/// What to run after an op.
+enum Flow {
+    Next(usize),
+    Call(u32),
+    Return,
+}
+
+/// A running module: its memory, globals and tables.
+struct Instance {
+    name: String,
+    module: Module,
+    memory: Vec<u8>,
+    max_pages: u32,
+    globals: Vec<u64>,
+    tables: Vec<Vec<Option<u32>>>,
+    fuel: u64,
+}
+
+impl Instance {
+    fn new(name: &str, module: Module) -> Result<Instance, String> {
+        let (pages, max) = module.memory.unwrap_or((0, Some(0)));
+        let max_pages = max.unwrap_or(MAX_MEMORY_PAGES).min(MAX_MEMORY_PAGES);
+        if pages > max_pages {
+            return Err(format!("the module needs {} pages of memory, at most {} are allowed", pages, max_pages));
+        }
+        let mut tables = Vec::new();
+        for &(size, _) in &module.tables {
+            if size > MAX_TABLE_SIZE {
+                return Err(format!("table of {} elements", size));
+            }
+            tables.push(vec![None; size as usize]);
+        }
+        let mut instance = Instance {
+            name: name.to_string(),
+            memory: vec![0; pages as usize * PAGE_SIZE],
+            max_pages,
+            globals: Vec::with_capacity(module.globals.len()),
+            tables,
+            fuel: FUEL_PER_CALL,
+            module: Module::default(),
+        };
+        for init in &module.globals {
+            let value = instance.eval(*init);
+            instance.globals.push(value);
+        }
+        for segment in &module.elems {
+            let offset = instance.eval(segment.offset) as u32 as usize;
+            let table = instance.tables.get_mut(segment.table as usize).ok_or("element segment of an unknown table")?;
+            let slots = table.get_mut(offset..offset + segment.funcs.len()).ok_or("element segment out of its table")?;
+            for (slot, &func) in slots.iter_mut().zip(&segment.funcs) {
+                module.check_func(func)?;
+                *slot = Some(func);
+            }
+        }
+        for segment in &module.data {
+            let Some(offset) = segment.offset else { continue };
+            let offset = instance.eval(offset) as u32 as usize;
+            let bytes = instance.memory.get_mut(offset..offset + segment.bytes.len()).ok_or("data segment out of memory")?;
+            bytes.copy_from_slice(&segment.bytes);
+        }
+        let start = module.start;
+        instance.module = module;
+        if let Some(start) = start {
+            instance.invoke(start, Vec::new())?;
+        }
+        Ok(instance)
+    }
+
+    fn eval(&self, expr: ConstExpr) -> u64 {
+        match expr {
+            ConstExpr::Value(value) => value,
+            ConstExpr::Global(index) => self.globals[index as usize],
+        }
+    }
+
+    /// Calls the exported function `name` with a fresh fuel.
+    fn call(&mut self, name: &str, args: &[u64]) -> Result<Vec<u64>, String> {
+        let Some(Export::Func(index)) = self.module.exports.get(name).copied() else {
+            return Err(format!("no function {} exported", name));
+        };
+        self.fuel = FUEL_PER_CALL;
+        self.invoke(index, args.to_vec()).map_err(|e| format!("{}: {}", name, e))
+    }
+
+    fn write(&mut self, ptr: u64, bytes: &[u8]) -> Result<(), String> {
+        let range = self.range(ptr, bytes.len() as u64).ok_or("alloc gave an address out of memory")?;
+        self.memory[range].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn read(&self, ptr: u64, len: u64) -> Result<&[u8], String> {
+        let range = self.range(ptr, len).ok_or("the answer is out of memory")?;
+        Ok(&self.memory[range])
+    }
+
+    /// Bytes `[ptr, ptr + len)` of the memory, when they are all in it.
+    fn range(&self, ptr: u64, len: u64) -> Option<std::ops::Range<usize>> {
+        let end = ptr.checked_add(len)?;
+        (end <= self.memory.len() as u64).then_some(ptr as usize..end as usize)
+    }
+
+    /// Runs the function `index` to its end, calls included, on a fresh value stack.
+    fn invoke(&mut self, index: u32, args: Vec<u64>) -> Result<Vec<u64>, String> {
+        let mut stack = args;
+        let Some(frame) = self.enter(index, &mut stack)? else {
+            return Ok(stack);
+        };
+        self.run(frame, stack)
+    }
+
+    /// Starts a call of the function `index`, taking its arguments from `stack`: the frame
+    /// to run, or `None` for host functions, which are done at once.
+    fn enter(&mut self, index: u32, stack: &mut Vec<u64>) -> Result<Option<Frame>, String> {
+        let ty = &self.module.types[self.module.func_types[index as usize] as usize];
+        let results = ty.results.len();
+        let from = stack.len().checked_sub(ty.params.len()).ok_or("stack underflow")?;
+        let mut locals = stack.split_off(from);
+        match &self.module.funcs[index as usize] {
+            Func::Host(host) => {
+                let values = self.host_call(*host, &locals)?;
+                stack.extend(values);
+                Ok(None)
+            }
+            Func::Wasm { locals: extra, code } => {
+                locals.resize(locals.len() + extra, 0);
+                let label = Label { height: stack.len(), arity: results, target: code.len() - 1, is_loop: false };
+                Ok(Some(Frame { code: Arc::clone(code), locals, labels: vec![label], pc: 0, base: stack.len(), results }))
+            }
+        }
+    }
+
+    fn host_call(&self, host: HostFunc, args: &[u64]) -> Result<Vec<u64>, String> {
+        match host {
+            HostFunc::Log => {
+                let text = self.read(args[0] & 0xFFFF_FFFF, args[1] & 0xFFFF_FFFF)?;
+                let text = String::from_utf8_lossy(text);
+                logging::info("wasm_log", &[("plugin", self.name.as_str().into())], format!("{}: {}", self.name, text));
+            }
+        }
+        Ok(Vec::new())
+    }
+
+    /// Address of the `len` bytes at `base + offset`, trapping when they are not all in memory.
+    fn address(&self, base: u64, offset: u32, len: usize) -> Result<usize, String> {
+        let start = (base & 0xFFFF_FFFF) + u64::from(offset);
+        self.range(start, len as u64).map(|range| range.start).ok_or_else(|| "out of bounds memory access".to_string())
+    }
+
+    /// Runs `frame` and the calls it makes, their frames kept on the heap rather than as
+    /// Rust calls: the values left on `stack` once it returns.
+    fn run(&mut self, mut frame: Frame, mut stack: Vec<u64>) -> Result<Vec<u64>, String> {
+        let mut callers: Vec<Frame> = Vec::new();
+        loop {
+            if self.fuel == 0 {
+                return Err("out of fuel".to_string());
+            }
+            self.fuel -= 1;
+            let pc = frame.pc;
+            let flow = match &frame.code[pc] {
+                Op::Unreachable => return Err("unreachable executed".to_string()),
+                Op::Nop => Flow::Next(pc + 1),
+                &Op::Block { params, results, end } => {
+                    let height = stack.len().checked_sub(params).ok_or("stack underflow")?;
+                    frame.labels.push(Label { height, arity: results, target: end, is_loop: false });
+                    Flow::Next(pc + 1)
+                }
+                &Op::Loop { params } => {
+                    let height = stack.len().checked_sub(params).ok_or("stack underflow")?;
+                    frame.labels.push(Label { height, arity: params, target: pc, is_loop: true });
+                    Flow::Next(pc + 1)
+                }
+                &Op::If { params, results, else_at, end } => {
+                    let condition = pop(&mut stack)? as u32;
+                    let height = stack.len().checked_sub(params).ok_or("stack underflow")?;
+                    frame.labels.push(Label { height, arity: results, target: end, is_loop: false });
+                    Flow::Next(match (condition, else_at) {
+                        (0, Some(else_at)) => else_at + 1,
+                        (0, None) => end,
+                        _ => pc + 1,
+                    })
+                }
+                &Op::Else { end } => Flow::Next(end),
+                Op::End => {
+                    frame.labels.pop();
+                    if frame.labels.is_empty() { Flow::Return } else { Flow::Next(pc + 1) }
+                }
+                &Op::Br(depth) => match branch(&mut stack, &mut frame.labels, depth)? {
+                    Some(target) => Flow::Next(target),
+                    None => Flow::Return,
+                },
+                &Op::BrIf(depth) => {
+                    if pop(&mut stack)? as u32 == 0 {
+                        Flow::Next(pc + 1)
+                    } else {
+                        match branch(&mut stack, &mut frame.labels, depth)? {
+                            Some(target) => Flow::Next(target),
+                            None => Flow::Return,
+                        }
+                    }
+                }
+                Op::BrTable(targets, default) => {
+                    let index = pop(&mut stack)? as u32 as usize;
+                    match branch(&mut stack, &mut frame.labels, *targets.get(index).unwrap_or(default))? {
+                        Some(target) => Flow::Next(target),
+                        None => Flow::Return,
+                    }
+                }
+                Op::Return => Flow::Return,
+                &Op::Call(index) => Flow::Call(index),
+                &Op::CallIndirect(ty, table) => {
+                    let slot = pop(&mut stack)? as u32 as usize;
+                    let func = match self.tables.get(table as usize).ok_or("unknown table")?.get(slot) {
+                        Some(Some(func)) => *func,
+                        Some(None) => return Err("uninitialized element".to_string()),
+                        None => return Err("undefined element".to_string()),
+                    };
+                    if self.module.types[self.module.func_types[func as usize] as usize] != self.module.types[ty as usize] {
+                        return Err("indirect call type mismatch".to_string());
+                    }
+                    Flow::Call(func)
+                }
+                Op::Drop => {
+                    pop(&mut stack)?;
+                    Flow::Next(pc + 1)
+                }
+                Op::Select => {
+                    let (condition, second, first) = (pop(&mut stack)?, pop(&mut stack)?, pop(&mut stack)?);
+                    stack.push(if condition as u32 != 0 { first } else { second });
+                    Flow::Next(pc + 1)
+                }
+                &Op::LocalGet(index) => {
+                    stack.push(*frame.locals.get(index as usize).ok_or("unknown local")?);
+                    Flow::Next(pc + 1)
+                }
+                &Op::LocalSet(index) => {
+                    let value = pop(&mut stack)?;
+                    *frame.locals.get_mut(index as usize).ok_or("unknown local")? = value;
+                    Flow::Next(pc + 1)
+                }
+                &Op::LocalTee(index) => {
+                    let value = *stack.last().ok_or("stack underflow")?;
+                    *frame.locals.get_mut(index as usize).ok_or("unknown local")? = value;
+                    Flow::Next(pc + 1)
+                }
+                &Op::GlobalGet(index) => {
+                    stack.push(*self.globals.get(index as usize).ok_or("unknown global")?);
+                    Flow::Next(pc + 1)
+                }
+                &Op::GlobalSet(index) => {
+                    let value = pop(&mut stack)?;
+                    *self.globals.get_mut(index as usize).ok_or("unknown global")? = value;
+                    Flow::Next(pc + 1)
+                }
+                &Op::Load(op, offset) => {
+                    let size = match op {
+                        0x28 | 0x2A | 0x34 | 0x35 => 4,
+                        0x29 | 0x2B => 8,
+                        0x2C | 0x2D | 0x30 | 0x31 => 1,
+                        _ => 2,
+                    };
+                    let base = pop(&mut stack)?;
+                    let at = self.address(base, offset, size)?;
+                    let mut bytes = [0; 8];
+                    bytes[..size].copy_from_slice(&self.memory[at..at + size]);
+                    let raw = u64::from_le_bytes(bytes);
+                    stack.push(match op {
+                        0x2C => u64::from(raw as i8 as i32 as u32),
+                        0x2E => u64::from(raw as i16 as i32 as u32),
+                        0x30 => raw as i8 as i64 as u64,
+                        0x32 => raw as i16 as i64 as u64,
+                        0x34 => raw as i32 as i64 as u64,
+                        _ => raw,
+                    });
+                    Flow::Next(pc + 1)
+                }
+                &Op::Store(op, offset) => {
+                    let size = match op {
+                        0x36 | 0x38 | 0x3E => 4,
+                        0x37 | 0x39 => 8,
+                        0x3A | 0x3C => 1,
+                        _ => 2,
+                    };
+                    let value = pop(&mut stack)?;
+                    let base = pop(&mut stack)?;
+                    let at = self.address(base, offset, size)?;
+                    self.memory[at..at + size].copy_from_slice(&value.to_le_bytes()[..size]);
+                    Flow::Next(pc + 1)
+                }
+                Op::MemorySize => {
+                    stack.push((self.memory.len() / PAGE_SIZE) as u64);
+                    Flow::Next(pc + 1)
+                }
+                Op::MemoryGrow => {
+                    let delta = pop(&mut stack)? as u32;
+                    let pages = (self.memory.len() / PAGE_SIZE) as u32;
+                    let previous = match pages.checked_add(delta) {
+                        Some(new) if new <= self.max_pages => {
+                            self.memory.resize(new as usize * PAGE_SIZE, 0);
+                            pages
+                        }
+                        _ => u32::MAX, // -1, the memory is left as it is
+                    };
+                    stack.push(u64::from(previous));
+                    Flow::Next(pc + 1)
+                }
+                &Op::Const(value) => {
+                    stack.push(value);
+                    Flow::Next(pc + 1)
+                }
+                &Op::Numeric(op) => {
+                    let operand = pop(&mut stack)?;
+                    let value = if is_unary(op) { unary(op, operand)? } else { binary(op, pop(&mut stack)?, operand)? };
+                    stack.push(value);
+                    Flow::Next(pc + 1)
+                }
+                &Op::TruncSat(kind) => {
+                    let operand = pop(&mut stack)?;
+                    stack.push(truncate(kind, operand, true)?);
+                    Flow::Next(pc + 1)
+                }
+                Op::MemoryCopy => {
+                    let (len, source, destination) = (pop(&mut stack)?, pop(&mut stack)?, pop(&mut stack)?);
+                    let (len, source, destination) = (len & 0xFFFF_FFFF, source & 0xFFFF_FFFF, destination & 0xFFFF_FFFF);
+                    let source = self.range(source, len).ok_or("out of bounds memory access")?;
+                    let destination = self.range(destination, len).ok_or("out of bounds memory access")?;
+                    self.memory.copy_within(source, destination.start);
+                    Flow::Next(pc + 1)
+                }
+                Op::MemoryFill => {
+                    let (len, value, destination) = (pop(&mut stack)?, pop(&mut stack)?, pop(&mut stack)?);
+                    let destination = self.range(destination & 0xFFFF_FFFF, len & 0xFFFF_FFFF).ok_or("out of bounds memory access")?;
+                    self.memory[destination].fill(value as u8);
+                    Flow::Next(pc + 1)
+                }
+                Op::RefNull => {
+                    stack.push(NULL_REF);
+                    Flow::Next(pc + 1)
+                }
+                Op::RefIsNull => {
+                    let value = pop(&mut stack)?;
+                    stack.push(u64::from(value == NULL_REF));
+                    Flow::Next(pc + 1)
+                }
+                &Op::RefFunc(index) => {
+                    stack.push(u64::from(index));
+                    Flow::Next(pc + 1)
+                }
+                Op::Unsupported(name) => return Err(format!("{} is not supported", name)),
+            };
+            match flow {
+                Flow::Next(next) => frame.pc = next,
+                Flow::Call(index) => {
+                    frame.pc = pc + 1;
+                    if let Some(callee) = self.enter(index, &mut stack)? {
+                        if callers.len() + 1 >= MAX_CALL_DEPTH {
+                            return Err("call stack exhausted".to_string());
+                        }
+                        callers.push(std::mem::replace(&mut frame, callee));
+                    }
+                }
+                Flow::Return => {
+                    let from = stack.len().checked_sub(frame.results).filter(|&from| from >= frame.base).ok_or("stack underflow")?;
+                    stack.drain(frame.base..from);
+                    match callers.pop() {
+                        Some(caller) => frame = caller,
+                        None => return Ok(stack),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn pop(stack: &mut Vec<u64>) -> Result<u64, String> {
+    stack.pop().ok_or_else(|| "stack underflow".to_string())
+}
+
+/// Branches to the label `depth` blocks out, keeping the values it carries: the op to run
+/// next, or `None` when the branch leaves the function.
+fn branch(stack: &mut Vec<u64>, labels: &mut Vec<Label>, depth: u32) -> Result<Option<usize>, String> {
+    let index = labels.len().checked_sub(depth as usize + 1).ok_or("branch out of the function")?;
+    let label = labels[index];
+    let from = stack.len().checked_sub(label.arity).filter(|&from| from >= label.height).ok_or("stack underflow")?;
+    stack.drain(label.height..from);
+    labels.truncate(index);
+    Ok(if label.is_loop {
+        Some(label.target) // The loop op, entering the loop again
+    } else if labels.is_empty() {
+        None
+    } else {
+        Some(label.target + 1) // Past the end op, whose label is already gone
+    })
+}
+
+fn is_unary(op: u8) -> bool {
+    matches!(op, 0x45 | 0x50 | 0x67..=0x69 | 0x79..=0x7B | 0x8B..=0x91 | 0x99..=0x9F | 0xA7..=0xC4)
+}
+
+fn f32_bits(value: f32) -> u64 {
+    u64::from(value.to_bits())
+}
+
+/// Minimum as WASM has it: NaN when either is, and -0 below +0.
+fn min(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == b {
+        if a.is_sign_negative() { a } else { b }
+    } else {
+        a.min(b)
+    }
+}
+
+/// Maximum as WASM has it, see `min`.
+fn max(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == b {
+        if a.is_sign_positive() { a } else { b }
+    } else {
+        a.max(b)
+    }
+}
+
+/// Converts a float to an integer, `kind` as the `trunc_sat` ops number them: bit 0 for
+/// unsigned, bit 1 for a f64 operand, bit 2 for an i64 result. Out of range values trap
+/// unless `saturate`.
+fn truncate(kind: u8, bits: u64, saturate: bool) -> Result<u64, String> {
+    let value = if kind & 2 == 0 { f64::from(f32::from_bits(bits as u32)) } else { f64::from_bits(bits) };
+    let (wide, signed) = (kind & 4 != 0, kind & 1 == 0);
+    if !saturate {
+        if value.is_nan() {
+            return Err("invalid conversion to integer".to_string());
+        }
+        // Exclusive bounds, the next floats out of range once truncated
+        let (low, high) = match (wide, signed) {
+            (false, true) => (-2_147_483_649.0, 2_147_483_648.0),
+            (false, false) => (-1.0, 4_294_967_296.0),
+            (true, true) => (-9_223_372_036_854_777_856.0, 9_223_372_036_854_775_808.0),
+            (true, false) => (-1.0, 18_446_744_073_709_551_616.0),
+        };
+        if value <= low || value >= high {
+            return Err("integer overflow".to_string());
+        }
+    }
+    // Casts of floats saturate, and give 0 for NaN
+    Ok(match (wide, signed) {
+        (false, true) => u64::from(value as i32 as u32),
+        (false, false) => u64::from(value as u32),
+        (true, true) => value as i64 as u64,
+        (true, false) => value as u64,
+    })
+}
+
+fn unary(op: u8, a: u64) -> Result<u64, String> {
+    let (a32, fa, da) = (a as u32, f32::from_bits(a as u32), f64::from_bits(a));
+    Ok(match op {
+        0x45 => u64::from(a32 == 0),
+        0x50 => u64::from(a == 0),
+        0x67 => u64::from(a32.leading_zeros()),
+        0x68 => u64::from(a32.trailing_zeros()),
+        0x69 => u64::from(a32.count_ones()),
+        0x79 => u64::from(a.leading_zeros()),
+        0x7A => u64::from(a.trailing_zeros()),
+        0x7B => u64::from(a.count_ones()),
+        // Sign changes work on the bits, leaving NaNs as they are
+        0x8B => a & 0x7FFF_FFFF,
+        0x8C => a ^ 0x8000_0000,
+        0x8D => f32_bits(fa.ceil()),
+        0x8E => f32_bits(fa.floor()),
+        0x8F => f32_bits(fa.trunc()),
+        0x90 => f32_bits(fa.round_ties_even()),
+        0x91 => f32_bits(fa.sqrt()),
+        0x99 => a & !(1 << 63),
+        0x9A => a ^ 1 << 63,
+        0x9B => da.ceil().to_bits(),
+        0x9C => da.floor().to_bits(),
+        0x9D => da.trunc().to_bits(),
+        0x9E => da.round_ties_even().to_bits(),
+        0x9F => da.sqrt().to_bits(),
+        0xA7 => a & 0xFFFF_FFFF,
+        0xA8 => truncate(0, a, false)?,
+        0xA9 => truncate(1, a, false)?,
+        0xAA => truncate(2, a, false)?,
+        0xAB => truncate(3, a, false)?,
+        0xAC => a32 as i32 as i64 as u64,
+        0xAD => u64::from(a32),
+        0xAE => truncate(4, a, false)?,
+        0xAF => truncate(5, a, false)?,
+        0xB0 => truncate(6, a, false)?,
+        0xB1 => truncate(7, a, false)?,
+        0xB2 => f32_bits(a32 as i32 as f32),
+        0xB3 => f32_bits(a32 as f32),
+        0xB4 => f32_bits(a as i64 as f32),
+        0xB5 => f32_bits(a as f32),
+        0xB6 => f32_bits(da as f32),
+        0xB7 => f64::from(a32 as i32).to_bits(),
+        0xB8 => f64::from(a32).to_bits(),
+        0xB9 => (a as i64 as f64).to_bits(),
+        0xBA => (a as f64).to_bits(),
+        0xBB => f64::from(fa).to_bits(),
+        0xBC..=0xBF => a, // Reinterpretations keep the bits
+        0xC0 => u64::from(a32 as i8 as i32 as u32),
+        0xC1 => u64::from(a32 as i16 as i32 as u32),
+        0xC2 => a as i8 as i64 as u64,
+        0xC3 => a as i16 as i64 as u64,
+        0xC4 => a as i32 as i64 as u64,
+        other => return Err(format!("unknown instruction 0x{:02x}", other)),
+    })
+}
+
+fn binary(op: u8, a: u64, b: u64) -> Result<u64, String> {
+    let (a32, b32) = (a as u32, b as u32);
+    let (fa, fb) = (f32::from_bits(a32), f32::from_bits(b32));
+    let (da, db) = (f64::from_bits(a), f64::from_bits(b));
+    let divide_by_zero = || "integer divide by zero".to_string();
+    Ok(match op {
+        0x46 => u64::from(a32 == b32),
+        0x47 => u64::from(a32 != b32),
+        0x48 => u64::from((a32 as i32) < b32 as i32),
+        0x49 => u64::from(a32 < b32),
+        0x4A => u64::from(a32 as i32 > b32 as i32),
+        0x4B => u64::from(a32 > b32),
+        0x4C => u64::from(a32 as i32 <= b32 as i32),
+        0x4D => u64::from(a32 <= b32),
+        0x4E => u64::from(a32 as i32 >= b32 as i32),
+        0x4F => u64::from(a32 >= b32),
+        0x51 => u64::from(a == b),
+        0x52 => u64::from(a != b),
+        0x53 => u64::from((a as i64) < b as i64),
+        0x54 => u64::from(a < b),
+        0x55 => u64::from(a as i64 > b as i64),
+        0x56 => u64::from(a > b),
+        0x57 => u64::from(a as i64 <= b as i64),
+        0x58 => u64::from(a <= b),
+        0x59 => u64::from(a as i64 >= b as i64),
+        0x5A => u64::from(a >= b),
+        0x5B => u64::from(fa == fb),
+        0x5C => u64::from(fa != fb),
+        0x5D => u64::from(fa < fb),
+        0x5E => u64::from(fa > fb),
+        0x5F => u64::from(fa <= fb),
+        0x60 => u64::from(fa >= fb),
+        0x61 => u64::from(da == db),
+        0x62 => u64::from(da != db),
+        0x63 => u64::from(da < db),
+        0x64 => u64::from(da > db),
+        0x65 => u64::from(da <= db),
+        0x66 => u64::from(da >= db),
+        0x6A => u64::from(a32.wrapping_add(b32)),
+        0x6B => u64::from(a32.wrapping_sub(b32)),
+        0x6C => u64::from(a32.wrapping_mul(b32)),
+        0x6D => match (a32 as i32).checked_div(b32 as i32) {
+            Some(quotient) => u64::from(quotient as u32),
+            None if b32 == 0 => return Err(divide_by_zero()),
+            None => return Err("integer overflow".to_string()),
+        },
+        0x6E => u64::from(a32.checked_div(b32).ok_or_else(divide_by_zero)?),
+        0x6F if b32 == 0 => return Err(divide_by_zero()),
+        0x6F => u64::from((a32 as i32).wrapping_rem(b32 as i32) as u32),
+        0x70 => u64::from(a32.checked_rem(b32).ok_or_else(divide_by_zero)?),
+        0x71 => u64::from(a32 & b32),
+        0x72 => u64::from(a32 | b32),
+        0x73 => u64::from(a32 ^ b32),
+        0x74 => u64::from(a32.wrapping_shl(b32)),
+        0x75 => u64::from((a32 as i32).wrapping_shr(b32) as u32),
+        0x76 => u64::from(a32.wrapping_shr(b32)),
+        0x77 => u64::from(a32.rotate_left(b32)),
+        0x78 => u64::from(a32.rotate_right(b32)),
+        0x7C => a.wrapping_add(b),
+        0x7D => a.wrapping_sub(b),
+        0x7E => a.wrapping_mul(b),
+        0x7F => match (a as i64).checked_div(b as i64) {
+            Some(quotient) => quotient as u64,
+            None if b == 0 => return Err(divide_by_zero()),
+            None => return Err("integer overflow".to_string()),
+        },
+        0x80 => a.checked_div(b).ok_or_else(divide_by_zero)?,
+        0x81 if b == 0 => return Err(divide_by_zero()),
+        0x81 => (a as i64).wrapping_rem(b as i64) as u64,
+        0x82 => a.checked_rem(b).ok_or_else(divide_by_zero)?,
+        0x83 => a & b,
+        0x84 => a | b,
+        0x85 => a ^ b,
+        0x86 => a.wrapping_shl(b32),
+        0x87 => (a as i64).wrapping_shr(b32) as u64,
+        0x88 => a.wrapping_shr(b32),
+        0x89 => a.rotate_left(b32),
+        0x8A => a.rotate_right(b32),
+        0x92 => f32_bits(fa + fb),
+        0x93 => f32_bits(fa - fb),
+        0x94 => f32_bits(fa * fb),
+        0x95 => f32_bits(fa / fb),
+        0x96 => f32_bits(min(f64::from(fa), f64::from(fb)) as f32),
+        0x97 => f32_bits(max(f64::from(fa), f64::from(fb)) as f32),
+        0x98 => f32_bits(fa.copysign(fb)),
+        0xA0 => (da + db).to_bits(),
+        0xA1 => (da - db).to_bits(),
+        0xA2 => (da * db).to_bits(),
+        0xA3 => (da / db).to_bits(),
+        0xA4 => min(da, db).to_bits(),
+        0xA5 => max(da, db).to_bits(),
+        0xA6 => da.copysign(db).to_bits(),
+        other => return Err(format!("unknown instruction 0x{:02x}", other)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lookup::{LookupReport, LookupSource};
+    use crate::status::NifStatus;
+
+    fn leb(mut value: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(byte);
+                return out;
+            }
+            out.push(byte | 0x80);
+        }
+    }
+
+    fn sleb(mut value: i64) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+                out.push(byte);
+                return out;
+            }
+            out.push(byte | 0x80);
+        }
+    }
+
+    fn vector(items: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = leb(items.len() as u64);
+        items.iter().for_each(|item| out.extend(item));
+        out
+    }
+
+    fn name(text: &str) -> Vec<u8> {
+        [leb(text.len() as u64), text.as_bytes().to_vec()].concat()
+    }
+
+    type Locals = Vec<(u32, u8)>; // Count and type of the locals declared
+
+    /// Hand-assembled module: functions as their type, declared locals and code.
+    #[derive(Default)]
+    struct Builder {
+        types: Vec<(Vec<u8>, Vec<u8>)>,
+        log_import: Option<u32>, // Type of `env.log`
+        funcs: Vec<(u32, Locals, Vec<u8>)>,
+        table: Option<u32>,
+        memory: bool,
+        exports: Vec<(&'static str, u8, u32)>,
+        elems: Vec<Vec<u32>>, // At offset 0
+        data: Vec<(i64, Vec<u8>)>,
+    }
+
+    impl Builder {
+        fn build(&self) -> Vec<u8> {
+            let mut out = b"\0asm\x01\0\0\0".to_vec();
+            let mut section = |id: u8, contents: Vec<u8>| {
+                out.push(id);
+                out.extend(leb(contents.len() as u64));
+                out.extend(contents);
+            };
+            section(1, vector(&self.types.iter().map(|(params, results)| [vec![0x60], vector(&params.iter().map(|&ty| vec![ty]).collect::<Vec<_>>()), vector(&results.iter().map(|&ty| vec![ty]).collect::<Vec<_>>())].concat()).collect::<Vec<_>>()));
+            if let Some(ty) = self.log_import {
+                section(2, vector(&[[name("env"), name("log"), vec![0], leb(u64::from(ty))].concat()]));
+            }
+            section(3, vector(&self.funcs.iter().map(|(ty, _, _)| leb(u64::from(*ty))).collect::<Vec<_>>()));
+            if let Some(size) = self.table {
+                section(4, vector(&[[vec![FUNCREF, 0], leb(u64::from(size))].concat()]));
+            }
+            if self.memory {
+                section(5, vector(&[vec![0, 1]]));
+            }
+            section(7, vector(&self.exports.iter().map(|(export, kind, index)| [name(export), vec![*kind], leb(u64::from(*index))].concat()).collect::<Vec<_>>()));
+            if !self.elems.is_empty() {
+                section(9, vector(&self.elems.iter().map(|funcs| [vec![0, 0x41, 0, 0x0B], vector(&funcs.iter().map(|&func| leb(u64::from(func))).collect::<Vec<_>>())].concat()).collect::<Vec<_>>()));
+            }
+            section(10, vector(&self.funcs.iter().map(|(_, locals, code)| {
+                let body = [vector(&locals.iter().map(|&(count, ty)| [leb(u64::from(count)), vec![ty]].concat()).collect::<Vec<_>>()), code.clone()].concat();
+                [leb(body.len() as u64), body].concat()
+            }).collect::<Vec<_>>()));
+            if !self.data.is_empty() {
+                section(11, vector(&self.data.iter().map(|(offset, bytes)| [vec![0, 0x41], sleb(*offset), vec![0x0B], vector(&bytes.iter().map(|&byte| vec![byte]).collect::<Vec<_>>())].concat()).collect::<Vec<_>>()));
+            }
+            out
+        }
+
+        fn instance(&self) -> Instance {
+            Instance::new("test.wasm", Module::parse(&self.build()).unwrap()).unwrap()
+        }
+    }
+
+    /// Module of one exported function `f` of the given signature.
+    fn function(params: &[u8], results: &[u8], locals: &[(u32, u8)], code: &[u8]) -> Instance {
+        let builder = Builder {
+            types: vec![(params.to_vec(), results.to_vec())],
+            funcs: vec![(0, locals.to_vec(), code.to_vec())],
+            memory: true,
+            exports: vec![("f", 0, 0)],
+            ..Builder::default()
+        };
+        builder.instance()
+    }
+
+    #[test]
+    fn loops_and_arithmetic() {
+        // Factorial: multiplies the local 1 by the parameter until it is down to 0
+        let code = [
+            0x41, 1, 0x21, 1, 0x02, 0x40, 0x03, 0x40, 0x20, 0, 0x45, 0x0D, 1, 0x20, 1, 0x20, 0, 0x6C, 0x21, 1, 0x20, 0, 0x41, 1,
+            0x6B, 0x21, 0, 0x0C, 0, 0x0B, 0x0B, 0x20, 1, 0x0B,
+        ];
+        let mut instance = function(&[I32], &[I32], &[(1, I32)], &code);
+        assert_eq!(instance.call("f", &[5]).unwrap(), [120]);
+        assert_eq!(instance.call("f", &[10]).unwrap(), [3_628_800]);
+        assert_eq!(instance.call("f", &[0]).unwrap(), [1]);
+    }
+
+    #[test]
+    fn recursion() {
+        // Fibonacci, calling itself
+        let code = [
+            0x20, 0, 0x41, 2, 0x48, 0x04, I32, 0x20, 0, 0x05, 0x20, 0, 0x41, 1, 0x6B, 0x10, 0, 0x20, 0, 0x41, 2, 0x6B, 0x10, 0,
+            0x6A, 0x0B, 0x0B,
+        ];
+        let mut instance = function(&[I32], &[I32], &[], &code);
+        assert_eq!(instance.call("f", &[20]).unwrap(), [6765]);
+
+        let mut endless = function(&[], &[], &[], &[0x10, 0, 0x0B]);
+        assert_eq!(endless.call("f", &[]).unwrap_err(), "f: call stack exhausted");
+    }
+
+    #[test]
+    fn memory_access() {
+        // Stores -2 at 8 and loads it back as a signed byte and an unsigned half word
+        let code = [0x41, 8, 0x41, 0x7E, 0x36, 2, 0, 0x41, 8, 0x2C, 0, 0, 0x41, 8, 0x2F, 1, 0, 0x0B];
+        let mut instance = function(&[], &[I32, I32], &[], &code);
+        assert_eq!(instance.call("f", &[]).unwrap(), [0xFFFF_FFFE, 0xFFFE]);
+
+        let code = [&[0x41][..], &sleb(65_535), &[0x28, 2, 0, 0x0B]].concat();
+        let mut instance = function(&[], &[I32], &[], &code);
+        assert_eq!(instance.call("f", &[]).unwrap_err(), "f: out of bounds memory access");
+
+        // memory.grow gives the previous size, and -1 past the limit
+        let code = [&[0x20, 0, 0x40, 0, 0x1A, 0x3F, 0][..], &[0x0B]].concat();
+        let mut instance = function(&[I32], &[I32], &[], &code);
+        assert_eq!(instance.call("f", &[2]).unwrap(), [3]);
+        assert_eq!(instance.call("f", &[MAX_MEMORY_PAGES as u64]).unwrap(), [3]);
+    }
+
+    #[test]
+    fn branch_table() {
+        let code = [
+            0x02, 0x40, 0x02, 0x40, 0x02, 0x40, 0x20, 0, 0x0E, 2, 0, 1, 2, 0x0B, 0x41, 10, 0x0F, 0x0B, 0x41, 20, 0x0F, 0x0B, 0x41,
+            30, 0x0B,
+        ];
+        let mut instance = function(&[I32], &[I32], &[], &code);
+        let results: Vec<u64> = [0, 1, 2, 5].iter().map(|&index| instance.call("f", &[index]).unwrap()[0]).collect();
+        assert_eq!(results, [10, 20, 30, 30]);
+    }
+
+    #[test]
+    fn indirect_calls() {
+        let builder = Builder {
+            types: vec![(vec![], vec![I32]), (vec![], vec![I64]), (vec![I32], vec![I32])],
+            funcs: vec![(0, vec![], vec![0x41, 7, 0x0B]), (1, vec![], vec![0x42, 8, 0x0B]), (2, vec![], vec![0x20, 0, 0x11, 0, 0, 0x0B])],
+            table: Some(3),
+            exports: vec![("f", 0, 2)],
+            elems: vec![vec![0, 1]],
+            ..Builder::default()
+        };
+        let mut instance = builder.instance();
+        assert_eq!(instance.call("f", &[0]).unwrap(), [7]);
+        assert_eq!(instance.call("f", &[1]).unwrap_err(), "f: indirect call type mismatch");
+        assert_eq!(instance.call("f", &[2]).unwrap_err(), "f: uninitialized element");
+        assert_eq!(instance.call("f", &[3]).unwrap_err(), "f: undefined element");
+    }
+
+    #[test]
+    fn traps() {
+        let trap = |code: &[u8]| function(&[], &[I32], &[], code).call("f", &[]).unwrap_err();
+        assert_eq!(trap(&[0x41, 1, 0x41, 0, 0x6D, 0x0B]), "f: integer divide by zero");
+        assert_eq!(trap(&[0x41, 0x80, 0x80, 0x80, 0x80, 0x78, 0x41, 0x7F, 0x6D, 0x0B]), "f: integer overflow");
+        assert_eq!(trap(&[0x00, 0x0B]), "f: unreachable executed");
+        // NaN traps when truncated, and saturates to 0
+        assert_eq!(trap(&[0x43, 0, 0, 0xC0, 0x7F, 0xA8, 0x0B]), "f: invalid conversion to integer");
+        let mut saturated = function(&[], &[I32], &[], &[0x43, 0, 0, 0xC0, 0x7F, 0xFC, 0, 0x0B]);
+        assert_eq!(saturated.call("f", &[]).unwrap(), [0]);
+
+        let mut endless = function(&[], &[], &[], &[0x03, 0x40, 0x0C, 0, 0x0B, 0x0B]);
+        endless.fuel = 1000;
+        assert_eq!(endless.invoke(0, Vec::new()).unwrap_err(), "out of fuel");
+    }
+
+    #[test]
+    fn floats() {
+        // f64: min(-0, 0) is -0, nearest(2.5) is 2
+        let code = [&[0x44][..], &(-0.0f64).to_le_bytes(), &[0x44], &0.0f64.to_le_bytes(), &[0xA4, 0x0B]].concat();
+        assert_eq!(function(&[], &[F64], &[], &code).call("f", &[]).unwrap(), [(-0.0f64).to_bits()]);
+        let code = [&[0x44][..], &2.5f64.to_le_bytes(), &[0x9E, 0x0B]].concat();
+        assert_eq!(function(&[], &[F64], &[], &code).call("f", &[]).unwrap(), [2.0f64.to_bits()]);
+        // i32.trunc_f64_u of 3e9, past i32 but not u32
+        let code = [&[0x44][..], &3e9f64.to_le_bytes(), &[0xAB, 0x0B]].concat();
+        assert_eq!(function(&[], &[I32], &[], &code).call("f", &[]).unwrap(), [3_000_000_000]);
+    }
+
+    #[test]
+    fn invalid_modules() {
+        assert_eq!(Module::parse(b"\0asm\x02\0\0\0").unwrap_err(), "unsupported WASM version");
+        assert_eq!(Module::parse(b"MZ").unwrap_err(), "not a WASM module");
+        // v128.const
+        let builder = Builder { types: vec![(vec![], vec![])], funcs: vec![(0, vec![], vec![0xFD, 12, 0x0B])], ..Builder::default() };
+        assert_eq!(Module::parse(&builder.build()).unwrap_err(), "SIMD is not supported");
+    }
+
+    fn result() -> LookupResult {
+        LookupResult {
+            nif: "500960046".to_string(),
+            status: NifStatus::ValidKnown,
+            entity: Some(NifEntity { nif: "500960046".to_string(), name: "Exemplo, Lda.".to_string(), ..NifEntity::default() }),
+            candidates: Vec::new(),
+            postal_check: None,
+            name_check: None,
+            location: None,
+            parse_confidence: None,
+            checked_at: None,
+            confidence: None,
+            tag: None,
+            source: LookupSource::Remote,
+            report: LookupReport::default(),
+        }
+    }
+
+    /// Plugin answering `answer`, or 0 when empty, after logging `logged` when given.
+    fn plugin(answer: &str, logged: Option<&str>) -> Result<WasmHook, String> {
+        let (answer_at, log_at) = (16, 4096);
+        let mut on_result = Vec::new();
+        if let Some(text) = logged {
+            on_result.extend([&[0x41][..], &sleb(log_at), &[0x41], &sleb(text.len() as i64), &[0x10, 0]].concat());
+        }
+        let packed = if answer.is_empty() { 0 } else { answer_at << 32 | answer.len() as i64 };
+        on_result.extend([&[0x42][..], &sleb(packed), &[0x0B]].concat());
+        let mut builder = Builder {
+            types: vec![(vec![I32], vec![I32]), (vec![I32, I32], vec![I64]), (vec![I32, I32], vec![])],
+            funcs: vec![(0, vec![], [&[0x41][..], &sleb(8192), &[0x0B]].concat()), (1, vec![], on_result)],
+            memory: true,
+            exports: vec![("memory", 2, 0), ("alloc", 0, 0), ("on_result", 0, 1)],
+            data: vec![(answer_at, answer.as_bytes().to_vec())],
+            ..Builder::default()
+        };
+        if let Some(text) = logged {
+            // The import comes first, moving the functions up by one
+            builder.log_import = Some(2);
+            builder.exports = vec![("memory", 2, 0), ("alloc", 0, 1), ("on_result", 0, 2)];
+            builder.data.push((log_at, text.as_bytes().to_vec()));
+        }
+        WasmHook::from_bytes("plugin.wasm", &builder.build())
+    }
+
+    #[test]
+    fn plugin_enriches_and_tags() {
+        let mut hook = plugin(r#"{"tag":"vip","entity":{"email":"geral@exemplo.pt","locality":null}}"#, None).unwrap();
+        let mut input = result();
+        input.entity.as_mut().unwrap().locality = Some("Lisboa".to_string());
+        let processed = hook.process(input).unwrap().unwrap();
+        assert_eq!(processed.tag.as_deref(), Some("vip"));
+        let entity = processed.entity.unwrap();
+        assert_eq!(entity.email.as_deref(), Some("geral@exemplo.pt"));
+        assert_eq!(entity.locality, None);
+        assert_eq!(entity.name, "Exemplo, Lda.");
+    }
+
+    #[test]
+    fn plugin_drops_or_keeps() {
+        assert_eq!(plugin(r#"{"keep":false}"#, None).unwrap().process(result()).unwrap(), None);
+        assert_eq!(plugin("", None).unwrap().process(result()).unwrap(), Some(result()));
+        assert_eq!(plugin("", Some("seen")).unwrap().process(result()).unwrap(), Some(result()));
+        assert_eq!(apply(&mut [plugin("", None).unwrap(), plugin(r#"{"keep":false}"#, None).unwrap()], result()), None);
+    }
+
+    #[test]
+    fn invalid_answers_keep_the_result() {
+        assert_eq!(plugin(r#"{"entity":{"nif":"123"}}"#, None).unwrap().process(result()).unwrap_err(), "the entity NIF cannot be changed");
+        assert_eq!(plugin("[1]", None).unwrap().process(result()).unwrap_err(), "the answer is not a JSON object");
+        let mut no_entity = result();
+        no_entity.entity = None;
+        assert_eq!(plugin(r#"{"entity":{"email":"a@b.pt"}}"#, None).unwrap().process(no_entity).unwrap_err(), "the entity details need a name");
+        let mut hooks = [plugin(r#"{"tag":1}"#, None).unwrap()];
+        assert_eq!(apply(&mut hooks, result()), Some(result()));
+    }
+
+    #[test]
+    fn plugin_exports_checked() {
+        let builder = Builder { types: vec![(vec![I32], vec![I32])], funcs: vec![(0, vec![], vec![0x20, 0, 0x0B])], memory: true, exports: vec![("memory", 2, 0), ("alloc", 0, 0)], ..Builder::default() };
+        assert_eq!(WasmHook::from_bytes("p.wasm", &builder.build()).err().unwrap(), "p.wasm does not export the function on_result");
+        let bytes = [&b"\0asm\x01\0\0\0"[..], &[1, 4, 1, 0x60, 0, 0, 2, 12, 1], &name("env"), &name("exit"), &[0, 0]].concat();
+        assert_eq!(
+            WasmHook::from_bytes("p.wasm", &bytes).err().unwrap(),
+            "invalid WASM module p.wasm: unknown import env.exit, only env.log is offered"
+        );
+    }
+}
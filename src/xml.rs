@@ -0,0 +1,231 @@
+// xml.rs
+
+/// Event of `XmlReader`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum XmlEvent {
+    Start(String), // Element name, without its namespace prefix
+    End(String),   // Same, at the closing tag (or right after a self-closing one)
+    Text(String),  // Decoded character data, CDATA included; whitespace between elements is skipped
+}
+
+/// Minimal XML pull parser for data files such as SAF-T: elements and text only.
+///
+/// Attributes, comments, processing instructions and the doctype are skipped, and
+/// namespace prefixes dropped. Tags must nest properly; DTD entities are not supported.
+pub struct XmlReader<'a> {
+    text: &'a str,
+    pos: usize,
+    pos_line: usize,   // Line at `pos`
+    event_line: usize, // Line where the last event started
+    open: Vec<String>, // Elements not closed yet
+    self_closed: bool, // The last `Start` was `<name/>`, its `End` comes next
+}
+
+impl<'a> XmlReader<'a> {
+    pub fn new(text: &'a str) -> Self {
+        XmlReader {
+            text: text.strip_prefix('\u{feff}').unwrap_or(text), // Byte order mark
+            pos: 0,
+            pos_line: 1,
+            event_line: 1,
+            open: Vec::new(),
+            self_closed: false,
+        }
+    }
+
+    /// Line where the last returned event started, counting from 1.
+    pub fn line(&self) -> usize {
+        self.event_line
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("invalid XML at line {}: {}", self.pos_line, message)
+    }
+
+    /// Moves past `len` bytes, keeping count of the lines.
+    fn advance(&mut self, len: usize) {
+        self.pos_line += self.text[self.pos..self.pos + len].matches('\n').count();
+        self.pos += len;
+    }
+
+    /// Moves past the next `end`, or fails on an unterminated construct.
+    fn skip_past(&mut self, end: &str, what: &str) -> Result<(), String> {
+        let found = self.text[self.pos..].find(end).ok_or_else(|| self.error(&format!("unterminated {}", what)))?;
+        self.advance(found + end.len());
+        Ok(())
+    }
+
+    /// Returns the next event, or `None` at the end of the document.
+    pub fn next_event(&mut self) -> Result<Option<XmlEvent>, String> {
+        if self.self_closed {
+            self.self_closed = false;
+            return Ok(self.open.pop().map(XmlEvent::End));
+        }
+        let text = self.text;
+        loop {
+            let rest = &text[self.pos..];
+            if rest.is_empty() {
+                return match self.open.last() {
+                    Some(name) => Err(self.error(&format!("unexpected end of file inside <{}>", name))),
+                    None => Ok(None),
+                };
+            }
+            self.event_line = self.pos_line;
+            if !rest.starts_with('<') {
+                let len = rest.find('<').unwrap_or(rest.len());
+                let raw = &rest[..len];
+                self.advance(len);
+                if raw.trim().is_empty() {
+                    continue;
+                }
+                return decode_entities(raw).map(|data| Some(XmlEvent::Text(data))).map_err(|e| self.error(&e));
+            }
+            if rest.starts_with("<!--") {
+                self.skip_past("-->", "comment")?;
+            } else if let Some(cdata) = rest.strip_prefix("<![CDATA[") {
+                let len = cdata.find("]]>").ok_or_else(|| self.error("unterminated CDATA section"))?;
+                let data = cdata[..len].to_string();
+                self.advance("<![CDATA[".len() + len + "]]>".len());
+                return Ok(Some(XmlEvent::Text(data)));
+            } else if rest.starts_with("<?") {
+                self.skip_past("?>", "processing instruction")?;
+            } else if rest.starts_with("<!") {
+                self.skip_past(">", "declaration")?;
+            } else {
+                return self.tag().map(Some);
+            }
+        }
+    }
+
+    /// Reads a start or end tag at `pos`.
+    fn tag(&mut self) -> Result<XmlEvent, String> {
+        // The tag ends at the first `>` outside a quoted attribute value
+        let text = self.text;
+        let rest = &text[self.pos..];
+        let mut quote = None;
+        let len = rest
+            .char_indices()
+            .find(|&(_, c)| match quote {
+                Some(q) if c == q => {
+                    quote = None;
+                    false
+                }
+                Some(_) => false,
+                None if c == '"' || c == '\'' => {
+                    quote = Some(c);
+                    false
+                }
+                None => c == '>',
+            })
+            .map(|(i, _)| i)
+            .ok_or_else(|| self.error("unterminated tag"))?;
+        let inner = &rest[1..len];
+        self.advance(len + 1);
+
+        if let Some(name) = inner.strip_prefix('/') {
+            let name = local_name(name.trim());
+            return match self.open.pop() {
+                Some(open) if open == name => Ok(XmlEvent::End(name)),
+                Some(open) => Err(self.error(&format!("expected </{}>, found </{}>", open, name))),
+                None => Err(self.error(&format!("unexpected </{}>", name))),
+            };
+        }
+        self.self_closed = inner.ends_with('/');
+        let name = inner.trim_end_matches('/').split_whitespace().next().unwrap_or_default();
+        if name.is_empty() {
+            return Err(self.error("tag without a name"));
+        }
+        let name = local_name(name);
+        self.open.push(name.clone());
+        Ok(XmlEvent::Start(name))
+    }
+}
+
+/// Drops the namespace prefix of `ns:Name`.
+fn local_name(name: &str) -> String {
+    name.rsplit(':').next().unwrap_or(name).to_string()
+}
+
+/// Replaces the predefined entities and character references.
+fn decode_entities(raw: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        let end = rest[start..].find(';').ok_or("unterminated entity reference")? + start;
+        let entity = &rest[start + 1..end];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => match entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                None => entity.strip_prefix('#').and_then(|dec| dec.parse().ok()).and_then(char::from_u32),
+            },
+        };
+        out.push(decoded.ok_or_else(|| format!("unknown entity &{};", entity))?);
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events(text: &str) -> Result<Vec<XmlEvent>, String> {
+        let mut reader = XmlReader::new(text);
+        let mut events = Vec::new();
+        while let Some(event) = reader.next_event()? {
+            events.push(event);
+        }
+        Ok(events)
+    }
+
+    #[test]
+    fn elements_and_text() {
+        let text = "\u{feff}<?xml version=\"1.0\"?>\n<!DOCTYPE a>\n<ns:a x=\"1 > 0\">\n  <!-- note -->\n  <b>R&amp;D &#231;&#xE7;</b>\n  <c/><![CDATA[<raw>]]>\n</ns:a>";
+        assert_eq!(
+            events(text).unwrap(),
+            [
+                XmlEvent::Start("a".to_string()),
+                XmlEvent::Start("b".to_string()),
+                XmlEvent::Text("R&D çç".to_string()),
+                XmlEvent::End("b".to_string()),
+                XmlEvent::Start("c".to_string()),
+                XmlEvent::End("c".to_string()),
+                XmlEvent::Text("<raw>".to_string()),
+                XmlEvent::End("a".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn lines_of_events() {
+        let mut reader = XmlReader::new("<a>\n\n<b>x</b>\n</a>");
+        reader.next_event().unwrap();
+        assert_eq!(reader.line(), 1);
+        assert_eq!(reader.next_event().unwrap(), Some(XmlEvent::Start("b".to_string())));
+        assert_eq!(reader.line(), 3);
+    }
+
+    #[test]
+    fn malformed_documents() {
+        for (text, error) in [
+            ("<a><b></a>", "expected </b>, found </a>"),
+            ("</a>", "unexpected </a>"),
+            ("<a>", "unexpected end of file inside <a>"),
+            ("<a x='1", "unterminated tag"),
+            ("<a><!-- x</a>", "unterminated comment"),
+            ("<a>&nbsp;</a>", "unknown entity &nbsp;"),
+            ("<a>\n&amp</a>", "line 2: unterminated entity reference"),
+            ("< >", "tag without a name"),
+        ] {
+            let found = events(text).unwrap_err();
+            assert!(found.contains(error), "{:?}: {}", text, found);
+        }
+    }
+}
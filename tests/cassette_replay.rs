@@ -0,0 +1,115 @@
+// tests/cassette_replay.rs
+
+//! Runs whole lookups against the cassettes of `tests/cassettes`, recorded answers of nif.pt
+//! and the fallback sites, so nothing goes out to the network.
+
+#![cfg(feature = "client")]
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use check_nif::cassette::Cassette;
+use check_nif::fallback::Fallback;
+use check_nif::{lookup_nif, LookupOptions, LookupSource, NifStatus};
+
+fn cassette(name: &str) -> Arc<Cassette> {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/cassettes").join(name);
+    Arc::new(Cassette::replay(&path).unwrap())
+}
+
+fn options(name: &str) -> LookupOptions {
+    let mut options = LookupOptions::default();
+    options.cassette = Some(cassette(name));
+    options
+}
+
+#[test]
+fn known_entity() {
+    let result = lookup_nif("PT500960046", &options("lookups.ndjson"));
+    assert_eq!(result.nif, "500960046");
+    assert_eq!(result.status, NifStatus::ValidKnown);
+    assert_eq!(result.source, LookupSource::Remote);
+    let entity = result.entity.unwrap();
+    assert_eq!(entity.name, "Exemplo Comércio, Lda.");
+    assert_eq!(entity.address.as_deref(), Some("Rua do Exemplo, 1, 2.º Esquerdo"));
+    assert_eq!(entity.postal_code.as_deref(), Some("1000-001"));
+    assert_eq!(entity.locality.as_deref(), Some("Lisboa"));
+    assert_eq!(entity.phone.as_deref(), Some("213 000 000"));
+    assert_eq!(entity.email.as_deref(), Some("geral@exemplo.pt"));
+}
+
+#[test]
+fn valid_unknown_and_invalid() {
+    let options = options("lookups.ndjson");
+    let result = lookup_nif("509442013", &options);
+    assert_eq!(result.status, NifStatus::ValidUnknown);
+    assert!(result.entity.is_none());
+    assert_eq!(lookup_nif("123456780", &options).status, NifStatus::Error);
+}
+
+#[test]
+fn multiple_results() {
+    let result = lookup_nif("505222221", &options("lookups.ndjson"));
+    assert_eq!(result.status, NifStatus::MultipleResults);
+    let candidates: Vec<(&str, Option<&str>, Option<&str>)> = result
+        .candidates
+        .iter()
+        .map(|candidate| (candidate.name.as_str(), candidate.nif.as_deref(), candidate.locality.as_deref()))
+        .collect();
+    assert_eq!(
+        candidates,
+        [
+            ("Outra Empresa, SA", Some("501442600"), Some("Porto")),
+            ("Outra Empresa Norte, Lda.", Some("502011378"), Some("Braga")),
+        ]
+    );
+}
+
+#[test]
+fn answers_replayed_in_turn() {
+    let options = options("lookups.ndjson");
+    assert_eq!(lookup_nif("510123457", &options).status, NifStatus::HttpError(503));
+    let result = lookup_nif("510123457", &options);
+    assert_eq!(result.status, NifStatus::ValidKnown);
+    assert_eq!(result.entity.unwrap().locality.as_deref(), Some("Faro"));
+    // The last answer again once they are used up
+    assert_eq!(lookup_nif("510123457", &options).status, NifStatus::ValidKnown);
+}
+
+#[test]
+fn rate_limited() {
+    let result = lookup_nif("509111114", &options("lookups.ndjson"));
+    assert_eq!(result.status, NifStatus::HttpError(429));
+}
+
+#[test]
+fn unsupported_layout() {
+    let result = lookup_nif("503504564", &options("lookups.ndjson"));
+    assert_eq!(result.status, NifStatus::UnsupportedLayout);
+    assert_eq!(result.report.error.as_deref(), Some("unsupported page layout"));
+}
+
+#[test]
+fn request_never_recorded() {
+    let result = lookup_nif("502011378", &options("lookups.ndjson"));
+    assert_eq!(result.status, NifStatus::Unknown);
+    assert!(result.report.error.unwrap().starts_with("no recorded answer in "));
+}
+
+#[test]
+fn falls_back_when_nif_pt_is_down() {
+    let mut options = options("fallback.ndjson");
+    let result = lookup_nif("504615947", &options);
+    assert_eq!(result.status, NifStatus::HttpError(503));
+
+    options.fallbacks = vec![Fallback::Racius];
+    let result = lookup_nif("504615947", &options);
+    assert_eq!(result.status, NifStatus::ValidKnown);
+    assert_eq!(result.source, LookupSource::Fallback(Fallback::Racius));
+    let entity = result.entity.unwrap();
+    assert_eq!(entity.name, "Fallback Serviços, Lda.");
+    assert_eq!(entity.address.as_deref(), Some("Avenida Central, 10"));
+    assert_eq!(entity.postal_code.as_deref(), Some("4000-100"));
+    assert_eq!(entity.locality.as_deref(), Some("Porto"));
+    assert_eq!(entity.phone.as_deref(), Some("222 000 000"));
+}
@@ -0,0 +1,124 @@
+// tests/wasm_hook.rs
+
+//! Runs the `check_nif` binary with `--wasm-hook` plugins over the lookups of the
+//! `tests/cassettes/lookups.ndjson` cassette, so nothing goes out to the network.
+
+#![cfg(feature = "wasm")]
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn uleb(mut value: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn sleb(mut value: i64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn section(id: u8, content: &[u8]) -> Vec<u8> {
+    [&[id][..], &uleb(content.len() as u64), content].concat()
+}
+
+fn name(text: &str) -> Vec<u8> {
+    [&uleb(text.len() as u64)[..], text.as_bytes()].concat()
+}
+
+/// Plugin whose `on_result` runs `body`, with `data` in its memory at address 16.
+fn module(body: &[u8], data: &[u8]) -> Vec<u8> {
+    let alloc = [&[0x00, 0x41][..], &sleb(8192), &[0x0B]].concat(); // No locals, i32.const 8192
+    let on_result = [&[0x00][..], body].concat();
+    let exports = [&[0x03][..], &name("memory"), &[0x02, 0x00], &name("alloc"), &[0x00, 0x00], &name("on_result"), &[0x00, 0x01]].concat();
+    let code = [&[0x02][..], &uleb(alloc.len() as u64), &alloc, &uleb(on_result.len() as u64), &on_result].concat();
+    let segment = [&[0x01, 0x00, 0x41, 0x10, 0x0B][..], &uleb(data.len() as u64), data].concat();
+    [
+        &b"\0asm\x01\0\0\0"[..],
+        &section(1, &[0x02, 0x60, 0x01, 0x7F, 0x01, 0x7F, 0x60, 0x02, 0x7F, 0x7F, 0x01, 0x7E]), // (i32) -> i32, (i32, i32) -> i64
+        &section(3, &[0x02, 0x00, 0x01]),
+        &section(5, &[0x01, 0x00, 0x01]),
+        &section(7, &exports),
+        &section(10, &code),
+        &section(11, &segment),
+    ]
+    .concat()
+}
+
+/// Plugin giving `answer` for every result.
+fn answering(answer: &str) -> Vec<u8> {
+    let packed = 16 << 32 | answer.len() as i64;
+    module(&[&[0x42][..], &sleb(packed), &[0x0B]].concat(), answer.as_bytes())
+}
+
+fn write_plugin(name: &str, bytes: &[u8]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("check_nif-wasm-hook-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    std::fs::write(&path, bytes).unwrap();
+    path
+}
+
+fn check_nif(plugin: &Path) -> Output {
+    let cassette = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/cassettes/lookups.ndjson");
+    Command::new(env!("CARGO_BIN_EXE_check_nif"))
+        .arg("--cassette")
+        .arg(&cassette)
+        .args(["--no-cache", "--no-store", "--format", "json", "--wasm-hook"])
+        .arg(plugin)
+        .args(["500960046", "509442013"])
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn plugin_drops_results() {
+    let output = check_nif(&write_plugin("drop.wasm", &answering(r#"{"keep":false}"#)));
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(!stdout.contains("500960046"), "{}", stdout);
+    assert!(!stdout.contains("509442013"), "{}", stdout);
+}
+
+#[test]
+fn plugin_edits_results() {
+    let answer = r#"{"tag":"vip","entity":{"name":"Exemplo","email":"compras@exemplo.pt","phone":null}}"#;
+    let output = check_nif(&write_plugin("edit.wasm", &answering(answer)));
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains(r#""name":"Exemplo","#), "{}", stdout);
+    assert!(stdout.contains(r#""locality":"Lisboa","email":"compras@exemplo.pt""#), "{}", stdout);
+    assert!(!stdout.contains("213 000 000"), "{}", stdout);
+    assert_eq!(stdout.matches(r#""tag":"vip""#).count(), 2, "{}", stdout);
+    // The result without entity gets one, named by the plugin
+    assert!(stdout.contains(r#"{"nif":"509442013","name":"Exemplo","email":"compras@exemplo.pt"}"#), "{}", stdout);
+}
+
+#[test]
+fn plugin_out_of_fuel_keeps_results() {
+    // loop br 0 end: never returns, until the fuel of the call runs out
+    let endless = module(&[0x03, 0x40, 0x0C, 0x00, 0x0B, 0x42, 0x00, 0x0B], &[]);
+    let output = check_nif(&write_plugin("endless.wasm", &endless));
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains(r#""nif":"500960046","status":"valid_known""#), "{}", stdout);
+    assert!(stdout.contains(r#""nif":"509442013","status":"valid_unknown""#), "{}", stdout);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(stderr.matches("out of fuel").count(), 2, "{}", stderr);
+}